@@ -0,0 +1,66 @@
+//! 基准共用的 QUIC 测试包构造
+
+use ring::aead::quic::{HeaderProtectionKey, AES_128};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_128_GCM};
+use sniproxy_ng::quic::crypto::{derive_initial_keys_for_role, InitialKeyRole};
+
+/// 只含 SNI 扩展的 ClientHello，包在一个 CRYPTO frame 中
+fn crypto_frame(sni: &str) -> Vec<u8> {
+    let name = sni.as_bytes();
+    let mut ext = vec![0x00, 0x00];
+    ext.extend_from_slice(&((name.len() + 5) as u16).to_be_bytes());
+    ext.extend_from_slice(&((name.len() + 3) as u16).to_be_bytes());
+    ext.push(0x00);
+    ext.extend_from_slice(&(name.len() as u16).to_be_bytes());
+    ext.extend_from_slice(name);
+
+    let mut body = vec![0x03, 0x03];
+    body.extend_from_slice(&[0u8; 32]);
+    body.extend_from_slice(&[0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+    body.extend_from_slice(&(ext.len() as u16).to_be_bytes());
+    body.extend_from_slice(&ext);
+
+    let mut hello = vec![0x01, 0x00];
+    hello.extend_from_slice(&(body.len() as u16).to_be_bytes());
+    hello.extend_from_slice(&body);
+
+    let mut frame = vec![0x06, 0x00];
+    frame.extend_from_slice(&(hello.len() as u16 | 0x4000).to_be_bytes());
+    frame.extend_from_slice(&hello);
+    frame
+}
+
+/// 构造 1200 字节、受保护的 QUIC v1 客户端 Initial 包 (PN = 0，2 字节编码)
+pub fn client_initial(dcid: &[u8], sni: &str) -> Vec<u8> {
+    let keys = derive_initial_keys_for_role(dcid, 0x00000001, InitialKeyRole::Client).unwrap();
+
+    let mut header = vec![0xc1, 0x00, 0x00, 0x00, 0x01, dcid.len() as u8];
+    header.extend_from_slice(dcid);
+    header.extend_from_slice(&[0x00, 0x00]);
+
+    let mut payload = crypto_frame(sni);
+    let padded = 1200 - header.len() - 2 - 2 - 16;
+    payload.resize(padded, 0x00);
+
+    header.extend_from_slice(&((2 + payload.len() + 16) as u16 | 0x4000).to_be_bytes());
+    let pn_offset = header.len();
+    header.extend_from_slice(&[0x00, 0x00]);
+
+    let key = LessSafeKey::new(UnboundKey::new(&AES_128_GCM, &keys.key).unwrap());
+    key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(keys.iv),
+        Aad::from(&header[..]),
+        &mut payload,
+    )
+    .unwrap();
+
+    let mut packet = header;
+    packet.extend_from_slice(&payload);
+
+    let hp = HeaderProtectionKey::new(&AES_128, &keys.hp_key).unwrap();
+    let mask = hp.new_mask(&packet[pn_offset + 4..pn_offset + 20]).unwrap();
+    packet[0] ^= mask[0] & 0x0f;
+    packet[pn_offset] ^= mask[1];
+    packet[pn_offset + 1] ^= mask[2];
+    packet
+}
@@ -0,0 +1,68 @@
+//! QUIC Initial SNI 提取基准：冷缓存 vs 热缓存 (Initial 密钥已派生)，
+//! 以及无法解密的包只尝试 client 方向 vs 两个方向都尝试的代价
+//!
+//! 运行: cargo bench --bench quic_initial
+
+mod common;
+
+use common::client_initial;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use sniproxy_ng::quic::decrypt::{
+    extract_sni_from_quic_initial, extract_sni_with_roles, BOTH_ROLES, CLIENT_ROLE,
+};
+use sniproxy_ng::quic::key_cache::InitialKeyCache;
+use sniproxy_ng::quic::reassembly::CryptoReassembler;
+
+const DCID: [u8; 8] = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+
+fn bench_extract_sni(c: &mut Criterion) {
+    let packet = client_initial(&DCID, "www.example.com");
+    let mut reassembler = CryptoReassembler::default();
+
+    c.bench_function("extract_sni_cold_keys", |b| {
+        b.iter(|| {
+            let mut pkt = packet.clone();
+            let mut key_cache = InitialKeyCache::new(1);
+            black_box(
+                extract_sni_from_quic_initial(&mut pkt, &mut reassembler, &mut key_cache).unwrap(),
+            )
+        })
+    });
+
+    let mut key_cache = InitialKeyCache::default();
+    c.bench_function("extract_sni_warm_keys", |b| {
+        b.iter(|| {
+            let mut pkt = packet.clone();
+            black_box(
+                extract_sni_from_quic_initial(&mut pkt, &mut reassembler, &mut key_cache).unwrap(),
+            )
+        })
+    });
+}
+
+fn bench_undecryptable(c: &mut Criterion) {
+    // 篡改 AEAD tag，两个方向都无法解密
+    let mut packet = client_initial(&DCID, "www.example.com");
+    let last = packet.len() - 1;
+    packet[last] ^= 0xff;
+    let mut reassembler = CryptoReassembler::default();
+    let mut key_cache = InitialKeyCache::default();
+
+    for (name, roles) in [
+        ("undecryptable_client_role", CLIENT_ROLE),
+        ("undecryptable_both_roles", BOTH_ROLES),
+    ] {
+        c.bench_function(name, |b| {
+            b.iter(|| {
+                let mut pkt = packet.clone();
+                black_box(
+                    extract_sni_with_roles(&mut pkt, roles, &mut reassembler, &mut key_cache)
+                        .is_err(),
+                )
+            })
+        });
+    }
+}
+
+criterion_group!(benches, bench_extract_sni, bench_undecryptable);
+criterion_main!(benches);
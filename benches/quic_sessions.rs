@@ -0,0 +1,162 @@
+//! QUIC 会话转发基准：大量客户端并发发送小包 (已有会话的转发路径)
+//!
+//! 运行: cargo bench --bench quic_sessions
+
+mod common;
+
+use common::client_initial;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use sniproxy_ng::quic::session::{QuicSessionConfig, QuicSessionManager};
+use sniproxy_ng::router::Router;
+use sniproxy_ng::upstream::UpstreamSet;
+use sniproxy_ng::Config;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::runtime::Runtime;
+
+/// 每个客户端每轮发送的包数
+const PACKETS_PER_CLIENT: usize = 16;
+
+/// 最小的 SOCKS5 UDP ASSOCIATE 服务器，relay 收到的包直接丢弃
+async fn spawn_socks5() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(serve_associate(stream));
+        }
+    });
+    addr
+}
+
+async fn serve_associate(mut stream: TcpStream) -> std::io::Result<()> {
+    let mut greeting = [0u8; 2];
+    stream.read_exact(&mut greeting).await?;
+    let mut methods = vec![0u8; greeting[1] as usize];
+    stream.read_exact(&mut methods).await?;
+    stream.write_all(&[0x05, 0x00]).await?;
+
+    // [ver][cmd][rsv][atyp=IPv4][addr][port]
+    let mut request = [0u8; 10];
+    stream.read_exact(&mut request).await?;
+
+    let relay = UdpSocket::bind("127.0.0.1:0").await?;
+    let port = relay.local_addr()?.port().to_be_bytes();
+    stream
+        .write_all(&[0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1, port[0], port[1]])
+        .await?;
+
+    let mut buf = [0u8; 2048];
+    let mut control = [0u8; 1];
+    loop {
+        tokio::select! {
+            res = relay.recv_from(&mut buf) => { res?; }
+            res = stream.read(&mut control) => {
+                if res? == 0 {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// 建立 `clients` 个会话，返回管理器和各客户端地址
+async fn setup(clients: usize) -> (QuicSessionManager, Vec<SocketAddr>) {
+    let socks5_addr = spawn_socks5().await;
+    let mut config: Config = toml::from_str(
+        r#"
+[server]
+listen_https_addr = "127.0.0.1:0"
+
+[socks5]
+addr = "127.0.0.1:1080"
+timeout = 5
+
+[rules]
+allow = ["127.0.0.1"]
+"#,
+    )
+    .unwrap();
+    config.socks5.addr = socks5_addr.into();
+
+    let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    let session_config = QuicSessionConfig {
+        max_sessions_per_ip: clients,
+        ..Default::default()
+    };
+    let manager = QuicSessionManager::new(
+        session_config,
+        Router::new(config.clone()),
+        Arc::new(UpstreamSet::single(config.socks5)),
+        socket,
+    );
+
+    let mut addrs = Vec::with_capacity(clients);
+    for i in 0..clients {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 20000 + i as u16));
+        let dcid = (i as u64).to_be_bytes();
+        manager
+            .handle_packet(&client_initial(&dcid, "127.0.0.1"), addr)
+            .await
+            .unwrap();
+        addrs.push(addr);
+    }
+    while manager.session_count() < clients {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+
+    (manager, addrs)
+}
+
+fn bench_forward(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let mut group = c.benchmark_group("quic_session_forward");
+    for clients in [16usize, 256] {
+        let (manager, addrs) = rt.block_on(setup(clients));
+        group.throughput(Throughput::Elements((clients * PACKETS_PER_CLIENT) as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(clients), &clients, |b, _| {
+            b.iter_custom(|iters| run_rounds(&rt, &manager, &addrs, iters))
+        });
+    }
+    group.finish();
+}
+
+/// 每轮所有客户端并发发送 `PACKETS_PER_CLIENT` 个 64 字节 Short Header 包
+fn run_rounds(
+    rt: &Runtime,
+    manager: &QuicSessionManager,
+    addrs: &[SocketAddr],
+    iters: u64,
+) -> Duration {
+    rt.block_on(async {
+        let start = Instant::now();
+        for _ in 0..iters {
+            let tasks: Vec<_> = addrs
+                .iter()
+                .map(|&addr| {
+                    let manager = manager.clone();
+                    tokio::spawn(async move {
+                        let packet = [0x40u8; 64];
+                        for _ in 0..PACKETS_PER_CLIENT {
+                            manager.handle_packet(&packet, addr).await.unwrap();
+                        }
+                    })
+                })
+                .collect();
+            for task in tasks {
+                task.await.unwrap();
+            }
+        }
+        start.elapsed()
+    })
+}
+
+criterion_group!(benches, bench_forward);
+criterion_main!(benches);
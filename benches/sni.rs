@@ -0,0 +1,65 @@
+//! TLS SNI 提取基准：复制主机名的 extract_sni vs 借用输入的 extract_sni_ref，
+//! 以及完整解析 ClientHello (SNI + ALPN + supported_versions) 的代价
+//!
+//! 开始计时前先用计数分配器统计每次提取的堆分配次数并打印。
+//!
+//! 运行: cargo bench --bench sni
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use sniproxy_ng::tls::sni::{extract_sni, extract_sni_ref, parse_client_hello};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// 统计分配次数的全局分配器
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+#[path = "../tests/common/fixtures.rs"]
+mod fixtures;
+
+/// `f` 执行一次的堆分配次数
+fn allocations<T>(f: impl Fn() -> T) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    black_box(f());
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+fn bench_extract_sni(c: &mut Criterion) {
+    // 浏览器形态的 ClientHello (单个 TLS record)
+    let hello = fixtures::client_hello("chrome_tcp_ech_grease").data;
+    let hello = hello.as_slice();
+    println!(
+        "allocations per extraction: extract_sni={}, extract_sni_ref={}, parse_client_hello={}",
+        allocations(|| extract_sni(hello).unwrap()),
+        allocations(|| extract_sni_ref(hello).unwrap()),
+        allocations(|| parse_client_hello(hello).unwrap()),
+    );
+
+    c.bench_function("extract_sni_owned", |b| {
+        b.iter(|| black_box(extract_sni(black_box(hello)).unwrap()))
+    });
+    c.bench_function("extract_sni_ref", |b| {
+        b.iter(|| black_box(extract_sni_ref(black_box(hello)).unwrap()))
+    });
+    c.bench_function("parse_client_hello", |b| {
+        b.iter(|| black_box(parse_client_hello(black_box(hello)).unwrap()))
+    });
+}
+
+criterion_group!(benches, bench_extract_sni);
+criterion_main!(benches);
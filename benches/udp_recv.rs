@@ -0,0 +1,106 @@
+//! UDP 收包基准：逐包 recv_from vs 批量 recv_batch (Linux 上为 recvmmsg)
+//!
+//! 每轮先向监听 socket 灌入一批预先构造好的 Initial 包 (不计时)，
+//! 再计时读空接收队列，吞吐量即每秒收包数。
+//!
+//! 运行: cargo bench --bench udp_recv
+
+mod common;
+
+use common::client_initial;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use sniproxy_ng::quic::udp::{bind_udp_socket, recv_batch, RecvBatch, DEFAULT_MAX_DATAGRAM_SIZE};
+use std::net::{SocketAddr, UdpSocket as StdUdpSocket};
+use std::time::{Duration, Instant};
+
+/// 每轮灌入的包数 (需小于默认接收缓冲区能容纳的数量)
+const BURST: usize = 32;
+
+struct Blaster {
+    sender: StdUdpSocket,
+    target: SocketAddr,
+    packets: Vec<Vec<u8>>,
+}
+
+impl Blaster {
+    fn new(target: SocketAddr) -> Self {
+        let packets = (0..BURST as u64)
+            .map(|i| client_initial(&i.to_be_bytes(), "www.example.com"))
+            .collect();
+        Self {
+            sender: StdUdpSocket::bind("127.0.0.1:0").unwrap(),
+            target,
+            packets,
+        }
+    }
+
+    /// 发送一轮包并等待其进入接收队列
+    fn blast(&self) {
+        for packet in &self.packets {
+            self.sender.send_to(packet, self.target).unwrap();
+        }
+        std::thread::sleep(Duration::from_micros(200));
+    }
+
+    /// 重复 `iters` 轮：灌包 (不计时)，然后计时读空
+    fn timed(&self, iters: u64, mut recv_all: impl FnMut()) -> Duration {
+        let mut total = Duration::ZERO;
+        for _ in 0..iters {
+            self.blast();
+            let start = Instant::now();
+            recv_all();
+            total += start.elapsed();
+        }
+        total
+    }
+}
+
+fn bench_recv(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let socket =
+        rt.block_on(async { bind_udp_socket("127.0.0.1:0".parse().unwrap(), false).unwrap() });
+    let blaster = Blaster::new(socket.local_addr().unwrap());
+
+    let mut group = c.benchmark_group("udp_recv");
+    group.throughput(Throughput::Elements(BURST as u64));
+
+    group.bench_function("recv_from", |b| {
+        let mut buf = [0u8; DEFAULT_MAX_DATAGRAM_SIZE];
+        b.iter_custom(|iters| {
+            blaster.timed(iters, || {
+                rt.block_on(async {
+                    for _ in 0..BURST {
+                        socket.recv_from(&mut buf).await.unwrap();
+                    }
+                })
+            })
+        })
+    });
+
+    for batch_size in [8usize, 32] {
+        group.bench_with_input(
+            BenchmarkId::new("recv_batch", batch_size),
+            &batch_size,
+            |b, &batch_size| {
+                let mut batch = RecvBatch::new(batch_size, DEFAULT_MAX_DATAGRAM_SIZE);
+                b.iter_custom(|iters| {
+                    blaster.timed(iters, || {
+                        rt.block_on(async {
+                            let mut received = 0;
+                            while received < BURST {
+                                received += recv_batch(&socket, &mut batch).await.unwrap();
+                            }
+                        })
+                    })
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_recv);
+criterion_main!(benches);
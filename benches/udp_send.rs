@@ -0,0 +1,97 @@
+//! UDP 回包基准：逐包 send_to vs GSO 合并发送 (仅 Linux)
+//!
+//! 每轮向本机 socket 发送 32 个 1200 字节的数据报 (QUIC 常见的满包大小)，
+//! 逐包发送需要 32 次 sendto，GSO 只需 1 次 sendmsg；接收端在计时之外读空。
+//! 内核不支持 UDP_SEGMENT 时跳过 GSO 一组。
+//!
+//! 运行: cargo bench --bench udp_send
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use sniproxy_ng::quic::udp::{
+    bind_udp_socket, gso_supported, recv_batch, GsoBatch, RecvBatch, DEFAULT_MAX_DATAGRAM_SIZE,
+    GSO_MAX_SEGMENTS,
+};
+use std::sync::atomic::AtomicBool;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::runtime::Runtime;
+
+/// 每轮发送的数据报数
+const BURST: usize = 32;
+
+/// 单个数据报大小
+const DATAGRAM_SIZE: usize = 1200;
+
+/// 读空接收端 (不计时)
+fn drain(rt: &Runtime, receiver: &UdpSocket, batch: &mut RecvBatch) {
+    rt.block_on(async {
+        let mut received = 0;
+        while received < BURST {
+            received += recv_batch(receiver, batch).await.unwrap();
+        }
+    })
+}
+
+fn bench_send(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let (sender, receiver) = rt.block_on(async {
+        (
+            UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            bind_udp_socket("127.0.0.1:0".parse().unwrap(), false).unwrap(),
+        )
+    });
+    let dest = receiver.local_addr().unwrap();
+    let payload = [0x5a; DATAGRAM_SIZE];
+    let mut batch = RecvBatch::new(BURST, DEFAULT_MAX_DATAGRAM_SIZE);
+
+    let mut group = c.benchmark_group("udp_send");
+    group.throughput(Throughput::Elements(BURST as u64));
+
+    group.bench_function("send_to", |b| {
+        b.iter_custom(|iters| {
+            let mut total = Duration::ZERO;
+            for _ in 0..iters {
+                let start = Instant::now();
+                rt.block_on(async {
+                    for _ in 0..BURST {
+                        sender.send_to(&payload, dest).await.unwrap();
+                    }
+                });
+                total += start.elapsed();
+                drain(&rt, &receiver, &mut batch);
+            }
+            total
+        })
+    });
+
+    if gso_supported(&sender) {
+        let gso = AtomicBool::new(true);
+        let mut outgoing = GsoBatch::new(GSO_MAX_SEGMENTS);
+        group.bench_function("gso", |b| {
+            b.iter_custom(|iters| {
+                let mut total = Duration::ZERO;
+                for _ in 0..iters {
+                    let start = Instant::now();
+                    rt.block_on(async {
+                        for _ in 0..BURST {
+                            assert!(outgoing.push(&payload));
+                        }
+                        outgoing.flush(&sender, dest, &gso).await.unwrap();
+                    });
+                    total += start.elapsed();
+                    drain(&rt, &receiver, &mut batch);
+                }
+                total
+            })
+        });
+    } else {
+        eprintln!("UDP_SEGMENT is not supported here, skipping udp_send/gso");
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_send);
+criterion_main!(benches);
@@ -0,0 +1,63 @@
+//! 嵌入构建信息 (见 `src/build_info.rs`)：git 提交、rustc 版本和启用的 feature
+//!
+//! 没有 .git 的构建 (Docker、源码包) 可以通过环境变量 `SNIPROXY_GIT_COMMIT` 指定提交，
+//! 都没有时为 "unknown"。
+
+use std::env;
+use std::path::Path;
+use std::process::Command;
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=SNIPROXY_GIT_COMMIT");
+
+    let commit = env::var("SNIPROXY_GIT_COMMIT")
+        .ok()
+        .filter(|commit| !commit.trim().is_empty())
+        .or_else(git_commit)
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=SNIPROXY_GIT_COMMIT={}", commit.trim());
+
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version = command_output(Command::new(rustc).arg("--version"))
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=SNIPROXY_RUSTC_VERSION={}", rustc_version);
+
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(name, _)| {
+            name.strip_prefix("CARGO_FEATURE_")
+                .map(|feature| feature.to_ascii_lowercase().replace('_', "-"))
+        })
+        .collect();
+    features.sort();
+    println!("cargo:rustc-env=SNIPROXY_FEATURES={}", features.join(","));
+}
+
+/// 当前 HEAD 的短提交号
+fn git_commit() -> Option<String> {
+    let git_dir = Path::new(".git");
+    if !git_dir.exists() {
+        return None;
+    }
+    // HEAD 移动 (切换分支、提交) 时重新生成
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    if let Ok(head) = std::fs::read_to_string(git_dir.join("HEAD")) {
+        if let Some(reference) = head.trim().strip_prefix("ref: ") {
+            if git_dir.join(reference).exists() {
+                println!("cargo:rerun-if-changed=.git/{}", reference);
+            }
+        }
+    }
+
+    command_output(Command::new("git").args(["rev-parse", "--short=12", "HEAD"]))
+}
+
+fn command_output(command: &mut Command) -> Option<String> {
+    let output = command.output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let text = String::from_utf8(output.stdout).ok()?;
+    let text = text.trim();
+    (!text.is_empty()).then(|| text.to_string())
+}
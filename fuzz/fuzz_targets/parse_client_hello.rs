@@ -0,0 +1,26 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sniproxy_ng::tls::sni::{extract_sni_ref, parse_client_hello, Extensions, SniStatus};
+use std::borrow::Cow;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = parse_client_hello(data);
+    // 借用的 SNI 必须落在输入范围内
+    if let Ok(SniStatus::Found(Cow::Borrowed(sni))) = extract_sni_ref(data) {
+        let range = data.as_ptr_range();
+        assert!(range.contains(&sni.as_ptr()));
+        assert!(sni.as_ptr() as usize + sni.len() <= range.end as usize);
+    }
+    // 扩展迭代器产出的数据同样不能越界，出错后结束
+    let mut extensions = Extensions::new(data);
+    let mut total = 0;
+    for extension in extensions.by_ref() {
+        match extension {
+            Ok((_, ext_data)) => total += 4 + ext_data.len(),
+            Err(_) => break,
+        }
+    }
+    assert!(total <= data.len());
+    assert!(extensions.next().is_none());
+});
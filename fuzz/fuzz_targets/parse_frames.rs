@@ -0,0 +1,18 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sniproxy_ng::quic::frame::{parse_frames, Frame};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(frames) = parse_frames(data) {
+        for frame in frames {
+            if let Frame::Crypto {
+                offset,
+                data: crypto,
+            } = frame
+            {
+                assert!(offset + (crypto.len() as u64) < 1 << 62);
+            }
+        }
+    }
+});
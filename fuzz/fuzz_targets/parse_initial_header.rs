@@ -0,0 +1,14 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sniproxy_ng::quic::parse_initial_header;
+use sniproxy_ng::quic::parser::{extract_dcid, MAX_CID_LEN};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = extract_dcid(data);
+    if let Ok(header) = parse_initial_header(data) {
+        assert!(header.dcid.len() <= MAX_CID_LEN);
+        assert!(header.scid.len() <= MAX_CID_LEN);
+        assert!(header.pn_offset + header.payload_len <= data.len());
+    }
+});
@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sniproxy_ng::quic::parser::{encode_varint, parse_varint};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok((value, len)) = parse_varint(data) {
+        assert!(len <= data.len());
+        assert!(value < 1 << 62);
+        // 解析结果重新编码后必须得到同一个值
+        assert_eq!(parse_varint(&encode_varint(value)).unwrap().0, value);
+    }
+});
@@ -46,7 +46,10 @@ in
       after = [ "network.target" ];
 
       serviceConfig = {
-        Type = "simple";
+        # 监听 socket 全部绑定后发送 READY=1，运行时每 15 秒发送 WATCHDOG=1
+        Type = "notify";
+        WatchdogSec = 30;
+        Restart = "on-failure";
         ExecStart = "${cfg.package}/bin/sniproxy-ng";
         WorkingDirectory = "/var/lib/sniproxy-ng";
         ExecStartPre = "${lib.getBin pkgs.coreutils}/bin/ln -sf /etc/sniproxy-ng/config.toml /var/lib/sniproxy-ng/config.toml";
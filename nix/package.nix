@@ -2,7 +2,7 @@
 
 rustPlatform.buildRustPackage {
   pname = "sniproxy-ng";
-  version = "0.1.0";
+  version = "0.2.0";
   src = ./..;
   cargoLock.lockFile = ../Cargo.lock;
 
@@ -0,0 +1,253 @@
+#!/usr/bin/env python3
+"""生成 tests/fixtures/client_hellos 中构造的 ClientHello
+
+浏览器 (Chrome、Firefox，TCP 与 QUIC) 的 ClientHello 无法在离线环境中抓取，这里按
+浏览器实际发送的扩展顺序和取值构造，随机字段使用固定种子，重复运行得到相同的结果。
+抓取的 fixture (curl、openssl s_client) 由 --capture 通过本机监听获得，需要 curl 和
+openssl。生成后每个 fixture 有 <name>.hex 和 <name>.json 两个文件。
+
+firefox_tcp_ech_grease 与 openssl_s_client_tcp 早于本脚本加入，不由它生成。
+"""
+
+import argparse
+import json
+import random
+import socket
+import subprocess
+from pathlib import Path
+
+CORPUS = Path(__file__).resolve().parent.parent / "tests" / "fixtures" / "client_hellos"
+
+
+def u16(value: int) -> bytes:
+    return value.to_bytes(2, "big")
+
+
+def vec8(data: bytes) -> bytes:
+    return bytes([len(data)]) + data
+
+
+def vec16(data: bytes) -> bytes:
+    return u16(len(data)) + data
+
+
+def ext(ext_type: int, data: bytes) -> bytes:
+    return u16(ext_type) + vec16(data)
+
+
+def sni(name: str) -> bytes:
+    return ext(0x0000, vec16(b"\x00" + vec16(name.encode())))
+
+
+def alpn(*protocols: str) -> bytes:
+    return ext(0x0010, vec16(b"".join(vec8(p.encode()) for p in protocols)))
+
+
+def supported_versions(*versions: int) -> bytes:
+    return ext(0x002B, vec8(b"".join(u16(v) for v in versions)))
+
+
+def key_share(rng: random.Random, *groups: tuple[int, int]) -> bytes:
+    shares = b"".join(u16(group) + vec16(rng.randbytes(length)) for group, length in groups)
+    return ext(0x0033, vec16(shares))
+
+
+def ech_grease(rng: random.Random, payload_len: int) -> bytes:
+    # outer(0) + HPKE (KDF HKDF-SHA256, AEAD AES-128-GCM) + config_id + enc + payload
+    body = b"\x00" + u16(0x0001) + u16(0x0001) + bytes([rng.randrange(256)])
+    body += vec16(rng.randbytes(32)) + vec16(rng.randbytes(payload_len))
+    return ext(0xFE0D, body)
+
+
+def client_hello(rng: random.Random, ciphers: list[int], extensions: list[bytes]) -> bytes:
+    body = u16(0x0303) + rng.randbytes(32) + vec8(rng.randbytes(32))
+    body += vec16(b"".join(u16(c) for c in ciphers)) + vec8(b"\x00")
+    body += vec16(b"".join(extensions))
+    return b"\x01" + len(body).to_bytes(3, "big") + body
+
+
+def record(handshake: bytes) -> bytes:
+    return b"\x16" + u16(0x0301) + vec16(handshake)
+
+
+GREASE = [0x0A0A, 0x1A1A, 0x2A2A, 0x3A3A, 0x4A4A, 0x5A5A, 0x6A6A, 0x7A7A, 0x8A8A]
+CHROME_CIPHERS = [0x1301, 0x1302, 0x1303, 0xC02B, 0xC02F, 0xC02C, 0xC030, 0xCCA9, 0xCCA8,
+                  0xC013, 0xC014, 0x009C, 0x009D, 0x002F, 0x0035]
+SIG_ALGS = ext(0x000D, vec16(b"".join(u16(a) for a in
+                                      [0x0403, 0x0804, 0x0401, 0x0503, 0x0805, 0x0501, 0x0806, 0x0601])))
+QUIC_TRANSPORT_PARAMETERS = ext(0x0039, bytes.fromhex("0504800600000604800600000704800600000408"
+                                                      "8000c0000104800075300e0104"))
+
+
+def chrome_tcp(rng: random.Random, kyber: bool) -> bytes:
+    grease = rng.choice(GREASE)
+    shares = [(grease, 1), (0x6399, 1216), (0x001D, 32)] if kyber else [(grease, 1), (0x001D, 32)]
+    groups = [grease, 0x6399, 0x001D, 0x0017, 0x0018] if kyber else [grease, 0x001D, 0x0017, 0x0018]
+    extensions = [
+        ext(rng.choice(GREASE), b""),
+        sni("www.example.com"),
+        ext(0x0017, b""),
+        ext(0xFF01, b"\x00"),
+        ext(0x000A, vec16(b"".join(u16(g) for g in groups))),
+        ext(0x000B, b"\x01\x00"),
+        ext(0x0023, b""),
+        alpn("h2", "http/1.1"),
+        ext(0x0005, b"\x01\x00\x00\x00\x00"),
+        SIG_ALGS,
+        ext(0x0012, b""),
+        key_share(rng, *shares),
+        ext(0x002D, b"\x01\x01"),
+        supported_versions(grease, 0x0304, 0x0303),
+        ext(0x001B, b"\x02\x00\x02"),
+        ext(0x4469, b"\x00\x03\x02h2"),
+        ech_grease(rng, 144),
+        ext(rng.choice(GREASE), b"\x00"),
+    ]
+    return record(client_hello(rng, [rng.choice(GREASE)] + CHROME_CIPHERS, extensions))
+
+
+def quic_hello(rng: random.Random, firefox: bool) -> bytes:
+    """QUIC CRYPTO 流中的 ClientHello (解密后的 Handshake 消息，无 record 头)"""
+    if firefox:
+        extensions = [
+            sni("www.example.com"),
+            ext(0x000A, vec16(b"".join(u16(g) for g in [0x001D, 0x0017, 0x0018]))),
+            alpn("h3"),
+            key_share(rng, (0x001D, 32), (0x0017, 65)),
+            supported_versions(0x0304),
+            SIG_ALGS,
+            ext(0x002D, b"\x01\x01"),
+            QUIC_TRANSPORT_PARAMETERS,
+            ech_grease(rng, 239),
+        ]
+        return client_hello(rng, [0x1301, 0x1303, 0x1302], extensions)
+    grease = rng.choice(GREASE)
+    extensions = [
+        ext(grease, b""),
+        alpn("h3"),
+        ext(0x002D, b"\x01\x01"),
+        QUIC_TRANSPORT_PARAMETERS,
+        sni("www.example.com"),
+        ext(0x000A, vec16(b"".join(u16(g) for g in [grease, 0x11EC, 0x001D, 0x0017]))),
+        key_share(rng, (grease, 1), (0x11EC, 1216), (0x001D, 32)),
+        SIG_ALGS,
+        supported_versions(grease, 0x0304),
+        ext(0x001B, b"\x02\x00\x02"),
+        ech_grease(rng, 176),
+        ext(rng.choice(GREASE), b"\x00"),
+    ]
+    return client_hello(rng, [0x1301, 0x1302, 0x1303], extensions)
+
+
+# RFC 9001 Appendix A.2 client Initial 中 CRYPTO frame 的数据
+RFC9001_CLIENT_HELLO = bytes.fromhex(
+    "010000ed0303ebf8fa56f12939b9584a3896472ec40bb863cfd3e86804fe3a47"
+    "f06a2b69484c00000413011302010000c000000010000e00000b6578616d706c"
+    "652e636f6dff01000100000a00080006001d0017001800100007000504616c70"
+    "6e000500050100000000003300260024001d00209370b2c9caa47fbabaf4559f"
+    "edba753de171fa71f50f1ce15d43e994ec74d748002b0003020304000d001000"
+    "0e0403050306030203080408050806002d00020101001c00024001003900320408"
+    "ffffffffffffffff05048000ffff07048000ffff0801100104800075300901100f"
+    "088394c8f03e51570806048000ffff"
+)
+
+
+def write(name: str, data: bytes, sidecar: dict) -> None:
+    lines = [data[i:i + 32].hex() for i in range(0, len(data), 32)]
+    (CORPUS / f"{name}.hex").write_text("\n".join(lines) + "\n")
+    (CORPUS / f"{name}.json").write_text(json.dumps(sidecar, indent=2, ensure_ascii=False) + "\n")
+
+
+def expected(sni_name, alpn_list, versions, ech, record_version="TLS 1.0", legacy="TLS 1.2"):
+    return {
+        "sni": sni_name,
+        "alpn": alpn_list,
+        "supported_versions": versions,
+        "ech": ech,
+        "record_version": record_version,
+        "legacy_version": legacy,
+    }
+
+
+def capture(command: list[str]) -> bytes:
+    """在本机监听，运行 `command` (其中的 {port} 替换为端口)，返回收到的第一个 TLS record"""
+    server = socket.socket()
+    server.bind(("127.0.0.1", 0))
+    server.listen(1)
+    port = server.getsockname()[1]
+    client = subprocess.Popen([arg.format(port=port) for arg in command],
+                              stdin=subprocess.PIPE, stdout=subprocess.DEVNULL,
+                              stderr=subprocess.DEVNULL)
+    conn, _ = server.accept()
+    data = b""
+    while len(data) < 5 or len(data) < 5 + int.from_bytes(data[3:5], "big"):
+        chunk = conn.recv(65536)
+        if not chunk:
+            break
+        data += chunk
+    client.kill()
+    return data[:5 + int.from_bytes(data[3:5], "big")]
+
+
+def main() -> None:
+    parser = argparse.ArgumentParser(description=__doc__)
+    parser.add_argument("--capture", action="store_true", help="同时重新抓取 curl / openssl 的 ClientHello")
+    args = parser.parse_args()
+    CORPUS.mkdir(parents=True, exist_ok=True)
+    tcp13 = ["TLS 1.3", "TLS 1.2"]
+
+    rng = random.Random(3680)
+    write("chrome_tcp_ech_grease", chrome_tcp(rng, kyber=False), {
+        "source": "constructed: Chrome 120 extension order and values, ECH GREASE",
+        "captured": False,
+        "transport": "tcp",
+        "expected": expected("www.example.com", ["h2", "http/1.1"], tcp13, True),
+    })
+    write("chrome_tcp_kyber_jumbo", chrome_tcp(rng, kyber=True), {
+        "source": "constructed: Chrome 124 with an X25519Kyber768Draft00 key share (1.2 KB)",
+        "captured": False,
+        "transport": "tcp",
+        "expected": expected("www.example.com", ["h2", "http/1.1"], tcp13, True),
+    })
+    write("chrome_quic", quic_hello(rng, firefox=False), {
+        "source": "constructed: Chrome HTTP/3 ClientHello as found in the decrypted CRYPTO stream, "
+                  "X25519MLKEM768 key share",
+        "captured": False,
+        "transport": "quic",
+        "expected": expected("www.example.com", ["h3"], ["TLS 1.3"], True, record_version=None),
+    })
+    write("firefox_quic", quic_hello(rng, firefox=True), {
+        "source": "constructed: Firefox HTTP/3 ClientHello as found in the decrypted CRYPTO stream",
+        "captured": False,
+        "transport": "quic",
+        "expected": expected("www.example.com", ["h3"], ["TLS 1.3"], True, record_version=None),
+    })
+
+    write("rfc9001_quic", RFC9001_CLIENT_HELLO, {
+        "source": "RFC 9001 Appendix A.2 client Initial, CRYPTO frame data",
+        "captured": True,
+        "transport": "quic",
+        "expected": expected("example.com", ["alpn"], ["TLS 1.3"], False, record_version=None),
+    })
+
+    if args.capture:
+        write("curl_openssl_tcp", capture(
+            ["curl", "-s", "--max-time", "3", "--resolve", "www.example.com:{port}:127.0.0.1",
+             "https://www.example.com:{port}/"]), {
+            "source": "captured: curl 7.88.1 (OpenSSL 3.0), https://www.example.com/",
+            "captured": True,
+            "transport": "tcp",
+            "expected": expected("www.example.com", ["h2", "http/1.1"],
+                                 tcp13 + ["TLS 1.1", "TLS 1.0"], False),
+        })
+        write("openssl_s_client_no_sni", capture(
+            ["openssl", "s_client", "-connect", "127.0.0.1:{port}", "-noservername"]), {
+            "source": "captured: openssl 3.0 s_client -noservername",
+            "captured": True,
+            "transport": "tcp",
+            "expected": expected(None, [], tcp13, False),
+        })
+
+
+if __name__ == "__main__":
+    main()
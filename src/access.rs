@@ -0,0 +1,439 @@
+//! 访问日志
+//!
+//! 每个 TCP/HTTP 连接结束时在 `access` target 下输出一条 info 事件 (见 [`AccessEntry`])，
+//! 记录客户端、请求的主机、出口、结果和转发字节数。配置了 `[logging.access]` 时这些事件
+//! 写入单独的文件 (主日志不再包含)，按 `rotation` 轮转；未配置时与其它事件一起写入主日志。
+//!
+//! `format = "combined"` 近似 nginx 的 combined 格式，现有的日志分析工具可以直接使用：
+//!
+//! ```text
+//! 203.0.113.7 - - [16/Oct/2026:08:05:09 +0000] "CONNECT www.example.com:443 TLS" 200 5120 "-" "-"
+//! ```
+//!
+//! 请求行中 TLS 连接为 `CONNECT <sni>:443 TLS`，HTTP 为 `<方法> <host>:<端口> HTTP/1.1`，
+//! 没有取得主机名时为 `-`；状态码由结果推出 (见 [`status_for`])，字节数为发给
+//! 客户端的字节数。`format = "json"` 每行一个对象，包含事件的全部字段。
+
+use crate::config::{AccessLogConfig, AccessLogFormat, LogRotation};
+use crate::logging;
+use crate::metrics::{Listener, Registry};
+use crate::reason::{FailureReason, Outcome, RejectReason};
+use std::collections::BTreeMap;
+use std::fmt::{self, Write as _};
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tracing::field::{Field, Visit};
+use tracing::{info, Event, Metadata, Subscriber};
+use tracing_appender::non_blocking::{NonBlocking, WorkerGuard};
+use tracing_appender::rolling::{InitError, RollingFileAppender, Rotation};
+use tracing_subscriber::filter::filter_fn;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::{self as tfmt, FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// 访问记录的 target
+pub const TARGET: &str = "access";
+
+/// 是否是访问记录
+pub fn is_access(metadata: &Metadata<'_>) -> bool {
+    metadata.target() == TARGET
+}
+
+/// 一个连接的访问记录，处理过程中逐步填入，连接结束时以 [`AccessEntry::finish`] 计数并输出
+#[derive(Debug)]
+pub struct AccessEntry {
+    listener: Listener,
+    conn_id: u64,
+    client: SocketAddr,
+    started: Instant,
+    /// HTTP 请求方法；TLS 连接为 None
+    pub method: Option<String>,
+    /// 请求的主机名 (SNI、Host 或 CONNECT 目标)
+    pub host: Option<String>,
+    pub port: u16,
+    /// 选中的出口
+    pub upstream: Option<String>,
+    /// 连接的结果，未设置时为 [`FailureReason::Internal`]
+    pub outcome: Outcome,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+}
+
+impl AccessEntry {
+    pub fn new(listener: Listener, conn_id: u64, client: SocketAddr, port: u16) -> Self {
+        Self {
+            listener,
+            conn_id,
+            client,
+            started: Instant::now(),
+            method: None,
+            host: None,
+            port,
+            upstream: None,
+            outcome: Outcome::Failed(FailureReason::Internal),
+            bytes_up: 0,
+            bytes_down: 0,
+        }
+    }
+
+    /// 把结果计入 `metrics` 按原因的计数，并输出访问记录；每个连接调用一次
+    pub fn finish(&self, metrics: &Registry) {
+        metrics.record_outcome(self.listener, self.outcome);
+        info!(
+            target: TARGET,
+            listener = self.listener.label(),
+            conn_id = self.conn_id,
+            client = %self.client,
+            method = self.method.as_deref(),
+            host = self.host.as_deref(),
+            port = self.port,
+            upstream = self.upstream.as_deref(),
+            outcome = self.outcome.as_label(),
+            status = status_for(self.outcome),
+            bytes_up = self.bytes_up,
+            bytes_down = self.bytes_down,
+            duration_ms = logging::millis(self.started.elapsed()),
+            "access"
+        );
+    }
+}
+
+/// combined 格式中的 HTTP 状态码
+pub fn status_for(outcome: Outcome) -> u16 {
+    match outcome {
+        Outcome::Closed => 200,
+        Outcome::ClientClosed => 499,
+        Outcome::Rejected(RejectReason::HeaderTooLarge) => 431,
+        Outcome::Rejected(reason) if reason.is_policy() => 403,
+        Outcome::Rejected(_) => 400,
+        Outcome::Failed(reason) => match reason {
+            // 转发过数据，只是空闲超时后关闭
+            FailureReason::IdleTimeout => 200,
+            FailureReason::ClientTimeout => 408,
+            FailureReason::Socks5ConnectFailed | FailureReason::UpstreamConnectFailed => 502,
+            FailureReason::UpstreamTimeout => 504,
+            FailureReason::PoolExhausted => 503,
+            FailureReason::ClientIo
+            | FailureReason::QuicDecryptFailed
+            | FailureReason::QuicMalformed
+            | FailureReason::QuicSessionFailed
+            | FailureReason::Internal => 500,
+        },
+    }
+}
+
+/// 无法打开访问日志文件
+#[derive(Debug, thiserror::Error)]
+pub enum AccessLogError {
+    #[error("Failed to create access log directory {}: {source}", path.display())]
+    CreateDir { path: PathBuf, source: io::Error },
+    #[error("logging.access.file has no file name: {0}")]
+    NoFileName(String),
+    #[error("Failed to open access log {path}: {source}")]
+    Open { path: String, source: InitError },
+}
+
+/// 打开访问日志文件 (非阻塞写入)，返回的 guard 需保持到进程退出
+pub fn writer(config: &AccessLogConfig) -> Result<(NonBlocking, WorkerGuard), AccessLogError> {
+    let path = Path::new(&config.file);
+    let dir = path
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    std::fs::create_dir_all(dir).map_err(|source| AccessLogError::CreateDir {
+        path: dir.to_path_buf(),
+        source,
+    })?;
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| AccessLogError::NoFileName(config.file.clone()))?;
+
+    let mut builder = RollingFileAppender::builder()
+        .rotation(match config.rotation {
+            LogRotation::Never => Rotation::NEVER,
+            LogRotation::Minutely => Rotation::MINUTELY,
+            LogRotation::Hourly => Rotation::HOURLY,
+            LogRotation::Daily => Rotation::DAILY,
+        })
+        .filename_prefix(file_name);
+    if let Some(max_files) = config.max_files {
+        builder = builder.max_log_files(max_files.max(1));
+    }
+    let appender = builder.build(dir).map_err(|source| AccessLogError::Open {
+        path: config.file.clone(),
+        source,
+    })?;
+    Ok(tracing_appender::non_blocking(appender))
+}
+
+/// 只输出访问记录的 fmt 层
+pub fn layer<S>(format: AccessLogFormat, writer: NonBlocking) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    match format {
+        AccessLogFormat::Combined => tfmt::layer()
+            .with_writer(writer)
+            .event_format(Combined)
+            .with_filter(filter_fn(is_access))
+            .boxed(),
+        AccessLogFormat::Json => tfmt::layer()
+            .json()
+            .flatten_event(true)
+            .with_current_span(false)
+            .with_span_list(false)
+            .with_target(false)
+            .with_writer(writer)
+            .with_filter(filter_fn(is_access))
+            .boxed(),
+    }
+}
+
+/// combined 格式的事件格式化
+struct Combined;
+
+impl<S, N> FormatEvent<S, N> for Combined
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        _ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        writeln!(writer, "{}", combined_line(&fields.0, SystemTime::now()))
+    }
+}
+
+#[derive(Default)]
+struct Fields(BTreeMap<&'static str, String>);
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name(), value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.insert(field.name(), format!("{:?}", value));
+    }
+}
+
+/// 由访问记录的字段拼出一行 combined 格式
+fn combined_line(fields: &BTreeMap<&'static str, String>, now: SystemTime) -> String {
+    let field = |name| fields.get(name).map(String::as_str);
+    let client = field("client").unwrap_or("-");
+    let remote = client
+        .parse::<SocketAddr>()
+        .map_or_else(|_| client.to_string(), |addr| addr.ip().to_string());
+    let request = match field("host") {
+        Some(host) => {
+            let (method, protocol) = match field("listener") {
+                Some("http") => (field("method").unwrap_or("-"), "HTTP/1.1"),
+                _ => ("CONNECT", "TLS"),
+            };
+            format!(
+                "{} {}:{} {}",
+                method,
+                host,
+                field("port").unwrap_or("-"),
+                protocol
+            )
+        }
+        None => "-".to_string(),
+    };
+    let secs = now.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    format!(
+        "{} - - [{}] \"{}\" {} {} \"-\" \"-\"",
+        remote,
+        clf_time(secs),
+        request.replace('"', "\\\""),
+        field("status").unwrap_or("-"),
+        field("bytes_down").unwrap_or("0"),
+    )
+}
+
+/// Common Log Format 的时间 (UTC)，例如 `16/Oct/2026:08:05:09 +0000`
+fn clf_time(unix_secs: u64) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    // 公历日期换算 (Howard Hinnant, days_from_civil 的逆运算)
+    let days = (unix_secs / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let doe = days.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    let secs_of_day = unix_secs % 86_400;
+    let mut out = String::new();
+    let _ = write!(
+        out,
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    );
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tracing_subscriber::prelude::*;
+
+    #[test]
+    fn formats_combined_lines() {
+        assert_eq!(clf_time(1_792_137_909), "16/Oct/2026:08:05:09 +0000");
+        assert_eq!(clf_time(1_709_251_199), "29/Feb/2024:23:59:59 +0000");
+        assert_eq!(clf_time(0), "01/Jan/1970:00:00:00 +0000");
+
+        let now = UNIX_EPOCH + Duration::from_secs(1_792_137_909);
+        let fields = |pairs: &[(&'static str, &str)]| {
+            pairs
+                .iter()
+                .map(|(name, value)| (*name, value.to_string()))
+                .collect::<BTreeMap<_, _>>()
+        };
+        assert_eq!(
+            combined_line(
+                &fields(&[
+                    ("listener", "tcp"),
+                    ("client", "203.0.113.7:51000"),
+                    ("host", "www.example.com"),
+                    ("port", "443"),
+                    ("status", "200"),
+                    ("bytes_down", "5120"),
+                ]),
+                now
+            ),
+            "203.0.113.7 - - [16/Oct/2026:08:05:09 +0000] \"CONNECT www.example.com:443 TLS\" 200 5120 \"-\" \"-\""
+        );
+        assert_eq!(
+            combined_line(
+                &fields(&[
+                    ("listener", "http"),
+                    ("client", "[2001:db8::1]:8080"),
+                    ("method", "GET"),
+                    ("host", "example.org"),
+                    ("port", "80"),
+                    ("status", "403"),
+                ]),
+                now
+            ),
+            "2001:db8::1 - - [16/Oct/2026:08:05:09 +0000] \"GET example.org:80 HTTP/1.1\" 403 0 \"-\" \"-\""
+        );
+        // 没有取得主机名
+        assert!(combined_line(
+            &fields(&[("client", "192.0.2.1:1"), ("status", "400")]),
+            now
+        )
+        .starts_with("192.0.2.1 - - [16/Oct/2026:08:05:09 +0000] \"-\" 400 0"));
+    }
+
+    /// 写入共享缓冲区的主日志
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// 经 [`writer`] 和 [`layer`] 写一条访问记录和一条普通事件，返回 (访问日志, 主日志)
+    fn write_events(format: AccessLogFormat) -> (String, String) {
+        let dir = std::env::temp_dir().join(format!(
+            "sniproxy-ng-access-{:?}-{}",
+            format,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        let config = AccessLogConfig {
+            file: dir.join("access.log").to_string_lossy().into_owned(),
+            format,
+            rotation: LogRotation::Never,
+            max_files: None,
+        };
+        let main_log = Buffer::default();
+        {
+            let (writer, _guard) = writer(&config).unwrap();
+            let main_writer = main_log.clone();
+            let subscriber = tracing_subscriber::registry()
+                .with(
+                    tfmt::layer()
+                        .with_ansi(false)
+                        .with_writer(move || main_writer.clone())
+                        .with_filter(filter_fn(|metadata| !is_access(metadata))),
+                )
+                .with(layer(format, writer));
+            tracing::subscriber::with_default(subscriber, || {
+                tracing::info!(outcome = "established", "route established");
+                let mut entry = AccessEntry::new(
+                    Listener::Tcp,
+                    7,
+                    "198.51.100.20:40000".parse().unwrap(),
+                    443,
+                );
+                entry.host = Some("www.example.com".to_string());
+                entry.upstream = Some("default".to_string());
+                entry.outcome = Outcome::Closed;
+                entry.bytes_up = 517;
+                entry.bytes_down = 4096;
+                entry.finish(&Registry::default());
+            });
+        }
+        let access = std::fs::read_to_string(dir.join("access.log")).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        let main = String::from_utf8(main_log.0.lock().unwrap().clone()).unwrap();
+        (access, main)
+    }
+
+    #[test]
+    fn access_events_go_only_to_the_access_file() {
+        let (access, main) = write_events(AccessLogFormat::Combined);
+        let lines: Vec<_> = access.lines().collect();
+        assert_eq!(lines.len(), 1, "{}", access);
+        assert!(lines[0].starts_with("198.51.100.20 - - ["), "{}", lines[0]);
+        assert!(
+            lines[0].ends_with("] \"CONNECT www.example.com:443 TLS\" 200 4096 \"-\" \"-\""),
+            "{}",
+            lines[0]
+        );
+        assert!(main.contains("route established"), "{}", main);
+        assert!(!main.contains("www.example.com"), "{}", main);
+
+        let (access, _) = write_events(AccessLogFormat::Json);
+        let lines: Vec<_> = access.lines().collect();
+        assert_eq!(lines.len(), 1, "{}", access);
+        let record: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(record["message"], "access");
+        assert_eq!(record["host"], "www.example.com");
+        assert_eq!(record["upstream"], "default");
+        assert_eq!(record["status"], 200);
+        assert_eq!(record["bytes_up"], 517);
+        assert_eq!(record["conn_id"], 7);
+    }
+}
@@ -0,0 +1,560 @@
+//! TCP/HTTP 流量的后端
+//!
+//! 默认经 SOCKS5 上游 ([`UpstreamSet`]) 转发；只有 HTTP 正向代理的环境可以配置
+//! `[backend] type = "http_connect"`，用 `CONNECT host:port` 建立隧道。HTTP 代理
+//! 无法承载 UDP，此时 QUIC 不可用。
+//!
+//! 处理器先用 [`Backend::pick`] 选出 [`Dialer`]，再用 [`Dialer::connect_tcp`] 建连。
+
+use crate::config::{BackendKind, Config, ConfigError, UpstreamProtocol};
+use crate::outbound::{self, BindError, DialError, OutboundBind, ProxyResolver};
+use crate::socks5::socks4::{Socks4Error, Socks4aClient};
+use crate::socks5::{Socks5Client, Socks5Error, Socks5TcpStream};
+use crate::upstream::{ActiveSlot, Upstream, UpstreamSet};
+use std::fmt;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use tracing::debug;
+
+/// CONNECT 响应头的最大长度
+const MAX_RESPONSE_HEADER_LEN: usize = 8 * 1024;
+
+/// 经后端建立的到目标的字节流
+pub enum BackendStream {
+    /// SOCKS5 CONNECT 隧道
+    Socks5(Socks5TcpStream),
+    /// HTTP CONNECT 或 SOCKS4a 隧道 (握手完成后就是原始 TCP 连接)
+    Tcp(TcpStream),
+    /// 计入上游在途连接数的流，关闭 (丢弃) 时计数减一
+    Counted {
+        stream: Box<BackendStream>,
+        _slot: ActiveSlot,
+    },
+}
+
+impl BackendStream {
+    /// 底层到代理的 TCP 连接
+    pub fn tcp_stream(&self) -> &TcpStream {
+        match self {
+            BackendStream::Socks5(stream) => stream.get_socket_ref().tcp_stream(),
+            BackendStream::Tcp(stream) => stream,
+            BackendStream::Counted { stream, .. } => stream.tcp_stream(),
+        }
+    }
+}
+
+impl AsyncRead for BackendStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            BackendStream::Socks5(stream) => Pin::new(stream).poll_read(cx, buf),
+            BackendStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            BackendStream::Counted { stream, .. } => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for BackendStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            BackendStream::Socks5(stream) => Pin::new(stream).poll_write(cx, data),
+            BackendStream::Tcp(stream) => Pin::new(stream).poll_write(cx, data),
+            BackendStream::Counted { stream, .. } => Pin::new(stream.as_mut()).poll_write(cx, data),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            BackendStream::Socks5(stream) => Pin::new(stream).poll_flush(cx),
+            BackendStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            BackendStream::Counted { stream, .. } => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            BackendStream::Socks5(stream) => Pin::new(stream).poll_shutdown(cx),
+            BackendStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            BackendStream::Counted { stream, .. } => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+/// HTTP CONNECT 建连失败
+#[derive(Error, Debug)]
+pub enum HttpConnectError {
+    /// 代理要求认证或认证失败 (407)
+    #[error("HTTP proxy requires authentication (407 Proxy Authentication Required)")]
+    ProxyAuthRequired,
+
+    /// 代理拒绝建立隧道
+    #[error("HTTP proxy refused CONNECT: {status} {reason}")]
+    Rejected { status: u16, reason: String },
+
+    /// 代理的响应不是合法的 HTTP 响应
+    #[error("Invalid response from HTTP proxy: {0}")]
+    InvalidResponse(String),
+
+    /// 与代理之间的 I/O 错误 (含连接代理失败)
+    #[error("HTTP proxy I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// 出站 socket 绑定源地址或网卡失败
+    #[error("HTTP proxy {0}")]
+    Bind(#[from] BindError),
+
+    /// 建连或握手超时
+    #[error("HTTP CONNECT timed out after {0:?}")]
+    Timeout(Duration),
+}
+
+/// 经出口 ([`Dialer`]) 建连失败，按出口类型区分
+#[derive(Error, Debug)]
+pub enum ConnectError {
+    /// SOCKS5 上游
+    #[error(transparent)]
+    Socks5(#[from] Socks5Error),
+
+    /// SOCKS4a 上游
+    #[error(transparent)]
+    Socks4(#[from] Socks4Error),
+
+    /// HTTP 正向代理
+    #[error(transparent)]
+    HttpConnect(#[from] HttpConnectError),
+}
+
+/// 经 HTTP 正向代理的 CONNECT 方法建立隧道
+#[derive(Debug, Clone)]
+pub struct HttpConnectBackend {
+    proxy_addr: SocketAddr,
+    /// 可选的认证信息
+    auth: Option<(String, String)>,
+    /// 到代理的连接的源地址/网卡
+    bind: OutboundBind,
+    /// 建连和握手超时
+    timeout: Duration,
+}
+
+impl From<DialError> for HttpConnectError {
+    fn from(error: DialError) -> Self {
+        match error {
+            DialError::Bind(e) => HttpConnectError::Bind(e),
+            DialError::Connect { source, .. } => HttpConnectError::Io(source),
+        }
+    }
+}
+
+impl HttpConnectBackend {
+    /// 创建 HTTP CONNECT 后端
+    pub fn new(proxy_addr: SocketAddr) -> Self {
+        Self {
+            proxy_addr,
+            auth: None,
+            bind: OutboundBind::default(),
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// 设置认证信息 (Proxy-Authorization: Basic)
+    pub fn with_auth(mut self, username: String, password: String) -> Self {
+        self.auth = Some((username, password));
+        self
+    }
+
+    /// 设置到代理的连接的源地址/网卡
+    pub fn with_bind(mut self, bind: OutboundBind) -> Self {
+        self.bind = bind;
+        self
+    }
+
+    /// 设置建连和握手超时
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// 代理地址
+    pub fn proxy_addr(&self) -> SocketAddr {
+        self.proxy_addr
+    }
+
+    /// 建立到 `host:port` 的隧道，返回握手完成后的原始连接
+    pub async fn connect(&self, host: &str, port: u16) -> Result<TcpStream, HttpConnectError> {
+        debug!(
+            "HTTP CONNECT to {}:{} via proxy {}",
+            host, port, self.proxy_addr
+        );
+        tokio::time::timeout(self.timeout, self.handshake(host, port))
+            .await
+            .map_err(|_| HttpConnectError::Timeout(self.timeout))?
+    }
+
+    async fn handshake(&self, host: &str, port: u16) -> Result<TcpStream, HttpConnectError> {
+        let mut stream =
+            outbound::connect(&ProxyResolver::from(self.proxy_addr), &self.bind).await?;
+
+        // IPv6 字面量需要方括号
+        let authority = if host.contains(':') {
+            format!("[{}]:{}", host, port)
+        } else {
+            format!("{}:{}", host, port)
+        };
+        let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority);
+        if let Some((username, password)) = &self.auth {
+            request.push_str(&format!(
+                "Proxy-Authorization: Basic {}\r\n",
+                base64_encode(format!("{}:{}", username, password).as_bytes())
+            ));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        // 逐字节读取响应头：之后的字节属于隧道，不能多读
+        let mut header = Vec::with_capacity(256);
+        while !header.ends_with(b"\r\n\r\n") {
+            if header.len() >= MAX_RESPONSE_HEADER_LEN {
+                return Err(HttpConnectError::InvalidResponse(
+                    "response header too long".to_string(),
+                ));
+            }
+            let byte = stream.read_u8().await?;
+            header.push(byte);
+        }
+
+        let status_line = header
+            .split(|b| *b == b'\n')
+            .next()
+            .map(|line| String::from_utf8_lossy(line).trim_end().to_string())
+            .unwrap_or_default();
+        let mut parts = status_line.splitn(3, ' ');
+        let (Some(version), Some(status)) = (parts.next(), parts.next()) else {
+            return Err(HttpConnectError::InvalidResponse(status_line));
+        };
+        let Ok(status) = status.parse::<u16>() else {
+            return Err(HttpConnectError::InvalidResponse(status_line));
+        };
+        if !version.starts_with("HTTP/1.") {
+            return Err(HttpConnectError::InvalidResponse(status_line));
+        }
+
+        match status {
+            200..=299 => Ok(stream),
+            407 => Err(HttpConnectError::ProxyAuthRequired),
+            _ => Err(HttpConnectError::Rejected {
+                status,
+                reason: parts.next().unwrap_or_default().to_string(),
+            }),
+        }
+    }
+}
+
+/// TCP/HTTP 流量的后端
+#[derive(Debug, Clone)]
+pub enum Backend {
+    /// SOCKS5 上游集合
+    Socks5(Arc<UpstreamSet>),
+    /// HTTP 正向代理
+    HttpConnect(Arc<HttpConnectBackend>),
+}
+
+impl Backend {
+    /// 由 `[backend]` 创建；`http_connect` 缺少代理地址时返回错误
+    pub fn from_config(config: &Config, upstreams: Arc<UpstreamSet>) -> Result<Self, ConfigError> {
+        match config.backend.kind {
+            BackendKind::Socks5 => Ok(Backend::Socks5(upstreams)),
+            BackendKind::HttpConnect => {
+                let Some(addr) = config.backend.addr else {
+                    return Err(ConfigError::Invalid {
+                        section: "backend",
+                        reason: "backend.type = \"http_connect\" requires backend.addr".to_string(),
+                    });
+                };
+                let mut backend = HttpConnectBackend::new(addr)
+                    .with_bind(OutboundBind::from_config(&config.socks5))
+                    .with_timeout(Duration::from_secs(config.socks5.timeout));
+                if let (Some(username), Some(password)) =
+                    (&config.backend.username, &config.backend.password)
+                {
+                    backend = backend.with_auth(username.clone(), password.clone());
+                }
+                Ok(Backend::HttpConnect(Arc::new(backend)))
+            }
+        }
+    }
+
+    /// 选择一个连接的出口
+    ///
+    /// SOCKS5 后端按路由规则指定的上游或 `key` 的哈希选择 (见 [`UpstreamSet::select`])；
+    /// HTTP CONNECT 后端只有一个代理，忽略这两个参数。
+    pub fn pick(&self, route: Option<&str>, key: &[u8]) -> Dialer {
+        match self {
+            Backend::Socks5(upstreams) => Dialer::Socks5(upstreams.select(route, key)),
+            Backend::HttpConnect(backend) => Dialer::HttpConnect(Arc::clone(backend)),
+        }
+    }
+}
+
+/// 选定的出口，负责建连并记录被动健康状态
+#[derive(Debug, Clone)]
+pub enum Dialer {
+    /// SOCKS 上游 (SOCKS5 或 SOCKS4a，见 `protocol`)
+    Socks5(Arc<Upstream>),
+    /// HTTP 正向代理
+    HttpConnect(Arc<HttpConnectBackend>),
+}
+
+impl Dialer {
+    /// 经该出口建立到 `host:port` 的连接
+    ///
+    /// 经 SOCKS 上游的连接在关闭前计入该上游的在途连接数 (`least_connections` 使用)。
+    pub async fn connect_tcp(&self, host: &str, port: u16) -> Result<BackendStream, ConnectError> {
+        match self {
+            Dialer::Socks5(upstream) if upstream.config().protocol == UpstreamProtocol::Socks4a => {
+                let config = upstream.config();
+                let client = Socks4aClient::new(config.addr.to_string())
+                    .with_resolver(upstream.resolver())
+                    .with_user_id(config.username.clone().unwrap_or_default())
+                    .with_bind(OutboundBind::from_config(config))
+                    .with_timeout(Duration::from_secs(config.timeout));
+                let stream = client.connect(host, port).await?;
+                Ok(BackendStream::Counted {
+                    stream: Box::new(BackendStream::Tcp(stream)),
+                    _slot: upstream.open_stream(),
+                })
+            }
+            Dialer::Socks5(upstream) => {
+                let client =
+                    socks5_client(upstream).with_metrics(upstream.metrics(), upstream.name());
+                let stream = client.connect(host, port).await?;
+                Ok(BackendStream::Counted {
+                    stream: Box::new(BackendStream::Socks5(stream)),
+                    _slot: upstream.open_stream(),
+                })
+            }
+            Dialer::HttpConnect(backend) => {
+                Ok(BackendStream::Tcp(backend.connect(host, port).await?))
+            }
+        }
+    }
+
+    /// 记录一次成功的建连
+    pub fn record_success(&self) {
+        if let Dialer::Socks5(upstream) = self {
+            upstream.record_success();
+        }
+    }
+
+    /// 记录一次失败的建连
+    pub fn record_failure(&self) {
+        if let Dialer::Socks5(upstream) = self {
+            upstream.record_failure();
+        }
+    }
+}
+
+impl fmt::Display for Dialer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Dialer::Socks5(upstream) => write!(f, "socks5 '{}'", upstream.name()),
+            Dialer::HttpConnect(backend) => write!(f, "http_connect {}", backend.proxy_addr),
+        }
+    }
+}
+
+/// 由上游配置创建 SOCKS5 客户端；没有代理链时共享上游的地址解析缓存
+fn socks5_client(upstream: &Upstream) -> Socks5Client {
+    let client = Socks5Client::from_config(upstream.config());
+    if upstream.config().chain.is_empty() {
+        client.with_resolver(upstream.resolver())
+    } else {
+        client
+    }
+}
+
+/// 标准 base64 (带填充)，用于 Proxy-Authorization
+fn base64_encode(input: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(input.len().div_ceil(3) * 4);
+    for chunk in input.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// 模拟 HTTP 正向代理：校验 CONNECT 请求和认证，成功后回显隧道数据
+    async fn spawn_connect_proxy(expected_auth: Option<&str>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let expected_auth = expected_auth.map(|auth| format!("Basic {}", auth));
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let expected_auth = expected_auth.clone();
+                tokio::spawn(async move {
+                    let mut header = Vec::new();
+                    while !header.ends_with(b"\r\n\r\n") {
+                        header.push(stream.read_u8().await.unwrap());
+                    }
+                    let header = String::from_utf8(header).unwrap();
+                    assert!(
+                        header.starts_with("CONNECT example.com:443 HTTP/1.1\r\n"),
+                        "{}",
+                        header
+                    );
+                    assert!(header.contains("\r\nHost: example.com:443\r\n"));
+
+                    let auth = header
+                        .lines()
+                        .find_map(|line| line.strip_prefix("Proxy-Authorization: "));
+                    if expected_auth.is_some() && auth != expected_auth.as_deref() {
+                        stream
+                            .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"proxy\"\r\n\r\n")
+                            .await
+                            .unwrap();
+                        return;
+                    }
+                    stream
+                        .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                        .await
+                        .unwrap();
+                    let (mut reader, mut writer) = stream.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn test_base64_encode() {
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foo"), "Zm9v");
+        assert_eq!(base64_encode(b"user:pass"), "dXNlcjpwYXNz");
+    }
+
+    #[tokio::test]
+    async fn http_connect_tunnels_after_200() {
+        let proxy = spawn_connect_proxy(Some("dXNlcjpwYXNz")).await;
+        let backend = Backend::HttpConnect(Arc::new(
+            HttpConnectBackend::new(proxy).with_auth("user".to_string(), "pass".to_string()),
+        ));
+        let dialer = backend.pick(None, b"example.com");
+        assert_eq!(dialer.to_string(), format!("http_connect {}", proxy));
+
+        let mut stream = dialer.connect_tcp("example.com", 443).await.unwrap();
+        assert!(matches!(stream, BackendStream::Tcp(_)));
+        stream.write_all(b"client hello").await.unwrap();
+        let mut echoed = [0u8; 12];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"client hello");
+    }
+
+    #[tokio::test]
+    async fn http_connect_reports_407_and_other_statuses() {
+        let proxy = spawn_connect_proxy(Some("dXNlcjpwYXNz")).await;
+
+        // 缺少认证
+        let error = HttpConnectBackend::new(proxy)
+            .connect("example.com", 443)
+            .await
+            .err()
+            .unwrap();
+        assert!(
+            matches!(error, HttpConnectError::ProxyAuthRequired),
+            "{}",
+            error
+        );
+
+        // 错误的认证同样是 407
+        let dialer = Dialer::HttpConnect(Arc::new(
+            HttpConnectBackend::new(proxy).with_auth("user".to_string(), "wrong".to_string()),
+        ));
+        let error = dialer.connect_tcp("example.com", 443).await.err().unwrap();
+        assert!(matches!(
+            error,
+            ConnectError::HttpConnect(HttpConnectError::ProxyAuthRequired)
+        ));
+
+        // 其它非 2xx 状态
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 256];
+            let _ = stream.read(&mut buf).await;
+            stream
+                .write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n")
+                .await
+                .unwrap();
+        });
+        let error = HttpConnectBackend::new(addr)
+            .connect("example.com", 443)
+            .await
+            .err()
+            .unwrap();
+        match error {
+            HttpConnectError::Rejected { status, reason } => {
+                assert_eq!(status, 403);
+                assert_eq!(reason, "Forbidden");
+            }
+            other => panic!("unexpected error: {}", other),
+        }
+    }
+
+    #[test]
+    fn test_http_connect_requires_addr() {
+        let mut config: Config = toml::from_str(
+            r#"
+[server]
+listen_https_addr = "127.0.0.1:443"
+
+[socks5]
+addr = "127.0.0.1:1080"
+
+[backend]
+type = "http_connect"
+"#,
+        )
+        .unwrap();
+        let upstreams = Arc::new(UpstreamSet::from_config(&config).unwrap());
+        assert!(Backend::from_config(&config, Arc::clone(&upstreams)).is_err());
+
+        config.backend.addr = Some("127.0.0.1:3128".parse().unwrap());
+        assert!(matches!(
+            Backend::from_config(&config, upstreams).unwrap(),
+            Backend::HttpConnect(_)
+        ));
+    }
+}
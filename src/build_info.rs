@@ -0,0 +1,101 @@
+//! 构建信息
+//!
+//! 版本号、git 提交、rustc 版本和启用的 cargo feature 在编译时由 `build.rs` 嵌入，
+//! 启动时写入日志，`sniproxy-ng --version --verbose` 输出，并与生效配置的摘要
+//! (见 [`Config::redacted_summary`]) 一起由指标端点的 `GET /status` 返回，
+//! 便于确认每个实例运行的是什么。
+
+use crate::config::Config;
+use serde::Serialize;
+use std::fmt;
+
+/// 编译进二进制的构建信息
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BuildInfo {
+    /// crate 版本
+    pub version: &'static str,
+    /// git 短提交号，没有 .git 也没有指定 `SNIPROXY_GIT_COMMIT` 时为 "unknown"
+    pub git_commit: &'static str,
+    /// `rustc --version` 的输出
+    pub rustc: &'static str,
+    /// 启用的 cargo feature
+    pub features: Vec<&'static str>,
+}
+
+impl BuildInfo {
+    /// 当前二进制的构建信息
+    pub fn current() -> Self {
+        let features = env!("SNIPROXY_FEATURES");
+        BuildInfo {
+            version: env!("CARGO_PKG_VERSION"),
+            git_commit: env!("SNIPROXY_GIT_COMMIT"),
+            rustc: env!("SNIPROXY_RUSTC_VERSION"),
+            features: features.split(',').filter(|f| !f.is_empty()).collect(),
+        }
+    }
+
+    /// `--version --verbose` 的多行输出
+    pub fn verbose(&self) -> String {
+        let features = if self.features.is_empty() {
+            "none".to_string()
+        } else {
+            self.features.join(", ")
+        };
+        format!(
+            "sniproxy-ng {}\ncommit: {}\nrustc: {}\nfeatures: {}\n",
+            self.version, self.git_commit, self.rustc, features
+        )
+    }
+}
+
+/// 单行形式，用于启动日志
+impl fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "sniproxy-ng {} (commit {}, {}",
+            self.version, self.git_commit, self.rustc
+        )?;
+        if !self.features.is_empty() {
+            write!(f, ", features: {}", self.features.join(","))?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// `GET /status` 的内容：构建信息和脱敏后的配置摘要
+pub fn status(config: &Config) -> serde_json::Value {
+    serde_json::json!({
+        "build": BuildInfo::current(),
+        "config": config.redacted_summary(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_the_build() {
+        let info = BuildInfo::current();
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert!(!info.git_commit.is_empty());
+        assert!(!info.rustc.is_empty());
+
+        let verbose = info.verbose();
+        assert!(verbose.starts_with(&format!("sniproxy-ng {}\n", info.version)));
+        assert!(verbose.contains(&format!("commit: {}\n", info.git_commit)));
+
+        let info = BuildInfo {
+            version: "1.2.3",
+            git_commit: "0123456789ab",
+            rustc: "rustc 1.80.0",
+            features: vec!["testing"],
+        };
+        assert_eq!(
+            info.to_string(),
+            "sniproxy-ng 1.2.3 (commit 0123456789ab, rustc 1.80.0, features: testing)"
+        );
+        assert!(info.verbose().ends_with("features: testing\n"));
+    }
+}
@@ -1,13 +1,76 @@
-use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use thiserror::Error;
+
+/// 配置文件无法读取、解析，或配置本身无效
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum ConfigError {
+    /// 无法读取配置文件
+    #[error("Failed to read config file {path}: {source}")]
+    Read {
+        path: String,
+        source: std::io::Error,
+    },
+
+    /// TOML 语法错误或字段不符合要求
+    #[error("Failed to parse config file {path}: {source}")]
+    Parse {
+        path: String,
+        source: toml::de::Error,
+    },
+
+    /// 无法序列化配置
+    #[error("Failed to serialize config: {0}")]
+    Serialize(#[from] toml::ser::Error),
+
+    /// 无法写入配置文件
+    #[error("Failed to write config file {path}: {source}")]
+    Write {
+        path: String,
+        source: std::io::Error,
+    },
+
+    /// 没有配置任何监听器
+    #[error("No listener configured. Please set listen_https_addr or listen_http_addr in config.")]
+    NoListener,
+
+    /// 启动监听器时对应的监听地址 (`field` 如 `server.listen_https_addr`) 未配置
+    #[error("{field} is not configured")]
+    MissingListenAddr { field: &'static str },
+
+    /// 某一部分的取值无效 (`section` 如 `socks5`、`backend`)
+    #[error("Invalid {section} configuration: {reason}")]
+    Invalid {
+        section: &'static str,
+        reason: String,
+    },
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub server: ServerConfig,
     pub socks5: Socks5Config,
     #[serde(default)]
+    pub backend: BackendConfig,
+    #[serde(default)]
+    pub pool: PoolConfig,
+    #[serde(default)]
     pub rules: RulesConfig,
+    #[serde(default)]
+    pub http: HttpConfig,
+    #[serde(default)]
+    pub tls: TlsConfig,
+    #[serde(default)]
+    pub quic: QuicConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub logging: LoggingConfig,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -16,7 +79,8 @@ pub struct ServerConfig {
     pub listen_https_addr: Option<SocketAddr>,
     /// HTTP 监听地址 (例如: "0.0.0.0:80")
     pub listen_http_addr: Option<SocketAddr>,
-    /// 日志级别: trace, debug, info, warn, error
+    /// 文件日志级别: trace, debug, info, warn, error，或 EnvFilter 语法 (例如
+    /// `info,sniproxy_ng::quic=debug`)；设置了 `RUST_LOG` 时以它为准
     #[serde(default = "default_log_level")]
     pub log_level: String,
     /// 日志格式: json, pretty
@@ -25,7 +89,7 @@ pub struct ServerConfig {
     /// 本地日志文件路径
     #[serde(default = "default_log_file")]
     pub log_file: String,
-    /// 控制台日志级别，默认只输出告警和错误，避免前台噪声
+    /// 控制台日志级别 (语法同 `log_level`)，默认只输出告警和错误，避免前台噪声
     #[serde(default = "default_console_log_level")]
     pub console_log_level: String,
     /// 最大同时处理的客户端连接数
@@ -34,26 +98,370 @@ pub struct ServerConfig {
     /// 转发阶段空闲超时(秒)
     #[serde(default = "default_transfer_idle_timeout")]
     pub transfer_idle_timeout: u64,
+    /// 统计日志的间隔(秒)，每个间隔输出一行汇总 (0 = 关闭)
+    #[serde(default = "default_stats_log_interval")]
+    pub stats_log_interval: u64,
     #[serde(default = "default_quic_mode")]
     pub quic_mode: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Socks5Config {
-    /// SOCKS5 代理地址
-    pub addr: SocketAddr,
+    /// SOCKS5 代理地址，"IP:PORT" 或 "域名:PORT"
+    pub addr: ProxyAddr,
     /// TCP 连接超时(秒)
     #[serde(default = "default_timeout")]
     pub timeout: u64,
     /// 连接池最大连接数
     #[serde(default = "default_max_connections")]
     pub max_connections: usize,
+    /// 连接池中每个连接最多被取出使用的次数，达到后关闭而不再放回 (0 = 不限)
+    #[serde(default)]
+    pub max_uses_per_connection: u64,
+    /// SOCKS5 CONNECT / UDP ASSOCIATE 可重试的失败 (代理不可达、超时等) 最多重试的次数
+    #[serde(default)]
+    pub connect_retries: u32,
+    /// 连接池满时等待连接名额的最长时间(秒)，超时直接断开客户端 (0 = 一直等待)
+    #[serde(default = "default_pool_acquire_timeout")]
+    pub pool_acquire_timeout: u64,
     /// 可选: SOCKS5 认证 - 用户名
     #[serde(default)]
     pub username: Option<String>,
     /// 可选: SOCKS5 认证 - 密码
     #[serde(default)]
     pub password: Option<String>,
+    /// QUIC 目标域名的解析方式
+    #[serde(default)]
+    pub resolve: ResolveMode,
+    /// 额外的 SOCKS5 上游 (`[socks5]` 本身是名为 "default" 的上游)
+    #[serde(default)]
+    pub upstreams: Vec<Socks5Upstream>,
+    /// 可选: 代理链，TCP 依次经过各跳 (非空时代替 `addr` 和认证信息，QUIC 不可用)
+    #[serde(default)]
+    pub chain: Vec<Socks5ChainHop>,
+    /// 上游使用的协议
+    #[serde(default)]
+    pub protocol: UpstreamProtocol,
+    /// 可选: 到上游的连接使用的源地址 (多出口主机的策略路由)
+    #[serde(default)]
+    pub bind_addr: Option<std::net::IpAddr>,
+    /// 可选: 到上游的连接绑定的网卡 (仅 Linux，SO_BINDTODEVICE)
+    #[serde(default)]
+    pub bind_device: Option<String>,
+    /// 多个 QUIC 会话共享 SOCKS5 UDP relay，而不是每个会话各自 UDP ASSOCIATE
+    #[serde(default)]
+    pub udp_relay_sharing: bool,
+    /// 共享模式下每个上游最多保持的 relay 数
+    #[serde(default = "default_udp_relay_pool_size")]
+    pub udp_relay_pool_size: usize,
+    /// 重组 SOCKS5 服务器分片的 UDP 数据报 (FRAG != 0)；否则丢弃分片
+    #[serde(default)]
+    pub udp_reassembly: bool,
+    /// UDP ASSOCIATE 控制连接的 TCP keepalive 间隔(秒)，0 表示不开启
+    #[serde(default = "default_udp_control_keepalive")]
+    pub udp_control_keepalive: u64,
+    /// 主动健康检查的间隔(秒)，0 表示只使用被动健康状态
+    #[serde(default = "default_health_interval")]
+    pub health_interval: u64,
+    /// 可选: 健康检查经上游 CONNECT 的目标 ("HOST:PORT")；不设置时只做 TCP 连接和 SOCKS5 问候
+    #[serde(default)]
+    pub health_canary: Option<ProxyAddr>,
+    /// 没有路由规则的连接在上游间的分散方式
+    #[serde(default)]
+    pub balance: BalanceStrategy,
+    /// `[socks5]` 本身的权重 (`weighted` 与 `least_connections` 使用)
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+/// SOCKS 上游的协议
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamProtocol {
+    /// SOCKS5 (TCP CONNECT 与 UDP ASSOCIATE)
+    #[default]
+    Socks5,
+    /// SOCKS4a，只有 TCP CONNECT；`username` 作为用户 ID，`password` 不使用
+    Socks4a,
+}
+
+/// 代理链中的一跳 (`socks5.chain`)
+///
+/// 可以只写地址 `"10.0.0.1:1080"`，也可以写成带认证的表
+/// `{ addr = "exit.example.net:1080", username = "u", password = "p" }`。
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "ChainHopRepr")]
+pub struct Socks5ChainHop {
+    /// 代理地址，"IP:PORT" 或 "域名:PORT" (第一跳之后的域名由上一跳解析)
+    pub addr: String,
+    /// 可选: 该跳的 SOCKS5 认证 - 用户名
+    #[serde(default)]
+    pub username: Option<String>,
+    /// 可选: 该跳的 SOCKS5 认证 - 密码
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ChainHopRepr {
+    Addr(String),
+    Full {
+        addr: String,
+        #[serde(default)]
+        username: Option<String>,
+        #[serde(default)]
+        password: Option<String>,
+    },
+}
+
+impl From<ChainHopRepr> for Socks5ChainHop {
+    fn from(repr: ChainHopRepr) -> Self {
+        match repr {
+            ChainHopRepr::Addr(addr) => Self {
+                addr,
+                username: None,
+                password: None,
+            },
+            ChainHopRepr::Full {
+                addr,
+                username,
+                password,
+            } => Self {
+                addr,
+                username,
+                password,
+            },
+        }
+    }
+}
+
+/// 额外的 SOCKS5 上游 (`[[socks5.upstreams]]`)，超时和解析方式沿用 `[socks5]`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Socks5Upstream {
+    /// 上游名称，供 `rules.routes` 引用
+    pub name: String,
+    /// SOCKS5 代理地址，"IP:PORT" 或 "域名:PORT"
+    pub addr: ProxyAddr,
+    /// 可选: SOCKS5 认证 - 用户名
+    #[serde(default)]
+    pub username: Option<String>,
+    /// 可选: SOCKS5 认证 - 密码
+    #[serde(default)]
+    pub password: Option<String>,
+    /// 该上游使用的协议
+    #[serde(default)]
+    pub protocol: UpstreamProtocol,
+    /// 权重 (`weighted` 与 `least_connections` 使用)
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+/// 上游代理地址 (`socks5.addr`、`socks5.upstreams[].addr`)
+///
+/// 域名形式在建连时解析，结果缓存一段时间，连接失败时重新解析
+/// (见 [`crate::outbound::ProxyResolver`])，用于 IP 会轮换的负载均衡器等。
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum ProxyAddr {
+    /// "IP:PORT"
+    Ip(SocketAddr),
+    /// "域名:PORT"
+    Host { host: String, port: u16 },
+}
+
+impl ProxyAddr {
+    /// 主机部分 (IP 或域名)
+    pub fn host(&self) -> String {
+        match self {
+            ProxyAddr::Ip(addr) => addr.ip().to_string(),
+            ProxyAddr::Host { host, .. } => host.clone(),
+        }
+    }
+
+    /// 端口
+    pub fn port(&self) -> u16 {
+        match self {
+            ProxyAddr::Ip(addr) => addr.port(),
+            ProxyAddr::Host { port, .. } => *port,
+        }
+    }
+}
+
+impl FromStr for ProxyAddr {
+    type Err = String;
+
+    fn from_str(value: &str) -> std::result::Result<Self, String> {
+        if let Ok(addr) = value.parse::<SocketAddr>() {
+            return Ok(ProxyAddr::Ip(addr));
+        }
+        let invalid = |reason: &str| {
+            format!(
+                "invalid proxy address '{}' (expected IP:PORT or HOST:PORT): {}",
+                value, reason
+            )
+        };
+        let (host, port) = value
+            .rsplit_once(':')
+            .ok_or_else(|| invalid("missing port"))?;
+        let port = port.parse().map_err(|_| invalid("invalid port"))?;
+        if !is_valid_hostname(host) {
+            return Err(invalid("invalid hostname"));
+        }
+        Ok(ProxyAddr::Host {
+            host: host.to_ascii_lowercase(),
+            port,
+        })
+    }
+}
+
+/// 按 RFC 1123 检查域名：点分的标签，每个标签 1-63 个字母、数字或连字符，不以连字符开头或结尾
+fn is_valid_hostname(host: &str) -> bool {
+    let host = host.strip_suffix('.').unwrap_or(host);
+    !host.is_empty()
+        && host.len() <= 253
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        })
+}
+
+impl TryFrom<String> for ProxyAddr {
+    type Error = String;
+
+    fn try_from(value: String) -> std::result::Result<Self, String> {
+        value.parse()
+    }
+}
+
+impl From<ProxyAddr> for String {
+    fn from(addr: ProxyAddr) -> Self {
+        addr.to_string()
+    }
+}
+
+impl From<SocketAddr> for ProxyAddr {
+    fn from(addr: SocketAddr) -> Self {
+        ProxyAddr::Ip(addr)
+    }
+}
+
+impl fmt::Display for ProxyAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxyAddr::Ip(addr) => addr.fmt(f),
+            ProxyAddr::Host { host, port } => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+/// TCP/HTTP 流量的后端 (`[backend]`)
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackendConfig {
+    /// 后端类型
+    #[serde(rename = "type", default)]
+    pub kind: BackendKind,
+    /// HTTP 代理地址 (`type = "http_connect"` 时必填)
+    #[serde(default)]
+    pub addr: Option<SocketAddr>,
+    /// 可选: HTTP 代理认证 (Proxy-Authorization: Basic) - 用户名
+    #[serde(default)]
+    pub username: Option<String>,
+    /// 可选: HTTP 代理认证 - 密码
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+/// TCP 连接池 (`[pool]`)
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PoolConfig {
+    /// 持续保持最少空闲连接数的目标，由连接池维护任务补足
+    #[serde(default)]
+    pub keep_warm: Vec<KeepWarmTarget>,
+}
+
+/// `pool.keep_warm` 的一项
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeepWarmTarget {
+    /// 目标 "域名:PORT" 或 "IP:PORT"
+    pub target: ProxyAddr,
+    /// 最少保持的空闲连接数
+    pub min_idle: usize,
+}
+
+impl KeepWarmTarget {
+    /// 连接池中的键，与 [`crate::socks5::ConnectionPool::get_connection`] 一致
+    pub fn key(&self) -> String {
+        format!("{}:{}", self.target.host(), self.target.port())
+    }
+}
+
+/// 后端类型
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackendKind {
+    /// `[socks5]` 及 `[[socks5.upstreams]]`
+    #[default]
+    Socks5,
+    /// HTTP 正向代理的 CONNECT 方法，只能承载 TCP，QUIC 不可用
+    HttpConnect,
+}
+
+/// 上游间的负载分散方式 (`socks5.balance`)
+///
+/// 都只在健康的上游间进行；TCP 按在途连接计数，QUIC 按在途会话计数。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BalanceStrategy {
+    /// 按键 (QUIC 为 DCID，TCP 为 SNI) 做 rendezvous 哈希，同一键固定到同一上游
+    #[default]
+    Hash,
+    /// 按配置顺序使用第一个健康的上游
+    Failover,
+    /// 依次轮流
+    RoundRobin,
+    /// 在途连接数与权重之比最小的上游
+    LeastConnections,
+    /// 按权重比例轮流
+    Weighted,
+}
+
+/// QUIC 目标域名的解析方式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResolveMode {
+    /// 把域名原样交给 SOCKS5 服务器解析 (UDP 包头使用域名地址类型)
+    #[default]
+    Remote,
+    /// 本地解析后按 IP 转发，用于不接受域名目标的 SOCKS5 服务器
+    Local,
+}
+
+/// SOCKS5 UDP relay 的 TCP 控制连接断开后的处理方式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RelayFailureMode {
+    /// 立即结束会话，客户端重连时重新建立
+    #[default]
+    Teardown,
+    /// 重新 UDP ASSOCIATE，沿用原目标继续转发
+    Reconnect,
+}
+
+/// 使用 ECH 但外层 ClientHello 没有 SNI 时的处理方式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EchPolicy {
+    /// 与普通无 SNI 的 Initial 一样拒绝
+    #[default]
+    Reject,
+    /// 转发到 `quic.ech_default_target` (仍需通过白名单)
+    ForwardDefault,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -61,6 +469,373 @@ pub struct RulesConfig {
     /// 白名单域名模式数组，空数组表示允许所有域名
     #[serde(default)]
     pub allow: Vec<String>,
+    /// 按域名指定 SOCKS5 上游，按顺序取第一个匹配的规则；未匹配的域名在健康的上游间分散
+    #[serde(default)]
+    pub routes: Vec<RouteRule>,
+    /// 使用 ECH (含 GREASE ECH) 的 TCP 连接的处理方式
+    #[serde(default)]
+    pub ech_policy: EchTrafficPolicy,
+    /// 拒绝 IP 地址形式的 SNI (RFC 6066 不允许，通常来自扫描器)
+    #[serde(default)]
+    pub reject_ip_sni: bool,
+    /// 以 U-label (含非 ASCII 字符) 发送的 SNI 的处理方式
+    #[serde(default)]
+    pub unicode_sni: UnicodeSniPolicy,
+}
+
+/// 以 U-label 发送的 SNI 的处理方式 (`rules.unicode_sni`)
+///
+/// RFC 6066 要求国际化域名以 `xn--` 形式的 A-label 发送，个别客户端直接发送 UTF-8。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnicodeSniPolicy {
+    /// 与其它不合法的 SNI 一样拒绝
+    #[default]
+    Reject,
+    /// 按 IDNA 转换成 A-label 后做白名单检查和路由
+    Convert,
+}
+
+/// 使用 ECH 的 TCP 连接的处理方式 (`rules.ech_policy`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EchTrafficPolicy {
+    /// 与其它连接一样按外层 SNI 路由
+    #[default]
+    Allow,
+    /// 断开连接
+    Reject,
+    /// 不看 `rules.routes`，固定经 `[socks5]` 本身 (名为 "default" 的上游)
+    DefaultBackend,
+}
+
+/// 把匹配的域名固定到某个 SOCKS5 上游 (`[[rules.routes]]`)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RouteRule {
+    /// 域名模式，语法与 `rules.allow` 相同
+    pub pattern: String,
+    /// 上游名称 ("default" 或 `socks5.upstreams` 中的 name)
+    pub upstream: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HttpConfig {
+    /// CONNECT 允许的目标端口，默认只允许 443
+    #[serde(default = "default_connect_ports")]
+    pub connect_ports: Vec<u16>,
+    /// CONNECT 隧道建立后校验 TLS SNI 是否与 CONNECT 目标一致
+    #[serde(default)]
+    pub connect_verify_tls: bool,
+    /// CONNECT 请求头 (到空行为止) 的最大字节数，超过时回复 431
+    #[serde(default = "default_connect_max_header_bytes")]
+    pub connect_max_header_bytes: usize,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            connect_ports: default_connect_ports(),
+            connect_verify_tls: false,
+            connect_max_header_bytes: default_connect_max_header_bytes(),
+        }
+    }
+}
+
+/// TCP 入口对 ClientHello 版本的检查 (`[tls]`)
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TlsConfig {
+    /// record 版本异常或客户端最高版本低于 `min_accept_version` 时输出告警
+    #[serde(default)]
+    pub log_version_anomalies: bool,
+    /// 客户端应支持的最低版本，按 supported_versions (没有时按 legacy_version) 中的最高版本判断
+    #[serde(default)]
+    pub min_accept_version: TlsVersion,
+    /// 拒绝最高版本低于 `min_accept_version` 的客户端
+    #[serde(default)]
+    pub reject_below_min_version: bool,
+}
+
+/// Prometheus 指标 (`[metrics]`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetricsConfig {
+    /// `/metrics`、`/healthz`、`/readyz` 和 `/domains/top` 的监听地址 (例如: "127.0.0.1:9090")，
+    /// 不设置时不提供这些端点
+    #[serde(default)]
+    pub listen_addr: Option<SocketAddr>,
+    /// tokio 运行时指标的采样间隔 (秒)，0 表示不采样；见 [`crate::runtime`]
+    #[serde(default = "default_runtime_sample_interval")]
+    pub runtime_sample_interval: u64,
+    /// 可选: statsd/DogStatsD 的 UDP 地址 (例如: "127.0.0.1:8125")，设置后定期推送指标，
+    /// 可与 `listen_addr` 同时使用；见 [`crate::statsd`]
+    #[serde(default)]
+    pub statsd_addr: Option<SocketAddr>,
+    /// statsd 指标名的前缀
+    #[serde(default = "default_statsd_prefix")]
+    pub statsd_prefix: String,
+    /// 附加到每个 statsd 指标的 DogStatsD 标签 (例如: ["env:prod", "region:hk"])
+    #[serde(default)]
+    pub statsd_tags: Vec<String>,
+    /// statsd 的推送间隔(秒)
+    #[serde(default = "default_statsd_flush_interval")]
+    pub statsd_flush_interval: u64,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: None,
+            runtime_sample_interval: default_runtime_sample_interval(),
+            statsd_addr: None,
+            statsd_prefix: default_statsd_prefix(),
+            statsd_tags: Vec::new(),
+            statsd_flush_interval: default_statsd_flush_interval(),
+        }
+    }
+}
+
+/// 日志输出 (`[logging]`)；级别和主日志文件见 `[server]`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LoggingConfig {
+    /// 单独的访问日志 (`[logging.access]`)，不设置时访问记录写入主日志
+    #[serde(default)]
+    pub access: Option<AccessLogConfig>,
+}
+
+/// 访问日志 (`[logging.access]`)，见 [`crate::access`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccessLogConfig {
+    /// 文件路径 (例如: "/var/log/sniproxy/access.log")
+    pub file: String,
+    /// 行格式: combined (近似 nginx combined) 或 json
+    #[serde(default)]
+    pub format: AccessLogFormat,
+    /// 轮转周期: never, minutely, hourly, daily；轮转后的文件名带日期后缀
+    #[serde(default)]
+    pub rotation: LogRotation,
+    /// 轮转时最多保留的文件数，不设置时不删除旧文件
+    #[serde(default)]
+    pub max_files: Option<usize>,
+}
+
+/// 访问日志的行格式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLogFormat {
+    #[default]
+    Combined,
+    Json,
+}
+
+/// 日志文件的轮转周期
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogRotation {
+    #[default]
+    Never,
+    Minutely,
+    Hourly,
+    Daily,
+}
+
+/// TLS 协议版本 (`tls.min_accept_version`)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum TlsVersion {
+    #[serde(rename = "1.0")]
+    Tls10,
+    #[serde(rename = "1.1")]
+    Tls11,
+    #[default]
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+impl TlsVersion {
+    /// 线上的版本号 (0x0301 - 0x0304)
+    pub fn wire(self) -> u16 {
+        match self {
+            TlsVersion::Tls10 => 0x0301,
+            TlsVersion::Tls11 => 0x0302,
+            TlsVersion::Tls12 => 0x0303,
+            TlsVersion::Tls13 => 0x0304,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuicConfig {
+    /// 在 trace 日志中输出 Initial 密钥、IV、nonce 等密钥材料，仅用于本地排查
+    #[serde(default)]
+    pub debug_crypto: bool,
+    /// client 方向解密失败后再用 server 方向的 Initial 密钥重试
+    ///
+    /// 监听端只会收到客户端的 Initial，默认关闭以免无法解密的包付出两倍代价。
+    #[serde(default)]
+    pub try_server_role: bool,
+    /// SNI 提取失败的 Initial 转储目录 (`sniproxy-ng decode <file>` 离线分析)；None 表示不转储
+    #[serde(default)]
+    pub debug_dump_dir: Option<PathBuf>,
+    /// 每分钟最多写入的转储数
+    #[serde(default = "default_quic_debug_dump_per_minute")]
+    pub debug_dump_per_minute: u32,
+    /// 转储目录总大小上限 (字节)，达到后不再写入
+    #[serde(default = "default_quic_debug_dump_max_bytes")]
+    pub debug_dump_max_bytes: u64,
+    /// 最大 QUIC 会话数 (含正在建立的会话)
+    #[serde(default = "default_quic_max_sessions")]
+    pub max_sessions: usize,
+    /// 每个客户端 IP 的最大 QUIC 会话数
+    #[serde(default = "default_quic_max_sessions_per_ip")]
+    pub max_sessions_per_ip: usize,
+    /// 会话数达到上限时淘汰最久未活动的会话，而不是拒绝新连接
+    #[serde(default = "default_true")]
+    pub evict_idle_on_full: bool,
+    /// UDP 收包 worker 数；大于 1 时每个 worker 用 SO_REUSEPORT 绑定独立的 socket
+    #[serde(default = "default_quic_udp_workers")]
+    pub udp_workers: usize,
+    /// 每次唤醒最多读取的数据报数 (Linux 上为单次 recvmmsg 的批量)
+    #[serde(default = "default_quic_recv_batch_size")]
+    pub recv_batch_size: usize,
+    /// 启用 UDP GSO/GRO (仅 Linux)：发往同一客户端的回包合并为一次 sendmsg，
+    /// 监听 socket 接收内核合并的读取；内核不支持时自动退回逐包收发
+    #[serde(default)]
+    pub enable_gso: bool,
+    /// 本地解析 (socks5.resolve = "local") 的 QUIC 目标缓存时间(秒)
+    #[serde(default = "default_quic_dns_cache_ttl")]
+    pub dns_cache_ttl: u64,
+    /// 单个 UDP 数据报的最大字节数 (1200..=65535)，决定收包和 relay 缓冲区大小
+    #[serde(default = "default_quic_max_datagram_size")]
+    pub max_datagram_size: usize,
+    /// 每个来源 IP (IPv6 按 /64 计) 每秒允许进入 SNI 提取的新 Initial 数 (0 表示不限)
+    #[serde(default = "default_quic_initial_rate")]
+    pub initial_rate: f64,
+    /// 每个来源 IP 允许的 Initial 突发数
+    #[serde(default = "default_quic_initial_burst")]
+    pub initial_burst: u32,
+    /// SOCKS5 UDP relay 的控制连接断开后结束会话还是重新关联
+    #[serde(default)]
+    pub relay_failure: RelayFailureMode,
+    /// 发往 relay 的包超过该时间(秒)仍收不到任何回包时判定 relay 已失效，
+    /// 按 `relay_failure` 处理；0 表示不检测
+    #[serde(default = "default_quic_relay_probe_interval")]
+    pub relay_probe_interval: u64,
+    /// 建立会话时 UDP ASSOCIATE 的最大尝试次数 (指数退避，总等待不超过暂存包的有效期)
+    #[serde(default = "default_quic_associate_attempts")]
+    pub associate_attempts: u32,
+    /// 同一上游连续多少次 ASSOCIATE 失败后打开断路器，新会话直接失败 (0 表示不使用)
+    #[serde(default = "default_quic_associate_breaker_threshold")]
+    pub associate_breaker_threshold: u32,
+    /// 断路器打开后多久放行一个探测 (秒)
+    #[serde(default = "default_quic_associate_breaker_cooldown")]
+    pub associate_breaker_cooldown: u64,
+    /// 每个会话等待发往 relay 的包队列长度，队列满时丢弃新到的包
+    #[serde(default = "default_quic_session_queue_capacity")]
+    pub session_queue_capacity: usize,
+    /// 收包处理结果 (转发/暂存/拒绝等) 汇总日志的间隔(秒)，0 表示不输出
+    #[serde(default = "default_quic_stats_interval")]
+    pub stats_interval: u64,
+    /// 使用 ECH 但外层没有 SNI 的 Initial 是拒绝还是转发到默认目标
+    #[serde(default)]
+    pub ech_without_sni: EchPolicy,
+    /// `ech_without_sni = "forward_default"` 时使用的目标域名
+    #[serde(default)]
+    pub ech_default_target: Option<String>,
+    /// 只接受 ALPN 与该列表有交集的会话 (例如 ["h3"])；空列表表示不检查
+    #[serde(default)]
+    pub require_alpn: Vec<String>,
+    /// 配置了 `require_alpn` 时，是否接受没有 ALPN 扩展的 ClientHello
+    #[serde(default = "default_true")]
+    pub allow_missing_alpn: bool,
+    /// 按目标域名覆盖会话空闲超时，按顺序取第一个匹配的模式
+    #[serde(default)]
+    pub idle_overrides: Vec<IdleOverride>,
+}
+
+/// 按目标域名覆盖 QUIC 会话的空闲超时 (`[[quic.idle_overrides]]`)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdleOverride {
+    /// 域名模式，语法与 `rules.allow` 相同
+    pub pattern: String,
+    /// 空闲超时，例如 "30s"、"10m"、"1h"；不带单位时按秒计
+    #[serde(with = "duration_str")]
+    pub idle_timeout: Duration,
+}
+
+/// 解析 "30s" / "10m" / "1h" 形式的时长，不带单位时按秒计
+pub fn parse_duration(value: &str) -> std::result::Result<Duration, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration '{}'", value))?;
+    let multiplier = match unit.trim() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        _ => {
+            return Err(format!(
+                "invalid duration unit in '{}' (expected s, m or h)",
+                value
+            ))
+        }
+    };
+    number
+        .checked_mul(multiplier)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("duration '{}' is too large", value))
+}
+
+/// 以 "<秒数>s" 字符串读写 Duration
+mod duration_str {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(value: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{}s", value.as_secs()))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        super::parse_duration(&value).map_err(serde::de::Error::custom)
+    }
+}
+
+impl Default for QuicConfig {
+    fn default() -> Self {
+        Self {
+            debug_crypto: false,
+            try_server_role: false,
+            debug_dump_dir: None,
+            debug_dump_per_minute: default_quic_debug_dump_per_minute(),
+            debug_dump_max_bytes: default_quic_debug_dump_max_bytes(),
+            max_sessions: default_quic_max_sessions(),
+            max_sessions_per_ip: default_quic_max_sessions_per_ip(),
+            evict_idle_on_full: true,
+            udp_workers: default_quic_udp_workers(),
+            recv_batch_size: default_quic_recv_batch_size(),
+            enable_gso: false,
+            dns_cache_ttl: default_quic_dns_cache_ttl(),
+            max_datagram_size: default_quic_max_datagram_size(),
+            initial_rate: default_quic_initial_rate(),
+            initial_burst: default_quic_initial_burst(),
+            relay_failure: RelayFailureMode::default(),
+            relay_probe_interval: default_quic_relay_probe_interval(),
+            associate_attempts: default_quic_associate_attempts(),
+            associate_breaker_threshold: default_quic_associate_breaker_threshold(),
+            associate_breaker_cooldown: default_quic_associate_breaker_cooldown(),
+            session_queue_capacity: default_quic_session_queue_capacity(),
+            stats_interval: default_quic_stats_interval(),
+            ech_without_sni: EchPolicy::default(),
+            ech_default_target: None,
+            require_alpn: Vec::new(),
+            allow_missing_alpn: true,
+            idle_overrides: Vec::new(),
+        }
+    }
 }
 
 // 默认值函数
@@ -88,10 +863,102 @@ fn default_transfer_idle_timeout() -> u64 {
     300
 }
 
+fn default_stats_log_interval() -> u64 {
+    60
+}
+
+fn default_runtime_sample_interval() -> u64 {
+    5
+}
+
+fn default_statsd_prefix() -> String {
+    "sniproxy".to_string()
+}
+
+fn default_statsd_flush_interval() -> u64 {
+    10
+}
+
 fn default_quic_mode() -> String {
     "off".to_string()
 }
 
+fn default_quic_max_sessions() -> usize {
+    4096
+}
+
+fn default_quic_max_sessions_per_ip() -> usize {
+    64
+}
+
+fn default_quic_udp_workers() -> usize {
+    1
+}
+
+fn default_quic_recv_batch_size() -> usize {
+    32
+}
+
+fn default_quic_dns_cache_ttl() -> u64 {
+    60
+}
+
+fn default_quic_max_datagram_size() -> usize {
+    1500
+}
+
+fn default_quic_initial_rate() -> f64 {
+    100.0
+}
+
+fn default_quic_initial_burst() -> u32 {
+    500
+}
+
+fn default_quic_debug_dump_per_minute() -> u32 {
+    10
+}
+
+fn default_quic_debug_dump_max_bytes() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_quic_session_queue_capacity() -> usize {
+    1024
+}
+
+fn default_quic_stats_interval() -> u64 {
+    60
+}
+
+fn default_quic_relay_probe_interval() -> u64 {
+    30
+}
+
+fn default_quic_associate_attempts() -> u32 {
+    3
+}
+
+fn default_quic_associate_breaker_threshold() -> u32 {
+    5
+}
+
+fn default_quic_associate_breaker_cooldown() -> u64 {
+    5
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_connect_ports() -> Vec<u16> {
+    vec![443]
+}
+
+fn default_connect_max_header_bytes() -> usize {
+    16 * 1024
+}
+
 fn default_timeout() -> u64 {
     30
 }
@@ -100,28 +967,127 @@ fn default_max_connections() -> usize {
     100
 }
 
+fn default_pool_acquire_timeout() -> u64 {
+    10
+}
+
+fn default_udp_control_keepalive() -> u64 {
+    30
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+fn default_health_interval() -> u64 {
+    15
+}
+
+fn default_udp_relay_pool_size() -> usize {
+    4
+}
+
 impl Config {
     /// 从文件加载配置
-    pub fn load(path: &str) -> Result<Self> {
-        let content = std::fs::read_to_string(path)
-            .with_context(|| format!("Failed to read config file: {}", path))?;
+    pub fn load(path: &str) -> crate::Result<Self> {
+        let content = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_string(),
+            source,
+        })?;
 
-        let config: Config = toml::from_str(&content)
-            .with_context(|| format!("Failed to parse config file: {}", path))?;
+        let config: Config = toml::from_str(&content).map_err(|source| ConfigError::Parse {
+            path: path.to_string(),
+            source,
+        })?;
 
         Ok(config)
     }
 
     /// 保存配置到文件
-    #[allow(dead_code)]
-    pub fn save(&self, path: &str) -> Result<()> {
-        let content = toml::to_string_pretty(self).context("Failed to serialize config")?;
+    pub fn save(&self, path: &str) -> crate::Result<()> {
+        let content = toml::to_string_pretty(self).map_err(ConfigError::from)?;
 
-        std::fs::write(path, content)
-            .with_context(|| format!("Failed to write config file: {}", path))?;
+        std::fs::write(path, content).map_err(|source| ConfigError::Write {
+            path: path.to_string(),
+            source,
+        })?;
 
         Ok(())
     }
+
+    /// 生效配置的摘要，用于启动日志和 `GET /status` (见 [`crate::build_info`])
+    ///
+    /// 只列出监听地址、规则数量和后端地址等排查需要的字段；用户名、密码等凭据一律
+    /// 只给出是否配置 (`"auth": true`)，不会出现在摘要中。
+    pub fn redacted_summary(&self) -> serde_json::Value {
+        let auth = |username: &Option<String>, password: &Option<String>| {
+            username.is_some() || password.is_some()
+        };
+        let socks5 = &self.socks5;
+        let backend = match self.backend.kind {
+            BackendKind::Socks5 => serde_json::json!({
+                "type": self.backend.kind,
+                "addr": socks5.addr.to_string(),
+                "protocol": socks5.protocol,
+                "auth": auth(&socks5.username, &socks5.password),
+                "chain": socks5
+                    .chain
+                    .iter()
+                    .map(|hop| serde_json::json!({
+                        "addr": hop.addr,
+                        "auth": auth(&hop.username, &hop.password),
+                    }))
+                    .collect::<Vec<_>>(),
+                "upstreams": socks5
+                    .upstreams
+                    .iter()
+                    .map(|upstream| serde_json::json!({
+                        "name": upstream.name,
+                        "addr": upstream.addr.to_string(),
+                        "protocol": upstream.protocol,
+                        "weight": upstream.weight,
+                        "auth": auth(&upstream.username, &upstream.password),
+                    }))
+                    .collect::<Vec<_>>(),
+                "balance": socks5.balance,
+                "udp_relay_sharing": socks5.udp_relay_sharing,
+            }),
+            BackendKind::HttpConnect => serde_json::json!({
+                "type": self.backend.kind,
+                "addr": self.backend.addr,
+                "auth": auth(&self.backend.username, &self.backend.password),
+            }),
+        };
+        serde_json::json!({
+            "listen": {
+                "https": self.server.listen_https_addr,
+                "http": self.server.listen_http_addr,
+                "metrics": self.metrics.listen_addr,
+                "statsd": self.metrics.statsd_addr,
+                "quic_mode": self.server.quic_mode,
+            },
+            "backend": backend,
+            "rules": {
+                "allow": self.rules.allow.len(),
+                "routes": self.rules.routes.len(),
+                "ech_policy": self.rules.ech_policy,
+                "reject_ip_sni": self.rules.reject_ip_sni,
+                "unicode_sni": self.rules.unicode_sni,
+            },
+            "limits": {
+                "max_client_connections": self.server.max_client_connections,
+                "max_connections": socks5.max_connections,
+                "quic_max_sessions": self.quic.max_sessions,
+                "quic_max_sessions_per_ip": self.quic.max_sessions_per_ip,
+            },
+            "http": {
+                "connect_ports": self.http.connect_ports,
+            },
+            "pool": {
+                "keep_warm": self.pool.keep_warm.len(),
+            },
+        })
+    }
 }
 
 #[cfg(test)]
@@ -146,9 +1112,38 @@ transfer_idle_timeout = 300
 addr = "127.0.0.1:1080"
 timeout = 30
 max_connections = 100
+max_uses_per_connection = 50
+pool_acquire_timeout = 3
+resolve = "local"
 
 [rules]
 allow = ["*.google.com"]
+
+[http]
+connect_ports = [443, 8443]
+connect_verify_tls = true
+connect_max_header_bytes = 8192
+
+[quic]
+debug_crypto = true
+debug_dump_dir = "/var/tmp/sniproxy"
+debug_dump_per_minute = 5
+max_sessions = 100
+max_sessions_per_ip = 4
+evict_idle_on_full = false
+udp_workers = 4
+recv_batch_size = 64
+enable_gso = true
+max_datagram_size = 9000
+initial_rate = 20
+initial_burst = 40
+relay_failure = "reconnect"
+relay_probe_interval = 0
+associate_attempts = 1
+associate_breaker_threshold = 0
+session_queue_capacity = 64
+stats_interval = 0
+dns_cache_ttl = 300
 "#;
 
         let config: Config = toml::from_str(toml_str).unwrap();
@@ -162,6 +1157,34 @@ allow = ["*.google.com"]
         assert_eq!(config.server.transfer_idle_timeout, 300);
         assert_eq!(config.server.quic_mode, "off");
         assert_eq!(config.rules.allow.len(), 1);
+        assert_eq!(config.http.connect_ports, vec![443, 8443]);
+        assert!(config.http.connect_verify_tls);
+        assert_eq!(config.http.connect_max_header_bytes, 8192);
+        assert!(config.quic.debug_crypto);
+        assert_eq!(
+            config.quic.debug_dump_dir.as_deref(),
+            Some(std::path::Path::new("/var/tmp/sniproxy"))
+        );
+        assert_eq!(config.quic.debug_dump_per_minute, 5);
+        assert_eq!(config.quic.max_sessions, 100);
+        assert_eq!(config.quic.max_sessions_per_ip, 4);
+        assert!(!config.quic.evict_idle_on_full);
+        assert_eq!(config.socks5.resolve, ResolveMode::Local);
+        assert_eq!(config.socks5.max_uses_per_connection, 50);
+        assert_eq!(config.socks5.pool_acquire_timeout, 3);
+        assert_eq!(config.quic.udp_workers, 4);
+        assert_eq!(config.quic.recv_batch_size, 64);
+        assert!(config.quic.enable_gso);
+        assert_eq!(config.quic.max_datagram_size, 9000);
+        assert_eq!(config.quic.initial_rate, 20.0);
+        assert_eq!(config.quic.initial_burst, 40);
+        assert_eq!(config.quic.relay_failure, RelayFailureMode::Reconnect);
+        assert_eq!(config.quic.relay_probe_interval, 0);
+        assert_eq!(config.quic.associate_attempts, 1);
+        assert_eq!(config.quic.associate_breaker_threshold, 0);
+        assert_eq!(config.quic.session_queue_capacity, 64);
+        assert_eq!(config.quic.stats_interval, 0);
+        assert_eq!(config.quic.dns_cache_ttl, 300);
     }
 
     #[test]
@@ -185,6 +1208,38 @@ addr = "127.0.0.1:1080"
         assert_eq!(config.server.max_client_connections, 512);
         assert_eq!(config.server.transfer_idle_timeout, 300);
         assert_eq!(config.server.quic_mode, "off");
+        assert_eq!(config.http.connect_ports, vec![443]);
+        assert!(!config.http.connect_verify_tls);
+        assert_eq!(config.http.connect_max_header_bytes, 16384);
+        assert_eq!(config.socks5.resolve, ResolveMode::Remote);
+        assert!(!config.quic.debug_crypto);
+        assert!(config.quic.debug_dump_dir.is_none());
+        assert_eq!(config.quic.debug_dump_max_bytes, 64 * 1024 * 1024);
+        assert_eq!(config.quic.max_sessions, 4096);
+        assert_eq!(config.quic.max_sessions_per_ip, 64);
+        assert!(config.quic.evict_idle_on_full);
+        assert_eq!(config.quic.udp_workers, 1);
+        assert_eq!(config.quic.recv_batch_size, 32);
+        assert!(!config.quic.enable_gso);
+        assert_eq!(config.quic.max_datagram_size, 1500);
+        assert_eq!(config.quic.initial_rate, 100.0);
+        assert_eq!(config.quic.initial_burst, 500);
+        assert_eq!(config.quic.relay_failure, RelayFailureMode::Teardown);
+        assert_eq!(config.quic.relay_probe_interval, 30);
+        assert_eq!(config.socks5.udp_control_keepalive, 30);
+        assert_eq!(config.socks5.health_interval, 15);
+        assert!(config.socks5.health_canary.is_none());
+        assert_eq!(config.socks5.balance, BalanceStrategy::Hash);
+        assert_eq!(config.socks5.weight, 1);
+        assert_eq!(config.socks5.connect_retries, 0);
+        assert!(!config.socks5.udp_reassembly);
+        assert_eq!(config.quic.associate_attempts, 3);
+        assert_eq!(config.quic.associate_breaker_threshold, 5);
+        assert_eq!(config.quic.associate_breaker_cooldown, 5);
+        assert_eq!(config.quic.session_queue_capacity, 1024);
+        assert_eq!(config.quic.stats_interval, 60);
+        assert_eq!(config.quic.dns_cache_ttl, 60);
+        assert!(config.quic.idle_overrides.is_empty());
     }
 
     #[test]
@@ -201,6 +1256,210 @@ addr = "127.0.0.1:1080"
         let config: Config = toml::from_str(toml_str).unwrap();
         assert!(config.server.listen_https_addr.is_none());
         assert_eq!(config.server.listen_http_addr.unwrap().port(), 80);
+        assert_eq!(config.backend.kind, BackendKind::Socks5);
+    }
+
+    #[test]
+    fn test_http_connect_backend() {
+        let toml_str = r#"
+[server]
+listen_http_addr = "0.0.0.0:80"
+
+[socks5]
+addr = "127.0.0.1:1080"
+
+[backend]
+type = "http_connect"
+addr = "10.0.0.2:3128"
+username = "user"
+password = "pass"
+"#;
+
+        let config: Config = toml::from_str(toml_str).unwrap();
+        assert_eq!(config.backend.kind, BackendKind::HttpConnect);
+        assert_eq!(config.backend.addr.unwrap().port(), 3128);
+        assert_eq!(config.backend.username.as_deref(), Some("user"));
+    }
+
+    #[test]
+    fn test_pool_keep_warm() {
+        let toml_str = r#"
+[server]
+listen_https_addr = "0.0.0.0:443"
+
+[socks5]
+addr = "127.0.0.1:1080"
+"#;
+        let config: Config = toml::from_str(toml_str).unwrap();
+        assert!(config.pool.keep_warm.is_empty());
+
+        let config: Config = toml::from_str(&format!(
+            "{}\n[pool]\nkeep_warm = [{{ target = \"api.example.com:443\", min_idle = 3 }}]\n",
+            toml_str
+        ))
+        .unwrap();
+        let warm = &config.pool.keep_warm[0];
+        assert_eq!(warm.key(), "api.example.com:443");
+        assert_eq!(warm.min_idle, 3);
+    }
+
+    #[test]
+    fn test_socks5_chain() {
+        let toml_str = r#"
+[server]
+listen_https_addr = "0.0.0.0:443"
+
+[socks5]
+addr = "127.0.0.1:1080"
+chain = [
+    "10.0.0.1:1080",
+    { addr = "exit.example.net:1080", username = "user", password = "pass" },
+]
+"#;
+
+        let config: Config = toml::from_str(toml_str).unwrap();
+        let chain = &config.socks5.chain;
+        assert_eq!(chain.len(), 2);
+        assert_eq!(chain[0].addr, "10.0.0.1:1080");
+        assert!(chain[0].username.is_none());
+        assert_eq!(chain[1].addr, "exit.example.net:1080");
+        assert_eq!(chain[1].password.as_deref(), Some("pass"));
+    }
+
+    #[test]
+    fn test_socks5_proxy_addr() {
+        let parse = |addr: &str| {
+            toml::from_str::<Config>(&format!(
+                "[server]\nlisten_https_addr = \"0.0.0.0:443\"\n\n[socks5]\naddr = \"{}\"\n",
+                addr
+            ))
+            .map(|config| config.socks5.addr)
+        };
+
+        assert_eq!(
+            parse("127.0.0.1:1080").unwrap(),
+            ProxyAddr::Ip("127.0.0.1:1080".parse().unwrap())
+        );
+        assert_eq!(
+            parse("[::1]:1080").unwrap(),
+            ProxyAddr::Ip("[::1]:1080".parse().unwrap())
+        );
+        let host = parse("Proxy-LB.example.net:1080").unwrap();
+        assert_eq!(
+            host,
+            ProxyAddr::Host {
+                host: "proxy-lb.example.net".to_string(),
+                port: 1080
+            }
+        );
+        assert_eq!(host.to_string(), "proxy-lb.example.net:1080");
+        assert_eq!(host.port(), 1080);
+
+        for invalid in [
+            "proxy_lb.example.net:1080",
+            "-proxy.example.net:1080",
+            "proxy.example.net",
+            "proxy.example.net:65536",
+            "::1:1080",
+            ":1080",
+        ] {
+            let error = parse(invalid).unwrap_err().to_string();
+            assert!(error.contains("invalid proxy address"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn test_socks5_bind() {
+        let toml_str = r#"
+[server]
+listen_https_addr = "0.0.0.0:443"
+
+[socks5]
+addr = "127.0.0.1:1080"
+bind_addr = "192.0.2.10"
+bind_device = "eth1"
+"#;
+
+        let config: Config = toml::from_str(toml_str).unwrap();
+        assert_eq!(config.socks5.bind_addr, Some("192.0.2.10".parse().unwrap()));
+        assert_eq!(config.socks5.bind_device.as_deref(), Some("eth1"));
+    }
+
+    #[test]
+    fn test_socks5_udp_relay_sharing() {
+        let toml_str = r#"
+[server]
+listen_https_addr = "0.0.0.0:443"
+
+[socks5]
+addr = "127.0.0.1:1080"
+"#;
+        let config: Config = toml::from_str(toml_str).unwrap();
+        assert!(!config.socks5.udp_relay_sharing);
+        assert_eq!(config.socks5.udp_relay_pool_size, 4);
+
+        let config: Config = toml::from_str(&format!(
+            "{}udp_relay_sharing = true\nudp_relay_pool_size = 2\n",
+            toml_str
+        ))
+        .unwrap();
+        assert!(config.socks5.udp_relay_sharing);
+        assert_eq!(config.socks5.udp_relay_pool_size, 2);
+    }
+
+    #[test]
+    fn test_tls_version_policy() {
+        let toml_str = r#"
+[server]
+listen_https_addr = "0.0.0.0:443"
+
+[socks5]
+addr = "127.0.0.1:1080"
+"#;
+
+        let config: Config = toml::from_str(toml_str).unwrap();
+        assert!(!config.tls.log_version_anomalies);
+        assert_eq!(config.tls.min_accept_version, TlsVersion::Tls12);
+        assert!(!config.tls.reject_below_min_version);
+
+        let config: Config = toml::from_str(&format!(
+            "{}\n[tls]\nlog_version_anomalies = true\nmin_accept_version = \"1.3\"\nreject_below_min_version = true\n",
+            toml_str
+        ))
+        .unwrap();
+        assert!(config.tls.log_version_anomalies);
+        assert_eq!(config.tls.min_accept_version.wire(), 0x0304);
+        assert!(config.tls.reject_below_min_version);
+
+        assert!(toml::from_str::<Config>(&format!(
+            "{}\n[tls]\nmin_accept_version = \"1.4\"\n",
+            toml_str
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn test_metrics_config() {
+        let toml_str = r#"
+[server]
+listen_https_addr = "0.0.0.0:443"
+
+[socks5]
+addr = "127.0.0.1:1080"
+"#;
+
+        let config: Config = toml::from_str(toml_str).unwrap();
+        assert!(config.metrics.listen_addr.is_none());
+
+        let config: Config = toml::from_str(&format!(
+            "{}\n[metrics]\nlisten_addr = \"127.0.0.1:9090\"\n",
+            toml_str
+        ))
+        .unwrap();
+        assert_eq!(
+            config.metrics.listen_addr,
+            Some("127.0.0.1:9090".parse().unwrap())
+        );
     }
 
     #[test]
@@ -215,5 +1474,172 @@ addr = "127.0.0.1:1080"
 
         let config: Config = toml::from_str(toml_str).unwrap();
         assert!(config.rules.allow.is_empty());
+        assert_eq!(config.rules.ech_policy, EchTrafficPolicy::Allow);
+        assert!(!config.rules.reject_ip_sni);
+        assert_eq!(config.rules.unicode_sni, UnicodeSniPolicy::Reject);
+
+        let config: Config = toml::from_str(&format!(
+            "{}\n[rules]\nech_policy = \"default_backend\"\nreject_ip_sni = true\nunicode_sni = \"convert\"\n",
+            toml_str
+        ))
+        .unwrap();
+        assert_eq!(config.rules.ech_policy, EchTrafficPolicy::DefaultBackend);
+        assert!(config.rules.reject_ip_sni);
+        assert_eq!(config.rules.unicode_sni, UnicodeSniPolicy::Convert);
+    }
+
+    #[test]
+    fn test_quic_idle_overrides() {
+        let toml_str = r#"
+[server]
+listen_https_addr = "0.0.0.0:443"
+
+[socks5]
+addr = "127.0.0.1:1080"
+
+[[quic.idle_overrides]]
+pattern = "*.meet.example.com"
+idle_timeout = "10m"
+
+[[quic.idle_overrides]]
+pattern = "*.ads.example.com"
+idle_timeout = "15"
+"#;
+
+        let config: Config = toml::from_str(toml_str).unwrap();
+        assert_eq!(
+            config.quic.idle_overrides,
+            vec![
+                IdleOverride {
+                    pattern: "*.meet.example.com".to_string(),
+                    idle_timeout: Duration::from_secs(600),
+                },
+                IdleOverride {
+                    pattern: "*.ads.example.com".to_string(),
+                    idle_timeout: Duration::from_secs(15),
+                },
+            ]
+        );
+
+        // 保存后重新加载得到相同的值
+        let saved = toml::to_string_pretty(&config).unwrap();
+        let reloaded: Config = toml::from_str(&saved).unwrap();
+        assert_eq!(reloaded.quic.idle_overrides, config.quic.idle_overrides);
+
+        let invalid = toml_str.replace("\"10m\"", "\"10d\"");
+        assert!(toml::from_str::<Config>(&invalid).is_err());
+    }
+
+    #[test]
+    fn redacted_summary_never_contains_credentials() {
+        let toml_str = r#"
+[server]
+listen_https_addr = "0.0.0.0:443"
+
+[socks5]
+addr = "proxy.internal:1080"
+username = "socks-user-7f3a"
+password = "socks-pass-91c2"
+chain = [
+    "10.0.0.1:1080",
+    { addr = "exit.example.net:1080", username = "hop-user-5d1e", password = "hop-pass-0b8f" },
+]
+
+[[socks5.upstreams]]
+name = "backup"
+addr = "10.0.0.2:1080"
+username = "upstream-user-c4a9"
+password = "upstream-pass-e62d"
+
+[backend]
+addr = "10.0.0.3:3128"
+username = "http-user-3b70"
+password = "http-pass-a1f4"
+
+[rules]
+allow = ["*.google.com", "example.com"]
+routes = [{ pattern = "*.google.com", upstream = "backup" }]
+
+[metrics]
+listen_addr = "127.0.0.1:9090"
+"#;
+        let mut config: Config = toml::from_str(toml_str).unwrap();
+        let secrets = [
+            "socks-user-7f3a",
+            "socks-pass-91c2",
+            "hop-user-5d1e",
+            "hop-pass-0b8f",
+            "upstream-user-c4a9",
+            "upstream-pass-e62d",
+            "http-user-3b70",
+            "http-pass-a1f4",
+        ];
+
+        for kind in [BackendKind::Socks5, BackendKind::HttpConnect] {
+            config.backend.kind = kind;
+            let summary = config.redacted_summary();
+            let text = summary.to_string();
+            for secret in secrets {
+                assert!(!text.contains(secret), "{} leaked in {}", secret, text);
+            }
+            assert_eq!(summary["listen"]["https"], "0.0.0.0:443");
+            assert_eq!(summary["listen"]["metrics"], "127.0.0.1:9090");
+            assert_eq!(summary["rules"]["allow"], 2);
+            assert_eq!(summary["rules"]["routes"], 1);
+            assert_eq!(summary["backend"]["auth"], true);
+        }
+
+        config.backend.kind = BackendKind::Socks5;
+        let summary = config.redacted_summary();
+        assert_eq!(summary["backend"]["type"], "socks5");
+        assert_eq!(summary["backend"]["addr"], "proxy.internal:1080");
+        assert_eq!(summary["backend"]["chain"][0]["auth"], false);
+        assert_eq!(
+            summary["backend"]["chain"][1]["addr"],
+            "exit.example.net:1080"
+        );
+        assert_eq!(summary["backend"]["upstreams"][0]["name"], "backup");
+        assert_eq!(summary["backend"]["upstreams"][0]["addr"], "10.0.0.2:1080");
+
+        config.backend.kind = BackendKind::HttpConnect;
+        let summary = config.redacted_summary();
+        assert_eq!(summary["backend"]["type"], "http_connect");
+        assert_eq!(summary["backend"]["addr"], "10.0.0.3:3128");
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("10m"), Ok(Duration::from_secs(600)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("45"), Ok(Duration::from_secs(45)));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("1.5m").is_err());
+        assert!(parse_duration("99999999999999999999h").is_err());
+    }
+
+    #[test]
+    fn test_load_errors() {
+        let missing =
+            std::env::temp_dir().join(format!("sniproxy-ng-missing-{}.toml", std::process::id()));
+        let missing = missing.to_str().unwrap();
+        match Config::load(missing) {
+            Err(crate::Error::Config(ConfigError::Read { path, source })) => {
+                assert_eq!(path, missing);
+                assert_eq!(source.kind(), std::io::ErrorKind::NotFound);
+            }
+            other => panic!("unexpected result: {:?}", other.map(drop)),
+        }
+
+        let invalid =
+            std::env::temp_dir().join(format!("sniproxy-ng-invalid-{}.toml", std::process::id()));
+        std::fs::write(&invalid, "[server]\nlisten_https_addr = 443\n").unwrap();
+        let result = Config::load(invalid.to_str().unwrap());
+        let _ = std::fs::remove_file(&invalid);
+        assert!(matches!(
+            result,
+            Err(crate::Error::Config(ConfigError::Parse { .. }))
+        ));
     }
 }
@@ -0,0 +1,310 @@
+//! 按域名的流量统计
+//!
+//! 回答"哪些域名用得最多"而不必处理全部日志：TCP/HTTP 连接关闭时、QUIC 会话结束时
+//! 把 (域名, 上下行字节数) 记入 [`DomainStats`]。跟踪的域名数有上限 (默认
+//! [`DEFAULT_CAPACITY`])，满了以后按 space-saving 算法淘汰连接数最少的域名：
+//! 新域名继承被淘汰者的连接数作为估计值 (误差记在 `max_overcount`)，被淘汰域名
+//! 实际记录的连接和字节数并入 `other`，因此扫描大量不同 SNI 时内存不会增长，
+//! 各域名与 `other` 的合计仍等于全部流量。
+//!
+//! 管理端口的 `GET /domains/top?n=50` 以 JSON 返回前 n 个域名 (见 [`TopDomains`])，
+//! `/metrics` 只输出前 [`METRIC_DOMAINS`] 个域名，其余合计为 `domain="other"`。
+
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+
+/// 默认跟踪的域名数上限
+pub const DEFAULT_CAPACITY: usize = 1000;
+
+/// `/metrics` 中单独输出的域名数
+pub const METRIC_DOMAINS: usize = 20;
+
+/// 一个域名 (或 `other`) 的累计流量
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct DomainTraffic {
+    /// 连接数 (QUIC 为会话数)
+    pub connections: u64,
+    /// 客户端到上游的字节数
+    pub bytes_up: u64,
+    /// 上游到客户端的字节数
+    pub bytes_down: u64,
+}
+
+impl DomainTraffic {
+    fn add(&mut self, other: DomainTraffic) {
+        self.connections += other.connections;
+        self.bytes_up += other.bytes_up;
+        self.bytes_down += other.bytes_down;
+    }
+}
+
+/// 排行中的一项
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DomainEntry {
+    pub domain: String,
+    /// 连接数为估计值，最多多算 `max_overcount`；字节数是开始跟踪后的实际值
+    #[serde(flatten)]
+    pub traffic: DomainTraffic,
+    pub max_overcount: u64,
+}
+
+/// `GET /domains/top` 的响应
+#[derive(Debug, Clone, Serialize)]
+pub struct TopDomains {
+    /// 跟踪的域名数上限
+    pub capacity: usize,
+    /// 当前跟踪的域名数
+    pub tracked: usize,
+    /// 按连接数从多到少
+    pub domains: Vec<DomainEntry>,
+    /// 已被淘汰的域名的合计
+    pub other: DomainTraffic,
+}
+
+#[derive(Debug)]
+struct Slot {
+    traffic: DomainTraffic,
+    /// 继承自被淘汰域名的连接数
+    error: u64,
+    /// 最近一次记录的序号，连接数相同时先淘汰最久未出现的
+    seq: u64,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    slots: HashMap<Arc<str>, Slot>,
+    /// (连接数, 序号, 域名)，第一个即下一个被淘汰的域名
+    order: BTreeSet<(u64, u64, Arc<str>)>,
+    other: DomainTraffic,
+    next_seq: u64,
+}
+
+/// 有界的按域名流量统计
+#[derive(Debug)]
+pub struct DomainStats {
+    capacity: usize,
+    inner: Mutex<Inner>,
+}
+
+impl Default for DomainStats {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+impl DomainStats {
+    /// 最多跟踪 `capacity` 个域名
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    /// 记录一个结束的连接 (QUIC 为会话)
+    pub fn record(&self, domain: &str, bytes_up: u64, bytes_down: u64) {
+        let domain = domain.to_ascii_lowercase();
+        let traffic = DomainTraffic {
+            connections: 1,
+            bytes_up,
+            bytes_down,
+        };
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let inner = &mut *inner;
+        let seq = inner.next_seq;
+        inner.next_seq += 1;
+
+        if let Some((key, slot)) = inner.slots.get_key_value(domain.as_str()) {
+            let key = Arc::clone(key);
+            inner
+                .order
+                .remove(&(slot.traffic.connections, slot.seq, Arc::clone(&key)));
+            let slot = inner.slots.get_mut(&key).expect("slot just looked up");
+            slot.traffic.add(traffic);
+            slot.seq = seq;
+            inner.order.insert((slot.traffic.connections, seq, key));
+            return;
+        }
+
+        let mut error = 0;
+        if inner.slots.len() >= self.capacity {
+            if let Some((count, _, evicted)) = inner.order.pop_first() {
+                if let Some(slot) = inner.slots.remove(&evicted) {
+                    inner.other.add(DomainTraffic {
+                        connections: slot.traffic.connections - slot.error,
+                        ..slot.traffic
+                    });
+                }
+                error = count;
+            }
+        }
+        let key: Arc<str> = domain.into();
+        let connections = error + 1;
+        inner.order.insert((connections, seq, Arc::clone(&key)));
+        inner.slots.insert(
+            key,
+            Slot {
+                traffic: DomainTraffic {
+                    connections,
+                    ..traffic
+                },
+                error,
+                seq,
+            },
+        );
+    }
+
+    /// 连接数最多的 `n` 个域名
+    pub fn top(&self, n: usize) -> TopDomains {
+        let inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let domains = inner
+            .order
+            .iter()
+            .rev()
+            .take(n)
+            .filter_map(|(_, _, domain)| {
+                let slot = inner.slots.get(domain)?;
+                Some(DomainEntry {
+                    domain: domain.to_string(),
+                    traffic: slot.traffic,
+                    max_overcount: slot.error,
+                })
+            })
+            .collect();
+        TopDomains {
+            capacity: self.capacity,
+            tracked: inner.slots.len(),
+            domains,
+            other: inner.other,
+        }
+    }
+
+    /// 前 `n` 个域名，其余 (含已淘汰的) 合计为一项，供 `/metrics` 限制基数
+    pub fn top_with_rest(&self, n: usize) -> (Vec<DomainEntry>, DomainTraffic) {
+        let all = self.top(self.capacity);
+        let mut rest = all.other;
+        for entry in &all.domains[n.min(all.domains.len())..] {
+            rest.add(DomainTraffic {
+                connections: entry.traffic.connections - entry.max_overcount,
+                ..entry.traffic
+            });
+        }
+        let mut top = all.domains;
+        top.truncate(n);
+        (top, rest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 各域名实际记录的连接数与 `other` 之和
+    fn exact_connections(top: &TopDomains) -> u64 {
+        top.domains
+            .iter()
+            .map(|entry| entry.traffic.connections - entry.max_overcount)
+            .sum::<u64>()
+            + top.other.connections
+    }
+
+    #[test]
+    fn ranks_domains_by_connections() {
+        let stats = DomainStats::new(10);
+        for _ in 0..3 {
+            stats.record("www.example.com", 100, 1000);
+        }
+        stats.record("API.example.com", 10, 20);
+        stats.record("api.example.com", 10, 20);
+        stats.record("cdn.example.net", 1, 2);
+
+        let top = stats.top(2);
+        assert_eq!(top.tracked, 3);
+        assert_eq!(
+            top.domains,
+            vec![
+                DomainEntry {
+                    domain: "www.example.com".to_string(),
+                    traffic: DomainTraffic {
+                        connections: 3,
+                        bytes_up: 300,
+                        bytes_down: 3000,
+                    },
+                    max_overcount: 0,
+                },
+                DomainEntry {
+                    domain: "api.example.com".to_string(),
+                    traffic: DomainTraffic {
+                        connections: 2,
+                        bytes_up: 20,
+                        bytes_down: 40,
+                    },
+                    max_overcount: 0,
+                },
+            ]
+        );
+
+        let (top, rest) = stats.top_with_rest(1);
+        assert_eq!(top.len(), 1);
+        assert_eq!(
+            rest,
+            DomainTraffic {
+                connections: 3,
+                bytes_up: 21,
+                bytes_down: 42,
+            }
+        );
+    }
+
+    #[test]
+    fn scan_of_unique_snis_stays_bounded() {
+        let stats = DomainStats::new(DEFAULT_CAPACITY);
+        for i in 0..100_000u64 {
+            stats.record(&format!("scan-{}.example.org", i), 1, 0);
+            // 真实的热门域名夹在扫描流量中
+            if i % 100 == 0 {
+                stats.record("www.example.com", 10, 100);
+            }
+        }
+
+        let top = stats.top(DEFAULT_CAPACITY);
+        assert_eq!(top.tracked, DEFAULT_CAPACITY);
+        assert_eq!(top.domains.len(), DEFAULT_CAPACITY);
+        {
+            let inner = stats.inner.lock().unwrap();
+            assert_eq!(inner.slots.len(), DEFAULT_CAPACITY);
+            assert_eq!(inner.order.len(), DEFAULT_CAPACITY);
+        }
+
+        let hot = &top.domains[0];
+        assert_eq!(hot.domain, "www.example.com");
+        assert_eq!(hot.traffic.connections - hot.max_overcount, 1000);
+        assert_eq!(hot.traffic.bytes_down, 100_000);
+        // 淘汰的流量计入 other，合计不丢失
+        assert_eq!(exact_connections(&top), 101_000);
+        assert_eq!(
+            top.domains
+                .iter()
+                .map(|entry| entry.traffic.bytes_up)
+                .sum::<u64>()
+                + top.other.bytes_up,
+            110_000
+        );
+    }
+
+    #[test]
+    fn evicts_least_recent_among_equal_counts() {
+        let stats = DomainStats::new(2);
+        stats.record("a.example", 1, 1);
+        stats.record("b.example", 1, 1);
+        stats.record("a.example", 1, 1);
+        stats.record("c.example", 1, 1);
+
+        let top = stats.top(10);
+        let domains: Vec<_> = top.domains.iter().map(|e| e.domain.as_str()).collect();
+        assert_eq!(domains, ["c.example", "a.example"]);
+        assert_eq!(top.domains[0].max_overcount, 1);
+        assert_eq!(top.other.connections, 1);
+    }
+}
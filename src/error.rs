@@ -0,0 +1,132 @@
+//! 库的错误类型
+//!
+//! 对外的入口 ([`Config::load`](crate::Config::load)、[`Server`](crate::Server) 与
+//! [`ServerHandle`](crate::ServerHandle)、`tcp`/`http`/`quic` 的 `run`/`serve`) 返回
+//! [`Error`]，调用方可以按变体区分配置错误、端口占用和监听器失败，不需要依赖 anyhow。
+//! 各模块自己的错误类型 ([`ConfigError`]、[`SniError`]、[`QuicError`]、[`HttpError`]、
+//! [`Socks5Error`]) 都可以用 `?` 转换过来。
+//!
+//! 内部仍以 anyhow 传递、尚未归类的错误转换为 [`Error::Other`]，逐步迁移到具体的变体。
+//!
+//! 稳定性：`Error` 标记为 `#[non_exhaustive]`，匹配时需要通配分支，新增变体不算破坏性变更；
+//! 已有变体的名字和字段保持不变。`Display` 的文字 (包括其中的来源错误) 只用于日志，
+//! 可能随版本调整，不应据此判断错误种类。
+
+use crate::config::ConfigError;
+use crate::http::HttpError;
+use crate::metrics::Listener;
+use crate::quic::error::QuicError;
+use crate::socks5::Socks5Error;
+use crate::tls::sni::SniError;
+use std::fmt::Write as _;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use thiserror::Error;
+
+/// 库对外返回的错误
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// 配置文件无法读取、解析，或配置本身无效
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+
+    /// ClientHello 格式错误
+    #[error("TLS SNI parsing failed: {0}")]
+    Sni(#[from] SniError),
+
+    /// QUIC Initial 解析或解密失败
+    #[error(transparent)]
+    Quic(#[from] QuicError),
+
+    /// HTTP 请求格式错误
+    #[error(transparent)]
+    Http(#[from] HttpError),
+
+    /// SOCKS5 后端建连失败
+    #[error(transparent)]
+    Socks5(#[from] Socks5Error),
+
+    /// 其它 I/O 错误
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    /// 监听地址绑定失败 (端口被占用、权限不足等)；`listener` 为 `HTTPS`、`QUIC`、`HTTP`
+    /// 或 `metrics endpoint`
+    #[error("Failed to bind {listener} listener on {addr}: {source}")]
+    ListenerBind {
+        listener: &'static str,
+        addr: SocketAddr,
+        source: io::Error,
+    },
+
+    /// 监听器连续失败，超过重启次数上限 (见 [`RestartPolicy`](crate::RestartPolicy))
+    #[error("{} listener failed after {restarts} restarts: {source}", listener.label())]
+    ListenerFailed {
+        listener: Listener,
+        restarts: u32,
+        source: Box<Error>,
+    },
+
+    /// 优雅关闭超时，仍有连接未结束
+    #[error("shutdown did not complete within {timeout:?} ({open} connections still open)")]
+    ShutdownTimeout { timeout: Duration, open: u64 },
+
+    /// 尚未归类的内部错误
+    #[error("{}", chain(.0.as_ref()))]
+    Other(Box<dyn std::error::Error + Send + Sync>),
+}
+
+/// 库对外返回的结果
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl From<anyhow::Error> for Error {
+    fn from(error: anyhow::Error) -> Self {
+        Error::Other(error.into())
+    }
+}
+
+/// 错误及其来源，以 `: ` 连接 (同 anyhow 的 `{:#}`)
+fn chain(error: &(dyn std::error::Error + 'static)) -> String {
+    let mut out = error.to_string();
+    let mut source = error.source();
+    while let Some(error) = source {
+        let _ = write!(out, ": {}", error);
+        source = error.source();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn module_errors_convert_into_variants() {
+        let error: Error = SniError::NotClientHello.into();
+        assert!(matches!(error, Error::Sni(SniError::NotClientHello)));
+
+        let error: Error = Socks5Error::ConnectionRefused.into();
+        assert!(matches!(
+            error,
+            Error::Socks5(Socks5Error::ConnectionRefused)
+        ));
+
+        let error: Error = crate::http::extract_host(b"GET / HTTP/1.1\r\n\r\n")
+            .unwrap_err()
+            .into();
+        assert!(matches!(error, Error::Http(HttpError::HostNotFound)));
+    }
+
+    #[test]
+    fn anyhow_errors_keep_their_context() {
+        let error: Error = Err::<(), _>(io::Error::other("connection reset"))
+            .context("relay failed")
+            .unwrap_err()
+            .into();
+        assert!(matches!(error, Error::Other(_)));
+        assert_eq!(error.to_string(), "relay failed: connection reset");
+    }
+}
@@ -0,0 +1,515 @@
+//! 上游主动健康检查
+//!
+//! 每 `socks5.health_interval` 并发探测所有上游一次，结果记入
+//! [`Upstream::record_probe_success`] / [`Upstream::record_probe_failure`]，由上游维护
+//! healthy/degraded/down 状态 (见 [`crate::upstream::BackendState`])；TCP 和 QUIC
+//! 选择上游时跳过 down 的上游，探测再次成功后自动恢复。
+//!
+//! 探测内容：
+//! - 设置了 `socks5.health_canary` 时经该上游完整建立一次到 canary 的 CONNECT
+//!   (SOCKS4a 上游和代理链同样适用)；
+//! - 否则连接代理 (代理链为第一跳) 并完成 SOCKS5 方法协商；SOCKS4a 没有问候，
+//!   只检查 TCP 连接。
+//!
+//! [`Readiness`] 据此 (以及监听器状态和关闭信号) 回答 `/readyz`，见 [`crate::metrics`]。
+
+use crate::backend::{ConnectError, Dialer};
+use crate::config::{BackendKind, Config, ProxyAddr, UpstreamProtocol};
+use crate::metrics::Registry;
+use crate::outbound::{self, DialError, OutboundBind, ProxyResolver};
+use crate::upstream::{Upstream, UpstreamSet};
+use serde::Serialize;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::watch;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::MissedTickBehavior;
+use tracing::debug;
+
+/// 上游健康检查器
+#[derive(Debug, Clone)]
+pub struct HealthChecker {
+    upstreams: Arc<UpstreamSet>,
+    interval: Duration,
+    canary: Option<ProxyAddr>,
+}
+
+impl HealthChecker {
+    /// 由 `[socks5]` 创建；`health_interval = 0` 时返回 `None`
+    pub fn from_config(config: &Config, upstreams: Arc<UpstreamSet>) -> Option<Self> {
+        (config.socks5.health_interval > 0).then(|| Self {
+            upstreams,
+            interval: Duration::from_secs(config.socks5.health_interval),
+            canary: config.socks5.health_canary.clone(),
+        })
+    }
+
+    /// 并发探测所有上游一次并记录结果
+    pub async fn check_all(&self) {
+        let mut probes = JoinSet::new();
+        for upstream in self.upstreams.iter() {
+            let upstream = Arc::clone(upstream);
+            let canary = self.canary.clone();
+            probes.spawn(async move {
+                match probe(&upstream, canary.as_ref()).await {
+                    Ok(latency) => {
+                        debug!(
+                            upstream = %upstream.name(),
+                            latency = ?latency,
+                            outcome = "ok",
+                            "health check finished"
+                        );
+                        upstream.record_probe_success(latency);
+                    }
+                    Err(e) => {
+                        debug!(
+                            upstream = %upstream.name(),
+                            outcome = "failed",
+                            error = %format_args!("{:#}", e),
+                            "health check finished"
+                        );
+                        upstream.record_probe_failure(format!("{:#}", e));
+                    }
+                }
+            });
+        }
+        while probes.join_next().await.is_some() {}
+    }
+
+    /// 启动健康检查任务，`shutdown` 变为 true 时结束，返回任务句柄
+    pub fn spawn(self, mut shutdown: watch::Receiver<bool>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(self.interval);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                let stopping = tokio::select! {
+                    _ = ticker.tick() => false,
+                    _ = shutdown.wait_for(|stopping| *stopping) => true,
+                };
+                if stopping {
+                    break;
+                }
+                self.check_all().await;
+                if let Ok(states) = serde_json::to_string(&self.upstreams.health()) {
+                    debug!(states = %states, "SOCKS5 upstream health");
+                }
+            }
+        })
+    }
+}
+
+/// 实例是否可以接收流量 (`/readyz`)
+///
+/// 就绪要求：
+/// - 所有已声明的监听器都已绑定 (见 [`Registry::expect_listener`])；
+/// - SOCKS5 后端至少有一个上游最近一次探测成功，且距今不超过一个健康检查间隔加探测超时；
+///   未启用主动健康检查时退回被动健康状态，HTTP CONNECT 后端不检查；
+/// - 尚未收到关闭信号，关闭期间返回未就绪，负载均衡器先摘除实例再等待连接结束。
+///
+/// 配置在创建 `Readiness` 之前已经加载并校验，不单独检查。
+#[derive(Debug)]
+pub struct Readiness {
+    registry: Arc<Registry>,
+    /// SOCKS5 后端的上游；HTTP CONNECT 后端为 `None`
+    upstreams: Option<Arc<UpstreamSet>>,
+    /// 探测成功的有效期；未启用主动健康检查时为 `None`
+    max_probe_age: Option<Duration>,
+    shutdown: watch::Receiver<bool>,
+}
+
+/// 一个未就绪的组件
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FailingComponent {
+    /// 组件名：`shutdown`、`listener:<tcp|http|quic>` 或 `backend`
+    pub component: String,
+    pub reason: String,
+}
+
+/// 一次就绪检查的结果，可序列化为 `/readyz` 的响应体
+#[derive(Debug, Clone, Serialize)]
+pub struct ReadinessReport {
+    /// "ready" 或 "not_ready"
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub failing: Vec<FailingComponent>,
+}
+
+impl ReadinessReport {
+    pub fn is_ready(&self) -> bool {
+        self.failing.is_empty()
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+impl Readiness {
+    /// 由配置创建；`shutdown` 与监听器使用同一个关闭信号
+    pub fn new(
+        config: &Config,
+        upstreams: Arc<UpstreamSet>,
+        registry: Arc<Registry>,
+        shutdown: watch::Receiver<bool>,
+    ) -> Self {
+        let max_probe_age = (config.socks5.health_interval > 0).then(|| {
+            let timeout = upstreams
+                .iter()
+                .map(|upstream| upstream.config().timeout)
+                .max()
+                .unwrap_or(config.socks5.timeout);
+            Duration::from_secs(config.socks5.health_interval + timeout)
+        });
+        Self {
+            registry,
+            upstreams: (config.backend.kind == BackendKind::Socks5).then_some(upstreams),
+            max_probe_age,
+            shutdown,
+        }
+    }
+
+    /// 检查各组件
+    pub fn check(&self) -> ReadinessReport {
+        let mut failing = Vec::new();
+        if *self.shutdown.borrow() {
+            failing.push(FailingComponent {
+                component: "shutdown".to_string(),
+                reason: "shutting down, draining connections".to_string(),
+            });
+        }
+        for listener in self.registry.unbound_listeners() {
+            failing.push(FailingComponent {
+                component: format!("listener:{}", listener.label()),
+                reason: "not bound".to_string(),
+            });
+        }
+        if let Some(reason) = self.backend_failure() {
+            failing.push(FailingComponent {
+                component: "backend".to_string(),
+                reason,
+            });
+        }
+        ReadinessReport {
+            status: if failing.is_empty() {
+                "ready"
+            } else {
+                "not_ready"
+            },
+            failing,
+        }
+    }
+
+    /// 后端不可用的原因
+    fn backend_failure(&self) -> Option<String> {
+        let upstreams = self.upstreams.as_ref()?;
+        let Some(max_age) = self.max_probe_age else {
+            return (!upstreams.iter().any(|u| u.is_healthy()))
+                .then(|| "all SOCKS5 upstreams are marked down".to_string());
+        };
+        let now = Instant::now();
+        let reachable = upstreams.iter().any(|upstream| {
+            upstream
+                .last_probe_success()
+                .is_some_and(|at| now.duration_since(at) <= max_age)
+        });
+        if reachable {
+            return None;
+        }
+        let errors: Vec<String> = upstreams
+            .iter()
+            .map(|upstream| {
+                let health = upstream.health();
+                format!(
+                    "{}: {}",
+                    health.name,
+                    health.last_error.as_deref().unwrap_or("no recent probe")
+                )
+            })
+            .collect();
+        Some(format!(
+            "no SOCKS5 upstream passed a health check in the last {:?} ({})",
+            max_age,
+            errors.join("; ")
+        ))
+    }
+}
+
+/// 一次探测失败的原因
+#[derive(Error, Debug)]
+pub enum ProbeError {
+    /// 经上游到 canary 的 CONNECT 失败
+    #[error("CONNECT to canary {canary} failed: {source}")]
+    Canary {
+        canary: String,
+        source: ConnectError,
+    },
+
+    /// 无法连接代理 (代理链为第一跳)
+    #[error(transparent)]
+    Dial(#[from] DialError),
+
+    /// 与代理之间的 I/O 错误
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    /// 代理没有应答 SOCKS5 问候
+    #[error("no SOCKS5 greeting reply: {0}")]
+    NoGreetingReply(io::Error),
+
+    /// 代理不接受提供的认证方法
+    #[error("no acceptable SOCKS5 authentication method")]
+    NoAcceptableMethod,
+
+    /// 代理选择了未提供的认证方法
+    #[error("unexpected SOCKS5 method {0:#04x}")]
+    UnexpectedMethod(u8),
+
+    /// 应答不是 SOCKS5
+    #[error("not a SOCKS5 server (version {0:#04x})")]
+    NotSocks5(u8),
+
+    /// 超过上游的 `timeout` 仍未完成
+    #[error("timed out after {0:?}")]
+    Timeout(Duration),
+}
+
+/// 探测一个上游，返回探测耗时
+///
+/// 超时取该上游的 `timeout`。
+pub async fn probe(
+    upstream: &Arc<Upstream>,
+    canary: Option<&ProxyAddr>,
+) -> Result<Duration, ProbeError> {
+    let timeout = Duration::from_secs(upstream.config().timeout);
+    let start = Instant::now();
+    let check = async {
+        match canary {
+            Some(canary) => {
+                let dialer = Dialer::Socks5(Arc::clone(upstream));
+                let mut stream = dialer
+                    .connect_tcp(&canary.host(), canary.port())
+                    .await
+                    .map_err(|source| ProbeError::Canary {
+                        canary: canary.to_string(),
+                        source,
+                    })?;
+                stream.shutdown().await.ok();
+                Ok(())
+            }
+            None => greet(upstream).await,
+        }
+    };
+    match tokio::time::timeout(timeout, check).await {
+        Ok(result) => result.map(|()| start.elapsed()),
+        Err(_) => Err(ProbeError::Timeout(timeout)),
+    }
+}
+
+/// 连接代理并完成 SOCKS5 方法协商
+async fn greet(upstream: &Upstream) -> Result<(), ProbeError> {
+    let config = upstream.config();
+    let (resolver, auth) = match config.chain.first() {
+        Some(hop) => (
+            Arc::new(ProxyResolver::new(hop.addr.clone())),
+            hop.username.is_some() && hop.password.is_some(),
+        ),
+        None => (
+            upstream.resolver(),
+            config.username.is_some() && config.password.is_some(),
+        ),
+    };
+    let mut stream = outbound::connect(&resolver, &OutboundBind::from_config(config)).await?;
+    if config.protocol == UpstreamProtocol::Socks4a {
+        return Ok(());
+    }
+
+    // 只提供实际会使用的方法：无认证 (0x00) 或用户名/密码 (0x02)
+    let method = if auth { 0x02 } else { 0x00 };
+    stream.write_all(&[0x05, 0x01, method]).await?;
+    let mut reply = [0u8; 2];
+    stream
+        .read_exact(&mut reply)
+        .await
+        .map_err(ProbeError::NoGreetingReply)?;
+    match reply {
+        [0x05, selected] if selected == method => Ok(()),
+        [0x05, 0xff] => Err(ProbeError::NoAcceptableMethod),
+        [0x05, selected] => Err(ProbeError::UnexpectedMethod(selected)),
+        [version, _] => Err(ProbeError::NotSocks5(version)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Listener;
+    use crate::upstream::BackendState;
+    use std::net::SocketAddr;
+    use tokio::net::TcpListener;
+
+    /// 只应答 SOCKS5 问候的服务器，`reply` 为选择的方法
+    fn spawn_greeter(listener: TcpListener, reply: u8) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut greeting = [0u8; 3];
+                    if stream.read_exact(&mut greeting).await.is_ok() {
+                        stream.write_all(&[0x05, reply]).await.ok();
+                    }
+                });
+            }
+        })
+    }
+
+    fn checker(default: SocketAddr, backup: SocketAddr) -> HealthChecker {
+        let mut config: Config = toml::from_str(&format!(
+            r#"
+[server]
+listen_https_addr = "127.0.0.1:443"
+
+[socks5]
+addr = "{}"
+timeout = 2
+
+[[socks5.upstreams]]
+name = "backup"
+addr = "{}"
+"#,
+            default, backup
+        ))
+        .unwrap();
+        config.socks5.health_interval = 1;
+        let upstreams = Arc::new(UpstreamSet::from_config(&config).unwrap());
+        HealthChecker::from_config(&config, upstreams).unwrap()
+    }
+
+    #[tokio::test]
+    async fn stopped_upstream_goes_down_and_is_readmitted_after_restart() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = spawn_greeter(listener, 0x00);
+        let backup = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let checker = checker(addr, backup.local_addr().unwrap());
+        let _backup = spawn_greeter(backup, 0x00);
+        let default = checker.upstreams.get("default").unwrap().clone();
+
+        checker.check_all().await;
+        let health = default.health();
+        assert_eq!(health.state, BackendState::Healthy);
+        assert!(health.latency_ewma.is_some());
+
+        // 停止代理：先 degraded，连续失败达到阈值后 down，不再参与分散
+        server.abort();
+        server.await.ok();
+        checker.check_all().await;
+        assert_eq!(default.state(), BackendState::Degraded);
+        assert!(default.is_healthy());
+        checker.check_all().await;
+        checker.check_all().await;
+        let health = default.health();
+        assert_eq!(health.state, BackendState::Down);
+        assert!(!health.selectable);
+        assert_eq!(health.consecutive_failures, 3);
+        assert!(health.last_error.is_some());
+        for i in 0..32u8 {
+            assert_eq!(checker.upstreams.select(None, &[i]).name(), "backup");
+            assert_eq!(checker.upstreams.select_udp(None, &[i]).name(), "backup");
+        }
+
+        // 在同一端口重新启动，一次成功的探测即恢复
+        let _server = spawn_greeter(TcpListener::bind(addr).await.unwrap(), 0x00);
+        checker.check_all().await;
+        let health = default.health();
+        assert_eq!(health.state, BackendState::Healthy);
+        assert!(health.selectable);
+        assert_eq!(health.consecutive_failures, 0);
+        assert_eq!(health.transitions, 3);
+
+        let json = serde_json::to_value(checker.upstreams.health()).unwrap();
+        assert_eq!(json[0]["state"], "healthy");
+        assert_eq!(json[1]["name"], "backup");
+    }
+
+    #[test]
+    fn readiness_reports_each_failing_component() {
+        let unused: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        let checker = checker(unused, unused);
+        let mut config: Config = toml::from_str(&format!(
+            "[server]\n[socks5]\naddr = \"{}\"\ntimeout = 2\n",
+            unused
+        ))
+        .unwrap();
+        config.socks5.health_interval = 1;
+        let registry = Arc::new(Registry::default());
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let readiness = Readiness::new(
+            &config,
+            Arc::clone(&checker.upstreams),
+            Arc::clone(&registry),
+            shutdown_rx.clone(),
+        );
+        let components = |report: ReadinessReport| {
+            report
+                .failing
+                .into_iter()
+                .map(|c| c.component)
+                .collect::<Vec<_>>()
+        };
+
+        // 尚未探测
+        registry.expect_listener(Listener::Http);
+        assert_eq!(components(readiness.check()), ["listener:http", "backend"]);
+
+        // 任一上游最近一次探测成功即可
+        let listening = registry.listening(Listener::Http);
+        let backup = checker.upstreams.get("backup").unwrap();
+        backup.record_probe_success(Duration::from_millis(1));
+        let report = readiness.check();
+        assert!(report.is_ready());
+        assert_eq!(report.to_json(), r#"{"status":"ready"}"#);
+        backup.record_probe_failure("connection refused");
+        let report = readiness.check();
+        assert!(report.failing[0]
+            .reason
+            .contains("backup: connection refused"));
+        assert!(report.failing[0]
+            .reason
+            .contains("default: no recent probe"));
+
+        // 监听器退出
+        backup.record_probe_success(Duration::from_millis(1));
+        drop(listening);
+        assert_eq!(components(readiness.check()), ["listener:http"]);
+
+        shutdown_tx.send(true).unwrap();
+        assert_eq!(components(readiness.check()), ["shutdown", "listener:http"]);
+
+        // HTTP CONNECT 后端不检查 SOCKS5 上游
+        config.backend.kind = BackendKind::HttpConnect;
+        let readiness = Readiness::new(
+            &config,
+            Arc::clone(&checker.upstreams),
+            Arc::new(Registry::default()),
+            watch::channel(false).1,
+        );
+        backup.record_probe_failure("connection refused");
+        assert!(readiness.check().is_ready());
+    }
+
+    #[tokio::test]
+    async fn rejected_greeting_is_a_failed_probe() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _server = spawn_greeter(listener, 0xff);
+        let checker = checker(addr, addr);
+        let default = checker.upstreams.get("default").unwrap();
+
+        let error = probe(default, None).await.unwrap_err();
+        assert!(matches!(error, ProbeError::NoAcceptableMethod), "{}", error);
+        checker.check_all().await;
+        assert_eq!(default.state(), BackendState::Degraded);
+    }
+}
@@ -1,71 +1,180 @@
 //! HTTP/1.1 代理模块
 //!
-//! 通过 Host 请求头提取目标域名,通过 SOCKS5 转发流量。
+//! 通过 Host 请求头提取目标域名,经后端 (SOCKS5 或 HTTP CONNECT) 转发流量。
+//! 同时支持 CONNECT 隧道，目标端口受 `http.connect_ports` 限制。
 
-use crate::config::Config;
-use crate::relay::{copy_with_idle_timeout, log_accept_error};
+use crate::access::AccessEntry;
+use crate::backend::{Backend, BackendStream, ConnectError};
+use crate::config::{Config, ConfigError};
+use crate::logging::{self, connection_span};
+use crate::metrics::{Direction, Listener, Registry, TaskKind};
+use crate::reason::{FailureReason, Outcome, RejectReason};
+use crate::relay::{copy_with_idle_timeout, forwarding_outcome, log_accept_error, ConnectionBytes};
 use crate::router::Router;
-use anyhow::{anyhow, Result};
+use crate::server::bind_tcp;
+use crate::socks5::metrics::Socks5Operation;
+use crate::socks5::Socks5ErrorCounters;
+use crate::task;
+use crate::tls::sni::{extract_sni_ref, read_client_hello, SniStatus};
+use anyhow::{anyhow, bail, Context, Result};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::net::TcpListener;
-use tokio::sync::Semaphore;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{watch, Semaphore};
 use tracing::{debug, info, trace, warn};
 
 pub mod error;
 pub mod parser;
 
+#[cfg(test)]
+mod tests;
+
 pub use error::HttpError;
-pub use parser::extract_host;
+pub use parser::{extract_connect_target, extract_host, find_header_end};
+
+/// 监听器内各连接共享的转发参数
+struct HttpRuntime {
+    backend: Backend,
+    timeout: Duration,
+    transfer_idle_timeout: Duration,
+    connect_ports: Vec<u16>,
+    connect_verify_tls: bool,
+    connect_max_header_bytes: usize,
+    /// SOCKS5 CONNECT 失败数，按分类计数
+    socks5_errors: Arc<Socks5ErrorCounters>,
+    metrics: Arc<Registry>,
+}
+
+impl HttpRuntime {
+    fn from_config(config: &Config, backend: Backend, metrics: Arc<Registry>) -> Self {
+        Self {
+            backend,
+            timeout: Duration::from_secs(config.socks5.timeout),
+            transfer_idle_timeout: Duration::from_secs(config.server.transfer_idle_timeout.max(1)),
+            connect_ports: config.http.connect_ports.clone(),
+            connect_verify_tls: config.http.connect_verify_tls,
+            connect_max_header_bytes: config.http.connect_max_header_bytes,
+            socks5_errors: Arc::new(Socks5ErrorCounters::default()),
+            metrics,
+        }
+    }
+
+    /// 经后端连接 `host:port`；建连失败时按分类记录日志并返回 `None`
+    async fn connect(
+        &self,
+        router: &Router,
+        host: &str,
+        port: u16,
+        access: &mut AccessEntry,
+    ) -> Result<Option<BackendStream>> {
+        let dialer = self.backend.pick(router.route(host), host.as_bytes());
+        access.upstream = Some(dialer.to_string());
+        let error = match dialer.connect_tcp(host, port).await {
+            Ok(stream) => {
+                dialer.record_success();
+                return Ok(Some(stream));
+            }
+            Err(e) => e,
+        };
+        dialer.record_failure();
+        access.outcome = Outcome::Failed(FailureReason::of_connect(&error));
+
+        if let ConnectError::Socks5(socks5_error) = &error {
+            let count = self.socks5_errors.record(socks5_error);
+            socks5_error.log(Socks5Operation::Connect, &dialer, count);
+        } else {
+            warn!(
+                target = %format_args!("{}:{}", host, port),
+                upstream = %dialer,
+                outcome = access.outcome.as_label(),
+                error = %error,
+                "upstream connect failed"
+            );
+        }
+        Ok(None)
+    }
+}
 
-/// 运行 HTTP 代理服务器
-pub async fn run(config: Config, router: Arc<Router>) -> Result<()> {
+/// 运行 HTTP 代理服务器，连接数、白名单拒绝和转发字节数记入 `metrics`
+///
+/// 收到 `shutdown` 信号后停止接受新连接并返回，已建立的连接继续转发直到结束。
+pub async fn run(
+    config: Arc<Config>,
+    router: Arc<Router>,
+    backend: Backend,
+    shutdown: watch::Receiver<bool>,
+    metrics: Arc<Registry>,
+) -> crate::Result<()> {
     let listen_addr = config
         .server
         .listen_http_addr
-        .ok_or_else(|| anyhow!("HTTP listen address not configured"))?;
+        .ok_or(ConfigError::MissingListenAddr {
+            field: "server.listen_http_addr",
+        })?;
+
+    info!(listener = "http", addr = %listen_addr, "starting http proxy server");
 
-    info!("Starting HTTP proxy server on {}", listen_addr);
+    let listener = bind_tcp("HTTP", listen_addr).await?;
+    serve(listener, config, router, backend, shutdown, metrics).await
+}
 
-    let listener = TcpListener::bind(&listen_addr).await?;
-    info!("HTTP proxy server listening on {}", listen_addr);
+/// 在已绑定的 `listener` 上运行 HTTP 代理服务器，其余同 [`run`]
+pub async fn serve(
+    listener: TcpListener,
+    config: Arc<Config>,
+    router: Arc<Router>,
+    backend: Backend,
+    mut shutdown: watch::Receiver<bool>,
+    metrics: Arc<Registry>,
+) -> crate::Result<()> {
+    info!(listener = "http", addr = %listener.local_addr()?, "http proxy server listening");
+    let _listening = metrics.listening(Listener::Http);
+    let _task = metrics.track_task(TaskKind::Listener);
 
     let accept_limit = Arc::new(Semaphore::new(config.server.max_client_connections.max(1)));
+    let runtime = Arc::new(HttpRuntime::from_config(&config, backend, metrics));
 
     loop {
-        let client_permit = accept_limit
-            .clone()
-            .acquire_owned()
-            .await
-            .map_err(|e| anyhow!("HTTP accept limiter closed: {}", e))?;
+        let accepted = tokio::select! {
+            accepted = async {
+                let client_permit = accept_limit
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .map_err(|e| anyhow!("HTTP accept limiter closed: {}", e))?;
+                Ok::<_, anyhow::Error>((client_permit, listener.accept().await))
+            } => accepted?,
+            _ = shutdown.wait_for(|stop| *stop) => {
+                info!(listener = "http", "shutting down listener");
+                return Ok(());
+            }
+        };
+        let (client_permit, accepted) = accepted;
 
-        match listener.accept().await {
+        match accepted {
             Ok((client_stream, client_addr)) => {
-                trace!("Accepted HTTP connection from {}", client_addr);
+                let conn_id = logging::next_conn_id();
+                let span = connection_span(Listener::Http, conn_id, client_addr);
+                trace!(parent: &span, "connection accepted");
 
                 let router_clone = router.clone();
-                let socks5_addr = config.socks5.addr.to_string();
-                let socks5_username = config.socks5.username.clone();
-                let socks5_password = config.socks5.password.clone();
-                let socks5_timeout = config.socks5.timeout;
-                let transfer_idle_timeout = config.server.transfer_idle_timeout.max(1);
+                let runtime_clone = Arc::clone(&runtime);
+                let connection = runtime.metrics.connection(Listener::Http);
+                let registry = Arc::clone(&runtime.metrics);
 
-                tokio::spawn(async move {
+                task::spawn_logged(Listener::Http, &runtime.metrics, span, async move {
                     let _client_permit = client_permit;
-                    if let Err(e) = handle_client(
-                        client_stream,
-                        client_addr,
-                        router_clone,
-                        socks5_addr,
-                        socks5_username,
-                        socks5_password,
-                        socks5_timeout,
-                        transfer_idle_timeout,
-                    )
-                    .await
+                    let _connection = connection;
+                    let mut access = AccessEntry::new(Listener::Http, conn_id, client_addr, 80);
+                    if let Err(e) =
+                        handle_client(client_stream, router_clone, runtime_clone, &mut access).await
                     {
-                        warn!("HTTP client {} failed: {}", client_addr, e);
+                        access.outcome = Outcome::Failed(FailureReason::of(&e));
+                        warn!(outcome = access.outcome.as_label(), error = %format_args!("{:#}", e), "connection failed");
                     }
+                    access.finish(&registry);
                 });
             }
             Err(e) => {
@@ -77,54 +186,61 @@ pub async fn run(config: Config, router: Arc<Router>) -> Result<()> {
 }
 
 /// 处理单个 HTTP 客户端连接
+///
+/// 在连接的 span (见 [`connection_span`]) 内运行，日志不再重复客户端地址。
 async fn handle_client(
-    client_stream: tokio::net::TcpStream,
-    client_addr: std::net::SocketAddr,
+    client_stream: TcpStream,
     router: Arc<Router>,
-    socks5_addr: String,
-    socks5_username: Option<String>,
-    socks5_password: Option<String>,
-    socks5_timeout: u64,
-    transfer_idle_timeout: u64,
+    runtime: Arc<HttpRuntime>,
+    access: &mut AccessEntry,
 ) -> Result<()> {
-    use tokio::io::{AsyncReadExt, AsyncWriteExt};
-
-    trace!("Handling HTTP client {}", client_addr);
+    trace!("handling connection");
 
     let mut buffer = vec![0u8; 4096];
     let mut client_stream = client_stream;
-    let timeout = Duration::from_secs(socks5_timeout);
-    let n = tokio::time::timeout(timeout, client_stream.peek(&mut buffer))
+    let n = tokio::time::timeout(runtime.timeout, client_stream.peek(&mut buffer))
         .await
-        .map_err(|_| {
-            anyhow!(
-                "Timed out waiting for initial HTTP data from {}",
-                client_addr
-            )
-        })??;
+        .context("Timed out waiting for initial HTTP data")??;
 
     if n == 0 {
-        debug!("HTTP client {} closed connection immediately", client_addr);
+        access.outcome = Outcome::ClientClosed;
+        debug!(
+            outcome = "client_closed",
+            "client closed connection immediately"
+        );
         return Ok(());
     }
 
-    trace!("Peeked {} HTTP bytes from {}", n, client_addr);
+    trace!(bytes = n, "peeked initial HTTP data");
+
+    access.method = buffer[..n]
+        .split(|b| *b == b' ')
+        .next()
+        .filter(|method| !method.is_empty() && method.iter().all(u8::is_ascii_uppercase))
+        .map(|method| String::from_utf8_lossy(method).into_owned());
+    if buffer[..n].starts_with(b"CONNECT ") {
+        return handle_connect(client_stream, router, runtime, access).await;
+    }
 
     let host = match extract_host(&buffer[..n]) {
         Ok(h) => {
-            debug!("Extracted Host: {} from {}", h, client_addr);
+            logging::record_sni(&h);
+            access.host = Some(h.clone());
+            debug!("host extracted");
             h
         }
         Err(e) => {
-            warn!("Failed to extract Host from {}: {}", client_addr, e);
+            access.outcome = Outcome::Rejected(RejectReason::NoHost);
+            warn!(outcome = access.outcome.as_label(), error = %e, "failed to extract Host");
             return Ok(());
         }
     };
 
     if !router.is_allowed(&host) {
+        access.outcome = Outcome::Rejected(RejectReason::NotWhitelisted);
         warn!(
-            "Domain '{}' not in whitelist, rejecting HTTP connection from {}",
-            host, client_addr
+            outcome = access.outcome.as_label(),
+            "domain not in whitelist, connection rejected"
         );
         return Ok(());
     }
@@ -132,61 +248,270 @@ async fn handle_client(
     let target_host = host.clone();
     let target_port = 80;
 
-    debug!(
-        "Connecting HTTP upstream to {}:{} via SOCKS5",
-        target_host, target_port
+    let target = format!("{}:{}", target_host, target_port);
+    debug!(target = %target, "connecting upstream");
+
+    let Some(mut upstream_stream) = runtime
+        .connect(&router, &target_host, target_port, access)
+        .await?
+    else {
+        return Ok(());
+    };
+
+    info!(target = %target, outcome = "established", "route established");
+
+    client_stream.read_exact(&mut buffer[..n]).await?;
+    upstream_stream.write_all(&buffer[..n]).await?;
+    runtime
+        .metrics
+        .record_bytes(Listener::Http, Direction::ClientToUpstream, n as u64);
+    trace!(bytes = n, "initial HTTP data written upstream");
+
+    let bytes = ConnectionBytes::default();
+    bytes.up.fetch_add(n as u64, Ordering::Relaxed);
+    access.outcome = forward(client_stream, upstream_stream, &runtime, "http", &bytes).await;
+
+    let (up, down) = bytes.totals();
+    runtime.metrics.domains().record(&host, up, down);
+    access.bytes_up = up;
+    access.bytes_down = down;
+    trace!(
+        outcome = access.outcome.as_label(),
+        bytes_up = up,
+        bytes_down = down,
+        "connection closed"
     );
+    Ok(())
+}
+
+/// 处理 CONNECT 隧道请求
+async fn handle_connect(
+    mut client_stream: TcpStream,
+    router: Arc<Router>,
+    runtime: Arc<HttpRuntime>,
+    access: &mut AccessEntry,
+) -> Result<()> {
+    let limit = runtime.connect_max_header_bytes;
+    let Some(request) = read_connect_header(&mut client_stream, runtime.timeout, limit).await?
+    else {
+        access.outcome = Outcome::Rejected(RejectReason::HeaderTooLarge);
+        warn!(
+            limit,
+            outcome = access.outcome.as_label(),
+            "CONNECT request header too large"
+        );
+        client_stream
+            .write_all(b"HTTP/1.1 431 Request Header Fields Too Large\r\n\r\n")
+            .await?;
+        return Ok(());
+    };
+
+    let (host, port) = match extract_connect_target(&request) {
+        Ok(target) => target,
+        Err(e) => {
+            access.outcome = Outcome::Rejected(RejectReason::InvalidConnect);
+            warn!(outcome = access.outcome.as_label(), error = %e, "invalid CONNECT request");
+            client_stream
+                .write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n")
+                .await?;
+            return Ok(());
+        }
+    };
+
+    logging::record_sni(&host);
+    access.host = Some(host.clone());
+    access.port = port;
+    let target = format!("{}:{}", host, port);
+    if !runtime.connect_ports.contains(&port) {
+        access.outcome = Outcome::Rejected(RejectReason::PortNotAllowed);
+        warn!(
+            target = %target,
+            outcome = access.outcome.as_label(),
+            "CONNECT port not allowed, connection rejected"
+        );
+        client_stream
+            .write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n")
+            .await?;
+        return Ok(());
+    }
+
+    if !router.is_allowed(&host) {
+        access.outcome = Outcome::Rejected(RejectReason::NotWhitelisted);
+        warn!(
+            target = %target,
+            outcome = access.outcome.as_label(),
+            "domain not in whitelist, CONNECT rejected"
+        );
+        client_stream
+            .write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n")
+            .await?;
+        return Ok(());
+    }
 
-    use crate::socks5::Socks5Client;
+    debug!(target = %target, "connecting upstream");
 
-    let client = if let (Some(username), Some(password)) = (socks5_username, socks5_password) {
-        Socks5Client::new(&socks5_addr)
-            .with_auth(username, password)
-            .with_timeout(timeout)
-    } else {
-        Socks5Client::new(&socks5_addr).with_timeout(timeout)
+    let Some(mut upstream_stream) = runtime.connect(&router, &host, port, access).await? else {
+        client_stream
+            .write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n")
+            .await?;
+        return Ok(());
     };
 
-    let mut socks5_stream = client.connect(&target_host, target_port).await?;
+    client_stream
+        .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+        .await?;
+
+    info!(target = %target, outcome = "established", "CONNECT tunnel established");
+
+    let bytes = ConnectionBytes::default();
+
+    if runtime.connect_verify_tls {
+        let client_hello = read_client_hello(&mut client_stream, runtime.timeout)
+            .await
+            .context("CONNECT tunnel")?;
+        if client_hello.is_empty() {
+            bail!("Client closed CONNECT tunnel before sending ClientHello");
+        }
+        let sni = match extract_sni_ref(&client_hello)? {
+            SniStatus::Found(sni) => Some(sni),
+            SniStatus::NotPresent => None,
+            SniStatus::NeedMoreData { need_at_least } => bail!(
+                "Incomplete ClientHello in CONNECT tunnel ({} of at least {} bytes)",
+                client_hello.len(),
+                need_at_least
+            ),
+        };
+
+        if !sni
+            .as_deref()
+            .is_some_and(|sni| sni.eq_ignore_ascii_case(host.trim_end_matches('.')))
+        {
+            access.outcome = Outcome::Rejected(RejectReason::SniMismatch);
+            warn!(
+                target = %target,
+                tls_sni = sni.as_deref().unwrap_or("<none>"),
+                outcome = access.outcome.as_label(),
+                "CONNECT SNI mismatch"
+            );
+            return Ok(());
+        }
+
+        upstream_stream.write_all(&client_hello).await?;
+        runtime.metrics.record_bytes(
+            Listener::Http,
+            Direction::ClientToUpstream,
+            client_hello.len() as u64,
+        );
+        bytes
+            .up
+            .fetch_add(client_hello.len() as u64, Ordering::Relaxed);
+        trace!(
+            bytes = client_hello.len(),
+            "verified ClientHello written upstream"
+        );
+    }
+
+    access.outcome = forward(client_stream, upstream_stream, &runtime, "connect", &bytes).await;
 
-    info!(
-        "HTTP route established: client={}, host={}, target={}:{}",
-        client_addr, host, target_host, target_port
+    let (up, down) = bytes.totals();
+    runtime.metrics.domains().record(&host, up, down);
+    access.bytes_up = up;
+    access.bytes_down = down;
+    trace!(
+        outcome = access.outcome.as_label(),
+        bytes_up = up,
+        bytes_down = down,
+        "CONNECT tunnel closed"
     );
+    Ok(())
+}
 
-    client_stream.read_exact(&mut buffer[..n]).await?;
-    socks5_stream.write_all(&buffer[..n]).await?;
-    trace!("Wrote {} bytes of initial HTTP data to SOCKS5 stream", n);
+/// 读取 CONNECT 请求头 (到 `\r\n\r\n` 为止)，其后的数据留在连接中，属于隧道
+///
+/// 请求头超过 `limit` 字节时返回 `None`；连接在请求头收全之前关闭时返回错误。
+async fn read_connect_header(
+    client_stream: &mut TcpStream,
+    timeout: Duration,
+    limit: usize,
+) -> Result<Option<Vec<u8>>> {
+    let mut header = Vec::new();
+    let mut buffer = vec![0u8; 4096];
+    loop {
+        let n = tokio::time::timeout(timeout, client_stream.peek(&mut buffer))
+            .await
+            .context("Timed out waiting for the CONNECT request header")??;
+        if n == 0 {
+            bail!("Client closed connection before the CONNECT request header was complete");
+        }
+
+        // 上一段的末尾可能是 `\r\n\r\n` 的前半部分
+        let searched = header.len().saturating_sub(3);
+        header.extend_from_slice(&buffer[..n]);
+        let end = find_header_end(&header[searched..]).map(|end| searched + end);
+        // 只消耗到请求头结尾；超限时也消耗已收到的部分，回复不会因未读数据而被 RST 丢弃
+        let unread = end.map_or(0, |end| header.len() - end);
+        client_stream.read_exact(&mut buffer[..n - unread]).await?;
+        match end {
+            Some(end) if end <= limit => {
+                header.truncate(end);
+                return Ok(Some(header));
+            }
+            Some(_) => return Ok(None),
+            None if header.len() > limit => return Ok(None),
+            None => {}
+        }
+    }
+}
 
+/// 双向转发，任一方向结束时关闭连接；`kind` 为 "http" 或 "connect"，
+/// 转发的字节数同时累加到 `bytes`，返回连接的结果 (见 [`forwarding_outcome`])
+async fn forward<S>(
+    mut client_stream: TcpStream,
+    proxy_stream: S,
+    runtime: &HttpRuntime,
+    kind: &str,
+    bytes: &ConnectionBytes,
+) -> Outcome
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
     let (mut client_read, mut client_write) = client_stream.split();
-    let (mut proxy_read, mut proxy_write) = tokio::io::split(socks5_stream);
+    let (mut proxy_read, mut proxy_write) = tokio::io::split(proxy_stream);
+    let idle_timeout = runtime.transfer_idle_timeout;
+    let metrics = &runtime.metrics;
 
-    let idle_timeout = Duration::from_secs(transfer_idle_timeout);
     let client_to_proxy = async {
-        copy_with_idle_timeout(&mut client_read, &mut proxy_write, idle_timeout)
-            .await
-            .map_err(|e| anyhow!("Client to proxy copy failed: {}", e))
+        copy_with_idle_timeout(
+            &mut client_read,
+            &mut proxy_write,
+            idle_timeout,
+            &[
+                metrics.bytes(Listener::Http, Direction::ClientToUpstream),
+                &bytes.up,
+            ],
+        )
+        .await
     };
 
     let proxy_to_client = async {
-        copy_with_idle_timeout(&mut proxy_read, &mut client_write, idle_timeout)
-            .await
-            .map_err(|e| anyhow!("Proxy to client copy failed: {}", e))
+        copy_with_idle_timeout(
+            &mut proxy_read,
+            &mut client_write,
+            idle_timeout,
+            &[
+                metrics.bytes(Listener::Http, Direction::UpstreamToClient),
+                &bytes.down,
+            ],
+        )
+        .await
     };
 
-    tokio::select! {
-        result = client_to_proxy => {
-            if let Err(e) = result {
-                debug!("HTTP client-to-proxy forwarding ended: {}", e);
-            }
-        }
-        result = proxy_to_client => {
-            if let Err(e) = result {
-                debug!("HTTP proxy-to-client forwarding ended: {}", e);
-            }
-        }
+    let (direction, result) = tokio::select! {
+        result = client_to_proxy => ("client_to_upstream", result),
+        result = proxy_to_client => ("upstream_to_client", result),
+    };
+    if let Err(e) = &result {
+        debug!(kind, direction, error = %e, "forwarding ended");
     }
-
-    trace!("HTTP connection from {} closed", client_addr);
-    Ok(())
+    forwarding_outcome(&result)
 }
@@ -1,6 +1,6 @@
 //! HTTP Host 头解析器
 
-use crate::http::{HttpError, Result};
+use crate::http::error::{HttpError, Result};
 
 /// 从 HTTP 请求中提取 Host 头
 ///
@@ -40,14 +40,77 @@ pub fn extract_host(buf: &[u8]) -> Result<String> {
             };
 
             if host.is_empty() {
-                return Err(HttpError::MalformedHost("empty host".to_string()).into());
+                return Err(HttpError::MalformedHost("empty host".to_string()));
             }
 
             return Ok(host.to_string());
         }
     }
 
-    Err(HttpError::HostNotFound.into())
+    Err(HttpError::HostNotFound)
+}
+
+/// 从 HTTP CONNECT 请求行中提取目标主机和端口
+///
+/// # 参数
+/// - `buf`: CONNECT 请求数据（至少包含请求行）
+///
+/// # 返回
+/// - (host, port)，IPv6 地址保留方括号
+///
+/// # 示例
+/// ```
+/// use sniproxy_ng::http::extract_connect_target;
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+///
+/// let request = b"CONNECT www.example.com:443 HTTP/1.1\r\nHost: www.example.com:443\r\n\r\n";
+/// let (host, port) = extract_connect_target(request)?;
+/// assert_eq!(host, "www.example.com");
+/// assert_eq!(port, 443);
+/// # Ok(()) }
+/// ```
+pub fn extract_connect_target(buf: &[u8]) -> Result<(String, u16)> {
+    let header_len = find_header_end(buf).unwrap_or(buf.len());
+    let request = std::str::from_utf8(&buf[..header_len])?;
+
+    let request_line = request.lines().next().unwrap_or_default();
+    let mut parts = request_line.split_whitespace();
+
+    if parts.next() != Some("CONNECT") {
+        return Err(HttpError::InvalidRequest("not a CONNECT request".to_string()));
+    }
+
+    let authority = parts
+        .next()
+        .ok_or_else(|| HttpError::InvalidRequest("missing CONNECT target".to_string()))?;
+
+    let (host, port) = if authority.starts_with('[') {
+        let end = authority
+            .find("]:")
+            .ok_or_else(|| HttpError::MalformedHost(authority.to_string()))?;
+        (&authority[..=end], &authority[end + 2..])
+    } else {
+        authority
+            .rsplit_once(':')
+            .ok_or_else(|| HttpError::MalformedHost(authority.to_string()))?
+    };
+
+    if host.is_empty() {
+        return Err(HttpError::MalformedHost("empty host".to_string()));
+    }
+
+    let port = port
+        .parse::<u16>()
+        .map_err(|_| HttpError::MalformedHost(authority.to_string()))?;
+
+    Ok((host.to_string(), port))
+}
+
+/// 查找 HTTP 请求头结束位置 (`\r\n\r\n` 之后的偏移)
+pub fn find_header_end(buf: &[u8]) -> Option<usize> {
+    buf.windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map(|pos| pos + 4)
 }
 
 #[cfg(test)]
@@ -96,6 +159,36 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_extract_connect_target() {
+        let request = b"CONNECT www.example.com:443 HTTP/1.1\r\nHost: www.example.com:443\r\n\r\n";
+        let (host, port) = extract_connect_target(request).unwrap();
+        assert_eq!(host, "www.example.com");
+        assert_eq!(port, 443);
+    }
+
+    #[test]
+    fn test_extract_connect_target_ipv6() {
+        let request = b"CONNECT [::1]:8443 HTTP/1.1\r\n\r\n";
+        let (host, port) = extract_connect_target(request).unwrap();
+        assert_eq!(host, "[::1]");
+        assert_eq!(port, 8443);
+    }
+
+    #[test]
+    fn test_extract_connect_target_invalid() {
+        assert!(extract_connect_target(b"GET / HTTP/1.1\r\n\r\n").is_err());
+        assert!(extract_connect_target(b"CONNECT www.example.com HTTP/1.1\r\n\r\n").is_err());
+        assert!(extract_connect_target(b"CONNECT www.example.com:http HTTP/1.1\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn test_find_header_end() {
+        let request = b"CONNECT a:443 HTTP/1.1\r\n\r\n\x16\x03\x01";
+        assert_eq!(find_header_end(request), Some(request.len() - 3));
+        assert_eq!(find_header_end(b"CONNECT a:443 HTTP/1.1\r\n"), None);
+    }
+
     #[test]
     fn test_extract_host_invalid_utf8() {
         let request = b"GET / HTTP/1.1\r\nHost: \xff\xfe\r\n\r\n";
@@ -1,8 +1,343 @@
-#[cfg(test)]
-mod tests {
-    use super::*;
+use super::*;
+use crate::config::Config;
+use crate::upstream::UpstreamSet;
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
 
-    #[tokio::test]
-    async fn test_http_server_starts() {
+/// 最小 SOCKS5 服务器：完成握手后回显隧道数据
+async fn spawn_echo_socks5_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+
+        let mut greeting = [0u8; 3];
+        stream.read_exact(&mut greeting).await.unwrap();
+        stream.write_all(&[0x05, 0x00]).await.unwrap();
+
+        // [ver][cmd][rsv][atyp=domain][len][domain...][port]
+        let mut request = [0u8; 5];
+        stream.read_exact(&mut request).await.unwrap();
+        let mut rest = vec![0u8; request[4] as usize + 2];
+        stream.read_exact(&mut rest).await.unwrap();
+        stream
+            .write_all(&[0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1, 0x01, 0xbb])
+            .await
+            .unwrap();
+
+        let (mut read, mut write) = stream.split();
+        let _ = tokio::io::copy(&mut read, &mut write).await;
+    });
+
+    addr
+}
+
+fn test_config(socks5_addr: SocketAddr, verify_tls: bool) -> Config {
+    let mut config: Config = toml::from_str(
+        r#"
+[server]
+listen_http_addr = "127.0.0.1:0"
+
+[socks5]
+addr = "127.0.0.1:1080"
+timeout = 2
+
+[rules]
+allow = ["*example.com"]
+"#,
+    )
+    .unwrap();
+    config.socks5.addr = socks5_addr.into();
+    config.http.connect_verify_tls = verify_tls;
+    config
+}
+
+/// 构造带 SNI 的最小 TLS ClientHello record
+fn client_hello(sni: &str) -> Vec<u8> {
+    let name = sni.as_bytes();
+    let mut ext = Vec::new();
+    ext.extend_from_slice(&[0x00, 0x00]);
+    ext.extend_from_slice(&((name.len() + 5) as u16).to_be_bytes());
+    ext.extend_from_slice(&((name.len() + 3) as u16).to_be_bytes());
+    ext.push(0x00);
+    ext.extend_from_slice(&(name.len() as u16).to_be_bytes());
+    ext.extend_from_slice(name);
+
+    let mut body = vec![0x03, 0x03];
+    body.extend_from_slice(&[0u8; 32]);
+    body.push(0x00);
+    body.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]);
+    body.extend_from_slice(&[0x01, 0x00]);
+    body.extend_from_slice(&(ext.len() as u16).to_be_bytes());
+    body.extend_from_slice(&ext);
+
+    let mut handshake = vec![0x01, 0x00];
+    handshake.extend_from_slice(&(body.len() as u16).to_be_bytes());
+    handshake.extend_from_slice(&body);
+
+    let mut record = vec![0x16, 0x03, 0x01];
+    record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+    record.extend_from_slice(&handshake);
+    record
+}
+
+/// 在本地建立一对 TCP 连接，服务端交给 handle_client 处理；任务结束时与 [`run`]
+/// 一样结束访问记录，返回访问记录和计入结果的注册表
+async fn spawn_handler(
+    config: Config,
+) -> (
+    TcpStream,
+    tokio::task::JoinHandle<(AccessEntry, Arc<Registry>)>,
+) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let router = Arc::new(Router::new(config.clone()));
+    let upstreams = UpstreamSet::from_config(&config).unwrap();
+    let registry = Arc::new(Registry::default());
+    let runtime = Arc::new(HttpRuntime::from_config(
+        &config,
+        Backend::Socks5(Arc::new(upstreams)),
+        Arc::clone(&registry),
+    ));
+
+    let handler = tokio::spawn(async move {
+        let (stream, client) = listener.accept().await.unwrap();
+        let mut access = AccessEntry::new(Listener::Http, 1, client, 80);
+        if let Err(e) = handle_client(stream, router, runtime, &mut access).await {
+            access.outcome = Outcome::Failed(FailureReason::of(&e));
+        }
+        access.finish(&registry);
+        (access, registry)
+    });
+
+    (TcpStream::connect(addr).await.unwrap(), handler)
+}
+
+async fn read_response_head(stream: &mut TcpStream) -> String {
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if stream.read(&mut byte).await.unwrap() == 0 {
+            break;
+        }
+        head.push(byte[0]);
     }
+    String::from_utf8(head).unwrap()
+}
+
+#[tokio::test]
+async fn connect_to_disallowed_port_is_forbidden() {
+    let socks5_addr = spawn_echo_socks5_server().await;
+    let (mut client, handler) = spawn_handler(test_config(socks5_addr, false)).await;
+
+    client
+        .write_all(b"CONNECT www.example.com:22 HTTP/1.1\r\nHost: www.example.com:22\r\n\r\n")
+        .await
+        .unwrap();
+
+    let head = read_response_head(&mut client).await;
+    assert!(
+        head.starts_with("HTTP/1.1 403"),
+        "unexpected response: {}",
+        head
+    );
+
+    let (access, registry) = handler.await.unwrap();
+    assert_eq!(access.method.as_deref(), Some("CONNECT"));
+    assert_eq!(access.host.as_deref(), Some("www.example.com"));
+    assert_eq!(access.port, 22);
+    assert_eq!(
+        access.outcome,
+        Outcome::Rejected(RejectReason::PortNotAllowed)
+    );
+    assert_eq!(crate::access::status_for(access.outcome), 403);
+    assert_eq!(
+        registry.rejections(Listener::Http, RejectReason::PortNotAllowed),
+        1
+    );
+}
+
+#[tokio::test]
+async fn connect_with_mismatched_sni_is_cut() {
+    let socks5_addr = spawn_echo_socks5_server().await;
+    let (mut client, handler) = spawn_handler(test_config(socks5_addr, true)).await;
+
+    client
+        .write_all(b"CONNECT www.example.com:443 HTTP/1.1\r\n\r\n")
+        .await
+        .unwrap();
+    let head = read_response_head(&mut client).await;
+    assert!(
+        head.starts_with("HTTP/1.1 200"),
+        "unexpected response: {}",
+        head
+    );
+
+    client.write_all(&client_hello("evil.test")).await.unwrap();
+
+    let mut buf = [0u8; 64];
+    let n = tokio::time::timeout(Duration::from_secs(2), client.read(&mut buf))
+        .await
+        .unwrap()
+        .unwrap_or(0);
+    assert_eq!(n, 0);
+    let (access, registry) = handler.await.unwrap();
+    assert_eq!(access.outcome, Outcome::Rejected(RejectReason::SniMismatch));
+    assert!(access.upstream.is_some());
+    assert_eq!(
+        registry.rejections(Listener::Http, RejectReason::SniMismatch),
+        1
+    );
+}
+
+#[tokio::test]
+async fn connect_with_matching_sni_flows() {
+    let socks5_addr = spawn_echo_socks5_server().await;
+    let (mut client, _handler) = spawn_handler(test_config(socks5_addr, true)).await;
+
+    client
+        .write_all(b"CONNECT www.example.com:443 HTTP/1.1\r\n\r\n")
+        .await
+        .unwrap();
+    let head = read_response_head(&mut client).await;
+    assert!(
+        head.starts_with("HTTP/1.1 200"),
+        "unexpected response: {}",
+        head
+    );
+
+    let hello = client_hello("www.example.com");
+    client.write_all(&hello).await.unwrap();
+
+    let mut echoed = vec![0u8; hello.len()];
+    tokio::time::timeout(Duration::from_secs(2), client.read_exact(&mut echoed))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(echoed, hello);
+}
+
+#[tokio::test]
+async fn connect_header_spanning_several_reads_is_accepted() {
+    let socks5_addr = spawn_echo_socks5_server().await;
+    let (mut client, _handler) = spawn_handler(test_config(socks5_addr, false)).await;
+
+    // 请求头超过一次 peek 的 4096 字节，分两次到达；请求头之后紧跟隧道数据
+    let cookie = format!("Cookie: {}\r\n", "a".repeat(6000));
+    client
+        .write_all(b"CONNECT www.example.com:443 HTTP/1.1\r\n")
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    client
+        .write_all(format!("{}\r\nhello", cookie).as_bytes())
+        .await
+        .unwrap();
+
+    let head = read_response_head(&mut client).await;
+    assert!(
+        head.starts_with("HTTP/1.1 200"),
+        "unexpected response: {}",
+        head
+    );
+    let mut echoed = [0u8; 5];
+    tokio::time::timeout(Duration::from_secs(2), client.read_exact(&mut echoed))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&echoed, b"hello");
+}
+
+#[tokio::test]
+async fn connect_header_over_the_limit_is_rejected() {
+    let socks5_addr = spawn_echo_socks5_server().await;
+    let mut config = test_config(socks5_addr, false);
+    config.http.connect_max_header_bytes = 1024;
+    let (mut client, handler) = spawn_handler(config).await;
+
+    let cookie = format!("Cookie: {}\r\n", "a".repeat(2000));
+    client
+        .write_all(format!("CONNECT www.example.com:443 HTTP/1.1\r\n{}\r\n", cookie).as_bytes())
+        .await
+        .unwrap();
+
+    let head = read_response_head(&mut client).await;
+    assert!(
+        head.starts_with("HTTP/1.1 431"),
+        "unexpected response: {}",
+        head
+    );
+    let (access, registry) = handler.await.unwrap();
+    assert_eq!(
+        access.outcome,
+        Outcome::Rejected(RejectReason::HeaderTooLarge)
+    );
+    assert_eq!(crate::access::status_for(access.outcome), 431);
+    assert!(access.upstream.is_none());
+    assert_eq!(
+        registry.rejections(Listener::Http, RejectReason::HeaderTooLarge),
+        1
+    );
+}
+
+#[tokio::test]
+async fn host_not_in_whitelist_is_counted() {
+    let socks5_addr = spawn_echo_socks5_server().await;
+    let (mut client, handler) = spawn_handler(test_config(socks5_addr, false)).await;
+
+    client
+        .write_all(b"GET / HTTP/1.1\r\nHost: evil.test\r\n\r\n")
+        .await
+        .unwrap();
+
+    let (access, registry) = handler.await.unwrap();
+    assert_eq!(access.method.as_deref(), Some("GET"));
+    assert_eq!(
+        access.outcome,
+        Outcome::Rejected(RejectReason::NotWhitelisted)
+    );
+    assert_eq!(
+        registry.rejections(Listener::Http, RejectReason::NotWhitelisted),
+        1
+    );
+    assert!(registry
+        .render()
+        .await
+        .contains("sniproxy_whitelist_rejections_total{listener=\"http\"} 1\n"));
+}
+
+#[tokio::test]
+async fn upstream_connect_failure_is_counted() {
+    // 绑定后立即释放的端口，连接会被拒绝
+    let socks5_addr = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let (mut client, handler) = spawn_handler(test_config(socks5_addr, false)).await;
+
+    client
+        .write_all(b"CONNECT www.example.com:443 HTTP/1.1\r\n\r\n")
+        .await
+        .unwrap();
+    let head = read_response_head(&mut client).await;
+    assert!(
+        head.starts_with("HTTP/1.1 502"),
+        "unexpected response: {}",
+        head
+    );
+
+    let (access, registry) = handler.await.unwrap();
+    assert_eq!(
+        access.outcome,
+        Outcome::Failed(FailureReason::Socks5ConnectFailed)
+    );
+    assert_eq!(crate::access::status_for(access.outcome), 502);
+    assert_eq!(
+        registry.failures(Listener::Http, FailureReason::Socks5ConnectFailed),
+        1
+    );
+    assert_eq!(registry.totals().await.failures["socks5_connect_failed"], 1);
 }
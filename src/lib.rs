@@ -1,15 +1,40 @@
 //! sniproxy-ng 库
 //!
-//! SNI 代理服务器，支持 QUIC/HTTP3 和 HTTP/1.1，使用 SOCKS5 后端
+//! SNI 代理服务器，支持 QUIC/HTTP3 和 HTTP/1.1，使用 SOCKS5 或 HTTP CONNECT 后端
 
+pub mod access;
+pub mod backend;
+pub mod build_info;
 pub mod config;
+pub mod domains;
+pub mod error;
+pub mod health;
 pub mod http;
+pub mod logging;
+pub mod metrics;
+pub mod outbound;
 pub mod quic;
+pub mod rate_limit;
+pub mod reason;
 pub mod relay;
 pub mod router;
+pub mod runtime;
+#[cfg(unix)]
+pub mod sd_notify;
+pub mod server;
+#[cfg(any(unix, windows))]
+pub mod signals;
 pub mod socks5;
+pub mod stats;
+pub mod statsd;
+pub mod supervisor;
+pub mod task;
 pub mod tcp;
 pub mod tls;
+pub mod upstream;
 
 // 重新导出常用类型
-pub use config::Config;
+pub use config::{Config, ConfigError};
+pub use error::{Error, Result};
+pub use server::{LocalAddrs, Server, ServerHandle};
+pub use supervisor::RestartPolicy;
@@ -0,0 +1,812 @@
+//! 日志字段约定
+//!
+//! 处理连接的日志事件以 tracing 字段携带变量，消息是固定的小写短语 (例如 `"sni extracted"`)，
+//! 不再把变量拼进消息；`server.log_format = "json"` 时可以直接按字段检索和聚合。
+//! 各模块统一使用以下字段名：
+//!
+//! - `listener`: 监听器，`tcp`、`http` 或 `quic` (见 [`Listener::label`])
+//! - `conn_id`: 进程内唯一的 TCP/HTTP 连接编号 (见 [`next_conn_id`])
+//! - `dcid`: QUIC 客户端最初的 DCID，十六进制
+//! - `client`: 客户端地址 `ip:port`
+//! - `sni`: 客户端请求的主机名 (TLS SNI、HTTP Host 或 CONNECT 目标)
+//! - `target`: 经后端连接的目标 `host:port`
+//! - `upstream`: 选中的出口 (SOCKS5 上游名或 HTTP 代理地址)
+//! - `outcome`: 事件的结果，snake_case 的固定取值，例如 `established`、`not_whitelisted`、
+//!   `malformed_client_hello`，拒绝和失败使用 [`crate::reason`] 中原因的标签，
+//!   SOCKS5 错误使用 [`crate::socks5::Socks5Error::metric_label`]
+//! - `error`: 错误描述
+//!
+//! `listener`、`conn_id`、`client` 和 `sni` 记录在连接的 span 上 (TCP/HTTP 见
+//! [`connection_span`]，QUIC 见 [`crate::quic::span`])，连接内的事件自动带上，
+//! JSON 输出中位于 `span`/`spans`；其余字段记录在事件上。其它数值 (字节数、耗时、
+//! 计数等) 使用描述性的 snake_case 字段名，连接各阶段的耗时以毫秒记为 `*_ms`
+//! (见 [`millis`])。
+//!
+//! 同一来源反复触发的警告 (例如对 QUIC 端口乱发包的客户端) 经 [`allow_warning`]
+//! 限流：每个 (原因, 来源网段) 在 [`SUPPRESS_WINDOW`] 内只输出第一条，其余计数，
+//! 窗口结束后输出一行 `"suppressed similar warnings"` 汇总被压下的条数。
+//!
+//! 控制台和文件输出的过滤器可以在运行时替换 (见 [`LogFilter`])：指标端口的
+//! `GET`/`PUT /admin/log_level` 查询和设置，SIGUSR1 在配置的级别与 `debug` 之间切换，
+//! 不必为了排查问题带着 `RUST_LOG=debug` 重启而丢失现场。配置的过滤器来自 `RUST_LOG`
+//! 或 `server.log_level`/`server.console_log_level` (见 [`ConfiguredFilters`])，SIGHUP 或
+//! `POST /admin/log_level/reload` 重新读取配置文件中的这两项；没有信号的 Windows 上使用后者，
+//! `DELETE /admin/log_level` 恢复配置的过滤器。
+
+use crate::config::{Config, ServerConfig};
+use crate::metrics::Listener;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tracing::{info, warn, Span, Subscriber};
+use tracing_subscriber::filter::ParseError;
+use tracing_subscriber::{reload, EnvFilter};
+
+static NEXT_CONN_ID: AtomicU64 = AtomicU64::new(1);
+
+/// 重复警告的限流窗口
+pub const SUPPRESS_WINDOW: Duration = Duration::from_secs(30);
+
+/// 同时跟踪的 (原因, 来源) 数上限，超出后新来源合并为不区分来源的一项
+const MAX_SUPPRESS_KEYS: usize = 4096;
+
+static WARNINGS: LazyLock<WarnLimiter> = LazyLock::new(|| WarnLimiter::new(SUPPRESS_WINDOW));
+
+/// 分配一个连接编号
+pub fn next_conn_id() -> u64 {
+    NEXT_CONN_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// 创建 TCP/HTTP 连接的 `conn` span；`sni` 字段留空，由 [`record_sni`] 填入
+pub fn connection_span(listener: Listener, conn_id: u64, client: SocketAddr) -> Span {
+    tracing::info_span!(
+        "conn",
+        listener = listener.label(),
+        conn_id,
+        client = %client,
+        sni = tracing::field::Empty
+    )
+}
+
+/// 耗时字段 (`*_ms`) 的取值，单位毫秒
+pub fn millis(elapsed: Duration) -> f64 {
+    elapsed.as_secs_f64() * 1000.0
+}
+
+/// 在当前 span 中记录客户端请求的主机名
+pub fn record_sni(sni: &str) {
+    Span::current().record("sni", sni);
+}
+
+/// 替换过滤器失败
+#[derive(Debug, Error)]
+pub enum LogFilterError {
+    #[error("invalid log filter: {0}")]
+    Parse(#[from] ParseError),
+    #[error("failed to reload log filter: {0}")]
+    Reload(#[from] reload::Error),
+    #[error("no config file to reload log levels from")]
+    NoConfigFile,
+    #[error("{0}")]
+    Config(crate::Error),
+    #[error("invalid {setting} {value:?}: {source}")]
+    Setting {
+        setting: &'static str,
+        value: String,
+        source: ParseError,
+    },
+}
+
+/// 配置的过滤器取自哪里
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterSource {
+    /// 环境变量 `RUST_LOG`，控制台和文件使用同一个过滤器
+    Env,
+    /// `server.console_log_level` 与 `server.log_level`
+    Config,
+}
+
+impl FilterSource {
+    /// 日志中记录的来源
+    pub fn label(self) -> &'static str {
+        match self {
+            FilterSource::Env => "RUST_LOG",
+            FilterSource::Config => "config",
+        }
+    }
+}
+
+/// 控制台和文件输出配置的过滤器 (EnvFilter 语法，例如 `info,sniproxy_ng::quic=debug`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfiguredFilters {
+    pub console: String,
+    pub file: String,
+    pub source: FilterSource,
+}
+
+impl ConfiguredFilters {
+    /// `RUST_LOG` 非空时优先，否则使用 `server` 中的两项；过滤器无法解析时返回错误
+    pub fn resolve(rust_log: Option<&str>, server: &ServerConfig) -> Result<Self, LogFilterError> {
+        let filters = match rust_log.map(str::trim).filter(|filter| !filter.is_empty()) {
+            Some(filter) => ConfiguredFilters {
+                console: filter.to_string(),
+                file: filter.to_string(),
+                source: FilterSource::Env,
+            },
+            None => ConfiguredFilters {
+                console: server.console_log_level.trim().to_string(),
+                file: server.log_level.trim().to_string(),
+                source: FilterSource::Config,
+            },
+        };
+        let (console, file) = match filters.source {
+            FilterSource::Env => ("RUST_LOG", "RUST_LOG"),
+            FilterSource::Config => ("server.console_log_level", "server.log_level"),
+        };
+        for (setting, value) in [(console, &filters.console), (file, &filters.file)] {
+            EnvFilter::try_new(value).map_err(|source| LogFilterError::Setting {
+                setting,
+                value: value.clone(),
+                source,
+            })?;
+        }
+        Ok(filters)
+    }
+
+    /// 按当前进程的 `RUST_LOG` 解析
+    pub fn from_env(server: &ServerConfig) -> Result<Self, LogFilterError> {
+        Self::resolve(
+            std::env::var(EnvFilter::DEFAULT_ENV).ok().as_deref(),
+            server,
+        )
+    }
+
+    /// 输出 `output` (`console` 或 `file`) 的过滤器
+    fn for_output(&self, output: &str) -> Option<&str> {
+        match output {
+            "console" => Some(self.console.as_str()),
+            "file" => Some(self.file.as_str()),
+            _ => None,
+        }
+    }
+}
+
+/// 一个输出 (控制台或文件) 的可替换过滤器
+struct FilterSlot {
+    output: &'static str,
+    configured: String,
+    current: String,
+    reload: Box<dyn Fn(EnvFilter) -> Result<(), reload::Error> + Send + Sync>,
+}
+
+/// `GET /admin/log_level` 的响应
+#[derive(Debug, Serialize)]
+pub struct LogFilterState {
+    /// 各输出当前的过滤器
+    pub filters: BTreeMap<&'static str, String>,
+    /// 是否有输出不再使用配置的过滤器
+    pub overridden: bool,
+}
+
+/// 运行时可替换的日志过滤器
+///
+/// 初始化日志时每个输出以 [`LogFilter::layer`] 取得可重载的过滤层；之后
+/// [`set`](Self::set) 把所有输出换成同一个过滤器，[`reset`](Self::reset) 恢复各自配置的
+/// 过滤器 (`RUST_LOG` 或 `server.log_level`/`server.console_log_level`)。
+#[derive(Default)]
+pub struct LogFilter {
+    slots: Mutex<Vec<FilterSlot>>,
+    /// [`reload_config`](Self::reload_config) 读取的配置文件
+    config_path: Option<String>,
+}
+
+impl LogFilter {
+    /// 配置的过滤器可以从 `config_path` 重新读取 (见 [`reload_config`](Self::reload_config))
+    pub fn with_config_path(config_path: impl Into<String>) -> Self {
+        Self {
+            slots: Mutex::default(),
+            config_path: Some(config_path.into()),
+        }
+    }
+
+    /// 以 `configured` 创建输出 `output` 的过滤层，供 `with_filter` 使用
+    pub fn layer<S>(&self, output: &'static str, configured: &str) -> reload::Layer<EnvFilter, S>
+    where
+        S: Subscriber + 'static,
+    {
+        let (layer, handle) = reload::Layer::new(EnvFilter::new(configured));
+        self.lock().push(FilterSlot {
+            output,
+            configured: configured.to_string(),
+            current: configured.to_string(),
+            reload: Box::new(move |filter| handle.reload(filter)),
+        });
+        layer
+    }
+
+    /// 所有输出改用 `filter` (EnvFilter 语法，例如 `info,sniproxy_ng::quic=trace`)
+    pub fn set(&self, filter: &str) -> Result<(), LogFilterError> {
+        let filter = filter.trim();
+        EnvFilter::try_new(filter)?;
+        self.apply(|_| filter.to_string())
+    }
+
+    /// 各输出恢复配置的过滤器
+    pub fn reset(&self) -> Result<(), LogFilterError> {
+        self.apply(|slot| slot.configured.clone())
+    }
+
+    /// 更新 `console`/`file` 输出配置的过滤器 (重新加载配置时)
+    ///
+    /// 没有被 [`set`](Self::set) 临时替换的输出立即生效；被替换的输出保持当前过滤器，
+    /// 之后 [`reset`](Self::reset) 时恢复为新配置的过滤器。
+    pub fn reconfigure(&self, filters: &ConfiguredFilters) -> Result<(), LogFilterError> {
+        let mut slots = self.lock();
+        let mut changed = Vec::new();
+        for slot in slots.iter_mut() {
+            let Some(filter) = filters.for_output(slot.output) else {
+                continue;
+            };
+            if slot.configured == filter {
+                continue;
+            }
+            if slot.current == slot.configured {
+                (slot.reload)(EnvFilter::try_new(filter)?)?;
+                slot.current = filter.to_string();
+            }
+            slot.configured = filter.to_string();
+            changed.push((slot.output, filter.to_string()));
+        }
+        drop(slots);
+        for (output, filter) in changed {
+            info!(output, filter = %filter, source = filters.source.label(), "configured log filter changed");
+        }
+        Ok(())
+    }
+
+    /// 在配置的过滤器与 `debug` 之间切换 (SIGUSR1)
+    pub fn toggle_debug(&self) -> Result<(), LogFilterError> {
+        if self.state().overridden {
+            self.reset()
+        } else {
+            self.set("debug")
+        }
+    }
+
+    /// 各输出当前的过滤器
+    pub fn state(&self) -> LogFilterState {
+        let slots = self.lock();
+        LogFilterState {
+            filters: slots
+                .iter()
+                .map(|slot| (slot.output, slot.current.clone()))
+                .collect(),
+            overridden: slots.iter().any(|slot| slot.current != slot.configured),
+        }
+    }
+
+    /// 重新读取配置文件中的 `server.log_level`/`server.console_log_level`
+    /// (SIGHUP 或 `POST /admin/log_level/reload`)
+    ///
+    /// 设置了 `RUST_LOG` 时它仍然优先，不做改变；读取或解析失败时保持原来的过滤器。
+    pub fn reload_config(&self) -> Result<(), LogFilterError> {
+        let path = self
+            .config_path
+            .as_deref()
+            .ok_or(LogFilterError::NoConfigFile)?;
+        let config = Config::load(path).map_err(LogFilterError::Config)?;
+        let filters = ConfiguredFilters::from_env(&config.server)?;
+        if filters.source == FilterSource::Env {
+            info!("RUST_LOG is set, ignoring log levels from {}", path);
+            return Ok(());
+        }
+        self.reconfigure(&filters)
+    }
+
+    fn apply(&self, filter_for: impl Fn(&FilterSlot) -> String) -> Result<(), LogFilterError> {
+        let mut slots = self.lock();
+        for slot in slots.iter_mut() {
+            let filter = filter_for(slot);
+            (slot.reload)(EnvFilter::try_new(&filter)?)?;
+            slot.current = filter;
+        }
+        drop(slots);
+        // 在新的过滤器下记录，调高级别时也能看到
+        for (output, filter) in self.state().filters {
+            info!(output, filter = %filter, "log filter changed");
+        }
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<FilterSlot>> {
+        self.slots.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// 每次收到 SIGHUP 时重新读取配置文件中的日志级别 (见 [`LogFilter::reload_config`]；
+/// 其它配置需要重启才生效)
+#[cfg(unix)]
+pub fn spawn_sighup_reload(log_filter: Arc<LogFilter>) -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signals = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while signals.recv().await.is_some() {
+            if let Err(e) = log_filter.reload_config() {
+                warn!(error = %e, "failed to reload log levels from config");
+            }
+        }
+    });
+    Ok(())
+}
+
+/// 每次收到 SIGUSR1 时在配置的过滤器与 `debug` 之间切换 (无需 HTTP 端点)
+#[cfg(unix)]
+pub fn spawn_sigusr1_toggle(log_filter: Arc<LogFilter>) -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signals = signal(SignalKind::user_defined1())?;
+    tokio::spawn(async move {
+        while signals.recv().await.is_some() {
+            if let Err(e) = log_filter.toggle_debug() {
+                warn!(error = %e, "failed to toggle log filter");
+            }
+        }
+    });
+    Ok(())
+}
+
+/// 是否输出一条警告；`reason` 是低基数的分类，`source` 为客户端地址 (与来源无关的
+/// 失败传 None)。返回 false 时调用方跳过这条日志，由限流器稍后汇总
+pub fn allow_warning(reason: &'static str, source: Option<IpAddr>) -> bool {
+    WARNINGS.allow(reason, source, Instant::now())
+}
+
+/// 限流的粒度：IPv4 按 /24，IPv6 按 /64
+fn source_prefix(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(v4) => IpAddr::V4(Ipv4Addr::from(v4.to_bits() & !0xff)),
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => source_prefix(IpAddr::V4(v4)),
+            None => IpAddr::V6(Ipv6Addr::from(v6.to_bits() & !u128::from(u64::MAX))),
+        },
+    }
+}
+
+type SuppressKey = (&'static str, Option<IpAddr>);
+
+#[derive(Debug)]
+struct SuppressWindow {
+    started: Instant,
+    suppressed: u64,
+}
+
+#[derive(Debug)]
+struct WarnLimiterInner {
+    windows: HashMap<SuppressKey, SuppressWindow>,
+    last_sweep: Instant,
+}
+
+/// 按 (原因, 来源网段) 限流的警告计数器，见 [`allow_warning`]
+#[derive(Debug)]
+struct WarnLimiter {
+    window: Duration,
+    inner: Mutex<WarnLimiterInner>,
+}
+
+impl WarnLimiter {
+    fn new(window: Duration) -> Self {
+        Self {
+            window,
+            inner: Mutex::new(WarnLimiterInner {
+                windows: HashMap::new(),
+                last_sweep: Instant::now(),
+            }),
+        }
+    }
+
+    fn allow(&self, reason: &'static str, source: Option<IpAddr>, now: Instant) -> bool {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        if now.saturating_duration_since(inner.last_sweep) >= self.window {
+            self.sweep(&mut inner, now);
+        }
+
+        let mut key = (reason, source.map(source_prefix));
+        if !inner.windows.contains_key(&key) && inner.windows.len() >= MAX_SUPPRESS_KEYS {
+            key.1 = None;
+        }
+        match inner.windows.get_mut(&key) {
+            None => {
+                inner.windows.insert(
+                    key,
+                    SuppressWindow {
+                        started: now,
+                        suppressed: 0,
+                    },
+                );
+                true
+            }
+            Some(window) if now.saturating_duration_since(window.started) < self.window => {
+                window.suppressed += 1;
+                false
+            }
+            Some(window) => {
+                self.report(key, window, now);
+                *window = SuppressWindow {
+                    started: now,
+                    suppressed: 0,
+                };
+                true
+            }
+        }
+    }
+
+    /// 汇总并移除已结束的窗口，不再出现的来源不会一直占用内存
+    fn sweep(&self, inner: &mut WarnLimiterInner, now: Instant) {
+        inner.last_sweep = now;
+        inner.windows.retain(|key, window| {
+            if now.saturating_duration_since(window.started) < self.window {
+                return true;
+            }
+            self.report(*key, window, now);
+            false
+        });
+    }
+
+    fn report(&self, (reason, source): SuppressKey, window: &SuppressWindow, now: Instant) {
+        if window.suppressed == 0 {
+            return;
+        }
+        warn!(
+            reason,
+            source = source.map(tracing::field::display),
+            suppressed = window.suppressed,
+            window_secs = now.saturating_duration_since(window.started).as_secs(),
+            "suppressed similar warnings"
+        );
+    }
+}
+
+/// 捕获日志事件的 subscriber，供测试断言事件字段
+#[cfg(test)]
+pub(crate) mod capture {
+    use std::collections::BTreeMap;
+    use std::fmt;
+    use std::sync::{Arc, Mutex};
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Level, Subscriber};
+    use tracing_subscriber::layer::{Context, SubscriberExt};
+    use tracing_subscriber::registry::LookupSpan;
+    use tracing_subscriber::Layer;
+
+    /// 一个事件及其所在 span 的全部字段 (事件字段优先)
+    #[derive(Debug, Clone)]
+    pub struct CapturedEvent {
+        pub level: Level,
+        pub message: String,
+        pub fields: BTreeMap<String, String>,
+    }
+
+    impl CapturedEvent {
+        pub fn field(&self, name: &str) -> Option<&str> {
+            self.fields.get(name).map(String::as_str)
+        }
+    }
+
+    #[derive(Default)]
+    struct Fields(BTreeMap<String, String>);
+
+    impl Visit for Fields {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.insert(field.name().to_string(), value.to_string());
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0
+                .insert(field.name().to_string(), format!("{:?}", value));
+        }
+    }
+
+    struct CaptureLayer(Arc<Mutex<Vec<CapturedEvent>>>);
+
+    impl<S> Layer<S> for CaptureLayer
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+            let mut fields = Fields::default();
+            attrs.record(&mut fields);
+            if let Some(span) = ctx.span(id) {
+                span.extensions_mut().insert(fields);
+            }
+        }
+
+        fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+            if let Some(span) = ctx.span(id) {
+                if let Some(fields) = span.extensions_mut().get_mut::<Fields>() {
+                    values.record(fields);
+                }
+            }
+        }
+
+        fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+            let mut fields = Fields::default();
+            if let Some(scope) = ctx.event_scope(event) {
+                for span in scope.from_root() {
+                    if let Some(span_fields) = span.extensions().get::<Fields>() {
+                        fields.0.extend(span_fields.0.clone());
+                    }
+                }
+            }
+            event.record(&mut fields);
+            let message = fields.0.remove("message").unwrap_or_default();
+            self.0.lock().unwrap().push(CapturedEvent {
+                level: *event.metadata().level(),
+                message,
+                fields: fields.0,
+            });
+        }
+    }
+
+    /// 在当前线程上捕获事件，守卫存活期间有效 (`#[tokio::test]` 的单线程运行时中
+    /// 包括 spawn 出的任务)
+    pub fn capture() -> (
+        Arc<Mutex<Vec<CapturedEvent>>>,
+        tracing::subscriber::DefaultGuard,
+    ) {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let subscriber = tracing_subscriber::registry().with(CaptureLayer(Arc::clone(&events)));
+        (events, tracing::subscriber::set_default(subscriber))
+    }
+
+    /// 同 [`capture`]，但只捕获 `log_filter` 中名为 `capture` 的输出放行的事件
+    pub fn capture_filtered(
+        log_filter: &super::LogFilter,
+        configured: &str,
+    ) -> (
+        Arc<Mutex<Vec<CapturedEvent>>>,
+        tracing::subscriber::DefaultGuard,
+    ) {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let layer =
+            CaptureLayer(Arc::clone(&events)).with_filter(log_filter.layer("capture", configured));
+        let subscriber = tracing_subscriber::registry().with(layer);
+        (events, tracing::subscriber::set_default(subscriber))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::capture::capture;
+    use super::*;
+    use tracing::Instrument;
+
+    #[test]
+    fn log_filter_reloads_at_runtime() {
+        let log_filter = LogFilter::default();
+        let (events, _guard) = capture::capture_filtered(&log_filter, "info");
+        let messages = || {
+            events
+                .lock()
+                .unwrap()
+                .drain(..)
+                .map(|event| event.message)
+                .collect::<Vec<_>>()
+        };
+
+        tracing::debug!("hidden");
+        tracing::info!("shown");
+        assert_eq!(messages(), ["shown"]);
+
+        log_filter.set(" info,sniproxy_ng::logging=trace ").unwrap();
+        tracing::trace!("module trace");
+        let state = log_filter.state();
+        assert!(state.overridden);
+        assert_eq!(state.filters["capture"], "info,sniproxy_ng::logging=trace");
+        assert_eq!(messages(), ["log filter changed", "module trace"]);
+
+        // 无效的过滤器不改变当前设置
+        assert!(matches!(
+            log_filter.set("sniproxy_ng=loud"),
+            Err(LogFilterError::Parse(_))
+        ));
+        assert!(log_filter.state().overridden);
+
+        // SIGUSR1：恢复配置 → 切到 debug → 再恢复
+        log_filter.toggle_debug().unwrap();
+        assert!(!log_filter.state().overridden);
+        tracing::debug!("hidden");
+        log_filter.toggle_debug().unwrap();
+        assert_eq!(log_filter.state().filters["capture"], "debug");
+        tracing::debug!("debug shown");
+        assert_eq!(
+            messages(),
+            ["log filter changed", "log filter changed", "debug shown"]
+        );
+    }
+
+    fn server_config(log_level: &str, console_log_level: &str) -> ServerConfig {
+        let mut config: Config =
+            toml::from_str("[server]\n[socks5]\naddr = \"127.0.0.1:1080\"").unwrap();
+        config.server.log_level = log_level.to_string();
+        config.server.console_log_level = console_log_level.to_string();
+        config.server
+    }
+
+    #[test]
+    fn configured_filters_come_from_config_unless_rust_log_is_set() {
+        let server = server_config("info,sniproxy_ng::quic=debug", "warn");
+        let filters = ConfiguredFilters::resolve(None, &server).unwrap();
+        assert_eq!(filters.source, FilterSource::Config);
+        assert_eq!(filters.console, "warn");
+        assert_eq!(filters.file, "info,sniproxy_ng::quic=debug");
+        let directives = EnvFilter::try_new(&filters.file).unwrap().to_string();
+        assert!(
+            directives.contains("sniproxy_ng::quic=debug"),
+            "{}",
+            directives
+        );
+        assert!(directives.contains("info"), "{}", directives);
+
+        // 空的 RUST_LOG 视为未设置
+        assert_eq!(
+            ConfiguredFilters::resolve(Some(" "), &server).unwrap(),
+            filters
+        );
+
+        let filters = ConfiguredFilters::resolve(Some("sniproxy_ng=trace"), &server).unwrap();
+        assert_eq!(
+            filters,
+            ConfiguredFilters {
+                console: "sniproxy_ng=trace".to_string(),
+                file: "sniproxy_ng=trace".to_string(),
+                source: FilterSource::Env,
+            }
+        );
+
+        let error =
+            ConfiguredFilters::resolve(None, &server_config("info,sniproxy_ng=loud", "warn"))
+                .unwrap_err()
+                .to_string();
+        assert!(
+            error.starts_with("invalid server.log_level \"info,sniproxy_ng=loud\""),
+            "{}",
+            error
+        );
+        let error = ConfiguredFilters::resolve(Some("=="), &server)
+            .unwrap_err()
+            .to_string();
+        assert!(error.starts_with("invalid RUST_LOG"), "{}", error);
+    }
+
+    #[test]
+    fn reconfigure_keeps_runtime_overrides() {
+        let log_filter = LogFilter::default();
+        let (events, _guard) = capture::capture_filtered(&log_filter, "info");
+        let _console = log_filter.layer::<tracing_subscriber::Registry>("console", "warn");
+        let _file = log_filter.layer::<tracing_subscriber::Registry>("file", "info");
+
+        let reloaded =
+            |file: &str| ConfiguredFilters::resolve(None, &server_config(file, "error")).unwrap();
+        log_filter.reconfigure(&reloaded("debug")).unwrap();
+        let state = log_filter.state();
+        assert_eq!(state.filters["console"], "error");
+        assert_eq!(state.filters["file"], "debug");
+        // 不属于 console/file 的输出不受影响
+        assert_eq!(state.filters["capture"], "info");
+        assert!(!state.overridden);
+        assert_eq!(
+            events
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|event| event.message == "configured log filter changed")
+                .count(),
+            2
+        );
+
+        // 运行时替换的过滤器保持到 reset，reset 后使用新配置
+        log_filter.set("trace").unwrap();
+        log_filter
+            .reconfigure(&reloaded("warn,sniproxy_ng=info"))
+            .unwrap();
+        assert_eq!(log_filter.state().filters["file"], "trace");
+        log_filter.reset().unwrap();
+        let state = log_filter.state();
+        assert_eq!(state.filters["file"], "warn,sniproxy_ng=info");
+        assert_eq!(state.filters["console"], "error");
+    }
+
+    fn ip(s: &str) -> Option<IpAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    #[test]
+    fn repeated_warnings_are_counted_and_summarized() {
+        let (events, _guard) = capture();
+        let limiter = WarnLimiter::new(SUPPRESS_WINDOW);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert!(limiter.allow("decryption", ip("192.0.2.1"), at(0)));
+        // 同一 /24 内的其它地址与第一条合并
+        for i in 0..5 {
+            assert!(!limiter.allow("decryption", ip(&format!("192.0.2.{}", 10 + i)), at(1)));
+        }
+        // 不同的原因或网段各自计数
+        assert!(limiter.allow("tls", ip("192.0.2.1"), at(2)));
+        assert!(limiter.allow("decryption", ip("198.51.100.1"), at(2)));
+        assert!(limiter.allow("decryption", ip("2001:db8:0:1::1"), at(1)));
+        assert!(!limiter.allow("decryption", ip("2001:db8:0:1::2"), at(2)));
+        assert!(events.lock().unwrap().is_empty());
+
+        // 窗口结束后的第一条放行，并先汇总上一个窗口
+        assert!(limiter.allow("decryption", ip("192.0.2.1"), at(31)));
+        assert!(!limiter.allow("decryption", ip("192.0.2.1"), at(32)));
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 2, "{:#?}", events);
+        let summary = events
+            .iter()
+            .find(|event| event.field("source") == Some("192.0.2.0"))
+            .unwrap();
+        assert_eq!(summary.message, "suppressed similar warnings");
+        assert_eq!(summary.level, tracing::Level::WARN);
+        assert_eq!(summary.field("reason"), Some("decryption"));
+        assert_eq!(summary.field("suppressed"), Some("5"));
+        assert_eq!(summary.field("window_secs"), Some("31"));
+        // 其余结束的窗口由清理一并汇总；没有压下任何警告的窗口不输出
+        let swept = events
+            .iter()
+            .find(|event| event.field("source") == Some("2001:db8:0:1::"))
+            .unwrap();
+        assert_eq!(swept.field("suppressed"), Some("1"));
+    }
+
+    #[test]
+    fn expired_windows_are_swept() {
+        let (_events, _guard) = capture();
+        let limiter = WarnLimiter::new(SUPPRESS_WINDOW);
+        let start = Instant::now();
+        for i in 0..100u32 {
+            let source = Some(IpAddr::V4(Ipv4Addr::from(i << 8)));
+            assert!(limiter.allow("tls", source, start));
+        }
+        assert_eq!(limiter.inner.lock().unwrap().windows.len(), 100);
+
+        assert!(limiter.allow("accept", None, start + SUPPRESS_WINDOW));
+        let inner = limiter.inner.lock().unwrap();
+        assert_eq!(inner.windows.len(), 1);
+        assert!(inner.windows.contains_key(&("accept", None)));
+    }
+
+    #[tokio::test]
+    async fn span_fields_are_attached_to_events() {
+        let (events, _guard) = capture();
+        let client: SocketAddr = "192.0.2.1:40000".parse().unwrap();
+        let conn_id = next_conn_id();
+        async {
+            tracing::debug!("before sni");
+            record_sni("www.example.com");
+            tracing::info!(outcome = "established", "route established");
+        }
+        .instrument(connection_span(Listener::Tcp, conn_id, client))
+        .await;
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].field("sni"), None);
+        let event = &events[1];
+        assert_eq!(event.message, "route established");
+        assert_eq!(event.field("listener"), Some("tcp"));
+        assert_eq!(event.field("conn_id"), Some(conn_id.to_string().as_str()));
+        assert_eq!(event.field("client"), Some("192.0.2.1:40000"));
+        assert_eq!(event.field("sni"), Some("www.example.com"));
+        assert_eq!(event.field("outcome"), Some("established"));
+        assert!(next_conn_id() > conn_id);
+    }
+}
@@ -1,183 +1,200 @@
-mod config;
-mod http;
-mod quic;
-mod relay;
-mod router;
-mod socks5;
-mod tcp;
-mod tls;
-
-use anyhow::Result;
+use anyhow::{Context, Result};
+#[cfg(unix)]
+use sniproxy_ng::sd_notify;
+use sniproxy_ng::{access, build_info, logging, quic, signals, Config, Server};
 use std::path::Path;
+use std::time::Duration;
+use tokio::sync::mpsc;
 use tracing::{error, info, warn};
 use tracing_appender::non_blocking::WorkerGuard;
+use tracing_subscriber::filter::{filter_fn, FilterExt};
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
-use config::Config;
+#[cfg(windows)]
+mod service;
+
+/// 配置文件路径 (相对于工作目录)
+const CONFIG_PATH: &str = "config.toml";
+
+/// 收到关闭信号后等待连接排空的最长时间，再次收到信号时立即退出
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+fn main() -> Result<()> {
+    // 子命令：sniproxy-ng decode <file>
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("decode") {
+        let Some(path) = args.get(1) else {
+            eprintln!("Usage: sniproxy-ng decode <file>");
+            std::process::exit(2);
+        };
+        return decode(Path::new(path));
+    }
+    // sniproxy-ng --version [--verbose]：只输出构建信息，不读取配置、不启动监听器
+    if args.iter().any(|arg| arg == "--version" || arg == "-V") {
+        let info = build_info::BuildInfo::current();
+        if args.iter().any(|arg| arg == "--verbose" || arg == "-v") {
+            print!("{}", info.verbose());
+        } else {
+            println!("sniproxy-ng {}", info.version);
+        }
+        return Ok(());
+    }
+    // sniproxy-ng --service：由 Windows 服务控制管理器启动
+    #[cfg(windows)]
+    if args.first().map(String::as_str) == Some("--service") {
+        return service::run();
+    }
 
-#[tokio::main]
-async fn main() -> Result<()> {
     // 加载配置
-    let config = match Config::load("config.toml") {
+    // 日志要按配置初始化，在此之前的错误直接输出到 stderr
+    let config = match Config::load(CONFIG_PATH) {
         Ok(c) => c,
         Err(e) => {
-            eprintln!("Error: Failed to load config.toml: {}", e);
+            eprintln!("Error: Failed to load {}: {}", CONFIG_PATH, e);
             eprintln!("Please create config.toml based on config.toml.example");
             std::process::exit(1);
         }
     };
 
-    let _log_guard = init_logging(&config)?;
-
-    info!("Starting sniproxy-ng...");
-    info!("Configuration loaded successfully");
-
-    info!("SOCKS5 backend: {}", config.socks5.addr);
-    if config.rules.allow.is_empty() {
-        info!("Whitelist: allowing all domains (no rules configured)");
-    } else {
-        info!("Whitelist: {} domain patterns", config.rules.allow.len());
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    let failed = runtime.block_on(async {
+        let (stop_tx, stop_rx) = mpsc::unbounded_channel();
+        signals::ShutdownSignals::new()
+            .context("Failed to install shutdown signal handlers")?
+            .forward(stop_tx);
+        run(config, stop_rx, || {}, || {}).await
+    })?;
+    if failed {
+        std::process::exit(1);
     }
+    Ok(())
+}
 
-    // 创建路由器
-    let router = std::sync::Arc::new(router::Router::new(config.clone()));
-    let mut tasks = Vec::new();
-
-    // HTTPS 监听器 (TCP + QUIC)
-    if let Some(addr) = config.server.listen_https_addr {
-        info!("HTTPS listener configured on {}", addr);
+/// 运行代理，直到 `stop` 收到关闭请求 (发起关闭的信号名) 或有监听器重启失败；
+/// 返回是否因失败而退出
+///
+/// 监听 socket 全部绑定后调用 `on_ready`，开始关闭时调用 `on_stopping`。关闭期间再次收到
+/// 请求时不再等待连接排空。
+async fn run(
+    config: Config,
+    mut stop: mpsc::UnboundedReceiver<&'static str>,
+    on_ready: impl FnOnce(),
+    on_stopping: impl FnOnce(),
+) -> Result<bool> {
+    let (_log_guards, log_filter) = init_logging(&config)?;
+    #[cfg(unix)]
+    if let Err(e) = logging::spawn_sigusr1_toggle(log_filter.clone()) {
+        warn!(
+            "Failed to install SIGUSR1 handler for log level toggling: {}",
+            e
+        );
+    }
+    #[cfg(unix)]
+    if let Err(e) = logging::spawn_sighup_reload(log_filter.clone()) {
+        warn!(
+            "Failed to install SIGHUP handler for log level reload: {}",
+            e
+        );
+    }
 
-        // 检查端口是否需要权限
-        if addr.port() < 1024 {
-            warn!(
-                "Warning: Port {} requires root privileges. Run with sudo if binding fails.",
-                addr.port()
-            );
-        }
+    info!("Starting {}", build_info::BuildInfo::current());
+    info!("Configuration loaded successfully");
 
-        let https_config = config.clone();
-        // TCP 监听器
-        let tcp_config = https_config.clone();
-        tasks.push(tokio::spawn(async move {
-            if let Err(e) = tcp::run(tcp_config).await {
-                error!("TCP listener error: {}", e);
-            }
-        }));
-
-        // UDP 监听器 (QUIC/HTTP3)
-        match should_start_quic(&https_config).await {
-            Ok(true) => {
-                tasks.push(tokio::spawn(async move {
-                    if let Err(e) = quic::run(https_config).await {
-                        error!("QUIC listener error: {}", e);
-                    }
-                }));
-            }
-            Ok(false) => {
-                info!("QUIC/HTTP3 listener disabled; clients should fall back to HTTPS/TCP");
-            }
-            Err(e) => {
-                error!("QUIC startup check failed: {}", e);
-            }
+    #[cfg(unix)]
+    let stats_interval = Duration::from_secs(config.server.stats_log_interval);
+    let server = match Server::new(config).await {
+        Ok(server) => server.with_log_filter(log_filter),
+        Err(e) => {
+            error!("{}", e);
+            return Ok(true);
         }
-    }
+    };
+    let mut handle = server.start();
+    on_ready();
 
-    // HTTP 监听器
-    if let Some(addr) = config.server.listen_http_addr {
-        info!("HTTP listener configured on {}", addr);
+    // systemd Type=notify：监听 socket 已全部绑定 (QUIC 的 UDP relay 探测也已完成)
+    #[cfg(unix)]
+    let notifier = sd_notify::Notifier::from_env().map(std::sync::Arc::new);
+    #[cfg(unix)]
+    let notify_task = notifier.as_ref().and_then(|notifier| {
+        notifier.ready();
+        let watchdog = sd_notify::watchdog_interval(
+            std::env::var("WATCHDOG_USEC").ok().as_deref(),
+            std::env::var("WATCHDOG_PID").ok().as_deref(),
+        );
+        sd_notify::spawn(
+            notifier.clone(),
+            handle.metrics().clone(),
+            stats_interval,
+            watchdog,
+        )
+    });
 
-        // 检查端口是否需要权限
-        if addr.port() < 1024 {
-            warn!(
-                "Warning: Port {} requires root privileges. Run with sudo if binding fails.",
-                addr.port()
-            );
+    // 监听器重启失败时同样关闭，并以非零状态退出，交由 systemd/k8s 重启进程
+    let failed = tokio::select! {
+        Some(signal) = stop.recv() => {
+            info!(signal, "Received shutdown signal, draining connections...");
+            None
         }
-
-        let http_config = config.clone();
-        let http_router = router.clone();
-        tasks.push(tokio::spawn(async move {
-            if let Err(e) = http::run(http_config, http_router).await {
-                error!("HTTP listener error: {}", e);
-            }
-        }));
+        result = handle.wait() => result.err(),
+    };
+    if let Some(e) = &failed {
+        error!("{}, shutting down", e);
     }
-
-    // 检查是否至少配置了一个监听器
-    if tasks.is_empty() {
-        anyhow::bail!(
-            "No listener configured. Please set listen_https_addr or listen_http_addr in config."
-        );
+    on_stopping();
+    #[cfg(unix)]
+    if let Some(notifier) = &notifier {
+        notifier.stopping();
     }
 
-    // 设置 Ctrl+C 信号处理
-    let ctrl_c = tokio::signal::ctrl_c();
-
+    // 通知监听器停止接受新连接，等待 QUIC 会话、TCP 连接池和已建立的连接关闭
     tokio::select! {
-        // Ctrl+C 信号
-        _ = ctrl_c => {
-            info!("Received shutdown signal, shutting down...");
-        }
-        // 等待任意任务结束
-        result = async {
-            for task in tasks {
-                task.await.ok();
-            }
-            Ok::<(), anyhow::Error>(())
-        } => {
+        result = handle.shutdown(SHUTDOWN_TIMEOUT) => {
             if let Err(e) = result {
-                error!("Task error: {}", e);
+                warn!("{}", e);
             }
         }
+        Some(signal) = stop.recv() => {
+            warn!(signal, "Received second shutdown signal, exiting immediately");
+        }
     }
 
+    #[cfg(unix)]
+    if let Some(task) = notify_task {
+        task.abort();
+    }
     info!("sniproxy-ng shutdown complete");
-    Ok(())
+    Ok(failed.is_some())
 }
 
-async fn should_start_quic(config: &Config) -> Result<bool> {
-    let mode = std::env::var("SNIPROXY_QUIC_MODE")
-        .unwrap_or_else(|_| config.server.quic_mode.clone());
-    info!("QUIC/HTTP3 startup mode: {}", mode);
-
-    match mode.as_str() {
-        "off" => Ok(false),
-        "on" => {
-            match quic::session::probe_socks5_udp_relay(&config.socks5).await {
-                Ok(()) => {
-                    info!("SOCKS5 UDP relay probe succeeded; forcing QUIC/HTTP3 on");
-                }
-                Err(e) => {
-                    warn!(
-                        "SOCKS5 UDP relay probe failed, but SNIPROXY_QUIC_MODE=on; starting QUIC anyway: {}",
-                        e
-                    );
-                }
-            }
-            Ok(true)
-        }
-        "auto" => match quic::session::probe_socks5_udp_relay(&config.socks5).await {
-            Ok(()) => {
-                info!("SOCKS5 UDP relay probe succeeded; enabling QUIC/HTTP3");
-                Ok(true)
-            }
-            Err(e) => {
-                warn!(
-                    "SOCKS5 UDP relay probe failed; disabling QUIC/HTTP3 so clients can fall back to HTTPS/TCP: {}",
-                    e
-                );
-                Ok(false)
-            }
-        },
-        other => anyhow::bail!(
-            "Invalid SNIPROXY_QUIC_MODE '{}'; expected auto, on, or off",
-            other
-        ),
+/// 在 trace 日志下重放转储的 QUIC Initial 的 SNI 提取 (见 `quic.debug_dump_dir`)
+fn decode(path: &Path) -> Result<()> {
+    let filter = std::env::var("RUST_LOG")
+        .map(EnvFilter::new)
+        .unwrap_or_else(|_| EnvFilter::new("trace"));
+    tracing_subscriber::registry()
+        .with(fmt::layer().compact().with_writer(std::io::stderr))
+        .with(filter)
+        .init();
+    quic::crypto::set_debug_crypto(true);
+
+    if let Some(meta) = quic::dump::read_meta(path)? {
+        println!(
+            "src={} timestamp_ms={} error={}",
+            meta.src, meta.timestamp_ms, meta.error
+        );
     }
+    let extraction = quic::dump::decode_dump(path)?;
+    println!("{:?}", extraction);
+    Ok(())
 }
 
-/// 初始化日志系统
-fn init_logging(config: &Config) -> Result<WorkerGuard> {
+/// 初始化日志系统，返回需保持到退出的写入 guard 和可在运行时替换过滤器的 [`logging::LogFilter`]
+///
+/// 配置了 `[logging.access]` 时访问记录只写入访问日志，不进入控制台和主日志文件。
+fn init_logging(config: &Config) -> Result<(Vec<WorkerGuard>, std::sync::Arc<logging::LogFilter>)> {
     let log_path = Path::new(&config.server.log_file);
     let log_dir = log_path.parent().filter(|p| !p.as_os_str().is_empty());
     if let Some(dir) = log_dir {
@@ -192,15 +209,27 @@ fn init_logging(config: &Config) -> Result<WorkerGuard> {
         tracing_appender::rolling::never(log_dir.unwrap_or_else(|| Path::new(".")), file_name);
     let (file_writer, guard) = tracing_appender::non_blocking(appender);
 
-    let rust_log = std::env::var(EnvFilter::DEFAULT_ENV).ok();
-    let file_filter = rust_log
-        .as_deref()
-        .map(EnvFilter::new)
-        .unwrap_or_else(|| EnvFilter::new(config.server.log_level.clone()));
-    let console_filter = rust_log
-        .as_deref()
-        .map(EnvFilter::new)
-        .unwrap_or_else(|| EnvFilter::new(config.server.console_log_level.clone()));
+    let filters = logging::ConfiguredFilters::from_env(&config.server)?;
+    let log_filter = std::sync::Arc::new(logging::LogFilter::with_config_path(CONFIG_PATH));
+    let console_level = filters.console.as_str();
+    let file_level = filters.file.as_str();
+
+    let (access_writer, access_guard) = config
+        .logging
+        .access
+        .as_ref()
+        .map(access::writer)
+        .transpose()?
+        .unzip();
+    let access_format = config
+        .logging
+        .access
+        .as_ref()
+        .map(|access| access.format)
+        .unwrap_or_default();
+    let separate_access = access_writer.is_some();
+    let app_only =
+        move |metadata: &tracing::Metadata<'_>| !(separate_access && access::is_access(metadata));
 
     match config.server.log_format.as_str() {
         "json" => {
@@ -209,17 +238,26 @@ fn init_logging(config: &Config) -> Result<WorkerGuard> {
                 .with_writer(std::io::stderr)
                 .with_target(false)
                 .with_thread_ids(false)
-                .with_filter(console_filter);
+                .with_filter(
+                    log_filter
+                        .layer("console", console_level)
+                        .and(filter_fn(app_only)),
+                );
             let file_layer = fmt::layer()
                 .json()
                 .with_writer(file_writer)
                 .with_target(false)
                 .with_thread_ids(true)
-                .with_filter(file_filter);
+                .with_filter(
+                    log_filter
+                        .layer("file", file_level)
+                        .and(filter_fn(app_only)),
+                );
 
             tracing_subscriber::registry()
                 .with(console_layer)
                 .with(file_layer)
+                .with(access_writer.map(|writer| access::layer(access_format, writer)))
                 .init();
         }
         _ => {
@@ -228,19 +266,37 @@ fn init_logging(config: &Config) -> Result<WorkerGuard> {
                 .with_writer(std::io::stderr)
                 .with_target(false)
                 .with_thread_ids(false)
-                .with_filter(console_filter);
+                .with_filter(
+                    log_filter
+                        .layer("console", console_level)
+                        .and(filter_fn(app_only)),
+                );
             let file_layer = fmt::layer()
                 .with_writer(file_writer)
                 .with_target(false)
                 .with_thread_ids(true)
-                .with_filter(file_filter);
+                .with_filter(
+                    log_filter
+                        .layer("file", file_level)
+                        .and(filter_fn(app_only)),
+                );
 
             tracing_subscriber::registry()
                 .with(console_layer)
                 .with(file_layer)
+                .with(access_writer.map(|writer| access::layer(access_format, writer)))
                 .init();
         }
     }
 
-    Ok(guard)
+    info!(
+        source = filters.source.label(),
+        console = %filters.console,
+        file = %filters.file,
+        "log filter configured"
+    );
+    Ok((
+        std::iter::once(guard).chain(access_guard).collect(),
+        log_filter,
+    ))
 }
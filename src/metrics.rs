@@ -0,0 +1,1501 @@
+//! Prometheus 指标
+//!
+//! [`Registry`] 保存各监听器的计数 (接受的连接、SNI 提取结果、按原因的拒绝与失败、转发字节数)，
+//! 以 `Arc<Registry>` 交给 TCP、HTTP 和 QUIC 监听器，记录只是原子加减。
+//! 长期运行的任务 (监听器、清理任务、QUIC 会话、relay) 以 [`Registry::track_task`]
+//! 返回的守卫计入按类别的任务数，泄漏表现为持续增长的 `sniproxy_tasks`。
+//! 其它模块已经自行维护的状态 (连接池、QUIC 会话、SOCKS5 握手耗时) 不重复计数，
+//! 而是注册 collector，在抓取时读取并输出；周期性统计日志 (见 [`crate::stats`])
+//! 同样以注册的来源读取其中的一部分。
+//!
+//! 推送式的输出 (statsd，见 [`crate::statsd`]) 定期以 [`Registry::samples`] 读取注册表自身的
+//! 计数，并以 [`Registry::add_sink`] 注册的 [`Sink`] 接收每一次耗时观测。
+//!
+//! 设置了 `metrics.listen_addr` 时，[`run`] 在该地址以文本格式 (exposition format 0.0.4)
+//! 提供 `GET /metrics`，同时提供供编排系统探测的 `GET /healthz` (进程存活) 与
+//! `GET /readyz` (见 [`Readiness`])，以及按域名的流量排行 `GET /domains/top?n=50`
+//! (见 [`crate::domains`])。`GET /quic/sessions` 以 JSON 数组返回当前的 QUIC 会话
+//! (见 [`QuicSessionSnapshot`](crate::quic::session::QuicSessionSnapshot))，`GET /pool`
+//! 返回 SOCKS5 连接池的统计 (见 [`PoolStats`](crate::socks5::pool::PoolStats))，
+//! `GET /upstreams` 返回各上游的健康状态 (见 [`UpstreamHealth`](crate::upstream::UpstreamHealth))。
+//! `GET /admin/log_level` 返回当前的日志过滤器，
+//! `PUT /admin/log_level` 以请求体中的过滤器替换，`DELETE /admin/log_level` 恢复配置的过滤器，
+//! `POST /admin/log_level/reload` 重新读取配置文件中的日志级别 (见 [`LogFilter`])。
+//! `GET /status` 返回构建信息和脱敏后的配置摘要 (见 [`crate::build_info`])。
+
+use crate::domains::{DomainStats, DomainTraffic, METRIC_DOMAINS};
+use crate::health::Readiness;
+use crate::logging::LogFilter;
+use crate::reason::{FailureReason, Outcome, RejectReason};
+use crate::socks5::metrics::{LatencyMetrics, BUCKET_BOUNDS_MS};
+use crate::stats::Totals;
+use crate::upstream::{BackendState, UpstreamSet};
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::fmt::{self, Write as _};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tracing::{debug, info};
+
+/// 抓取请求的最大字节数，超过后按请求格式错误处理
+const MAX_REQUEST_LEN: usize = 8192;
+
+/// 读取抓取请求的超时
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 指标所属的监听器
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Listener {
+    /// HTTPS (TLS over TCP)
+    Tcp,
+    /// HTTP 明文与 CONNECT
+    Http,
+    /// QUIC/HTTP3
+    Quic,
+}
+
+impl Listener {
+    const ALL: [Listener; 3] = [Listener::Tcp, Listener::Http, Listener::Quic];
+
+    /// `listener` 标签的取值
+    pub fn label(self) -> &'static str {
+        match self {
+            Listener::Tcp => "tcp",
+            Listener::Http => "http",
+            Listener::Quic => "quic",
+        }
+    }
+}
+
+/// 转发方向
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// 客户端 → 上游
+    ClientToUpstream,
+    /// 上游 → 客户端
+    UpstreamToClient,
+}
+
+impl Direction {
+    const ALL: [Direction; 2] = [Direction::ClientToUpstream, Direction::UpstreamToClient];
+
+    /// `direction` 标签的取值
+    pub fn label(self) -> &'static str {
+        match self {
+            Direction::ClientToUpstream => "client_to_upstream",
+            Direction::UpstreamToClient => "upstream_to_client",
+        }
+    }
+}
+
+/// 连接建立过程中的阶段
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// 接受连接 (QUIC 为首个 Initial) → 提取到 SNI
+    Sni,
+    /// 提取到 SNI → 上游连接建立 (QUIC 为 UDP relay 就绪)
+    Connect,
+    /// 上游连接建立 → 收到上游的第一个字节 (仅 TCP)
+    FirstByte,
+}
+
+impl Phase {
+    /// 各监听器记录的阶段
+    const SERIES: [(Listener, Phase); 5] = [
+        (Listener::Tcp, Phase::Sni),
+        (Listener::Tcp, Phase::Connect),
+        (Listener::Tcp, Phase::FirstByte),
+        (Listener::Quic, Phase::Sni),
+        (Listener::Quic, Phase::Connect),
+    ];
+
+    /// `phase` 标签的取值
+    pub fn label(self) -> &'static str {
+        match self {
+            Phase::Sni => "accept_to_sni",
+            Phase::Connect => "sni_to_connected",
+            Phase::FirstByte => "connected_to_first_byte",
+        }
+    }
+}
+
+/// 长期运行的任务类别 (见 [`Registry::track_task`])
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskKind {
+    /// 监听器的接收循环 (含指标端点)
+    Listener,
+    /// 周期清理任务 (连接池维护、QUIC 会话清理)
+    Cleanup,
+    /// QUIC 会话的转发任务
+    QuicSession,
+    /// 共享 SOCKS5 UDP relay 的读取任务
+    Relay,
+    /// 其它后台任务 (统计日志、运行时采样)
+    Background,
+}
+
+impl TaskKind {
+    const ALL: [TaskKind; 5] = [
+        TaskKind::Listener,
+        TaskKind::Cleanup,
+        TaskKind::QuicSession,
+        TaskKind::Relay,
+        TaskKind::Background,
+    ];
+
+    /// `kind` 标签的取值
+    pub fn label(self) -> &'static str {
+        match self {
+            TaskKind::Listener => "listener",
+            TaskKind::Cleanup => "cleanup",
+            TaskKind::QuicSession => "quic_session",
+            TaskKind::Relay => "relay",
+            TaskKind::Background => "background",
+        }
+    }
+}
+
+/// 无锁的固定分桶耗时直方图，分桶与 SOCKS5 握手耗时相同 ([`BUCKET_BOUNDS_MS`])
+#[derive(Debug, Default)]
+struct PhaseHistogram {
+    /// 最后一项为溢出桶
+    buckets: [AtomicU64; BUCKET_BOUNDS_MS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl PhaseHistogram {
+    fn observe(&self, elapsed: Duration) {
+        let index = BUCKET_BOUNDS_MS
+            .iter()
+            .position(|bound| elapsed <= Duration::from_millis(*bound))
+            .unwrap_or(BUCKET_BOUNDS_MS.len());
+        self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+/// 单个监听器的计数
+#[derive(Debug, Default)]
+struct ListenerMetrics {
+    accepted: AtomicU64,
+    active: AtomicU64,
+    sni_success: AtomicU64,
+    sni_failure: AtomicU64,
+    /// 按 [`RejectReason::ALL`] 的顺序
+    rejections: [AtomicU64; RejectReason::ALL.len()],
+    /// 按 [`FailureReason::ALL`] 的顺序
+    failures: [AtomicU64; FailureReason::ALL.len()],
+    /// 按 [`Direction::ALL`] 的顺序
+    bytes: [AtomicU64; 2],
+    /// panic 的连接处理任务数 (见 [`crate::task::spawn_logged`])
+    handler_panics: AtomicU64,
+    /// 本进程是否启动该监听器
+    expected: AtomicBool,
+    /// 监听器是否已绑定并在接受连接
+    bound: AtomicBool,
+    /// 按 [`Phase`] 的顺序
+    phases: [PhaseHistogram; 3],
+}
+
+/// 抓取时执行的 collector，返回已编码的指标文本
+type Collector = Box<dyn Fn() -> Pin<Box<dyn Future<Output = String> + Send>> + Send + Sync>;
+
+/// 推送式的指标输出，除了定期读取 [`Registry::samples`] 外还接收每一次耗时观测
+pub trait Sink: Send + Sync {
+    /// 一个连接阶段的耗时 (与 [`Registry::record_phase`] 同时调用)
+    fn timing(&self, listener: Listener, phase: Phase, elapsed: Duration);
+}
+
+/// 注册表自身的一个计数，名称不带前缀，各段以 `.` 分隔 (例如 `connections.accepted`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sample {
+    pub name: &'static str,
+    /// [`MetricKind::Counter`] 为累计值，[`MetricKind::Gauge`] 为当前值
+    pub kind: MetricKind,
+    pub tags: Vec<(&'static str, &'static str)>,
+    pub value: u64,
+}
+
+/// 统计日志读取的来源，返回累计计数
+type StatsSource = Box<dyn Fn() -> Pin<Box<dyn Future<Output = Totals> + Send>> + Send + Sync>;
+
+/// `GET /quic/sessions`、`GET /upstreams` 读取的 JSON 视图
+type JsonSource = Box<dyn Fn() -> serde_json::Value + Send + Sync>;
+
+/// `GET /pool` 读取的连接池统计
+type PoolSource =
+    Box<dyn Fn() -> Pin<Box<dyn Future<Output = serde_json::Value> + Send>> + Send + Sync>;
+
+/// 指标注册表
+#[derive(Default)]
+pub struct Registry {
+    /// 按 [`Listener::ALL`] 的顺序
+    listeners: [ListenerMetrics; 3],
+    /// 按 [`TaskKind::ALL`] 的顺序
+    tasks: [AtomicU64; 5],
+    collectors: Mutex<Vec<Collector>>,
+    stats_sources: Mutex<Vec<StatsSource>>,
+    quic_sessions: Mutex<Option<JsonSource>>,
+    upstream_health: Mutex<Option<JsonSource>>,
+    pool_stats: Mutex<Option<PoolSource>>,
+    sinks: RwLock<Vec<Arc<dyn Sink>>>,
+    domains: DomainStats,
+}
+
+impl fmt::Debug for Registry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Registry")
+            .field("listeners", &self.listeners)
+            .field("tasks", &self.tasks)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Registry {
+    fn listener(&self, listener: Listener) -> &ListenerMetrics {
+        &self.listeners[listener as usize]
+    }
+
+    /// 记录一个新接受的连接 (QUIC 为新建立的会话)
+    pub fn record_accepted(&self, listener: Listener) {
+        self.listener(listener)
+            .accepted
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 记录一个新接受的连接，并在返回的守卫存活期间计入活动连接数
+    pub fn connection(self: &Arc<Self>, listener: Listener) -> ConnectionGuard {
+        self.record_accepted(listener);
+        self.listener(listener)
+            .active
+            .fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard {
+            registry: Arc::clone(self),
+            listener,
+        }
+    }
+
+    /// 某个监听器当前的活动连接数
+    pub fn active_connections(&self, listener: Listener) -> u64 {
+        self.listener(listener).active.load(Ordering::Relaxed)
+    }
+
+    /// 计入一个运行中的任务，返回的守卫应移入任务中，任务结束 (或被取消) 时减去
+    pub fn track_task(self: &Arc<Self>, kind: TaskKind) -> TaskGuard {
+        self.tasks[kind as usize].fetch_add(1, Ordering::Relaxed);
+        TaskGuard {
+            registry: Arc::clone(self),
+            kind,
+        }
+    }
+
+    /// 某个类别当前运行中的任务数
+    pub fn tasks(&self, kind: TaskKind) -> u64 {
+        self.tasks[kind as usize].load(Ordering::Relaxed)
+    }
+
+    /// 记录一次 SNI 提取结果；`found` 为 false 表示数据无法解析或没有 SNI
+    pub fn record_sni(&self, listener: Listener, found: bool) {
+        let metrics = self.listener(listener);
+        let counter = if found {
+            &metrics.sni_success
+        } else {
+            &metrics.sni_failure
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 记录一次拒绝
+    pub fn record_rejection(&self, listener: Listener, reason: RejectReason) {
+        self.listener(listener).rejections[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 记录一次失败
+    pub fn record_failure(&self, listener: Listener, reason: FailureReason) {
+        self.listener(listener).failures[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 记录一个连接的最终结果，拒绝和失败按原因计数
+    pub fn record_outcome(&self, listener: Listener, outcome: Outcome) {
+        match outcome {
+            Outcome::Closed | Outcome::ClientClosed => {}
+            Outcome::Rejected(reason) => self.record_rejection(listener, reason),
+            Outcome::Failed(reason) => self.record_failure(listener, reason),
+        }
+    }
+
+    /// 某个监听器按原因的拒绝数
+    pub fn rejections(&self, listener: Listener, reason: RejectReason) -> u64 {
+        self.listener(listener).rejections[reason as usize].load(Ordering::Relaxed)
+    }
+
+    /// 某个监听器按原因的失败数
+    pub fn failures(&self, listener: Listener, reason: FailureReason) -> u64 {
+        self.listener(listener).failures[reason as usize].load(Ordering::Relaxed)
+    }
+
+    /// 记录一次 panic 的连接处理任务
+    pub fn record_handler_panic(&self, listener: Listener) {
+        self.listener(listener)
+            .handler_panics
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 某个监听器 panic 的连接处理任务数
+    pub fn handler_panics(&self, listener: Listener) -> u64 {
+        self.listener(listener)
+            .handler_panics
+            .load(Ordering::Relaxed)
+    }
+
+    /// 记录转发的字节数
+    pub fn record_bytes(&self, listener: Listener, direction: Direction, bytes: u64) {
+        self.bytes(listener, direction)
+            .fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// 记录一个连接阶段的耗时
+    pub fn record_phase(&self, listener: Listener, phase: Phase, elapsed: Duration) {
+        self.listener(listener).phases[phase as usize].observe(elapsed);
+        for sink in self.sinks.read().unwrap_or_else(|e| e.into_inner()).iter() {
+            sink.timing(listener, phase, elapsed);
+        }
+    }
+
+    /// 注册推送式的输出
+    pub fn add_sink(&self, sink: Arc<dyn Sink>) {
+        self.sinks
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(sink);
+    }
+
+    /// 注册表自身的全部计数 (不含 collector 和按域名的统计)，供推送式输出定期读取
+    pub fn samples(&self) -> Vec<Sample> {
+        let mut samples = Vec::new();
+        let mut push = |name, kind, tags: Vec<(&'static str, &'static str)>, value| {
+            samples.push(Sample {
+                name,
+                kind,
+                tags,
+                value,
+            })
+        };
+        for listener in Listener::ALL {
+            let metrics = self.listener(listener);
+            let tags = || vec![("listener", listener.label())];
+            let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+            push(
+                "connections.accepted",
+                MetricKind::Counter,
+                tags(),
+                load(&metrics.accepted),
+            );
+            if listener != Listener::Quic {
+                push(
+                    "connections.active",
+                    MetricKind::Gauge,
+                    tags(),
+                    load(&metrics.active),
+                );
+            }
+            for (result, counter) in [
+                ("success", &metrics.sni_success),
+                ("failure", &metrics.sni_failure),
+            ] {
+                let mut tags = tags();
+                tags.push(("result", result));
+                push("sni.extractions", MetricKind::Counter, tags, load(counter));
+            }
+            for reason in RejectReason::ALL {
+                let mut tags = tags();
+                tags.push(("reason", reason.as_label()));
+                push(
+                    "rejections",
+                    MetricKind::Counter,
+                    tags,
+                    self.rejections(listener, reason),
+                );
+            }
+            for reason in FailureReason::ALL {
+                let mut tags = tags();
+                tags.push(("reason", reason.as_label()));
+                push(
+                    "failures",
+                    MetricKind::Counter,
+                    tags,
+                    self.failures(listener, reason),
+                );
+            }
+            for direction in Direction::ALL {
+                let mut tags = tags();
+                tags.push(("direction", direction.label()));
+                push(
+                    "bytes_forwarded",
+                    MetricKind::Counter,
+                    tags,
+                    load(self.bytes(listener, direction)),
+                );
+            }
+            push(
+                "handler_panics",
+                MetricKind::Counter,
+                tags(),
+                load(&metrics.handler_panics),
+            );
+        }
+        for kind in TaskKind::ALL {
+            push(
+                "tasks",
+                MetricKind::Gauge,
+                vec![("kind", kind.label())],
+                self.tasks(kind),
+            );
+        }
+        samples
+    }
+
+    /// 按域名的流量统计
+    pub fn domains(&self) -> &DomainStats {
+        &self.domains
+    }
+
+    /// 转发字节数的计数器，供转发循环边转发边累加
+    pub fn bytes(&self, listener: Listener, direction: Direction) -> &AtomicU64 {
+        &self.listener(listener).bytes[direction as usize]
+    }
+
+    /// 注册抓取时执行的 collector
+    pub fn register_collector<F, Fut>(&self, collector: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = String> + Send + 'static,
+    {
+        self.collectors
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(Box::new(move || Box::pin(collector())));
+    }
+
+    fn render_phases(&self, out: &mut Exposition) {
+        let bounds: Vec<f64> = BUCKET_BOUNDS_MS
+            .iter()
+            .map(|ms| *ms as f64 / 1000.0)
+            .collect();
+        out.family(
+            "sniproxy_connection_phase_seconds",
+            MetricKind::Histogram,
+            "Time spent in each phase of connection setup",
+        );
+        for (listener, phase) in Phase::SERIES {
+            let histogram = &self.listener(listener).phases[phase as usize];
+            let buckets: Vec<u64> = histogram
+                .buckets
+                .iter()
+                .map(|count| count.load(Ordering::Relaxed))
+                .collect();
+            out.histogram(
+                "sniproxy_connection_phase_seconds",
+                &[("listener", listener.label()), ("phase", phase.label())],
+                &bounds,
+                &buckets,
+                histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0,
+            );
+        }
+    }
+
+    /// 前 [`METRIC_DOMAINS`] 个域名，其余合计为 `domain="other"`，标签基数有上限
+    fn render_domains(&self, out: &mut Exposition) {
+        let (top, rest) = self.domains.top_with_rest(METRIC_DOMAINS);
+        let rows: Vec<(&str, DomainTraffic)> = top
+            .iter()
+            .map(|entry| (entry.domain.as_str(), entry.traffic))
+            .chain(std::iter::once(("other", rest)))
+            .collect();
+
+        out.family(
+            "sniproxy_top_domain_connections",
+            MetricKind::Gauge,
+            "Finished connections (QUIC sessions) of the busiest domains, estimated; the rest as domain=\"other\"",
+        );
+        for (domain, traffic) in &rows {
+            out.sample(
+                "sniproxy_top_domain_connections",
+                &[("domain", domain)],
+                traffic.connections,
+            );
+        }
+        out.family(
+            "sniproxy_top_domain_bytes",
+            MetricKind::Gauge,
+            "Bytes forwarded for the busiest domains; the rest as domain=\"other\"",
+        );
+        for (domain, traffic) in &rows {
+            for (direction, bytes) in [
+                (Direction::ClientToUpstream, traffic.bytes_up),
+                (Direction::UpstreamToClient, traffic.bytes_down),
+            ] {
+                out.sample(
+                    "sniproxy_top_domain_bytes",
+                    &[("domain", domain), ("direction", direction.label())],
+                    bytes,
+                );
+            }
+        }
+    }
+
+    /// 注册 `GET /quic/sessions` 的会话列表来源；监听器重启后由新的会话管理器替换
+    pub fn register_quic_sessions<F>(&self, source: F)
+    where
+        F: Fn() -> serde_json::Value + Send + Sync + 'static,
+    {
+        *self.quic_sessions.lock().unwrap_or_else(|e| e.into_inner()) = Some(Box::new(source));
+    }
+
+    /// 当前的 QUIC 会话列表；没有运行 QUIC 监听器时为空数组
+    pub fn quic_sessions(&self) -> serde_json::Value {
+        self.quic_sessions
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_ref()
+            .map_or_else(|| serde_json::Value::Array(Vec::new()), |source| source())
+    }
+
+    /// 注册 `GET /upstreams` 的上游健康状态来源
+    pub fn register_upstream_health<F>(&self, source: F)
+    where
+        F: Fn() -> serde_json::Value + Send + Sync + 'static,
+    {
+        *self
+            .upstream_health
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = Some(Box::new(source));
+    }
+
+    /// 各上游的健康状态；没有注册时为空数组
+    pub fn upstream_health(&self) -> serde_json::Value {
+        self.upstream_health
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_ref()
+            .map_or_else(|| serde_json::Value::Array(Vec::new()), |source| source())
+    }
+
+    /// 注册 `GET /pool` 的连接池统计来源；监听器重启后由新的连接池替换
+    pub fn register_pool_stats<F, Fut>(&self, source: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = serde_json::Value> + Send + 'static,
+    {
+        *self.pool_stats.lock().unwrap_or_else(|e| e.into_inner()) =
+            Some(Box::new(move || Box::pin(source())));
+    }
+
+    /// 当前的连接池统计；没有运行 TCP 监听器时为 null
+    pub async fn pool_stats(&self) -> serde_json::Value {
+        let stats = self
+            .pool_stats
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_ref()
+            .map(|source| source());
+        match stats {
+            Some(stats) => stats.await,
+            None => serde_json::Value::Null,
+        }
+    }
+
+    /// 注册统计日志读取的来源
+    pub fn register_stats_source<F, Fut>(&self, source: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Totals> + Send + 'static,
+    {
+        self.stats_sources
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(Box::new(move || Box::pin(source())));
+    }
+
+    /// 注册表自身的计数与全部来源之和
+    pub async fn totals(&self) -> Totals {
+        let mut totals = Totals::default();
+        for metrics in &self.listeners {
+            totals.connections += metrics.accepted.load(Ordering::Relaxed);
+            totals.active_connections += metrics.active.load(Ordering::Relaxed);
+        }
+        totals.bytes_in = Listener::ALL
+            .into_iter()
+            .map(|l| {
+                self.bytes(l, Direction::ClientToUpstream)
+                    .load(Ordering::Relaxed)
+            })
+            .sum();
+        totals.bytes_out = Listener::ALL
+            .into_iter()
+            .map(|l| {
+                self.bytes(l, Direction::UpstreamToClient)
+                    .load(Ordering::Relaxed)
+            })
+            .sum();
+        totals.rejections = RejectReason::ALL
+            .into_iter()
+            .map(|reason| {
+                let count = Listener::ALL
+                    .into_iter()
+                    .map(|l| self.rejections(l, reason))
+                    .sum();
+                (reason.as_label(), count)
+            })
+            .collect();
+        totals.failures = FailureReason::ALL
+            .into_iter()
+            .map(|reason| {
+                let count = Listener::ALL
+                    .into_iter()
+                    .map(|l| self.failures(l, reason))
+                    .sum();
+                (reason.as_label(), count)
+            })
+            .collect();
+
+        let pending: Vec<_> = {
+            let sources = self.stats_sources.lock().unwrap_or_else(|e| e.into_inner());
+            sources.iter().map(|source| source()).collect()
+        };
+        for source in pending {
+            totals.merge(source.await);
+        }
+        totals
+    }
+
+    /// 声明将要启动的监听器，未绑定之前实例不就绪
+    pub fn expect_listener(&self, listener: Listener) {
+        self.listener(listener)
+            .expected
+            .store(true, Ordering::Relaxed);
+    }
+
+    /// 记录监听器已绑定，返回的守卫存活期间视为在接受连接
+    pub fn listening(self: &Arc<Self>, listener: Listener) -> ListeningGuard {
+        self.listener(listener).bound.store(true, Ordering::Relaxed);
+        ListeningGuard {
+            registry: Arc::clone(self),
+            listener,
+        }
+    }
+
+    /// 已声明但尚未绑定 (或已退出) 的监听器
+    pub fn unbound_listeners(&self) -> Vec<Listener> {
+        Listener::ALL
+            .into_iter()
+            .filter(|&listener| {
+                let metrics = self.listener(listener);
+                metrics.expected.load(Ordering::Relaxed) && !metrics.bound.load(Ordering::Relaxed)
+            })
+            .collect()
+    }
+
+    /// 输出全部指标
+    pub async fn render(&self) -> String {
+        let mut out = Exposition::default();
+        self.render_listeners(&mut out);
+        self.render_phases(&mut out);
+        self.render_domains(&mut out);
+        out.family(
+            "sniproxy_tasks",
+            MetricKind::Gauge,
+            "Long-lived tasks currently running; steady growth points to a leak",
+        );
+        for kind in TaskKind::ALL {
+            out.sample(
+                "sniproxy_tasks",
+                &[("kind", kind.label())],
+                self.tasks(kind),
+            );
+        }
+        let pending: Vec<_> = {
+            let collectors = self.collectors.lock().unwrap_or_else(|e| e.into_inner());
+            collectors.iter().map(|collector| collector()).collect()
+        };
+        let mut text = out.finish();
+        for collected in pending {
+            text.push_str(&collected.await);
+        }
+        text
+    }
+
+    fn render_listeners(&self, out: &mut Exposition) {
+        let each = |out: &mut Exposition, name: &str, value: fn(&ListenerMetrics) -> u64| {
+            for listener in Listener::ALL {
+                out.sample(
+                    name,
+                    &[("listener", listener.label())],
+                    value(self.listener(listener)),
+                );
+            }
+        };
+
+        out.family(
+            "sniproxy_connections_accepted_total",
+            MetricKind::Counter,
+            "Client connections accepted (new sessions for QUIC)",
+        );
+        each(out, "sniproxy_connections_accepted_total", |m| {
+            m.accepted.load(Ordering::Relaxed)
+        });
+
+        out.family(
+            "sniproxy_connections_active",
+            MetricKind::Gauge,
+            "Client connections currently open (QUIC: see sniproxy_quic_sessions_active)",
+        );
+        for listener in [Listener::Tcp, Listener::Http] {
+            out.sample(
+                "sniproxy_connections_active",
+                &[("listener", listener.label())],
+                self.listener(listener).active.load(Ordering::Relaxed),
+            );
+        }
+
+        out.family(
+            "sniproxy_sni_extractions_total",
+            MetricKind::Counter,
+            "SNI extractions from client hellos by result",
+        );
+        for listener in Listener::ALL {
+            let metrics = self.listener(listener);
+            for (result, counter) in [
+                ("success", &metrics.sni_success),
+                ("failure", &metrics.sni_failure),
+            ] {
+                out.sample(
+                    "sniproxy_sni_extractions_total",
+                    &[("listener", listener.label()), ("result", result)],
+                    counter.load(Ordering::Relaxed),
+                );
+            }
+        }
+
+        out.family(
+            "sniproxy_whitelist_rejections_total",
+            MetricKind::Counter,
+            "Connections rejected because the host is not in rules.allow",
+        );
+        each(out, "sniproxy_whitelist_rejections_total", |m| {
+            m.rejections[RejectReason::NotWhitelisted as usize].load(Ordering::Relaxed)
+        });
+
+        out.family(
+            "sniproxy_rejections_total",
+            MetricKind::Counter,
+            "Connections (new QUIC flows) rejected, by reason",
+        );
+        for listener in Listener::ALL {
+            for reason in RejectReason::ALL {
+                out.sample(
+                    "sniproxy_rejections_total",
+                    &[
+                        ("listener", listener.label()),
+                        ("reason", reason.as_label()),
+                    ],
+                    self.rejections(listener, reason),
+                );
+            }
+        }
+
+        out.family(
+            "sniproxy_failures_total",
+            MetricKind::Counter,
+            "Connections (new QUIC flows) that failed, by reason",
+        );
+        for listener in Listener::ALL {
+            for reason in FailureReason::ALL {
+                out.sample(
+                    "sniproxy_failures_total",
+                    &[
+                        ("listener", listener.label()),
+                        ("reason", reason.as_label()),
+                    ],
+                    self.failures(listener, reason),
+                );
+            }
+        }
+
+        out.family(
+            "sniproxy_bytes_forwarded_total",
+            MetricKind::Counter,
+            "Bytes forwarded between clients and upstreams",
+        );
+        for listener in Listener::ALL {
+            for direction in Direction::ALL {
+                out.sample(
+                    "sniproxy_bytes_forwarded_total",
+                    &[
+                        ("listener", listener.label()),
+                        ("direction", direction.label()),
+                    ],
+                    self.bytes(listener, direction).load(Ordering::Relaxed),
+                );
+            }
+        }
+
+        out.family(
+            "sniproxy_handler_panics_total",
+            MetricKind::Counter,
+            "Connection handler tasks that panicked",
+        );
+        each(out, "sniproxy_handler_panics_total", |m| {
+            m.handler_panics.load(Ordering::Relaxed)
+        });
+    }
+}
+
+/// 活动连接守卫，drop 时从活动连接数中减去
+#[derive(Debug)]
+#[must_use]
+pub struct ConnectionGuard {
+    registry: Arc<Registry>,
+    listener: Listener,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.registry
+            .listener(self.listener)
+            .active
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// 任务守卫，drop 时从该类别的任务数中减去
+#[derive(Debug)]
+#[must_use]
+pub struct TaskGuard {
+    registry: Arc<Registry>,
+    kind: TaskKind,
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        self.registry.tasks[self.kind as usize].fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// 监听器绑定守卫，drop 时 (监听器退出) 标记为未绑定
+#[derive(Debug)]
+#[must_use]
+pub struct ListeningGuard {
+    registry: Arc<Registry>,
+    listener: Listener,
+}
+
+impl Drop for ListeningGuard {
+    fn drop(&mut self) {
+        self.registry
+            .listener(self.listener)
+            .bound
+            .store(false, Ordering::Relaxed);
+    }
+}
+
+/// 指标类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricKind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+impl MetricKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+            MetricKind::Histogram => "histogram",
+        }
+    }
+}
+
+/// 文本格式编码器
+///
+/// 同一指标的样本必须连续输出，先调用 [`family`](Self::family) 再输出其全部样本。
+#[derive(Debug, Default)]
+pub struct Exposition {
+    out: String,
+}
+
+impl Exposition {
+    /// 输出指标的 HELP 和 TYPE 行
+    pub fn family(&mut self, name: &str, kind: MetricKind, help: &str) {
+        let _ = writeln!(self.out, "# HELP {} {}", name, help);
+        let _ = writeln!(self.out, "# TYPE {} {}", name, kind.as_str());
+    }
+
+    /// 输出一个样本
+    pub fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl fmt::Display) {
+        self.out.push_str(name);
+        if !labels.is_empty() {
+            self.out.push('{');
+            for (i, (label, value)) in labels.iter().enumerate() {
+                if i > 0 {
+                    self.out.push(',');
+                }
+                let _ = write!(self.out, "{}=\"", label);
+                escape_label_value(&mut self.out, value);
+                self.out.push('"');
+            }
+            self.out.push('}');
+        }
+        let _ = writeln!(self.out, " {}", value);
+    }
+
+    /// 输出一个直方图的 `_bucket`、`_sum` 和 `_count` 样本
+    ///
+    /// `buckets` 为各上界 (`bounds`，单位秒) 内的次数，不累计，最后一项为溢出桶。
+    pub fn histogram(
+        &mut self,
+        name: &str,
+        labels: &[(&str, &str)],
+        bounds: &[f64],
+        buckets: &[u64],
+        sum: f64,
+    ) {
+        let bucket_name = format!("{}_bucket", name);
+        let mut cumulative = 0;
+        for (i, count) in buckets.iter().enumerate() {
+            cumulative += count;
+            let le = bounds.get(i).map_or("+Inf".to_string(), f64::to_string);
+            let mut bucket_labels = labels.to_vec();
+            bucket_labels.push(("le", &le));
+            self.sample(&bucket_name, &bucket_labels, cumulative);
+        }
+        self.sample(&format!("{}_sum", name), labels, sum);
+        self.sample(&format!("{}_count", name), labels, cumulative);
+    }
+
+    /// 编码结果
+    pub fn finish(self) -> String {
+        self.out
+    }
+}
+
+fn escape_label_value(out: &mut String, value: &str) {
+    for c in value.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+    }
+}
+
+/// 各上游 SOCKS5 握手耗时与结果的 collector，失败次数同时计入统计日志
+pub fn register_socks5_latency(registry: &Registry, latency: Arc<LatencyMetrics>) {
+    let errors = Arc::clone(&latency);
+    registry.register_stats_source(move || {
+        let mut socks5_errors = BTreeMap::new();
+        for histogram in errors.histograms() {
+            for (result, count) in histogram.results {
+                if result != "ok" {
+                    *socks5_errors.entry(result).or_default() += count;
+                }
+            }
+        }
+        async move {
+            Totals {
+                socks5_errors,
+                ..Default::default()
+            }
+        }
+    });
+    registry.register_collector(move || {
+        let histograms = latency.histograms();
+        async move {
+            let bounds: Vec<f64> = BUCKET_BOUNDS_MS
+                .iter()
+                .map(|ms| *ms as f64 / 1000.0)
+                .collect();
+            let mut out = Exposition::default();
+            out.family(
+                "sniproxy_socks5_connect_duration_seconds",
+                MetricKind::Histogram,
+                "SOCKS5 handshake latency per upstream and operation",
+            );
+            for histogram in &histograms {
+                out.histogram(
+                    "sniproxy_socks5_connect_duration_seconds",
+                    &[
+                        ("upstream", &histogram.backend),
+                        ("operation", histogram.operation.label()),
+                    ],
+                    &bounds,
+                    &histogram.buckets,
+                    histogram.sum.as_secs_f64(),
+                );
+            }
+            out.family(
+                "sniproxy_socks5_handshakes_total",
+                MetricKind::Counter,
+                "SOCKS5 handshakes per upstream, operation and result",
+            );
+            for histogram in &histograms {
+                for (result, count) in &histogram.results {
+                    out.sample(
+                        "sniproxy_socks5_handshakes_total",
+                        &[
+                            ("upstream", &histogram.backend),
+                            ("operation", histogram.operation.label()),
+                            ("result", result),
+                        ],
+                        count,
+                    );
+                }
+            }
+            out.finish()
+        }
+    });
+}
+
+/// 管理端口的 `/upstreams` 列出各上游的健康状态，抓取时输出主动健康检查的状态和状态变化次数
+pub fn register_upstream_health(registry: &Registry, upstreams: Arc<UpstreamSet>) {
+    let snapshot = Arc::clone(&upstreams);
+    registry.register_upstream_health(move || {
+        serde_json::to_value(snapshot.health()).unwrap_or_default()
+    });
+    registry.register_collector(move || {
+        let health = upstreams.health();
+        async move {
+            let mut out = Exposition::default();
+            out.family(
+                "sniproxy_upstream_state",
+                MetricKind::Gauge,
+                "Active health check state per upstream (1 for the current state)",
+            );
+            for upstream in &health {
+                for state in [
+                    BackendState::Healthy,
+                    BackendState::Degraded,
+                    BackendState::Down,
+                ] {
+                    out.sample(
+                        "sniproxy_upstream_state",
+                        &[("upstream", &upstream.name), ("state", &state.to_string())],
+                        u8::from(upstream.state == state),
+                    );
+                }
+            }
+            out.family(
+                "sniproxy_upstream_selectable",
+                MetricKind::Gauge,
+                "Whether the upstream takes part in load balancing",
+            );
+            for upstream in &health {
+                out.sample(
+                    "sniproxy_upstream_selectable",
+                    &[("upstream", &upstream.name)],
+                    u8::from(upstream.selectable),
+                );
+            }
+            out.family(
+                "sniproxy_upstream_state_transitions_total",
+                MetricKind::Counter,
+                "Active health check state changes per upstream",
+            );
+            for upstream in &health {
+                out.sample(
+                    "sniproxy_upstream_state_transitions_total",
+                    &[("upstream", &upstream.name)],
+                    upstream.transitions,
+                );
+            }
+            out.finish()
+        }
+    });
+}
+
+/// 在 `addr` 上提供 `/metrics`、`/healthz`、`/readyz`、`/domains/top`、`/quic/sessions`、
+/// `/upstreams`、`/pool`、`/admin/log_level` 和 `/status`，直到 `shutdown` 变为 true
+pub async fn run(
+    addr: SocketAddr,
+    registry: Arc<Registry>,
+    readiness: Arc<Readiness>,
+    log_filter: Arc<LogFilter>,
+    status: Arc<serde_json::Value>,
+    shutdown: watch::Receiver<bool>,
+) -> crate::Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|source| crate::Error::ListenerBind {
+            listener: "metrics endpoint",
+            addr,
+            source,
+        })?;
+    serve(listener, registry, readiness, log_filter, status, shutdown).await
+}
+
+/// 在已绑定的 `listener` 上提供 `/metrics`、`/healthz`、`/readyz`、`/domains/top`、
+/// `/quic/sessions`、`/upstreams`、`/pool`、`/admin/log_level` 和 `/status` (内容为 `status`)，
+/// 直到 `shutdown` 变为 true
+pub async fn serve(
+    listener: TcpListener,
+    registry: Arc<Registry>,
+    readiness: Arc<Readiness>,
+    log_filter: Arc<LogFilter>,
+    status: Arc<serde_json::Value>,
+    mut shutdown: watch::Receiver<bool>,
+) -> crate::Result<()> {
+    let _task = registry.track_task(TaskKind::Listener);
+    if let Ok(addr) = listener.local_addr() {
+        info!(listener = "metrics", addr = %addr, "metrics endpoint listening");
+    }
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = shutdown.wait_for(|stop| *stop) => return Ok(()),
+        };
+        let registry = Arc::clone(&registry);
+        let readiness = Arc::clone(&readiness);
+        let log_filter = Arc::clone(&log_filter);
+        let status = Arc::clone(&status);
+        tokio::spawn(async move {
+            if let Err(e) = handle_scrape(stream, &registry, &readiness, &log_filter, &status).await
+            {
+                debug!(peer = %peer, error = %e, "metrics request failed");
+            }
+        });
+    }
+}
+
+/// 处理一个抓取或探测请求，响应后关闭连接
+async fn handle_scrape(
+    mut stream: TcpStream,
+    registry: &Registry,
+    readiness: &Readiness,
+    log_filter: &LogFilter,
+    status: &serde_json::Value,
+) -> Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    let header_end = loop {
+        if let Some(pos) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        if request.len() > MAX_REQUEST_LEN {
+            anyhow::bail!("request header too large");
+        }
+        let n = tokio::time::timeout(REQUEST_TIMEOUT, stream.read(&mut buf)).await??;
+        if n == 0 {
+            anyhow::bail!("connection closed before the request was complete");
+        }
+        request.extend_from_slice(&buf[..n]);
+    };
+    // 只有 PUT /admin/log_level 带请求体
+    let body_len = content_length(&request[..header_end]);
+    if header_end.saturating_add(body_len) > MAX_REQUEST_LEN {
+        anyhow::bail!("request body too large");
+    }
+    while request.len() < header_end + body_len {
+        let n = tokio::time::timeout(REQUEST_TIMEOUT, stream.read(&mut buf)).await??;
+        if n == 0 {
+            anyhow::bail!("connection closed before the request body was complete");
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let request_body = &request[header_end..header_end + body_len];
+
+    let request_line = request.split(|b| *b == b'\r').next().unwrap_or_default();
+    let mut parts = request_line.split(|b| *b == b' ');
+    let (method, target) = (parts.next(), parts.next());
+    // 只有 /domains/top 使用查询字符串
+    let (path, query) = match target {
+        Some(target) => {
+            let mut split = target.splitn(2, |b| *b == b'?');
+            (split.next(), split.next().unwrap_or_default())
+        }
+        None => (None, &[][..]),
+    };
+    let (status, content_type, body) = match (method, path) {
+        (Some(b"GET"), Some(b"/metrics")) => (
+            "200 OK",
+            "text/plain; version=0.0.4; charset=utf-8",
+            registry.render().await,
+        ),
+        (Some(b"GET"), Some(b"/healthz")) => (
+            "200 OK",
+            "application/json",
+            "{\"status\":\"ok\"}\n".to_string(),
+        ),
+        (Some(b"GET"), Some(b"/readyz")) => {
+            let report = readiness.check();
+            let status = if report.is_ready() {
+                "200 OK"
+            } else {
+                "503 Service Unavailable"
+            };
+            (status, "application/json", report.to_json() + "\n")
+        }
+        (Some(b"GET"), Some(b"/domains/top")) => match top_domains_limit(query) {
+            Some(n) => {
+                let top = registry.domains().top(n);
+                let body = serde_json::to_string(&top).unwrap_or_else(|_| "{}".to_string());
+                ("200 OK", "application/json", body + "\n")
+            }
+            None => (
+                "400 Bad Request",
+                "text/plain",
+                "n must be a positive integer\n".to_string(),
+            ),
+        },
+        (Some(b"GET"), Some(b"/quic/sessions")) => (
+            "200 OK",
+            "application/json",
+            format!("{}\n", registry.quic_sessions()),
+        ),
+        (Some(b"GET"), Some(b"/upstreams")) => (
+            "200 OK",
+            "application/json",
+            format!("{}\n", registry.upstream_health()),
+        ),
+        (Some(b"GET"), Some(b"/pool")) => (
+            "200 OK",
+            "application/json",
+            format!("{}\n", registry.pool_stats().await),
+        ),
+        (Some(b"GET"), Some(b"/status")) => ("200 OK", "application/json", format!("{}\n", status)),
+        (Some(b"GET"), Some(b"/admin/log_level")) => {
+            ("200 OK", "application/json", log_filter_json(log_filter))
+        }
+        (Some(b"PUT"), Some(b"/admin/log_level")) => match log_filter_body(request_body) {
+            Some(filter) => match log_filter.set(&filter) {
+                Ok(()) => ("200 OK", "application/json", log_filter_json(log_filter)),
+                Err(e) => ("400 Bad Request", "text/plain", format!("{}\n", e)),
+            },
+            None => (
+                "400 Bad Request",
+                "text/plain",
+                "body must be a log filter string\n".to_string(),
+            ),
+        },
+        (Some(b"DELETE"), Some(b"/admin/log_level")) => match log_filter.reset() {
+            Ok(()) => ("200 OK", "application/json", log_filter_json(log_filter)),
+            Err(e) => (
+                "500 Internal Server Error",
+                "text/plain",
+                format!("{}\n", e),
+            ),
+        },
+        (Some(b"POST"), Some(b"/admin/log_level/reload")) => match log_filter.reload_config() {
+            Ok(()) => ("200 OK", "application/json", log_filter_json(log_filter)),
+            Err(e) => (
+                "500 Internal Server Error",
+                "text/plain",
+                format!("{}\n", e),
+            ),
+        },
+        (Some(b"GET"), _) => ("404 Not Found", "text/plain", "Not Found\n".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "Method Not Allowed\n".to_string(),
+        ),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// 请求头中的 `Content-Length`，没有或无法解析时为 0
+fn content_length(head: &[u8]) -> usize {
+    head.split(|b| *b == b'\n')
+        .filter_map(|line| std::str::from_utf8(line).ok())
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// `PUT /admin/log_level` 的请求体：纯文本或 JSON 字符串，例如 `"info,sniproxy_ng::quic=trace"`
+fn log_filter_body(body: &[u8]) -> Option<String> {
+    let body = std::str::from_utf8(body).ok()?.trim();
+    let filter = if body.starts_with('"') {
+        serde_json::from_str::<String>(body).ok()?
+    } else {
+        body.to_string()
+    };
+    (!filter.trim().is_empty()).then_some(filter)
+}
+
+fn log_filter_json(log_filter: &LogFilter) -> String {
+    serde_json::to_string(&log_filter.state()).unwrap_or_else(|_| "{}".to_string()) + "\n"
+}
+
+/// `/domains/top` 的 `n` 参数，缺省为 50；不是正整数时返回 `None`
+fn top_domains_limit(query: &[u8]) -> Option<usize> {
+    let value = query
+        .split(|b| *b == b'&')
+        .find_map(|pair| pair.strip_prefix(b"n="));
+    match value {
+        None => Some(50),
+        Some(value) => std::str::from_utf8(value)
+            .ok()?
+            .parse()
+            .ok()
+            .filter(|n| *n > 0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_render_listener_metrics() {
+        let registry = Arc::new(Registry::default());
+        let guard = registry.connection(Listener::Tcp);
+        drop(registry.connection(Listener::Http));
+        registry.record_accepted(Listener::Quic);
+        registry.record_sni(Listener::Tcp, true);
+        registry.record_sni(Listener::Tcp, false);
+        registry.record_rejection(Listener::Quic, RejectReason::NotWhitelisted);
+        registry.record_outcome(
+            Listener::Http,
+            Outcome::Failed(FailureReason::UpstreamTimeout),
+        );
+        registry.record_outcome(Listener::Http, Outcome::Closed);
+        registry.record_bytes(Listener::Tcp, Direction::UpstreamToClient, 1500);
+
+        let text = registry.render().await;
+        for line in [
+            "# TYPE sniproxy_connections_accepted_total counter",
+            "sniproxy_connections_accepted_total{listener=\"tcp\"} 1",
+            "sniproxy_connections_accepted_total{listener=\"quic\"} 1",
+            "sniproxy_connections_active{listener=\"tcp\"} 1",
+            "sniproxy_connections_active{listener=\"http\"} 0",
+            "sniproxy_sni_extractions_total{listener=\"tcp\",result=\"success\"} 1",
+            "sniproxy_sni_extractions_total{listener=\"tcp\",result=\"failure\"} 1",
+            "sniproxy_whitelist_rejections_total{listener=\"quic\"} 1",
+            "# TYPE sniproxy_rejections_total counter",
+            "sniproxy_rejections_total{listener=\"quic\",reason=\"not_whitelisted\"} 1",
+            "sniproxy_rejections_total{listener=\"tcp\",reason=\"no_sni\"} 0",
+            "sniproxy_failures_total{listener=\"http\",reason=\"upstream_timeout\"} 1",
+            "sniproxy_failures_total{listener=\"http\",reason=\"idle_timeout\"} 0",
+            "sniproxy_bytes_forwarded_total{listener=\"tcp\",direction=\"upstream_to_client\"} 1500",
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing {:?} in\n{}", line, text);
+        }
+
+        drop(guard);
+        assert!(registry
+            .render()
+            .await
+            .contains("sniproxy_connections_active{listener=\"tcp\"} 0\n"));
+    }
+
+    #[tokio::test]
+    async fn test_histogram_and_collectors() {
+        use crate::socks5::metrics::{MetricsSink, Socks5Operation};
+
+        let registry = Registry::default();
+        let latency = Arc::new(LatencyMetrics::default());
+        register_socks5_latency(&registry, Arc::clone(&latency));
+        registry.register_collector(|| async {
+            let mut out = Exposition::default();
+            out.family("test_gauge", MetricKind::Gauge, "A test gauge");
+            out.sample("test_gauge", &[("name", "a\"b\\c\nd")], 7);
+            out.finish()
+        });
+        latency.record(
+            "default",
+            Socks5Operation::Connect,
+            "ok",
+            Duration::from_millis(3),
+        );
+        latency.record(
+            "default",
+            Socks5Operation::Connect,
+            "timeout",
+            Duration::from_secs(30),
+        );
+
+        let text = registry.render().await;
+        for line in [
+            "sniproxy_socks5_connect_duration_seconds_bucket{upstream=\"default\",operation=\"connect\",le=\"0.002\"} 0",
+            "sniproxy_socks5_connect_duration_seconds_bucket{upstream=\"default\",operation=\"connect\",le=\"0.005\"} 1",
+            "sniproxy_socks5_connect_duration_seconds_bucket{upstream=\"default\",operation=\"connect\",le=\"10\"} 1",
+            "sniproxy_socks5_connect_duration_seconds_bucket{upstream=\"default\",operation=\"connect\",le=\"+Inf\"} 2",
+            "sniproxy_socks5_connect_duration_seconds_sum{upstream=\"default\",operation=\"connect\"} 30.003",
+            "sniproxy_socks5_connect_duration_seconds_count{upstream=\"default\",operation=\"connect\"} 2",
+            "sniproxy_socks5_handshakes_total{upstream=\"default\",operation=\"connect\",result=\"timeout\"} 1",
+            "test_gauge{name=\"a\\\"b\\\\c\\nd\"} 7",
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing {:?} in\n{}", line, text);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upstream_health() {
+        let config: crate::Config = toml::from_str(
+            r#"
+[server]
+listen_https_addr = "127.0.0.1:443"
+
+[socks5]
+addr = "127.0.0.1:1080"
+
+[[socks5.upstreams]]
+name = "backup"
+addr = "127.0.0.1:1081"
+"#,
+        )
+        .unwrap();
+        let upstreams = Arc::new(UpstreamSet::from_config(&config).unwrap());
+        let registry = Registry::default();
+        assert_eq!(registry.upstream_health(), serde_json::json!([]));
+        register_upstream_health(&registry, Arc::clone(&upstreams));
+
+        let default = upstreams.get("default").unwrap();
+        for _ in 0..3 {
+            default.record_probe_failure("connection refused");
+        }
+
+        let text = registry.render().await;
+        for line in [
+            "sniproxy_upstream_state{upstream=\"default\",state=\"down\"} 1",
+            "sniproxy_upstream_state{upstream=\"default\",state=\"healthy\"} 0",
+            "sniproxy_upstream_state{upstream=\"backup\",state=\"healthy\"} 1",
+            "sniproxy_upstream_selectable{upstream=\"default\"} 0",
+            "sniproxy_upstream_state_transitions_total{upstream=\"default\"} 2",
+            "sniproxy_upstream_state_transitions_total{upstream=\"backup\"} 0",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "missing {:?} in\n{}",
+                line,
+                text
+            );
+        }
+
+        let json = registry.upstream_health();
+        assert_eq!(json[0]["name"], "default");
+        assert_eq!(json[0]["state"], "down");
+        assert_eq!(json[0]["last_error"], "connection refused");
+        assert_eq!(json[1]["selectable"], true);
+    }
+}
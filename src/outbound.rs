@@ -0,0 +1,328 @@
+//! 到上游的出站连接的源地址与网卡
+//!
+//! 多出口主机需要让到上游的流量从指定的源地址 (`socks5.bind_addr`) 或网卡
+//! (`socks5.bind_device`，仅 Linux，SO_BINDTODEVICE) 离开。到上游代理的 TCP 连接
+//! 都经 [`connect`] 建立：先用 socket2 创建并绑定 socket 再发起连接，绑定失败
+//! ([`BindError`]) 与连接失败分别报告。
+//!
+//! 代理地址经 [`ProxyResolver`] 解析。域名形式的地址解析结果缓存 [`RESOLVE_TTL`]，
+//! 连接失败时立即重新解析，地址有变化就用新地址再试一次，从而跟上负载均衡器的 IP 轮换。
+
+use crate::config::Socks5Config;
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::net::{TcpSocket, TcpStream};
+use tracing::debug;
+
+/// 域名形式的代理地址解析结果的缓存时长
+pub const RESOLVE_TTL: Duration = Duration::from_secs(30);
+
+/// 出站 socket 的绑定设置
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OutboundBind {
+    /// 源地址 (端口由系统分配)
+    pub addr: Option<IpAddr>,
+    /// 绑定的网卡名
+    pub device: Option<String>,
+}
+
+/// 出站 socket 绑定源地址或网卡失败
+#[derive(Error, Debug)]
+#[error("failed to bind outbound socket to {local}: {source}")]
+pub struct BindError {
+    /// 绑定目标 (源地址或 "device eth1")
+    pub local: String,
+    #[source]
+    pub source: io::Error,
+}
+
+/// [`connect`] 的失败
+#[derive(Error, Debug)]
+pub enum DialError {
+    /// 本地绑定失败，通常是配置问题
+    #[error(transparent)]
+    Bind(#[from] BindError),
+
+    /// 解析或连接上游失败
+    #[error("failed to connect to {addr}: {source}")]
+    Connect {
+        addr: String,
+        #[source]
+        source: io::Error,
+    },
+}
+
+/// 代理地址及其解析缓存
+///
+/// 同一上游的所有客户端 (TCP CONNECT、连接池、QUIC 的 UDP ASSOCIATE) 共享一个实例。
+#[derive(Debug)]
+pub struct ProxyResolver {
+    /// "IP:PORT" 或 "域名:PORT"
+    addr: String,
+    /// IP 形式的地址，不需要解析
+    literal: Option<SocketAddr>,
+    ttl: Duration,
+    /// 最近一次的解析结果和解析时间
+    cache: Mutex<Option<(Vec<SocketAddr>, Instant)>>,
+}
+
+impl ProxyResolver {
+    /// 创建解析器，`addr` 为 "IP:PORT" 或 "域名:PORT"
+    pub fn new(addr: impl Into<String>) -> Self {
+        let addr = addr.into();
+        Self {
+            literal: addr.parse().ok(),
+            addr,
+            ttl: RESOLVE_TTL,
+            cache: Mutex::new(None),
+        }
+    }
+
+    /// 配置的代理地址
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    /// 解析代理地址；缓存未过期时直接返回缓存
+    pub async fn resolve(&self) -> io::Result<Vec<SocketAddr>> {
+        if let Some(addr) = self.literal {
+            return Ok(vec![addr]);
+        }
+        if let Some((addrs, resolved_at)) = &*self.cache.lock().unwrap_or_else(|e| e.into_inner()) {
+            if resolved_at.elapsed() < self.ttl {
+                return Ok(addrs.clone());
+            }
+        }
+
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host(&self.addr).await?.collect();
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "proxy hostname resolved to no addresses",
+            ));
+        }
+        debug!(proxy = %self.addr, addrs = ?addrs, "proxy resolved");
+        *self.cache.lock().unwrap_or_else(|e| e.into_inner()) =
+            Some((addrs.clone(), Instant::now()));
+        Ok(addrs)
+    }
+
+    /// 丢弃缓存，下次建连时重新解析
+    pub fn invalidate(&self) {
+        self.cache.lock().unwrap_or_else(|e| e.into_inner()).take();
+    }
+}
+
+impl From<SocketAddr> for ProxyResolver {
+    fn from(addr: SocketAddr) -> Self {
+        Self::new(addr.to_string())
+    }
+}
+
+impl OutboundBind {
+    /// 由 `[socks5]` 的 `bind_addr`/`bind_device` 创建
+    pub fn from_config(config: &Socks5Config) -> Self {
+        Self {
+            addr: config.bind_addr,
+            device: config.bind_device.clone(),
+        }
+    }
+
+    /// 没有任何绑定设置
+    pub fn is_empty(&self) -> bool {
+        self.addr.is_none() && self.device.is_none()
+    }
+
+    /// UDP relay socket 的本地地址：配置了源地址时使用它，否则为 IPv4 通配地址
+    pub fn udp_local_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.addr.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)), 0)
+    }
+
+    /// 把已创建的 socket 绑定到配置的网卡
+    pub fn apply_device(&self, socket: SockRef<'_>) -> Result<(), BindError> {
+        let Some(device) = &self.device else {
+            return Ok(());
+        };
+        bind_device(socket, device).map_err(|source| BindError {
+            local: format!("device {}", device),
+            source,
+        })
+    }
+
+    fn bind_tcp(&self, target: SocketAddr) -> Result<TcpSocket, BindError> {
+        let local = self.addr.map(|ip| SocketAddr::new(ip, 0));
+        let describe = || match local {
+            Some(local) => local.to_string(),
+            None => "an unspecified address".to_string(),
+        };
+        let socket = Socket::new(
+            Domain::for_address(target),
+            Type::STREAM,
+            Some(Protocol::TCP),
+        )
+        .and_then(|socket| socket.set_nonblocking(true).map(|()| socket))
+        .map_err(|source| BindError {
+            local: describe(),
+            source,
+        })?;
+        self.apply_device(SockRef::from(&socket))?;
+        if let Some(local) = local {
+            socket.bind(&local.into()).map_err(|source| BindError {
+                local: describe(),
+                source,
+            })?;
+        }
+        Ok(TcpSocket::from_std_stream(socket.into()))
+    }
+}
+
+/// 按绑定设置建立到代理的 TCP 连接
+///
+/// 配置了源地址时只尝试与其地址族相同的解析结果。连接失败时丢弃解析缓存并重新解析，
+/// 地址有变化就再试一次。
+pub async fn connect(proxy: &ProxyResolver, bind: &OutboundBind) -> Result<TcpStream, DialError> {
+    let connect_error = |source| DialError::Connect {
+        addr: proxy.addr().to_string(),
+        source,
+    };
+    let addrs = proxy.resolve().await.map_err(connect_error)?;
+    match connect_any(proxy.addr(), &addrs, bind).await {
+        Err(DialError::Connect { source, .. }) if proxy.literal.is_none() => {
+            proxy.invalidate();
+            let fresh = proxy.resolve().await.map_err(connect_error)?;
+            if fresh == addrs {
+                return Err(connect_error(source));
+            }
+            debug!(
+                proxy = %proxy.addr(),
+                addrs = ?fresh,
+                "proxy address changed, retrying the connection"
+            );
+            connect_any(proxy.addr(), &fresh, bind).await
+        }
+        result => result,
+    }
+}
+
+/// 依次尝试代理 `addr` 的解析结果 `addrs`
+async fn connect_any(
+    addr: &str,
+    addrs: &[SocketAddr],
+    bind: &OutboundBind,
+) -> Result<TcpStream, DialError> {
+    let connect_error = |source| DialError::Connect {
+        addr: addr.to_string(),
+        source,
+    };
+    if bind.is_empty() {
+        return TcpStream::connect(addrs).await.map_err(connect_error);
+    }
+
+    let targets = addrs
+        .iter()
+        .copied()
+        .filter(|target| bind.addr.is_none_or(|ip| ip.is_ipv4() == target.is_ipv4()));
+    let mut last_error = io::Error::new(
+        io::ErrorKind::AddrNotAvailable,
+        "no resolved address matches the address family of socks5.bind_addr",
+    );
+    for target in targets {
+        let socket = bind.bind_tcp(target)?;
+        match socket.connect(target).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(connect_error(last_error))
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_device(socket: SockRef<'_>, device: &str) -> io::Result<()> {
+    socket.bind_device(Some(device.as_bytes()))
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_device(_socket: SockRef<'_>, _device: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "socks5.bind_device is only supported on Linux",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn connection_leaves_from_bind_addr() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let bind = OutboundBind {
+            addr: Some("127.0.0.2".parse().unwrap()),
+            device: None,
+        };
+
+        let proxy = ProxyResolver::from(addr);
+        let (stream, accepted) = tokio::join!(connect(&proxy, &bind), listener.accept());
+        let stream = stream.unwrap();
+        let (_, peer) = accepted.unwrap();
+        assert_eq!(peer.ip(), "127.0.0.2".parse::<IpAddr>().unwrap());
+        assert_eq!(stream.local_addr().unwrap(), peer);
+    }
+
+    #[tokio::test]
+    async fn bind_and_connect_failures_are_distinguished() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed = listener.local_addr().unwrap();
+        drop(listener);
+
+        // TEST-NET-3 地址不属于本机
+        let foreign = OutboundBind {
+            addr: Some("203.0.113.7".parse().unwrap()),
+            device: None,
+        };
+        let closed = ProxyResolver::from(closed);
+        let error = connect(&closed, &foreign).await.unwrap_err();
+        assert!(matches!(error, DialError::Bind(_)), "{}", error);
+        assert!(error.to_string().contains("203.0.113.7:0"), "{}", error);
+
+        let local = OutboundBind {
+            addr: Some("127.0.0.1".parse().unwrap()),
+            device: None,
+        };
+        let error = connect(&closed, &local).await.unwrap_err();
+        assert!(matches!(error, DialError::Connect { .. }), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn hostname_proxy_is_re_resolved_after_connect_failure() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let stale = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stale_addr = stale.local_addr().unwrap();
+        drop(stale);
+
+        let proxy = ProxyResolver::new(format!("localhost:{}", port));
+        let addrs = proxy.resolve().await.unwrap();
+        assert!(addrs.contains(&SocketAddr::from(([127, 0, 0, 1], port))));
+
+        // 缓存中是代理轮换前的地址：连接失败后重新解析并连上新地址
+        *proxy.cache.lock().unwrap() = Some((vec![stale_addr], Instant::now()));
+        assert_eq!(proxy.resolve().await.unwrap(), vec![stale_addr]);
+        let bind = OutboundBind::default();
+        let (stream, accepted) = tokio::join!(connect(&proxy, &bind), listener.accept());
+        stream.unwrap();
+        accepted.unwrap();
+        assert_eq!(proxy.resolve().await.unwrap(), addrs);
+
+        // IP 形式的地址不经过缓存
+        let literal = ProxyResolver::from(stale_addr);
+        assert_eq!(literal.resolve().await.unwrap(), vec![stale_addr]);
+        assert!(literal.cache.lock().unwrap().is_none());
+    }
+}
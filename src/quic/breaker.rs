@@ -0,0 +1,173 @@
+//! SOCKS5 UDP ASSOCIATE 的断路器
+//!
+//! SOCKS5 服务器短暂不可达时，每个新流都会各自重试 ASSOCIATE；并发的会话建立
+//! 共享同一个断路器：连续失败达到阈值后打开，冷却期内直接失败；冷却结束后只放行
+//! 一个探测请求，探测成功才关闭，失败则重新打开。
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// 断路器状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// 正常放行，记录连续失败次数
+    Closed { failures: u32 },
+    /// 冷却中，`until` 之前直接拒绝
+    Open { until: Instant },
+    /// 探测请求进行中，其它请求直接拒绝
+    HalfOpen,
+}
+
+/// 状态变化，调用方据此记录日志和计数
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    /// 状态未变
+    None,
+    /// 由关闭或探测失败转为打开
+    Opened,
+    /// 探测成功，转为关闭
+    Closed,
+}
+
+/// 按连续失败次数打开的断路器
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    state: Mutex<State>,
+    threshold: u32,
+    cooldown: Duration,
+}
+
+impl CircuitBreaker {
+    /// 创建断路器；`threshold` 为 0 时永不打开
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            state: Mutex::new(State::Closed { failures: 0 }),
+            threshold,
+            cooldown,
+        }
+    }
+
+    /// 是否允许发起一次尝试；冷却结束后第一个调用方成为探测请求
+    pub fn try_acquire(&self) -> bool {
+        self.try_acquire_at(Instant::now())
+    }
+
+    fn try_acquire_at(&self, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        match *state {
+            State::Closed { .. } => true,
+            State::Open { until } if now >= until => {
+                *state = State::HalfOpen;
+                true
+            }
+            State::Open { .. } | State::HalfOpen => false,
+        }
+    }
+
+    /// 记录一次成功
+    pub fn record_success(&self) -> Transition {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let was_closed = matches!(*state, State::Closed { .. });
+        *state = State::Closed { failures: 0 };
+        if was_closed {
+            Transition::None
+        } else {
+            Transition::Closed
+        }
+    }
+
+    /// 记录一次失败
+    pub fn record_failure(&self) -> Transition {
+        self.record_failure_at(Instant::now())
+    }
+
+    fn record_failure_at(&self, now: Instant) -> Transition {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let open = State::Open {
+            until: now + self.cooldown,
+        };
+        match *state {
+            State::Closed { failures } => {
+                let failures = failures.saturating_add(1);
+                if self.threshold > 0 && failures >= self.threshold {
+                    *state = open;
+                    Transition::Opened
+                } else {
+                    *state = State::Closed { failures };
+                    Transition::None
+                }
+            }
+            State::HalfOpen => {
+                *state = open;
+                Transition::Opened
+            }
+            // 打开前已发出的尝试随后失败，不延长冷却
+            State::Open { .. } => Transition::None,
+        }
+    }
+
+    /// 当前是否处于打开 (含探测中) 状态
+    pub fn is_open(&self) -> bool {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        !matches!(*state, State::Closed { .. })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COOLDOWN: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn test_opens_after_consecutive_failures() {
+        let breaker = CircuitBreaker::new(3, COOLDOWN);
+        let now = Instant::now();
+
+        assert_eq!(breaker.record_failure_at(now), Transition::None);
+        assert_eq!(breaker.record_failure_at(now), Transition::None);
+        // 成功一次清零
+        assert_eq!(breaker.record_success(), Transition::None);
+        assert_eq!(breaker.record_failure_at(now), Transition::None);
+        assert_eq!(breaker.record_failure_at(now), Transition::None);
+        assert!(breaker.try_acquire_at(now));
+        assert_eq!(breaker.record_failure_at(now), Transition::Opened);
+
+        assert!(breaker.is_open());
+        assert!(!breaker.try_acquire_at(now + Duration::from_secs(1)));
+        // 打开前发出的尝试随后失败，不重复计为打开
+        assert_eq!(breaker.record_failure_at(now), Transition::None);
+    }
+
+    #[test]
+    fn test_single_probe_after_cooldown() {
+        let breaker = CircuitBreaker::new(1, COOLDOWN);
+        let now = Instant::now();
+        assert_eq!(breaker.record_failure_at(now), Transition::Opened);
+
+        // 冷却结束后只放行一个探测
+        let later = now + COOLDOWN;
+        assert!(breaker.try_acquire_at(later));
+        assert!(!breaker.try_acquire_at(later));
+
+        // 探测失败重新打开并重新计时
+        assert_eq!(breaker.record_failure_at(later), Transition::Opened);
+        assert!(!breaker.try_acquire_at(later + Duration::from_secs(1)));
+
+        // 探测成功后关闭
+        assert!(breaker.try_acquire_at(later + COOLDOWN));
+        assert_eq!(breaker.record_success(), Transition::Closed);
+        assert!(!breaker.is_open());
+        assert!(breaker.try_acquire_at(later + COOLDOWN));
+    }
+
+    #[test]
+    fn test_zero_threshold_never_opens() {
+        let breaker = CircuitBreaker::new(0, COOLDOWN);
+        let now = Instant::now();
+        for _ in 0..100 {
+            assert_eq!(breaker.record_failure_at(now), Transition::None);
+        }
+        assert!(breaker.try_acquire_at(now));
+    }
+}
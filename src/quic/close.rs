@@ -0,0 +1,227 @@
+//! 拒绝 QUIC 连接时回复的 CONNECTION_CLOSE
+//!
+//! 被白名单拒绝的客户端如果收不到任何回复，要等待多个 PTO (数秒) 才会放弃
+//! 并回退到 TCP。这里以服务端身份构造一个只含 CONNECTION_CLOSE frame 的
+//! Initial 包，让客户端立即结束连接。
+//!
+//! 参考 RFC 9000 Section 10.2.3: Immediate Close during the Handshake
+//! 参考 RFC 9000 Section 19.19: CONNECTION_CLOSE Frames
+//! 参考 RFC 9001 Section 5: Packet Protection
+
+use crate::quic::crypto::InitialKeys;
+use crate::quic::decrypt::construct_nonce;
+use crate::quic::error::{QuicError, Result};
+use crate::quic::header::apply_header_protection;
+use crate::quic::parser::{encode_varint, initial_packet_type};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_128_GCM};
+
+/// 传输层错误码 NO_ERROR (RFC 9000 Section 20.1)，用于代理正常关闭
+pub const NO_ERROR: u64 = 0x00;
+
+/// 传输层错误码 CONNECTION_REFUSED (RFC 9000 Section 20.1)
+pub const CONNECTION_REFUSED: u64 = 0x02;
+
+/// TLS no_application_protocol alert 对应的 CRYPTO_ERROR (RFC 9001 Section 4.8)
+pub const NO_APPLICATION_PROTOCOL: u64 = 0x0100 + 120;
+
+/// CONNECTION_CLOSE frame 类型 (传输层错误)
+const FRAME_CONNECTION_CLOSE: u64 = 0x1c;
+
+/// 我们构造的包固定使用 2 字节 Packet Number 编码
+const PN_LEN: usize = 2;
+
+/// AES-128-GCM 认证标签长度
+const TAG_LEN: usize = 16;
+
+/// 构造不带 reason phrase 的 CONNECTION_CLOSE frame
+///
+/// ```text
+/// CONNECTION_CLOSE Frame {
+///   Type (i) = 0x1c,
+///   Error Code (i),
+///   Frame Type (i),
+///   Reason Phrase Length (i),
+///   Reason Phrase (..),
+/// }
+/// ```
+pub fn connection_close_frame(error_code: u64) -> Vec<u8> {
+    let mut frame = encode_varint(FRAME_CONNECTION_CLOSE);
+    frame.extend_from_slice(&encode_varint(error_code));
+    frame.extend_from_slice(&encode_varint(0)); // 不是由某个 frame 触发
+    frame.extend_from_slice(&encode_varint(0)); // 无 reason phrase
+    frame
+}
+
+/// 用给定密钥保护 frames，返回完整的 Initial 包
+///
+/// Token 长度固定为 0，Length 字段固定用 2 字节 VarInt。
+pub fn seal_initial_packet(
+    version: u32,
+    keys: &InitialKeys,
+    dcid: &[u8],
+    scid: &[u8],
+    packet_number: u64,
+    frames: &[u8],
+) -> Result<Vec<u8>> {
+    // Header Protection 的 sample 从 PN 起第 4 字节开始取 16 字节，
+    // payload 太短时补 PADDING (0x00)
+    let mut plaintext = frames.to_vec();
+    plaintext.resize(frames.len().max(4 - PN_LEN), 0x00);
+
+    let mut header = vec![0xc0 | (initial_packet_type(version) << 4) | (PN_LEN as u8 - 1)];
+    header.extend_from_slice(&version.to_be_bytes());
+    header.push(dcid.len() as u8);
+    header.extend_from_slice(dcid);
+    header.push(scid.len() as u8);
+    header.extend_from_slice(scid);
+    header.push(0); // Token length
+
+    let length = (PN_LEN + plaintext.len() + TAG_LEN) as u16;
+    header.extend_from_slice(&(length | 0x4000).to_be_bytes());
+    let pn_offset = header.len();
+    header.extend_from_slice(&(packet_number as u16).to_be_bytes());
+
+    let nonce = construct_nonce(&keys.iv, packet_number)?;
+    let unbound_key = UnboundKey::new(&AES_128_GCM, &keys.key)
+        .map_err(|e| QuicError::Other(format!("Failed to create AEAD key: {:?}", e)))?;
+    LessSafeKey::new(unbound_key)
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(&header[..]),
+            &mut plaintext,
+        )
+        .map_err(|e| QuicError::Other(format!("Encryption failed: {:?}", e)))?;
+
+    let mut packet = header;
+    packet.extend_from_slice(&plaintext);
+    apply_header_protection(&mut packet, pn_offset, PN_LEN, keys)?;
+
+    Ok(packet)
+}
+
+/// 构造回复给客户端的 CONNECTION_CLOSE Initial 包
+///
+/// # 参数
+/// - `version`: 客户端 Initial 的版本号
+/// - `server_keys`: 由客户端原始 DCID 派生的 server 方向 Initial 密钥
+/// - `client_dcid`: 客户端包中的 DCID (回复中作为 SCID)
+/// - `client_scid`: 客户端包中的 SCID (回复中作为 DCID)
+/// - `error_code`: 传输层错误码
+pub fn build_connection_close(
+    version: u32,
+    server_keys: &InitialKeys,
+    client_dcid: &[u8],
+    client_scid: &[u8],
+    error_code: u64,
+) -> Result<Vec<u8>> {
+    seal_initial_packet(
+        version,
+        server_keys,
+        client_scid,
+        client_dcid,
+        0,
+        &connection_close_frame(error_code),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quic::crypto::{derive_initial_keys_for_role, InitialKeyRole};
+    use crate::quic::header::remove_header_protection;
+    use crate::quic::parser::{parse_initial_header, parse_varint, QUIC_VERSION_1, QUIC_VERSION_2};
+
+    const CLIENT_DCID: [u8; 8] = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+    const CLIENT_SCID: [u8; 4] = [0xaa, 0xbb, 0xcc, 0xdd];
+
+    /// 以客户端的视角打开服务端 Initial 包，返回 (header, 明文 payload)
+    fn open_as_client(
+        packet: &[u8],
+        version: u32,
+    ) -> Result<(crate::quic::parser::InitialHeader, Vec<u8>)> {
+        let keys = derive_initial_keys_for_role(&CLIENT_DCID, version, InitialKeyRole::Server)?;
+        let header = parse_initial_header(packet)?;
+        let mut packet = packet.to_vec();
+        let (_, packet_number, pn_len) =
+            remove_header_protection(&mut packet, header.pn_offset, &keys)?;
+
+        let payload_start = header.pn_offset + pn_len as usize;
+        let payload_end = header.pn_offset + header.payload_len;
+        let (aad, rest) = packet.split_at(payload_start);
+        let mut payload = rest[..payload_end - payload_start].to_vec();
+
+        let key = LessSafeKey::new(UnboundKey::new(&AES_128_GCM, &keys.key).unwrap());
+        let plaintext = key
+            .open_in_place(
+                Nonce::assume_unique_for_key(construct_nonce(&keys.iv, packet_number)?),
+                Aad::from(aad),
+                &mut payload,
+            )
+            .map_err(|e| QuicError::DecryptionFailed(format!("{:?}", e)))?
+            .to_vec();
+        Ok((header, plaintext))
+    }
+
+    fn server_keys(version: u32) -> InitialKeys {
+        derive_initial_keys_for_role(&CLIENT_DCID, version, InitialKeyRole::Server).unwrap()
+    }
+
+    #[test]
+    fn test_connection_close_frame_encoding() {
+        assert_eq!(
+            connection_close_frame(CONNECTION_REFUSED),
+            vec![0x1c, 0x02, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn test_connection_close_decrypts_with_client_view_keys() {
+        for version in [QUIC_VERSION_1, QUIC_VERSION_2] {
+            let packet = build_connection_close(
+                version,
+                &server_keys(version),
+                &CLIENT_DCID,
+                &CLIENT_SCID,
+                CONNECTION_REFUSED,
+            )
+            .unwrap();
+
+            let (header, plaintext) = open_as_client(&packet, version).unwrap();
+            assert_eq!(header.version, version);
+            assert_eq!(&header.dcid[..], &CLIENT_SCID);
+            assert_eq!(&header.scid[..], &CLIENT_DCID);
+            assert_eq!(header.pn_offset + header.payload_len, packet.len());
+
+            assert_eq!(plaintext[0], 0x1c);
+            let (error_code, _) = parse_varint(&plaintext[1..]).unwrap();
+            assert_eq!(error_code, CONNECTION_REFUSED);
+        }
+    }
+
+    #[test]
+    fn test_connection_close_is_not_readable_with_client_keys() {
+        let client_keys =
+            derive_initial_keys_for_role(&CLIENT_DCID, QUIC_VERSION_1, InitialKeyRole::Client)
+                .unwrap();
+        let packet = build_connection_close(
+            QUIC_VERSION_1,
+            &client_keys,
+            &CLIENT_DCID,
+            &CLIENT_SCID,
+            CONNECTION_REFUSED,
+        )
+        .unwrap();
+
+        assert!(open_as_client(&packet, QUIC_VERSION_1).is_err());
+    }
+
+    #[test]
+    fn test_short_payload_is_padded_for_header_protection_sample() {
+        let keys = server_keys(QUIC_VERSION_1);
+        let packet =
+            seal_initial_packet(QUIC_VERSION_1, &keys, &CLIENT_SCID, &[], 0, &[0x01]).unwrap();
+
+        let header = parse_initial_header(&packet).unwrap();
+        assert!(packet.len() >= header.pn_offset + 4 + 16);
+    }
+}
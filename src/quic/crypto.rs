@@ -5,8 +5,25 @@
 
 use crate::quic::error::{QuicError, Result};
 use ring::hkdf::{Prk, Salt, HKDF_SHA256};
+use std::sync::atomic::{AtomicBool, Ordering};
 use tracing::debug;
 
+/// 是否允许在 trace 日志中输出密钥材料 (key / IV / nonce / HP mask)
+///
+/// 默认关闭；只有配置 `quic.debug_crypto = true` 时才会打开，
+/// 避免密钥出现在生产日志中。
+static DEBUG_CRYPTO: AtomicBool = AtomicBool::new(false);
+
+/// 设置是否输出密钥材料日志
+pub fn set_debug_crypto(enabled: bool) {
+    DEBUG_CRYPTO.store(enabled, Ordering::Relaxed);
+}
+
+/// 是否输出密钥材料日志
+pub fn debug_crypto_enabled() -> bool {
+    DEBUG_CRYPTO.load(Ordering::Relaxed)
+}
+
 /// QUIC Version 1 Initial Salt
 ///
 /// 这是用于从 DCID 派生初始密钥的 Salt 值。
@@ -33,18 +50,18 @@ pub const INITIAL_SALT_V2: &[u8] = &[
 /// - key: 用于 AES-GCM 解密 payload
 /// - iv: 初始化向量
 /// - hp_key: 用于 header protection
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy)]
 pub struct InitialKeys {
     /// AEAD 密钥 (16 bytes for AES-128-GCM)
-    pub key: Vec<u8>,
+    pub key: [u8; 16],
     /// 初始化向量 (12 bytes)
-    pub iv: Vec<u8>,
+    pub iv: [u8; 12],
     /// Header Protection 密钥 (16 bytes for AES-ECB)
-    pub hp_key: Vec<u8>,
+    pub hp_key: [u8; 16],
 }
 
 /// QUIC Initial keys role (client vs server)
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum InitialKeyRole {
     Client,
     Server,
@@ -90,14 +107,18 @@ fn label_quic_hp(version: u32) -> &'static [u8] {
 /// - 包含 key, iv, hp_key 的 InitialKeys 结构
 ///
 /// # 示例
-/// ```ignore
-/// let dcid = vec![0x01, 0x02, 0x03, 0x04];
+/// RFC 9001 附录 A.1 的测试向量：
+///
+/// ```
+/// use sniproxy_ng::quic::crypto::derive_initial_keys;
+///
+/// let dcid = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
 /// let keys = derive_initial_keys(&dcid, 0x00000001)?;
-/// assert_eq!(keys.key.len(), 16);
-/// assert_eq!(keys.iv.len(), 12);
-/// assert_eq!(keys.hp_key.len(), 16);
+/// assert_eq!(keys.key[..4], [0x1f, 0x36, 0x96, 0x13]);
+/// assert_eq!(keys.iv[..4], [0xfa, 0x04, 0x4b, 0x2f]);
+/// assert_eq!(keys.hp_key[..4], [0x9f, 0x50, 0x44, 0x9e]);
+/// # Ok::<(), sniproxy_ng::quic::error::QuicError>(())
 /// ```
-#[allow(dead_code)]
 pub fn derive_initial_keys(dcid: &[u8], version: u32) -> Result<InitialKeys> {
     derive_initial_keys_for_role(dcid, version, InitialKeyRole::Client)
 }
@@ -112,28 +133,71 @@ pub fn derive_initial_keys_for_role(
     role: InitialKeyRole,
 ) -> Result<InitialKeys> {
     debug!(
-        "Deriving initial keys from DCID: {:?} ({} bytes), version: {:#x}",
-        dcid,
-        dcid.len(),
-        version
+        dcid_len = dcid.len(),
+        version = %format_args!("{:#x}", version),
+        role = ?role,
+        "deriving Initial keys"
     );
 
+    // Step 1-2: HKDF-Extract + HKDF-Expand-Label ("client in" / "server in")
+    let client_initial_secret_bytes = derive_role_secret(dcid, version, role)?;
+
+    debug!(role = ?role, "Initial secret derived for role");
+
+    // 将 Vec<u8> 转换为 Prk
+    let client_initial_secret = Prk::new_less_safe(HKDF_SHA256, &client_initial_secret_bytes);
+
+    // Step 3: Derive key (AES-128-GCM key = 16 bytes)
+    let key = hkdf_expand_label(&client_initial_secret, label_quic_key(version), b"", 16)
+        .map_err(|e| QuicError::KeyDerivationFailed(format!("HKDF-Expand 'quic key': {}", e)))?;
+
+    debug!(bytes = key.len(), "AEAD key derived");
+
+    // Step 4: Derive IV (12 bytes for QUIC)
+    let iv = hkdf_expand_label(&client_initial_secret, label_quic_iv(version), b"", 12)
+        .map_err(|e| QuicError::KeyDerivationFailed(format!("HKDF-Expand 'quic iv': {}", e)))?;
+
+    debug!(bytes = iv.len(), "IV derived");
+
+    // Step 5: Derive Header Protection key (16 bytes for AES-128-ECB)
+    let hp_key = hkdf_expand_label(&client_initial_secret, label_quic_hp(version), b"", 16)
+        .map_err(|e| QuicError::KeyDerivationFailed(format!("HKDF-Expand 'quic hp': {}", e)))?;
+
+    debug!(bytes = hp_key.len(), "HP key derived");
+
+    Ok(InitialKeys {
+        key: fixed_len(key, "quic key")?,
+        iv: fixed_len(iv, "quic iv")?,
+        hp_key: fixed_len(hp_key, "quic hp")?,
+    })
+}
+
+/// 派生 `role` 方向的 Initial secret (client_initial_secret / server_initial_secret)
+///
+/// ```text
+/// initial_secret = HKDF-Extract(initial_salt, dcid)
+/// secret = HKDF-Expand-Label(initial_secret, "client in" | "server in", "", 32)
+/// ```
+fn derive_role_secret(dcid: &[u8], version: u32, role: InitialKeyRole) -> Result<Vec<u8>> {
     // Step 1: HKDF-Extract
     // RFC 9001: initial_secret = HKDF-Extract(salt, dcid)
     // 根据 QUIC 版本选择正确的 Salt
     let salt_bytes = match version {
         0x00000001 => {
-            debug!("Using QUIC v1 Initial Salt");
+            debug!("using QUIC v1 Initial salt");
             INITIAL_SALT_V1
         }
         // QUIC v2 (draft / final)
         0x6b3343cf | 0x709a50c4 => {
-            debug!("Using QUIC v2 Initial Salt");
+            debug!("using QUIC v2 Initial salt");
             INITIAL_SALT_V2
         }
         _ => {
             // 未知版本，默认使用 v1 salt（向后兼容）
-            debug!("Unknown QUIC version {:#x}, defaulting to v1 salt", version);
+            debug!(
+                version = %format_args!("{:#x}", version),
+                "unknown QUIC version, defaulting to v1 salt"
+            );
             INITIAL_SALT_V1
         }
     };
@@ -141,12 +205,12 @@ pub fn derive_initial_keys_for_role(
     let salt = Salt::new(HKDF_SHA256, salt_bytes);
     let initial_secret = salt.extract(dcid);
 
-    debug!("Initial secret derived: {} bytes", 32);
+    debug!(bytes = 32, "Initial secret derived");
 
     // Step 2: HKDF-Expand-Label for "client in" / "server in"
     // RFC 8446 Section 7.1
     // ring 的 extract() 已经返回 Prk，我们可以直接用它来 expand
-    let client_initial_secret_bytes = {
+    let secret = {
         struct LengthLimit(usize);
         impl ring::hkdf::KeyType for LengthLimit {
             fn len(&self) -> usize {
@@ -173,31 +237,15 @@ pub fn derive_initial_keys_for_role(
             .map_err(|e| QuicError::KeyDerivationFailed(format!("Fill '{:?}': {}", role, e)))?;
         secret
     };
+    Ok(secret)
+}
 
-    debug!("Initial secret derived for role: {:?}", role);
-
-    // 将 Vec<u8> 转换为 Prk
-    let client_initial_secret = Prk::new_less_safe(HKDF_SHA256, &client_initial_secret_bytes);
-
-    // Step 3: Derive key (AES-128-GCM key = 16 bytes)
-    let key = hkdf_expand_label(&client_initial_secret, label_quic_key(version), b"", 16)
-        .map_err(|e| QuicError::KeyDerivationFailed(format!("HKDF-Expand 'quic key': {}", e)))?;
-
-    debug!("AEAD key derived: {} bytes", key.len());
-
-    // Step 4: Derive IV (12 bytes for QUIC)
-    let iv = hkdf_expand_label(&client_initial_secret, label_quic_iv(version), b"", 12)
-        .map_err(|e| QuicError::KeyDerivationFailed(format!("HKDF-Expand 'quic iv': {}", e)))?;
-
-    debug!("IV derived: {} bytes", iv.len());
-
-    // Step 5: Derive Header Protection key (16 bytes for AES-128-ECB)
-    let hp_key = hkdf_expand_label(&client_initial_secret, label_quic_hp(version), b"", 16)
-        .map_err(|e| QuicError::KeyDerivationFailed(format!("HKDF-Expand 'quic hp': {}", e)))?;
-
-    debug!("HP key derived: {} bytes", hp_key.len());
-
-    Ok(InitialKeys { key, iv, hp_key })
+/// 将 HKDF 输出转换为定长数组
+fn fixed_len<const N: usize>(bytes: Vec<u8>, label: &str) -> Result<[u8; N]> {
+    let len = bytes.len();
+    bytes.try_into().map_err(|_| {
+        QuicError::KeyDerivationFailed(format!("'{}' has {} bytes, expected {}", label, len, N))
+    })
 }
 
 /// HKDF-Expand-Label 函数
@@ -305,37 +353,41 @@ impl HkdfLabel {
 mod tests {
     use super::*;
 
-    /// RFC 9001 Appendix A.3 - Test Case 1
-    ///
-    /// 输入 DCID: 0x8394c8f03e515708
-    /// 期望输出 client_initial_secret: 0x2d... (32 bytes)
+    use crate::quic::test_util::{hex, RFC9001_DCID};
+
+    /// RFC 9001 Appendix A.1: client 方向的 Initial secret 和密钥
+    #[test]
+    fn test_rfc9001_client_initial_keys() {
+        assert_eq!(
+            derive_role_secret(&RFC9001_DCID, 0x00000001, InitialKeyRole::Client).unwrap(),
+            hex("c00cf151ca5be075ed0ebfb5c80323c42d6b7db67881289af4008f1f6c357aea")
+        );
+
+        let keys = derive_initial_keys(&RFC9001_DCID, 0x00000001).unwrap();
+        assert_eq!(keys.key.to_vec(), hex("1f369613dd76d5467730efcbe3b1a22d"));
+        assert_eq!(keys.iv.to_vec(), hex("fa044b2f42a3fd3b46fb255c"));
+        assert_eq!(
+            keys.hp_key.to_vec(),
+            hex("9f50449e04a0e810283a1e9933adedd2")
+        );
+    }
+
+    /// RFC 9001 Appendix A.1: server 方向的 Initial secret 和密钥
     #[test]
-    fn test_rfc9001_test_vector_1() {
-        let dcid = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
-
-        let keys = derive_initial_keys(&dcid, 0x00000001).expect("Failed to derive keys");
-
-        // 验证长度
-        assert_eq!(keys.key.len(), 16, "Key length should be 16 bytes");
-        assert_eq!(keys.iv.len(), 12, "IV length should be 12 bytes");
-        assert_eq!(keys.hp_key.len(), 16, "HP key length should be 16 bytes");
-
-        // RFC 9001 Appendix A.3 提供了完整的测试向量
-        // client_initial_secret (前 16 bytes):
-        // 0x2d, 0x5a, 0x8b, 0xa6, 0x2f, 0x86, 0x30, 0xb9,
-        // 0x55, 0x5d, 0xb6, 0x5f, 0x39, 0xbd, 0x8f, 0x31,
-        //
-        // quic_key: 0x1f, 0x36, 0x46, 0x47, 0x98, 0x39, 0x89, 0x45,
-        //          0x04, 0xdf, 0x0b, 0x96, 0x54, 0x70, 0x4f, 0x84,
-        //
-        // quic_iv: 0x5b, 0x6c, 0x9f, 0x0e, 0x7e, 0x6a, 0x7b, 0xb4,
-        //         0x1d, 0xb6, 0x56, 0x34,
-        //
-        // quic_hp: 0x7a, 0xc8, 0x5c, 0x72, 0x6d, 0xa2, 0x28, 0x6e,
-        //         0x7d, 0x5e, 0x4b, 0x49, 0xb1, 0x66, 0x43, 0x80,
-
-        // 注意：由于我们没有完整的 RFC 测试向量验证，
-        // 这里只验证长度。在生产环境中应该使用完整的测试向量。
+    fn test_rfc9001_server_initial_keys() {
+        assert_eq!(
+            derive_role_secret(&RFC9001_DCID, 0x00000001, InitialKeyRole::Server).unwrap(),
+            hex("3c199828fd139efd216c155ad844cc81fb82fa8d7446fa7d78be803acdda951b")
+        );
+
+        let keys = derive_initial_keys_for_role(&RFC9001_DCID, 0x00000001, InitialKeyRole::Server)
+            .unwrap();
+        assert_eq!(keys.key.to_vec(), hex("cf3a5331653c364c88f0f379b6067e37"));
+        assert_eq!(keys.iv.to_vec(), hex("0ac1493ca1905853b0bba03e"));
+        assert_eq!(
+            keys.hp_key.to_vec(),
+            hex("c206b8d9b9f0f37644430b490eeaa314")
+        );
     }
 
     #[test]
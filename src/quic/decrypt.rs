@@ -3,38 +3,41 @@
 //! 参考 RFC 9001 Section 5: Packet Protection
 //! 参考 RFC 9000 Section 18: QUIC Frames (CRYPTO Frame)
 
-use crate::quic::crypto::{InitialKeyRole, InitialKeys};
+use crate::quic::crypto::{debug_crypto_enabled, InitialKeyRole, InitialKeys};
 use crate::quic::error::{QuicError, Result};
-use crate::quic::parser::parse_varint;
-use crate::tls::sni::{extract_sni, SniError};
+use crate::quic::frame::{parse_frames, Frame};
+use crate::quic::key_cache::InitialKeyCache;
+use crate::quic::reassembly::CryptoReassembler;
+use crate::tls::sni::{handshake_message_len, parse_client_hello, ClientHelloStatus};
 use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_128_GCM};
-use std::collections::{BTreeMap, HashMap};
-use std::sync::{Mutex, Once};
-use std::time::{Duration, Instant};
-use tracing::{debug, info, warn};
-
-#[derive(Debug)]
-struct PendingCrypto {
-    role: InitialKeyRole,
-    fragments: BTreeMap<u64, Vec<u8>>,
-    last_update: Instant,
+use tracing::{debug, info, trace, warn};
+
+/// 单个 Initial 包的 SNI 提取结果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SniExtraction {
+    /// ClientHello 完整，携带 SNI
+    ///
+    /// 使用 ECH 时 `sni` 是外层 ClientHello 的 public_name，`ech` 为 true；
+    /// `alpn` 为客户端提供的 ALPN 列表 (没有 ALPN 扩展时为空)。
+    Found {
+        sni: String,
+        ech: bool,
+        alpn: Vec<String>,
+    },
+    /// ClientHello 完整，但没有 SNI 扩展
+    Missing { ech: bool, alpn: Vec<String> },
+    /// ClientHello 尚未收全，分片缓存在重组器中，需要等待后续 Initial
+    ///
+    /// `have` 为从 offset 0 起连续收到的 CRYPTO 字节数；`need` 为 Handshake
+    /// 头部声明的总长度，头部 (4 字节) 尚未收全时为 None。
+    Incomplete { have: usize, need: Option<usize> },
 }
 
-// NOTE: Avoid std::sync::OnceLock to keep compatibility with older Rust toolchains.
-// This is a small, controlled unsafe initialization for a global Mutex<HashMap<...>>.
-static PENDING_CRYPTO_INIT: Once = Once::new();
-static mut PENDING_CRYPTO_PTR: *const Mutex<HashMap<Vec<u8>, PendingCrypto>> = std::ptr::null();
+/// 监听端只会收到客户端发出的 Initial，默认只尝试 client 方向的密钥
+pub const CLIENT_ROLE: &[InitialKeyRole] = &[InitialKeyRole::Client];
 
-fn pending_crypto_map() -> &'static Mutex<HashMap<Vec<u8>, PendingCrypto>> {
-    unsafe {
-        PENDING_CRYPTO_INIT.call_once(|| {
-            let m = Mutex::new(HashMap::new());
-            PENDING_CRYPTO_PTR = Box::into_raw(Box::new(m));
-        });
-        // SAFETY: initialized by Once exactly once and never freed (intentionally global).
-        &*PENDING_CRYPTO_PTR
-    }
-}
+/// 依次尝试 client 和 server 方向的密钥 (`quic.try_server_role = true`)
+pub const BOTH_ROLES: &[InitialKeyRole] = &[InitialKeyRole::Client, InitialKeyRole::Server];
 
 /// 从 QUIC Initial Packet 中提取 SNI
 ///
@@ -45,107 +48,126 @@ fn pending_crypto_map() -> &'static Mutex<HashMap<Vec<u8>, PendingCrypto>> {
 /// 4. 解密 CRYPTO Frame
 /// 5. 解析 TLS ClientHello 提取 SNI
 ///
+/// ClientHello 跨多个 Initial 包时，分片缓存在 `reassembler` 中，
+/// 在 ClientHello 完整之前返回 [`SniExtraction::Incomplete`]。
+///
 /// # 参数
 /// - `packet`: 完整的 UDP payload (QUIC Initial Packet)
+/// - `reassembler`: CRYPTO 分片重组状态
+/// - `key_cache`: Initial 密钥缓存，重传包复用已派生的密钥
 ///
 /// # 返回
-/// - SNI (如果找到)
+/// - 提取结果 (找到 SNI / 没有 SNI / ClientHello 尚未收全)
 ///
 /// # 示例
-/// ```ignore
-/// let packet = hex::decode("c30000000108...")?;
-/// let sni = extract_sni_from_quic_initial(&packet)?;
-/// assert_eq!(sni, Some("www.google.com".to_string()));
 /// ```
-pub fn extract_sni_from_quic_initial(packet: &mut [u8]) -> Result<Option<String>> {
-    debug!(
-        "Starting QUIC SNI extraction (packet length: {})",
-        packet.len()
-    );
-    debug!(
-        "Raw packet header (first 32 bytes): {:02x?}",
-        &packet[..packet.len().min(32)]
-    );
+/// use sniproxy_ng::quic::decrypt::{extract_sni_from_quic_initial, SniExtraction};
+/// use sniproxy_ng::quic::key_cache::InitialKeyCache;
+/// use sniproxy_ng::quic::reassembly::CryptoReassembler;
+/// use sniproxy_ng::quic::test_util::rfc9001_client_initial;
+///
+/// let mut reassembler = CryptoReassembler::default();
+/// let mut key_cache = InitialKeyCache::default();
+/// let mut packet = rfc9001_client_initial();
+/// let sni = extract_sni_from_quic_initial(&mut packet, &mut reassembler, &mut key_cache)?;
+/// assert_eq!(
+///     sni,
+///     SniExtraction::Found {
+///         sni: "example.com".to_string(),
+///         ech: false,
+///         alpn: vec!["alpn".to_string()],
+///     }
+/// );
+/// # Ok::<(), sniproxy_ng::quic::error::QuicError>(())
+/// ```
+pub fn extract_sni_from_quic_initial(
+    packet: &mut [u8],
+    reassembler: &mut CryptoReassembler,
+    key_cache: &mut InitialKeyCache,
+) -> Result<SniExtraction> {
+    extract_sni_with_roles(packet, CLIENT_ROLE, reassembler, key_cache)
+}
+
+/// 按 `roles` 的顺序尝试各方向的 Initial 密钥提取 SNI
+///
+/// 某个方向的 reserved bits 校验和 AEAD 解密都通过后即以其结果为准，不再尝试其余方向。
+pub fn extract_sni_with_roles(
+    packet: &mut [u8],
+    roles: &[InitialKeyRole],
+    reassembler: &mut CryptoReassembler,
+    key_cache: &mut InitialKeyCache,
+) -> Result<SniExtraction> {
+    debug!(bytes = packet.len(), "starting quic sni extraction");
 
     // Step 1: 解析 Initial Header
     let header = crate::quic::parse_initial_header(packet)?;
     debug!(
-        "Parsed Initial header: version={:#x}, dcid_len={}, scid_len={}, token_len={}, payload_len={}, pn_offset={}",
-        header.version,
-        header.dcid.len(),
-        header.scid.len(),
-        header.token_len,
-        header.payload_len,
-        header.pn_offset
+        version = %format_args!("{:#x}", header.version),
+        dcid_len = header.dcid.len(),
+        scid_len = header.scid.len(),
+        token_len = header.token_len,
+        payload_len = header.payload_len,
+        pn_offset = header.pn_offset,
+        "Initial header parsed"
     );
 
     // Packet Number length is protected by QUIC header protection, so this value is
     // only useful for low-level debugging before header protection is removed.
     let protected_pn_len = (packet[0] & 0x03) + 1;
     debug!(
-        "Protected PN length bits before unprotection: {}",
-        protected_pn_len
-    );
-    debug!(
-        "Initial header parsed: version={:#x}, dcid_len={}",
-        header.version,
-        header.dcid.len()
+        protected_pn_len,
+        "protected PN length bits before unprotection"
     );
 
-    // Step 2/3/4/5: Try both directions (client/server).
+    // Step 2/3/4/5: 依次尝试各方向的密钥
     //
-    // QUIC Initial header looks the same in both directions; to be robust we try both
-    // "client in" and "server in" labels and pick the one that yields valid reserved bits
-    // and successful AEAD decryption.
-    let original = packet.to_vec();
-    for role in [InitialKeyRole::Client, InitialKeyRole::Server] {
-        let mut pkt = original.clone();
-        debug!("Trying QUIC Initial decryption role: {:?}", role);
+    // 两个方向的 Initial header 格式相同；取第一个 reserved bits 合法且 AEAD 解密成功的方向。
+    // Header Protection 只改动首字节和 Packet Number，换方向重试时只需恢复这几个字节，
+    // 不必重新复制整个包。
+    let mut pkt = packet.to_vec();
+    let protected_end = (header.pn_offset + 4).min(packet.len());
+    for (attempt, &role) in roles.iter().enumerate() {
+        if attempt > 0 {
+            pkt[0] = packet[0];
+            pkt[header.pn_offset..protected_end]
+                .copy_from_slice(&packet[header.pn_offset..protected_end]);
+        }
+        debug!(role = ?role, "trying Initial decryption role");
 
+        let keys = key_cache.get_or_derive(&header.dcid, header.version, role)?;
         debug!(
-            "Deriving keys from DCID: {:02x?} ({} bytes), version: {:#x}, role={:?}",
-            header.dcid,
-            header.dcid.len(),
-            header.version,
-            role
-        );
-        let keys =
-            crate::quic::crypto::derive_initial_keys_for_role(&header.dcid, header.version, role)?;
-        debug!(
-            "Initial keys derived successfully, pn_offset={}",
-            header.pn_offset
+            pn_offset = header.pn_offset,
+            "Initial keys derived, removing header protection"
         );
-
-        debug!("Removing header protection at offset {}", header.pn_offset);
         let (unprotected_first_byte, packet_number, pn_len) =
             crate::quic::remove_header_protection(&mut pkt, header.pn_offset, &keys)?;
-        debug!(
-            "Header protection removed: PN={}, pn_len={}",
-            packet_number, pn_len
-        );
+        debug!(packet_number, pn_len, "header protection removed");
 
         // Long Header reserved bits are bits 3-2; after unprotection they MUST be 0.
         let reserved = (unprotected_first_byte & 0x0c) >> 2;
         debug!(
-            "Unprotected first byte: {:#04x} (reserved bits={:#x})",
-            unprotected_first_byte, reserved
+            first_byte = %format_args!("{:#04x}", unprotected_first_byte),
+            reserved,
+            "first byte unprotected"
         );
         if reserved != 0 {
             warn!(
-                "Role {:?}: reserved bits non-zero after header unprotection (reserved={:#x}); skipping decrypt attempt.",
-                role, reserved
+                role = ?role,
+                reserved,
+                "reserved bits non-zero after header unprotection, skipping decrypt attempt"
             );
             continue;
         }
 
         if packet_number >= 100 {
             warn!(
-                "Packet Number {} is unusually large for Initial packet. Attempting decryption anyway. (role={:?})",
-                packet_number, role
+                packet_number,
+                role = ?role,
+                "packet number is unusually large for an Initial, attempting decryption anyway"
             );
         }
 
-        debug!("Extracting and decrypting CRYPTO frame (role={:?})", role);
+        debug!(role = ?role, "extracting and decrypting CRYPTO frames");
         let crypto_data = match extract_and_decrypt_crypto_frame(
             &pkt,
             header.pn_offset,
@@ -155,50 +177,54 @@ pub fn extract_sni_from_quic_initial(packet: &mut [u8]) -> Result<Option<String>
             &keys,
             &header.dcid,
             role,
+            reassembler,
         ) {
             Ok(v) => v,
             Err(e) => {
-                warn!("Role {:?}: decryption attempt failed: {}", role, e);
+                warn!(role = ?role, error = %e, "decryption attempt failed");
                 continue;
             }
         };
-        debug!(
-            "CRYPTO stream available: {} bytes (role={:?})",
-            crypto_data.len(),
-            role
-        );
-
-        let sni = match extract_sni(&crypto_data) {
-            Ok(sni) => sni,
-            Err(e) if matches!(e.downcast_ref::<SniError>(), Some(SniError::DataTooShort)) => {
+        debug!(bytes = crypto_data.len(), role = ?role, "CRYPTO stream available");
+
+        let hello = match parse_client_hello(&crypto_data)? {
+            ClientHelloStatus::Complete(hello) => hello,
+            ClientHelloStatus::NeedMoreData { .. } => {
+                // 只有 Handshake 消息本身还没收全才值得等待；
+                // 消息已完整却仍然太短 (DataTooShort) 说明 ClientHello 格式错误
+                let have = crypto_data.len();
+                let need = handshake_message_len(&crypto_data);
                 debug!(
-                    "TLS ClientHello is incomplete ({} bytes available); waiting for more CRYPTO data",
-                    crypto_data.len()
+                    have,
+                    need = ?need,
+                    "ClientHello incomplete, waiting for more CRYPTO data"
                 );
-                return Ok(None);
-            }
-            Err(e) => {
-                return Err(QuicError::TlsError(format!(
-                    "Failed to extract SNI from TLS: {}",
-                    e
-                )));
+                return Ok(SniExtraction::Incomplete { have, need });
             }
         };
 
-        if let Some(ref sni) = sni {
-            info!("✅ Successfully extracted SNI: {} (role={:?})", sni, role);
-        } else {
-            debug!("⚠️  No SNI found in packet (role={:?})", role);
-        }
+        // ClientHello 已完整，不再需要缓存分片
+        reassembler.remove(&header.dcid);
 
         // Preserve the decoded packet bytes for any downstream debugging.
         packet.copy_from_slice(&pkt);
-        return Ok(sni);
+        let (ech, alpn) = (hello.ech.is_present(), hello.alpn);
+        return Ok(match hello.sni {
+            Some(sni) => {
+                info!(sni = %sni, role = ?role, ech, alpn = ?alpn, "sni extracted");
+                SniExtraction::Found { sni, ech, alpn }
+            }
+            None => {
+                debug!(role = ?role, ech, alpn = ?alpn, "no SNI in ClientHello");
+                SniExtraction::Missing { ech, alpn }
+            }
+        });
     }
 
-    Err(QuicError::DecryptionFailed(
-        "All QUIC Initial decryption attempts failed (client/server).".to_string(),
-    ))
+    Err(QuicError::DecryptionFailed(format!(
+        "All QUIC Initial decryption attempts failed (roles: {:?})",
+        roles
+    )))
 }
 
 /// 提取并解密 CRYPTO Frame
@@ -212,9 +238,10 @@ pub fn extract_sni_from_quic_initial(packet: &mut [u8]) -> Result<Option<String>
 /// - `pn_len`: Packet Number 长度 (1-4 bytes)
 /// - `packet_number`: Packet Number (已解码)
 /// - `keys`: Initial Keys
+/// - `reassembler`: 跨包 CRYPTO 分片重组状态 (按 DCID)
 ///
 /// # 返回
-/// - 解密后的 CRYPTO data (TLS ClientHello)
+/// - 从 offset 0 开始的连续 CRYPTO data (TLS ClientHello)
 #[allow(clippy::too_many_arguments)]
 fn extract_and_decrypt_crypto_frame(
     packet: &[u8],
@@ -225,6 +252,7 @@ fn extract_and_decrypt_crypto_frame(
     keys: &InitialKeys,
     dcid: &[u8],
     role: InitialKeyRole,
+    reassembler: &mut CryptoReassembler,
 ) -> Result<Vec<u8>> {
     // 计算 payload 的起始位置
     // Payload = PN 之后的所有数据
@@ -243,6 +271,14 @@ fn extract_and_decrypt_crypto_frame(
         .checked_add(payload_len)
         .ok_or_else(|| QuicError::DecryptionFailed("payload_end overflow".to_string()))?;
 
+    // Length 字段至少要覆盖 PN 本身
+    if payload_end < payload_start {
+        return Err(QuicError::DecryptionFailed(format!(
+            "Length field {} shorter than packet number ({} bytes)",
+            payload_len, pn_len
+        )));
+    }
+
     if packet.len() < payload_end {
         return Err(QuicError::PacketTooShort {
             expected: payload_end,
@@ -253,12 +289,11 @@ fn extract_and_decrypt_crypto_frame(
     // Debug aid: dump a small window around PN offset (after header protection removal).
     let dump_start = pn_offset.saturating_sub(12);
     let dump_end = (pn_offset + 24).min(packet.len());
-    debug!(
-        "Bytes around pn_offset {} ({}..{}): {:02x?}",
+    trace!(
         pn_offset,
-        dump_start,
-        dump_end,
-        &packet[dump_start..dump_end]
+        range = ?(dump_start..dump_end),
+        bytes = ?&packet[dump_start..dump_end],
+        "bytes around pn_offset"
     );
 
     // 获取加密的 payload（不包含 header / PN）
@@ -266,23 +301,21 @@ fn extract_and_decrypt_crypto_frame(
     // AEAD AAD = header up to and including PN (after header protection removal)
     let aad = &packet[..payload_start];
     debug!(
-        "AAD length: {} (header..PN), encrypted_payload_len: {} (length field={} includes PN+payload, pn_len={})",
-        aad.len(),
-        encrypted_payload.len(),
-        payload_len,
-        pn_len
+        aad_len = aad.len(),
+        encrypted_payload_len = encrypted_payload.len(),
+        length_field = payload_len,
+        pn_len,
+        "AAD and encrypted payload split"
     );
 
     // 先解密整个 payload (QUIC 中 frame type 也是加密的)
     debug!(
-        "About to decrypt: payload_len={}, packet_number={}, pn_offset={}",
-        encrypted_payload.len(),
-        packet_number,
-        pn_offset
+        payload_len = encrypted_payload.len(),
+        packet_number, pn_offset, "about to decrypt"
     );
-    debug!(
-        "Encrypted payload (first 32 bytes): {:02x?}",
-        &encrypted_payload[..encrypted_payload.len().min(32)]
+    trace!(
+        first_bytes = ?&encrypted_payload[..encrypted_payload.len().min(32)],
+        "encrypted payload"
     );
     // 先解密整个 payload (QUIC 中 frame type 也是加密的)
     // QUIC packet protection 的 AEAD 必须带 AAD（RFC 9001 Section 5.3）：
@@ -299,22 +332,25 @@ fn extract_and_decrypt_crypto_frame(
             )));
         }
 
-        // 分离 ciphertext 和 auth tag
+        // payload 末尾 16 字节为 auth tag
         let ciphertext_len = encrypted_payload.len() - TAG_LEN;
-        let ciphertext = &encrypted_payload[..ciphertext_len];
-        let tag = &encrypted_payload[ciphertext_len..];
 
         debug!(
-            "Decrypting: ciphertext_len={}, tag_len={}, pn={}",
-            ciphertext_len, TAG_LEN, packet_number
+            ciphertext_len,
+            tag_len = TAG_LEN,
+            packet_number,
+            "decrypting"
         );
-        debug!("Key: {:02x?}", keys.key);
-        debug!("IV: {:02x?}", keys.iv);
+        if debug_crypto_enabled() {
+            trace!(key = ?keys.key, iv = ?keys.iv, "packet protection keys");
+        }
 
         // 构造 nonce: IV xor Packet Number
         // RFC 9001: nonce = IV ^ (packet_number as big-endian)
         let nonce = construct_nonce(&keys.iv, packet_number)?;
-        debug!("Nonce constructed: {:02x?}", nonce.as_ref());
+        if debug_crypto_enabled() {
+            trace!(nonce = ?nonce.as_ref(), "nonce constructed");
+        }
 
         // 创建 AEAD key
         let unbound_key = UnboundKey::new(&AES_128_GCM, &keys.key).map_err(|e| {
@@ -322,12 +358,8 @@ fn extract_and_decrypt_crypto_frame(
         })?;
         let aead_key = LessSafeKey::new(unbound_key);
 
-        // 拼接 ciphertext + tag (ring 的格式)
-        let mut ciphertext_and_tag = ciphertext.to_vec();
-        ciphertext_and_tag.extend_from_slice(tag);
-
-        // 解密
-        let mut plaintext = ciphertext_and_tag.clone();
+        // ring 需要 ciphertext + tag 连续存放，与包中的布局相同，复制一次即可原地解密
+        let mut plaintext = encrypted_payload.to_vec();
         aead_key
             .open_in_place(
                 Nonce::assume_unique_for_key(nonce),
@@ -340,72 +372,23 @@ fn extract_and_decrypt_crypto_frame(
         plaintext.truncate(ciphertext_len);
         plaintext
     };
-    debug!(
-        "Decrypted payload: {} bytes, first 10 bytes: {:02x?}",
-        decrypted_payload.len(),
-        &decrypted_payload[..decrypted_payload.len().min(10)]
+    trace!(
+        bytes = decrypted_payload.len(),
+        first_bytes = ?&decrypted_payload[..decrypted_payload.len().min(10)],
+        "payload decrypted"
     );
 
     // Parse QUIC frames and collect CRYPTO fragments.
-    let mut cursor = decrypted_payload.as_slice();
-    let mut crypto_frags: Vec<(u64, Vec<u8>)> = Vec::new();
-
-    while !cursor.is_empty() {
-        let (frame_type, type_len) = parse_varint(cursor).map_err(|e| {
-            QuicError::CryptoFrameError(format!("Failed to parse frame type: {}", e))
-        })?;
-        cursor = &cursor[type_len..];
-
-        match frame_type {
-            0x00 => {
-                // PADDING: one byte per frame, but encoded as varint 0x00. We already consumed it.
-                continue;
-            }
-            0x01 => {
-                // PING: no payload.
-                continue;
+    let crypto_frags: Vec<(u64, Vec<u8>)> = parse_frames(&decrypted_payload)?
+        .into_iter()
+        .filter_map(|frame| match frame {
+            Frame::Crypto { offset, data } => {
+                debug!(offset, length = data.len(), "CRYPTO frame");
+                Some((offset, data.to_vec()))
             }
-            0x06 => {
-                // CRYPTO: Offset (varint) + Length (varint) + Data
-                let (crypto_offset, off_len) = parse_varint(cursor).map_err(|e| {
-                    QuicError::CryptoFrameError(format!("Failed to parse CRYPTO offset: {}", e))
-                })?;
-                cursor = &cursor[off_len..];
-
-                let (crypto_length, len_len) = parse_varint(cursor).map_err(|e| {
-                    QuicError::CryptoFrameError(format!("Failed to parse CRYPTO length: {}", e))
-                })?;
-                cursor = &cursor[len_len..];
-
-                let crypto_length = crypto_length as usize;
-                if cursor.len() < crypto_length {
-                    return Err(QuicError::CryptoFrameError(format!(
-                        "CRYPTO data truncated: expected {}, got {}",
-                        crypto_length,
-                        cursor.len()
-                    )));
-                }
-
-                let data = cursor[..crypto_length].to_vec();
-                cursor = &cursor[crypto_length..];
-                debug!(
-                    "CRYPTO frame: offset={}, length={}, data_len={}",
-                    crypto_offset,
-                    crypto_length,
-                    data.len()
-                );
-                crypto_frags.push((crypto_offset, data));
-            }
-            _ => {
-                // For Initial packets, we mainly care about CRYPTO. Stop on unknown types.
-                debug!(
-                    "Stopping frame parsing on unknown frame type: {:#x}",
-                    frame_type
-                );
-                break;
-            }
-        }
-    }
+            _ => None,
+        })
+        .collect();
 
     if crypto_frags.is_empty() {
         return Err(QuicError::CryptoFrameError(
@@ -414,42 +397,7 @@ fn extract_and_decrypt_crypto_frame(
     }
 
     // Buffer CRYPTO fragments across packets (per DCID).
-    // Keyed by DCID only; if role changes, we reset.
-    let mut map = pending_crypto_map()
-        .lock()
-        .map_err(|_| QuicError::CryptoFrameError("Pending CRYPTO lock poisoned".to_string()))?;
-    let entry = map.entry(dcid.to_vec()).or_insert_with(|| PendingCrypto {
-        role,
-        fragments: BTreeMap::new(),
-        last_update: Instant::now(),
-    });
-
-    // Basic cleanup: if stale, reset.
-    if entry.last_update.elapsed() > Duration::from_secs(3) || entry.role != role {
-        entry.role = role;
-        entry.fragments.clear();
-    }
-    entry.last_update = Instant::now();
-
-    for (off, data) in crypto_frags {
-        entry.fragments.insert(off, data);
-    }
-
-    // Reassemble contiguous CRYPTO stream from offset 0.
-    let mut out: Vec<u8> = Vec::new();
-    let mut cur: u64 = 0;
-    for (off, data) in entry.fragments.iter() {
-        if *off > cur {
-            break; // gap
-        }
-        let start = (cur - *off) as usize;
-        if start < data.len() {
-            out.extend_from_slice(&data[start..]);
-            cur += (data.len() - start) as u64;
-        }
-    }
-
-    Ok(out)
+    Ok(reassembler.insert(dcid, role, crypto_frags))
 }
 
 /// 解密 CRYPTO payload
@@ -473,7 +421,7 @@ fn extract_and_decrypt_crypto_frame(
 ///
 /// # 返回
 /// - Nonce (12 bytes)
-fn construct_nonce(iv: &[u8], packet_number: u64) -> Result<[u8; 12]> {
+pub(crate) fn construct_nonce(iv: &[u8], packet_number: u64) -> Result<[u8; 12]> {
     if iv.len() != 12 {
         return Err(QuicError::DecryptionFailed(format!(
             "Invalid IV length: {} (expected 12)",
@@ -501,6 +449,9 @@ fn construct_nonce(iv: &[u8], packet_number: u64) -> Result<[u8; 12]> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::tls::fixtures;
+    use proptest::prelude::*;
+    use proptest::sample::Index;
 
     #[test]
     fn test_construct_nonce() {
@@ -528,6 +479,460 @@ mod tests {
         assert_eq!(nonce.as_ref(), iv);
     }
 
+    #[test]
+    fn test_extract_sni_from_v1_initial() {
+        use crate::quic::test_util::{build_client_initial, client_hello_handshake, crypto_frame};
+
+        let dcid = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x01];
+        let frames = crypto_frame(0, &client_hello_handshake("www.example.com"));
+        let mut packet = build_client_initial(0x00000001, &dcid, 0, &frames);
+
+        let sni = extract_sni_from_quic_initial(
+            &mut packet,
+            &mut CryptoReassembler::default(),
+            &mut InitialKeyCache::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            sni,
+            SniExtraction::Found {
+                sni: "www.example.com".to_string(),
+                ech: false,
+                alpn: Vec::new()
+            }
+        );
+    }
+
+    /// RFC 9001 Appendix A.2 的完整 client Initial
+    #[test]
+    fn test_rfc9001_client_initial() {
+        use crate::quic::crypto::derive_initial_keys;
+        use crate::quic::header::remove_header_protection;
+        use crate::quic::test_util::{
+            hex, rfc9001_client_initial, RFC9001_CLIENT_CRYPTO_FRAME, RFC9001_DCID,
+        };
+
+        let packet = rfc9001_client_initial();
+        assert_eq!(packet.len(), 1200);
+        // 受保护的 header、sample 和认证标签与 RFC 一致
+        assert_eq!(
+            &packet[..22],
+            &hex("c000000001088394c8f03e5157080000449e7b9aec34")[..]
+        );
+        assert_eq!(
+            &packet[22..38],
+            &hex("d1b1c98dd7689fb8ec11d242b123dc9b")[..]
+        );
+        assert_eq!(
+            &packet[1184..],
+            &hex("e221af44860018ab0856972e194cd934")[..]
+        );
+
+        // 解密得到的 CRYPTO 数据就是 ClientHello (frame 头 4 字节之后)
+        let keys = derive_initial_keys(&RFC9001_DCID, 0x00000001).unwrap();
+        let mut unprotected = packet.clone();
+        let (_, packet_number, pn_len) =
+            remove_header_protection(&mut unprotected, 18, &keys).unwrap();
+        let crypto = extract_and_decrypt_crypto_frame(
+            &unprotected,
+            18,
+            0x049e,
+            pn_len,
+            packet_number,
+            &keys,
+            &RFC9001_DCID,
+            InitialKeyRole::Client,
+            &mut CryptoReassembler::default(),
+        )
+        .unwrap();
+        assert_eq!(crypto, hex(RFC9001_CLIENT_CRYPTO_FRAME)[4..]);
+
+        let mut packet = packet;
+        let sni = extract_sni_from_quic_initial(
+            &mut packet,
+            &mut CryptoReassembler::default(),
+            &mut InitialKeyCache::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            sni,
+            SniExtraction::Found {
+                sni: "example.com".to_string(),
+                ech: false,
+                alpn: vec!["alpn".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn test_extract_sni_from_v2_initial() {
+        use crate::quic::test_util::{build_client_initial, client_hello_handshake, crypto_frame};
+
+        let dcid = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x02];
+        let frames = crypto_frame(0, &client_hello_handshake("quic-v2.example.com"));
+        let mut packet = build_client_initial(0x6b3343cf, &dcid, 0, &frames);
+        assert_eq!(packet[0] & 0x30, 0x10);
+
+        let sni = extract_sni_from_quic_initial(
+            &mut packet,
+            &mut CryptoReassembler::default(),
+            &mut InitialKeyCache::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            sni,
+            SniExtraction::Found {
+                sni: "quic-v2.example.com".to_string(),
+                ech: false,
+                alpn: Vec::new()
+            }
+        );
+    }
+
+    #[test]
+    fn test_extract_sni_after_ack_frame() {
+        use crate::quic::test_util::{build_client_initial, client_hello_handshake, crypto_frame};
+
+        let dcid = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x05];
+        // ACK (largest=0, delay=0, 0 ranges, first range=0) + CRYPTO
+        let mut frames = vec![0x02, 0x00, 0x00, 0x00, 0x00];
+        frames.extend_from_slice(&crypto_frame(
+            0,
+            &client_hello_handshake("retx.example.com"),
+        ));
+        let mut packet = build_client_initial(0x00000001, &dcid, 1, &frames);
+
+        let sni = extract_sni_from_quic_initial(
+            &mut packet,
+            &mut CryptoReassembler::default(),
+            &mut InitialKeyCache::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            sni,
+            SniExtraction::Found {
+                sni: "retx.example.com".to_string(),
+                ech: false,
+                alpn: Vec::new()
+            }
+        );
+    }
+
+    #[test]
+    fn test_extract_outer_sni_from_ech_initial() {
+        use crate::quic::test_util::{
+            build_client_initial, crypto_frame, ech_client_hello_handshake,
+        };
+
+        let dcid = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x0e];
+        let hello = ech_client_hello_handshake(Some("public.example.com"));
+        let mut packet = build_client_initial(0x00000001, &dcid, 0, &crypto_frame(0, &hello));
+        let mut reassembler = CryptoReassembler::default();
+        let mut key_cache = InitialKeyCache::default();
+
+        assert_eq!(
+            extract_sni_from_quic_initial(&mut packet, &mut reassembler, &mut key_cache).unwrap(),
+            SniExtraction::Found {
+                sni: "public.example.com".to_string(),
+                ech: true,
+                alpn: Vec::new()
+            }
+        );
+
+        let hello = ech_client_hello_handshake(None);
+        let mut packet = build_client_initial(0x00000001, &dcid, 0, &crypto_frame(0, &hello));
+        assert_eq!(
+            extract_sni_from_quic_initial(&mut packet, &mut reassembler, &mut key_cache).unwrap(),
+            SniExtraction::Missing {
+                ech: true,
+                alpn: Vec::new()
+            }
+        );
+    }
+
+    #[test]
+    fn test_server_role_is_tried_only_when_enabled() {
+        use crate::quic::close::seal_initial_packet;
+        use crate::quic::crypto::derive_initial_keys_for_role;
+        use crate::quic::test_util::{client_hello_handshake, crypto_frame};
+
+        // 用 server 方向密钥保护的 Initial
+        let dcid = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x05];
+        let keys = derive_initial_keys_for_role(&dcid, 0x00000001, InitialKeyRole::Server).unwrap();
+        let frames = crypto_frame(0, &client_hello_handshake("server.example.com"));
+        let packet = seal_initial_packet(0x00000001, &keys, &dcid, &[], 0, &frames).unwrap();
+        let mut reassembler = CryptoReassembler::default();
+        let mut key_cache = InitialKeyCache::default();
+
+        let mut pkt = packet.clone();
+        assert!(matches!(
+            extract_sni_from_quic_initial(&mut pkt, &mut reassembler, &mut key_cache),
+            Err(QuicError::DecryptionFailed(_))
+        ));
+        assert_eq!(pkt, packet);
+
+        let mut pkt = packet.clone();
+        assert_eq!(
+            extract_sni_with_roles(&mut pkt, BOTH_ROLES, &mut reassembler, &mut key_cache).unwrap(),
+            SniExtraction::Found {
+                sni: "server.example.com".to_string(),
+                ech: false,
+                alpn: Vec::new()
+            }
+        );
+    }
+
+    #[test]
+    fn test_extract_sni_across_two_initials() {
+        use crate::quic::test_util::{build_client_initial, client_hello_handshake, crypto_frame};
+
+        let dcid = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x03];
+        let hello = client_hello_handshake("split.example.com");
+        let (head, tail) = hello.split_at(hello.len() / 2);
+        let mut reassembler = CryptoReassembler::default();
+        let mut key_cache = InitialKeyCache::default();
+
+        let mut first = build_client_initial(0x00000001, &dcid, 0, &crypto_frame(0, head));
+        assert_eq!(
+            extract_sni_from_quic_initial(&mut first, &mut reassembler, &mut key_cache).unwrap(),
+            SniExtraction::Incomplete {
+                have: head.len(),
+                need: Some(hello.len())
+            }
+        );
+        assert_eq!(reassembler.len(), 1);
+
+        let mut second =
+            build_client_initial(0x00000001, &dcid, 1, &crypto_frame(head.len() as u64, tail));
+        assert_eq!(
+            extract_sni_from_quic_initial(&mut second, &mut reassembler, &mut key_cache).unwrap(),
+            SniExtraction::Found {
+                sni: "split.example.com".to_string(),
+                ech: false,
+                alpn: Vec::new()
+            }
+        );
+        assert!(reassembler.is_empty());
+    }
+
+    /// 把 ClientHello 按 `chunk` 字节切分，每段放进一个客户端 Initial
+    fn split_into_initials(dcid: &[u8], hello: &[u8], chunk: usize) -> Vec<Vec<u8>> {
+        use crate::quic::test_util::{build_client_initial, crypto_frame};
+
+        hello
+            .chunks(chunk)
+            .enumerate()
+            .map(|(pn, data)| {
+                let frame = crypto_frame((pn * chunk) as u64, data);
+                build_client_initial(0x00000001, dcid, pn as u64, &frame)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_extract_sni_from_quic_fixture_corpus() {
+        let dcid = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+        for fixture in fixtures::client_hellos_over(fixtures::Transport::Quic) {
+            let mut reassembler = CryptoReassembler::default();
+            let mut key_cache = InitialKeyCache::default();
+            let mut packets = split_into_initials(&dcid, &fixture.data, 1000);
+            let last = packets.len() - 1;
+            for (i, packet) in packets.iter_mut().enumerate() {
+                let result =
+                    extract_sni_from_quic_initial(packet, &mut reassembler, &mut key_cache)
+                        .unwrap();
+                if i < last {
+                    assert!(
+                        matches!(result, SniExtraction::Incomplete { .. }),
+                        "{}: {:?}",
+                        fixture.name,
+                        result
+                    );
+                    continue;
+                }
+                let expected = &fixture.expected;
+                let wanted = match &expected.sni {
+                    Some(sni) => SniExtraction::Found {
+                        sni: sni.clone(),
+                        ech: expected.ech,
+                        alpn: expected.alpn.clone(),
+                    },
+                    None => SniExtraction::Missing {
+                        ech: expected.ech,
+                        alpn: expected.alpn.clone(),
+                    },
+                };
+                assert_eq!(result, wanted, "{}", fixture.name);
+            }
+            assert!(reassembler.is_empty(), "{}", fixture.name);
+        }
+    }
+
+    #[test]
+    fn test_incomplete_status_edge_cases() {
+        use crate::quic::test_util::{build_client_initial, client_hello_handshake, crypto_frame};
+
+        let dcid = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x06];
+        let hello = client_hello_handshake("edge.example.com");
+        let mut reassembler = CryptoReassembler::default();
+        let mut key_cache = InitialKeyCache::default();
+
+        // 首个分片不足 4 字节：连 Handshake 长度都还不知道
+        let mut first = build_client_initial(0x00000001, &dcid, 0, &crypto_frame(0, &hello[..3]));
+        assert_eq!(
+            extract_sni_from_quic_initial(&mut first, &mut reassembler, &mut key_cache).unwrap(),
+            SniExtraction::Incomplete {
+                have: 3,
+                need: None
+            }
+        );
+
+        // offset 0 的分片缺失 (乱序到达)：没有连续数据
+        let dcid2 = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x07];
+        let mut later =
+            build_client_initial(0x00000001, &dcid2, 1, &crypto_frame(10, &hello[10..]));
+        assert_eq!(
+            extract_sni_from_quic_initial(&mut later, &mut reassembler, &mut key_cache).unwrap(),
+            SniExtraction::Incomplete {
+                have: 0,
+                need: None
+            }
+        );
+
+        let mut rest = build_client_initial(0x00000001, &dcid, 1, &crypto_frame(3, &hello[3..]));
+        assert_eq!(
+            extract_sni_from_quic_initial(&mut rest, &mut reassembler, &mut key_cache).unwrap(),
+            SniExtraction::Found {
+                sni: "edge.example.com".to_string(),
+                ech: false,
+                alpn: Vec::new()
+            }
+        );
+    }
+
+    #[test]
+    fn test_complete_but_truncated_client_hello_is_an_error() {
+        use crate::quic::test_util::{build_client_initial, crypto_frame};
+
+        // Handshake 长度 8 字节已全部到达，但 ClientHello 至少需要 38 字节
+        let dcid = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x09];
+        let hello = [0x01, 0x00, 0x00, 0x08, 0x03, 0x03, 0, 0, 0, 0, 0, 0];
+        let mut packet = build_client_initial(0x00000001, &dcid, 0, &crypto_frame(0, &hello));
+        assert!(matches!(
+            extract_sni_from_quic_initial(
+                &mut packet,
+                &mut CryptoReassembler::default(),
+                &mut InitialKeyCache::default(),
+            ),
+            Err(QuicError::TlsError(crate::tls::sni::SniError::DataTooShort))
+        ));
+    }
+
+    /// 对任意输入依次运行 DCID 提取、头部解析和 SNI 提取，结果不重要，只要不 panic
+    fn parse_all(mut packet: Vec<u8>) {
+        let _ = crate::quic::parser::extract_dcid(&packet);
+        let _ = crate::quic::parse_initial_header(&packet);
+        let _ = extract_sni_from_quic_initial(
+            &mut packet,
+            &mut CryptoReassembler::default(),
+            &mut InitialKeyCache::default(),
+        );
+    }
+
+    proptest! {
+        /// 随机字节只能返回错误，不能 panic
+        #[test]
+        fn test_random_packets_never_panic(
+            mut packet in proptest::collection::vec(any::<u8>(), 0..1500),
+        ) {
+            // 保留 Long Header + v1 前缀，让随机字节进入后续解析
+            let prefix = [0xC0, 0x00, 0x00, 0x00, 0x01];
+            let n = prefix.len().min(packet.len());
+            packet[..n].copy_from_slice(&prefix[..n]);
+            parse_all(packet);
+        }
+
+        /// 随机篡改并截断的合法 Initial 只能返回错误，不能 panic
+        #[test]
+        fn test_mutated_initials_never_panic(
+            mutations in proptest::collection::vec((any::<Index>(), any::<u8>()), 1..=8),
+            truncate in any::<Index>(),
+        ) {
+            use crate::quic::test_util::{
+                build_client_initial, client_hello_handshake, crypto_frame,
+            };
+
+            let dcid = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x04];
+            let frames = crypto_frame(0, &client_hello_handshake("fuzz.example.com"));
+            let mut packet = build_client_initial(0x00000001, &dcid, 0, &frames);
+            for (idx, byte) in mutations {
+                let idx = idx.index(packet.len());
+                packet[idx] = byte;
+            }
+            packet.truncate(truncate.index(packet.len() + 1));
+            parse_all(packet);
+        }
+    }
+
+    /// 收集日志输出的 writer
+    #[derive(Clone, Default)]
+    struct CapturedLogs(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_key_material_is_not_logged_by_default() {
+        use crate::quic::test_util::{build_client_initial, client_hello_handshake, crypto_frame};
+
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::TRACE)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+
+        let dcid = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x04];
+        let frames = crypto_frame(0, &client_hello_handshake("www.example.com"));
+        let mut packet = build_client_initial(0x00000001, &dcid, 0, &frames);
+
+        let sni = tracing::subscriber::with_default(subscriber, || {
+            extract_sni_from_quic_initial(
+                &mut packet,
+                &mut CryptoReassembler::default(),
+                &mut InitialKeyCache::default(),
+            )
+        })
+        .unwrap();
+        assert_eq!(
+            sni,
+            SniExtraction::Found {
+                sni: "www.example.com".to_string(),
+                ech: false,
+                alpn: Vec::new()
+            }
+        );
+
+        let output = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        for needle in ["Key:", "IV:", "Nonce", "Mask"] {
+            assert!(!output.contains(needle), "{} leaked into logs", needle);
+        }
+
+        let info_lines: Vec<&str> = output.lines().filter(|l| l.contains(" INFO ")).collect();
+        assert_eq!(info_lines.len(), 1, "{:?}", info_lines);
+        assert!(info_lines[0].contains("www.example.com"));
+        assert!(info_lines[0].is_ascii());
+    }
+
     #[test]
     fn test_construct_nonce_invalid_iv_length() {
         let iv = [0u8; 10]; // 错误长度
@@ -0,0 +1,274 @@
+//! QUIC 目标域名的本地 DNS 缓存 (`socks5.resolve = "local"`)
+//!
+//! 热门域名的每个新会话都要重新解析，突发连接时既增加建连延迟又压垮解析器。
+//! `lookup_host` 不暴露 TTL，统一使用配置的 `quic.dns_cache_ttl`；
+//! 同一域名的并发查询合并为一次 (single-flight)，查询失败不缓存。命中和未命中次数
+//! 以 `sniproxy_quic_dns_cache_hits_total` / `sniproxy_quic_dns_cache_misses_total` 导出。
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::sync::OnceCell;
+
+/// 查询成功但没有任何地址
+#[derive(Error, Debug)]
+#[error("No A/AAAA record for {0}")]
+pub struct NoRecords(pub String);
+
+/// 单个域名的缓存槽
+struct Slot {
+    /// 解析结果和过期时间；未初始化表示查询尚未完成
+    cell: OnceCell<(Vec<IpAddr>, Instant)>,
+    /// 最近一次使用的序号 (LRU)
+    last_used: AtomicU64,
+}
+
+impl Slot {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.cell
+            .get()
+            .is_some_and(|(_, expires_at)| now >= *expires_at)
+    }
+}
+
+/// 有界的 hostname → 地址列表缓存
+pub struct DnsCache {
+    entries: Mutex<HashMap<String, Arc<Slot>>>,
+    ttl: Duration,
+    capacity: usize,
+    tick: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl Default for DnsCache {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(60), 1024)
+    }
+}
+
+impl DnsCache {
+    /// 创建缓存，`capacity` 为最多缓存的域名数量
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            ttl,
+            capacity: capacity.max(1),
+            tick: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// 查询缓存，未命中或已过期时调用 `lookup` 解析
+    ///
+    /// 同一域名的并发调用只有一个会执行 `lookup`，其余等待其结果；
+    /// `lookup` 没有返回地址时为 [`NoRecords`] 错误。
+    pub async fn resolve<F, Fut, E>(&self, host: &str, lookup: F) -> Result<Vec<IpAddr>, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Vec<IpAddr>, E>>,
+        E: From<NoRecords>,
+    {
+        let slot = self.slot(host);
+        let mut looked_up = false;
+        let result = slot
+            .cell
+            .get_or_try_init(|| {
+                looked_up = true;
+                async {
+                    let addrs = lookup().await?;
+                    if addrs.is_empty() {
+                        return Err(NoRecords(host.to_string()).into());
+                    }
+                    Ok((addrs, Instant::now() + self.ttl))
+                }
+            })
+            .await;
+
+        if looked_up {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        }
+        result.map(|(addrs, _)| addrs.clone())
+    }
+
+    /// 取得域名的缓存槽；不存在或已过期时换上新的空槽
+    fn slot(&self, host: &str) -> Arc<Slot> {
+        let tick = self.tick.fetch_add(1, Ordering::Relaxed) + 1;
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+
+        if let Some(slot) = entries.get(host) {
+            if !slot.is_expired(now) {
+                slot.last_used.store(tick, Ordering::Relaxed);
+                return Arc::clone(slot);
+            }
+        } else if entries.len() >= self.capacity {
+            entries.retain(|_, slot| !slot.is_expired(now));
+            if entries.len() >= self.capacity {
+                let lru = entries
+                    .iter()
+                    .min_by_key(|(_, slot)| slot.last_used.load(Ordering::Relaxed))
+                    .map(|(host, _)| host.clone());
+                if let Some(lru) = lru {
+                    entries.remove(&lru);
+                }
+            }
+        }
+
+        let slot = Arc::new(Slot {
+            cell: OnceCell::new(),
+            last_used: AtomicU64::new(tick),
+        });
+        entries.insert(host.to_string(), Arc::clone(&slot));
+        slot
+    }
+
+    /// 当前缓存的域名数量
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    /// 缓存是否为空
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 命中次数 (含等待同一域名进行中的查询)
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// 未命中 (实际执行解析) 次数
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([192, 0, 2, last])
+    }
+
+    fn found(last: u8) -> anyhow::Result<Vec<IpAddr>> {
+        Ok(vec![ip(last)])
+    }
+
+    #[tokio::test]
+    async fn test_second_lookup_is_served_from_cache() {
+        let cache = DnsCache::default();
+        let first = cache
+            .resolve("example.com", || async { found(1) })
+            .await
+            .unwrap();
+        let second = cache
+            .resolve("example.com", || async { found(2) })
+            .await
+            .unwrap();
+
+        assert_eq!(first, vec![ip(1)]);
+        assert_eq!(second, vec![ip(1)]);
+        assert_eq!(cache.hits(), 1);
+        assert_eq!(cache.misses(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_expired_entry_is_resolved_again() {
+        let cache = DnsCache::new(Duration::from_millis(10), 16);
+        cache
+            .resolve("example.com", || async { found(1) })
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let addrs = cache
+            .resolve("example.com", || async { found(2) })
+            .await
+            .unwrap();
+        assert_eq!(addrs, vec![ip(2)]);
+        assert_eq!(cache.misses(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_lookups_are_merged() {
+        let cache = Arc::new(DnsCache::default());
+        let lookups = Arc::new(AtomicUsize::new(0));
+
+        let tasks: Vec<_> = (0..8)
+            .map(|_| {
+                let cache = Arc::clone(&cache);
+                let lookups = Arc::clone(&lookups);
+                tokio::spawn(async move {
+                    cache
+                        .resolve("example.com", || async move {
+                            lookups.fetch_add(1, Ordering::SeqCst);
+                            tokio::time::sleep(Duration::from_millis(50)).await;
+                            found(1)
+                        })
+                        .await
+                        .unwrap()
+                })
+            })
+            .collect();
+        for task in tasks {
+            assert_eq!(task.await.unwrap(), vec![ip(1)]);
+        }
+
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.misses(), 1);
+        assert_eq!(cache.hits(), 7);
+    }
+
+    #[tokio::test]
+    async fn test_failed_lookup_is_not_cached() {
+        let cache = DnsCache::default();
+        assert!(cache
+            .resolve("example.com", || async { Err(anyhow::anyhow!("SERVFAIL")) })
+            .await
+            .is_err());
+        assert!(cache
+            .resolve("example.com", || async {
+                Ok::<_, anyhow::Error>(Vec::new())
+            })
+            .await
+            .is_err());
+
+        let addrs = cache
+            .resolve("example.com", || async { found(1) })
+            .await
+            .unwrap();
+        assert_eq!(addrs, vec![ip(1)]);
+        assert_eq!(cache.misses(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_least_recently_used_host_is_evicted() {
+        let cache = DnsCache::new(Duration::from_secs(60), 2);
+        for host in ["a.example", "b.example", "a.example", "c.example"] {
+            cache.resolve(host, || async { found(1) }).await.unwrap();
+        }
+        assert_eq!(cache.len(), 2);
+
+        let misses = cache.misses();
+        cache
+            .resolve("a.example", || async { found(1) })
+            .await
+            .unwrap();
+        assert_eq!(cache.misses(), misses);
+        cache
+            .resolve("b.example", || async { found(1) })
+            .await
+            .unwrap();
+        assert_eq!(cache.misses(), misses + 1);
+    }
+}
@@ -0,0 +1,312 @@
+//! SNI 提取失败的 Initial 转储 (`quic.debug_dump_dir`)
+//!
+//! 线上提取失败时只有一行错误日志，无法复现。启用后每个提取失败的 Initial
+//! 原样写成 `.bin`，旁边的 `.json` 记录来源地址、时间和错误；之后可以用
+//! `sniproxy-ng decode <file>` 在 trace 日志下离线重放提取过程。
+//!
+//! 每分钟最多写入 `debug_dump_per_minute` 个转储，目录总大小达到
+//! `debug_dump_max_bytes` 后不再写入 (不会删除已有文件)。
+
+use crate::quic::decrypt::{extract_sni_with_roles, SniExtraction, BOTH_ROLES};
+use crate::quic::error::QuicError;
+use crate::quic::key_cache::InitialKeyCache;
+use crate::quic::reassembly::CryptoReassembler;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tracing::{debug, info, warn};
+
+/// 限速窗口
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// 转储目录或转储文件的读写错误
+#[derive(Error, Debug)]
+pub enum DumpError {
+    /// 创建、读取或写入失败
+    #[error("Failed to {action} {}: {source}", path.display())]
+    Io {
+        action: &'static str,
+        path: PathBuf,
+        source: io::Error,
+    },
+
+    /// 说明文件不是合法的 JSON
+    #[error("Failed to parse {}: {source}", path.display())]
+    Meta {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+
+    /// 重放时 SNI 提取失败
+    #[error(transparent)]
+    Quic(#[from] QuicError),
+}
+
+impl DumpError {
+    fn io(action: &'static str, path: &Path) -> impl FnOnce(io::Error) -> DumpError {
+        let path = path.to_path_buf();
+        move |source| DumpError::Io {
+            action,
+            path,
+            source,
+        }
+    }
+}
+
+/// 转储旁的 `.json` 说明文件
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DumpMeta {
+    /// 客户端地址
+    pub src: String,
+    /// 收到该包的 Unix 时间 (毫秒)
+    pub timestamp_ms: u64,
+    /// SNI 提取的错误
+    pub error: String,
+}
+
+/// 提取失败的 Initial 转储器
+#[derive(Debug)]
+pub struct InitialDumper {
+    dir: PathBuf,
+    max_per_minute: u32,
+    max_dir_bytes: u64,
+    /// 当前窗口的开始时间和已写入的转储数
+    window_start: Instant,
+    written_in_window: u32,
+    /// 目录中已有文件的总字节数 (启动时统计，之后按写入累加)
+    dir_bytes: u64,
+    /// 已提示过目录达到上限
+    full_warned: bool,
+    seq: u64,
+}
+
+impl InitialDumper {
+    /// 创建转储器，目录不存在时创建
+    pub fn new(
+        dir: impl Into<PathBuf>,
+        max_per_minute: u32,
+        max_dir_bytes: u64,
+    ) -> Result<Self, DumpError> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir).map_err(DumpError::io("create dump directory", &dir))?;
+        let dir_bytes = std::fs::read_dir(&dir)
+            .map_err(DumpError::io("read dump directory", &dir))?
+            .filter_map(|entry| entry.ok()?.metadata().ok())
+            .filter(|metadata| metadata.is_file())
+            .map(|metadata| metadata.len())
+            .sum();
+
+        info!(
+            dir = %dir.display(),
+            max_per_minute,
+            dir_bytes,
+            max_dir_bytes,
+            "dumping QUIC Initials that fail SNI extraction"
+        );
+        Ok(Self {
+            dir,
+            max_per_minute,
+            max_dir_bytes,
+            window_start: Instant::now(),
+            written_in_window: 0,
+            dir_bytes,
+            full_warned: false,
+            seq: 0,
+        })
+    }
+
+    /// 转储一个提取失败的 Initial；超出限速或目录大小上限时跳过
+    ///
+    /// 文件在阻塞线程池中写入，不阻塞调用方。
+    pub fn dump(&mut self, packet: &[u8], src: SocketAddr, error: &str) {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let meta = DumpMeta {
+            src: src.to_string(),
+            timestamp_ms,
+            error: error.to_string(),
+        };
+        let json = match serde_json::to_vec_pretty(&meta) {
+            Ok(json) => json,
+            Err(e) => {
+                warn!(error = %e, "failed to serialize QUIC dump metadata");
+                return;
+            }
+        };
+
+        let bytes = (packet.len() + json.len()) as u64;
+        if !self.claim_at(Instant::now(), bytes) {
+            return;
+        }
+
+        self.seq += 1;
+        let stem = self
+            .dir
+            .join(format!("initial-{}-{}", timestamp_ms, self.seq));
+        let packet = packet.to_vec();
+        tokio::task::spawn_blocking(move || match write_dump(&stem, &packet, &json) {
+            Ok(()) => {
+                debug!(path = %format_args!("{}.bin", stem.display()), "failed QUIC Initial dumped")
+            }
+            Err(e) => warn!(path = %stem.display(), error = %e, "failed to write QUIC dump"),
+        });
+    }
+
+    /// 为一个 `bytes` 字节的转储申请额度
+    fn claim_at(&mut self, now: Instant, bytes: u64) -> bool {
+        if now.duration_since(self.window_start) >= WINDOW {
+            self.window_start = now;
+            self.written_in_window = 0;
+        }
+        if self.written_in_window >= self.max_per_minute {
+            return false;
+        }
+        if self.dir_bytes + bytes > self.max_dir_bytes {
+            if !self.full_warned {
+                self.full_warned = true;
+                warn!(
+                    dir = %self.dir.display(),
+                    max_dir_bytes = self.max_dir_bytes,
+                    "QUIC dump directory is full, no more dumps will be written"
+                );
+            }
+            return false;
+        }
+
+        self.written_in_window += 1;
+        self.dir_bytes += bytes;
+        true
+    }
+}
+
+/// 写入 `<stem>.bin` 和 `<stem>.json`
+fn write_dump(stem: &Path, packet: &[u8], json: &[u8]) -> io::Result<()> {
+    std::fs::write(stem.with_extension("bin"), packet)?;
+    std::fs::write(stem.with_extension("json"), json)?;
+    Ok(())
+}
+
+/// 读取转储旁的 `.json` 说明文件；不存在时返回 None
+pub fn read_meta(path: &Path) -> Result<Option<DumpMeta>, DumpError> {
+    let meta_path = path.with_extension("json");
+    if !meta_path.exists() {
+        return Ok(None);
+    }
+    let content = std::fs::read(&meta_path).map_err(DumpError::io("read", &meta_path))?;
+    let meta = serde_json::from_slice(&content).map_err(|source| DumpError::Meta {
+        path: meta_path,
+        source,
+    })?;
+    Ok(Some(meta))
+}
+
+/// 对转储的 Initial 重新执行 SNI 提取 (依次尝试 client 和 server 方向密钥)
+pub fn decode_dump(path: &Path) -> Result<SniExtraction, DumpError> {
+    let mut packet =
+        std::fs::read(path.with_extension("bin")).map_err(DumpError::io("read", path))?;
+    let mut reassembler = CryptoReassembler::new(Duration::from_secs(3), 1);
+    let mut key_cache = InitialKeyCache::new(2);
+    let extraction =
+        extract_sni_with_roles(&mut packet, BOTH_ROLES, &mut reassembler, &mut key_cache)?;
+    Ok(extraction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 与 fuzz 语料相同的 v1 客户端 Initial (SNI 为 www.example.com)，附带一份说明文件
+    const FIXTURE: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/quic_initial_dump.bin"
+    );
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("sniproxy-ng-dump-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_dumps_are_rate_limited_per_minute() {
+        let dir = temp_dir("rate");
+        let mut dumper = InitialDumper::new(&dir, 3, u64::MAX).unwrap();
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert!(dumper.claim_at(start, 100));
+        }
+        assert!(!dumper.claim_at(start + Duration::from_secs(30), 100));
+
+        // 下一分钟重新计数
+        assert!(dumper.claim_at(start + WINDOW, 100));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_dumps_stop_at_directory_size_limit() {
+        let dir = temp_dir("size");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("existing.bin"), [0u8; 600]).unwrap();
+
+        // 已有 600 字节，上限 1000
+        let mut dumper = InitialDumper::new(&dir, 100, 1000).unwrap();
+        let now = Instant::now();
+        assert!(dumper.claim_at(now, 300));
+        assert!(!dumper.claim_at(now, 300));
+        assert!(dumper.claim_at(now, 100));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_dump_writes_packet_and_sidecar() {
+        let dir = temp_dir("write");
+        let mut dumper = InitialDumper::new(&dir, 10, u64::MAX).unwrap();
+        let src: SocketAddr = "192.0.2.1:443".parse().unwrap();
+        dumper.dump(&[0xc0, 0x00, 0x01], src, "Decryption failed");
+
+        let bin = tokio::time::timeout(Duration::from_secs(2), async {
+            loop {
+                let found = std::fs::read_dir(&dir).unwrap().find_map(|entry| {
+                    let path = entry.unwrap().path();
+                    (path.extension()? == "bin").then_some(path)
+                });
+                // `.json` 在 `.bin` 之后写入，能解析说明两个文件都已写完
+                if let Some(meta) = found.as_deref().and_then(|path| read_meta(path).ok()?) {
+                    break (found.unwrap(), meta);
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let (bin, meta) = bin;
+
+        assert_eq!(std::fs::read(&bin).unwrap(), vec![0xc0, 0x00, 0x01]);
+        assert_eq!(meta.src, "192.0.2.1:443");
+        assert_eq!(meta.error, "Decryption failed");
+        assert!(decode_dump(&bin).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_decode_checked_in_fixture() {
+        let path = Path::new(FIXTURE);
+        let meta = read_meta(path).unwrap().unwrap();
+        assert_eq!(meta.src, "198.51.100.7:51234");
+
+        match decode_dump(path).unwrap() {
+            SniExtraction::Found { sni, ech, .. } => {
+                assert_eq!(sni, "www.example.com");
+                assert!(!ech);
+            }
+            other => panic!("unexpected extraction result: {:?}", other),
+        }
+    }
+}
@@ -1,4 +1,5 @@
 //! QUIC SNI 提取错误类型
+use crate::tls::sni::SniError;
 use thiserror::Error;
 
 /// QUIC SNI 提取过程中可能出现的错误
@@ -14,7 +15,6 @@ pub enum QuicError {
 
     /// DCID (Destination Connection ID) 无效
     #[error("Invalid DCID: {0}")]
-    #[allow(dead_code)]
     InvalidDcid(String),
 
     /// 密钥派生失败
@@ -31,16 +31,15 @@ pub enum QuicError {
 
     /// Packet Number 解码失败
     #[error("Packet number decoding failed: {0}")]
-    #[allow(dead_code)]
     PacketNumberError(String),
 
     /// CRYPTO Frame 解析失败
     #[error("CRYPTO frame parsing failed: {0}")]
     CryptoFrameError(String),
 
-    /// TLS SNI 解析失败
+    /// ClientHello 格式错误 (数据没收全不是错误，由调用方继续等待)
     #[error("TLS SNI parsing failed: {0}")]
-    TlsError(String),
+    TlsError(#[from] SniError),
 
     /// VarInt 解码失败
     #[error("VarInt decoding failed: {0}")]
@@ -52,13 +51,110 @@ pub enum QuicError {
 
     /// 未找到 SNI
     #[error("No SNI found in packet")]
-    #[allow(dead_code)]
     NoSniFound,
 
     /// 其他错误
     #[error("Other error: {0}")]
-    #[allow(dead_code)]
     Other(String),
 }
 
+impl QuicError {
+    /// 所有可能的 [`metric_label`](Self::metric_label) 取值
+    pub const METRIC_LABELS: [&'static str; 10] = [
+        "packet_too_short",
+        "not_initial",
+        "unsupported_version",
+        "key_derivation",
+        "header_protection",
+        "decryption",
+        "crypto_frame",
+        "tls",
+        "no_sni",
+        "other",
+    ];
+
+    /// 用于统计的稳定、低基数的错误分类
+    ///
+    /// 不使用通配分支：新增变体时必须在这里决定其分类。
+    pub fn metric_label(&self) -> &'static str {
+        match self {
+            QuicError::PacketTooShort { .. } | QuicError::VarIntError(_) => "packet_too_short",
+            QuicError::NotInitialPacket(_) | QuicError::InvalidDcid(_) => "not_initial",
+            QuicError::UnsupportedVersion { .. } => "unsupported_version",
+            QuicError::KeyDerivationFailed(_) => "key_derivation",
+            QuicError::HeaderProtectionFailed(_) | QuicError::PacketNumberError(_) => {
+                "header_protection"
+            }
+            QuicError::DecryptionFailed(_) => "decryption",
+            QuicError::CryptoFrameError(_) => "crypto_frame",
+            QuicError::TlsError(_) => "tls",
+            QuicError::NoSniFound => "no_sni",
+            QuicError::Other(_) => "other",
+        }
+    }
+}
+
 pub type Result<T> = std::result::Result<T, QuicError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metric_labels_are_stable() {
+        let cases = [
+            (
+                QuicError::PacketTooShort {
+                    expected: 2,
+                    actual: 1,
+                },
+                "packet_too_short",
+            ),
+            (QuicError::VarIntError(String::new()), "packet_too_short"),
+            (QuicError::NotInitialPacket(0x40), "not_initial"),
+            (QuicError::InvalidDcid(String::new()), "not_initial"),
+            (
+                QuicError::UnsupportedVersion { version: 0xff },
+                "unsupported_version",
+            ),
+            (
+                QuicError::KeyDerivationFailed(String::new()),
+                "key_derivation",
+            ),
+            (
+                QuicError::HeaderProtectionFailed(String::new()),
+                "header_protection",
+            ),
+            (
+                QuicError::PacketNumberError(String::new()),
+                "header_protection",
+            ),
+            (QuicError::DecryptionFailed(String::new()), "decryption"),
+            (QuicError::CryptoFrameError(String::new()), "crypto_frame"),
+            (QuicError::TlsError(SniError::DataTooShort), "tls"),
+            (QuicError::NoSniFound, "no_sni"),
+            (QuicError::Other(String::new()), "other"),
+        ];
+
+        for (error, label) in &cases {
+            assert_eq!(error.metric_label(), *label, "{:?}", error);
+        }
+        for label in QuicError::METRIC_LABELS {
+            assert!(
+                cases.iter().any(|(_, l)| *l == label),
+                "unused label {}",
+                label
+            );
+        }
+    }
+
+    #[test]
+    fn test_sni_error_keeps_its_variant() {
+        let error: QuicError = SniError::ConflictingServerNames.into();
+        assert!(matches!(
+            error,
+            QuicError::TlsError(SniError::ConflictingServerNames)
+        ));
+        assert_eq!(error.metric_label(), "tls");
+    }
+}
@@ -0,0 +1,134 @@
+//! 按 DCID 分片的 SNI 提取状态
+//!
+//! CRYPTO 分片重组器和 Initial 密钥缓存都按 DCID 索引。[`SniExtractor`] 把它们拆成若干分片、
+//! 各自加锁，锁只在单次提取期间持有：不同连接的 Initial 可以在多个 UDP worker 上并行做
+//! 密钥派生、解密和 ClientHello 解析，不必在会话管理器的锁上排队；同一 DCID 的 Initial
+//! 总是落在同一分片，分片重组不受影响。
+
+use crate::quic::crypto::{InitialKeyRole, InitialKeys};
+use crate::quic::decrypt::{extract_sni_with_roles, SniExtraction};
+use crate::quic::error::Result;
+use crate::quic::key_cache::InitialKeyCache;
+use crate::quic::reassembly::CryptoReassembler;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// 分片数
+const SHARDS: usize = 16;
+
+/// 一个分片：该分片内 DCID 的重组状态和密钥
+#[derive(Debug)]
+struct Shard {
+    reassembler: CryptoReassembler,
+    key_cache: InitialKeyCache,
+}
+
+/// 按 DCID 分片加锁的重组器与密钥缓存
+///
+/// 由调用方持有 (例如 `QuicSessionManager`)。条目上限按分片平分，
+/// 分片的选择使用随机种子，无法用构造的 DCID 把流量集中到同一分片。
+#[derive(Debug)]
+pub struct SniExtractor {
+    shards: Vec<Mutex<Shard>>,
+    hasher: RandomState,
+}
+
+impl SniExtractor {
+    /// 创建提取状态，`reassembly_max_entries` 与 `key_cache_size` 为所有分片合计的上限
+    pub fn new(
+        reassembly_ttl: Duration,
+        reassembly_max_entries: usize,
+        key_cache_size: usize,
+    ) -> Self {
+        let shards = (0..SHARDS)
+            .map(|_| {
+                Mutex::new(Shard {
+                    reassembler: CryptoReassembler::new(
+                        reassembly_ttl,
+                        reassembly_max_entries.div_ceil(SHARDS),
+                    ),
+                    key_cache: InitialKeyCache::new(key_cache_size.div_ceil(SHARDS)),
+                })
+            })
+            .collect();
+        Self {
+            shards,
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn shard(&self, dcid: &[u8]) -> &Mutex<Shard> {
+        let index = self.hasher.hash_one(dcid) as usize % self.shards.len();
+        &self.shards[index]
+    }
+
+    /// 依次尝试 `roles` 提取 `packet` (DCID 为 `dcid` 的 Initial) 的 SNI，
+    /// 见 [`extract_sni_with_roles`]
+    pub fn extract(
+        &self,
+        dcid: &[u8],
+        packet: &mut [u8],
+        roles: &[InitialKeyRole],
+    ) -> Result<SniExtraction> {
+        let mut shard = self.shard(dcid).lock().unwrap();
+        let shard = &mut *shard;
+        extract_sni_with_roles(packet, roles, &mut shard.reassembler, &mut shard.key_cache)
+    }
+
+    /// 读取缓存的 Initial 密钥，未命中时派生并写入缓存
+    pub fn keys(&self, dcid: &[u8], version: u32, role: InitialKeyRole) -> Result<InitialKeys> {
+        self.shard(dcid)
+            .lock()
+            .unwrap()
+            .key_cache
+            .get_or_derive(dcid, version, role)
+    }
+
+    /// 淘汰所有分片中过期的重组条目，返回淘汰数量
+    pub fn evict_expired(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().reassembler.evict_expired())
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quic::test_util::{build_client_initial, client_hello_handshake, crypto_frame};
+
+    #[test]
+    fn test_extracts_across_shards() {
+        let extractor = SniExtractor::new(Duration::from_secs(3), 1024, 1024);
+        for i in 0..32u8 {
+            let dcid = [i, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77];
+            let sni = format!("host{}.example.com", i);
+            let frames = crypto_frame(0, &client_hello_handshake(&sni));
+            let mut packet = build_client_initial(0x00000001, &dcid, 0, &frames);
+            match extractor.extract(&dcid, &mut packet, &[InitialKeyRole::Client]) {
+                Ok(SniExtraction::Found { sni: found, .. }) => assert_eq!(found, sni),
+                other => panic!("unexpected extraction result: {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_keys_match_derived_keys() {
+        let extractor = SniExtractor::new(Duration::from_secs(3), 16, 16);
+        let dcid = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+        let keys = extractor
+            .keys(&dcid, 0x00000001, InitialKeyRole::Server)
+            .unwrap();
+        let direct = crate::quic::crypto::derive_initial_keys_for_role(
+            &dcid,
+            0x00000001,
+            InitialKeyRole::Server,
+        )
+        .unwrap();
+        assert_eq!(keys.key, direct.key);
+        assert_eq!(keys.hp_key, direct.hp_key);
+    }
+}
@@ -0,0 +1,346 @@
+//! 解密后 Initial payload 的 frame 解析
+//!
+//! 与密钥和解密逻辑分离的纯函数，便于单独 fuzz (见 `fuzz/`)。
+//! 识别 Initial 包中允许出现的 PADDING / PING / ACK / CRYPTO / CONNECTION_CLOSE；
+//! 重传的 Initial 常在 CRYPTO 之前带 ACK，必须跳过它们才能拿到 CRYPTO 数据。
+//! 遇到其它类型即停止解析 (其长度格式未知，后续字节无法可靠跳过)。
+//!
+//! 参考 RFC 9000 Section 12.4: Frames and Frame Types
+//! 参考 RFC 9000 Section 19.3: ACK Frames
+//! 参考 RFC 9000 Section 19.6: CRYPTO Frames
+//! 参考 RFC 9000 Section 19.19: CONNECTION_CLOSE Frames
+
+use crate::quic::error::{QuicError, Result};
+use crate::quic::parser::parse_varint;
+use tracing::debug;
+
+/// VarInt 能表示的最大值 (2^62 - 1)
+const MAX_VARINT: u64 = (1 << 62) - 1;
+
+/// Initial payload 中的一个 frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame<'a> {
+    /// 连续的 PADDING frame，合并为一项，值为字节数
+    Padding(usize),
+    /// PING frame
+    Ping,
+    /// ACK frame (含 ECN 计数的 0x03 也归为此类)，只保留 Largest Acknowledged
+    Ack { largest: u64 },
+    /// CRYPTO frame
+    Crypto { offset: u64, data: &'a [u8] },
+    /// CONNECTION_CLOSE frame (0x1c 或 0x1d)
+    ConnectionClose { error_code: u64 },
+}
+
+/// 在 payload 上前进的读取游标
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn read_varint(&mut self, what: &str) -> Result<u64> {
+        let (value, len) = parse_varint(self.buf)
+            .map_err(|e| QuicError::CryptoFrameError(format!("Failed to parse {}: {}", what, e)))?;
+        self.buf = &self.buf[len..];
+        Ok(value)
+    }
+
+    /// 读取 `len` 字节，不足时返回错误
+    fn take(&mut self, len: u64, what: &str) -> Result<&'a [u8]> {
+        let len = usize::try_from(len)
+            .ok()
+            .filter(|len| *len <= self.buf.len())
+            .ok_or_else(|| {
+                QuicError::CryptoFrameError(format!(
+                    "{} truncated: expected {}, got {}",
+                    what,
+                    len,
+                    self.buf.len()
+                ))
+            })?;
+        let (data, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(data)
+    }
+}
+
+/// 解析解密后的 payload，返回其中的 frames
+///
+/// 遇到未识别的 frame 类型时停止，返回之前已解析的 frames；
+/// 任何字段越界或 CRYPTO 流结束位置超过 2^62 - 1 都返回错误。
+pub fn parse_frames(payload: &[u8]) -> Result<Vec<Frame<'_>>> {
+    let mut frames = Vec::new();
+    let mut reader = Reader { buf: payload };
+
+    while !reader.buf.is_empty() {
+        let before = reader.buf.len();
+        let frame_type = reader.read_varint("frame type")?;
+
+        match frame_type {
+            0x00 => {
+                // PADDING 单字节，和前一个 PADDING 合并
+                let len = before - reader.buf.len();
+                if let Some(Frame::Padding(total)) = frames.last_mut() {
+                    *total += len;
+                } else {
+                    frames.push(Frame::Padding(len));
+                }
+            }
+            0x01 => frames.push(Frame::Ping),
+            0x02 | 0x03 => frames.push(parse_ack(&mut reader, frame_type == 0x03)?),
+            0x06 => {
+                // CRYPTO: Offset (varint) + Length (varint) + Data
+                let offset = reader.read_varint("CRYPTO offset")?;
+                let length = reader.read_varint("CRYPTO length")?;
+
+                if offset
+                    .checked_add(length)
+                    .is_none_or(|end| end > MAX_VARINT)
+                {
+                    return Err(QuicError::CryptoFrameError(format!(
+                        "CRYPTO stream end exceeds 2^62-1: offset={}, length={}",
+                        offset, length
+                    )));
+                }
+
+                let data = reader.take(length, "CRYPTO data")?;
+                frames.push(Frame::Crypto { offset, data });
+            }
+            0x1c | 0x1d => {
+                // Error Code + [Frame Type (仅 0x1c)] + Reason Phrase Length + Reason Phrase
+                let error_code = reader.read_varint("CONNECTION_CLOSE error code")?;
+                if frame_type == 0x1c {
+                    reader.read_varint("CONNECTION_CLOSE frame type")?;
+                }
+                let reason_len = reader.read_varint("CONNECTION_CLOSE reason length")?;
+                reader.take(reason_len, "CONNECTION_CLOSE reason")?;
+                frames.push(Frame::ConnectionClose { error_code });
+            }
+            _ => {
+                debug!(
+                    frame_type = %format_args!("{:#x}", frame_type),
+                    "unknown frame type, frame parsing stopped"
+                );
+                break;
+            }
+        }
+    }
+
+    Ok(frames)
+}
+
+/// 跳过 ACK frame 的其余字段
+///
+/// ```text
+/// ACK Frame {
+///   Type (i) = 0x02..0x03,
+///   Largest Acknowledged (i),
+///   ACK Delay (i),
+///   ACK Range Count (i),
+///   First ACK Range (i),
+///   ACK Range (..) ...,      // 每个: Gap (i) + ACK Range Length (i)
+///   [ECN Counts (..)],       // 仅 0x03: ECT0 + ECT1 + ECN-CE
+/// }
+/// ```
+fn parse_ack<'a>(reader: &mut Reader<'a>, ecn: bool) -> Result<Frame<'a>> {
+    let largest = reader.read_varint("ACK largest acknowledged")?;
+    reader.read_varint("ACK delay")?;
+    let range_count = reader.read_varint("ACK range count")?;
+    reader.read_varint("ACK first range")?;
+
+    // 每个 range 至少 2 字节，提前拒绝不可能的计数
+    if range_count > (reader.buf.len() / 2) as u64 {
+        return Err(QuicError::CryptoFrameError(format!(
+            "ACK range count {} exceeds remaining {} bytes",
+            range_count,
+            reader.buf.len()
+        )));
+    }
+    for _ in 0..range_count {
+        reader.read_varint("ACK gap")?;
+        reader.read_varint("ACK range length")?;
+    }
+
+    if ecn {
+        for _ in 0..3 {
+            reader.read_varint("ACK ECN count")?;
+        }
+    }
+
+    Ok(Frame::Ack { largest })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quic::parser::encode_varint;
+
+    #[test]
+    fn test_parse_crypto_ping_and_padding() {
+        let mut payload = vec![0x01, 0x06, 0x00, 0x03];
+        payload.extend_from_slice(b"abc");
+        payload.extend_from_slice(&[0x00; 5]);
+
+        let frames = parse_frames(&payload).unwrap();
+        assert_eq!(
+            frames,
+            vec![
+                Frame::Ping,
+                Frame::Crypto {
+                    offset: 0,
+                    data: b"abc"
+                },
+                Frame::Padding(5),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unknown_frame_stops_parsing() {
+        // STREAM frame (0x08) 不允许出现在 Initial 中，之后的字节不再解析
+        let payload = [0x06, 0x00, 0x01, b'a', 0x08, 0xff, 0xff];
+        let frames = parse_frames(&payload).unwrap();
+        assert_eq!(
+            frames,
+            vec![Frame::Crypto {
+                offset: 0,
+                data: b"a"
+            }]
+        );
+    }
+
+    #[test]
+    fn test_ack_before_crypto_in_retransmitted_initial() {
+        // 重传 Initial 的典型布局：ACK 服务端的 Initial，然后重发 CRYPTO，最后 PADDING
+        let payload = [
+            0x02, // ACK
+            0x01, // Largest Acknowledged = 1
+            0x40, 0x1a, // ACK Delay = 26
+            0x01, // ACK Range Count = 1
+            0x00, // First ACK Range = 0
+            0x00, 0x00, // Gap = 0, ACK Range Length = 0
+            0x06, 0x00, 0x03, b'a', b'b', b'c', // CRYPTO offset=0 len=3
+            0x00, 0x00, 0x00, // PADDING
+        ];
+
+        let frames = parse_frames(&payload).unwrap();
+        assert_eq!(
+            frames,
+            vec![
+                Frame::Ack { largest: 1 },
+                Frame::Crypto {
+                    offset: 0,
+                    data: b"abc"
+                },
+                Frame::Padding(3),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ack_ecn_and_connection_close_are_skipped() {
+        let mut payload = vec![
+            0x03, 0x05, 0x00, 0x00, 0x02, // ACK_ECN，无额外 range
+            0x01, 0x02, 0x03, // ECT0 / ECT1 / ECN-CE
+            0x06, 0x00, 0x01, b'x', // CRYPTO
+            0x1c, 0x0a, 0x06, 0x02, b'n', b'o', // CONNECTION_CLOSE，reason = "no"
+            0x1d, 0x00, 0x00, // 应用层 CONNECTION_CLOSE
+            0x06, 0x01, 0x01, b'y', // CRYPTO
+        ];
+        payload.extend_from_slice(&[0x00; 4]);
+
+        let frames = parse_frames(&payload).unwrap();
+        assert_eq!(
+            frames,
+            vec![
+                Frame::Ack { largest: 5 },
+                Frame::Crypto {
+                    offset: 0,
+                    data: b"x"
+                },
+                Frame::ConnectionClose { error_code: 0x0a },
+                Frame::ConnectionClose { error_code: 0 },
+                Frame::Crypto {
+                    offset: 1,
+                    data: b"y"
+                },
+                Frame::Padding(4),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_malformed_ack_is_rejected() {
+        // Range Count 远大于剩余字节
+        let mut payload = vec![0x02, 0x00, 0x00];
+        payload.extend_from_slice(&encode_varint(1 << 40));
+        payload.extend_from_slice(&[0x00; 8]);
+        assert!(parse_frames(&payload).is_err());
+
+        // ACK_ECN 缺少 ECN 计数
+        assert!(parse_frames(&[0x03, 0x00, 0x00, 0x00, 0x00, 0x01]).is_err());
+
+        // CONNECTION_CLOSE reason 越界
+        assert!(parse_frames(&[0x1c, 0x00, 0x00, 0x05, b'a']).is_err());
+    }
+
+    #[test]
+    fn test_truncated_crypto_frame_is_rejected() {
+        // Length = 2^40，远超剩余字节
+        let mut payload = vec![0x06, 0x00];
+        payload.extend_from_slice(&encode_varint(1 << 40));
+        payload.extend_from_slice(b"abc");
+        assert!(matches!(
+            parse_frames(&payload),
+            Err(QuicError::CryptoFrameError(_))
+        ));
+
+        // 缺少 Length 字段
+        assert!(parse_frames(&[0x06, 0x00]).is_err());
+    }
+
+    #[test]
+    fn test_crypto_stream_end_is_bounded() {
+        let mut payload = vec![0x06];
+        payload.extend_from_slice(&encode_varint(MAX_VARINT));
+        payload.extend_from_slice(&[0x01, b'a']);
+        assert!(matches!(
+            parse_frames(&payload),
+            Err(QuicError::CryptoFrameError(_))
+        ));
+    }
+
+    /// 随机 payload 只能返回错误，不能 panic；解析出的 CRYPTO 数据必须在 payload 内
+    #[test]
+    fn test_random_payloads_never_panic() {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        for _ in 0..5000 {
+            let len = (next() % 256) as usize;
+            let mut payload: Vec<u8> = (0..len).map(|_| next() as u8).collect();
+            // 让一部分输入从 CRYPTO frame 开始
+            if let Some(first) = payload.first_mut() {
+                if next() % 2 == 0 {
+                    *first = 0x06;
+                }
+            }
+
+            if let Ok(frames) = parse_frames(&payload) {
+                let crypto_len: usize = frames
+                    .iter()
+                    .map(|frame| match frame {
+                        Frame::Crypto { data, .. } => data.len(),
+                        _ => 0,
+                    })
+                    .sum();
+                assert!(crypto_len <= payload.len());
+            }
+        }
+    }
+}
@@ -1,12 +1,12 @@
-//! QUIC Header Protection 移除和 Packet Number 解码
+//! QUIC Header Protection 移除/施加和 Packet Number 解码
 //!
 //! 参考 RFC 9001 Section 5.4: Header Protection
 //! 参考 RFC 9000 Section 17.1: Packet Number Encoding and Decoding
 
-use crate::quic::crypto::InitialKeys;
+use crate::quic::crypto::{debug_crypto_enabled, InitialKeys};
 use crate::quic::error::{QuicError, Result};
 use ring::aead::quic::{HeaderProtectionKey, AES_128};
-use tracing::{debug, warn};
+use tracing::{debug, trace, warn};
 
 /// 移除 QUIC Initial Packet 的 Header Protection
 ///
@@ -33,9 +33,9 @@ pub fn remove_header_protection(
 ) -> Result<(u8, u64, u8)> {
     // 检查包长度
     // 最小长度：pn_offset + 4 (sample) + 16 (sample length)
-    if packet.len() < pn_offset + 4 {
+    if packet.len() < pn_offset.saturating_add(4) {
         return Err(QuicError::PacketTooShort {
-            expected: pn_offset + 4,
+            expected: pn_offset.saturating_add(4),
             actual: packet.len(),
         });
     }
@@ -46,14 +46,15 @@ pub fn remove_header_protection(
     let protected_first_byte = packet[0];
     let protected_pn_len = (protected_first_byte & 0x03) + 1;
     debug!(
-        "Protected first byte: {:#04x}, protected_pn_len(UNRELIABLE): {}",
-        protected_first_byte, protected_pn_len
+        first_byte = %format_args!("{:#04x}", protected_first_byte),
+        protected_pn_len,
+        "protected first byte (pn_len unreliable)"
     );
 
     // 计算 sample 位置
     // RFC 9001: sample 是从 PN 字段开始的第 4 个字节
-    let sample_start = pn_offset + 4;
-    let sample_end = sample_start + 16;
+    let sample_start = pn_offset.saturating_add(4);
+    let sample_end = sample_start.saturating_add(16);
 
     if packet.len() < sample_end {
         return Err(QuicError::PacketTooShort {
@@ -63,22 +64,13 @@ pub fn remove_header_protection(
     }
 
     let sample = &packet[sample_start..sample_end];
-    debug!(
-        "Sample: start={}, end={}, first 16 bytes: {:02x?}",
-        sample_start, sample_end, sample
-    );
-
-    // 创建 Header Protection Key
-    let hp_key = HeaderProtectionKey::new(&AES_128, &keys.hp_key).map_err(|e| {
-        QuicError::HeaderProtectionFailed(format!("Failed to create HP key: {:?}", e))
-    })?;
+    trace!(sample_start, sample_end, sample = ?sample, "header protection sample");
 
-    // 生成 mask
-    let mask = hp_key.new_mask(sample).map_err(|e| {
-        QuicError::HeaderProtectionFailed(format!("Failed to generate mask: {:?}", e))
-    })?;
+    let mask = header_protection_mask(sample, keys)?;
 
-    debug!("Mask generated: {:02x?}", mask);
+    if debug_crypto_enabled() {
+        trace!(mask = ?mask, "mask generated");
+    }
 
     // 解密 first byte
     // 只需要修改低 4 bits (packet number length)
@@ -86,23 +78,23 @@ pub fn remove_header_protection(
     let unprotected_first_byte = protected_first_byte ^ (mask[0] & 0x0F);
 
     debug!(
-        "First byte: protected={:#04x}, unprotected={:#04x}",
-        protected_first_byte, unprotected_first_byte
+        protected = %format_args!("{:#04x}", protected_first_byte),
+        unprotected = %format_args!("{:#04x}", unprotected_first_byte),
+        "first byte unprotected"
     );
 
     // 从 unprotected first byte 获取 PN length (RFC 9001 Section 5.4)
     // pn_len = (first_byte & 0x03) + 1, range: 1..=4
     let pn_len = (unprotected_first_byte & 0x03) + 1;
-    debug!("Unprotected PN length: {}", pn_len);
+    debug!(pn_len, "unprotected PN length");
 
     // 解密 Packet Number
     // ⚠️ 重要：先读取 protected bytes，因为 XOR 是 in-place 的
     let protected_pn_bytes: Vec<u8> = packet[pn_offset..pn_offset + pn_len as usize].to_vec();
-    debug!(
-        "Protected PN bytes (at offset {}): {:02x?}",
-        pn_offset, protected_pn_bytes
-    );
-    debug!("Mask for PN: {:02x?}", &mask[1..pn_len as usize + 1]);
+    trace!(pn_offset, bytes = ?protected_pn_bytes, "protected PN bytes");
+    if debug_crypto_enabled() {
+        trace!(mask = ?&mask[1..pn_len as usize + 1], "PN mask");
+    }
 
     let mut pn_bytes = [0u8; 4];
     for i in 0..pn_len as usize {
@@ -111,10 +103,7 @@ pub fn remove_header_protection(
         packet[idx] = pn_bytes[i]; // In-place 解密
     }
 
-    debug!(
-        "Unprotected PN bytes: {:02x?}",
-        &pn_bytes[..pn_len as usize]
-    );
+    trace!(bytes = ?&pn_bytes[..pn_len as usize], "unprotected PN bytes");
 
     // 解码 Packet Number
     //
@@ -125,7 +114,7 @@ pub fn remove_header_protection(
     for &b in pn_bytes[..pn_len as usize].iter() {
         packet_number = (packet_number << 8) | (b as u64);
     }
-    debug!("Packet Number decoded: {}", packet_number);
+    debug!(packet_number, "packet number decoded");
 
     // ⚠️ 对于 Initial packet，PN 通常很小（第一个包 PN=0）
     // 但如果 PN>100，可能：
@@ -134,9 +123,8 @@ pub fn remove_header_protection(
     // 我们记录警告但继续尝试解密
     if packet_number > 100 {
         warn!(
-            "Decoded PN {} is unusually large for Initial packet. \
-              This might be a retransmission or non-standard implementation.",
-            packet_number
+            packet_number,
+            "decoded PN is unusually large for an Initial, possibly a retransmission or non-standard implementation"
         );
         // 不返回错误，继续尝试解密
     }
@@ -147,6 +135,51 @@ pub fn remove_header_protection(
     Ok((unprotected_first_byte, packet_number, pn_len))
 }
 
+/// 对我们自己构造的 Long Header 包施加 Header Protection
+///
+/// `remove_header_protection` 的逆操作：用 sample 生成 mask，
+/// 对 first byte 的低 4 bits 和 Packet Number 字段做 XOR。
+///
+/// # 参数
+/// - `packet`: AEAD 加密后的完整包 (会被修改)
+/// - `pn_offset`: Packet Number 在 packet 中的偏移量
+/// - `pn_len`: Packet Number 的编码长度 (1-4)
+/// - `keys`: Initial Keys (包含 hp_key)
+pub fn apply_header_protection(
+    packet: &mut [u8],
+    pn_offset: usize,
+    pn_len: usize,
+    keys: &InitialKeys,
+) -> Result<()> {
+    let sample_start = pn_offset.saturating_add(4);
+    let sample_end = sample_start.saturating_add(16);
+    if packet.len() < sample_end {
+        return Err(QuicError::PacketTooShort {
+            expected: sample_end,
+            actual: packet.len(),
+        });
+    }
+
+    let mask = header_protection_mask(&packet[sample_start..sample_end], keys)?;
+
+    packet[0] ^= mask[0] & 0x0F;
+    for i in 0..pn_len {
+        packet[pn_offset + i] ^= mask[1 + i];
+    }
+
+    Ok(())
+}
+
+/// 用 hp_key 对 16 字节 sample 做 AES-ECB，得到 5 字节 mask (RFC 9001 Section 5.4.3)
+fn header_protection_mask(sample: &[u8], keys: &InitialKeys) -> Result<[u8; 5]> {
+    let hp_key = HeaderProtectionKey::new(&AES_128, &keys.hp_key).map_err(|e| {
+        QuicError::HeaderProtectionFailed(format!("Failed to create HP key: {:?}", e))
+    })?;
+    hp_key
+        .new_mask(sample)
+        .map_err(|e| QuicError::HeaderProtectionFailed(format!("Failed to generate mask: {:?}", e)))
+}
+
 /// 解码 Packet Number
 ///
 /// RFC 9000 Section 17.1:
@@ -174,7 +207,6 @@ pub fn remove_header_protection(
 /// else:
 ///     return candidate + pn_win
 /// ```
-#[allow(dead_code)]
 pub fn decode_packet_number(truncated_pn: &[u8], expected_pn: u64) -> Result<u64> {
     let pn_len = truncated_pn.len();
 
@@ -209,10 +241,7 @@ pub fn decode_packet_number(truncated_pn: &[u8], expected_pn: u64) -> Result<u64
             candidate + pn_win
         };
 
-    debug!(
-        "PN decode: truncated={}, expected={}, decoded={}",
-        truncated, expected_pn, decoded
-    );
+    debug!(truncated, expected_pn, decoded, "PN decoded");
 
     Ok(decoded)
 }
@@ -295,9 +324,9 @@ mod tests {
         // 测试错误处理
         let mut short_packet = [0u8; 30];
         let keys = crate::quic::crypto::InitialKeys {
-            key: vec![0u8; 16],
-            iv: vec![0u8; 12],
-            hp_key: vec![0u8; 16],
+            key: [0u8; 16],
+            iv: [0u8; 12],
+            hp_key: [0u8; 16],
         };
 
         let result = remove_header_protection(&mut short_packet, 25, &keys);
@@ -305,13 +334,52 @@ mod tests {
         assert!(result.is_err());
     }
 
+    /// RFC 9001 Appendix A.2 / A.3 的 header protection mask
+    #[test]
+    fn test_rfc9001_header_protection_masks() {
+        use crate::quic::crypto::{derive_initial_keys_for_role, InitialKeyRole};
+        use crate::quic::test_util::{hex, RFC9001_DCID};
+
+        let client =
+            derive_initial_keys_for_role(&RFC9001_DCID, 0x00000001, InitialKeyRole::Client)
+                .unwrap();
+        let mask =
+            header_protection_mask(&hex("d1b1c98dd7689fb8ec11d242b123dc9b"), &client).unwrap();
+        assert_eq!(mask.to_vec(), hex("437b9aec36"));
+
+        let server =
+            derive_initial_keys_for_role(&RFC9001_DCID, 0x00000001, InitialKeyRole::Server)
+                .unwrap();
+        let mask =
+            header_protection_mask(&hex("2cd0991cd25b0aac406a5816b6394100"), &server).unwrap();
+        assert_eq!(mask.to_vec(), hex("2ec0d8356a"));
+    }
+
+    /// RFC 9001 Appendix A.2: 移除保护后 first byte 为 0xc3，PN 为 2 (4 字节)
+    #[test]
+    fn test_remove_header_protection_rfc9001_client_initial() {
+        use crate::quic::crypto::derive_initial_keys;
+        use crate::quic::test_util::{hex, rfc9001_client_initial, RFC9001_DCID};
+
+        let mut packet = rfc9001_client_initial();
+        let keys = derive_initial_keys(&RFC9001_DCID, 0x00000001).unwrap();
+        assert_eq!(
+            remove_header_protection(&mut packet, 18, &keys).unwrap(),
+            (0xc3, 2, 4)
+        );
+        assert_eq!(
+            &packet[..22],
+            &hex("c300000001088394c8f03e5157080000449e00000002")[..]
+        );
+    }
+
     #[test]
     fn test_remove_header_protection_packet_too_short() {
         let mut packet = [0u8; 10]; // 太短
         let keys = crate::quic::crypto::InitialKeys {
-            key: vec![0u8; 16],
-            iv: vec![0u8; 12],
-            hp_key: vec![0u8; 16],
+            key: [0u8; 16],
+            iv: [0u8; 12],
+            hp_key: [0u8; 16],
         };
 
         let result = remove_header_protection(&mut packet, 8, &keys);
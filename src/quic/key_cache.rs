@@ -0,0 +1,147 @@
+//! QUIC Initial 密钥缓存
+//!
+//! 同一连接的客户端通常会发送 2~4 个 Initial 包 (原始包 + PTO 重传)，
+//! 而且每个包都要尝试 client/server 两个方向。Initial 密钥只依赖
+//! (DCID, version, role)，缓存后重传包无需再跑完整的 HKDF 链。
+
+use crate::quic::crypto::{derive_initial_keys_for_role, InitialKeyRole, InitialKeys};
+use crate::quic::error::Result;
+use lru::LruCache;
+use std::num::NonZeroUsize;
+
+type CacheKey = (Vec<u8>, u32, InitialKeyRole);
+
+/// (DCID, version, role) → InitialKeys 的 LRU 缓存
+///
+/// 由调用方持有 (例如 `QuicSessionManager`)，不是全局状态。
+#[derive(Debug)]
+pub struct InitialKeyCache {
+    entries: LruCache<CacheKey, InitialKeys>,
+    hits: u64,
+    misses: u64,
+}
+
+impl Default for InitialKeyCache {
+    fn default() -> Self {
+        Self::new(1024)
+    }
+}
+
+impl InitialKeyCache {
+    /// 创建缓存，`capacity` 为最多缓存的密钥组数量
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: LruCache::new(NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN)),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// 读取缓存的密钥，未命中时派生并写入缓存
+    pub fn get_or_derive(
+        &mut self,
+        dcid: &[u8],
+        version: u32,
+        role: InitialKeyRole,
+    ) -> Result<InitialKeys> {
+        let key = (dcid.to_vec(), version, role);
+
+        if let Some(keys) = self.entries.get(&key) {
+            self.hits += 1;
+            return Ok(*keys);
+        }
+
+        self.misses += 1;
+        let keys = derive_initial_keys_for_role(dcid, version, role)?;
+        // 已满时淘汰最久未使用的一组
+        self.entries.put(key, keys);
+
+        Ok(keys)
+    }
+
+    /// 当前缓存的密钥组数量
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// 缓存是否为空
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// 命中次数
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// 未命中 (实际执行密钥派生) 次数
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cached_keys_match_derived_keys() {
+        let dcid = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+        let mut cache = InitialKeyCache::default();
+
+        let first = cache
+            .get_or_derive(&dcid, 0x00000001, InitialKeyRole::Client)
+            .unwrap();
+        let second = cache
+            .get_or_derive(&dcid, 0x00000001, InitialKeyRole::Client)
+            .unwrap();
+        let direct =
+            derive_initial_keys_for_role(&dcid, 0x00000001, InitialKeyRole::Client).unwrap();
+
+        assert_eq!(second.key, direct.key);
+        assert_eq!(second.iv, direct.iv);
+        assert_eq!(second.hp_key, first.hp_key);
+        assert_eq!(cache.misses(), 1);
+        assert_eq!(cache.hits(), 1);
+    }
+
+    #[test]
+    fn test_version_and_role_are_part_of_the_key() {
+        let dcid = [0x01, 0x02, 0x03, 0x04];
+        let mut cache = InitialKeyCache::default();
+
+        let v1 = cache
+            .get_or_derive(&dcid, 0x00000001, InitialKeyRole::Client)
+            .unwrap();
+        let v2 = cache
+            .get_or_derive(&dcid, 0x6b3343cf, InitialKeyRole::Client)
+            .unwrap();
+        let server = cache
+            .get_or_derive(&dcid, 0x00000001, InitialKeyRole::Server)
+            .unwrap();
+
+        assert_ne!(v1.key, v2.key);
+        assert_ne!(v1.key, server.key);
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.misses(), 3);
+    }
+
+    #[test]
+    fn test_least_recently_used_entry_is_evicted() {
+        let mut cache = InitialKeyCache::new(2);
+        let role = InitialKeyRole::Client;
+
+        cache.get_or_derive(&[1], 0x00000001, role).unwrap();
+        cache.get_or_derive(&[2], 0x00000001, role).unwrap();
+        // 访问 [1]，使 [2] 成为最久未使用
+        cache.get_or_derive(&[1], 0x00000001, role).unwrap();
+        cache.get_or_derive(&[3], 0x00000001, role).unwrap();
+        assert_eq!(cache.len(), 2);
+
+        let misses = cache.misses();
+        cache.get_or_derive(&[1], 0x00000001, role).unwrap();
+        assert_eq!(cache.misses(), misses);
+        cache.get_or_derive(&[2], 0x00000001, role).unwrap();
+        assert_eq!(cache.misses(), misses + 1);
+    }
+}
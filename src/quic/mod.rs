@@ -5,96 +5,422 @@
 //! # 架构
 //!
 //! - [`parser`][]: QUIC Initial Packet 解析 (提取 DCID, Version 等)
+//! - [`close`][]: 拒绝连接时回复 CONNECTION_CLOSE
 //! - [`crypto`][]: 密钥派生 (HKDF) 和解密 (AES-GCM)
+//! - [`dns_cache`][]: 本地解析模式下的目标域名缓存
+//! - [`dump`][]: SNI 提取失败的 Initial 转储和离线重放
+//! - [`key_cache`][]: 按 (DCID, version, role) 缓存 Initial 密钥
 //! - [`error`][]: 错误类型定义
+//! - [`extractor`][]: 按 DCID 分片加锁的重组器与密钥缓存，SNI 提取不持有会话管理器的锁
+//! - [`frame`][]: 解密后 payload 的 frame 解析
+//! - [`negative_cache`][]: 被拒绝流 (不在白名单/无 SNI) 的短期缓存
+//! - [`negotiation`][]: 不支持版本时回复 Version Negotiation
+//! - [`reassembly`][]: 跨 Initial packets 的 CRYPTO 分片重组
 //! - [`session`][]: QUIC 会话管理 (DCID → SOCKS5 UDP relay)
+//! - [`shared_relay`][]: 多个会话共享的 SOCKS5 UDP relay (`socks5.udp_relay_sharing`)
+//! - [`span`][]: 按连接 (DCID) 的 tracing span
+//! - [`udp`][]: UDP 批量收包 (recvmmsg)、GSO/GRO 和 SO_REUSEPORT 多 worker 绑定
 //!
 //! # 使用流程
 //!
-//! 1. 接收 UDP packet
+//! 1. 批量接收 UDP packets (可配置多个 worker)
 //! 2. 提取 DCID
-//! 3. 查找现有会话 → 转发包
-//! 4. 无会话 → 提取 SNI → 白名单检查 → 创建 SOCKS5 UDP relay → 创建会话 → 转发包
+//! 3. 查找现有会话 (client_addr，未命中时按 DCID 匹配以应对 NAT 重绑定) → 转发包
+//! 4. 无会话 → 提取 SNI → 白名单检查 → 后台创建 SOCKS5 UDP relay → 创建会话 → 转发包
+//!    (会话建立期间同一客户端的后续包暂存，建好后按序转发)
+//!    (ClientHello 跨多个 Initial 时先暂存已收到的 Initial，收全后与触发包一起转发)
 //! 5. 定期清理过期会话
 //!
 //! # 限制
 //!
-//! - 不支持 ECH (Encrypted ClientHello)
-//! - 仅支持 QUIC v1 (0x00000001)
-//! - 每个会话独立维护，不跨 Initial packets 处理分片
+//! - 不解密 ECH (Encrypted ClientHello)：识别 encrypted_client_hello 扩展后按外层
+//!   ClientHello 的 SNI (public_name) 做白名单检查和路由，真实目标对代理不可见；
+//!   外层没有 SNI 时按 `quic.ech_without_sni` 拒绝或转发到 `quic.ech_default_target`。
+//!   TCP 入口的 `rules.ech_policy` 不作用于 QUIC
+//! - 仅支持 QUIC v1 (0x00000001) 和 QUIC v2 (0x6b3343cf 及草案 0x709a50c4)
+//! - 跨 Initial packets 的 ClientHello 分片按 DCID 重组，超时或超出条目上限即丢弃
 
+pub mod breaker;
+pub mod close;
 pub mod crypto;
 pub mod decrypt;
+pub mod dns_cache;
+pub mod dump;
 pub mod error;
+pub mod extractor;
+pub mod frame;
 pub mod header;
+pub mod key_cache;
+pub mod negative_cache;
+pub mod negotiation;
+pub mod outcome;
 pub mod parser;
+pub mod reassembly;
 pub mod session;
+pub mod shared_relay;
+pub mod span;
+pub mod udp;
+
+/// 测试辅助，`testing` feature 下对集成测试公开
+#[cfg(any(test, feature = "testing"))]
+pub mod test_util;
 
 pub use header::remove_header_protection;
 pub use parser::parse_initial_header;
 
-use crate::config::Config;
+use crate::config::{BackendKind, Config, ConfigError, EchPolicy, UpstreamProtocol};
+use crate::logging;
+use crate::metrics::{Exposition, Listener, MetricKind, Registry, TaskGuard, TaskKind};
 use crate::router::Router;
+use crate::stats::Totals;
+use crate::upstream::UpstreamSet;
 use anyhow::Result as AnyhowResult;
+use std::io;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::UdpSocket;
+use tokio::sync::watch;
 use tracing::{debug, info, trace, warn};
 
+/// 后端无法转发 UDP 时返回原因
+///
+/// QUIC 依赖单跳 SOCKS5 的 UDP ASSOCIATE：HTTP CONNECT 后端、`socks5.chain` 和
+/// SOCKS4a 上游都只能承载 TCP。部分上游是 SOCKS4a 时 QUIC 只使用其余上游。
+pub fn udp_unsupported_reason(config: &Config) -> Option<&'static str> {
+    if config.backend.kind != BackendKind::Socks5 {
+        Some("QUIC/HTTP3 needs SOCKS5 UDP ASSOCIATE; UDP is unsupported over backend.type = \"http_connect\" (set server.quic_mode = \"off\")")
+    } else if !config.socks5.chain.is_empty() {
+        Some("QUIC/HTTP3 needs SOCKS5 UDP ASSOCIATE; UDP cannot traverse socks5.chain (set server.quic_mode = \"off\")")
+    } else if config.socks5.protocol == UpstreamProtocol::Socks4a
+        && config
+            .socks5
+            .upstreams
+            .iter()
+            .all(|u| u.protocol == UpstreamProtocol::Socks4a)
+    {
+        Some("QUIC/HTTP3 needs SOCKS5 UDP ASSOCIATE; every upstream uses socks4a, which has no UDP support (set server.quic_mode = \"off\")")
+    } else {
+        None
+    }
+}
+
+/// 关闭时等待会话任务退出的最长时间
+const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(5);
+
 /// 运行 QUIC/HTTP3 代理服务器
 ///
-/// 接收 UDP packets，提取 SNI，管理会话，通过 SOCKS5 UDP relay 转发流量
-pub async fn run(config: Config) -> AnyhowResult<()> {
+/// 接收 UDP packets，提取 SNI，管理会话，通过 SOCKS5 UDP relay 转发流量。
+/// `shutdown` 变为 true 后停止收包，关闭所有会话后返回。后端无法承载 UDP 时
+/// (见 [`udp_unsupported_reason`]) 直接返回错误。新会话、SNI 提取和转发字节数记入 `metrics`，
+/// 会话数在抓取时读取。
+pub async fn run(
+    config: Arc<Config>,
+    upstreams: Arc<UpstreamSet>,
+    shutdown: watch::Receiver<bool>,
+    metrics: Arc<Registry>,
+) -> crate::Result<()> {
     let listen_addr = config
         .server
         .listen_https_addr
-        .ok_or_else(|| anyhow::anyhow!("HTTPS listen address not configured"))?;
+        .ok_or(ConfigError::MissingListenAddr {
+            field: "server.listen_https_addr",
+        })?;
+    let sockets = bind(&config, listen_addr)?;
+    run_on_sockets(sockets, config, upstreams, shutdown, metrics).await
+}
 
-    info!("Starting QUIC/HTTP3 proxy server on {}", listen_addr);
-    debug!("QUIC SNI extraction module loaded");
+/// 绑定 `listen_addr` (通常是 `listen_https_addr`) 的 UDP socket；`quic.udp_workers` 大于 1 时
+/// 每个 worker 一个 SO_REUSEPORT socket，都绑定第一个 socket 的实际地址 (端口为 0 时也相同)
+pub fn bind(config: &Config, listen_addr: SocketAddr) -> crate::Result<Vec<Arc<UdpSocket>>> {
+    if let Some(reason) = udp_unsupported_reason(config) {
+        return Err(ConfigError::Invalid {
+            section: "quic",
+            reason: reason.to_string(),
+        }
+        .into());
+    }
+
+    info!(listener = "quic", addr = %listen_addr, "starting quic proxy server");
+    let workers = config.quic.udp_workers.max(1);
+    let reuse_port = workers > 1;
+    let bind = |addr, reuse_port| {
+        udp::bind_udp_socket(addr, reuse_port).map_err(|source| crate::Error::ListenerBind {
+            listener: "QUIC",
+            addr,
+            source,
+        })
+    };
+    let socket = Arc::new(bind(listen_addr, reuse_port)?);
+    let bound_addr = socket.local_addr()?;
+    let mut sockets = vec![socket];
+    for _ in 1..workers {
+        sockets.push(Arc::new(bind(bound_addr, true)?));
+    }
+    info!(listener = "quic", addr = %bound_addr, workers, "udp socket bound");
+    Ok(sockets)
+}
 
-    // 绑定 UDP socket
-    let socket = Arc::new(UdpSocket::bind(&listen_addr).await?);
-    info!("UDP socket bound to {}", listen_addr);
+/// 在 [`bind`] 绑定的 socket 上运行 QUIC 代理服务器，其余同 [`run`]
+pub async fn run_on_sockets(
+    sockets: Vec<Arc<UdpSocket>>,
+    config: Arc<Config>,
+    upstreams: Arc<UpstreamSet>,
+    shutdown: watch::Receiver<bool>,
+    metrics: Arc<Registry>,
+) -> crate::Result<()> {
+    let socket = sockets
+        .first()
+        .cloned()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no QUIC socket bound"))?;
+    crypto::set_debug_crypto(config.quic.debug_crypto);
+    if config.quic.debug_crypto {
+        warn!(
+            "quic.debug_crypto is enabled: QUIC Initial key material will be logged at trace level"
+        );
+    }
+    debug!("quic sni extraction module loaded");
+    let _listening = metrics.listening(Listener::Quic);
+    if config.quic.enable_gso {
+        for socket in &sockets {
+            if let Err(e) = udp::enable_gro(socket) {
+                warn!(error = %e, "UDP GRO is not available, receiving datagrams individually");
+                break;
+            }
+        }
+    }
 
     // 创建路由器
-    let router = Router::new(config.clone());
+    let router = Router::new(Arc::clone(&config));
+
+    let max_datagram_size = udp::clamp_datagram_size(config.quic.max_datagram_size);
+    if max_datagram_size != config.quic.max_datagram_size {
+        warn!(
+            configured = config.quic.max_datagram_size,
+            using = max_datagram_size,
+            "quic.max_datagram_size is out of range"
+        );
+    }
+
+    let ech_default_target = match config.quic.ech_without_sni {
+        EchPolicy::Reject => None,
+        EchPolicy::ForwardDefault => {
+            if config.quic.ech_default_target.is_none() {
+                warn!(
+                    "quic.ech_without_sni = \"forward_default\" but quic.ech_default_target is not set; ECH Initials without SNI will be rejected"
+                );
+            }
+            config.quic.ech_default_target.clone()
+        }
+    };
 
     // 创建会话管理器
-    let session_config = session::QuicSessionConfig::default();
-    let session_manager = session::QuicSessionManager::new(
-        session_config,
-        router,
-        config.socks5,
-        Arc::clone(&socket),
-    );
+    let session_config = session::QuicSessionConfig {
+        idle_overrides: config.quic.idle_overrides.clone(),
+        max_sessions: config.quic.max_sessions,
+        max_sessions_per_ip: config.quic.max_sessions_per_ip,
+        evict_idle_on_full: config.quic.evict_idle_on_full,
+        dns_cache_ttl: Duration::from_secs(config.quic.dns_cache_ttl),
+        max_datagram_size,
+        enable_gso: config.quic.enable_gso,
+        initial_rate: config.quic.initial_rate,
+        initial_burst: config.quic.initial_burst,
+        relay_failure: config.quic.relay_failure,
+        relay_probe_interval: (config.quic.relay_probe_interval > 0)
+            .then(|| Duration::from_secs(config.quic.relay_probe_interval)),
+        associate_attempts: config.quic.associate_attempts,
+        breaker_threshold: config.quic.associate_breaker_threshold,
+        breaker_cooldown: Duration::from_secs(config.quic.associate_breaker_cooldown),
+        session_queue_capacity: config.quic.session_queue_capacity,
+        shared_relays: if config.socks5.udp_relay_sharing {
+            config.socks5.udp_relay_pool_size.max(1)
+        } else {
+            0
+        },
+        udp_reassembly: config.socks5.udp_reassembly,
+        stats_interval: (config.quic.stats_interval > 0)
+            .then(|| Duration::from_secs(config.quic.stats_interval)),
+        ech_default_target,
+        try_server_role: config.quic.try_server_role,
+        debug_dump_dir: config.quic.debug_dump_dir.clone(),
+        debug_dump_per_minute: config.quic.debug_dump_per_minute,
+        debug_dump_max_bytes: config.quic.debug_dump_max_bytes,
+        require_alpn: config.quic.require_alpn.clone(),
+        allow_missing_alpn: config.quic.allow_missing_alpn,
+        ..Default::default()
+    };
+    // 回包统一从第一个 socket 发出；各 socket 绑定同一地址，五元组不变
+    let session_manager =
+        session::QuicSessionManager::new(session_config, router, upstreams, socket)
+            .with_metrics(Arc::clone(&metrics));
+    register_session_metrics(&metrics, &session_manager);
+
+    serve(
+        sockets,
+        session_manager,
+        config.quic.recv_batch_size,
+        shutdown,
+    )
+    .await
+}
 
-    // 启动会话清理任务
-    session_manager.spawn_cleanup_task();
+/// 抓取时输出会话数和本地解析缓存的命中数，统计日志读取会话数 (拒绝由收包循环计入注册表)，
+/// 管理端口的 `/quic/sessions` 列出当前会话
+fn register_session_metrics(metrics: &Registry, session_manager: &session::QuicSessionManager) {
+    let sessions = session_manager.clone();
+    metrics.register_quic_sessions(move || {
+        serde_json::to_value(sessions.snapshot()).unwrap_or_default()
+    });
+    let sessions = session_manager.clone();
+    metrics.register_stats_source(move || {
+        let sessions = sessions.clone();
+        async move {
+            let stats = sessions.stats().await;
+            Totals {
+                quic_sessions: stats.active_sessions as u64,
+                ..Default::default()
+            }
+        }
+    });
+    let session_manager = session_manager.clone();
+    metrics.register_collector(move || {
+        let session_manager = session_manager.clone();
+        async move {
+            let stats = session_manager.stats().await;
+            let mut out = Exposition::default();
+            for (name, help, value) in [
+                (
+                    "sniproxy_quic_sessions_active",
+                    "QUIC sessions currently forwarding",
+                    stats.active_sessions,
+                ),
+                (
+                    "sniproxy_quic_sessions_pending",
+                    "QUIC sessions waiting for DNS or UDP ASSOCIATE",
+                    stats.pending_sessions,
+                ),
+                (
+                    "sniproxy_quic_incomplete_client_hellos",
+                    "QUIC clients whose ClientHello spans Initials not yet received",
+                    stats.incomplete_client_hellos,
+                ),
+            ] {
+                out.family(name, MetricKind::Gauge, help);
+                out.sample(name, &[], value);
+            }
+            for (name, help, value) in [
+                (
+                    "sniproxy_quic_dns_cache_hits_total",
+                    "QUIC target lookups answered by the local DNS cache",
+                    stats.dns_cache_hits,
+                ),
+                (
+                    "sniproxy_quic_dns_cache_misses_total",
+                    "QUIC target lookups that ran a DNS query",
+                    stats.dns_cache_misses,
+                ),
+            ] {
+                out.family(name, MetricKind::Counter, help);
+                out.sample(name, &[], value);
+            }
+            out.finish()
+        }
+    });
+}
 
-    let mut buf = [0u8; 1500]; // MTU 1500
+/// 在已绑定的 socket 上运行收包循环，直到某个 worker 出错或 `shutdown` 变为 true
+///
+/// 每个 socket 一个 worker；`session_manager` 应以其中一个 socket 回包。
+/// 返回前关闭所有会话 (见 [`session::QuicSessionManager::shutdown`])。
+pub async fn serve(
+    sockets: Vec<Arc<UdpSocket>>,
+    session_manager: session::QuicSessionManager,
+    recv_batch_size: usize,
+    mut shutdown: watch::Receiver<bool>,
+) -> crate::Result<()> {
+    // 启动会话清理任务和收包汇总日志
+    let cleanup = session_manager.spawn_cleanup_task();
+    let stats_task = session_manager.spawn_stats_task();
 
-    loop {
-        // 接收 UDP packet
-        let (len, src_addr) = socket.recv_from(&mut buf).await?;
+    let max_datagram_size = session_manager.max_datagram_size();
+    let mut tasks = tokio::task::JoinSet::new();
+    for socket in sockets {
+        tasks.spawn(recv_loop(
+            socket,
+            session_manager.clone(),
+            recv_batch_size,
+            max_datagram_size,
+            session_manager.track_task(TaskKind::Listener),
+        ));
+    }
 
-        if len == 0 {
-            continue;
+    // 任一 worker 出错或收到关闭信号即退出
+    let result: AnyhowResult<()> = tokio::select! {
+        result = async {
+            while let Some(result) = tasks.join_next().await {
+                result??;
+            }
+            AnyhowResult::Ok(())
+        } => result,
+        _ = shutdown.wait_for(|stop| *stop) => {
+            info!(listener = "quic", "shutting down listener");
+            Ok(())
         }
+    };
 
-        trace!("Received {} UDP bytes from {}", len, src_addr);
+    tasks.abort_all();
+    if let Some(stats_task) = stats_task {
+        stats_task.abort();
+    }
+    session_manager.shutdown(SHUTDOWN_DEADLINE).await;
+    cleanup.await.ok();
+    Ok(result?)
+}
 
-        // 处理包 (会话管理器会处理 SNI 提取、白名单检查、relay 创建)
-        match session_manager.handle_packet(&buf[..len], src_addr).await {
-            Ok(forwarded) => {
-                if forwarded {
-                    trace!("QUIC packet forwarded from {}", src_addr);
-                } else {
-                    trace!("QUIC packet not forwarded from {}", src_addr);
-                }
+/// 单个 worker 的收包循环：批量读取数据报并逐个交给会话管理器；`_task` 在循环退出时释放
+async fn recv_loop(
+    socket: Arc<UdpSocket>,
+    session_manager: session::QuicSessionManager,
+    batch_size: usize,
+    max_datagram_size: usize,
+    _task: TaskGuard,
+) -> AnyhowResult<()> {
+    // 开启了 UDP_GRO 的 socket 需要能容纳合并读取的缓冲区
+    let mut batch = if udp::gro_enabled(&socket) {
+        udp::RecvBatch::with_gro(batch_size, max_datagram_size)
+    } else {
+        udp::RecvBatch::new(batch_size, max_datagram_size)
+    };
+    let mut truncation = udp::TruncationWarnings::default();
+
+    loop {
+        udp::recv_batch(&socket, &mut batch).await?;
+
+        for datagram in batch.iter() {
+            let (packet, src_addr) = (datagram.payload, datagram.src);
+            if packet.is_empty() {
+                continue;
             }
-            Err(e) => {
-                // 非致命错误，只记录警告
-                warn!("Failed to handle packet from {}: {}", src_addr, e);
+            if datagram.truncated {
+                truncation.record("UDP datagram", src_addr, batch.datagram_size());
+            }
+
+            trace!(listener = "quic", client = %src_addr, bytes = packet.len(), "udp packet received");
+
+            // 处理包 (会话管理器会处理 SNI 提取、白名单检查、relay 创建)
+            match session_manager.handle_packet(packet, src_addr).await {
+                Ok(outcome) => {
+                    trace!(listener = "quic", client = %src_addr, outcome = ?outcome, "packet handled");
+                    session_manager.record_packet(Some(&outcome), packet.len());
+                }
+                Err(e) => {
+                    // 非致命错误，只记录警告；同一来源的重复失败限流
+                    let reason = e.metric_label();
+                    if logging::allow_warning(reason, Some(src_addr.ip())) {
+                        warn!(listener = "quic", client = %src_addr, reason, error = %e, "failed to handle packet");
+                    }
+                    session_manager.record_failed_packet(&e, packet.len());
+                }
             }
         }
     }
@@ -0,0 +1,129 @@
+//! 被拒绝 QUIC 流的短期缓存
+//!
+//! 客户端对被拦截的域名会不断重传 Initial，每次都要做完整的密钥派生、
+//! Header Protection 移除、AEAD 解密和 TLS 解析。这里按 (client_addr, DCID)
+//! 记录拒绝原因，有效期内的重传直接丢弃，不再做任何密码学运算。
+
+use lru::LruCache;
+use std::fmt;
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::time::{Duration, Instant};
+
+/// 拒绝原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    /// SNI 不在白名单
+    NotWhitelisted,
+    /// 完整的 ClientHello 中没有 SNI
+    NoSni,
+    /// ALPN 与 `quic.require_alpn` 没有交集
+    AlpnMismatch,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::NotWhitelisted => write!(f, "not whitelisted"),
+            Rejection::NoSni => write!(f, "no SNI"),
+            Rejection::AlpnMismatch => write!(f, "ALPN mismatch"),
+        }
+    }
+}
+
+/// 有界的 TTL 拒绝缓存
+///
+/// 查询不改变顺序，条目按写入时间排列：最旧的在队尾，过期条目也都集中在队尾。
+#[derive(Debug)]
+pub struct NegativeCache {
+    entries: LruCache<(SocketAddr, Vec<u8>), (Rejection, Instant)>,
+    ttl: Duration,
+}
+
+impl NegativeCache {
+    /// 创建缓存
+    pub fn new(ttl: Duration, max_entries: usize) -> Self {
+        Self {
+            entries: LruCache::new(NonZeroUsize::new(max_entries).unwrap_or(NonZeroUsize::MIN)),
+            ttl,
+        }
+    }
+
+    /// 查询未过期的拒绝记录
+    pub fn get(&self, client: SocketAddr, dcid: &[u8]) -> Option<Rejection> {
+        let (reason, inserted_at) = self.entries.peek(&(client, dcid.to_vec()))?;
+        (inserted_at.elapsed() <= self.ttl).then_some(*reason)
+    }
+
+    /// 记录一次拒绝；缓存已满时淘汰最旧的条目
+    pub fn insert(&mut self, client: SocketAddr, dcid: &[u8], reason: Rejection) {
+        self.entries
+            .put((client, dcid.to_vec()), (reason, Instant::now()));
+    }
+
+    /// 从队尾淘汰所有过期条目，返回淘汰数量
+    pub fn evict_expired(&mut self) -> usize {
+        let mut evicted = 0;
+        while let Some((_, (_, inserted_at))) = self.entries.peek_lru() {
+            if inserted_at.elapsed() <= self.ttl {
+                break;
+            }
+            self.entries.pop_lru();
+            evicted += 1;
+        }
+        evicted
+    }
+
+    /// 当前缓存条目数
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// 缓存是否为空
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([192, 0, 2, 1], port))
+    }
+
+    #[test]
+    fn test_hit_is_keyed_by_client_and_dcid() {
+        let mut cache = NegativeCache::new(Duration::from_secs(30), 16);
+        cache.insert(addr(1), &[0xaa], Rejection::NotWhitelisted);
+
+        assert_eq!(cache.get(addr(1), &[0xaa]), Some(Rejection::NotWhitelisted));
+        assert_eq!(cache.get(addr(2), &[0xaa]), None);
+        assert_eq!(cache.get(addr(1), &[0xbb]), None);
+    }
+
+    #[test]
+    fn test_entries_expire() {
+        let mut cache = NegativeCache::new(Duration::from_millis(10), 16);
+        cache.insert(addr(1), &[0xaa], Rejection::NoSni);
+        std::thread::sleep(Duration::from_millis(20));
+
+        assert_eq!(cache.get(addr(1), &[0xaa]), None);
+        assert_eq!(cache.evict_expired(), 1);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_cache_is_bounded() {
+        let mut cache = NegativeCache::new(Duration::from_secs(30), 2);
+        cache.insert(addr(1), &[1], Rejection::NoSni);
+        std::thread::sleep(Duration::from_millis(2));
+        cache.insert(addr(1), &[2], Rejection::NoSni);
+        cache.insert(addr(1), &[3], Rejection::NoSni);
+
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(addr(1), &[1]), None);
+        assert_eq!(cache.get(addr(1), &[3]), Some(Rejection::NoSni));
+    }
+}
@@ -0,0 +1,171 @@
+//! QUIC Version Negotiation
+//!
+//! 参考 RFC 9000 Section 6: Version Negotiation
+//! 参考 RFC 9000 Section 17.2.1: Version Negotiation Packet
+//! 参考 RFC 8999 Section 5.1: Long Header (版本无关的不变量)
+
+use crate::quic::parser::{QUIC_VERSION_1, QUIC_VERSION_2, QUIC_VERSION_2_DRAFT};
+
+/// 我们能够提取 SNI 的 QUIC 版本，按偏好顺序写入 Version Negotiation 包
+pub const SUPPORTED_VERSIONS: &[u32] = &[QUIC_VERSION_1, QUIC_VERSION_2];
+
+/// 可能发起新连接的 UDP datagram 最小长度 (RFC 9000 Section 14.1)
+///
+/// 小于该长度的未知版本包不回复 Version Negotiation，避免放大攻击。
+pub const MIN_NEGOTIATION_TRIGGER_SIZE: usize = 1200;
+
+/// 按 RFC 8999 不变量解析 Long Header 的 (version, DCID, SCID)
+///
+/// 不关心版本号和包类型，只要结构完整即可。
+pub fn parse_invariant_long_header(packet: &[u8]) -> Option<(u32, &[u8], &[u8])> {
+    if packet.first()? & 0x80 == 0 {
+        return None;
+    }
+
+    let version = u32::from_be_bytes(packet.get(1..5)?.try_into().ok()?);
+
+    let dcil = *packet.get(5)? as usize;
+    let dcid = packet.get(6..6 + dcil)?;
+
+    let scil_pos = 6 + dcil;
+    let scil = *packet.get(scil_pos)? as usize;
+    let scid = packet.get(scil_pos + 1..scil_pos + 1 + scil)?;
+
+    Some((version, dcid, scid))
+}
+
+/// 判断是否应当为该包回复 Version Negotiation
+///
+/// 仅对结构完整、足够大、且版本号非 0 (0 本身就是 Version Negotiation) 的
+/// 不支持版本的 Long Header 包回复。
+pub fn should_negotiate(packet: &[u8]) -> bool {
+    if packet.len() < MIN_NEGOTIATION_TRIGGER_SIZE {
+        return false;
+    }
+
+    match parse_invariant_long_header(packet) {
+        Some((version, _, _)) => {
+            version != 0
+                && version != QUIC_VERSION_2_DRAFT
+                && !SUPPORTED_VERSIONS.contains(&version)
+        }
+        None => false,
+    }
+}
+
+/// 构造 Version Negotiation 包
+///
+/// RFC 9000 Section 17.2.1:
+/// ```text
+/// Version Negotiation Packet {
+///   Header Form (1) = 1,
+///   Unused (7),
+///   Version (32) = 0,
+///   Destination Connection ID Length (8),
+///   Destination Connection ID (0..2040),
+///   Source Connection ID Length (8),
+///   Source Connection ID (0..2040),
+///   Supported Version (32) ...,
+/// }
+/// ```
+///
+/// # 参数
+/// - `client_dcid`: 客户端包中的 DCID (回复中作为 SCID)
+/// - `client_scid`: 客户端包中的 SCID (回复中作为 DCID)
+/// - `versions`: 支持的版本列表
+pub fn build_version_negotiation(
+    client_dcid: &[u8],
+    client_scid: &[u8],
+    versions: &[u32],
+) -> Vec<u8> {
+    let mut packet =
+        Vec::with_capacity(7 + client_dcid.len() + client_scid.len() + versions.len() * 4);
+
+    // Unused 位任意，按 RFC 9000 建议置位 0x40 使其看起来带有 Fixed Bit
+    packet.push(0xc0);
+    packet.extend_from_slice(&0u32.to_be_bytes());
+    packet.push(client_scid.len() as u8);
+    packet.extend_from_slice(client_scid);
+    packet.push(client_dcid.len() as u8);
+    packet.extend_from_slice(client_dcid);
+
+    for version in versions {
+        packet.extend_from_slice(&version.to_be_bytes());
+    }
+
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unknown_version_packet(version: u32, len: usize) -> Vec<u8> {
+        let mut packet = vec![0xc0];
+        packet.extend_from_slice(&version.to_be_bytes());
+        packet.push(8);
+        packet.extend_from_slice(&[0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08]);
+        packet.push(4);
+        packet.extend_from_slice(&[0xaa, 0xbb, 0xcc, 0xdd]);
+        packet.resize(len, 0);
+        packet
+    }
+
+    #[test]
+    fn test_build_version_negotiation_layout() {
+        let dcid = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+        let scid = [0xaa, 0xbb, 0xcc, 0xdd];
+
+        let packet = build_version_negotiation(&dcid, &scid, SUPPORTED_VERSIONS);
+
+        let expected = [
+            0xc0, // Header Form = 1, Unused
+            0x00, 0x00, 0x00, 0x00, // Version = 0
+            0x04, // DCID Length (客户端 SCID)
+            0xaa, 0xbb, 0xcc, 0xdd, // DCID
+            0x08, // SCID Length (客户端 DCID)
+            0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08, // SCID
+            0x00, 0x00, 0x00, 0x01, // QUIC v1
+            0x6b, 0x33, 0x43, 0xcf, // QUIC v2
+        ];
+        assert_eq!(packet, expected);
+    }
+
+    #[test]
+    fn test_build_version_negotiation_empty_cids() {
+        let packet = build_version_negotiation(&[], &[], &[QUIC_VERSION_1]);
+        assert_eq!(packet, [0xc0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_parse_invariant_long_header() {
+        let packet = unknown_version_packet(0x1a2a3a4a, 1200);
+        let (version, dcid, scid) = parse_invariant_long_header(&packet).unwrap();
+        assert_eq!(version, 0x1a2a3a4a);
+        assert_eq!(dcid.len(), 8);
+        assert_eq!(scid, &[0xaa, 0xbb, 0xcc, 0xdd]);
+
+        // 截断的 SCID
+        assert!(parse_invariant_long_header(&packet[..16]).is_none());
+        // Short Header
+        assert!(parse_invariant_long_header(&[0x40, 0, 0, 0, 1, 0, 0]).is_none());
+    }
+
+    #[test]
+    fn test_should_negotiate() {
+        assert!(should_negotiate(&unknown_version_packet(0x1a2a3a4a, 1200)));
+        // 太小的包不回复，避免放大
+        assert!(!should_negotiate(&unknown_version_packet(0x1a2a3a4a, 100)));
+        // 支持的版本不需要协商
+        assert!(!should_negotiate(&unknown_version_packet(
+            QUIC_VERSION_1,
+            1200
+        )));
+        assert!(!should_negotiate(&unknown_version_packet(
+            QUIC_VERSION_2,
+            1200
+        )));
+        // Version 0 本身就是 Version Negotiation 包
+        assert!(!should_negotiate(&unknown_version_packet(0, 1200)));
+    }
+}
@@ -0,0 +1,252 @@
+//! 单个 UDP 包的处理结果及其计数
+//!
+//! [`QuicSessionManager::handle_packet`](crate::quic::session::QuicSessionManager::handle_packet)
+//! 返回 [`PacketOutcome`]；收包循环把结果和字节数累加到共享的 [`PacketCounters`]，
+//! 按 `quic.stats_interval` 输出一行汇总。拒绝原因与 TCP/HTTP 共用 [`RejectReason`]。
+
+use crate::quic::negative_cache::Rejection;
+pub use crate::reason::RejectReason;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+impl From<Rejection> for RejectReason {
+    fn from(rejection: Rejection) -> Self {
+        match rejection {
+            Rejection::NotWhitelisted => RejectReason::NotWhitelisted,
+            Rejection::NoSni => RejectReason::NoSni,
+            Rejection::AlpnMismatch => RejectReason::AlpnMismatch,
+        }
+    }
+}
+
+/// `handle_packet` 对一个 UDP 包的处理结果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PacketOutcome {
+    /// 交给了已有会话 (含 NAT 重绑定后按 DCID 找回的会话)
+    ForwardedExisting,
+    /// 新流的 Initial 通过准入，会话在后台建立，建好后转发
+    ForwardedNew { sni: String },
+    /// 暂存，等待会话建立完成或 ClientHello 收全
+    Buffered,
+    /// 不属于任何会话，也不是可处理的 QUIC Initial
+    IgnoredNotQuic,
+    /// 版本不受支持 (需要时已回复 Version Negotiation)
+    UnsupportedVersion,
+    /// 新流被拒绝
+    Rejected { reason: RejectReason },
+}
+
+impl PacketOutcome {
+    /// 所有统计分类，`handle_packet` 返回错误时计入 "error"
+    pub const METRIC_LABELS: [&'static str; 13] = [
+        "forwarded_existing",
+        "forwarded_new",
+        "buffered",
+        "ignored_not_quic",
+        "unsupported_version",
+        "rejected_not_whitelisted",
+        "rejected_no_sni",
+        "rejected_alpn",
+        "rejected_rate_limited",
+        "rejected_over_limit",
+        "rejected_reflected",
+        "rejected_other",
+        "error",
+    ];
+
+    /// 用于统计的稳定分类
+    pub fn metric_label(&self) -> &'static str {
+        match self {
+            PacketOutcome::ForwardedExisting => "forwarded_existing",
+            PacketOutcome::ForwardedNew { .. } => "forwarded_new",
+            PacketOutcome::Buffered => "buffered",
+            PacketOutcome::IgnoredNotQuic => "ignored_not_quic",
+            PacketOutcome::UnsupportedVersion => "unsupported_version",
+            PacketOutcome::Rejected { reason } => match reason {
+                RejectReason::NotWhitelisted => "rejected_not_whitelisted",
+                RejectReason::NoSni => "rejected_no_sni",
+                RejectReason::AlpnMismatch => "rejected_alpn",
+                RejectReason::RateLimited => "rejected_rate_limited",
+                RejectReason::OverLimit => "rejected_over_limit",
+                RejectReason::Reflected => "rejected_reflected",
+                // QUIC 不会以这些原因拒绝新流
+                RejectReason::NotTls
+                | RejectReason::MalformedClientHello
+                | RejectReason::IncompleteClientHello
+                | RejectReason::EchWithoutSni
+                | RejectReason::TlsVersion
+                | RejectReason::EchRejected
+                | RejectReason::NoHost
+                | RejectReason::InvalidConnect
+                | RejectReason::HeaderTooLarge
+                | RejectReason::PortNotAllowed
+                | RejectReason::SniMismatch => "rejected_other",
+            },
+        }
+    }
+
+    /// 包已转发或已暂存等待转发
+    pub fn is_forwarded(&self) -> bool {
+        matches!(
+            self,
+            PacketOutcome::ForwardedExisting
+                | PacketOutcome::ForwardedNew { .. }
+                | PacketOutcome::Buffered
+        )
+    }
+}
+
+/// 收包循环的处理结果计数 (各 worker 共享)
+#[derive(Debug, Default)]
+pub struct PacketCounters {
+    /// 按 [`PacketOutcome::METRIC_LABELS`] 下标计数
+    outcomes: [AtomicU64; PacketOutcome::METRIC_LABELS.len()],
+    /// 收到的字节数
+    bytes_received: AtomicU64,
+    /// 已转发或暂存等待转发的字节数
+    bytes_forwarded: AtomicU64,
+}
+
+/// [`PacketCounters`] 的快照
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PacketStats {
+    /// 按分类的包数 (含全部分类)
+    pub outcomes: BTreeMap<&'static str, u64>,
+    /// 收到的字节数
+    pub bytes_received: u64,
+    /// 已转发或暂存等待转发的字节数
+    pub bytes_forwarded: u64,
+}
+
+impl PacketCounters {
+    /// 记录一个 `len` 字节的包的处理结果；`None` 表示处理出错
+    pub fn record(&self, outcome: Option<&PacketOutcome>, len: usize) {
+        let label = outcome.map_or("error", PacketOutcome::metric_label);
+        if let Some(i) = PacketOutcome::METRIC_LABELS
+            .iter()
+            .position(|l| *l == label)
+        {
+            self.outcomes[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.bytes_received.fetch_add(len as u64, Ordering::Relaxed);
+        if outcome.is_some_and(PacketOutcome::is_forwarded) {
+            self.bytes_forwarded
+                .fetch_add(len as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// 当前计数
+    pub fn snapshot(&self) -> PacketStats {
+        PacketStats {
+            outcomes: PacketOutcome::METRIC_LABELS
+                .iter()
+                .zip(&self.outcomes)
+                .map(|(label, count)| (*label, count.load(Ordering::Relaxed)))
+                .collect(),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            bytes_forwarded: self.bytes_forwarded.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl PacketStats {
+    /// 与更早的快照 `earlier` 之间的增量
+    pub fn since(&self, earlier: &PacketStats) -> PacketStats {
+        PacketStats {
+            outcomes: self
+                .outcomes
+                .iter()
+                .map(|(label, count)| {
+                    let before = earlier.outcomes.get(label).copied().unwrap_or(0);
+                    (*label, count.saturating_sub(before))
+                })
+                .collect(),
+            bytes_received: self.bytes_received.saturating_sub(earlier.bytes_received),
+            bytes_forwarded: self.bytes_forwarded.saturating_sub(earlier.bytes_forwarded),
+        }
+    }
+
+    /// 收到的包数
+    pub fn packets(&self) -> u64 {
+        self.outcomes.values().sum()
+    }
+
+    /// 一行汇总，省略计数为 0 的分类
+    pub fn summary(&self) -> String {
+        let outcomes: Vec<String> = self
+            .outcomes
+            .iter()
+            .filter(|(_, count)| **count > 0)
+            .map(|(label, count)| format!("{}={}", label, count))
+            .collect();
+        format!(
+            "{} packets ({} bytes in, {} bytes forwarded): {}",
+            self.packets(),
+            self.bytes_received,
+            self.bytes_forwarded,
+            outcomes.join(" ")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metric_labels_cover_all_outcomes() {
+        let reasons = [
+            RejectReason::NotWhitelisted,
+            RejectReason::NoSni,
+            RejectReason::AlpnMismatch,
+            RejectReason::RateLimited,
+            RejectReason::OverLimit,
+            RejectReason::Reflected,
+            RejectReason::SniMismatch,
+        ];
+        let mut outcomes = vec![
+            PacketOutcome::ForwardedExisting,
+            PacketOutcome::ForwardedNew { sni: "a".into() },
+            PacketOutcome::Buffered,
+            PacketOutcome::IgnoredNotQuic,
+            PacketOutcome::UnsupportedVersion,
+        ];
+        outcomes.extend(reasons.map(|reason| PacketOutcome::Rejected { reason }));
+        let mut labels: Vec<&str> = outcomes.iter().map(PacketOutcome::metric_label).collect();
+        labels.push("error");
+        assert_eq!(labels, PacketOutcome::METRIC_LABELS);
+    }
+
+    #[test]
+    fn test_counters_and_summary() {
+        let counters = PacketCounters::default();
+        counters.record(Some(&PacketOutcome::ForwardedExisting), 100);
+        counters.record(Some(&PacketOutcome::ForwardedExisting), 100);
+        counters.record(Some(&PacketOutcome::Buffered), 50);
+        counters.record(
+            Some(&PacketOutcome::Rejected {
+                reason: RejectReason::NotWhitelisted,
+            }),
+            1200,
+        );
+        counters.record(None, 10);
+
+        let before = counters.snapshot();
+        counters.record(Some(&PacketOutcome::ForwardedExisting), 100);
+        let stats = counters.snapshot();
+        let delta = stats.since(&before);
+        assert_eq!(delta.packets(), 1);
+        assert_eq!(delta.bytes_forwarded, 100);
+
+        assert_eq!(stats.outcomes.len(), PacketOutcome::METRIC_LABELS.len());
+        assert_eq!(stats.outcomes["forwarded_existing"], 3);
+        assert_eq!(stats.outcomes["error"], 1);
+        assert_eq!(stats.packets(), 6);
+        assert_eq!(stats.bytes_received, 1560);
+        assert_eq!(stats.bytes_forwarded, 350);
+        assert_eq!(
+            stats.summary(),
+            "6 packets (1560 bytes in, 350 bytes forwarded): buffered=1 error=1 forwarded_existing=3 rejected_not_whitelisted=1"
+        );
+    }
+}
@@ -6,9 +6,63 @@ use crate::quic::error::{QuicError, Result};
 use bytes::Bytes;
 use tracing::{debug, trace};
 
+/// QUIC Version 1 (RFC 9000)
+pub const QUIC_VERSION_1: u32 = 0x00000001;
+
+/// QUIC Version 2 (RFC 9369)
+pub const QUIC_VERSION_2: u32 = 0x6b3343cf;
+
+/// QUIC Version 2 草案版本号 (draft-ietf-quic-v2)
+pub const QUIC_VERSION_2_DRAFT: u32 = 0x709a50c4;
+
+/// Connection ID 的最大长度 (RFC 9000 Section 17.2)
+pub const MAX_CID_LEN: usize = 20;
+
+/// 返回指定版本下 Initial 包的 Long Header Packet Type 值
+///
+/// QUIC v2 (RFC 9369 Section 3.2) 重新映射了 Long Header 的类型位：
+/// v1 中 Initial 为 0b00，v2 中 Initial 为 0b01。
+pub fn initial_packet_type(version: u32) -> u8 {
+    match version {
+        QUIC_VERSION_2 | QUIC_VERSION_2_DRAFT => 0b01,
+        _ => 0b00,
+    }
+}
+
+/// 返回指定版本下 Retry 包的 Long Header Packet Type 值
+///
+/// v1 中 Retry 为 0b11，v2 中为 0b00。
+pub fn retry_packet_type(version: u32) -> u8 {
+    match version {
+        QUIC_VERSION_2 | QUIC_VERSION_2_DRAFT => 0b00,
+        _ => 0b11,
+    }
+}
+
+/// 服务端发出的 Retry 包返回其 SCID，其它包返回 None
+///
+/// 客户端收到 Retry 后，之后的 Initial 以该 SCID 作为 DCID 并携带 Retry Token。
+///
+/// 参考 RFC 9000 Section 17.2.5: Retry Packet
+pub fn retry_scid(packet: &[u8]) -> Option<&[u8]> {
+    let (version, _, scid) = crate::quic::negotiation::parse_invariant_long_header(packet)?;
+    if !matches!(
+        version,
+        QUIC_VERSION_1 | QUIC_VERSION_2 | QUIC_VERSION_2_DRAFT
+    ) {
+        return None;
+    }
+    if (packet[0] >> 4) & 0x03 != retry_packet_type(version)
+        || scid.is_empty()
+        || scid.len() > MAX_CID_LEN
+    {
+        return None;
+    }
+    Some(scid)
+}
+
 /// QUIC Initial Packet Header 结构
 #[derive(Debug, Clone)]
-#[allow(dead_code)]
 pub struct InitialHeader {
     /// 第一个字节 (包含 Packet Type 和 Packet Number Length)
     pub first_byte: u8,
@@ -37,12 +91,15 @@ pub struct InitialHeader {
 /// - DCID 的字节切片
 ///
 /// # 示例
-/// ```ignore
-/// let packet = hex::decode("c30000000108...")?;
+/// ```
+/// use sniproxy_ng::quic::parser::extract_dcid;
+/// use sniproxy_ng::quic::test_util::{rfc9001_client_initial, RFC9001_DCID};
+///
+/// let packet = rfc9001_client_initial();
 /// let dcid = extract_dcid(&packet)?;
-/// assert_eq!(dcid.len(), 8);
+/// assert_eq!(dcid, RFC9001_DCID);
+/// # Ok::<(), sniproxy_ng::quic::error::QuicError>(())
 /// ```
-#[allow(dead_code)]
 pub fn extract_dcid(packet: &[u8]) -> Result<&[u8]> {
     // 首先检查是否有至少 1 字节
     if packet.is_empty() {
@@ -67,30 +124,32 @@ pub fn extract_dcid(packet: &[u8]) -> Result<&[u8]> {
         });
     }
 
-    // 检查 Packet Type: Initial packet 的 bits 6-5 是 0b00
-    // Long Header 格式: 0b1TTxxxxx
-    // 其中 TT 是 packet type:
+    // 跳过 First Byte (1 byte)
+    // 跳过 Version (4 bytes)
+    // Version 是 big-endian u32
+    let version = u32::from_be_bytes([packet[1], packet[2], packet[3], packet[4]]);
+
+    debug!(version = %format_args!("{:#010x}", version), "quic version");
+
+    // 检查 Packet Type (bits 5-4)
+    // Long Header 格式: 0b11TTxxxx
+    // 其中 TT 是 packet type，v1 中:
     //   0b00 = Initial
     //   0b01 = 0-RTT
     //   0b10 = Handshake
     //   0b11 = Retry
+    // v2 中 Initial 为 0b01，见 initial_packet_type
     let packet_type = (first_byte & 0x30) >> 4;
-    if packet_type != 0x00 {
+    if packet_type != initial_packet_type(version) {
         return Err(QuicError::NotInitialPacket(first_byte));
     }
 
-    // 跳过 First Byte (1 byte)
-    // 跳过 Version (4 bytes)
-    // Version 是 big-endian u32
-    let version = u32::from_be_bytes([packet[1], packet[2], packet[3], packet[4]]);
-
-    debug!("QUIC Version: {:#010x}", version);
-
     // DCID Length (1 byte)
     let dcil_pos = 5;
     let dcil = packet[dcil_pos] as usize;
 
-    trace!("DCID Length: {}", dcil);
+    trace!(dcid_len = dcil, "DCID length");
+    check_cid_len("DCID", dcil)?;
 
     // 检查长度是否足够
     if packet.len() < dcil_pos + 1 + dcil {
@@ -105,8 +164,6 @@ pub fn extract_dcid(packet: &[u8]) -> Result<&[u8]> {
     let dcid_end = dcid_start + dcil;
     let dcid = &packet[dcid_start..dcid_end];
 
-    debug!("Extracted DCID: {:?} ({} bytes)", dcid, dcid.len());
-
     Ok(dcid)
 }
 
@@ -132,12 +189,6 @@ pub fn parse_initial_header(packet: &[u8]) -> Result<InitialHeader> {
         return Err(QuicError::NotInitialPacket(first_byte));
     }
 
-    // 检查 Initial Packet Type
-    let packet_type = (first_byte & 0x30) >> 4;
-    if packet_type != 0x00 {
-        return Err(QuicError::NotInitialPacket(first_byte));
-    }
-
     if packet.len() < 6 {
         return Err(QuicError::PacketTooShort {
             expected: 6,
@@ -150,22 +201,32 @@ pub fn parse_initial_header(packet: &[u8]) -> Result<InitialHeader> {
 
     // 验证版本
     match version {
-        0x00000001 => {
-            debug!("QUIC Version 1");
+        QUIC_VERSION_1 => {
+            debug!("quic version 1");
+        }
+        QUIC_VERSION_2 => {
+            debug!("quic version 2");
         }
-        0x709a50c4 => {
-            debug!("QUIC Version 2 (draft)");
+        QUIC_VERSION_2_DRAFT => {
+            debug!("quic version 2 (draft)");
         }
         _ => {
             return Err(QuicError::UnsupportedVersion { version });
         }
     }
 
+    // 检查 Initial Packet Type (与版本相关)
+    let packet_type = (first_byte & 0x30) >> 4;
+    if packet_type != initial_packet_type(version) {
+        return Err(QuicError::NotInitialPacket(first_byte));
+    }
+
     let mut offset = 5;
 
     // 解析 DCID
     let dcil = packet[offset] as usize;
     offset += 1;
+    check_cid_len("DCID", dcil)?;
 
     if packet.len() < offset + dcil {
         return Err(QuicError::PacketTooShort {
@@ -177,7 +238,7 @@ pub fn parse_initial_header(packet: &[u8]) -> Result<InitialHeader> {
     let dcid = Bytes::copy_from_slice(&packet[offset..offset + dcil]);
     offset += dcil;
 
-    trace!("DCID: {:?} ({} bytes)", dcid, dcil);
+    trace!(dcid_len = dcil, "DCID parsed");
 
     // 解析 SCID
     if packet.len() < offset + 1 {
@@ -189,6 +250,7 @@ pub fn parse_initial_header(packet: &[u8]) -> Result<InitialHeader> {
 
     let scil = packet[offset] as usize;
     offset += 1;
+    check_cid_len("SCID", scil)?;
 
     if packet.len() < offset + scil {
         return Err(QuicError::PacketTooShort {
@@ -200,40 +262,34 @@ pub fn parse_initial_header(packet: &[u8]) -> Result<InitialHeader> {
     let scid = Bytes::copy_from_slice(&packet[offset..offset + scil]);
     offset += scil;
 
-    trace!("SCID: {:?} ({} bytes)", scid, scil);
+    trace!(scid_len = scil, "SCID parsed");
 
     // 解析 Token Length (VarInt)
     let (token_len, varint_len) =
         parse_varint(&packet[offset..]).map_err(|e| QuicError::VarIntError(e.to_string()))?;
     offset += varint_len;
 
-    let token_len = token_len as usize; // 转换为 usize
-
-    trace!("Token Length: {} bytes", token_len);
+    trace!(token_len, "token length parsed");
 
     // 跳过 Token
-    if packet.len() < offset + token_len {
-        return Err(QuicError::PacketTooShort {
-            expected: offset + token_len,
-            actual: packet.len(),
-        });
-    }
-
-    offset += token_len;
+    let token_end = checked_end(offset, token_len, packet.len())?;
+    let token_len = token_end - offset;
+    offset = token_end;
 
     // 解析 Payload Length (VarInt)
     let (payload_len, varint_len2) =
         parse_varint(&packet[offset..]).map_err(|e| QuicError::VarIntError(e.to_string()))?;
     offset += varint_len2;
 
-    let payload_len = payload_len as usize; // 转换为 usize
-
-    trace!("Payload Length: {} bytes", payload_len);
+    trace!(payload_len, "payload length parsed");
 
     // 记录 Packet Number 的起始位置
     let pn_offset = offset;
 
-    debug!("Packet Number Offset: {}", pn_offset);
+    // Length 覆盖 PN + 加密 payload，不能超出数据包 (多出的字节属于 coalesced packets)
+    let payload_len = checked_end(pn_offset, payload_len, packet.len())? - pn_offset;
+
+    debug!(pn_offset, "packet number offset");
 
     Ok(InitialHeader {
         first_byte,
@@ -246,6 +302,33 @@ pub fn parse_initial_header(packet: &[u8]) -> Result<InitialHeader> {
     })
 }
 
+/// 检查 Connection ID 长度不超过 RFC 9000 的上限
+fn check_cid_len(name: &str, len: usize) -> Result<()> {
+    if len > MAX_CID_LEN {
+        return Err(QuicError::InvalidDcid(format!(
+            "{} length {} exceeds {} bytes",
+            name, len, MAX_CID_LEN
+        )));
+    }
+    Ok(())
+}
+
+/// 计算 `offset + len` 并确认不超出 `packet_len`
+///
+/// `len` 来自 VarInt，最大可达 2^62，必须在转换为 usize 前后都做溢出检查。
+fn checked_end(offset: usize, len: u64, packet_len: usize) -> Result<usize> {
+    let end = usize::try_from(len)
+        .ok()
+        .and_then(|len| offset.checked_add(len));
+    match end {
+        Some(end) if end <= packet_len => Ok(end),
+        _ => Err(QuicError::PacketTooShort {
+            expected: end.unwrap_or(usize::MAX),
+            actual: packet_len,
+        }),
+    }
+}
+
 /// 解析 QUIC VarInt (Variable-Length Integer)
 ///
 /// RFC 9000 Section 16: Variable-Length Integer Encoding
@@ -313,10 +396,49 @@ pub fn parse_varint(data: &[u8]) -> std::result::Result<(u64, usize), String> {
     Ok((value, length))
 }
 
+/// 编码 QUIC VarInt (总是选择能容纳该值的最短编码)
+///
+/// 超过 2^62 - 1 的值无法编码，会被截断到低 62 位。
+pub fn encode_varint(value: u64) -> Vec<u8> {
+    if value < 1 << 6 {
+        vec![value as u8]
+    } else if value < 1 << 14 {
+        ((value as u16) | 0x4000).to_be_bytes().to_vec()
+    } else if value < 1 << 30 {
+        ((value as u32) | 0x8000_0000).to_be_bytes().to_vec()
+    } else {
+        ((value & 0x3fff_ffff_ffff_ffff) | 0xc000_0000_0000_0000)
+            .to_be_bytes()
+            .to_vec()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_retry_scid() {
+        // v1 Retry: type 0b11，DCID = 客户端 SCID，SCID = 服务端新选的 CID
+        let mut retry = vec![
+            0xf0, 0x00, 0x00, 0x00, 0x01, 0x02, 0xaa, 0xbb, 0x03, 1, 2, 3,
+        ];
+        retry.extend_from_slice(b"token");
+        retry.extend_from_slice(&[0u8; 16]);
+        assert_eq!(retry_scid(&retry), Some(&[1u8, 2, 3][..]));
+
+        // 同样的类型位在 v2 中是 Handshake
+        retry[1..5].copy_from_slice(&QUIC_VERSION_2.to_be_bytes());
+        assert_eq!(retry_scid(&retry), None);
+        retry[0] = 0xc0;
+        assert_eq!(retry_scid(&retry), Some(&[1u8, 2, 3][..]));
+
+        // v1 Initial 不是 Retry
+        retry[1..5].copy_from_slice(&QUIC_VERSION_1.to_be_bytes());
+        assert_eq!(retry_scid(&retry), None);
+        assert_eq!(retry_scid(&[0x40, 0x01]), None);
+    }
+
     #[test]
     fn test_extract_dcid_valid_initial_packet() {
         // 构造一个简单的 QUIC Initial packet
@@ -406,6 +528,112 @@ mod tests {
         assert_eq!(header.pn_offset, 25);
     }
 
+    #[test]
+    fn test_parse_initial_header_v2() {
+        let packet = [
+            0xD0, // Initial packet in v2 (Long Header, Type=0b01)
+            0x6b, 0x33, 0x43, 0xcf, // Version 2
+            0x08, // DCID Length = 8
+            0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, // DCID
+            0x00, // SCID Length = 0
+            0x00, // Token Length = 0
+            0x05, // Payload Length = 5
+            0x00, 0x01, 0x02, 0x03, 0x04, // PN + Payload
+        ];
+
+        let header = parse_initial_header(&packet).expect("Failed to parse v2 header");
+        assert_eq!(header.version, QUIC_VERSION_2);
+        assert_eq!(header.dcid.len(), 8);
+        assert_eq!(header.pn_offset, 17);
+
+        let dcid = extract_dcid(&packet).expect("Failed to extract v2 DCID");
+        assert_eq!(dcid, &[0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08]);
+    }
+
+    #[test]
+    fn test_packet_type_is_version_aware() {
+        // v2 中 0b00 是 Retry，不是 Initial
+        let mut v2_retry = [
+            0xC0, 0x6b, 0x33, 0x43, 0xcf, 0x04, 0x01, 0x02, 0x03, 0x04, 0x00, 0x00, 0x00,
+        ];
+        assert!(matches!(
+            parse_initial_header(&v2_retry),
+            Err(QuicError::NotInitialPacket(0xC0))
+        ));
+        assert!(extract_dcid(&v2_retry).is_err());
+
+        // v1 中 0b01 是 0-RTT，不是 Initial
+        v2_retry[0] = 0xD0;
+        v2_retry[1..5].copy_from_slice(&QUIC_VERSION_1.to_be_bytes());
+        assert!(matches!(
+            parse_initial_header(&v2_retry),
+            Err(QuicError::NotInitialPacket(0xD0))
+        ));
+        assert!(extract_dcid(&v2_retry).is_err());
+    }
+
+    #[test]
+    fn test_oversized_connection_ids_are_rejected() {
+        // DCID Length = 255
+        let mut packet = vec![0xC0, 0x00, 0x00, 0x00, 0x01, 0xFF];
+        packet.resize(300, 0x00);
+        assert!(matches!(
+            parse_initial_header(&packet),
+            Err(QuicError::InvalidDcid(_))
+        ));
+        assert!(matches!(
+            extract_dcid(&packet),
+            Err(QuicError::InvalidDcid(_))
+        ));
+
+        // DCID Length = 20 合法，SCID Length = 21 超限
+        let mut packet = vec![0xC0, 0x00, 0x00, 0x00, 0x01, 20];
+        packet.extend_from_slice(&[0x01; 20]);
+        packet.push(21);
+        packet.resize(100, 0x00);
+        assert!(matches!(
+            parse_initial_header(&packet),
+            Err(QuicError::InvalidDcid(_))
+        ));
+        assert_eq!(extract_dcid(&packet).unwrap(), &[0x01; 20]);
+    }
+
+    #[test]
+    fn test_length_fields_beyond_packet_are_rejected() {
+        let prefix = [
+            0xC0, // Initial packet
+            0x00, 0x00, 0x00, 0x01, // Version 1
+            0x04, 0x01, 0x02, 0x03, 0x04, // DCID
+            0x00, // SCID Length = 0
+        ];
+
+        // Payload Length = 2^40
+        let mut packet = prefix.to_vec();
+        packet.push(0x00);
+        packet.extend_from_slice(&encode_varint(1 << 40));
+        packet.extend_from_slice(&[0x00; 32]);
+        assert!(matches!(
+            parse_initial_header(&packet),
+            Err(QuicError::PacketTooShort { .. })
+        ));
+
+        // Token Length = 2^62 - 1
+        let mut packet = prefix.to_vec();
+        packet.extend_from_slice(&encode_varint((1 << 62) - 1));
+        packet.extend_from_slice(&[0x00; 32]);
+        assert!(matches!(
+            parse_initial_header(&packet),
+            Err(QuicError::PacketTooShort { .. })
+        ));
+
+        // Payload Length 恰好覆盖剩余字节时合法
+        let mut packet = prefix.to_vec();
+        packet.push(0x00);
+        packet.push(0x20);
+        packet.extend_from_slice(&[0x00; 32]);
+        assert_eq!(parse_initial_header(&packet).unwrap().payload_len, 32);
+    }
+
     #[test]
     fn test_unsupported_version() {
         let packet = [
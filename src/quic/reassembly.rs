@@ -0,0 +1,195 @@
+//! 跨 Initial packets 的 CRYPTO 流重组
+//!
+//! 较大的 ClientHello (例如带 post-quantum key share) 会被拆分到多个 Initial 包中，
+//! 需要按 DCID 缓存 CRYPTO 分片，直到能从 offset 0 拼出完整的 ClientHello。
+//!
+//! 参考 RFC 9000 Section 19.6: CRYPTO Frames
+
+use crate::quic::crypto::InitialKeyRole;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+/// 单个 DCID 的待重组 CRYPTO 分片
+#[derive(Debug)]
+struct PendingCrypto {
+    role: InitialKeyRole,
+    fragments: BTreeMap<u64, Vec<u8>>,
+    last_update: Instant,
+}
+
+/// CRYPTO 分片重组器
+///
+/// 由调用方持有 (例如 `QuicSessionManager`)，不同监听器之间互不共享。
+/// 超过 `ttl` 未更新的条目会被淘汰；条目数达到 `max_entries` 时淘汰最久未更新的条目。
+#[derive(Debug)]
+pub struct CryptoReassembler {
+    entries: HashMap<Vec<u8>, PendingCrypto>,
+    ttl: Duration,
+    max_entries: usize,
+}
+
+impl Default for CryptoReassembler {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(3), 1024)
+    }
+}
+
+impl CryptoReassembler {
+    /// 创建重组器
+    pub fn new(ttl: Duration, max_entries: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            ttl,
+            max_entries: max_entries.max(1),
+        }
+    }
+
+    /// 加入一个包中的 CRYPTO 分片，返回从 offset 0 开始的连续 CRYPTO 数据
+    ///
+    /// 同一 DCID 的条目若已过期或解密方向 (role) 改变，会先被重置。
+    pub fn insert(
+        &mut self,
+        dcid: &[u8],
+        role: InitialKeyRole,
+        fragments: Vec<(u64, Vec<u8>)>,
+    ) -> Vec<u8> {
+        if !self.entries.contains_key(dcid) && self.entries.len() >= self.max_entries {
+            self.evict_expired();
+            if self.entries.len() >= self.max_entries {
+                self.evict_oldest();
+            }
+        }
+
+        let ttl = self.ttl;
+        let entry = self
+            .entries
+            .entry(dcid.to_vec())
+            .or_insert_with(|| PendingCrypto {
+                role,
+                fragments: BTreeMap::new(),
+                last_update: Instant::now(),
+            });
+
+        if entry.last_update.elapsed() > ttl || entry.role != role {
+            entry.role = role;
+            entry.fragments.clear();
+        }
+        entry.last_update = Instant::now();
+
+        for (off, data) in fragments {
+            entry.fragments.insert(off, data);
+        }
+
+        // Reassemble contiguous CRYPTO stream from offset 0.
+        let mut out: Vec<u8> = Vec::new();
+        let mut cur: u64 = 0;
+        for (off, data) in entry.fragments.iter() {
+            if *off > cur {
+                break; // gap
+            }
+            let start = (cur - *off) as usize;
+            if start < data.len() {
+                out.extend_from_slice(&data[start..]);
+                cur += (data.len() - start) as u64;
+            }
+        }
+
+        out
+    }
+
+    /// 丢弃某个 DCID 的分片 (ClientHello 已完整解析后调用)
+    pub fn remove(&mut self, dcid: &[u8]) {
+        self.entries.remove(dcid);
+    }
+
+    /// 是否仍有该 DCID 的未完成分片
+    pub fn contains(&self, dcid: &[u8]) -> bool {
+        self.entries.contains_key(dcid)
+    }
+
+    /// 淘汰所有过期条目，返回淘汰数量
+    pub fn evict_expired(&mut self) -> usize {
+        let before = self.entries.len();
+        let ttl = self.ttl;
+        self.entries
+            .retain(|_, entry| entry.last_update.elapsed() <= ttl);
+        before - self.entries.len()
+    }
+
+    /// 当前缓存的 DCID 数量
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// 是否没有任何待重组的分片
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn evict_oldest(&mut self) {
+        let oldest = self
+            .entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_update)
+            .map(|(dcid, _)| dcid.clone());
+        if let Some(dcid) = oldest {
+            self.entries.remove(&dcid);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reassemble_out_of_order_fragments() {
+        let mut reassembler = CryptoReassembler::default();
+        let dcid = [0x01, 0x02, 0x03, 0x04];
+
+        // 先到达的是后半部分，存在空洞，不能输出
+        let out = reassembler.insert(&dcid, InitialKeyRole::Client, vec![(3, b"def".to_vec())]);
+        assert!(out.is_empty());
+
+        let out = reassembler.insert(&dcid, InitialKeyRole::Client, vec![(0, b"abc".to_vec())]);
+        assert_eq!(out, b"abcdef");
+        assert_eq!(reassembler.len(), 1);
+
+        reassembler.remove(&dcid);
+        assert!(reassembler.is_empty());
+    }
+
+    #[test]
+    fn test_stale_entry_is_evicted() {
+        let mut reassembler = CryptoReassembler::new(Duration::from_millis(10), 16);
+        reassembler.insert(&[0xaa], InitialKeyRole::Client, vec![(0, b"abc".to_vec())]);
+        std::thread::sleep(Duration::from_millis(20));
+
+        assert_eq!(reassembler.evict_expired(), 1);
+        assert!(reassembler.is_empty());
+    }
+
+    #[test]
+    fn test_stale_fragments_are_not_reused() {
+        let mut reassembler = CryptoReassembler::new(Duration::from_millis(10), 16);
+        reassembler.insert(&[0xaa], InitialKeyRole::Client, vec![(0, b"abc".to_vec())]);
+        std::thread::sleep(Duration::from_millis(20));
+
+        let out = reassembler.insert(&[0xaa], InitialKeyRole::Client, vec![(3, b"def".to_vec())]);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn test_max_entries_evicts_oldest() {
+        let mut reassembler = CryptoReassembler::new(Duration::from_secs(60), 2);
+        reassembler.insert(&[1], InitialKeyRole::Client, vec![(3, b"x".to_vec())]);
+        std::thread::sleep(Duration::from_millis(2));
+        reassembler.insert(&[2], InitialKeyRole::Client, vec![(3, b"x".to_vec())]);
+        reassembler.insert(&[3], InitialKeyRole::Client, vec![(0, b"abc".to_vec())]);
+
+        assert_eq!(reassembler.len(), 2);
+        // DCID [1] 最旧，已被淘汰；若仍在会拼出 "abcx"
+        let out = reassembler.insert(&[1], InitialKeyRole::Client, vec![(0, b"abc".to_vec())]);
+        assert_eq!(out, b"abc");
+    }
+}
@@ -1,56 +1,437 @@
 //! QUIC 会话管理
 //!
 //! 为每个 QUIC 连接 (DCID) 维护独立的 SOCKS5 UDP relay 会话。
+//! 会话以客户端地址为主索引，DCID 为辅助索引，客户端 NAT 重绑定后
+//! 仍能按 DCID 找回原会话并迁移到新地址。
+//!
+//! 会话表是并发 map：转发已有会话的包只做一次无锁查找和一次原子写，
+//! 不经过管理器的互斥锁；会话的创建、迁移、淘汰和清理仍在互斥锁内串行进行。
 
-use crate::config::Socks5Config;
-use crate::quic::decrypt::extract_sni_from_quic_initial;
+use crate::config::{IdleOverride, RelayFailureMode, ResolveMode, Socks5Config};
+use crate::logging;
+use crate::metrics::{Direction, Listener, Phase, Registry, TaskGuard, TaskKind};
+use crate::quic::breaker::{CircuitBreaker, Transition};
+use crate::quic::close;
+use crate::quic::crypto::InitialKeyRole;
+use crate::quic::decrypt::{SniExtraction, BOTH_ROLES, CLIENT_ROLE};
+use crate::quic::dns_cache::DnsCache;
+use crate::quic::dump::InitialDumper;
+use crate::quic::error::QuicError;
+use crate::quic::extractor::SniExtractor;
+use crate::quic::negative_cache::{NegativeCache, Rejection};
+use crate::quic::negotiation;
+use crate::quic::outcome::{PacketCounters, PacketOutcome, PacketStats};
+use crate::quic::parser::{retry_scid, InitialHeader};
+use crate::quic::shared_relay::{
+    RelayAttachment, RelayCounters, SharedRelayPool, SharedRelayStats,
+};
+use crate::quic::span;
+use crate::quic::udp::{self, GsoBatch, TruncationWarnings};
+use crate::rate_limit::PerIpRateLimiter;
+use crate::reason::{FailureReason, Outcome, RejectReason};
 use crate::router::Router;
-use crate::socks5::udp::Socks5UdpClient;
+use crate::socks5::client::Socks5ErrorCounters;
+use crate::socks5::metrics::Socks5Operation;
+use crate::socks5::udp::{
+    wait_control_closed, Reassembly, Socks5UdpClient, Socks5UdpDatagram, UdpReassembler,
+    SOCKS5_UDP_HEADER_MAX,
+};
+use crate::socks5::RetryPolicy;
+use crate::task;
+use crate::tls::sni::{HostnameError, SniError};
+use crate::upstream::{Upstream, UpstreamSet};
 use anyhow::{anyhow, Result};
-use std::collections::HashMap;
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use dashmap::DashMap;
+use fast_socks5::util::target_addr::{TargetAddr, ToTargetAddr};
+use serde::{Serialize, Serializer};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
-use tokio::net::UdpSocket;
-use tokio::sync::mpsc;
+use tokio::net::{TcpStream, UdpSocket};
 use tokio::sync::Mutex;
-use tracing::{debug, info, trace, warn};
+use tokio::sync::{mpsc, watch};
+use tracing::{debug, info, trace, warn, Instrument};
 
 /// 会话配置
 #[derive(Clone)]
 pub struct QuicSessionConfig {
     /// 会话空闲超时
     pub idle_timeout: Duration,
+    /// 按目标域名覆盖的空闲超时，取第一个匹配 SNI 的模式
+    pub idle_overrides: Vec<IdleOverride>,
     /// 会话清理间隔
     pub cleanup_interval: Duration,
+    /// 会话建立期间 (或等待 ClientHello 后续分片期间) 每个客户端最多暂存的包数
+    pub pending_max_packets: usize,
+    /// 会话建立期间 (或等待 ClientHello 后续分片期间) 每个客户端最多暂存的字节数
+    pub pending_max_bytes: usize,
+    /// 暂存包的有效期，超过后不再暂存且建立会话后丢弃；
+    /// ClientHello 超过该时间仍未收全时丢弃已暂存的 Initial
+    pub pending_ttl: Duration,
+    /// 跨包 CRYPTO 分片的有效期
+    pub crypto_reassembly_ttl: Duration,
+    /// 最多同时重组的 DCID 数量
+    pub crypto_reassembly_max_entries: usize,
+    /// 被拒绝流 (不在白名单/无 SNI) 的缓存有效期
+    pub negative_cache_ttl: Duration,
+    /// 被拒绝流缓存的最大条目数
+    pub negative_cache_max_entries: usize,
+    /// 会话移除后保留其路由的时长，期间该客户端带 Token 的 Initial 直接沿用原路由
+    pub route_tombstone_ttl: Duration,
+    /// Initial 密钥缓存容量 (按 DCID/version/role 计)
+    pub initial_key_cache_size: usize,
+    /// 最大会话数 (含正在建立的会话)
+    pub max_sessions: usize,
+    /// 每个客户端 IP 的最大会话数
+    pub max_sessions_per_ip: usize,
+    /// 达到最大会话数时淘汰最久未活动的会话
+    pub evict_idle_on_full: bool,
+    /// 本地解析 (socks5.resolve = "local") 结果的缓存时间
+    pub dns_cache_ttl: Duration,
+    /// 最多缓存的域名数量
+    pub dns_cache_size: usize,
+    /// 单个 UDP 数据报的最大字节数 (relay 缓冲区另加 SOCKS5 UDP 头部开销)
+    pub max_datagram_size: usize,
+    /// 回包时把 relay 上已就绪的等长数据报合并为一次 GSO 发送 (仅 Linux)
+    pub enable_gso: bool,
+    /// 每个来源 IP 每秒允许进入 SNI 提取的新 Initial 数 (0 表示不限)
+    pub initial_rate: f64,
+    /// 每个来源 IP 允许的 Initial 突发数
+    pub initial_burst: u32,
+    /// relay 控制连接断开后结束会话还是重新关联
+    pub relay_failure: RelayFailureMode,
+    /// 发往 relay 的包超过该时间仍收不到回包时判定 relay 已失效 (见 [`RelayWatchdog`])；
+    /// None 表示不检测
+    pub relay_probe_interval: Option<Duration>,
+    /// 建立会话时 UDP ASSOCIATE 的最大尝试次数
+    pub associate_attempts: u32,
+    /// ASSOCIATE 重试的初始间隔，之后逐次翻倍
+    pub associate_backoff: Duration,
+    /// 同一上游连续多少次 ASSOCIATE 失败后打开断路器 (0 表示不使用断路器)
+    pub breaker_threshold: u32,
+    /// 断路器打开后多久放行一个探测
+    pub breaker_cooldown: Duration,
+    /// 每个会话等待发往 relay 的包队列长度；队列满时丢弃新到的包，不阻塞收包循环
+    pub session_queue_capacity: usize,
+    /// 每个上游最多共享的 SOCKS5 UDP relay 数 (见 [`crate::quic::shared_relay`])；
+    /// 0 表示每个会话独占一个 relay
+    pub shared_relays: usize,
+    /// 重组 SOCKS5 UDP 分片；否则丢弃分片 (见 [`RelayFragments`])
+    pub udp_reassembly: bool,
+    /// 收包处理结果汇总的日志间隔；None 表示不输出
+    pub stats_interval: Option<Duration>,
+    /// 使用 ECH 但外层没有 SNI 时转发到的目标域名；None 表示拒绝
+    pub ech_default_target: Option<String>,
+    /// client 方向解密失败后是否再尝试 server 方向的 Initial 密钥
+    pub try_server_role: bool,
+    /// SNI 提取失败的 Initial 转储目录；None 表示不转储
+    pub debug_dump_dir: Option<PathBuf>,
+    /// 每分钟最多写入的转储数
+    pub debug_dump_per_minute: u32,
+    /// 转储目录总大小上限 (字节)
+    pub debug_dump_max_bytes: u64,
+    /// 只接受 ALPN 与该列表有交集的会话；空列表表示不检查
+    pub require_alpn: Vec<String>,
+    /// 配置了 `require_alpn` 时是否接受没有 ALPN 的 ClientHello
+    pub allow_missing_alpn: bool,
 }
 
 impl Default for QuicSessionConfig {
     fn default() -> Self {
         Self {
             idle_timeout: Duration::from_secs(60),
+            idle_overrides: Vec::new(),
             cleanup_interval: Duration::from_secs(30),
+            pending_max_packets: 10,
+            pending_max_bytes: 20 * 1024,
+            pending_ttl: Duration::from_secs(2),
+            crypto_reassembly_ttl: Duration::from_secs(3),
+            crypto_reassembly_max_entries: 1024,
+            negative_cache_ttl: Duration::from_secs(30),
+            negative_cache_max_entries: 4096,
+            route_tombstone_ttl: Duration::from_secs(10),
+            initial_key_cache_size: 1024,
+            max_sessions: 4096,
+            max_sessions_per_ip: 64,
+            evict_idle_on_full: true,
+            dns_cache_ttl: Duration::from_secs(60),
+            dns_cache_size: 1024,
+            max_datagram_size: crate::quic::udp::DEFAULT_MAX_DATAGRAM_SIZE,
+            enable_gso: false,
+            initial_rate: 100.0,
+            initial_burst: 500,
+            relay_failure: RelayFailureMode::Teardown,
+            relay_probe_interval: Some(Duration::from_secs(30)),
+            associate_attempts: 3,
+            associate_backoff: Duration::from_millis(250),
+            breaker_threshold: 5,
+            breaker_cooldown: Duration::from_secs(5),
+            session_queue_capacity: 1024,
+            shared_relays: 0,
+            udp_reassembly: false,
+            stats_interval: Some(Duration::from_secs(60)),
+            ech_default_target: None,
+            try_server_role: false,
+            debug_dump_dir: None,
+            debug_dump_per_minute: 10,
+            debug_dump_max_bytes: 64 * 1024 * 1024,
+            require_alpn: Vec::new(),
+            allow_missing_alpn: true,
         }
     }
 }
 
+impl QuicSessionConfig {
+    /// 目标为 `sni` 的会话使用的空闲超时
+    pub fn idle_timeout_for(&self, sni: &str) -> Duration {
+        self.idle_overrides
+            .iter()
+            .find(|o| Router::match_pattern(sni, &o.pattern))
+            .map_or(self.idle_timeout, |o| o.idle_timeout)
+    }
+}
+
 /// QUIC 会话 - 对应一个 DCID
-#[allow(dead_code)]
+///
+/// 以 `Arc` 共享存放在会话表中，转发路径只需读取 `tx` 并更新 `last_active`。
 pub struct QuicSession {
     /// DCID (Destination Connection ID)
     pub dcid: Vec<u8>,
+    /// 源站 Retry 后客户端改用的 DCID，与 `dcid` 一样登记在 DCID 索引中
+    ///
+    /// 客户端地址变化时只需改写这些索引条目，不必扫描整个索引。
+    pub retry_dcids: std::sync::Mutex<Vec<Vec<u8>>>,
+    /// 客户端 SCID (回复 CONNECTION_CLOSE 时作为 DCID)
+    pub scid: Vec<u8>,
+    /// QUIC 版本
+    pub version: u32,
     /// 提取的 SNI
     pub sni: String,
-    /// 目标服务器地址（SNI 解析出来的 ip:port，通常是 :443）
-    pub target_addr: SocketAddr,
-    /// 客户端地址
-    pub client_addr: SocketAddr,
+    /// 客户端是否使用 ECH (此时 `sni` 是外层 ClientHello 的 public_name)
+    pub ech: bool,
+    /// ClientHello 中的 ALPN 列表
+    pub alpn: Vec<String>,
+    /// 目标服务器地址（通常是 SNI:443；remote 模式下为域名，由 SOCKS5 服务器解析）
+    pub target_addr: TargetAddr,
+    /// 承载该会话的 SOCKS5 上游名称
+    pub upstream: String,
     /// 发往该会话的客户端 QUIC 包（由会话任务负责通过 SOCKS5 UDP 发往 target_addr）
     pub tx: mpsc::Sender<Vec<u8>>,
-    /// 最后活跃时间
-    pub last_active: Instant,
+    /// 当前客户端地址；变化时通知会话任务（NAT 重绑定后回包发往新地址）
+    pub client_tx: watch::Sender<SocketAddr>,
+    /// 最后活跃时间 (相对 `created_at` 的毫秒数)
+    pub last_active: AtomicU64,
     /// 创建时间
     pub created_at: Instant,
+    /// 空闲超时 (创建时按 SNI 确定，见 `QuicSessionConfig::idle_timeout_for`)
+    pub idle_timeout: Duration,
+    /// 流量计数 (与会话任务共享，更新时不需要任何锁)
+    pub traffic: Arc<SessionTraffic>,
+    /// 会话任务句柄，关闭监听器时等待其退出
+    pub task: std::sync::Mutex<Option<tokio::task::JoinHandle<()>>>,
+}
+
+/// 单个会话的流量计数
+///
+/// up 为客户端 → 目标，down 为目标 → 客户端；只统计成功发出的包。
+#[derive(Debug, Default)]
+pub struct SessionTraffic {
+    /// 客户端 → 目标的包数
+    pub packets_up: AtomicU64,
+    /// 客户端 → 目标的字节数
+    pub bytes_up: AtomicU64,
+    /// 目标 → 客户端的包数
+    pub packets_down: AtomicU64,
+    /// 目标 → 客户端的字节数
+    pub bytes_down: AtomicU64,
+    /// 因会话队列已满而丢弃的客户端包数
+    pub packets_dropped: AtomicU64,
+    /// 会话是否正在丢包 (用于只在开始和恢复时记录日志)
+    shedding: AtomicBool,
+    /// 所有会话合计的转发字节数
+    metrics: Arc<Registry>,
+}
+
+impl SessionTraffic {
+    fn record_up(&self, len: usize) {
+        self.packets_up.fetch_add(1, Ordering::Relaxed);
+        self.bytes_up.fetch_add(len as u64, Ordering::Relaxed);
+        self.metrics
+            .record_bytes(Listener::Quic, Direction::ClientToUpstream, len as u64);
+    }
+
+    fn record_down(&self, len: usize) {
+        self.packets_down.fetch_add(1, Ordering::Relaxed);
+        self.bytes_down.fetch_add(len as u64, Ordering::Relaxed);
+        self.metrics
+            .record_bytes(Listener::Quic, Direction::UpstreamToClient, len as u64);
+    }
+
+    /// 记录一次队列满丢包；返回 true 表示会话刚开始丢包
+    fn record_drop(&self) -> bool {
+        self.packets_dropped.fetch_add(1, Ordering::Relaxed);
+        !self.shedding.swap(true, Ordering::Relaxed)
+    }
+
+    /// 包成功入队；返回 true 表示会话刚从丢包中恢复
+    fn record_queued(&self) -> bool {
+        self.shedding.load(Ordering::Relaxed) && self.shedding.swap(false, Ordering::Relaxed)
+    }
+}
+
+/// 会话列表中的一项 (见 [`QuicSessionManager::snapshot`])
+///
+/// 管理端口的 `GET /quic/sessions` 以 JSON 数组返回，时长字段为毫秒 (`*_ms`)。
+#[derive(Debug, Clone, Serialize)]
+pub struct QuicSessionSnapshot {
+    /// 当前客户端地址
+    pub client: SocketAddr,
+    /// SNI
+    pub sni: String,
+    /// 是否使用 ECH
+    pub ech: bool,
+    /// ALPN 列表
+    pub alpn: Vec<String>,
+    /// 目标地址
+    #[serde(serialize_with = "serialize_display")]
+    pub target: TargetAddr,
+    /// SOCKS5 上游名称
+    pub upstream: String,
+    /// 会话已存在的时长
+    #[serde(rename = "age_ms", serialize_with = "serialize_millis")]
+    pub age: Duration,
+    /// 距最后一次活动的时长
+    #[serde(rename = "idle_ms", serialize_with = "serialize_millis")]
+    pub idle: Duration,
+    /// 该会话的空闲超时
+    #[serde(rename = "idle_timeout_ms", serialize_with = "serialize_millis")]
+    pub idle_timeout: Duration,
+    /// 客户端 → 目标的包数
+    pub packets_up: u64,
+    /// 客户端 → 目标的字节数
+    pub bytes_up: u64,
+    /// 目标 → 客户端的包数
+    pub packets_down: u64,
+    /// 目标 → 客户端的字节数
+    pub bytes_down: u64,
+    /// 因会话队列已满而丢弃的客户端包数
+    pub packets_dropped: u64,
+}
+
+fn serialize_display<S: Serializer>(
+    value: &impl std::fmt::Display,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.collect_str(value)
+}
+
+fn serialize_millis<S: Serializer>(
+    elapsed: &Duration,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_f64(logging::millis(*elapsed))
+}
+
+impl QuicSession {
+    /// 当前客户端地址
+    pub fn client_addr(&self) -> SocketAddr {
+        *self.client_tx.borrow()
+    }
+
+    /// 登记在 DCID 索引中的全部连接 ID：`dcid` 和 Retry 后的新 DCID
+    pub fn connection_ids(&self) -> Vec<Vec<u8>> {
+        let retry_dcids = self.retry_dcids.lock().unwrap_or_else(|e| e.into_inner());
+        std::iter::once(&self.dcid)
+            .chain(retry_dcids.iter())
+            .cloned()
+            .collect()
+    }
+
+    /// 记录一次活动
+    pub fn touch(&self) {
+        let elapsed = self.created_at.elapsed().as_millis() as u64;
+        self.last_active.store(elapsed, Ordering::Relaxed);
+    }
+
+    /// 距最后一次活动的时长
+    pub fn idle_time(&self) -> Duration {
+        let last_active = Duration::from_millis(self.last_active.load(Ordering::Relaxed));
+        self.created_at.elapsed().saturating_sub(last_active)
+    }
+
+    /// 会话结束时把流量记入按域名的统计
+    fn record_domain_traffic(&self) {
+        self.traffic.metrics.domains().record(
+            &self.sni,
+            self.traffic.bytes_up.load(Ordering::Relaxed),
+            self.traffic.bytes_down.load(Ordering::Relaxed),
+        );
+    }
+
+    /// 当前状态和流量计数的快照
+    pub fn snapshot(&self) -> QuicSessionSnapshot {
+        QuicSessionSnapshot {
+            client: self.client_addr(),
+            sni: self.sni.clone(),
+            ech: self.ech,
+            alpn: self.alpn.clone(),
+            target: self.target_addr.clone(),
+            upstream: self.upstream.clone(),
+            age: self.created_at.elapsed(),
+            idle: self.idle_time(),
+            idle_timeout: self.idle_timeout,
+            packets_up: self.traffic.packets_up.load(Ordering::Relaxed),
+            bytes_up: self.traffic.bytes_up.load(Ordering::Relaxed),
+            packets_down: self.traffic.packets_down.load(Ordering::Relaxed),
+            bytes_down: self.traffic.bytes_down.load(Ordering::Relaxed),
+            packets_dropped: self.traffic.packets_dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// 会话表: client_addr -> session
+///
+/// 读取无需持有管理器的互斥锁；插入和删除只在持有该锁时进行。
+type SessionMap = DashMap<SocketAddr, Arc<QuicSession>>;
+
+/// DCID -> client_addr 索引
+///
+/// 与会话表一样读取无需持有管理器的互斥锁：未知地址的每个包都要按 DCID 查找 (NAT 重绑定)。
+/// Short Header 不携带 DCID 长度，按索引中出现过的 DCID 长度逐一查表，而不是扫描所有会话。
+#[derive(Debug, Default)]
+struct DcidIndex {
+    map: DashMap<Vec<u8>, SocketAddr>,
+    /// 出现过的 DCID 长度，第 n 位表示长度 n (只增不减)
+    lengths: AtomicU32,
+}
+
+impl DcidIndex {
+    fn insert(&self, dcid: Vec<u8>, client: SocketAddr) {
+        if (1..32).contains(&dcid.len()) {
+            self.lengths.fetch_or(1 << dcid.len(), Ordering::Relaxed);
+        }
+        self.map.insert(dcid, client);
+    }
+
+    fn get(&self, dcid: &[u8]) -> Option<SocketAddr> {
+        self.map.get(dcid).map(|entry| *entry.value())
+    }
+
+    fn contains_key(&self, dcid: &[u8]) -> bool {
+        self.map.contains_key(dcid)
+    }
+
+    /// Short Header 包中的 DCID 对应的客户端地址
+    fn find_short(&self, packet: &[u8]) -> Option<SocketAddr> {
+        let lengths = self.lengths.load(Ordering::Relaxed);
+        (1..32)
+            .filter(|len| lengths & (1 << len) != 0)
+            .find_map(|len| self.get(packet.get(1..1 + len)?))
+    }
 }
 
 /// 会话管理器内部状态
@@ -60,516 +441,4992 @@ struct SessionManagerInner {
     /// 说明：QUIC 后续大量数据包会是 Short Header，无法可靠地从旁路解析出
     /// 连接 ID 长度/值来做无状态识别；因此我们采用更工程化的 5-tuple 方式：
     /// 一旦为某个 client_addr 建立会话，则转发该 client_addr 的全部 UDP 包。
-    sessions: HashMap<SocketAddr, QuicSession>,
+    ///
+    /// 与 `QuicSessionManager::sessions` 是同一张表。
+    sessions: Arc<SessionMap>,
+    /// DCID -> client_addr 辅助索引，用于客户端地址变化后找回会话
+    ///
+    /// 与 `QuicSessionManager::dcid_index` 是同一份；插入和删除只在持有该锁时进行。
+    dcid_index: Arc<DcidIndex>,
+    /// 正在建立会话的客户端: client_addr -> 暂存的包
+    pending: HashMap<SocketAddr, PendingFlow>,
+    /// 每个客户端 IP 的会话数 (含 `pending`)，随两张表的插入和删除增减，
+    /// 检查 `max_sessions_per_ip` 时不必遍历会话表
+    per_ip: HashMap<IpAddr, usize>,
+    /// ClientHello 跨多个 Initial、尚未收全的客户端: client_addr -> 已收到的原始包
+    ///
+    /// SNI 确定后这些包按序先于触发包转发，上游才能看到完整的 ClientHello。
+    awaiting_hello: HashMap<SocketAddr, PendingFlow>,
+    /// 最近移除的会话的路由: client_addr -> (SNI 等, 过期时间)
+    ///
+    /// 源站发出 Retry 后，客户端的下一个 Initial 携带 Token 和新的 DCID，且未必原样
+    /// 重传 ClientHello；会话恰好在此时被移除时据此恢复，而不是重新提取 SNI。
+    recent_routes: HashMap<SocketAddr, RecentRoute>,
+    /// 被拒绝的 (client_addr, DCID)，重传的 Initial 直接丢弃
+    negative_cache: NegativeCache,
+    /// 新 Initial 的按来源 IP 限速 (在任何密码学运算之前检查)
+    initial_limiter: PerIpRateLimiter,
+    /// 因限速被丢弃的 Initial 数
+    rate_limited_initials: u64,
+    /// 执行 SNI 提取 (含 Initial 密钥派生和解密) 的次数
+    sni_extractions: u64,
+    /// 准入的使用 ECH 的新流数
+    ech_admissions: u64,
+    /// 因 ALPN 不符被拒绝的新流数
+    rejected_alpn: u64,
+    /// 因会话数超限被拒绝的次数
+    rejected_over_limit: u64,
+    /// 因会话数达到上限被淘汰的会话数
+    evicted_for_capacity: u64,
+    /// 上一次输出超限警告的时间 (限频)
+    last_limit_warning: Option<Instant>,
+    /// 监听器正在关闭，不再保存新建好的会话
+    shutting_down: bool,
+    /// SNI 提取失败的 Initial 转储 (配置了 `debug_dump_dir` 时)
+    dumper: Option<InitialDumper>,
+    /// 处理结果计数
+    counters: Arc<OutcomeCounters>,
     /// 会话配置
     config: QuicSessionConfig,
     /// 路由器 (白名单检查)
     router: Router,
-    /// SOCKS5 配置
-    socks5_config: Socks5Config,
+    /// SOCKS5 上游 (与 TCP 共享健康状态)
+    upstreams: Arc<UpstreamSet>,
     /// 本地 UDP socket
     socket: Arc<UdpSocket>,
 }
 
-/// 会话管理器
-pub struct QuicSessionManager {
-    /// 共享的内部状态
-    inner: Arc<Mutex<SessionManagerInner>>,
-    /// 配置 (用于 cleanup task)
-    config: QuicSessionConfig,
+/// 准入时从 ClientHello 确定的会话属性
+#[derive(Clone)]
+struct AdmittedHello {
+    /// 用于路由的 SNI
+    sni: String,
+    /// 是否使用 ECH
+    ech: bool,
+    /// ALPN 列表
+    alpn: Vec<String>,
 }
 
-impl QuicSessionManager {
-    /// 创建新的会话管理器
-    pub fn new(
-        config: QuicSessionConfig,
-        router: Router,
-        socks5_config: Socks5Config,
-        socket: Arc<UdpSocket>,
-    ) -> Self {
-        debug!(
-            "Created QUIC session manager: idle_timeout={:?}, cleanup_interval={:?}",
-            config.idle_timeout, config.cleanup_interval
-        );
+/// 已移除会话的路由墓碑
+struct RecentRoute {
+    hello: AdmittedHello,
+    expires_at: Instant,
+}
 
-        let inner = SessionManagerInner {
-            sessions: HashMap::new(),
-            config: config.clone(),
-            router,
-            socks5_config,
-            socket,
-        };
+/// 新流 Initial 的准入结果
+enum Admission {
+    /// 允许，携带会话属性和此前暂存的同一 ClientHello 的 Initial
+    Allowed {
+        hello: AdmittedHello,
+        buffered: Vec<Vec<u8>>,
+    },
+    /// ClientHello 尚未收全，包已暂存
+    Incomplete,
+    /// 拒绝，携带原因和需要回复给客户端的 CONNECTION_CLOSE 的错误码 (如有)
+    Rejected(RejectReason, Option<u64>),
+}
 
-        Self {
-            inner: Arc::new(Mutex::new(inner)),
-            config,
+/// 被拒绝的流回复给客户端的 CONNECTION_CLOSE 错误码；没有 SNI 时静默丢弃
+fn close_code(rejection: Rejection) -> Option<u64> {
+    match rejection {
+        Rejection::NotWhitelisted => Some(close::CONNECTION_REFUSED),
+        Rejection::AlpnMismatch => Some(close::NO_APPLICATION_PROTOCOL),
+        Rejection::NoSni => None,
+    }
+}
+
+impl SessionManagerInner {
+    /// 保存会话并建立 DCID 索引
+    fn insert_session(&mut self, session: QuicSession) -> Arc<QuicSession> {
+        let client = session.client_addr();
+        let session = Arc::new(session);
+        self.dcid_index.insert(session.dcid.clone(), client);
+        self.sessions.insert(client, Arc::clone(&session));
+        self.count_ip(client.ip());
+        session
+    }
+
+    /// 移除会话及其 DCID 索引，并记下路由墓碑
+    fn remove_session(&mut self, client: SocketAddr) -> Option<Arc<QuicSession>> {
+        let (_, session) = self.sessions.remove(&client)?;
+        self.uncount_ip(client.ip());
+        for dcid in session.connection_ids() {
+            self.dcid_index
+                .map
+                .remove_if(&dcid, |_, indexed| *indexed == client);
         }
+        session.record_domain_traffic();
+        self.remember_route(client, &session);
+        Some(session)
     }
 
-    /// 处理 UDP 包
-    ///
-    /// 返回 Ok(true) 表示已转发，Ok(false) 表示未处理（非 QUIC 包）
-    pub async fn handle_packet(&self, packet: &[u8], src: SocketAddr) -> Result<bool> {
-        // 1) 优先按 client_addr 查找现有会话（用于转发后续 Short Header 包）
-        if self.has_session(src).await {
-            return self.forward_to_existing_session(src, packet).await;
+    /// 记录已移除会话的路由；墓碑数不超过会话数上限，满时先清过期的再淘汰最早过期的
+    fn remember_route(&mut self, client: SocketAddr, session: &QuicSession) {
+        let now = Instant::now();
+        if !self.recent_routes.contains_key(&client)
+            && self.recent_routes.len() >= self.config.max_sessions.max(1)
+        {
+            self.recent_routes.retain(|_, route| route.expires_at > now);
+            let oldest = self
+                .recent_routes
+                .iter()
+                .min_by_key(|(_, route)| route.expires_at)
+                .map(|(addr, _)| *addr);
+            if let (Some(addr), true) = (
+                oldest,
+                self.recent_routes.len() >= self.config.max_sessions.max(1),
+            ) {
+                self.recent_routes.remove(&addr);
+            }
         }
 
-        // 2) 无会话：只尝试从 QUIC Initial 提取 SNI 并建会话
-        self.create_and_forward_session(packet, src).await
+        self.recent_routes.insert(
+            client,
+            RecentRoute {
+                hello: AdmittedHello {
+                    sni: session.sni.clone(),
+                    ech: session.ech,
+                    alpn: session.alpn.clone(),
+                },
+                expires_at: now + self.config.route_tombstone_ttl,
+            },
+        );
     }
 
-    async fn has_session(&self, client: SocketAddr) -> bool {
-        let inner = self.inner.lock().await;
-        inner.sessions.contains_key(&client)
+    /// 带 Token 的 Initial 来自刚移除会话的客户端时，取出其原路由
+    fn take_recent_route(
+        &mut self,
+        src: SocketAddr,
+        header: &InitialHeader,
+    ) -> Option<AdmittedHello> {
+        if header.token_len == 0 {
+            return None;
+        }
+        let route = self.recent_routes.remove(&src)?;
+        if route.expires_at <= Instant::now() {
+            return None;
+        }
+        info!(
+            route_sni = %route.hello.sni,
+            "token-bearing Initial (likely after Retry), reusing previous route"
+        );
+        Some(route.hello)
     }
 
-    /// 转发到现有会话
-    async fn forward_to_existing_session(&self, client: SocketAddr, packet: &[u8]) -> Result<bool> {
-        let tx = {
-            let mut inner = self.inner.lock().await;
-            let Some(session) = inner.sessions.get_mut(&client) else {
-                return Ok(false);
-            };
-            session.last_active = Instant::now();
-            session.tx.clone()
-        };
+    /// `ip` 的会话 (或创建占位) 增加一个
+    fn count_ip(&mut self, ip: IpAddr) {
+        *self.per_ip.entry(ip).or_default() += 1;
+    }
 
-        tx.send(packet.to_vec())
-            .await
-            .map_err(|_| anyhow!("QUIC session task is gone (client={})", client))?;
+    /// `ip` 的会话 (或创建占位) 减少一个，减到 0 时删除条目
+    fn uncount_ip(&mut self, ip: IpAddr) {
+        if let std::collections::hash_map::Entry::Occupied(mut entry) = self.per_ip.entry(ip) {
+            *entry.get_mut() -= 1;
+            if *entry.get() == 0 {
+                entry.remove();
+            }
+        }
+    }
+
+    /// 检查会话数限制，必要时淘汰最久未活动的会话
+    ///
+    /// 返回 false 表示应拒绝为 `src` 创建新会话。
+    fn make_room_for(&mut self, src: SocketAddr) -> bool {
+        let ip = src.ip();
+        let per_ip = self.per_ip.get(&ip).copied().unwrap_or(0);
+        if per_ip >= self.config.max_sessions_per_ip {
+            self.reject_over_limit(format_args!(
+                "client IP {} already has {} QUIC sessions",
+                ip, per_ip
+            ));
+            return false;
+        }
 
-        Ok(true)
+        let total = self.sessions.len() + self.pending.len();
+        if total < self.config.max_sessions {
+            return true;
+        }
+
+        if self.config.evict_idle_on_full {
+            let idlest = self
+                .sessions
+                .iter()
+                .max_by_key(|entry| entry.value().idle_time())
+                .map(|entry| *entry.key());
+            if let Some(addr) = idlest {
+                if let Some(session) = self.remove_session(addr) {
+                    self.evicted_for_capacity += 1;
+                    debug!(
+                        evicted_client = %addr,
+                        evicted_sni = %session.sni,
+                        idle = ?session.idle_time(),
+                        outcome = "evicted",
+                        "session limit reached, evicted idlest session"
+                    );
+                    return true;
+                }
+            }
+        }
+
+        self.reject_over_limit(format_args!(
+            "QUIC session limit reached ({} sessions)",
+            total
+        ));
+        false
     }
 
-    /// 创建新会话并转发
-    async fn create_and_forward_session(&self, packet: &[u8], src: SocketAddr) -> Result<bool> {
-        // 仅处理 QUIC Initial。不是 Initial 直接忽略。
-        let header = match crate::quic::parse_initial_header(packet) {
-            Ok(h) => h,
-            Err(_) => {
-                trace!("Not a QUIC Initial packet from {}", src);
-                return Ok(false);
+    /// 根据新流 Initial 的 SNI 提取结果做白名单检查
+    ///
+    /// 被拒绝的流记入负缓存；白名单拒绝时附带 CONNECTION_CLOSE 的错误码。
+    fn admit_extraction(
+        &mut self,
+        extraction: std::result::Result<SniExtraction, QuicError>,
+        packet: &[u8],
+        src: SocketAddr,
+        header: &InitialHeader,
+    ) -> std::result::Result<Admission, QuicError> {
+        if let Err(e) = &extraction {
+            self.counters.record_error(e);
+            if let Some(dumper) = self.dumper.as_mut() {
+                dumper.dump(packet, src, &e.to_string());
+            }
+        }
+        let (sni, ech, alpn) = match extraction? {
+            SniExtraction::Found { sni, ech, alpn } => {
+                if ech {
+                    debug!(outer_sni = %sni, "client uses ECH, routing by outer SNI");
+                }
+                (sni, ech, alpn)
+            }
+            SniExtraction::Incomplete { .. } => {
+                self.buffer_incomplete_hello(packet, src, &header.dcid);
+                return Ok(Admission::Incomplete);
+            }
+            SniExtraction::Missing { ech: true, alpn }
+                if self.config.ech_default_target.is_some() =>
+            {
+                let target = self.config.ech_default_target.clone().unwrap_or_default();
+                debug!(
+                    target = %target,
+                    "client uses ECH without outer SNI, forwarding to default target"
+                );
+                (target, true, alpn)
+            }
+            SniExtraction::Missing { ech, .. } => {
+                self.counters.record_error(&QuicError::NoSniFound);
+                self.awaiting_hello.remove(&src);
+                self.negative_cache
+                    .insert(src, &header.dcid, Rejection::NoSni);
+                debug!(ech, outcome = "no_sni", "no SNI in QUIC Initial");
+                return Ok(Admission::Rejected(RejectReason::NoSni, None));
             }
         };
-        let dcid = header.dcid.to_vec();
-
-        // 提取 SNI
-        let mut packet_copy = packet.to_vec();
-        let sni = match extract_sni_from_quic_initial(&mut packet_copy)? {
-            Some(s) => s,
+        // U-label 形式的 SNI 按 rules.unicode_sni 转换，拒绝时与其它不合法的主机名一样处理
+        let sni = match self.router.sni_hostname(&sni) {
+            Some(Cow::Borrowed(_)) => sni,
+            Some(Cow::Owned(hostname)) => hostname,
             None => {
-                debug!("No SNI found in QUIC Initial packet from {}", src);
-                return Ok(false);
+                let e = QuicError::TlsError(SniError::InvalidHostname(HostnameError::NonAscii));
+                self.counters.record_error(&e);
+                self.awaiting_hello.remove(&src);
+                return Err(e);
             }
         };
+        span::record_sni(&sni);
+
+        let buffered = self.take_incomplete_hello(src, &header.dcid);
 
         // 白名单检查
-        {
-            let inner = self.inner.lock().await;
-            if !inner.router.is_allowed(&sni) {
-                warn!(
-                    "Domain {} not in whitelist, rejecting QUIC session from {}",
-                    sni, src
-                );
-                return Ok(false);
-            }
+        if !self.router.is_sni_allowed(&sni) {
+            warn!(
+                outcome = "not_whitelisted",
+                "domain not in whitelist, session rejected"
+            );
+            self.negative_cache
+                .insert(src, &header.dcid, Rejection::NotWhitelisted);
+            return Ok(Admission::Rejected(
+                RejectReason::NotWhitelisted,
+                close_code(Rejection::NotWhitelisted),
+            ));
         }
 
-        let socks5_config = {
-            let inner = self.inner.lock().await;
-            inner.socks5_config.clone()
-        };
-        let target_addr = resolve_target_addr(&sni, 443, &socks5_config).await?;
+        if !self.alpn_allowed(&alpn) {
+            warn!(
+                alpn = ?alpn,
+                require_alpn = ?self.config.require_alpn,
+                outcome = "alpn_mismatch",
+                "ALPN not allowed, session rejected"
+            );
+            self.rejected_alpn += 1;
+            self.negative_cache
+                .insert(src, &header.dcid, Rejection::AlpnMismatch);
+            return Ok(Admission::Rejected(
+                RejectReason::AlpnMismatch,
+                close_code(Rejection::AlpnMismatch),
+            ));
+        }
 
-        // 创建 SOCKS5 UDP relay
-        let (socks5_relay, relay_addr, socket) = {
-            let inner = self.inner.lock().await;
-            let socket = Arc::clone(&inner.socket);
+        if ech {
+            self.ech_admissions += 1;
+        }
+        Ok(Admission::Allowed {
+            hello: AdmittedHello { sni, ech, alpn },
+            buffered,
+        })
+    }
 
-            let udp_client = if let (Some(username), Some(password)) =
-                (&inner.socks5_config.username, &inner.socks5_config.password)
-            {
-                Socks5UdpClient::new(inner.socks5_config.addr.to_string())
-                    .with_auth(username.clone(), password.clone())
-                    .with_timeout(Duration::from_secs(inner.socks5_config.timeout))
-            } else {
-                Socks5UdpClient::new(inner.socks5_config.addr.to_string())
-                    .with_timeout(Duration::from_secs(inner.socks5_config.timeout))
-            };
+    /// ALPN 是否满足 `require_alpn`
+    fn alpn_allowed(&self, alpn: &[String]) -> bool {
+        let required = &self.config.require_alpn;
+        if required.is_empty() {
+            return true;
+        }
+        if alpn.is_empty() {
+            return self.config.allow_missing_alpn;
+        }
+        alpn.iter().any(|protocol| required.contains(protocol))
+    }
 
-            let (relay, relay_addr) = udp_client.associate().await?;
-            (relay, relay_addr, socket)
-        };
+    /// 暂存 ClientHello 尚未收全的 Initial
+    ///
+    /// 同一客户端换了 DCID (新连接) 或已过期时重新开始；
+    /// 等待中的客户端数达到重组器上限时淘汰最早的。
+    fn buffer_incomplete_hello(&mut self, packet: &[u8], src: SocketAddr, dcid: &[u8]) {
+        let ttl = self.config.pending_ttl;
+        if self
+            .awaiting_hello
+            .get(&src)
+            .is_some_and(|flow| flow.dcid != dcid || flow.created_at.elapsed() > ttl)
+        {
+            self.awaiting_hello.remove(&src);
+        }
 
-        info!(
-            "QUIC route established: client={}, sni={}, target={}, socks5_relay={}, dcid={:?}",
-            src, sni, target_addr, relay_addr, dcid
+        if !self.awaiting_hello.contains_key(&src)
+            && self.awaiting_hello.len() >= self.config.crypto_reassembly_max_entries
+        {
+            let oldest = self
+                .awaiting_hello
+                .iter()
+                .min_by_key(|(_, flow)| flow.created_at)
+                .map(|(addr, _)| *addr);
+            if let Some(addr) = oldest {
+                self.awaiting_hello.remove(&addr);
+            }
+        }
+
+        let flow = self
+            .awaiting_hello
+            .entry(src)
+            .or_insert_with(|| PendingFlow::new(dcid.to_vec()));
+        flow.push(packet, src, &self.config);
+        debug!(
+            buffered = flow.packets.len(),
+            "ClientHello incomplete, waiting for more Initials"
         );
+    }
 
-        // 会话任务：负责双向 UDP 转发
-        let (tx, mut rx) = mpsc::channel::<Vec<u8>>(1024);
-        let dcid_for_task = dcid.to_vec();
-        tokio::spawn(async move {
-            let relay = socks5_relay;
-            let mut buf = vec![0u8; 2048];
+    /// 同一 ClientHello 暂存的第一个 Initial 到达的时刻
+    fn first_initial_at(&self, src: SocketAddr, dcid: &[u8]) -> Option<Instant> {
+        self.awaiting_hello
+            .get(&src)
+            .filter(|flow| flow.dcid == dcid)
+            .map(|flow| flow.created_at)
+    }
 
-            loop {
-                tokio::select! {
-                    maybe_pkt = rx.recv() => {
-                        let Some(pkt) = maybe_pkt else {
-                            // sender dropped => session removed
-                            debug!("QUIC session task exiting (dcid={:?})", dcid_for_task);
-                            return;
-                        };
-
-                        // 注意：Socks5Datagram::send_to 的目标应该是“真实远端地址”，不是 SOCKS5 relay_addr
-                        if let Err(e) = relay.send_to(&pkt, target_addr).await {
-                            warn!("QUIC session send_to failed (dcid={:?}, target={}): {}", dcid_for_task, target_addr, e);
-                            return;
-                        }
-                    }
-                    recv_res = relay.recv_from(&mut buf) => {
-                        match recv_res {
-                            Ok((n, _remote)) => {
-                                if n == 0 {
-                                    continue;
-                                }
-                                // 返回客户端：从同一个本地 UDP socket 发回，保持五元组一致
-                                if let Err(e) = socket.send_to(&buf[..n], src).await {
-                                    warn!("QUIC session failed to send back to client (dcid={:?}, client={}): {}", dcid_for_task, src, e);
-                                    return;
-                                }
-                            }
-                            Err(e) => {
-                                warn!("QUIC session recv_from failed (dcid={:?}): {}", dcid_for_task, e);
-                                return;
-                            }
-                        }
-                    }
-                }
+    /// 取出同一 ClientHello 此前暂存的 Initial (DCID 不符或已过期时丢弃)
+    fn take_incomplete_hello(&mut self, src: SocketAddr, dcid: &[u8]) -> Vec<Vec<u8>> {
+        match self.awaiting_hello.remove(&src) {
+            Some(flow)
+                if flow.dcid == dcid && flow.created_at.elapsed() <= self.config.pending_ttl =>
+            {
+                flow.packets
             }
-        });
-
-        // 创建会话
-        let session = QuicSession {
-            dcid: dcid.to_vec(),
-            sni,
-            target_addr,
-            client_addr: src,
-            tx,
-            last_active: Instant::now(),
-            created_at: Instant::now(),
-        };
-
-        // 保存会话
-        {
-            let mut inner = self.inner.lock().await;
-            inner.sessions.insert(src, session);
+            _ => Vec::new(),
         }
+    }
 
-        // 转发第一个包（通过会话 task）
-        self.forward_to_existing_session(src, packet).await?;
-
-        Ok(true)
+    /// 各 SOCKS5 上游承载的活动会话数
+    fn upstream_sessions(&self) -> BTreeMap<String, usize> {
+        let mut counts: BTreeMap<String, usize> = self
+            .upstreams
+            .iter()
+            .map(|upstream| (upstream.name().to_string(), 0))
+            .collect();
+        for entry in self.sessions.iter() {
+            *counts.entry(entry.value().upstream.clone()).or_default() += 1;
+        }
+        counts
     }
 
-    /// 清理过期会话
-    pub async fn cleanup_expired_sessions(&self) -> usize {
-        let mut inner = self.inner.lock().await;
+    /// 记录一次超限拒绝；警告最多每 10 秒输出一次
+    fn reject_over_limit(&mut self, reason: std::fmt::Arguments<'_>) {
+        self.rejected_over_limit += 1;
         let now = Instant::now();
-        let initial_count = inner.sessions.len();
-        let idle_timeout = inner.config.idle_timeout;
+        let should_warn = self
+            .last_limit_warning
+            .is_none_or(|last| now.duration_since(last) >= Duration::from_secs(10));
+        if should_warn {
+            self.last_limit_warning = Some(now);
+            warn!(
+                reason = %reason,
+                outcome = "over_limit",
+                rejected_so_far = self.rejected_over_limit,
+                "new session rejected"
+            );
+        } else {
+            debug!(reason = %reason, outcome = "over_limit", "new session rejected");
+        }
+    }
+}
 
-        inner
-            .sessions
-            .retain(|_, session| now.duration_since(session.last_active) < idle_timeout);
+/// 会话管理器统计信息
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QuicSessionStats {
+    /// 活动会话数
+    pub active_sessions: usize,
+    /// 正在建立的会话数
+    pub pending_sessions: usize,
+    /// ClientHello 尚未收全、等待后续 Initial 的客户端数
+    pub incomplete_client_hellos: usize,
+    /// 执行 SNI 提取 (含 Initial 密钥派生和解密) 的次数
+    pub sni_extractions: u64,
+    /// 准入的使用 ECH 的新流数
+    pub ech_sessions: u64,
+    /// 因 ALPN 与 `require_alpn` 不符被拒绝的新流数
+    pub rejected_alpn: u64,
+    /// 因会话数超限被拒绝的次数
+    pub rejected_over_limit: u64,
+    /// 因会话数达到上限被淘汰的会话数
+    pub evicted_for_capacity: u64,
+    /// 本地解析缓存命中次数
+    pub dns_cache_hits: u64,
+    /// 本地解析缓存未命中 (实际解析) 次数
+    pub dns_cache_misses: u64,
+    /// relay 回包来源与会话目标不符而被丢弃的包数
+    pub spoofed_relay_packets: u64,
+    /// 控制连接仍然打开、但被无响应检测判定失效的 relay 数
+    pub relays_declared_dead: u64,
+    /// 丢弃的 SOCKS5 UDP 分片数 (见 [`FragmentPolicy`])
+    pub relay_fragments_dropped: u64,
+    /// 重组完成的 SOCKS5 UDP 数据报数
+    pub relay_datagrams_reassembled: u64,
+    /// 因会话队列已满 (relay 跟不上) 而丢弃的客户端包数
+    pub queue_full_drops: u64,
+    /// 新流 Initial 的处理失败数，按 [`QuicError::metric_label`] 分类 (含全部分类)
+    pub initial_errors: BTreeMap<&'static str, u64>,
+    /// 建立的会话数
+    pub sessions_created: u64,
+    /// 放入会话队列、等待发往 relay 的客户端包数
+    pub packets_forwarded: u64,
+    /// 因来源是 SOCKS5 relay 或本监听地址 (反射/回环) 而被丢弃的包数
+    pub reflected_packets: u64,
+    /// 因目标是本监听地址 (会形成转发环路) 而拒绝建立的会话数
+    pub self_target_rejections: u64,
+    /// 因来源 IP 限速被丢弃的新 Initial 数
+    pub rate_limited_initials: u64,
+    /// 失败后重试的 UDP ASSOCIATE 次数
+    pub associate_retries: u64,
+    /// ASSOCIATE 断路器打开的次数
+    pub breaker_opened: u64,
+    /// ASSOCIATE 断路器 (探测成功后) 关闭的次数
+    pub breaker_closed: u64,
+    /// 因断路器打开而直接失败的 ASSOCIATE 次数
+    pub breaker_rejections: u64,
+    /// 当前断路器打开的上游名称
+    pub open_breakers: Vec<String>,
+    /// UDP ASSOCIATE 失败数，按 [`crate::socks5::Socks5Error::metric_label`] 分类 (含全部分类)
+    pub associate_errors: BTreeMap<&'static str, u64>,
+    /// 各 SOCKS5 上游承载的活动会话数 (含没有会话的上游)
+    pub upstream_sessions: BTreeMap<String, usize>,
+    /// 共享 relay 及各自承载的会话数 (未开启共享时为空)
+    pub shared_relays: Vec<SharedRelayStats>,
+    /// 收包循环按处理结果分类的包数和字节数
+    pub packets: PacketStats,
+}
 
-        let removed = initial_count - inner.sessions.len();
-        if removed > 0 {
-            debug!("Cleaned up {} expired QUIC sessions", removed);
-        }
+/// 处理结果计数，读取时不需要管理器的互斥锁
+#[derive(Debug, Default)]
+struct OutcomeCounters {
+    /// 按 [`QuicError::METRIC_LABELS`] 下标计数
+    initial_errors: [AtomicU64; QuicError::METRIC_LABELS.len()],
+    sessions_created: AtomicU64,
+    packets_forwarded: AtomicU64,
+    reflected_packets: AtomicU64,
+    self_target_rejections: AtomicU64,
+    associate_retries: AtomicU64,
+    breaker_opened: AtomicU64,
+    breaker_closed: AtomicU64,
+    breaker_rejections: AtomicU64,
+    associate_errors: Socks5ErrorCounters,
+}
 
-        removed
+impl OutcomeCounters {
+    fn record_error(&self, error: &QuicError) {
+        let label = error.metric_label();
+        if let Some(i) = QuicError::METRIC_LABELS.iter().position(|l| *l == label) {
+            self.initial_errors[i].fetch_add(1, Ordering::Relaxed);
+        }
     }
 
-    /// 获取会话数量
-    #[allow(dead_code)]
-    pub async fn session_count(&self) -> usize {
-        let inner = self.inner.lock().await;
-        inner.sessions.len()
+    fn initial_errors(&self) -> BTreeMap<&'static str, u64> {
+        QuicError::METRIC_LABELS
+            .iter()
+            .zip(&self.initial_errors)
+            .map(|(label, count)| (*label, count.load(Ordering::Relaxed)))
+            .collect()
     }
+}
 
-    /// 启动会话清理任务
-    pub fn spawn_cleanup_task(&self) -> tokio::task::JoinHandle<()> {
-        let manager = self.clone();
-        tokio::spawn(async move {
-            let mut interval = tokio::time::interval(manager.config.cleanup_interval);
-            loop {
-                interval.tick().await;
-                manager.cleanup_expired_sessions().await;
-            }
-        })
-    }
+/// 会话建立期间暂存的客户端包
+///
+/// 同时作为"正在创建"的占位：同一 client_addr 或 DCID 只允许一个创建任务。
+struct PendingFlow {
+    /// 首个 Initial 的 DCID
+    dcid: Vec<u8>,
+    /// 按到达顺序暂存的包
+    packets: Vec<Vec<u8>>,
+    /// 已暂存字节数
+    bytes: usize,
+    /// 开始建立会话的时间
+    created_at: Instant,
 }
 
-async fn resolve_target_addr(
-    host: &str,
-    port: u16,
-    socks5_config: &Socks5Config,
-) -> Result<SocketAddr> {
-    if std::env::var("SNIPROXY_DNS_DIRECT").as_deref() == Ok("1") {
-        return tokio::net::lookup_host((host, port))
-            .await
-            .map_err(|e| anyhow!("Failed to resolve {}:{}: {}", host, port, e))?
-            .next()
-            .ok_or_else(|| anyhow!("No A/AAAA record for {}:{}", host, port));
+impl PendingFlow {
+    fn new(dcid: Vec<u8>) -> Self {
+        Self {
+            dcid,
+            packets: Vec::new(),
+            bytes: 0,
+            created_at: Instant::now(),
+        }
     }
 
-    resolve_with_socks5_udp_dns(host, port, socks5_config).await
+    /// 暂存一个包；超出数量/字节上限或已过期时丢弃该包
+    fn push(&mut self, packet: &[u8], src: SocketAddr, config: &QuicSessionConfig) {
+        if self.created_at.elapsed() > config.pending_ttl
+            || self.packets.len() >= config.pending_max_packets
+            || self.bytes + packet.len() > config.pending_max_bytes
+        {
+            debug!(
+                client = %src,
+                buffered = self.packets.len(),
+                buffered_bytes = self.bytes,
+                "pending flow buffer full or expired, packet dropped"
+            );
+            return;
+        }
+
+        self.bytes += packet.len();
+        self.packets.push(packet.to_vec());
+        trace!(
+            client = %src,
+            buffered = self.packets.len(),
+            "packet buffered while session is being created"
+        );
+    }
 }
 
-pub async fn probe_socks5_udp_relay(socks5_config: &Socks5Config) -> Result<()> {
-    let dns_server = upstream_dns_server()?;
-    resolve_with_socks5_udp_dns("example.com", 443, socks5_config)
-        .await
-        .map(|_| ())
-        .map_err(|e| {
-            anyhow!(
-                "SOCKS5 UDP relay probe failed via DNS server {}: {}",
-                dns_server,
-                e
-            )
-        })
+/// 为新 Initial 抢占会话创建权的结果
+enum CreateClaim {
+    /// 由当前调用负责创建会话
+    Claimed,
+    /// 同一客户端或 DCID 的会话正在创建，包已暂存
+    Pending,
+    /// 会话已被其他调用创建
+    Exists,
+    /// 会话数超过限制
+    OverLimit,
 }
 
-async fn resolve_with_socks5_udp_dns(
-    host: &str,
-    port: u16,
-    socks5_config: &Socks5Config,
-) -> Result<SocketAddr> {
+/// 会话管理器
+pub struct QuicSessionManager {
+    /// 共享的内部状态
+    inner: Arc<Mutex<SessionManagerInner>>,
+    /// 会话表 (转发路径无锁读取)
+    sessions: Arc<SessionMap>,
+    /// DCID 索引 (NAT 重绑定时无锁查找)
+    dcid_index: Arc<DcidIndex>,
+    /// 新流 Initial 的 SNI 提取状态，按 DCID 分片加锁，提取时不持有管理器的互斥锁
+    extractor: Arc<SniExtractor>,
+    /// 本地解析的目标域名缓存 (建立会话时不持有互斥锁访问)
+    dns_cache: Arc<DnsCache>,
+    /// 活动会话使用的 SOCKS5 relay 地址 (引用计数)，监听端忽略来自这些地址的包
+    relay_addrs: Arc<RelayAddrs>,
+    /// relay 回包来源与会话目标不符而被丢弃的包数
+    spoofed_relay_packets: Arc<AtomicU64>,
+    /// 被无响应检测判定失效的 relay 数
+    relays_declared_dead: Arc<AtomicU64>,
+    /// SOCKS5 UDP 分片的处理方式及计数
+    fragments: FragmentPolicy,
+    /// 因会话队列已满而丢弃的客户端包数 (所有会话合计，含已移除的会话)
+    queue_full_drops: Arc<AtomicU64>,
+    /// 回包是否使用 GSO；发送被内核拒绝后关闭
+    gso: Arc<AtomicBool>,
+    /// 本监听端的地址；来自这些地址的包和指向这些地址的目标会形成转发环路
+    listen_addrs: Arc<Vec<SocketAddr>>,
+    /// 各上游的 UDP ASSOCIATE 断路器，并发的会话建立共享
+    breakers: Arc<HashMap<String, CircuitBreaker>>,
+    /// 各上游的共享 relay；None 表示每个会话独占一个 relay
+    shared_relays: Option<Arc<SharedRelayPool>>,
+    /// 收包循环记录的处理结果 (见 [`QuicSessionManager::record_packet`])
+    packet_counters: Arc<PacketCounters>,
+    /// 处理结果计数 (与 `SessionManagerInner::counters` 是同一份)
+    counters: Arc<OutcomeCounters>,
+    /// Prometheus 指标 (见 [`QuicSessionManager::with_metrics`])
+    metrics: Arc<Registry>,
+    /// 关闭信号，清理任务随之退出
+    stopping: Arc<watch::Sender<bool>>,
+    /// 配置 (用于 cleanup task)
+    config: QuicSessionConfig,
+}
+
+impl QuicSessionManager {
+    /// 创建新的会话管理器
+    pub fn new(
+        config: QuicSessionConfig,
+        router: Router,
+        upstreams: Arc<UpstreamSet>,
+        socket: Arc<UdpSocket>,
+    ) -> Self {
+        debug!(
+            idle_timeout = ?config.idle_timeout,
+            cleanup_interval = ?config.cleanup_interval,
+            "quic session manager created"
+        );
+
+        let dumper = config.debug_dump_dir.as_ref().and_then(|dir| {
+            InitialDumper::new(
+                dir,
+                config.debug_dump_per_minute,
+                config.debug_dump_max_bytes,
+            )
+            .inspect_err(|e| warn!(error = %format_args!("{:#}", e), "QUIC Initial dumps disabled"))
+            .ok()
+        });
+
+        let gso = config.enable_gso && udp::gso_supported(&socket);
+        if config.enable_gso && !gso {
+            warn!("UDP GSO is not supported, sending QUIC datagrams one at a time");
+        }
+
+        let listen_addrs = Arc::new(socket.local_addr().into_iter().collect());
+        let breakers = Arc::new(
+            upstreams
+                .iter()
+                .map(|upstream| {
+                    let breaker =
+                        CircuitBreaker::new(config.breaker_threshold, config.breaker_cooldown);
+                    (upstream.name().to_string(), breaker)
+                })
+                .collect(),
+        );
+
+        let relay_addrs = Arc::new(RelayAddrs::new());
+        let spoofed_relay_packets = Arc::new(AtomicU64::new(0));
+        let relays_declared_dead = Arc::new(AtomicU64::new(0));
+        let fragments = FragmentPolicy {
+            reassemble: config.udp_reassembly,
+            ..FragmentPolicy::default()
+        };
+        let shared_relays = (config.shared_relays > 0).then(|| {
+            Arc::new(SharedRelayPool::new(
+                config.shared_relays,
+                config.session_queue_capacity,
+                config.max_datagram_size + SOCKS5_UDP_HEADER_MAX,
+                config.relay_probe_interval,
+                upstreams.iter().map(|upstream| upstream.name()),
+                Arc::clone(&relay_addrs),
+                RelayCounters {
+                    unmatched: Arc::clone(&spoofed_relay_packets),
+                    declared_dead: Arc::clone(&relays_declared_dead),
+                    fragments: fragments.clone(),
+                },
+            ))
+        });
+
+        let counters = Arc::new(OutcomeCounters::default());
+        let sessions = Arc::new(SessionMap::new());
+        let dcid_index = Arc::new(DcidIndex::default());
+        let inner = SessionManagerInner {
+            sessions: Arc::clone(&sessions),
+            dcid_index: Arc::clone(&dcid_index),
+            pending: HashMap::new(),
+            per_ip: HashMap::new(),
+            awaiting_hello: HashMap::new(),
+            recent_routes: HashMap::new(),
+            negative_cache: NegativeCache::new(
+                config.negative_cache_ttl,
+                config.negative_cache_max_entries,
+            ),
+            initial_limiter: PerIpRateLimiter::new(config.initial_rate, config.initial_burst),
+            rate_limited_initials: 0,
+            sni_extractions: 0,
+            ech_admissions: 0,
+            rejected_alpn: 0,
+            rejected_over_limit: 0,
+            evicted_for_capacity: 0,
+            last_limit_warning: None,
+            shutting_down: false,
+            dumper,
+            counters: Arc::clone(&counters),
+            config: config.clone(),
+            router,
+            upstreams,
+            socket,
+        };
+
+        Self {
+            inner: Arc::new(Mutex::new(inner)),
+            sessions,
+            dcid_index,
+            extractor: Arc::new(SniExtractor::new(
+                config.crypto_reassembly_ttl,
+                config.crypto_reassembly_max_entries,
+                config.initial_key_cache_size,
+            )),
+            dns_cache: Arc::new(DnsCache::new(config.dns_cache_ttl, config.dns_cache_size)),
+            relay_addrs,
+            spoofed_relay_packets,
+            relays_declared_dead,
+            fragments,
+            queue_full_drops: Arc::new(AtomicU64::new(0)),
+            gso: Arc::new(AtomicBool::new(gso)),
+            listen_addrs,
+            breakers,
+            shared_relays,
+            packet_counters: Arc::new(PacketCounters::default()),
+            counters,
+            metrics: Arc::default(),
+            stopping: Arc::new(watch::channel(false).0),
+            config,
+        }
+    }
+
+    /// 把新会话、SNI 提取、白名单拒绝和转发字节数记入 `metrics`
+    pub fn with_metrics(mut self, metrics: Arc<Registry>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// 在注册表中计入一个运行中的任务 (见 [`Registry::track_task`])
+    pub fn track_task(&self, kind: TaskKind) -> TaskGuard {
+        self.metrics.track_task(kind)
+    }
+
+    /// 处理 UDP 包，返回处理结果 (见 [`PacketOutcome`])
+    ///
+    /// 返回错误表示新流的 Initial 无法处理 (例如解密失败)。
+    pub async fn handle_packet(
+        &self,
+        packet: &[u8],
+        src: SocketAddr,
+    ) -> std::result::Result<PacketOutcome, QuicError> {
+        // 1) 优先按 client_addr 查找现有会话（用于转发后续 Short Header 包）
+        //    会话任务已退出时会话被移除，该包按新连接继续处理
+        if self.forward_to_existing_session(src, packet).await {
+            return Ok(PacketOutcome::ForwardedExisting);
+        }
+
+        // relay 或监听端自身不应向监听端发包 (relay 与客户端在同一主机时的回环、
+        // 配置错误或反射攻击)；否则可能用 server 方向密钥解出 SNI 并建立指向自身的会话
+        if self.relay_addrs.contains_key(&src) || self.is_listen_addr(src) {
+            self.record_reflected(src);
+            return Ok(PacketOutcome::Rejected {
+                reason: RejectReason::Reflected,
+            });
+        }
+
+        // 2) 未知地址：按 DCID 查找已有会话（客户端 NAT 重绑定）
+        if self.rebind_by_dcid(packet, src).await
+            && self.forward_to_existing_session(src, packet).await
+        {
+            return Ok(PacketOutcome::ForwardedExisting);
+        }
+
+        // 3) 会话正在建立：暂存，建好后按序转发
+        if self.buffer_pending(src, packet).await {
+            return Ok(PacketOutcome::Buffered);
+        }
+
+        // 4) 无会话：只尝试从 QUIC Initial 提取 SNI 并建会话
+        self.create_and_forward_session(packet, src).await
+    }
+
+    /// `addr` 是否是本监听端的地址
+    fn is_listen_addr(&self, addr: SocketAddr) -> bool {
+        self.listen_addrs
+            .iter()
+            .any(|listen| is_same_endpoint(*listen, addr))
+    }
+
+    /// 记录一个来自 relay 或监听端自身的包；只在第一次输出警告
+    fn record_reflected(&self, src: SocketAddr) {
+        if self
+            .counters
+            .reflected_packets
+            .fetch_add(1, Ordering::Relaxed)
+            == 0
+        {
+            warn!(
+                client = %src,
+                outcome = "reflected",
+                "packet from a SOCKS5 relay or this listener itself dropped (forwarding loop?)"
+            );
+        } else {
+            trace!(client = %src, outcome = "reflected", "reflected packet dropped");
+        }
+    }
+
+    /// 按 DCID 将已有会话迁移到新的客户端地址
+    ///
+    /// Long Header 直接读取 DCID 查索引；Short Header 不携带 DCID 长度，
+    /// 按索引中出现过的 DCID 长度逐一查表。查找不持有管理器的锁，只有找到会话时才加锁迁移。
+    async fn rebind_by_dcid(&self, packet: &[u8], src: SocketAddr) -> bool {
+        let old_addr = match long_header_dcid(packet) {
+            Some(dcid) => self.dcid_index.get(dcid),
+            None if is_short_header(packet) => self.dcid_index.find_short(packet),
+            None => None,
+        };
+        let Some(old_addr) = old_addr else {
+            return false;
+        };
+
+        let mut inner = self.inner.lock().await;
+        let Some((_, session)) = inner.sessions.remove(&old_addr) else {
+            return false;
+        };
+        inner.uncount_ip(old_addr.ip());
+
+        info!(
+            listener = "quic",
+            dcid = %span::Hex(&session.dcid),
+            sni = %session.sni,
+            previous_client = %old_addr,
+            client = %src,
+            "session rebound"
+        );
+
+        session.client_tx.send_replace(src);
+        // 原 DCID 和 Retry 后的新 DCID 都指向新地址
+        for dcid in session.connection_ids() {
+            if let Some(mut indexed) = inner.dcid_index.map.get_mut(&dcid) {
+                if *indexed == old_addr {
+                    *indexed = src;
+                }
+            }
+        }
+        inner.sessions.insert(src, session);
+        inner.count_ip(src.ip());
+        true
+    }
+
+    /// 源站对 `client` 的连接发出了 Retry：登记客户端随后使用的新 DCID
+    ///
+    /// 之后客户端地址变化时，携带新 DCID 的 Initial 仍能找回该会话。
+    async fn record_retry(&self, client: SocketAddr, new_dcid: &[u8]) {
+        let inner = self.inner.lock().await;
+        let Some(session) = inner
+            .sessions
+            .get(&client)
+            .map(|entry| Arc::clone(entry.value()))
+        else {
+            return;
+        };
+        info!(
+            listener = "quic",
+            client = %client,
+            new_dcid = %span::Hex(new_dcid),
+            "Retry from origin, tracking the new connection ID"
+        );
+        inner.dcid_index.insert(new_dcid.to_vec(), client);
+        let mut retry_dcids = session
+            .retry_dcids
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if !retry_dcids.iter().any(|dcid| dcid == new_dcid) {
+            retry_dcids.push(new_dcid.to_vec());
+        }
+    }
+
+    fn has_session(&self, client: SocketAddr) -> bool {
+        self.sessions.contains_key(&client)
+    }
+
+    /// 转发到现有会话
+    ///
+    /// 不获取管理器的互斥锁：一次会话表查找加一次原子写。
+    /// 会话队列已满时丢弃该包而不等待，一个慢 relay 不会阻塞收包循环和其它会话。
+    /// 返回 false 表示没有会话，或会话任务已退出 (此时会话已被移除)。
+    async fn forward_to_existing_session(&self, client: SocketAddr, packet: &[u8]) -> bool {
+        let Some(session) = self
+            .sessions
+            .get(&client)
+            .map(|entry| Arc::clone(entry.value()))
+        else {
+            return false;
+        };
+        session.touch();
+
+        match session.tx.try_send(packet.to_vec()) {
+            Ok(()) => {
+                self.counters
+                    .packets_forwarded
+                    .fetch_add(1, Ordering::Relaxed);
+                if session.traffic.record_queued() {
+                    info!(
+                        listener = "quic",
+                        client = %client,
+                        sni = %session.sni,
+                        dropped = session.traffic.packets_dropped.load(Ordering::Relaxed),
+                        "session queue drained, forwarding again"
+                    );
+                }
+                return true;
+            }
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                self.queue_full_drops.fetch_add(1, Ordering::Relaxed);
+                if session.traffic.record_drop() {
+                    warn!(
+                        listener = "quic",
+                        client = %client,
+                        sni = %session.sni,
+                        capacity = session.tx.max_capacity(),
+                        outcome = "queue_full",
+                        "session queue full, dropping packets"
+                    );
+                }
+                return true;
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {}
+        }
+
+        // relay 任务已退出：立即移除会话，不必等到空闲超时
+        let mut inner = self.inner.lock().await;
+        let same_session = inner
+            .sessions
+            .get(&client)
+            .is_some_and(|entry| Arc::ptr_eq(entry.value(), &session));
+        if same_session {
+            if let Some(session) = inner.remove_session(client) {
+                info!(
+                    listener = "quic",
+                    client = %client,
+                    sni = %session.sni,
+                    "session task is gone, session removed"
+                );
+            }
+        }
+        false
+    }
+
+    /// 会话建立期间暂存同一客户端的后续包
+    ///
+    /// 返回 true 表示该客户端正在建立会话（包已暂存或因超限被丢弃）。
+    async fn buffer_pending(&self, src: SocketAddr, packet: &[u8]) -> bool {
+        let mut inner = self.inner.lock().await;
+        let inner = &mut *inner;
+        let Some(flow) = inner.pending.get_mut(&src) else {
+            return false;
+        };
+        flow.push(packet, src, &inner.config);
+        true
+    }
+
+    /// 等待 ClientHello 后续分片的客户端发来非 Initial 包时暂存
+    ///
+    /// 返回 true 表示该客户端正在等待 ClientHello (包已暂存或因超限被丢弃)。
+    async fn buffer_awaiting_hello(&self, src: SocketAddr, packet: &[u8]) -> bool {
+        let mut inner = self.inner.lock().await;
+        let inner = &mut *inner;
+        let Some(flow) = inner.awaiting_hello.get_mut(&src) else {
+            return false;
+        };
+        flow.push(packet, src, &inner.config);
+        true
+    }
+
+    /// 在锁内原子地检查已有会话/正在创建的会话，必要时插入创建占位
+    ///
+    /// 同一客户端地址或同一 DCID 的并发 Initial 只会有一个调用得到 `Claimed`，
+    /// 其余的包暂存到已有占位中或直接交给已建好的会话。
+    async fn claim_creation(
+        &self,
+        packets: &[Vec<u8>],
+        src: SocketAddr,
+        dcid: &[u8],
+    ) -> CreateClaim {
+        let mut inner = self.inner.lock().await;
+        let inner = &mut *inner;
+
+        if inner.sessions.contains_key(&src) || inner.dcid_index.contains_key(dcid) {
+            return CreateClaim::Exists;
+        }
+
+        let existing = if inner.pending.contains_key(&src) {
+            Some(src)
+        } else {
+            inner
+                .pending
+                .iter()
+                .find(|(_, flow)| flow.dcid == dcid)
+                .map(|(addr, _)| *addr)
+        };
+
+        if let Some(addr) = existing {
+            if let Some(flow) = inner.pending.get_mut(&addr) {
+                for packet in packets {
+                    flow.push(packet, src, &inner.config);
+                }
+            }
+            return CreateClaim::Pending;
+        }
+
+        if !inner.make_room_for(src) {
+            return CreateClaim::OverLimit;
+        }
+
+        inner.pending.insert(src, PendingFlow::new(dcid.to_vec()));
+        inner.count_ip(src.ip());
+        CreateClaim::Claimed
+    }
+
+    /// 创建新会话并转发
+    ///
+    /// SNI 提取和白名单检查在当前调用中完成；DNS 解析和 SOCKS5 ASSOCIATE
+    /// 在后台任务中进行，期间同一客户端的后续包会被暂存。
+    async fn create_and_forward_session(
+        &self,
+        packet: &[u8],
+        src: SocketAddr,
+    ) -> std::result::Result<PacketOutcome, QuicError> {
+        // 仅处理 QUIC Initial。不是 Initial 直接忽略。
+        let header = match crate::quic::parse_initial_header(packet) {
+            Ok(h) => h,
+            Err(e @ QuicError::UnsupportedVersion { version }) => {
+                self.counters.record_error(&e);
+                self.send_version_negotiation(packet, src, version).await;
+                return Ok(PacketOutcome::UnsupportedVersion);
+            }
+            Err(e) => {
+                // 等待 ClientHello 期间客户端发出的其它 Long Header 包 (例如 0-RTT) 一并暂存
+                if self.buffer_awaiting_hello(src, packet).await {
+                    return Ok(PacketOutcome::Buffered);
+                }
+                self.counters.record_error(&e);
+                trace!(listener = "quic", client = %src, outcome = "not_quic", "not a QUIC Initial");
+                return Ok(PacketOutcome::IgnoredNotQuic);
+            }
+        };
+
+        let span = span::session_span(&header.dcid, src);
+        self.admit_and_create(packet, src, header)
+            .instrument(span)
+            .await
+    }
+
+    /// 对新流的 Initial 做准入检查，通过后在后台建立会话
+    ///
+    /// 在该流的 `quic_session` span 内运行，会话任务也继承该 span。
+    async fn admit_and_create(
+        &self,
+        packet: &[u8],
+        src: SocketAddr,
+        header: InitialHeader,
+    ) -> std::result::Result<PacketOutcome, QuicError> {
+        let received = Instant::now();
+        let dcid = header.dcid.to_vec();
+        // (首个 Initial 到达, 提取到 SNI) 的时刻；沿用原路由时没有提取
+        let mut milestones = None;
+
+        // 锁内只做廉价的检查；密钥派生、解密和 ClientHello 解析在锁外进行，
+        // 多个 UDP worker 的新流 Initial 不在管理器的锁上排队
+        let (admission, first_initial, socket) = {
+            let mut inner = self.inner.lock().await;
+            let inner = &mut *inner;
+
+            // 同一来源的新 Initial 过多：在任何密码学运算之前丢弃
+            if !inner.initial_limiter.allow(src.ip()) {
+                inner.rate_limited_initials += 1;
+                trace!(outcome = "rate_limited", "Initial rate limited");
+                return Ok(PacketOutcome::Rejected {
+                    reason: RejectReason::RateLimited,
+                });
+            }
+
+            // 带 Token 的 Initial 来自会话刚被移除的客户端：沿用原路由 (见 `recent_routes`)；
+            // 已被拒绝过的流：不再提取 SNI；白名单拒绝的重传仍回复 CONNECTION_CLOSE，
+            // 以防前一个回复丢失
+            let admission = if let Some(hello) = inner.take_recent_route(src, &header) {
+                Some(Admission::Allowed {
+                    hello,
+                    buffered: Vec::new(),
+                })
+            } else if let Some(reason) = inner.negative_cache.get(src, &dcid) {
+                debug!(reason = %reason, "repeated Initial of a rejected flow dropped");
+                Some(Admission::Rejected(reason.into(), close_code(reason)))
+            } else {
+                inner.sni_extractions += 1;
+                None
+            };
+            let first_initial = inner.first_initial_at(src, &dcid).unwrap_or(received);
+            (admission, first_initial, Arc::clone(&inner.socket))
+        };
+
+        let admission = match admission {
+            Some(admission) => admission,
+            None => {
+                let roles = if self.config.try_server_role {
+                    BOTH_ROLES
+                } else {
+                    CLIENT_ROLE
+                };
+                let extraction = self.extractor.extract(&dcid, &mut packet.to_vec(), roles);
+                let admission = self
+                    .inner
+                    .lock()
+                    .await
+                    .admit_extraction(extraction, packet, src, &header)?;
+                if matches!(admission, Admission::Allowed { .. }) {
+                    let sni_at = Instant::now();
+                    self.metrics
+                        .record_phase(Listener::Quic, Phase::Sni, sni_at - first_initial);
+                    milestones = Some((first_initial, sni_at));
+                }
+                admission
+            }
+        };
+
+        let (hello, mut packets) = match admission {
+            Admission::Allowed { hello, buffered } => (hello, buffered),
+            Admission::Incomplete => return Ok(PacketOutcome::Buffered),
+            Admission::Rejected(reason, close_code) => {
+                let close =
+                    close_code.and_then(|code| self.connection_close_for(packet, &header, code));
+                if let Some(response) = close {
+                    debug!("sending CONNECTION_CLOSE");
+                    if let Err(e) = socket.send_to(&response, src).await {
+                        warn!(error = %e, "failed to send CONNECTION_CLOSE");
+                    }
+                }
+                return Ok(PacketOutcome::Rejected { reason });
+            }
+        };
+        if !packets.is_empty() {
+            debug!(
+                buffered = packets.len(),
+                "ClientHello completed from buffered Initials"
+            );
+        }
+        packets.push(packet.to_vec());
+
+        // 标记该客户端正在建立会话；并发到达的同一客户端/DCID 的包不会重复创建
+        match self.claim_creation(&packets, src, &dcid).await {
+            CreateClaim::Claimed => {}
+            CreateClaim::Pending => return Ok(PacketOutcome::Buffered),
+            CreateClaim::OverLimit => {
+                return Ok(PacketOutcome::Rejected {
+                    reason: RejectReason::OverLimit,
+                })
+            }
+            CreateClaim::Exists => {
+                if !self.has_session(src) {
+                    self.rebind_by_dcid(packet, src).await;
+                }
+                let mut forwarded = false;
+                for pkt in &packets {
+                    forwarded |= self.forward_to_existing_session(src, pkt).await;
+                }
+                // 会话恰好在此期间被移除时该包没有去处
+                return Ok(if forwarded {
+                    PacketOutcome::ForwardedExisting
+                } else {
+                    PacketOutcome::IgnoredNotQuic
+                });
+            }
+        }
+
+        let sni = hello.sni.clone();
+        let manager = self.clone();
+        task::spawn_logged(
+            Listener::Quic,
+            &self.metrics,
+            tracing::Span::current(),
+            async move {
+                let result = manager
+                    .establish_session(hello, src, header, milestones)
+                    .await;
+                if let Err(e) = manager.finish_session(result, packets, src).await {
+                    let reason = match FailureReason::of(&e) {
+                        FailureReason::Internal => FailureReason::QuicSessionFailed,
+                        reason => reason,
+                    };
+                    manager.metrics.record_failure(Listener::Quic, reason);
+                    if logging::allow_warning("create_session", Some(src.ip())) {
+                        warn!(outcome = reason.as_label(), error = %e, "failed to create session");
+                    }
+                }
+            },
+        );
+
+        Ok(PacketOutcome::ForwardedNew { sni })
+    }
+
+    /// 为被拒绝的 Initial 构造 CONNECTION_CLOSE 回复
+    ///
+    /// 密钥按客户端原始 DCID 以 server 方向派生；构造失败只记录日志，退化为静默丢弃。
+    fn connection_close_for(
+        &self,
+        packet: &[u8],
+        header: &InitialHeader,
+        error_code: u64,
+    ) -> Option<Vec<u8>> {
+        let (_, _, scid) = negotiation::parse_invariant_long_header(packet)?;
+        self.build_close(header.version, &header.dcid, scid, error_code)
+    }
+
+    /// 用客户端原始 DCID 派生的 server 方向 Initial 密钥构造 CONNECTION_CLOSE
+    fn build_close(
+        &self,
+        version: u32,
+        dcid: &[u8],
+        scid: &[u8],
+        error_code: u64,
+    ) -> Option<Vec<u8>> {
+        let result = self
+            .extractor
+            .keys(dcid, version, InitialKeyRole::Server)
+            .and_then(|keys| close::build_connection_close(version, &keys, dcid, scid, error_code));
+        match result {
+            Ok(response) => Some(response),
+            Err(e) => {
+                debug!(error = %e, "failed to build CONNECTION_CLOSE");
+                None
+            }
+        }
+    }
+
+    /// 会话建立完成后：保存会话并按序转发首批包 (含此前暂存的 Initial) 和建立期间暂存的包；
+    /// 失败则丢弃暂存的包
+    async fn finish_session(
+        &self,
+        result: Result<QuicSession>,
+        packets: Vec<Vec<u8>>,
+        src: SocketAddr,
+    ) -> Result<()> {
+        let mut inner = self.inner.lock().await;
+        let pending = match inner.pending.remove(&src) {
+            Some(pending) => {
+                inner.uncount_ip(src.ip());
+                pending
+            }
+            // 监听器关闭时已清空
+            None => PendingFlow::new(Vec::new()),
+        };
+        let pending_ttl = inner.config.pending_ttl;
+
+        let session = match result {
+            Ok(_) if inner.shutting_down => {
+                return Err(anyhow!("QUIC listener is shutting down (client={})", src));
+            }
+            Ok(session) => session,
+            Err(e) => {
+                if !pending.packets.is_empty() {
+                    debug!(
+                        dropped = pending.packets.len(),
+                        "buffered packets dropped, session was not created"
+                    );
+                }
+                return Err(e);
+            }
+        };
+
+        // 首批包和暂存的包在持锁期间按序放入新会话的通道，保证顺序
+        let session = inner.insert_session(session);
+        self.counters
+            .sessions_created
+            .fetch_add(1, Ordering::Relaxed);
+
+        for pkt in packets {
+            self.enqueue_buffered(&session, src, pkt)?;
+        }
+
+        if pending.created_at.elapsed() > pending_ttl {
+            debug!(
+                dropped = pending.packets.len(),
+                "stale buffered packets discarded"
+            );
+            return Ok(());
+        }
+
+        let flushed = pending.packets.len();
+        for pkt in pending.packets {
+            self.enqueue_buffered(&session, src, pkt)?;
+        }
+        if flushed > 0 {
+            debug!(flushed, "buffered packets flushed");
+        }
+
+        Ok(())
+    }
+
+    /// 把会话建立前积累的包放入新会话的队列
+    ///
+    /// 积累的包多于队列容量时，多出的包与转发到现有会话时一样按队列满丢弃并计数；
+    /// 只有会话任务已退出才返回错误。
+    fn enqueue_buffered(
+        &self,
+        session: &QuicSession,
+        src: SocketAddr,
+        packet: Vec<u8>,
+    ) -> Result<()> {
+        match session.tx.try_send(packet) {
+            Ok(()) => {
+                self.counters
+                    .packets_forwarded
+                    .fetch_add(1, Ordering::Relaxed);
+            }
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                self.queue_full_drops.fetch_add(1, Ordering::Relaxed);
+                if session.traffic.record_drop() {
+                    warn!(
+                        listener = "quic",
+                        client = %src,
+                        sni = %session.sni,
+                        capacity = session.tx.max_capacity(),
+                        outcome = "queue_full",
+                        "session queue full, dropping buffered packets"
+                    );
+                }
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                return Err(anyhow!("QUIC session task is gone (client={})", src));
+            }
+        }
+        Ok(())
+    }
+
+    /// relay 就绪时记录 SNI → relay 就绪的耗时，返回记入日志的 (SNI 耗时, relay 耗时) 毫秒数
+    fn record_relay_ready(
+        &self,
+        milestones: Option<(Instant, Instant)>,
+    ) -> (Option<f64>, Option<f64>) {
+        let Some((first_initial, sni_at)) = milestones else {
+            return (None, None);
+        };
+        let connect = sni_at.elapsed();
+        self.metrics
+            .record_phase(Listener::Quic, Phase::Connect, connect);
+        (
+            Some(logging::millis(sni_at - first_initial)),
+            Some(logging::millis(connect)),
+        )
+    }
+
+    /// 解析目标地址、建立 SOCKS5 UDP relay 并启动会话任务
+    ///
+    /// `milestones` 为 (首个 Initial 到达, 提取到 SNI) 的时刻，用于记录 relay 就绪的耗时。
+    async fn establish_session(
+        &self,
+        hello: AdmittedHello,
+        src: SocketAddr,
+        header: InitialHeader,
+        milestones: Option<(Instant, Instant)>,
+    ) -> Result<QuicSession> {
+        let sni = hello.sni.as_str();
+        let (upstream, socket) = {
+            let inner = self.inner.lock().await;
+            let route = inner.router.route(sni);
+            (
+                inner.upstreams.select_udp(route, &header.dcid),
+                Arc::clone(&inner.socket),
+            )
+        };
+        if !upstream.supports_udp() {
+            return Err(anyhow!(
+                "Refusing QUIC session for SNI {}: upstream '{}' uses {:?}, which has no UDP support",
+                sni,
+                upstream.name(),
+                upstream.config().protocol
+            ));
+        }
+        let socks5_config = upstream.config();
+
+        let target_addr = match socks5_config.resolve {
+            // IP 字面量 SNI 仍按 IP 地址类型发送
+            ResolveMode::Remote => (sni, 443).to_target_addr()?,
+            ResolveMode::Local => {
+                let addrs = self
+                    .dns_cache
+                    .resolve(sni, || async {
+                        let addr = resolve_target_addr(sni, 443, socks5_config).await?;
+                        Ok::<_, anyhow::Error>(vec![addr.ip()])
+                    })
+                    .await?;
+                TargetAddr::Ip(SocketAddr::new(addrs[0], 443))
+            }
+        };
+        if let TargetAddr::Ip(addr) = &target_addr {
+            if self.is_listen_addr(*addr) {
+                self.counters
+                    .self_target_rejections
+                    .fetch_add(1, Ordering::Relaxed);
+                return Err(anyhow!(
+                    "Refusing QUIC session to {} (SNI {}): it is this listener and would loop",
+                    addr,
+                    sni
+                ));
+            }
+        }
+
+        // 创建 SOCKS5 UDP relay（不持锁，避免阻塞其他客户端的包）
+        // 重试和断路器由 associate_with_retry 负责
+        let udp_client = Socks5UdpClient::from_config(socks5_config)
+            .with_retry_policy(RetryPolicy::none())
+            .with_resolver(upstream.resolver())
+            .with_metrics(upstream.metrics(), upstream.name());
+        // 共享模式下 IP 目标优先使用共享的 relay
+        let shared = match (&self.shared_relays, &target_addr) {
+            (Some(pool), TargetAddr::Ip(addr)) => {
+                pool.attach(upstream.name(), *addr, &self.metrics, || {
+                    self.associate_for(&udp_client, &upstream)
+                })
+                .await?
+            }
+            _ => None,
+        };
+
+        // 会话任务：负责双向 UDP 转发
+        let (tx, rx) = mpsc::channel::<Vec<u8>>(self.config.session_queue_capacity.max(1));
+        let (client_tx, client_rx) = watch::channel(src);
+        let traffic = Arc::new(SessionTraffic {
+            metrics: Arc::clone(&self.metrics),
+            ..SessionTraffic::default()
+        });
+        let traffic_for_task = Arc::clone(&traffic);
+        // 会话任务存活期间计入上游的在途会话数 (socks5.balance = "least_connections")
+        let active = upstream.open_session();
+        let session_task = self.metrics.track_task(TaskKind::QuicSession);
+        let task = if let Some(attachment) = shared {
+            let (sni_ms, connect_ms) = self.record_relay_ready(milestones);
+            info!(
+                target = %target_addr,
+                upstream = upstream.name(),
+                relay = %attachment.relay().relay_addr(),
+                shared_relay = true,
+                alpn = ?hello.alpn,
+                sni_ms,
+                connect_ms,
+                outcome = "established",
+                "route established"
+            );
+            let task = self.clone().run_shared_session(
+                attachment,
+                target_addr.clone(),
+                rx,
+                client_rx,
+                socket,
+                traffic_for_task,
+            );
+            let task = async move {
+                let _active = active;
+                let _session_task = session_task;
+                task.await
+            };
+            tokio::spawn(task.instrument(tracing::Span::current()))
+        } else {
+            let mut rx = rx;
+            let (socks5_relay, relay_addr, control) =
+                self.associate_for(&udp_client, &upstream).await?;
+
+            let (sni_ms, connect_ms) = self.record_relay_ready(milestones);
+            info!(
+                target = %target_addr,
+                upstream = upstream.name(),
+                relay = %relay_addr,
+                shared_relay = false,
+                alpn = ?hello.alpn,
+                sni_ms,
+                connect_ms,
+                outcome = "established",
+                "route established"
+            );
+
+            let target_for_task = target_addr.clone();
+            let relay_addrs = Arc::clone(&self.relay_addrs);
+            let relay_failure = self.config.relay_failure;
+            let probe_interval = self.config.relay_probe_interval;
+            let fragment_policy = self.fragments.clone();
+            let spoofed = Arc::clone(&self.spoofed_relay_packets);
+            let gso = Arc::clone(&self.gso);
+            let relay_buf_size = self.config.max_datagram_size + SOCKS5_UDP_HEADER_MAX;
+            let upstream_for_task = Arc::clone(&upstream);
+            let manager = self.clone();
+            let task = async move {
+                let _active = active;
+                let _session_task = session_task;
+                let traffic = traffic_for_task;
+                let target_addr = target_for_task;
+                let mut pin = RemotePin::new(&target_addr);
+                let mut relay = socks5_relay;
+                let mut relay_addr = relay_addr;
+                let mut control = control;
+                let mut _relay_guard = RelayAddrGuard::register(&relay_addrs, relay_addr);
+                let mut buf = vec![0u8; relay_buf_size];
+                let mut truncation = TruncationWarnings::default();
+                let mut outgoing = GsoBatch::new(udp::GSO_MAX_SEGMENTS);
+                let mut watchdog = RelayWatchdog::new(probe_interval);
+                let mut fragments = RelayFragments::new(fragment_policy);
+
+                loop {
+                    tokio::select! {
+                        maybe_pkt = rx.recv() => {
+                            let Some(pkt) = maybe_pkt else {
+                                // sender dropped => session removed
+                                debug!("session task exiting");
+                                return;
+                            };
+
+                            // 注意：Socks5Datagram::send_to 的目标应该是“真实远端地址”，不是 SOCKS5 relay_addr
+                            if let Err(e) = send_to_target(&relay, &pkt, &target_addr).await {
+                                warn!(target = %target_addr, error = %e, "send to target failed, ending session");
+                                return;
+                            }
+                            traffic.record_up(pkt.len());
+                            watchdog.sent();
+                        }
+                        // 直接读取底层 socket 并自行解析 SOCKS5 UDP 头部，以便检测截断
+                        recv_res = relay.get_ref().recv(&mut buf) => {
+                            let mut recv_res = recv_res;
+                            let mut client = *client_rx.borrow();
+                            // 开启 GSO 时继续读取 relay 上已就绪的数据报，合并后一次发往客户端
+                            loop {
+                                let n = match recv_res {
+                                    Ok(n) => n,
+                                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                                    Err(e) => {
+                                        warn!(error = %e, "relay receive failed, ending session");
+                                        return;
+                                    }
+                                };
+                                watchdog.received();
+                                'datagram: {
+                                    if n >= buf.len() {
+                                        truncation.record("SOCKS5 relay datagram", relay_addr, buf.len());
+                                    }
+                                    let (frag, remote, payload) = match fast_socks5::parse_udp_request(&buf[..n]).await {
+                                        Ok(parsed) => parsed,
+                                        Err(e) => {
+                                            debug!(error = %e, "malformed SOCKS5 UDP datagram dropped");
+                                            break 'datagram;
+                                        }
+                                    };
+                                    if !pin.accepts(&remote) {
+                                        spoofed.fetch_add(1, Ordering::Relaxed);
+                                        debug!(remote = %remote, target = %target_addr, outcome = "spoofed", "relay packet from unexpected remote dropped");
+                                        break 'datagram;
+                                    }
+                                    let Some(payload) = fragments.accept(frag, &remote, payload, relay_addr) else {
+                                        break 'datagram;
+                                    };
+                                    let payload: &[u8] = &payload;
+                                    if payload.is_empty() {
+                                        break 'datagram;
+                                    }
+                                    // 返回客户端：从同一个本地 UDP socket 发回，保持五元组一致
+                                    let current = *client_rx.borrow();
+                                    if let Some(new_dcid) = retry_scid(payload) {
+                                        manager.record_retry(current, new_dcid).await;
+                                    }
+                                    if current != client || !outgoing.push(payload) {
+                                        if let Err(e) = outgoing.flush(&socket, client, &gso).await {
+                                            warn!(client = %client, error = %e, "send to client failed, ending session");
+                                            return;
+                                        }
+                                        client = current;
+                                        outgoing.push(payload);
+                                    }
+                                    traffic.record_down(payload.len());
+                                }
+                                if !gso.load(Ordering::Relaxed) || outgoing.len() >= udp::GSO_MAX_SEGMENTS {
+                                    break;
+                                }
+                                recv_res = relay.get_ref().try_recv(&mut buf);
+                            }
+                            if let Err(e) = outgoing.flush(&socket, client, &gso).await {
+                                warn!(client = %client, error = %e, "send to client failed, ending session");
+                                return;
+                            }
+                        }
+                        // SOCKS5 服务器关闭控制连接后 UDP 关联随之失效，发送也不会报错；
+                        // 控制连接仍然打开但 relay 不再回包时由 watchdog 发现
+                        lost = relay_lost(&mut control, watchdog.deadline()) => {
+                            if lost == RelayLoss::Unresponsive {
+                                manager.relays_declared_dead.fetch_add(1, Ordering::Relaxed);
+                            }
+                            if relay_failure == RelayFailureMode::Teardown {
+                                warn!(relay = %relay_addr, loss = %lost, "SOCKS5 UDP relay lost, ending session");
+                                return;
+                            }
+                            match manager.associate_with_retry(&udp_client, upstream_for_task.name()).await {
+                                Ok((new_relay, new_addr, new_control)) => {
+                                    upstream_for_task.record_success();
+                                    info!(relay = %relay_addr, loss = %lost, new_relay = %new_addr, "SOCKS5 UDP relay lost, re-associated");
+                                    relay = new_relay;
+                                    relay_addr = new_addr;
+                                    control = new_control;
+                                    watchdog.received();
+                                    // 登记新地址，旧登记在赋值时注销
+                                    _relay_guard = RelayAddrGuard::register(&relay_addrs, relay_addr);
+                                }
+                                Err(e) => {
+                                    upstream_for_task.record_failure();
+                                    warn!(relay = %relay_addr, loss = %lost, error = %e, "SOCKS5 UDP relay lost and re-association failed, ending session");
+                                    return;
+                                }
+                            }
+                        }
+                    }
+                }
+            };
+            tokio::spawn(task.instrument(tracing::Span::current()))
+        };
+        let idle_timeout = self.config.idle_timeout_for(&hello.sni);
+        if idle_timeout != self.config.idle_timeout {
+            debug!(idle_timeout = ?idle_timeout, "session uses an idle timeout override");
+        }
+
+        Ok(QuicSession {
+            dcid: header.dcid.to_vec(),
+            retry_dcids: std::sync::Mutex::new(Vec::new()),
+            scid: header.scid.to_vec(),
+            version: header.version,
+            sni: hello.sni,
+            ech: hello.ech,
+            alpn: hello.alpn,
+            target_addr,
+            upstream: upstream.name().to_string(),
+            tx,
+            client_tx,
+            last_active: AtomicU64::new(0),
+            created_at: Instant::now(),
+            idle_timeout,
+            traffic,
+            task: std::sync::Mutex::new(Some(task)),
+        })
+    }
+
+    /// 经 `upstream` 建立 UDP ASSOCIATE 并记录其被动健康状态
+    async fn associate_for(
+        &self,
+        udp_client: &Socks5UdpClient,
+        upstream: &Upstream,
+    ) -> Result<(Socks5UdpDatagram, SocketAddr, TcpStream)> {
+        let result = self.associate_with_retry(udp_client, upstream.name()).await;
+        match &result {
+            Ok(_) => upstream.record_success(),
+            Err(_) => upstream.record_failure(),
+        }
+        result
+    }
+
+    /// 共享 relay 上的会话任务
+    ///
+    /// 客户端的包经共享 relay 发往目标；回包由 relay 的读取任务按来源分发到
+    /// `attachment.inbound`，relay 失效时该队列关闭，会话随之结束。
+    async fn run_shared_session(
+        self,
+        mut attachment: RelayAttachment,
+        target_addr: TargetAddr,
+        mut rx: mpsc::Receiver<Vec<u8>>,
+        client_rx: watch::Receiver<SocketAddr>,
+        socket: Arc<UdpSocket>,
+        traffic: Arc<SessionTraffic>,
+    ) {
+        let mut outgoing = GsoBatch::new(udp::GSO_MAX_SEGMENTS);
+        loop {
+            tokio::select! {
+                maybe_pkt = rx.recv() => {
+                    let Some(pkt) = maybe_pkt else {
+                        debug!("session task exiting");
+                        return;
+                    };
+                    if let Err(e) = send_to_target(attachment.relay().datagram(), &pkt, &target_addr).await {
+                        warn!(target = %target_addr, error = %e, "send to target failed, ending session");
+                        return;
+                    }
+                    traffic.record_up(pkt.len());
+                    attachment.relay().record_sent();
+                }
+                maybe_payload = attachment.inbound.recv() => {
+                    let Some(mut payload) = maybe_payload else {
+                        debug!(
+                            relay = %attachment.relay().relay_addr(),
+                            target = %target_addr,
+                            "shared SOCKS5 UDP relay closed, ending session"
+                        );
+                        return;
+                    };
+                    let client = *client_rx.borrow();
+                    // 开启 GSO 时合并队列中已就绪的回包，一次发往客户端
+                    loop {
+                        if let Some(new_dcid) = retry_scid(&payload) {
+                            self.record_retry(client, new_dcid).await;
+                        }
+                        traffic.record_down(payload.len());
+                        if !outgoing.push(&payload) {
+                            if let Err(e) = outgoing.flush(&socket, client, &self.gso).await {
+                                warn!(client = %client, error = %e, "send to client failed, ending session");
+                                return;
+                            }
+                            outgoing.push(&payload);
+                        }
+                        if !self.gso.load(Ordering::Relaxed) || outgoing.len() >= udp::GSO_MAX_SEGMENTS {
+                            break;
+                        }
+                        match attachment.inbound.try_recv() {
+                            Ok(next) => payload = next,
+                            Err(_) => break,
+                        }
+                    }
+                    if let Err(e) = outgoing.flush(&socket, client, &self.gso).await {
+                        warn!(client = %client, error = %e, "send to client failed, ending session");
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    /// 带退避重试的 SOCKS5 UDP ASSOCIATE
+    ///
+    /// 最多尝试 `associate_attempts` 次，间隔从 `associate_backoff` 起逐次翻倍；
+    /// 等待会超过 `pending_ttl` (暂存的包届时已过期) 时不再重试。同一上游的并发
+    /// 尝试共享断路器，断路器打开时直接失败，不再发起注定失败的连接。
+    async fn associate_with_retry(
+        &self,
+        udp_client: &Socks5UdpClient,
+        upstream: &str,
+    ) -> Result<(Socks5UdpDatagram, SocketAddr, TcpStream)> {
+        let breaker = self.breakers.get(upstream);
+        let started = Instant::now();
+        let mut backoff = self.config.associate_backoff;
+        let mut attempt = 1;
+        loop {
+            if breaker.is_some_and(|breaker| !breaker.try_acquire()) {
+                self.counters
+                    .breaker_rejections
+                    .fetch_add(1, Ordering::Relaxed);
+                return Err(anyhow!(
+                    "SOCKS5 upstream '{}' is unreachable (circuit breaker open), not attempting UDP ASSOCIATE",
+                    upstream
+                ));
+            }
+
+            let result = udp_client.associate_monitored().await;
+            if let Some(breaker) = breaker {
+                let transition = match result {
+                    Ok(_) => breaker.record_success(),
+                    Err(_) => breaker.record_failure(),
+                };
+                self.record_breaker_transition(upstream, transition);
+            }
+            let e = match result {
+                Ok(associated) => return Ok(associated),
+                Err(e) => e,
+            };
+            let count = self.counters.associate_errors.record(&e);
+
+            // 认证失败、规则不允许等重试不会改变结果
+            if !e.is_retryable()
+                || attempt >= self.config.associate_attempts.max(1)
+                || started.elapsed() + backoff > self.config.pending_ttl
+            {
+                e.log(Socks5Operation::UdpAssociate, &upstream, count);
+                return Err(
+                    anyhow!(e).context(format!("UDP ASSOCIATE failed after {} attempts", attempt))
+                );
+            }
+            debug!(
+                upstream,
+                attempt,
+                backoff = ?backoff,
+                error = %e,
+                "socks5 udp associate failed, retrying"
+            );
+            self.counters
+                .associate_retries
+                .fetch_add(1, Ordering::Relaxed);
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+            attempt += 1;
+        }
+    }
+
+    /// 记录断路器状态变化
+    fn record_breaker_transition(&self, upstream: &str, transition: Transition) {
+        match transition {
+            Transition::None => {}
+            Transition::Opened => {
+                self.counters.breaker_opened.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    upstream,
+                    cooldown = ?self.config.breaker_cooldown,
+                    "socks5 udp associate keeps failing, circuit breaker opened"
+                );
+            }
+            Transition::Closed => {
+                self.counters.breaker_closed.fetch_add(1, Ordering::Relaxed);
+                info!(
+                    upstream,
+                    "socks5 udp associate recovered, circuit breaker closed"
+                );
+            }
+        }
+    }
+
+    /// 对不支持的 QUIC 版本回复 Version Negotiation (RFC 9000 Section 6)
+    async fn send_version_negotiation(&self, packet: &[u8], src: SocketAddr, version: u32) {
+        if !negotiation::should_negotiate(packet) {
+            trace!(
+                listener = "quic",
+                client = %src,
+                version = %format_args!("{:#010x}", version),
+                outcome = "unsupported_version",
+                "packet with unsupported version ignored"
+            );
+            return;
+        }
+        let Some((_, dcid, scid)) = negotiation::parse_invariant_long_header(packet) else {
+            return;
+        };
+
+        let response =
+            negotiation::build_version_negotiation(dcid, scid, negotiation::SUPPORTED_VERSIONS);
+        let socket = {
+            let inner = self.inner.lock().await;
+            Arc::clone(&inner.socket)
+        };
+
+        debug!(
+            listener = "quic",
+            client = %src,
+            version = %format_args!("{:#010x}", version),
+            "sending Version Negotiation"
+        );
+        if let Err(e) = socket.send_to(&response, src).await {
+            warn!(listener = "quic", client = %src, error = %e, "failed to send Version Negotiation");
+        }
+    }
+
+    /// 保存会话并建立 DCID 索引
+    #[cfg(test)]
+    async fn insert_session(&self, session: QuicSession) {
+        self.inner.lock().await.insert_session(session);
+    }
+
+    /// 清理过期会话
+    pub async fn cleanup_expired_sessions(&self) -> usize {
+        let mut inner = self.inner.lock().await;
+        let initial_count = inner.sessions.len();
+
+        let inner = &mut *inner;
+        // 超过各自空闲超时或 relay 任务已退出的会话
+        let expired: Vec<SocketAddr> = inner
+            .sessions
+            .iter()
+            .filter(|entry| {
+                let session = entry.value();
+                session.idle_time() >= session.idle_timeout || session.tx.is_closed()
+            })
+            .map(|entry| *entry.key())
+            .collect();
+        for client in expired {
+            inner.remove_session(client);
+        }
+        let now = Instant::now();
+        inner
+            .recent_routes
+            .retain(|_, route| route.expires_at > now);
+        let sessions = &inner.sessions;
+        inner
+            .dcid_index
+            .map
+            .retain(|_, client| sessions.contains_key(client));
+        self.extractor.evict_expired();
+        inner.negative_cache.evict_expired();
+        let pending_ttl = inner.config.pending_ttl;
+        inner
+            .awaiting_hello
+            .retain(|_, flow| flow.created_at.elapsed() <= pending_ttl);
+
+        let removed = initial_count - inner.sessions.len();
+        if removed > 0 {
+            debug!(removed, "expired quic sessions cleaned up");
+        }
+
+        removed
+    }
+
+    /// 获取统计信息
+    pub async fn stats(&self) -> QuicSessionStats {
+        // 新建共享 relay 期间会持有其上游的锁，先于管理器的锁读取
+        let shared_relays = match &self.shared_relays {
+            Some(pool) => pool.stats().await,
+            None => Vec::new(),
+        };
+        let inner = self.inner.lock().await;
+        QuicSessionStats {
+            active_sessions: inner.sessions.len(),
+            pending_sessions: inner.pending.len(),
+            incomplete_client_hellos: inner.awaiting_hello.len(),
+            sni_extractions: inner.sni_extractions,
+            ech_sessions: inner.ech_admissions,
+            rejected_alpn: inner.rejected_alpn,
+            rejected_over_limit: inner.rejected_over_limit,
+            evicted_for_capacity: inner.evicted_for_capacity,
+            dns_cache_hits: self.dns_cache.hits(),
+            dns_cache_misses: self.dns_cache.misses(),
+            spoofed_relay_packets: self.spoofed_relay_packets.load(Ordering::Relaxed),
+            relays_declared_dead: self.relays_declared_dead.load(Ordering::Relaxed),
+            relay_fragments_dropped: self.fragments.dropped.load(Ordering::Relaxed),
+            relay_datagrams_reassembled: self.fragments.reassembled.load(Ordering::Relaxed),
+            queue_full_drops: self.queue_full_drops.load(Ordering::Relaxed),
+            initial_errors: self.counters.initial_errors(),
+            sessions_created: self.counters.sessions_created.load(Ordering::Relaxed),
+            packets_forwarded: self.counters.packets_forwarded.load(Ordering::Relaxed),
+            reflected_packets: self.counters.reflected_packets.load(Ordering::Relaxed),
+            self_target_rejections: self.counters.self_target_rejections.load(Ordering::Relaxed),
+            rate_limited_initials: inner.rate_limited_initials,
+            associate_retries: self.counters.associate_retries.load(Ordering::Relaxed),
+            breaker_opened: self.counters.breaker_opened.load(Ordering::Relaxed),
+            breaker_closed: self.counters.breaker_closed.load(Ordering::Relaxed),
+            breaker_rejections: self.counters.breaker_rejections.load(Ordering::Relaxed),
+            open_breakers: self.open_breakers(),
+            associate_errors: self.counters.associate_errors.snapshot(),
+            upstream_sessions: inner.upstream_sessions(),
+            shared_relays,
+            packets: self.packet_counters.snapshot(),
+        }
+    }
+
+    /// 记录收包循环中一个 `len` 字节的包的处理结果；`None` 表示 `handle_packet` 出错
+    pub fn record_packet(&self, outcome: Option<&PacketOutcome>, len: usize) {
+        self.packet_counters.record(outcome, len);
+        match outcome {
+            Some(PacketOutcome::ForwardedNew { .. }) => {
+                self.metrics.record_accepted(Listener::Quic);
+                self.metrics.record_sni(Listener::Quic, true);
+            }
+            Some(PacketOutcome::Rejected { reason }) => {
+                match reason {
+                    RejectReason::NotWhitelisted => self.metrics.record_sni(Listener::Quic, true),
+                    RejectReason::NoSni => self.metrics.record_sni(Listener::Quic, false),
+                    _ => {}
+                }
+                self.metrics.record_rejection(Listener::Quic, *reason);
+            }
+            _ => {}
+        }
+    }
+
+    /// 记录收包循环中 `handle_packet` 出错的包，按 [`Outcome::of_quic_error`] 计入拒绝或失败；
+    /// ClientHello 格式错误或没有 SNI 计为 SNI 提取失败
+    pub fn record_failed_packet(&self, error: &QuicError, len: usize) {
+        self.record_packet(None, len);
+        let outcome = Outcome::of_quic_error(error);
+        if let Outcome::Rejected(_) = outcome {
+            self.metrics.record_sni(Listener::Quic, false);
+        }
+        self.metrics.record_outcome(Listener::Quic, outcome);
+    }
+
+    /// 启动收包汇总日志任务 (未配置间隔时返回 None)
+    ///
+    /// 每个间隔输出一行该间隔内的处理结果分类计数；没有收到包的间隔不输出。
+    pub fn spawn_stats_task(&self) -> Option<tokio::task::JoinHandle<()>> {
+        let period = self.config.stats_interval?;
+        let counters = Arc::clone(&self.packet_counters);
+        let task = self.metrics.track_task(TaskKind::Background);
+        Some(tokio::spawn(async move {
+            let _task = task;
+            let mut interval = tokio::time::interval(period);
+            interval.tick().await;
+            let mut last = counters.snapshot();
+            loop {
+                interval.tick().await;
+                let current = counters.snapshot();
+                let delta = current.since(&last);
+                if delta.packets() > 0 {
+                    info!(period = ?period, summary = %delta.summary(), "quic packets");
+                }
+                last = current;
+            }
+        }))
+    }
+
+    /// 断路器当前打开的上游名称 (按名称排序)
+    fn open_breakers(&self) -> Vec<String> {
+        let mut open: Vec<String> = self
+            .breakers
+            .iter()
+            .filter(|(_, breaker)| breaker.is_open())
+            .map(|(name, _)| name.clone())
+            .collect();
+        open.sort();
+        open
+    }
+
+    /// 单个 UDP 数据报的最大字节数
+    pub fn max_datagram_size(&self) -> usize {
+        self.config.max_datagram_size
+    }
+
+    /// 获取会话数量
+    pub fn session_count(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// 启动会话清理任务，[`shutdown`](Self::shutdown) 后任务结束
+    pub fn spawn_cleanup_task(&self) -> tokio::task::JoinHandle<()> {
+        let manager = self.clone();
+        let mut stopping = self.stopping.subscribe();
+        let task = self.metrics.track_task(TaskKind::Cleanup);
+        tokio::spawn(async move {
+            let _task = task;
+            let mut interval = tokio::time::interval(manager.config.cleanup_interval);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = stopping.wait_for(|stop| *stop) => break,
+                }
+                manager.cleanup_expired_sessions().await;
+                manager.log_summary();
+            }
+            debug!("quic session cleanup task stopped");
+        })
+    }
+
+    /// 关闭所有会话：停止清理任务，向客户端发送 CONNECTION_CLOSE，结束会话任务并最多等待 `deadline`
+    ///
+    /// 会话表清空后会话的发送端全部释放，会话任务随之退出并关闭 SOCKS5 relay 和控制连接；
+    /// 超时仍未退出的任务直接中止。CONNECTION_CLOSE 用 Initial 密钥保护，
+    /// 只有仍在握手的客户端能立即感知，握手已完成的客户端仍需等待自身的空闲超时。
+    pub async fn shutdown(&self, deadline: Duration) {
+        self.stopping.send_replace(true);
+        let deadline = tokio::time::Instant::now() + deadline;
+        let (sessions, closes, socket) = {
+            let mut inner = self.inner.lock().await;
+            let inner = &mut *inner;
+            inner.shutting_down = true;
+            inner.pending.clear();
+            inner.per_ip.clear();
+            inner.awaiting_hello.clear();
+            inner.dcid_index.map.clear();
+
+            let clients: Vec<SocketAddr> =
+                inner.sessions.iter().map(|entry| *entry.key()).collect();
+            let mut sessions = Vec::with_capacity(clients.len());
+            let mut closes = Vec::new();
+            for client in clients {
+                let Some((_, session)) = inner.sessions.remove(&client) else {
+                    continue;
+                };
+                session.record_domain_traffic();
+                if let Some(close) = self.build_close(
+                    session.version,
+                    &session.dcid,
+                    &session.scid,
+                    close::NO_ERROR,
+                ) {
+                    closes.push((client, close));
+                }
+                sessions.push(session);
+            }
+            (sessions, closes, Arc::clone(&inner.socket))
+        };
+
+        for (client, close) in &closes {
+            if let Err(e) = socket.send_to(close, client).await {
+                debug!(client = %client, error = %e, "failed to send CONNECTION_CLOSE");
+            }
+        }
+
+        let tasks: Vec<_> = sessions
+            .iter()
+            .filter_map(|session| {
+                session
+                    .task
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .take()
+            })
+            .collect();
+        let count = sessions.len();
+        drop(sessions);
+        if let Some(pool) = &self.shared_relays {
+            pool.close_all().await;
+        }
+
+        let mut aborted = 0;
+        for mut task in tasks {
+            if tokio::time::timeout_at(deadline, &mut task).await.is_err() {
+                task.abort();
+                aborted += 1;
+            }
+        }
+        info!(
+            closed = count,
+            connection_close_sent = closes.len(),
+            aborted,
+            "quic sessions closed on shutdown"
+        );
+    }
+
+    /// 列出所有活动会话 (不持有管理器的互斥锁)
+    pub fn snapshot(&self) -> Vec<QuicSessionSnapshot> {
+        self.sessions
+            .iter()
+            .map(|entry| entry.value().snapshot())
+            .collect()
+    }
+
+    /// 输出活动会话数和流量合计
+    fn log_summary(&self) {
+        let sessions = self.snapshot();
+        if sessions.is_empty() {
+            return;
+        }
+        let (up, down) = sessions.iter().fold((0u64, 0u64), |(up, down), s| {
+            (up + s.bytes_up, down + s.bytes_down)
+        });
+        info!(
+            active = sessions.len(),
+            bytes_up = up,
+            bytes_down = down,
+            "quic sessions"
+        );
+    }
+}
+
+/// 通过 SOCKS5 UDP relay 发往目标；域名目标使用域名地址类型，由 SOCKS5 服务器解析
+async fn send_to_target(
+    relay: &Socks5UdpDatagram,
+    data: &[u8],
+    target: &TargetAddr,
+) -> fast_socks5::Result<usize> {
+    match target {
+        TargetAddr::Ip(addr) => relay.send_to(data, *addr).await,
+        TargetAddr::Domain(domain, port) => relay.send_to(data, (domain.as_str(), *port)).await,
+    }
+}
+
+/// SOCKS5 relay 地址 -> 使用该地址的会话数
+pub(super) type RelayAddrs = DashMap<SocketAddr, usize>;
+
+/// `addr` 是否指向监听地址 `listen`
+///
+/// 监听在通配地址 (0.0.0.0 / ::) 时，同端口的回环地址和通配地址都视为自身。
+fn is_same_endpoint(listen: SocketAddr, addr: SocketAddr) -> bool {
+    if listen.port() != addr.port() {
+        return false;
+    }
+    let (listen_ip, ip) = (listen.ip().to_canonical(), addr.ip().to_canonical());
+    if listen_ip.is_unspecified() {
+        ip.is_loopback() || ip.is_unspecified()
+    } else {
+        listen_ip == ip
+    }
+}
+
+/// 会话任务持有期间把 relay 地址登记在 `RelayAddrs` 中
+pub(super) struct RelayAddrGuard {
+    addrs: Arc<RelayAddrs>,
+    addr: SocketAddr,
+}
+
+impl RelayAddrGuard {
+    pub(super) fn register(addrs: &Arc<RelayAddrs>, addr: SocketAddr) -> Self {
+        *addrs.entry(addr).or_insert(0) += 1;
+        Self {
+            addrs: Arc::clone(addrs),
+            addr,
+        }
+    }
+}
+
+impl Drop for RelayAddrGuard {
+    fn drop(&mut self) {
+        self.addrs.remove_if_mut(&self.addr, |_, count| {
+            *count -= 1;
+            *count == 0
+        });
+    }
+}
+
+/// SOCKS5 UDP 分片的处理方式及其计数，各会话任务和共享 relay 共用
+#[derive(Debug, Clone, Default)]
+pub struct FragmentPolicy {
+    /// 重组分片 (`socks5.udp_reassembly`)；否则丢弃
+    pub reassemble: bool,
+    /// 丢弃的分片数 (不重组时的全部分片，重组时不连续或过期的分片)
+    pub dropped: Arc<AtomicU64>,
+    /// 重组完成的数据报数
+    pub reassembled: Arc<AtomicU64>,
+}
+
+/// 一个 relay 的分片处理状态
+///
+/// 大多数 SOCKS5 服务器从不分片；把分片当作完整的 QUIC 包转发会破坏连接，
+/// 不重组时丢弃并计数，每个 relay 第一次丢弃时输出告警。
+#[derive(Debug)]
+pub(super) struct RelayFragments {
+    policy: FragmentPolicy,
+    reassembler: UdpReassembler,
+    warned: bool,
+}
+
+impl RelayFragments {
+    pub(super) fn new(policy: FragmentPolicy) -> Self {
+        Self {
+            policy,
+            reassembler: UdpReassembler::default(),
+            warned: false,
+        }
+    }
+
+    /// 返回完整的数据报：未分片时原样返回，分片收齐时返回重组结果，否则为 None
+    pub(super) fn accept<'a>(
+        &mut self,
+        frag: u8,
+        remote: &TargetAddr,
+        payload: &'a [u8],
+        relay_addr: SocketAddr,
+    ) -> Option<Cow<'a, [u8]>> {
+        if frag == 0 {
+            return Some(Cow::Borrowed(payload));
+        }
+        if !self.policy.reassemble {
+            self.policy.dropped.fetch_add(1, Ordering::Relaxed);
+            if !std::mem::replace(&mut self.warned, true) {
+                warn!(
+                    relay = %relay_addr,
+                    frag = %format_args!("{:#04x}", frag),
+                    remote = %remote,
+                    "SOCKS5 UDP relay sent a fragmented datagram, dropping fragments (set socks5.udp_reassembly = true to reassemble them)"
+                );
+            }
+            return None;
+        }
+        match self.reassembler.push(frag, remote, payload, Instant::now()) {
+            Reassembly::Complete(datagram) => {
+                self.policy.reassembled.fetch_add(1, Ordering::Relaxed);
+                Some(Cow::Owned(datagram))
+            }
+            Reassembly::Buffered => None,
+            Reassembly::Dropped => {
+                self.policy.dropped.fetch_add(1, Ordering::Relaxed);
+                debug!(
+                    frag = %format_args!("{:#04x}", frag),
+                    remote = %remote,
+                    relay = %relay_addr,
+                    "SOCKS5 UDP fragment out of sequence or expired, dropped"
+                );
+                None
+            }
+        }
+    }
+}
+
+/// relay 无响应检测 (`quic.relay_probe_interval`)
+///
+/// NAT/防火墙状态过期后 relay 可能不再转发，而控制连接看起来仍然打开。记录最近一次
+/// 收到回包之后第一个发往 relay 的包的时间，超过检测间隔仍没有任何回包时判定 relay
+/// 已失效。没有发送流量的空闲会话不会被判定失效。
+#[derive(Debug)]
+pub(super) struct RelayWatchdog {
+    interval: Option<Duration>,
+    /// 尚未得到回包的最早一次发送
+    unanswered_since: Option<Instant>,
+}
+
+impl RelayWatchdog {
+    pub(super) fn new(interval: Option<Duration>) -> Self {
+        Self {
+            interval,
+            unanswered_since: None,
+        }
+    }
+
+    /// 记录一次发往 relay 的包
+    pub(super) fn sent(&mut self) {
+        if self.interval.is_some() && self.unanswered_since.is_none() {
+            self.unanswered_since = Some(Instant::now());
+        }
+    }
+
+    /// 记录一次 relay 的回包
+    pub(super) fn received(&mut self) {
+        self.unanswered_since = None;
+    }
+
+    /// 判定 relay 失效的时间；没有待回复的包或未开启检测时为 None
+    pub(super) fn deadline(&self) -> Option<Instant> {
+        Some(self.unanswered_since? + self.interval?)
+    }
+}
+
+/// relay 失效的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RelayLoss {
+    /// SOCKS5 服务器关闭了控制连接
+    ControlClosed,
+    /// 控制连接仍然打开，但在检测间隔内没有任何回包
+    Unresponsive,
+}
+
+impl std::fmt::Display for RelayLoss {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            RelayLoss::ControlClosed => "closed its control connection",
+            RelayLoss::Unresponsive => "stopped answering while the control connection stayed open",
+        })
+    }
+}
+
+/// 等待 relay 失效：控制连接关闭，或到达 watchdog 的判定时间
+async fn relay_lost(control: &mut TcpStream, deadline: Option<Instant>) -> RelayLoss {
+    let unresponsive = async {
+        match deadline {
+            Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::select! {
+        _ = wait_control_closed(control) => RelayLoss::ControlClosed,
+        _ = unresponsive => RelayLoss::Unresponsive,
+    }
+}
+
+/// 会话期望的 relay 回包来源
+///
+/// SOCKS5 UDP 回包头部携带远端地址。目标为 IP 时只接受该地址；目标为域名
+/// (remote 解析模式) 时接受同名域名，以及端口一致的第一个 IP 地址，之后固定为该地址。
+struct RemotePin {
+    domain: Option<(String, u16)>,
+    port: u16,
+    addr: Option<SocketAddr>,
+}
+
+impl RemotePin {
+    fn new(target: &TargetAddr) -> Self {
+        match target {
+            TargetAddr::Ip(addr) => Self {
+                domain: None,
+                port: addr.port(),
+                addr: Some(canonical(*addr)),
+            },
+            TargetAddr::Domain(domain, port) => Self {
+                domain: Some((domain.clone(), *port)),
+                port: *port,
+                addr: None,
+            },
+        }
+    }
+
+    /// 判断回包来源是否属于本会话的目标
+    fn accepts(&mut self, remote: &TargetAddr) -> bool {
+        match remote {
+            TargetAddr::Ip(addr) => {
+                let addr = canonical(*addr);
+                match self.addr {
+                    Some(pinned) => pinned == addr,
+                    None if addr.port() == self.port => {
+                        self.addr = Some(addr);
+                        true
+                    }
+                    None => false,
+                }
+            }
+            TargetAddr::Domain(domain, port) => self
+                .domain
+                .as_ref()
+                .is_some_and(|(d, p)| d.eq_ignore_ascii_case(domain) && p == port),
+        }
+    }
+}
+
+/// IPv4-mapped IPv6 地址统一为 IPv4，便于比较
+pub(super) fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// 读取 Long Header 中的 DCID（任意 Long Header 包类型）
+fn long_header_dcid(packet: &[u8]) -> Option<&[u8]> {
+    if packet.first()? & 0x80 == 0 {
+        return None;
+    }
+    let dcil = *packet.get(5)? as usize;
+    packet.get(6..6 + dcil)
+}
+
+/// Short Header: Header Form = 0 且 Fixed Bit = 1
+fn is_short_header(packet: &[u8]) -> bool {
+    packet.first().is_some_and(|b| b & 0xc0 == 0x40)
+}
+
+async fn resolve_target_addr(
+    host: &str,
+    port: u16,
+    socks5_config: &Socks5Config,
+) -> Result<SocketAddr> {
+    if let Ok(ip) = host.parse::<std::net::IpAddr>() {
+        return Ok(SocketAddr::new(ip, port));
+    }
+
+    if std::env::var("SNIPROXY_DNS_DIRECT").as_deref() == Ok("1") {
+        return tokio::net::lookup_host((host, port))
+            .await
+            .map_err(|e| anyhow!("Failed to resolve {}:{}: {}", host, port, e))?
+            .next()
+            .ok_or_else(|| anyhow!("No A/AAAA record for {}:{}", host, port));
+    }
+
+    resolve_with_socks5_udp_dns(host, port, socks5_config).await
+}
+
+/// SOCKS5 UDP relay 探测失败 (见 [`probe_socks5_udp_relay`])
+#[derive(thiserror::Error, Debug)]
+pub enum RelayProbeError {
+    /// `SNIPROXY_DNS_SERVER` 不是合法的 `ip:port`
+    #[error("Invalid SNIPROXY_DNS_SERVER '{value}': {source}")]
+    InvalidDnsServer {
+        value: String,
+        source: std::net::AddrParseError,
+    },
+
+    /// 经 relay 的 DNS 查询失败 (ASSOCIATE 失败、超时或没有应答)
+    #[error("SOCKS5 UDP relay probe failed via DNS server {dns_server}: {reason}")]
+    Query {
+        dns_server: SocketAddr,
+        reason: String,
+    },
+}
+
+/// 经 SOCKS5 UDP relay 向上游 DNS 服务器查询一次，确认 relay 能转发 UDP
+pub async fn probe_socks5_udp_relay(
+    socks5_config: &Socks5Config,
+) -> std::result::Result<(), RelayProbeError> {
+    let dns_server = upstream_dns_server()?;
+    resolve_with_socks5_udp_dns("example.com", 443, socks5_config)
+        .await
+        .map(|_| ())
+        .map_err(|e| RelayProbeError::Query {
+            dns_server,
+            reason: format!("{:#}", e),
+        })
+}
+
+async fn resolve_with_socks5_udp_dns(
+    host: &str,
+    port: u16,
+    socks5_config: &Socks5Config,
+) -> Result<SocketAddr> {
     let dns_server = upstream_dns_server()?;
     let mut last_error = None;
 
-    for qtype in [1u16, 28u16] {
-        match query_socks5_udp_dns_once(host, port, dns_server, qtype, socks5_config).await {
-            Ok(Some(addr)) => return Ok(addr),
-            Ok(None) => {}
-            Err(e) => last_error = Some(e),
+    for qtype in [1u16, 28u16] {
+        match query_socks5_udp_dns_once(host, port, dns_server, qtype, socks5_config).await {
+            Ok(Some(addr)) => return Ok(addr),
+            Ok(None) => {}
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    if let Some(e) = last_error {
+        Err(e)
+    } else {
+        Err(anyhow!(
+            "No A/AAAA record for {}:{} from DNS server {}",
+            host,
+            port,
+            dns_server
+        ))
+    }
+}
+
+async fn query_socks5_udp_dns_once(
+    host: &str,
+    port: u16,
+    dns_server: SocketAddr,
+    qtype: u16,
+    socks5_config: &Socks5Config,
+) -> Result<Option<SocketAddr>> {
+    let query = build_dns_query(host, qtype)?;
+
+    let udp_client = Socks5UdpClient::from_config(socks5_config);
+    let (relay, _) = udp_client.associate().await?;
+    relay.send_to(&query, dns_server).await?;
+
+    let mut response = [0u8; 1500];
+    let (len, _) = tokio::time::timeout(Duration::from_secs(3), relay.recv_from(&mut response))
+        .await
+        .map_err(|_| {
+            anyhow!(
+                "SOCKS5 UDP DNS query for {} timed out via {}",
+                host,
+                dns_server
+            )
+        })??;
+
+    parse_dns_response(&response[..len], dns_txid(host, qtype), qtype, port)
+}
+
+fn build_dns_query(host: &str, qtype: u16) -> Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(512);
+    let txid = dns_txid(host, qtype);
+    query.extend_from_slice(&txid.to_be_bytes());
+    query.extend_from_slice(&0x0100u16.to_be_bytes()); // recursion desired
+    query.extend_from_slice(&1u16.to_be_bytes()); // qdcount
+    query.extend_from_slice(&0u16.to_be_bytes()); // ancount
+    query.extend_from_slice(&0u16.to_be_bytes()); // nscount
+    query.extend_from_slice(&0u16.to_be_bytes()); // arcount
+
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(anyhow!("Invalid DNS label in host '{}'", host));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&1u16.to_be_bytes()); // IN
+
+    Ok(query)
+}
+
+fn upstream_dns_server() -> std::result::Result<SocketAddr, RelayProbeError> {
+    let value = std::env::var("SNIPROXY_DNS_SERVER").unwrap_or_else(|_| "1.1.1.1:53".to_string());
+    value
+        .parse()
+        .map_err(|source| RelayProbeError::InvalidDnsServer { value, source })
+}
+
+fn parse_dns_response(
+    response: &[u8],
+    expected_txid: u16,
+    expected_qtype: u16,
+    port: u16,
+) -> Result<Option<SocketAddr>> {
+    if response.len() < 12 {
+        return Err(anyhow!("DNS response too short"));
+    }
+    if u16::from_be_bytes([response[0], response[1]]) != expected_txid {
+        return Err(anyhow!("DNS transaction id mismatch"));
+    }
+
+    let flags = u16::from_be_bytes([response[2], response[3]]);
+    if flags & 0x8000 == 0 {
+        return Err(anyhow!("DNS response is not marked as response"));
+    }
+    if flags & 0x000f != 0 {
+        return Ok(None);
+    }
+
+    let qdcount = u16::from_be_bytes([response[4], response[5]]) as usize;
+    let ancount = u16::from_be_bytes([response[6], response[7]]) as usize;
+    let mut offset = 12;
+
+    for _ in 0..qdcount {
+        offset = skip_dns_name(response, offset)?;
+        if response.len() < offset + 4 {
+            return Err(anyhow!("DNS question truncated"));
+        }
+        offset += 4;
+    }
+
+    for _ in 0..ancount {
+        offset = skip_dns_name(response, offset)?;
+        if response.len() < offset + 10 {
+            return Err(anyhow!("DNS answer truncated"));
+        }
+
+        let rr_type = u16::from_be_bytes([response[offset], response[offset + 1]]);
+        let rr_class = u16::from_be_bytes([response[offset + 2], response[offset + 3]]);
+        let rdlen = u16::from_be_bytes([response[offset + 8], response[offset + 9]]) as usize;
+        offset += 10;
+
+        if response.len() < offset + rdlen {
+            return Err(anyhow!("DNS answer data truncated"));
+        }
+
+        if rr_class == 1 && rr_type == expected_qtype {
+            match (rr_type, rdlen) {
+                (1, 4) => {
+                    let ip = Ipv4Addr::new(
+                        response[offset],
+                        response[offset + 1],
+                        response[offset + 2],
+                        response[offset + 3],
+                    );
+                    return Ok(Some(SocketAddr::new(ip.into(), port)));
+                }
+                (28, 16) => {
+                    let mut octets = [0u8; 16];
+                    octets.copy_from_slice(&response[offset..offset + 16]);
+                    return Ok(Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port)));
+                }
+                _ => {}
+            }
+        }
+
+        offset += rdlen;
+    }
+
+    Ok(None)
+}
+
+fn skip_dns_name(packet: &[u8], mut offset: usize) -> Result<usize> {
+    loop {
+        if offset >= packet.len() {
+            return Err(anyhow!("DNS name truncated"));
+        }
+
+        let len = packet[offset];
+        if len & 0xc0 == 0xc0 {
+            if offset + 1 >= packet.len() {
+                return Err(anyhow!("DNS compression pointer truncated"));
+            }
+            return Ok(offset + 2);
+        }
+
+        offset += 1;
+        if len == 0 {
+            return Ok(offset);
+        }
+
+        if len & 0xc0 != 0 {
+            return Err(anyhow!("Unsupported DNS label encoding"));
+        }
+
+        offset += len as usize;
+        if offset > packet.len() {
+            return Err(anyhow!("DNS label truncated"));
+        }
+    }
+}
+
+fn dns_txid(host: &str, qtype: u16) -> u16 {
+    let mut hash = 0x811c9dc5u32;
+    for byte in host.as_bytes() {
+        hash ^= *byte as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    (hash as u16) ^ qtype
+}
+
+impl Clone for QuicSessionManager {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+            sessions: Arc::clone(&self.sessions),
+            dcid_index: Arc::clone(&self.dcid_index),
+            extractor: Arc::clone(&self.extractor),
+            dns_cache: Arc::clone(&self.dns_cache),
+            relay_addrs: Arc::clone(&self.relay_addrs),
+            spoofed_relay_packets: Arc::clone(&self.spoofed_relay_packets),
+            relays_declared_dead: Arc::clone(&self.relays_declared_dead),
+            fragments: self.fragments.clone(),
+            queue_full_drops: Arc::clone(&self.queue_full_drops),
+            gso: Arc::clone(&self.gso),
+            listen_addrs: Arc::clone(&self.listen_addrs),
+            breakers: Arc::clone(&self.breakers),
+            shared_relays: self.shared_relays.clone(),
+            packet_counters: Arc::clone(&self.packet_counters),
+            counters: Arc::clone(&self.counters),
+            metrics: Arc::clone(&self.metrics),
+            stopping: Arc::clone(&self.stopping),
+            config: self.config.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_default() {
+        let config = QuicSessionConfig::default();
+        assert_eq!(config.idle_timeout, Duration::from_secs(60));
+        assert_eq!(config.cleanup_interval, Duration::from_secs(30));
+        assert_eq!(config.pending_max_packets, 10);
+        assert_eq!(config.pending_max_bytes, 20 * 1024);
+        assert_eq!(config.pending_ttl, Duration::from_secs(2));
+        assert_eq!(config.crypto_reassembly_ttl, Duration::from_secs(3));
+        assert_eq!(config.crypto_reassembly_max_entries, 1024);
+        assert_eq!(config.negative_cache_ttl, Duration::from_secs(30));
+        assert_eq!(config.negative_cache_max_entries, 4096);
+        assert_eq!(config.initial_key_cache_size, 1024);
+        assert_eq!(config.max_sessions, 4096);
+        assert_eq!(config.max_sessions_per_ip, 64);
+        assert!(config.evict_idle_on_full);
+        assert_eq!(config.dns_cache_ttl, Duration::from_secs(60));
+        assert_eq!(config.dns_cache_size, 1024);
+        assert_eq!(config.max_datagram_size, 1500);
+        assert_eq!(config.initial_rate, 100.0);
+        assert_eq!(config.initial_burst, 500);
+    }
+
+    fn test_manager(socket: Arc<UdpSocket>) -> QuicSessionManager {
+        test_manager_with_socks5(socket, "127.0.0.1:1080".parse().unwrap())
+    }
+
+    fn test_manager_with_socks5(
+        socket: Arc<UdpSocket>,
+        socks5_addr: SocketAddr,
+    ) -> QuicSessionManager {
+        test_manager_with_rules(socket, socks5_addr, Vec::new())
+    }
+
+    fn test_manager_with_rules(
+        socket: Arc<UdpSocket>,
+        socks5_addr: SocketAddr,
+        allow: Vec<String>,
+    ) -> QuicSessionManager {
+        test_manager_with_config(socket, socks5_addr, allow, QuicSessionConfig::default())
+    }
+
+    fn test_manager_with_config(
+        socket: Arc<UdpSocket>,
+        socks5_addr: SocketAddr,
+        allow: Vec<String>,
+        session_config: QuicSessionConfig,
+    ) -> QuicSessionManager {
+        let mut config: crate::config::Config = toml::from_str(
+            r#"
+[server]
+listen_https_addr = "127.0.0.1:0"
+
+[socks5]
+addr = "127.0.0.1:1080"
+timeout = 5
+"#,
+        )
+        .unwrap();
+        config.socks5.addr = socks5_addr.into();
+        config.rules.allow = allow;
+        test_manager_from(socket, config, session_config)
+    }
+
+    fn test_manager_from(
+        socket: Arc<UdpSocket>,
+        config: crate::config::Config,
+        session_config: QuicSessionConfig,
+    ) -> QuicSessionManager {
+        let upstreams = Arc::new(UpstreamSet::from_config(&config).unwrap());
+        QuicSessionManager::new(session_config, Router::new(config), upstreams, socket)
+    }
+
+    fn test_session(
+        dcid: &[u8],
+        client_addr: SocketAddr,
+    ) -> (
+        QuicSession,
+        mpsc::Receiver<Vec<u8>>,
+        watch::Receiver<SocketAddr>,
+    ) {
+        let (tx, rx) = mpsc::channel(16);
+        let (client_tx, client_rx) = watch::channel(client_addr);
+        let session = QuicSession {
+            dcid: dcid.to_vec(),
+            retry_dcids: std::sync::Mutex::new(Vec::new()),
+            scid: Vec::new(),
+            version: 0x00000001,
+            sni: "www.example.com".to_string(),
+            ech: false,
+            alpn: Vec::new(),
+            target_addr: TargetAddr::Ip("127.0.0.1:443".parse().unwrap()),
+            upstream: crate::upstream::DEFAULT_UPSTREAM.to_string(),
+            tx,
+            client_tx,
+            last_active: AtomicU64::new(0),
+            created_at: Instant::now(),
+            idle_timeout: QuicSessionConfig::default().idle_timeout,
+            traffic: Arc::new(SessionTraffic::default()),
+            task: std::sync::Mutex::new(None),
+        };
+        (session, rx, client_rx)
+    }
+
+    #[tokio::test]
+    async fn session_survives_client_address_change() {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let manager = test_manager(socket);
+        let dcid = [0xde, 0xad, 0xbe, 0xef, 0x01, 0x02, 0x03, 0x04];
+        let old_addr: SocketAddr = "192.0.2.1:50000".parse().unwrap();
+        let new_addr: SocketAddr = "192.0.2.1:50001".parse().unwrap();
+
+        let (session, mut rx, client_rx) = test_session(&dcid, old_addr);
+        manager.insert_session(session).await;
+
+        // 原地址的 Short Header 包正常转发
+        let mut short_pkt = vec![0x41];
+        short_pkt.extend_from_slice(&dcid);
+        short_pkt.extend_from_slice(&[0xaa; 24]);
+        assert!(manager
+            .handle_packet(&short_pkt, old_addr)
+            .await
+            .unwrap()
+            .is_forwarded());
+        assert_eq!(rx.recv().await.unwrap(), short_pkt);
+
+        // NAT 重绑定后，新地址的包按 DCID 前缀匹配到原会话
+        assert!(manager
+            .handle_packet(&short_pkt, new_addr)
+            .await
+            .unwrap()
+            .is_forwarded());
+        assert_eq!(rx.recv().await.unwrap(), short_pkt);
+        assert_eq!(*client_rx.borrow(), new_addr);
+        assert_eq!(manager.session_count(), 1);
+
+        // 之后新地址直接命中会话
+        assert!(manager
+            .handle_packet(&short_pkt, new_addr)
+            .await
+            .unwrap()
+            .is_forwarded());
+        assert_eq!(rx.recv().await.unwrap(), short_pkt);
+    }
+
+    #[tokio::test]
+    async fn initial_outcomes_are_counted_by_category() {
+        use crate::quic::test_util::{
+            build_client_initial, client_hello_handshake, client_hello_with_extensions,
+            crypto_frame,
+        };
+        use crate::socks5::test_util::spawn_mock_udp_associate;
+
+        let mut socks5 = spawn_mock_udp_associate(Duration::ZERO).await;
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let manager = test_manager_with_socks5(socket, socks5.addr);
+        let src = |port| SocketAddr::from(([192, 0, 2, 1], port));
+
+        // 未知地址的 Short Header 包
+        let mut short = vec![0x41];
+        short.extend_from_slice(&[0xaa; 24]);
+        assert!(!manager
+            .handle_packet(&short, src(1))
+            .await
+            .unwrap()
+            .is_forwarded());
+
+        // 不支持的版本
+        let mut unknown_version = vec![0xc0, 0x0a, 0x0a, 0x0a, 0x0a, 0x04, 1, 2, 3, 4, 0x00];
+        unknown_version.resize(1200, 0);
+        assert!(!manager
+            .handle_packet(&unknown_version, src(2))
+            .await
+            .unwrap()
+            .is_forwarded());
+
+        // 认证标签被篡改
+        let mut corrupted = build_client_initial(
+            0x00000001,
+            &[0x61, 0x01, 1, 2, 3, 4, 5, 6],
+            0,
+            &crypto_frame(0, &client_hello_handshake("127.0.0.1")),
+        );
+        *corrupted.last_mut().unwrap() ^= 0xff;
+        assert!(manager.handle_packet(&corrupted, src(3)).await.is_err());
+
+        // ClientHello 没有 SNI
+        let no_sni = build_client_initial(
+            0x00000001,
+            &[0x61, 0x02, 1, 2, 3, 4, 5, 6],
+            0,
+            &crypto_frame(0, &client_hello_with_extensions(None, &[])),
+        );
+        assert!(!manager
+            .handle_packet(&no_sni, src(4))
+            .await
+            .unwrap()
+            .is_forwarded());
+
+        // 成功建立会话并转发两个包
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client_addr = client.local_addr().unwrap();
+        let initial = build_client_initial(
+            0x00000001,
+            &[0x61, 0x03, 1, 2, 3, 4, 5, 6],
+            0,
+            &crypto_frame(0, &client_hello_handshake("127.0.0.1")),
+        );
+        assert!(manager
+            .handle_packet(&initial, client_addr)
+            .await
+            .unwrap()
+            .is_forwarded());
+        tokio::time::timeout(Duration::from_secs(2), socks5.received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(manager
+            .handle_packet(&short, client_addr)
+            .await
+            .unwrap()
+            .is_forwarded());
+
+        let stats = manager.stats().await;
+        assert_eq!(stats.initial_errors.len(), QuicError::METRIC_LABELS.len());
+        assert_eq!(stats.initial_errors["not_initial"], 1);
+        assert_eq!(stats.initial_errors["unsupported_version"], 1);
+        assert_eq!(stats.initial_errors["decryption"], 1);
+        assert_eq!(stats.initial_errors["no_sni"], 1);
+        assert_eq!(stats.initial_errors["tls"], 0);
+        assert_eq!(stats.sessions_created, 1);
+        assert_eq!(stats.packets_forwarded, 2);
+    }
+
+    #[tokio::test]
+    async fn stalled_relay_drops_packets_without_blocking_other_sessions() {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let manager = test_manager(socket);
+        let slow_addr: SocketAddr = "192.0.2.1:50000".parse().unwrap();
+        let fast_addr: SocketAddr = "192.0.2.2:50000".parse().unwrap();
+
+        // slow 会话的 relay 从不读取队列 (容量 16)
+        let (slow, _slow_rx, _) = test_session(&[0x60, 0x01], slow_addr);
+        let (fast, mut fast_rx, _) = test_session(&[0x60, 0x02], fast_addr);
+        manager.insert_session(slow).await;
+        manager.insert_session(fast).await;
+
+        let mut pkt = vec![0x41, 0x60, 0x01];
+        pkt.extend_from_slice(&[0xaa; 24]);
+        tokio::time::timeout(Duration::from_secs(1), async {
+            for _ in 0..64 {
+                assert!(manager
+                    .handle_packet(&pkt, slow_addr)
+                    .await
+                    .unwrap()
+                    .is_forwarded());
+            }
+        })
+        .await
+        .expect("receive path blocked on a full session queue");
+
+        // 其它会话的包立即送达
+        assert!(manager
+            .handle_packet(&pkt, fast_addr)
+            .await
+            .unwrap()
+            .is_forwarded());
+        let forwarded = tokio::time::timeout(Duration::from_millis(100), fast_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(forwarded, pkt);
+
+        let snapshots = manager.snapshot();
+        let dropped = |addr| {
+            snapshots
+                .iter()
+                .find(|s| s.client == addr)
+                .unwrap()
+                .packets_dropped
+        };
+        assert_eq!(dropped(slow_addr), 48);
+        assert_eq!(dropped(fast_addr), 0);
+        assert_eq!(manager.stats().await.queue_full_drops, 48);
+    }
+
+    #[tokio::test]
+    async fn long_header_with_known_dcid_rebinds_session() {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let manager = test_manager(socket);
+        let dcid = [0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88];
+        let old_addr: SocketAddr = "192.0.2.2:40000".parse().unwrap();
+        let new_addr: SocketAddr = "198.51.100.7:40000".parse().unwrap();
+
+        let (session, mut rx, client_rx) = test_session(&dcid, old_addr);
+        manager.insert_session(session).await;
+
+        // Handshake 包 (Long Header, type=0b10)
+        let mut long_pkt = vec![0xe0, 0x00, 0x00, 0x00, 0x01, dcid.len() as u8];
+        long_pkt.extend_from_slice(&dcid);
+        long_pkt.extend_from_slice(&[0x00; 16]);
+
+        assert!(manager
+            .handle_packet(&long_pkt, new_addr)
+            .await
+            .unwrap()
+            .is_forwarded());
+        assert_eq!(rx.recv().await.unwrap(), long_pkt);
+        assert_eq!(*client_rx.borrow(), new_addr);
+    }
+
+    #[tokio::test]
+    async fn short_header_rebinds_by_each_indexed_dcid_length() {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let manager = test_manager(socket);
+        let short_dcid = [0xa1, 0xa2, 0xa3, 0xa4];
+        let long_dcid = [0xb1; 20];
+        let (short, _short_rx, short_client) =
+            test_session(&short_dcid, "192.0.2.3:40000".parse().unwrap());
+        let (long, mut long_rx, long_client) =
+            test_session(&long_dcid, "192.0.2.4:40000".parse().unwrap());
+        manager.insert_session(short).await;
+        manager.insert_session(long).await;
+
+        let new_addr: SocketAddr = "198.51.100.8:40000".parse().unwrap();
+        let mut packet = vec![0x40];
+        packet.extend_from_slice(&long_dcid);
+        packet.extend_from_slice(&[0x00; 24]);
+
+        assert!(manager
+            .handle_packet(&packet, new_addr)
+            .await
+            .unwrap()
+            .is_forwarded());
+        assert_eq!(long_rx.recv().await.unwrap(), packet);
+        assert_eq!(*long_client.borrow(), new_addr);
+        assert_ne!(*short_client.borrow(), new_addr);
+        assert_eq!(manager.dcid_index.get(&long_dcid), Some(new_addr));
+
+        // 未知的 DCID 不匹配任何会话
+        let mut unknown = vec![0x40];
+        unknown.extend_from_slice(&[0xc1; 24]);
+        assert!(!manager.rebind_by_dcid(&unknown, new_addr).await);
+    }
+
+    #[tokio::test]
+    async fn handle_packet_reports_outcome_variant() {
+        use crate::quic::test_util::{build_client_initial, client_hello_handshake, crypto_frame};
+        use crate::socks5::test_util::spawn_mock_udp_associate;
+
+        let mut socks5 = spawn_mock_udp_associate(Duration::ZERO).await;
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let listen_addr = socket.local_addr().unwrap();
+        let manager =
+            test_manager_with_rules(socket, socks5.addr, vec!["*.allowed.test".to_string()]);
+        let initial = |dcid: u8, sni: &str| {
+            build_client_initial(
+                0x00000001,
+                &[0x39, dcid, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07],
+                0,
+                &crypto_frame(0, &client_hello_handshake(sni)),
+            )
+        };
+        let client: SocketAddr = "192.0.2.130:5000".parse().unwrap();
+
+        // 不属于任何会话的 Short Header 包
+        let mut short = vec![0x41];
+        short.extend_from_slice(&[0xaa; 24]);
+        assert_eq!(
+            manager.handle_packet(&short, client).await.unwrap(),
+            PacketOutcome::IgnoredNotQuic
+        );
+
+        // 白名单拒绝
+        assert_eq!(
+            manager
+                .handle_packet(
+                    &initial(1, "www.blocked.test"),
+                    "192.0.2.131:5000".parse().unwrap()
+                )
+                .await
+                .unwrap(),
+            PacketOutcome::Rejected {
+                reason: RejectReason::NotWhitelisted
+            }
+        );
+
+        // 来自监听端自身
+        assert_eq!(
+            manager
+                .handle_packet(&initial(2, "www.allowed.test"), listen_addr)
+                .await
+                .unwrap(),
+            PacketOutcome::Rejected {
+                reason: RejectReason::Reflected
+            }
+        );
+
+        // 新流通过准入，会话建立后同一客户端的包交给已有会话
+        assert_eq!(
+            manager
+                .handle_packet(&initial(3, "www.allowed.test"), client)
+                .await
+                .unwrap(),
+            PacketOutcome::ForwardedNew {
+                sni: "www.allowed.test".to_string()
+            }
+        );
+        tokio::time::timeout(Duration::from_secs(2), socks5.received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            manager.handle_packet(&short, client).await.unwrap(),
+            PacketOutcome::ForwardedExisting
+        );
+    }
+
+    #[tokio::test]
+    async fn packet_results_are_counted_by_reason() {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let metrics = Arc::new(Registry::default());
+        let manager = test_manager(socket).with_metrics(Arc::clone(&metrics));
+
+        for reason in [RejectReason::NotWhitelisted, RejectReason::RateLimited] {
+            manager.record_packet(Some(&PacketOutcome::Rejected { reason }), 1200);
+        }
+        for error in [
+            QuicError::DecryptionFailed("bad tag".into()),
+            QuicError::TlsError(crate::tls::sni::SniError::NotHandshake),
+            QuicError::PacketTooShort {
+                expected: 2,
+                actual: 1,
+            },
+        ] {
+            manager.record_failed_packet(&error, 1200);
+        }
+
+        for reason in [
+            RejectReason::NotWhitelisted,
+            RejectReason::RateLimited,
+            RejectReason::MalformedClientHello,
+        ] {
+            assert_eq!(
+                metrics.rejections(Listener::Quic, reason),
+                1,
+                "{:?}",
+                reason
+            );
+        }
+        for reason in [
+            FailureReason::QuicDecryptFailed,
+            FailureReason::QuicMalformed,
+        ] {
+            assert_eq!(metrics.failures(Listener::Quic, reason), 1, "{:?}", reason);
+        }
+        let totals = metrics.totals().await;
+        assert_eq!(totals.rejections.values().sum::<u64>(), 3);
+        assert_eq!(totals.failures.values().sum::<u64>(), 2);
+    }
+
+    #[tokio::test]
+    async fn unknown_version_gets_version_negotiation() {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let manager = test_manager(Arc::clone(&socket));
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client_addr = client.local_addr().unwrap();
+
+        let mut packet = vec![0xc0, 0x1a, 0x2a, 0x3a, 0x4a, 4, 1, 2, 3, 4, 2, 9, 9];
+        packet.resize(1200, 0);
+
+        assert_eq!(
+            manager.handle_packet(&packet, client_addr).await.unwrap(),
+            PacketOutcome::UnsupportedVersion
+        );
+
+        let mut buf = [0u8; 64];
+        let (n, from) = tokio::time::timeout(Duration::from_secs(1), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(from, socket.local_addr().unwrap());
+        assert_eq!(
+            &buf[..n],
+            negotiation::build_version_negotiation(
+                &[1, 2, 3, 4],
+                &[9, 9],
+                negotiation::SUPPORTED_VERSIONS
+            )
+        );
+        assert_eq!(manager.session_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn blocked_initial_gets_connection_close() {
+        use crate::quic::crypto::derive_initial_keys_for_role;
+        use crate::quic::test_util::{build_client_initial, client_hello_handshake, crypto_frame};
+
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let manager = test_manager_with_rules(
+            Arc::clone(&socket),
+            "127.0.0.1:1080".parse().unwrap(),
+            vec!["*.allowed.test".to_string()],
+        );
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client_addr = client.local_addr().unwrap();
+
+        let dcid = [0x37, 0x12, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66];
+        let initial = build_client_initial(
+            0x00000001,
+            &dcid,
+            0,
+            &crypto_frame(0, &client_hello_handshake("blocked.test")),
+        );
+        let server_keys =
+            derive_initial_keys_for_role(&dcid, 0x00000001, InitialKeyRole::Server).unwrap();
+        let expected =
+            close::build_connection_close(0x00000001, &server_keys, &dcid, &[], 0x02).unwrap();
+
+        // 首次拒绝和命中负缓存的重传都会回复
+        for _ in 0..2 {
+            assert!(!manager
+                .handle_packet(&initial, client_addr)
+                .await
+                .unwrap()
+                .is_forwarded());
+
+            let mut buf = [0u8; 1500];
+            let (n, from) =
+                tokio::time::timeout(Duration::from_secs(1), client.recv_from(&mut buf))
+                    .await
+                    .unwrap()
+                    .unwrap();
+            assert_eq!(from, socket.local_addr().unwrap());
+            assert_eq!(&buf[..n], expected);
+        }
+        assert_eq!(manager.stats().await.sni_extractions, 1);
+        assert_eq!(manager.session_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn packets_during_session_creation_are_buffered_and_flushed_in_order() {
+        use crate::quic::test_util::{build_client_initial, client_hello_handshake, crypto_frame};
+        use crate::socks5::test_util::spawn_mock_udp_associate;
+
+        let mut socks5 = spawn_mock_udp_associate(Duration::from_millis(200)).await;
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let manager = test_manager_with_socks5(socket, socks5.addr);
+        let client_addr: SocketAddr = "192.0.2.10:5000".parse().unwrap();
+
+        let dcid = [0x36, 0x08, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66];
+        let initial = build_client_initial(
+            0x00000001,
+            &dcid,
+            0,
+            &crypto_frame(0, &client_hello_handshake("127.0.0.1")),
+        );
+        let follow_ups: Vec<Vec<u8>> = (1u8..=3).map(|i| vec![0x40 | i; 64]).collect();
+
+        assert!(manager
+            .handle_packet(&initial, client_addr)
+            .await
+            .unwrap()
+            .is_forwarded());
+
+        // 在模拟 ASSOCIATE 延迟期间到达的包应被暂存
+        for pkt in &follow_ups {
+            assert!(manager
+                .handle_packet(pkt, client_addr)
+                .await
+                .unwrap()
+                .is_forwarded());
+        }
+        assert_eq!(manager.session_count(), 0);
+
+        let mut expected = vec![initial];
+        expected.extend(follow_ups);
+        for want in expected {
+            let (target, got) =
+                tokio::time::timeout(Duration::from_secs(2), socks5.received.recv())
+                    .await
+                    .unwrap()
+                    .unwrap();
+            assert_eq!(target, TargetAddr::Ip("127.0.0.1:443".parse().unwrap()));
+            assert_eq!(got, want);
+        }
+        assert_eq!(manager.session_count(), 1);
+        assert_eq!(socks5.associations.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn buffered_packets_beyond_queue_capacity_are_dropped_not_failed() {
+        use crate::quic::test_util::{build_client_initial, client_hello_handshake, crypto_frame};
+        use crate::socks5::test_util::spawn_mock_udp_associate;
+
+        let mut socks5 = spawn_mock_udp_associate(Duration::from_millis(100)).await;
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let manager = test_manager_with_config(
+            socket,
+            socks5.addr,
+            Vec::new(),
+            QuicSessionConfig {
+                session_queue_capacity: 2,
+                ..QuicSessionConfig::default()
+            },
+        );
+        let metrics = Arc::new(Registry::default());
+        let manager = manager.with_metrics(Arc::clone(&metrics));
+        let client_addr: SocketAddr = "192.0.2.11:5000".parse().unwrap();
+
+        let dcid = [0x36, 0x09, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66];
+        let initial = build_client_initial(
+            0x00000001,
+            &dcid,
+            0,
+            &crypto_frame(0, &client_hello_handshake("127.0.0.1")),
+        );
+        assert!(manager
+            .handle_packet(&initial, client_addr)
+            .await
+            .unwrap()
+            .is_forwarded());
+        let follow_ups: Vec<Vec<u8>> = (1u8..=5).map(|i| vec![0x40 | i; 64]).collect();
+        for pkt in &follow_ups {
+            manager.handle_packet(pkt, client_addr).await.unwrap();
+        }
+
+        // 队列只容得下前两个包，其余按队列满丢弃；会话照常建立
+        for want in [&initial, &follow_ups[0]] {
+            let (_, got) = tokio::time::timeout(Duration::from_secs(2), socks5.received.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&got, want);
+        }
+        assert_eq!(manager.session_count(), 1);
+        let stats = manager.stats().await;
+        assert_eq!(stats.queue_full_drops, 4);
+        assert_eq!(stats.sessions_created, 1);
+        assert_eq!(
+            metrics.failures(Listener::Quic, FailureReason::QuicSessionFailed),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn client_hello_split_across_initials_creates_session() {
+        use crate::quic::test_util::{
+            build_client_initial, crypto_frame, large_client_hello_handshake,
+        };
+        use crate::socks5::test_util::spawn_mock_udp_associate;
+
+        let mut socks5 = spawn_mock_udp_associate(Duration::ZERO).await;
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let manager = test_manager_with_socks5(socket, socks5.addr);
+        let client_addr: SocketAddr = "192.0.2.12:5000".parse().unwrap();
+
+        // 带 1216 字节 key share 的 ClientHello，拆到两个 Initial 中
+        let dcid = [0x36, 0x0a, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66];
+        let hello = large_client_hello_handshake("127.0.0.1", 1216);
+        let (head, tail) = hello.split_at(1000);
+        let first = build_client_initial(0x00000001, &dcid, 0, &crypto_frame(0, head));
+        let second =
+            build_client_initial(0x00000001, &dcid, 1, &crypto_frame(head.len() as u64, tail));
+
+        assert!(manager
+            .handle_packet(&first, client_addr)
+            .await
+            .unwrap()
+            .is_forwarded());
+        assert_eq!(manager.session_count(), 0);
+        assert_eq!(manager.stats().await.incomplete_client_hellos, 1);
+
+        assert!(manager
+            .handle_packet(&second, client_addr)
+            .await
+            .unwrap()
+            .is_forwarded());
+
+        // 上游按序收到两个 Initial，才能拼出完整的 ClientHello
+        for want in [first, second] {
+            let (target, got) =
+                tokio::time::timeout(Duration::from_secs(2), socks5.received.recv())
+                    .await
+                    .unwrap()
+                    .unwrap();
+            assert_eq!(target, TargetAddr::Ip("127.0.0.1:443".parse().unwrap()));
+            assert_eq!(got, want);
+        }
+        assert_eq!(manager.session_count(), 1);
+
+        let stats = manager.stats().await;
+        assert_eq!(stats.incomplete_client_hellos, 0);
+        assert_eq!(stats.sni_extractions, 2);
+    }
+
+    #[tokio::test]
+    async fn ech_without_sni_follows_policy() {
+        use crate::quic::test_util::{
+            build_client_initial, crypto_frame, ech_client_hello_handshake,
+        };
+        use crate::socks5::test_util::spawn_mock_udp_associate;
+
+        let dcid = [0x3e, 0xc0, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66];
+        let initial = build_client_initial(
+            0x00000001,
+            &dcid,
+            0,
+            &crypto_frame(0, &ech_client_hello_handshake(None)),
+        );
+        let client_addr: SocketAddr = "192.0.2.14:5000".parse().unwrap();
+
+        // 默认拒绝
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let manager = test_manager(socket);
+        assert!(!manager
+            .handle_packet(&initial, client_addr)
+            .await
+            .unwrap()
+            .is_forwarded());
+        assert_eq!(manager.session_count(), 0);
+        assert_eq!(manager.stats().await.ech_sessions, 0);
+
+        // 配置了默认目标时转发过去
+        let mut socks5 = spawn_mock_udp_associate(Duration::ZERO).await;
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let config = QuicSessionConfig {
+            ech_default_target: Some("127.0.0.1".to_string()),
+            ..Default::default()
+        };
+        let manager = test_manager_with_config(socket, socks5.addr, Vec::new(), config);
+        assert!(manager
+            .handle_packet(&initial, client_addr)
+            .await
+            .unwrap()
+            .is_forwarded());
+
+        let (target, got) = tokio::time::timeout(Duration::from_secs(2), socks5.received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(target, TargetAddr::Ip("127.0.0.1:443".parse().unwrap()));
+        assert_eq!(got, initial);
+
+        let snapshot = manager.snapshot();
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot[0].sni, "127.0.0.1");
+        assert!(snapshot[0].ech);
+        assert_eq!(manager.stats().await.ech_sessions, 1);
+    }
+
+    #[tokio::test]
+    async fn require_alpn_rejects_non_h3_clients() {
+        use crate::quic::test_util::{
+            alpn_client_hello_handshake, build_client_initial, crypto_frame,
+        };
+        use crate::socks5::test_util::spawn_mock_udp_associate;
+
+        let mut socks5 = spawn_mock_udp_associate(Duration::ZERO).await;
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let config = QuicSessionConfig {
+            require_alpn: vec!["h3".to_string()],
+            allow_missing_alpn: false,
+            ..Default::default()
+        };
+        let manager = test_manager_with_config(socket, socks5.addr, Vec::new(), config);
+
+        let initial = |dcid: u8, protocols: &[&str]| {
+            let hello = alpn_client_hello_handshake("127.0.0.1", protocols);
+            let dcid = [0x3a, dcid, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66];
+            build_client_initial(0x00000001, &dcid, 0, &crypto_frame(0, &hello))
+        };
+
+        // 非 h3 和没有 ALPN 的客户端被拒绝
+        let h2 = "192.0.2.15:5000".parse().unwrap();
+        assert!(!manager
+            .handle_packet(&initial(1, &["h2", "http/1.1"]), h2)
+            .await
+            .unwrap()
+            .is_forwarded());
+        let none = "192.0.2.15:5001".parse().unwrap();
+        assert!(!manager
+            .handle_packet(&initial(2, &[]), none)
+            .await
+            .unwrap()
+            .is_forwarded());
+        assert_eq!(manager.session_count(), 0);
+        assert_eq!(manager.stats().await.rejected_alpn, 2);
+
+        // 任一 ALPN 命中即可
+        let h3 = "192.0.2.15:5002".parse().unwrap();
+        let packet = initial(3, &["h3-29", "h3"]);
+        assert!(manager
+            .handle_packet(&packet, h3)
+            .await
+            .unwrap()
+            .is_forwarded());
+        let (_, got) = tokio::time::timeout(Duration::from_secs(2), socks5.received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(got, packet);
+
+        let snapshot = manager.snapshot();
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(
+            snapshot[0].alpn,
+            vec!["h3-29".to_string(), "h3".to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn retry_keeps_handshake_alive_across_rebind_and_expiry() {
+        use crate::quic::test_util::{
+            build_client_initial, build_client_initial_with_token, client_hello_handshake,
+            crypto_frame,
+        };
+        use crate::socks5::test_util::spawn_mock_udp_associate;
+
+        let mut socks5 = spawn_mock_udp_associate(Duration::ZERO).await;
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let config = QuicSessionConfig {
+            idle_timeout: Duration::from_millis(300),
+            ..Default::default()
+        };
+        let manager = test_manager_with_config(socket, socks5.addr, Vec::new(), config);
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client_addr = client.local_addr().unwrap();
+        let target = TargetAddr::Ip("127.0.0.1:443".parse().unwrap());
+
+        let first = build_client_initial(
+            0x00000001,
+            &[0x7e, 0x70, 1, 2, 3, 4, 5, 6],
+            0,
+            &crypto_frame(0, &client_hello_handshake("127.0.0.1")),
+        );
+        assert!(manager
+            .handle_packet(&first, client_addr)
+            .await
+            .unwrap()
+            .is_forwarded());
+        tokio::time::timeout(Duration::from_secs(2), socks5.received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+
+        // 源站回复 Retry，新 DCID 为 Retry 的 SCID
+        let new_dcid = [0x7e, 0x71, 9, 9, 9, 9, 9, 9];
+        let mut retry = vec![0xf0, 0x00, 0x00, 0x00, 0x01, 0x00, new_dcid.len() as u8];
+        retry.extend_from_slice(&new_dcid);
+        retry.extend_from_slice(b"retry-token");
+        retry.extend_from_slice(&[0u8; 16]);
+        socks5.reply(target.clone(), &retry).await;
+        let mut buf = [0u8; 1500];
+        let n = tokio::time::timeout(Duration::from_secs(2), client.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..n], &retry[..]);
+
+        // NAT 重绑定后带 Token 的 Initial (不含 ClientHello) 仍交给原会话
+        let rebound: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let with_token = |pn| {
+            build_client_initial_with_token(0x00000001, &new_dcid, b"retry-token", pn, &[0x01])
+        };
+        let packet = with_token(1);
+        assert!(manager
+            .handle_packet(&packet, rebound)
+            .await
+            .unwrap()
+            .is_forwarded());
+        let (got_target, got) =
+            tokio::time::timeout(Duration::from_secs(2), socks5.received.recv())
+                .await
+                .unwrap()
+                .unwrap();
+        assert_eq!((got_target, got), (target.clone(), packet));
+        assert_eq!(manager.snapshot()[0].client, rebound);
+        // 原 DCID 和 Retry 后的 DCID 都指向新地址
+        assert_eq!(
+            manager.dcid_index.get(&[0x7e, 0x70, 1, 2, 3, 4, 5, 6]),
+            Some(rebound)
+        );
+        assert_eq!(manager.dcid_index.get(&new_dcid), Some(rebound));
+
+        // 会话恰好过期：带 Token 的 Initial 按原路由重新建立会话，而不是被当作无 SNI 拒绝
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert_eq!(manager.cleanup_expired_sessions().await, 1);
+        let packet = with_token(2);
+        assert!(manager
+            .handle_packet(&packet, rebound)
+            .await
+            .unwrap()
+            .is_forwarded());
+        let (got_target, got) =
+            tokio::time::timeout(Duration::from_secs(2), socks5.received.recv())
+                .await
+                .unwrap()
+                .unwrap();
+        assert_eq!((got_target, got), (target, packet));
+        assert_eq!(manager.session_count(), 1);
+        assert_eq!(manager.snapshot()[0].sni, "127.0.0.1");
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_relays_and_notifies_clients() {
+        use crate::quic::parser::parse_initial_header;
+        use crate::quic::test_util::{build_client_initial, client_hello_handshake, crypto_frame};
+        use crate::socks5::test_util::spawn_mock_udp_associate;
+
+        let mut socks5 = spawn_mock_udp_associate(Duration::ZERO).await;
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let manager = test_manager_with_socks5(socket, socks5.addr);
+        let cleanup = manager.spawn_cleanup_task();
+
+        let mut clients = Vec::new();
+        for i in 0..2u8 {
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let dcid = [0x5d, i, 1, 2, 3, 4, 5, 6];
+            let initial = build_client_initial(
+                0x00000001,
+                &dcid,
+                0,
+                &crypto_frame(0, &client_hello_handshake("127.0.0.1")),
+            );
+            let addr = client.local_addr().unwrap();
+            assert!(manager
+                .handle_packet(&initial, addr)
+                .await
+                .unwrap()
+                .is_forwarded());
+            tokio::time::timeout(Duration::from_secs(2), socks5.received.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            clients.push((client, dcid));
+        }
+        assert_eq!(socks5.open_controls.load(Ordering::SeqCst), 2);
+
+        manager.shutdown(Duration::from_secs(1)).await;
+        assert_eq!(manager.session_count(), 0);
+        tokio::time::timeout(Duration::from_secs(1), cleanup)
+            .await
+            .expect("cleanup task did not stop")
+            .unwrap();
+
+        // 每个客户端都收到以其原始 DCID 为 SCID 的 CONNECTION_CLOSE
+        for (client, dcid) in &clients {
+            let mut buf = [0u8; 1500];
+            let n = tokio::time::timeout(Duration::from_secs(2), client.recv(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            let header = parse_initial_header(&buf[..n]).unwrap();
+            assert_eq!(&header.scid[..], &dcid[..]);
         }
+
+        // 会话任务已退出，SOCKS5 控制连接全部关闭
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while socks5.open_controls.load(Ordering::SeqCst) != 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
     }
 
-    if let Some(e) = last_error {
-        Err(e)
-    } else {
-        Err(anyhow!(
-            "No A/AAAA record for {}:{} from DNS server {}",
-            host,
-            port,
-            dns_server
-        ))
+    #[tokio::test]
+    async fn incomplete_client_hello_expires() {
+        use crate::quic::test_util::{
+            build_client_initial, crypto_frame, large_client_hello_handshake,
+        };
+
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let config = QuicSessionConfig {
+            pending_ttl: Duration::from_millis(20),
+            ..Default::default()
+        };
+        let manager = test_manager_with_config(
+            socket,
+            "127.0.0.1:1080".parse().unwrap(),
+            Vec::new(),
+            config,
+        );
+        let client_addr: SocketAddr = "192.0.2.13:5000".parse().unwrap();
+
+        let dcid = [0x36, 0x0b, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66];
+        let hello = large_client_hello_handshake("127.0.0.1", 1216);
+        let first = build_client_initial(0x00000001, &dcid, 0, &crypto_frame(0, &hello[..1000]));
+        assert!(manager
+            .handle_packet(&first, client_addr)
+            .await
+            .unwrap()
+            .is_forwarded());
+        assert_eq!(manager.stats().await.incomplete_client_hellos, 1);
+
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        manager.cleanup_expired_sessions().await;
+        assert_eq!(manager.stats().await.incomplete_client_hellos, 0);
     }
-}
 
-async fn query_socks5_udp_dns_once(
-    host: &str,
-    port: u16,
-    dns_server: SocketAddr,
-    qtype: u16,
-    socks5_config: &Socks5Config,
-) -> Result<Option<SocketAddr>> {
-    let query = build_dns_query(host, qtype)?;
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn concurrent_initials_create_a_single_session() {
+        use crate::quic::test_util::{build_client_initial, client_hello_handshake, crypto_frame};
+        use crate::socks5::test_util::spawn_mock_udp_associate;
 
-    let udp_client = if let (Some(username), Some(password)) =
-        (&socks5_config.username, &socks5_config.password)
-    {
-        Socks5UdpClient::new(socks5_config.addr.to_string())
-            .with_auth(username.clone(), password.clone())
-            .with_timeout(Duration::from_secs(socks5_config.timeout))
-    } else {
-        Socks5UdpClient::new(socks5_config.addr.to_string())
-            .with_timeout(Duration::from_secs(socks5_config.timeout))
-    };
-    let (relay, _) = udp_client.associate().await?;
-    relay.send_to(&query, dns_server).await?;
+        let mut socks5 = spawn_mock_udp_associate(Duration::from_millis(100)).await;
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let manager = test_manager_with_socks5(socket, socks5.addr);
 
-    let mut response = [0u8; 1500];
-    let (len, _) = tokio::time::timeout(Duration::from_secs(3), relay.recv_from(&mut response))
+        let dcid = [0x36, 0x09, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66];
+        let hello = crypto_frame(0, &client_hello_handshake("127.0.0.1"));
+        let first = build_client_initial(0x00000001, &dcid, 0, &hello);
+        let retransmit = build_client_initial(0x00000001, &dcid, 1, &hello);
+
+        // 同一客户端的两个 Initial 并发处理，以及同一 DCID 从另一个地址到达
+        let client_addr: SocketAddr = "192.0.2.20:5000".parse().unwrap();
+        let rebound_addr: SocketAddr = "192.0.2.20:5001".parse().unwrap();
+        let barrier = Arc::new(tokio::sync::Barrier::new(3));
+        let tasks: Vec<_> = [
+            (first, client_addr),
+            (retransmit.clone(), client_addr),
+            (retransmit, rebound_addr),
+        ]
+        .into_iter()
+        .map(|(packet, addr)| {
+            let manager = manager.clone();
+            let barrier = Arc::clone(&barrier);
+            tokio::spawn(async move {
+                barrier.wait().await;
+                manager.handle_packet(&packet, addr).await
+            })
+        })
+        .collect();
+        for task in tasks {
+            assert!(task.await.unwrap().unwrap().is_forwarded());
+        }
+
+        for _ in 0..3 {
+            tokio::time::timeout(Duration::from_secs(2), socks5.received.recv())
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        assert_eq!(socks5.associations.load(Ordering::SeqCst), 1);
+        assert_eq!(manager.session_count(), 1);
+        assert!(manager.inner.lock().await.pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn repeated_blocked_initial_is_decrypted_once() {
+        use crate::quic::test_util::{build_client_initial, client_hello_handshake, crypto_frame};
+
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let manager = test_manager_with_rules(
+            socket,
+            "127.0.0.1:1080".parse().unwrap(),
+            vec!["*.allowed.test".to_string()],
+        );
+        let client_addr: SocketAddr = "192.0.2.30:5000".parse().unwrap();
+
+        let dcid = [0x36, 0x12, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66];
+        let initial = build_client_initial(
+            0x00000001,
+            &dcid,
+            0,
+            &crypto_frame(0, &client_hello_handshake("blocked.test")),
+        );
+
+        for _ in 0..100 {
+            assert!(!manager
+                .handle_packet(&initial, client_addr)
+                .await
+                .unwrap()
+                .is_forwarded());
+        }
+        assert_eq!(manager.stats().await.sni_extractions, 1);
+        assert_eq!(manager.session_count(), 0);
+
+        // 其他客户端的同一 DCID 不受影响
+        let other: SocketAddr = "192.0.2.31:5000".parse().unwrap();
+        assert!(!manager
+            .handle_packet(&initial, other)
+            .await
+            .unwrap()
+            .is_forwarded());
+        assert_eq!(manager.stats().await.sni_extractions, 2);
+    }
+
+    #[tokio::test]
+    async fn initial_flood_from_one_ip_is_rate_limited() {
+        use crate::quic::test_util::{build_client_initial, client_hello_handshake, crypto_frame};
+
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let manager = test_manager_with_config(
+            socket,
+            "127.0.0.1:1080".parse().unwrap(),
+            vec!["*.allowed.test".to_string()],
+            QuicSessionConfig {
+                initial_rate: 1.0,
+                initial_burst: 5,
+                ..Default::default()
+            },
+        );
+        let frames = crypto_frame(0, &client_hello_handshake("blocked.test"));
+        let initial = |i: u16| {
+            let dcid = [0x36, 0x24, (i >> 8) as u8, i as u8];
+            build_client_initial(0x00000001, &dcid, 0, &frames)
+        };
+
+        // 每个包使用不同的 DCID 和端口，负缓存无法拦截
+        for i in 0..200u16 {
+            let src = SocketAddr::from(([192, 0, 2, 40], 1000 + i));
+            assert!(!manager
+                .handle_packet(&initial(i), src)
+                .await
+                .unwrap()
+                .is_forwarded());
+        }
+        let stats = manager.stats().await;
+        assert!(stats.sni_extractions <= 6, "{:?}", stats);
+        assert!(stats.rate_limited_initials >= 194, "{:?}", stats);
+
+        // 其他来源 IP 不受影响
+        let other = SocketAddr::from(([192, 0, 2, 41], 1000));
+        manager.handle_packet(&initial(1000), other).await.unwrap();
+        assert_eq!(
+            manager.stats().await.sni_extractions,
+            stats.sni_extractions + 1
+        );
+    }
+
+    fn limited_manager(
+        socket: Arc<UdpSocket>,
+        max_sessions: usize,
+        max_sessions_per_ip: usize,
+        evict_idle_on_full: bool,
+    ) -> QuicSessionManager {
+        test_manager_with_config(
+            socket,
+            "127.0.0.1:1080".parse().unwrap(),
+            Vec::new(),
+            QuicSessionConfig {
+                max_sessions,
+                max_sessions_per_ip,
+                evict_idle_on_full,
+                ..Default::default()
+            },
+        )
+    }
+
+    fn allowed_initial(dcid: &[u8]) -> Vec<u8> {
+        use crate::quic::test_util::{build_client_initial, client_hello_handshake, crypto_frame};
+
+        build_client_initial(
+            0x00000001,
+            dcid,
+            0,
+            &crypto_frame(0, &client_hello_handshake("127.0.0.1")),
+        )
+    }
+
+    #[tokio::test]
+    async fn initial_over_session_limit_is_refused() {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let manager = limited_manager(socket, 2, 64, false);
+
+        let mut receivers = Vec::new();
+        for (i, addr) in ["192.0.2.40:1000", "192.0.2.41:1000"].iter().enumerate() {
+            let (session, rx, _) = test_session(&[0x40, i as u8], addr.parse().unwrap());
+            manager.insert_session(session).await;
+            receivers.push(rx);
+        }
+
+        let initial = allowed_initial(&[0x36, 0x14, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06]);
+        let src: SocketAddr = "192.0.2.42:1000".parse().unwrap();
+        assert!(!manager
+            .handle_packet(&initial, src)
+            .await
+            .unwrap()
+            .is_forwarded());
+
+        let stats = manager.stats().await;
+        assert_eq!(stats.active_sessions, 2);
+        assert_eq!(stats.pending_sessions, 0);
+        assert_eq!(stats.rejected_over_limit, 1);
+        assert_eq!(stats.evicted_for_capacity, 0);
+    }
+
+    #[tokio::test]
+    async fn idlest_session_is_evicted_when_full() {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let manager = limited_manager(socket, 2, 64, true);
+
+        let idle_addr: SocketAddr = "192.0.2.50:1000".parse().unwrap();
+        let (mut idle, mut idle_rx, _) = test_session(&[0x50, 0x01], idle_addr);
+        idle.created_at = Instant::now() - Duration::from_secs(30);
+        manager.insert_session(idle).await;
+
+        let busy_addr: SocketAddr = "192.0.2.51:1000".parse().unwrap();
+        let (busy, mut busy_rx, _) = test_session(&[0x50, 0x02], busy_addr);
+        manager.insert_session(busy).await;
+
+        let initial = allowed_initial(&[0x36, 0x14, 0x11, 0x02, 0x03, 0x04, 0x05, 0x06]);
+        let src: SocketAddr = "192.0.2.52:1000".parse().unwrap();
+        assert!(manager
+            .handle_packet(&initial, src)
+            .await
+            .unwrap()
+            .is_forwarded());
+
+        // 最久未活动的会话被移除，其通道随之关闭
+        assert!(idle_rx.recv().await.is_none());
+        assert!(busy_rx.try_recv().is_err());
+        assert!(manager.has_session(busy_addr));
+        assert!(!manager.has_session(idle_addr));
+
+        let stats = manager.stats().await;
+        assert_eq!(stats.evicted_for_capacity, 1);
+        assert_eq!(stats.rejected_over_limit, 0);
+    }
+
+    #[tokio::test]
+    async fn initial_over_per_ip_limit_is_refused() {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let manager = limited_manager(socket, 4096, 1, true);
+
+        let (session, rx, _) = test_session(&[0x60, 0x01], "192.0.2.60:1000".parse().unwrap());
+        manager.insert_session(session).await;
+
+        let initial = allowed_initial(&[0x36, 0x14, 0x21, 0x02, 0x03, 0x04, 0x05, 0x06]);
+        let same_ip: SocketAddr = "192.0.2.60:1001".parse().unwrap();
+        assert!(!manager
+            .handle_packet(&initial, same_ip)
+            .await
+            .unwrap()
+            .is_forwarded());
+        assert_eq!(manager.stats().await.rejected_over_limit, 1);
+
+        // 会话移除后该 IP 的计数随之减少，可以再建会话
+        drop(rx);
+        assert_eq!(manager.cleanup_expired_sessions().await, 1);
+        assert!(manager.inner.lock().await.per_ip.is_empty());
+        manager.handle_packet(&initial, same_ip).await.unwrap();
+        assert_eq!(manager.stats().await.rejected_over_limit, 1);
+    }
+
+    #[tokio::test]
+    async fn dead_relay_session_is_replaced_by_next_initial() {
+        use crate::socks5::test_util::spawn_mock_udp_associate;
+
+        let mut socks5 = spawn_mock_udp_associate(Duration::ZERO).await;
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let manager = test_manager_with_socks5(socket, socks5.addr);
+        let client_addr: SocketAddr = "192.0.2.70:5000".parse().unwrap();
+        let initial = allowed_initial(&[0x36, 0x15, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06]);
+
+        assert!(manager
+            .handle_packet(&initial, client_addr)
+            .await
+            .unwrap()
+            .is_forwarded());
+        tokio::time::timeout(Duration::from_secs(2), socks5.received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(manager.session_count(), 1);
+
+        // relay 消失后，下一次发送触发 ICMP 不可达，会话任务随之退出
+        socks5.kill_relays();
+        tokio::time::timeout(Duration::from_secs(2), async {
+            loop {
+                manager
+                    .handle_packet(&[0x40; 64], client_addr)
+                    .await
+                    .unwrap();
+                let dead = manager
+                    .sessions
+                    .get(&client_addr)
+                    .is_none_or(|s| s.tx.is_closed());
+                if dead {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
         .await
-        .map_err(|_| {
-            anyhow!(
-                "SOCKS5 UDP DNS query for {} timed out via {}",
-                host,
-                dns_server
+        .unwrap();
+
+        // 客户端重传的 Initial 立即建立新会话
+        assert!(manager
+            .handle_packet(&initial, client_addr)
+            .await
+            .unwrap()
+            .is_forwarded());
+        let (_, payload) = tokio::time::timeout(Duration::from_secs(2), socks5.received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(payload, initial);
+        assert_eq!(socks5.associations.load(Ordering::SeqCst), 2);
+        assert_eq!(manager.session_count(), 1);
+    }
+
+    /// 建立一个经模拟 SOCKS5 的会话，返回 (管理器, 客户端地址)
+    async fn relay_failure_session(
+        socks5: &mut crate::socks5::test_util::MockUdpAssociate,
+        relay_failure: RelayFailureMode,
+        dcid: &[u8],
+    ) -> (QuicSessionManager, SocketAddr) {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let manager = test_manager_with_config(
+            socket,
+            socks5.addr,
+            Vec::new(),
+            QuicSessionConfig {
+                relay_failure,
+                ..Default::default()
+            },
+        );
+        let client_addr: SocketAddr = "192.0.2.95:5000".parse().unwrap();
+        assert!(manager
+            .handle_packet(&allowed_initial(dcid), client_addr)
+            .await
+            .unwrap()
+            .is_forwarded());
+        tokio::time::timeout(Duration::from_secs(2), socks5.received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        (manager, client_addr)
+    }
+
+    #[tokio::test]
+    async fn relay_control_close_tears_session_down() {
+        use crate::socks5::test_util::spawn_mock_udp_associate;
+
+        let mut socks5 = spawn_mock_udp_associate(Duration::ZERO).await;
+        let (manager, client_addr) = relay_failure_session(
+            &mut socks5,
+            RelayFailureMode::Teardown,
+            &[0x36, 0x25, 0x01, 0x02],
+        )
+        .await;
+
+        // 不发送任何包也能在控制连接关闭后立即发现
+        socks5.kill_relays();
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while !manager.sessions.get(&client_addr).unwrap().tx.is_closed() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        assert!(!manager
+            .handle_packet(&[0x40; 64], client_addr)
+            .await
+            .unwrap()
+            .is_forwarded());
+        assert_eq!(manager.session_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn relay_control_close_reassociates_session() {
+        use crate::socks5::test_util::spawn_mock_udp_associate;
+
+        let mut socks5 = spawn_mock_udp_associate(Duration::ZERO).await;
+        let (manager, client_addr) = relay_failure_session(
+            &mut socks5,
+            RelayFailureMode::Reconnect,
+            &[0x36, 0x25, 0x03, 0x04],
+        )
+        .await;
+
+        socks5.kill_relays();
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while socks5.associations.load(Ordering::SeqCst) < 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // 同一个会话经新的 relay 继续转发到原目标
+        assert!(manager
+            .handle_packet(&[0x40; 64], client_addr)
+            .await
+            .unwrap()
+            .is_forwarded());
+        let (target, payload) =
+            tokio::time::timeout(Duration::from_secs(2), socks5.received.recv())
+                .await
+                .unwrap()
+                .unwrap();
+        assert_eq!(target, TargetAddr::Ip("127.0.0.1:443".parse().unwrap()));
+        assert_eq!(payload, vec![0x40; 64]);
+        assert_eq!(manager.session_count(), 1);
+        assert_eq!(manager.relay_addrs.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn unresponsive_relay_is_declared_dead_and_reassociated() {
+        use crate::socks5::test_util::spawn_mock_udp_associate;
+
+        let mut socks5 = spawn_mock_udp_associate(Duration::ZERO).await;
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let manager = test_manager_with_config(
+            socket,
+            socks5.addr,
+            Vec::new(),
+            QuicSessionConfig {
+                relay_failure: RelayFailureMode::Reconnect,
+                relay_probe_interval: Some(Duration::from_millis(150)),
+                ..Default::default()
+            },
+        );
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client_addr = client.local_addr().unwrap();
+        assert!(manager
+            .handle_packet(&allowed_initial(&[0x36, 0x26, 0x01, 0x02]), client_addr)
+            .await
+            .unwrap()
+            .is_forwarded());
+        tokio::time::timeout(Duration::from_secs(2), socks5.received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+
+        // 得到回包后空闲的会话不会被判定失效
+        let target = TargetAddr::Ip("127.0.0.1:443".parse().unwrap());
+        socks5.reply(target, b"pong").await;
+        let mut buf = [0u8; 64];
+        let n = tokio::time::timeout(Duration::from_secs(1), client.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..n], b"pong");
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert_eq!(socks5.associations.load(Ordering::SeqCst), 1);
+        assert_eq!(manager.stats().await.relays_declared_dead, 0);
+
+        // relay 不再回包、控制连接仍然打开：超过检测间隔后重新关联
+        assert!(manager
+            .handle_packet(&[0x40; 64], client_addr)
+            .await
+            .unwrap()
+            .is_forwarded());
+        tokio::time::timeout(Duration::from_secs(2), socks5.received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while socks5.associations.load(Ordering::SeqCst) < 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(manager.stats().await.relays_declared_dead, 1);
+        assert_eq!(manager.session_count(), 1);
+        // 旧 relay 的控制连接随之关闭
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while socks5.open_controls.load(Ordering::SeqCst) != 1 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn existing_session_is_forwarded_without_manager_lock() {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let manager = test_manager(socket);
+        let client_addr: SocketAddr = "192.0.2.80:1000".parse().unwrap();
+        let (mut session, mut rx, _) = test_session(&[0x80, 0x01], client_addr);
+        session.created_at = Instant::now() - Duration::from_secs(30);
+        manager.insert_session(session).await;
+
+        // 创建/清理路径持有互斥锁时，已有会话的包照常转发并刷新活跃时间
+        let _guard = manager.inner.lock().await;
+        let forwarded = tokio::time::timeout(
+            Duration::from_secs(1),
+            manager.handle_packet(&[0x40; 32], client_addr),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert!(forwarded.is_forwarded());
+        assert_eq!(rx.recv().await.unwrap(), vec![0x40; 32]);
+        let session = manager.sessions.get(&client_addr).unwrap();
+        assert!(session.idle_time() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn remote_resolve_sends_sni_as_domain_target() {
+        use crate::quic::test_util::{build_client_initial, client_hello_handshake, crypto_frame};
+        use crate::socks5::test_util::spawn_mock_udp_associate;
+
+        let mut socks5 = spawn_mock_udp_associate(Duration::ZERO).await;
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let manager =
+            test_manager_with_rules(socket, socks5.addr, vec!["quic.example.test".to_string()]);
+        let initial = build_client_initial(
+            0x00000001,
+            &[0x36, 0x18, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06],
+            0,
+            &crypto_frame(0, &client_hello_handshake("quic.example.test")),
+        );
+
+        // 不做本地解析：.test 域名无法解析，会话仍能建立
+        let client_addr: SocketAddr = "192.0.2.90:5000".parse().unwrap();
+        assert!(manager
+            .handle_packet(&initial, client_addr)
+            .await
+            .unwrap()
+            .is_forwarded());
+        let (target, payload) =
+            tokio::time::timeout(Duration::from_secs(2), socks5.received.recv())
+                .await
+                .unwrap()
+                .unwrap();
+        assert_eq!(
+            target,
+            TargetAddr::Domain("quic.example.test".to_string(), 443)
+        );
+        assert_eq!(payload, initial);
+    }
+
+    #[tokio::test]
+    async fn unresponsive_shared_relay_ends_its_sessions() {
+        use crate::quic::test_util::{build_client_initial, client_hello_handshake, crypto_frame};
+        use crate::socks5::test_util::spawn_mock_udp_associate;
+
+        let mut socks5 = spawn_mock_udp_associate(Duration::ZERO).await;
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let manager = test_manager_with_config(
+            socket,
+            socks5.addr,
+            Vec::new(),
+            QuicSessionConfig {
+                shared_relays: 1,
+                relay_probe_interval: Some(Duration::from_millis(150)),
+                ..Default::default()
+            },
+        );
+        let initial = build_client_initial(
+            0x00000001,
+            &[0x36, 0x41, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06],
+            0,
+            &crypto_frame(0, &client_hello_handshake("127.0.0.2")),
+        );
+        let client_addr: SocketAddr = "127.0.0.1:40001".parse().unwrap();
+        assert!(manager
+            .handle_packet(&initial, client_addr)
+            .await
+            .unwrap()
+            .is_forwarded());
+        tokio::time::timeout(Duration::from_secs(2), socks5.received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(manager.stats().await.shared_relays.len(), 1);
+
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while !manager.sessions.get(&client_addr).unwrap().tx.is_closed() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let stats = manager.stats().await;
+        assert_eq!(stats.relays_declared_dead, 1);
+        assert!(stats.shared_relays.is_empty());
+    }
+
+    #[tokio::test]
+    async fn shared_relay_demultiplexes_sessions_by_reply_source() {
+        use crate::quic::test_util::{build_client_initial, client_hello_handshake, crypto_frame};
+        use crate::socks5::test_util::spawn_mock_udp_associate;
+
+        let mut socks5 = spawn_mock_udp_associate(Duration::ZERO).await;
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let manager = test_manager_with_config(
+            socket,
+            socks5.addr,
+            Vec::new(),
+            QuicSessionConfig {
+                shared_relays: 1,
+                ..Default::default()
+            },
+        );
+        // IP 字面量 SNI 按 IP 目标转发，可以共享 relay
+        let initial_to = |dcid: &[u8], sni: &str| {
+            build_client_initial(
+                0x00000001,
+                dcid,
+                0,
+                &crypto_frame(0, &client_hello_handshake(sni)),
             )
-        })??;
+        };
+        let mut clients = Vec::new();
+        for (i, target) in ["127.0.0.2", "127.0.0.3"].into_iter().enumerate() {
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let initial = initial_to(&[0x36, 0x40, i as u8, 0x01, 0x02, 0x03, 0x04, 0x05], target);
+            assert!(manager
+                .handle_packet(&initial, client.local_addr().unwrap())
+                .await
+                .unwrap()
+                .is_forwarded());
+            let (received, payload) =
+                tokio::time::timeout(Duration::from_secs(2), socks5.received.recv())
+                    .await
+                    .unwrap()
+                    .unwrap();
+            let expected: SocketAddr = format!("{}:443", target).parse().unwrap();
+            assert_eq!(received, TargetAddr::Ip(expected));
+            assert_eq!(payload, initial);
+            clients.push((client, expected));
+        }
+        assert_eq!(socks5.associations.load(Ordering::SeqCst), 1);
+        let stats = manager.stats().await;
+        assert_eq!(stats.shared_relays.len(), 1);
+        assert_eq!(stats.shared_relays[0].upstream, "default");
+        assert_eq!(stats.shared_relays[0].sessions, 2);
+        assert_eq!(manager.relay_addrs.len(), 1);
+
+        // 回包按 SOCKS5 头部的来源地址分发到各自的会话
+        for (i, (_, target)) in clients.iter().enumerate() {
+            socks5
+                .reply(TargetAddr::Ip(*target), format!("reply {}", i).as_bytes())
+                .await;
+        }
+        let nobody: SocketAddr = "198.51.100.9:443".parse().unwrap();
+        socks5.reply(TargetAddr::Ip(nobody), b"unrouted").await;
+        let mut buf = [0u8; 64];
+        for (i, (client, _)) in clients.iter().enumerate() {
+            let (n, _) = tokio::time::timeout(Duration::from_secs(1), client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&buf[..n], format!("reply {}", i).as_bytes());
+        }
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while manager.stats().await.spoofed_relay_packets == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // 共享 relay 上已有同一目标的会话：改用独占的 relay
+        let third: SocketAddr = "192.0.2.41:5000".parse().unwrap();
+        assert!(manager
+            .handle_packet(
+                &initial_to(
+                    &[0x36, 0x40, 0x09, 0x01, 0x02, 0x03, 0x04, 0x05],
+                    "127.0.0.2"
+                ),
+                third
+            )
+            .await
+            .unwrap()
+            .is_forwarded());
+        tokio::time::timeout(Duration::from_secs(2), socks5.received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(socks5.associations.load(Ordering::SeqCst), 2);
+        assert_eq!(manager.stats().await.shared_relays[0].sessions, 2);
+
+        // relay 失效后共享它的会话全部结束，不再计入统计
+        socks5.kill_relays();
+        tokio::time::timeout(Duration::from_secs(2), async {
+            loop {
+                let closed = clients.iter().all(|(client, _)| {
+                    let addr = client.local_addr().unwrap();
+                    manager.sessions.get(&addr).unwrap().tx.is_closed()
+                });
+                if closed {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(manager.stats().await.shared_relays.is_empty());
+        assert_eq!(manager.cleanup_expired_sessions().await, 3);
+        assert_eq!(socks5.open_controls.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn relay_packets_from_other_remotes_are_dropped() {
+        use crate::quic::test_util::{build_client_initial, client_hello_handshake, crypto_frame};
+        use crate::socks5::test_util::spawn_mock_udp_associate;
+
+        let mut socks5 = spawn_mock_udp_associate(Duration::ZERO).await;
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let manager =
+            test_manager_with_rules(socket, socks5.addr, vec!["quic.example.test".to_string()]);
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client_addr = client.local_addr().unwrap();
+        let initial = build_client_initial(
+            0x00000001,
+            &[0x36, 0x21, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06],
+            0,
+            &crypto_frame(0, &client_hello_handshake("quic.example.test")),
+        );
+
+        assert!(manager
+            .handle_packet(&initial, client_addr)
+            .await
+            .unwrap()
+            .is_forwarded());
+        tokio::time::timeout(Duration::from_secs(2), socks5.received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+
+        // 第一个回包把会话固定到 203.0.113.10:443，其他来源的包被丢弃
+        let target: SocketAddr = "203.0.113.10:443".parse().unwrap();
+        let other: SocketAddr = "198.51.100.9:443".parse().unwrap();
+        socks5.reply(TargetAddr::Ip(target), b"legit").await;
+        socks5.reply(TargetAddr::Ip(other), b"spoofed").await;
+        socks5.reply(TargetAddr::Ip(target), b"legit again").await;
+
+        let mut buf = [0u8; 64];
+        for expected in [&b"legit"[..], b"legit again"] {
+            let (n, _) = tokio::time::timeout(Duration::from_secs(1), client.recv_from(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&buf[..n], expected);
+        }
+        assert_eq!(manager.stats().await.spoofed_relay_packets, 1);
+    }
+
+    #[tokio::test]
+    async fn gso_replies_reach_client_in_order() {
+        use crate::quic::test_util::{build_client_initial, client_hello_handshake, crypto_frame};
+        use crate::socks5::test_util::spawn_mock_udp_associate;
+
+        let mut socks5 = spawn_mock_udp_associate(Duration::ZERO).await;
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let config = QuicSessionConfig {
+            enable_gso: true,
+            ..Default::default()
+        };
+        let manager = test_manager_with_config(socket, socks5.addr, Vec::new(), config);
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let initial = build_client_initial(
+            0x00000001,
+            &[0x36, 0x23, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06],
+            0,
+            &crypto_frame(0, &client_hello_handshake("quic.example.test")),
+        );
+        assert!(manager
+            .handle_packet(&initial, client.local_addr().unwrap())
+            .await
+            .unwrap()
+            .is_forwarded());
+        tokio::time::timeout(Duration::from_secs(2), socks5.received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+
+        // 等长的回包可合并为一次 GSO 发送，较短的包结束一批
+        let target = TargetAddr::Ip("203.0.113.10:443".parse().unwrap());
+        let mut expected: Vec<Vec<u8>> = (0..8u8).map(|i| vec![i; 1200]).collect();
+        expected.push(vec![0xff; 300]);
+        expected.push(vec![0xee; 1200]);
+        for payload in &expected {
+            socks5.reply(target.clone(), payload).await;
+        }
+
+        let mut buf = [0u8; 1500];
+        for payload in &expected {
+            let n = tokio::time::timeout(Duration::from_secs(1), client.recv(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&buf[..n], &payload[..]);
+        }
+        let snapshot = manager.snapshot();
+        assert_eq!(snapshot[0].packets_down, expected.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn large_relay_datagrams_reach_client_intact() {
+        use crate::quic::test_util::{build_client_initial, client_hello_handshake, crypto_frame};
+        use crate::socks5::test_util::spawn_mock_udp_associate;
+
+        let mut socks5 = spawn_mock_udp_associate(Duration::ZERO).await;
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let manager = test_manager_with_config(
+            socket,
+            socks5.addr,
+            vec!["quic.example.test".to_string()],
+            QuicSessionConfig {
+                max_datagram_size: 9000,
+                ..Default::default()
+            },
+        );
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let initial = build_client_initial(
+            0x00000001,
+            &[0x36, 0x22, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06],
+            0,
+            &crypto_frame(0, &client_hello_handshake("quic.example.test")),
+        );
+
+        let client_addr = client.local_addr().unwrap();
+        assert!(manager
+            .handle_packet(&initial, client_addr)
+            .await
+            .unwrap()
+            .is_forwarded());
+        tokio::time::timeout(Duration::from_secs(2), socks5.received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+
+        // 超过以太网 MTU 的回包 (加上 SOCKS5 头部) 完整送达客户端
+        let payload = vec![0x5a; 9000];
+        socks5
+            .reply(
+                TargetAddr::Domain("quic.example.test".to_string(), 443),
+                &payload,
+            )
+            .await;
+
+        let mut buf = vec![0u8; 16384];
+        let (n, _) = tokio::time::timeout(Duration::from_secs(1), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..n], payload);
+    }
+
+    #[tokio::test]
+    async fn fragmented_relay_datagrams_are_dropped_or_reassembled() {
+        use crate::socks5::test_util::spawn_mock_udp_associate;
+
+        for reassemble in [false, true] {
+            let mut socks5 = spawn_mock_udp_associate(Duration::ZERO).await;
+            let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+            let manager = test_manager_with_config(
+                socket,
+                socks5.addr,
+                Vec::new(),
+                QuicSessionConfig {
+                    udp_reassembly: reassemble,
+                    ..Default::default()
+                },
+            );
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let client_addr = client.local_addr().unwrap();
+            assert!(manager
+                .handle_packet(&allowed_initial(&[0x36, 0x26, 0x03, 0x04]), client_addr)
+                .await
+                .unwrap()
+                .is_forwarded());
+            tokio::time::timeout(Duration::from_secs(2), socks5.received.recv())
+                .await
+                .unwrap()
+                .unwrap();
+
+            let target = TargetAddr::Ip("127.0.0.1:443".parse().unwrap());
+            socks5.reply_fragment(target.clone(), 1, b"hel").await;
+            socks5.reply_fragment(target.clone(), 0x82, b"lo").await;
+            socks5.reply(target, b"whole").await;
+
+            let mut received = Vec::new();
+            let mut buf = [0u8; 64];
+            while received.last().map(Vec::as_slice) != Some(&b"whole"[..]) {
+                let n = tokio::time::timeout(Duration::from_secs(1), client.recv(&mut buf))
+                    .await
+                    .unwrap()
+                    .unwrap();
+                received.push(buf[..n].to_vec());
+            }
+            let stats = manager.stats().await;
+            if reassemble {
+                assert_eq!(received, [b"hello".to_vec(), b"whole".to_vec()]);
+                assert_eq!(stats.relay_datagrams_reassembled, 1);
+                assert_eq!(stats.relay_fragments_dropped, 0);
+            } else {
+                // 分片不会被当作完整的 QUIC 包转发
+                assert_eq!(received, [b"whole".to_vec()]);
+                assert_eq!(stats.relay_fragments_dropped, 2);
+                assert_eq!(stats.relay_datagrams_reassembled, 0);
+            }
+        }
+    }
 
-    parse_dns_response(&response[..len], dns_txid(host, qtype), qtype, port)
-}
+    #[tokio::test]
+    async fn snapshot_reports_session_traffic() {
+        use crate::quic::test_util::{build_client_initial, client_hello_handshake, crypto_frame};
+        use crate::socks5::test_util::spawn_mock_udp_associate;
 
-fn build_dns_query(host: &str, qtype: u16) -> Result<Vec<u8>> {
-    let mut query = Vec::with_capacity(512);
-    let txid = dns_txid(host, qtype);
-    query.extend_from_slice(&txid.to_be_bytes());
-    query.extend_from_slice(&0x0100u16.to_be_bytes()); // recursion desired
-    query.extend_from_slice(&1u16.to_be_bytes()); // qdcount
-    query.extend_from_slice(&0u16.to_be_bytes()); // ancount
-    query.extend_from_slice(&0u16.to_be_bytes()); // nscount
-    query.extend_from_slice(&0u16.to_be_bytes()); // arcount
+        let mut socks5 = spawn_mock_udp_associate(Duration::ZERO).await;
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let manager =
+            test_manager_with_rules(socket, socks5.addr, vec!["quic.example.test".to_string()]);
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client_addr = client.local_addr().unwrap();
+        let initial = build_client_initial(
+            0x00000001,
+            &[0x36, 0x23, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06],
+            0,
+            &crypto_frame(0, &client_hello_handshake("quic.example.test")),
+        );
 
-    for label in host.trim_end_matches('.').split('.') {
-        if label.is_empty() || label.len() > 63 {
-            return Err(anyhow!("Invalid DNS label in host '{}'", host));
+        assert!(manager
+            .handle_packet(&initial, client_addr)
+            .await
+            .unwrap()
+            .is_forwarded());
+        while manager.session_count() == 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        for _ in 0..2 {
+            assert!(manager
+                .handle_packet(&[0x40; 100], client_addr)
+                .await
+                .unwrap()
+                .is_forwarded());
+        }
+        for _ in 0..3 {
+            socks5.received.recv().await.unwrap();
         }
-        query.push(label.len() as u8);
-        query.extend_from_slice(label.as_bytes());
-    }
-    query.push(0);
-    query.extend_from_slice(&qtype.to_be_bytes());
-    query.extend_from_slice(&1u16.to_be_bytes()); // IN
 
-    Ok(query)
-}
+        let target = TargetAddr::Domain("quic.example.test".to_string(), 443);
+        socks5.reply(target.clone(), &[0x41; 300]).await;
+        let mut buf = [0u8; 1500];
+        tokio::time::timeout(Duration::from_secs(1), client.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
 
-fn upstream_dns_server() -> Result<SocketAddr> {
-    let dns_server =
-        std::env::var("SNIPROXY_DNS_SERVER").unwrap_or_else(|_| "1.1.1.1:53".to_string());
-    dns_server
-        .parse()
-        .map_err(|e| anyhow!("Invalid SNIPROXY_DNS_SERVER '{}': {}", dns_server, e))
-}
+        // 回包发给客户端后才计数，等待会话任务更新
+        let snapshot = tokio::time::timeout(Duration::from_secs(1), async {
+            loop {
+                let snapshot = manager.snapshot();
+                if snapshot[0].packets_down == 1 {
+                    return snapshot;
+                }
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
 
-fn parse_dns_response(
-    response: &[u8],
-    expected_txid: u16,
-    expected_qtype: u16,
-    port: u16,
-) -> Result<Option<SocketAddr>> {
-    if response.len() < 12 {
-        return Err(anyhow!("DNS response too short"));
-    }
-    if u16::from_be_bytes([response[0], response[1]]) != expected_txid {
-        return Err(anyhow!("DNS transaction id mismatch"));
+        assert_eq!(snapshot.len(), 1);
+        let session = &snapshot[0];
+        assert_eq!(session.client, client_addr);
+        assert_eq!(session.sni, "quic.example.test");
+        assert_eq!(session.target, target);
+        assert_eq!(session.packets_up, 3);
+        assert_eq!(session.bytes_up, initial.len() as u64 + 200);
+        assert_eq!(session.bytes_down, 300);
+        assert!(session.idle <= session.age);
+
+        // `/quic/sessions` 的 JSON 形式
+        let json = serde_json::to_value(session).unwrap();
+        assert_eq!(json["client"], client_addr.to_string());
+        assert_eq!(json["target"], "quic.example.test:443");
+        assert_eq!(json["packets_up"], 3);
+        assert!(json["age_ms"].as_f64().unwrap() >= json["idle_ms"].as_f64().unwrap());
+        assert!(json["idle_timeout_ms"].as_f64().unwrap() > 0.0);
     }
 
-    let flags = u16::from_be_bytes([response[2], response[3]]);
-    if flags & 0x8000 == 0 {
-        return Err(anyhow!("DNS response is not marked as response"));
+    #[test]
+    fn remote_pin_matches_target() {
+        let mut ip = RemotePin::new(&TargetAddr::Ip("192.0.2.1:443".parse().unwrap()));
+        assert!(ip.accepts(&TargetAddr::Ip("[::ffff:192.0.2.1]:443".parse().unwrap())));
+        assert!(!ip.accepts(&TargetAddr::Ip("192.0.2.2:443".parse().unwrap())));
+        assert!(!ip.accepts(&TargetAddr::Domain("example.test".to_string(), 443)));
+
+        let mut domain = RemotePin::new(&TargetAddr::Domain("Example.test".to_string(), 443));
+        assert!(domain.accepts(&TargetAddr::Domain("example.test".to_string(), 443)));
+        assert!(!domain.accepts(&TargetAddr::Domain("example.test".to_string(), 8443)));
+        assert!(!domain.accepts(&TargetAddr::Ip("192.0.2.1:8443".parse().unwrap())));
+        assert!(domain.accepts(&TargetAddr::Ip("192.0.2.1:443".parse().unwrap())));
+        assert!(!domain.accepts(&TargetAddr::Ip("192.0.2.2:443".parse().unwrap())));
     }
-    if flags & 0x000f != 0 {
-        return Ok(None);
+
+    #[tokio::test]
+    async fn packets_from_relay_address_are_ignored() {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let manager = test_manager(socket);
+        let relay: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+        let initial = allowed_initial(&[0x36, 0x22, 0x01, 0x02]);
+
+        let guard = RelayAddrGuard::register(&manager.relay_addrs, relay);
+        assert!(!manager
+            .handle_packet(&initial, relay)
+            .await
+            .unwrap()
+            .is_forwarded());
+        assert_eq!(manager.stats().await.sni_extractions, 0);
+        assert_eq!(manager.stats().await.reflected_packets, 1);
+
+        drop(guard);
+        assert!(manager.relay_addrs.is_empty());
     }
 
-    let qdcount = u16::from_be_bytes([response[4], response[5]]) as usize;
-    let ancount = u16::from_be_bytes([response[6], response[7]]) as usize;
-    let mut offset = 12;
+    #[tokio::test]
+    async fn reflected_initials_from_own_listener_create_no_session() {
+        use crate::quic::test_util::{build_client_initial, client_hello_handshake, crypto_frame};
+        use crate::socks5::test_util::spawn_mock_udp_associate;
 
-    for _ in 0..qdcount {
-        offset = skip_dns_name(response, offset)?;
-        if response.len() < offset + 4 {
-            return Err(anyhow!("DNS question truncated"));
-        }
-        offset += 4;
+        let mut socks5 = spawn_mock_udp_associate(Duration::ZERO).await;
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let listen_addr = socket.local_addr().unwrap();
+        let manager = test_manager_with_socks5(socket, socks5.addr);
+
+        // 监听端自己发出的包 (例如另一实例把回包反射回来) 不做任何解析
+        let initial = build_client_initial(
+            0x00000001,
+            &[0x36, 0x31, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06],
+            0,
+            &crypto_frame(0, &client_hello_handshake("quic.example.test")),
+        );
+        assert!(!manager
+            .handle_packet(&initial, listen_addr)
+            .await
+            .unwrap()
+            .is_forwarded());
+        assert!(!manager
+            .handle_packet(&initial, listen_addr)
+            .await
+            .unwrap()
+            .is_forwarded());
+
+        let stats = manager.stats().await;
+        assert_eq!(stats.reflected_packets, 2);
+        assert_eq!(stats.sni_extractions, 0);
+        assert_eq!(stats.pending_sessions, 0);
+        assert_eq!(manager.session_count(), 0);
+        assert!(socks5.received.try_recv().is_err());
     }
 
-    for _ in 0..ancount {
-        offset = skip_dns_name(response, offset)?;
-        if response.len() < offset + 10 {
-            return Err(anyhow!("DNS answer truncated"));
-        }
+    #[tokio::test]
+    async fn session_targeting_own_listener_is_refused() {
+        use crate::quic::test_util::{build_client_initial, client_hello_handshake, crypto_frame};
+        use crate::socks5::test_util::spawn_mock_udp_associate;
 
-        let rr_type = u16::from_be_bytes([response[offset], response[offset + 1]]);
-        let rr_class = u16::from_be_bytes([response[offset + 2], response[offset + 3]]);
-        let rdlen = u16::from_be_bytes([response[offset + 8], response[offset + 9]]) as usize;
-        offset += 10;
+        let mut socks5 = spawn_mock_udp_associate(Duration::ZERO).await;
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let mut manager = test_manager_with_socks5(socket, socks5.addr);
+        // 模拟监听在 0.0.0.0:443：SNI 为 127.0.0.1 的目标 127.0.0.1:443 就是自身
+        manager.listen_addrs = Arc::new(vec!["0.0.0.0:443".parse().unwrap()]);
 
-        if response.len() < offset + rdlen {
-            return Err(anyhow!("DNS answer data truncated"));
-        }
+        let initial = build_client_initial(
+            0x00000001,
+            &[0x36, 0x32, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06],
+            0,
+            &crypto_frame(0, &client_hello_handshake("127.0.0.1")),
+        );
+        let client: SocketAddr = "192.0.2.17:5000".parse().unwrap();
+        assert!(manager
+            .handle_packet(&initial, client)
+            .await
+            .unwrap()
+            .is_forwarded());
 
-        if rr_class == 1 && rr_type == expected_qtype {
-            match (rr_type, rdlen) {
-                (1, 4) => {
-                    let ip = Ipv4Addr::new(
-                        response[offset],
-                        response[offset + 1],
-                        response[offset + 2],
-                        response[offset + 3],
-                    );
-                    return Ok(Some(SocketAddr::new(ip.into(), port)));
-                }
-                (28, 16) => {
-                    let mut octets = [0u8; 16];
-                    octets.copy_from_slice(&response[offset..offset + 16]);
-                    return Ok(Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port)));
-                }
-                _ => {}
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while !manager.inner.lock().await.pending.is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
             }
-        }
-
-        offset += rdlen;
+        })
+        .await
+        .unwrap();
+        assert_eq!(manager.session_count(), 0);
+        assert_eq!(manager.stats().await.self_target_rejections, 1);
+        assert!(socks5.received.try_recv().is_err());
     }
 
-    Ok(None)
-}
+    #[test]
+    fn listen_endpoint_matching() {
+        let specific: SocketAddr = "192.0.2.1:443".parse().unwrap();
+        assert!(is_same_endpoint(specific, "192.0.2.1:443".parse().unwrap()));
+        assert!(is_same_endpoint(
+            specific,
+            "[::ffff:192.0.2.1]:443".parse().unwrap()
+        ));
+        assert!(!is_same_endpoint(
+            specific,
+            "192.0.2.2:443".parse().unwrap()
+        ));
+        assert!(!is_same_endpoint(
+            specific,
+            "192.0.2.1:8443".parse().unwrap()
+        ));
 
-fn skip_dns_name(packet: &[u8], mut offset: usize) -> Result<usize> {
-    loop {
-        if offset >= packet.len() {
-            return Err(anyhow!("DNS name truncated"));
-        }
+        let wildcard: SocketAddr = "[::]:443".parse().unwrap();
+        assert!(is_same_endpoint(wildcard, "127.0.0.1:443".parse().unwrap()));
+        assert!(is_same_endpoint(wildcard, "[::1]:443".parse().unwrap()));
+        assert!(!is_same_endpoint(
+            wildcard,
+            "203.0.113.5:443".parse().unwrap()
+        ));
+    }
 
-        let len = packet[offset];
-        if len & 0xc0 == 0xc0 {
-            if offset + 1 >= packet.len() {
-                return Err(anyhow!("DNS compression pointer truncated"));
-            }
-            return Ok(offset + 2);
-        }
+    #[tokio::test]
+    async fn cleanup_removes_sessions_whose_task_exited() {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let manager = test_manager(socket);
 
-        offset += 1;
-        if len == 0 {
-            return Ok(offset);
-        }
+        let (alive, _alive_rx, _) = test_session(&[0x70, 0x01], "192.0.2.71:1000".parse().unwrap());
+        let (dead, dead_rx, _) = test_session(&[0x70, 0x02], "192.0.2.72:1000".parse().unwrap());
+        manager.insert_session(alive).await;
+        manager.insert_session(dead).await;
+        drop(dead_rx);
 
-        if len & 0xc0 != 0 {
-            return Err(anyhow!("Unsupported DNS label encoding"));
-        }
+        assert_eq!(manager.cleanup_expired_sessions().await, 1);
+        assert_eq!(manager.session_count(), 1);
+        assert!(!manager.dcid_index.contains_key(&[0x70, 0x02]));
+    }
 
-        offset += len as usize;
-        if offset > packet.len() {
-            return Err(anyhow!("DNS label truncated"));
+    fn idle_override(pattern: &str, secs: u64) -> IdleOverride {
+        IdleOverride {
+            pattern: pattern.to_string(),
+            idle_timeout: Duration::from_secs(secs),
         }
     }
-}
 
-fn dns_txid(host: &str, qtype: u16) -> u16 {
-    let mut hash = 0x811c9dc5u32;
-    for byte in host.as_bytes() {
-        hash ^= *byte as u32;
-        hash = hash.wrapping_mul(0x01000193);
+    #[test]
+    fn idle_timeout_override_uses_first_matching_pattern() {
+        let config = QuicSessionConfig {
+            idle_overrides: vec![
+                idle_override("*.meet.example.com", 600),
+                idle_override("*example.com", 10),
+            ],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            config.idle_timeout_for("eu.meet.example.com"),
+            Duration::from_secs(600)
+        );
+        // `*.meet.example.com` 不匹配裸域名，落到第二个模式
+        assert_eq!(
+            config.idle_timeout_for("meet.example.com"),
+            Duration::from_secs(10)
+        );
+        assert_eq!(config.idle_timeout_for("example.org"), config.idle_timeout);
     }
-    (hash as u16) ^ qtype
-}
 
-impl Clone for QuicSessionManager {
-    fn clone(&self) -> Self {
-        Self {
-            inner: Arc::clone(&self.inner),
-            config: self.config.clone(),
+    #[tokio::test]
+    async fn cleanup_uses_per_session_idle_timeout() {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let manager = test_manager(socket);
+        let idle_for = Duration::from_secs(120);
+
+        // 空闲 2 分钟：10 分钟超时的会话保留，默认 60 秒和 1 秒超时的会话过期
+        let (mut long, _long_rx, _) =
+            test_session(&[0x71, 0x01], "192.0.2.81:1000".parse().unwrap());
+        long.created_at = Instant::now() - idle_for;
+        long.idle_timeout = Duration::from_secs(600);
+        let (mut default, _default_rx, _) =
+            test_session(&[0x71, 0x02], "192.0.2.82:1000".parse().unwrap());
+        default.created_at = Instant::now() - idle_for;
+        let (mut short, _short_rx, _) =
+            test_session(&[0x71, 0x03], "192.0.2.83:1000".parse().unwrap());
+        short.created_at = Instant::now() - idle_for;
+        short.idle_timeout = Duration::from_secs(1);
+        // 刚创建的 1 秒超时会话还未过期
+        let (mut fresh, _fresh_rx, _) =
+            test_session(&[0x71, 0x04], "192.0.2.84:1000".parse().unwrap());
+        fresh.idle_timeout = Duration::from_secs(1);
+
+        for session in [long, default, short, fresh] {
+            manager.insert_session(session).await;
         }
+
+        assert_eq!(manager.cleanup_expired_sessions().await, 2);
+        let mut remaining: Vec<_> = manager
+            .snapshot()
+            .into_iter()
+            .map(|s| (s.client.ip(), s.idle_timeout))
+            .collect();
+        remaining.sort();
+        assert_eq!(
+            remaining,
+            vec![
+                ("192.0.2.81".parse().unwrap(), Duration::from_secs(600)),
+                ("192.0.2.84".parse().unwrap(), Duration::from_secs(1)),
+            ]
+        );
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[tokio::test]
+    async fn session_idle_timeout_is_resolved_from_sni() {
+        use crate::quic::test_util::{build_client_initial, client_hello_handshake, crypto_frame};
+        use crate::socks5::test_util::spawn_mock_udp_associate;
 
-    #[test]
-    fn test_config_default() {
-        let config = QuicSessionConfig::default();
-        assert_eq!(config.idle_timeout, Duration::from_secs(60));
-        assert_eq!(config.cleanup_interval, Duration::from_secs(30));
+        let mut socks5 = spawn_mock_udp_associate(Duration::ZERO).await;
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let config = QuicSessionConfig {
+            idle_overrides: vec![
+                idle_override("*.example.com", 600),
+                idle_override("127.0.0.*", 5),
+            ],
+            ..Default::default()
+        };
+        let manager = test_manager_with_config(socket, socks5.addr, Vec::new(), config);
+
+        let packet = build_client_initial(
+            0x00000001,
+            &[0x3b, 0x01, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66],
+            0,
+            &crypto_frame(0, &client_hello_handshake("127.0.0.1")),
+        );
+        let client: SocketAddr = "192.0.2.16:5000".parse().unwrap();
+        assert!(manager
+            .handle_packet(&packet, client)
+            .await
+            .unwrap()
+            .is_forwarded());
+        tokio::time::timeout(Duration::from_secs(2), socks5.received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let snapshot = manager.snapshot();
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot[0].idle_timeout, Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn pending_buffer_is_dropped_when_session_creation_fails() {
+        use crate::quic::test_util::{build_client_initial, client_hello_handshake, crypto_frame};
+
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        // 没有 SOCKS5 服务监听的地址，ASSOCIATE 会失败
+        let unused = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let socks5_addr = unused.local_addr().unwrap();
+        drop(unused);
+        let manager = test_manager_with_socks5(socket, socks5_addr);
+        let client_addr: SocketAddr = "192.0.2.11:5000".parse().unwrap();
+
+        let dcid = [0x36, 0x08, 0x77, 0x22, 0x33, 0x44, 0x55, 0x66];
+        let initial = build_client_initial(
+            0x00000001,
+            &dcid,
+            0,
+            &crypto_frame(0, &client_hello_handshake("127.0.0.1")),
+        );
+
+        assert!(manager
+            .handle_packet(&initial, client_addr)
+            .await
+            .unwrap()
+            .is_forwarded());
+        assert!(manager
+            .handle_packet(&[0x40; 64], client_addr)
+            .await
+            .unwrap()
+            .is_forwarded());
+
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while !manager.inner.lock().await.pending.is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(manager.session_count(), 0);
     }
 
     #[test]
@@ -588,4 +5445,376 @@ mod tests {
         assert_eq!(map.get(&dcid2), Some(&"session1"));
         assert_eq!(map.get(&dcid3), None);
     }
+
+    /// 两个 mock relay 分别登记为 default 和 eu 上游
+    fn two_upstream_config(
+        default: SocketAddr,
+        eu: SocketAddr,
+        routes: &[(&str, &str)],
+    ) -> crate::config::Config {
+        let mut config: crate::config::Config = toml::from_str(
+            r#"
+[server]
+listen_https_addr = "127.0.0.1:0"
+
+[socks5]
+addr = "127.0.0.1:1080"
+timeout = 5
+"#,
+        )
+        .unwrap();
+        config.socks5.addr = default.into();
+        config.socks5.upstreams = vec![crate::config::Socks5Upstream {
+            name: "eu".to_string(),
+            addr: eu.into(),
+            username: None,
+            password: None,
+            protocol: crate::config::UpstreamProtocol::default(),
+            weight: 1,
+        }];
+        config.rules.routes = routes
+            .iter()
+            .map(|(pattern, upstream)| crate::config::RouteRule {
+                pattern: pattern.to_string(),
+                upstream: upstream.to_string(),
+            })
+            .collect();
+        config
+    }
+
+    #[tokio::test]
+    async fn route_rules_steer_sessions_to_named_upstreams() {
+        use crate::quic::test_util::{build_client_initial, client_hello_handshake, crypto_frame};
+        use crate::socks5::test_util::spawn_mock_udp_associate;
+
+        let mut default_relay = spawn_mock_udp_associate(Duration::ZERO).await;
+        let mut eu_relay = spawn_mock_udp_associate(Duration::ZERO).await;
+        let config = two_upstream_config(
+            default_relay.addr,
+            eu_relay.addr,
+            &[("*.eu.test", "eu"), ("*.us.test", "default")],
+        );
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let manager = test_manager_from(socket, config, QuicSessionConfig::default());
+
+        for (i, (sni, relay)) in [
+            ("www.eu.test", &mut eu_relay),
+            ("www.us.test", &mut default_relay),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let initial = build_client_initial(
+                0x00000001,
+                &[0x40 + i as u8, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07],
+                0,
+                &crypto_frame(0, &client_hello_handshake(sni)),
+            );
+            let client_addr = SocketAddr::from(([192, 0, 2, 100 + i as u8], 5000));
+            assert!(manager
+                .handle_packet(&initial, client_addr)
+                .await
+                .unwrap()
+                .is_forwarded());
+            let (target, _) = tokio::time::timeout(Duration::from_secs(2), relay.received.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(target, TargetAddr::Domain(sni.to_string(), 443));
+        }
+
+        let mut upstreams: Vec<_> = manager
+            .snapshot()
+            .into_iter()
+            .map(|s| (s.sni, s.upstream))
+            .collect();
+        upstreams.sort();
+        assert_eq!(
+            upstreams,
+            vec![
+                ("www.eu.test".to_string(), "eu".to_string()),
+                ("www.us.test".to_string(), "default".to_string()),
+            ]
+        );
+        let stats = manager.stats().await;
+        assert_eq!(stats.upstream_sessions.get("eu"), Some(&1));
+        assert_eq!(stats.upstream_sessions.get("default"), Some(&1));
+    }
+
+    #[tokio::test]
+    async fn unrouted_sessions_spread_across_upstreams() {
+        use crate::quic::test_util::{build_client_initial, client_hello_handshake, crypto_frame};
+        use crate::socks5::test_util::spawn_mock_udp_associate;
+
+        let mut default_relay = spawn_mock_udp_associate(Duration::ZERO).await;
+        let mut eu_relay = spawn_mock_udp_associate(Duration::ZERO).await;
+        let config = two_upstream_config(default_relay.addr, eu_relay.addr, &[]);
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let manager = test_manager_from(socket, config, QuicSessionConfig::default());
+
+        let hello = crypto_frame(0, &client_hello_handshake("spread.example.test"));
+        for i in 0..16u8 {
+            let dcid = [0x50, i, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07];
+            let initial = build_client_initial(0x00000001, &dcid, 0, &hello);
+            let client_addr = SocketAddr::from(([192, 0, 2, 1 + i], 5000));
+            assert!(manager
+                .handle_packet(&initial, client_addr)
+                .await
+                .unwrap()
+                .is_forwarded());
+        }
+        // 每个会话的 Initial 到达某个 relay 后会话即已建立
+        for _ in 0..16 {
+            tokio::time::timeout(Duration::from_secs(2), async {
+                tokio::select! {
+                    Some(_) = default_relay.received.recv() => {}
+                    Some(_) = eu_relay.received.recv() => {}
+                }
+            })
+            .await
+            .unwrap();
+        }
+
+        // 同一 SNI 的会话按 DCID 分散到两个上游
+        let stats = manager.stats().await;
+        assert_eq!(stats.active_sessions, 16);
+        let on_default = stats.upstream_sessions["default"];
+        let on_eu = stats.upstream_sessions["eu"];
+        assert_eq!(on_default + on_eu, 16);
+        assert!(on_default > 0 && on_eu > 0, "{:?}", stats.upstream_sessions);
+        assert_eq!(
+            default_relay.associations.load(Ordering::SeqCst),
+            on_default
+        );
+        assert_eq!(eu_relay.associations.load(Ordering::SeqCst), on_eu);
+    }
+
+    /// SOCKS5 前置：`failures` 大于 0 时直接关闭连接并减一 (模拟服务不可达)，
+    /// 否则把连接转发给 `target`；返回 (监听地址, 收到的连接数)
+    async fn spawn_flaky_socks5(
+        target: SocketAddr,
+        failures: Arc<std::sync::atomic::AtomicUsize>,
+    ) -> (SocketAddr, Arc<std::sync::atomic::AtomicUsize>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = Arc::clone(&accepted);
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let fail = failures
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                    .is_ok();
+                if fail {
+                    continue;
+                }
+                tokio::spawn(async move {
+                    let mut upstream = TcpStream::connect(target).await.unwrap();
+                    let _ = tokio::io::copy_bidirectional(&mut stream, &mut upstream).await;
+                });
+            }
+        });
+        (addr, accepted)
+    }
+
+    #[tokio::test]
+    async fn associate_is_retried_while_socks5_is_briefly_down() {
+        use crate::quic::test_util::{build_client_initial, client_hello_handshake, crypto_frame};
+        use crate::socks5::test_util::spawn_mock_udp_associate;
+
+        let mut socks5 = spawn_mock_udp_associate(Duration::ZERO).await;
+        let failures = Arc::new(std::sync::atomic::AtomicUsize::new(2));
+        let (flaky_addr, accepted) = spawn_flaky_socks5(socks5.addr, failures).await;
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let manager = test_manager_with_config(
+            socket,
+            flaky_addr,
+            vec!["quic.example.test".to_string()],
+            QuicSessionConfig {
+                associate_backoff: Duration::from_millis(20),
+                ..Default::default()
+            },
+        );
+        let initial = build_client_initial(
+            0x00000001,
+            &[0x37, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07],
+            0,
+            &crypto_frame(0, &client_hello_handshake("quic.example.test")),
+        );
+        let client_addr: SocketAddr = "192.0.2.120:5000".parse().unwrap();
+        assert!(manager
+            .handle_packet(&initial, client_addr)
+            .await
+            .unwrap()
+            .is_forwarded());
+        // 重试期间到达的包暂存，会话建好后随 Initial 一起转发
+        assert!(manager
+            .handle_packet(&[0x40; 32], client_addr)
+            .await
+            .unwrap()
+            .is_forwarded());
+
+        for expected in [initial.clone(), vec![0x40; 32]] {
+            let (_, payload) = tokio::time::timeout(Duration::from_secs(2), socks5.received.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(payload, expected);
+        }
+        assert_eq!(accepted.load(Ordering::SeqCst), 3);
+        let stats = manager.stats().await;
+        assert_eq!(stats.active_sessions, 1);
+        assert_eq!(stats.associate_retries, 2);
+        assert_eq!(stats.breaker_opened, 0);
+    }
+
+    #[tokio::test]
+    async fn associate_is_not_retried_when_ruleset_refuses() {
+        use crate::quic::test_util::{build_client_initial, client_hello_handshake, crypto_frame};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // 接受握手，但以"规则不允许" (0x02) 拒绝 UDP ASSOCIATE
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socks5_addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = Arc::clone(&accepted);
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut greeting = [0u8; 3];
+                    stream.read_exact(&mut greeting).await.unwrap();
+                    stream.write_all(&[0x05, 0x00]).await.unwrap();
+                    let mut request = [0u8; 10];
+                    stream.read_exact(&mut request).await.unwrap();
+                    stream
+                        .write_all(&[0x05, 0x02, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                        .await
+                        .unwrap();
+                });
+            }
+        });
+
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let manager = test_manager_with_config(
+            socket,
+            socks5_addr,
+            vec!["quic.example.test".to_string()],
+            QuicSessionConfig {
+                associate_backoff: Duration::from_millis(20),
+                ..Default::default()
+            },
+        );
+        let initial = build_client_initial(
+            0x00000001,
+            &[0x38, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07],
+            0,
+            &crypto_frame(0, &client_hello_handshake("quic.example.test")),
+        );
+        let client_addr: SocketAddr = "192.0.2.121:5000".parse().unwrap();
+        manager.handle_packet(&initial, client_addr).await.unwrap();
+
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while manager.stats().await.associate_errors["not_allowed"] == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let stats = manager.stats().await;
+        assert_eq!(stats.associate_errors["not_allowed"], 1);
+        assert_eq!(stats.associate_retries, 0);
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+        assert_eq!(stats.active_sessions, 0);
+    }
+
+    #[tokio::test]
+    async fn breaker_fails_fast_until_probe_succeeds() {
+        use crate::quic::test_util::{build_client_initial, client_hello_handshake, crypto_frame};
+        use crate::socks5::test_util::spawn_mock_udp_associate;
+
+        let mut socks5 = spawn_mock_udp_associate(Duration::ZERO).await;
+        let failures = Arc::new(std::sync::atomic::AtomicUsize::new(usize::MAX));
+        let (flaky_addr, accepted) = spawn_flaky_socks5(socks5.addr, Arc::clone(&failures)).await;
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let manager = test_manager_with_config(
+            socket,
+            flaky_addr,
+            vec!["quic.example.test".to_string()],
+            QuicSessionConfig {
+                associate_attempts: 1,
+                breaker_threshold: 2,
+                breaker_cooldown: Duration::from_millis(200),
+                ..Default::default()
+            },
+        );
+        let hello = crypto_frame(0, &client_hello_handshake("quic.example.test"));
+        let mut next_client = 0u8;
+        let mut start_session = || {
+            next_client += 1;
+            let initial = build_client_initial(
+                0x00000001,
+                &[0x38, next_client, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07],
+                0,
+                &hello,
+            );
+            (initial, SocketAddr::from(([192, 0, 2, next_client], 5000)))
+        };
+        let settle = |manager: QuicSessionManager| async move {
+            tokio::time::timeout(Duration::from_secs(2), async {
+                while !manager.inner.lock().await.pending.is_empty() {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+            })
+            .await
+            .unwrap();
+        };
+
+        // 连续两次失败后打开
+        for _ in 0..2 {
+            let (initial, client) = start_session();
+            assert!(manager
+                .handle_packet(&initial, client)
+                .await
+                .unwrap()
+                .is_forwarded());
+            settle(manager.clone()).await;
+        }
+        let stats = manager.stats().await;
+        assert_eq!(stats.breaker_opened, 1);
+        assert_eq!(stats.open_breakers, vec!["default".to_string()]);
+
+        // 打开期间不再连接 SOCKS5 服务器
+        let (initial, client) = start_session();
+        assert!(manager
+            .handle_packet(&initial, client)
+            .await
+            .unwrap()
+            .is_forwarded());
+        settle(manager.clone()).await;
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+        assert_eq!(manager.stats().await.breaker_rejections, 1);
+
+        // 服务恢复，冷却结束后的探测成功并关闭断路器
+        failures.store(0, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        let (initial, client) = start_session();
+        assert!(manager
+            .handle_packet(&initial, client)
+            .await
+            .unwrap()
+            .is_forwarded());
+        let (_, payload) = tokio::time::timeout(Duration::from_secs(2), socks5.received.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(payload, initial);
+
+        let stats = manager.stats().await;
+        assert_eq!(stats.active_sessions, 1);
+        assert_eq!(stats.breaker_closed, 1);
+        assert!(stats.open_breakers.is_empty());
+    }
 }
@@ -0,0 +1,416 @@
+//! 多个 QUIC 会话共享的 SOCKS5 UDP relay (`socks5.udp_relay_sharing`)
+//!
+//! SOCKS5 UDP relay 与目标无关：每个数据报的头部各自携带目标地址，回包的头部携带
+//! 来源地址。共享模式下每个上游最多保持 `udp_relay_pool_size` 个 relay，新会话在
+//! relay 数未达上限时新建 relay，之后轮流分配到已有的 relay；每个 relay 的读取任务
+//! 按回包头部的来源地址把数据报分发给对应的会话。
+//!
+//! 只有按回包来源能唯一确定会话时才能共享：
+//!
+//! - 目标必须是 IP 地址。remote 解析模式下目标是域名，回包来源是 SOCKS5 服务器解析
+//!   出的 IP，事先无法对应到会话，这类会话仍使用独占的 relay；
+//! - 同一个 relay 上不能有两个目标相同的会话。所有 relay 上都已有该目标时同样使用
+//!   独占的 relay。
+//!
+//! relay 的控制连接关闭、socket 出错或被无响应检测 (`quic.relay_probe_interval`，所有会话
+//! 合计的发送与回包) 判定失效时，读取任务结束并关闭所有会话的接收队列，会话随之结束
+//! (不论 `quic.relay_failure`)，客户端重连时分配到新的 relay。
+
+use super::session::{
+    canonical, FragmentPolicy, RelayAddrGuard, RelayAddrs, RelayFragments, RelayWatchdog,
+};
+use crate::metrics::{Registry, TaskKind};
+use crate::quic::udp::TruncationWarnings;
+use crate::socks5::udp::{wait_control_closed, Socks5UdpDatagram};
+use fast_socks5::util::target_addr::TargetAddr;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Mutex};
+use tracing::{debug, info, trace, warn};
+
+/// 一个共享 relay 的统计
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SharedRelayStats {
+    /// 上游名称
+    pub upstream: String,
+    /// SOCKS5 服务器的 relay 地址
+    pub relay_addr: SocketAddr,
+    /// 使用该 relay 的会话数
+    pub sessions: usize,
+}
+
+/// 共享 relay 的计数，与会话管理器共用
+#[derive(Debug, Clone, Default)]
+pub struct RelayCounters {
+    /// 找不到对应会话而丢弃的回包数
+    pub unmatched: Arc<AtomicU64>,
+    /// 被无响应检测判定失效的 relay 数
+    pub declared_dead: Arc<AtomicU64>,
+    /// SOCKS5 UDP 分片的处理方式及计数
+    pub fragments: FragmentPolicy,
+}
+
+/// 一个共享的 relay
+pub struct SharedRelay {
+    datagram: Socks5UdpDatagram,
+    relay_addr: SocketAddr,
+    /// 会话目标 (规范化的 IP 地址) -> 该会话的接收队列
+    routes: StdMutex<HashMap<SocketAddr, mpsc::Sender<Vec<u8>>>>,
+    /// 所有会话合计的无响应检测
+    watchdog: StdMutex<RelayWatchdog>,
+    closed: AtomicBool,
+    /// 读取任务，关闭连接池时中止
+    task: StdMutex<Option<tokio::task::JoinHandle<()>>>,
+}
+
+impl SharedRelay {
+    /// 发送用的 relay
+    pub fn datagram(&self) -> &Socks5UdpDatagram {
+        &self.datagram
+    }
+
+    /// SOCKS5 服务器的 relay 地址
+    pub fn relay_addr(&self) -> SocketAddr {
+        self.relay_addr
+    }
+
+    /// 记录一次经该 relay 发出的包
+    pub fn record_sent(&self) {
+        self.watchdog
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .sent();
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    fn session_count(&self) -> usize {
+        self.routes.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    /// 为目标 `remote` 登记一个会话；relay 已关闭或已有该目标时返回 None
+    fn try_attach(
+        self: &Arc<Self>,
+        remote: SocketAddr,
+        capacity: usize,
+    ) -> Option<RelayAttachment> {
+        let remote = canonical(remote);
+        let mut routes = self.routes.lock().unwrap_or_else(|e| e.into_inner());
+        if self.is_closed() || routes.contains_key(&remote) {
+            return None;
+        }
+        let (tx, rx) = mpsc::channel(capacity.max(1));
+        routes.insert(remote, tx);
+        Some(RelayAttachment {
+            relay: Arc::clone(self),
+            remote,
+            inbound: rx,
+        })
+    }
+
+    /// 标记为已关闭并释放所有会话的接收队列
+    fn close(&self) {
+        let mut routes = self.routes.lock().unwrap_or_else(|e| e.into_inner());
+        self.closed.store(true, Ordering::Release);
+        routes.clear();
+    }
+
+    /// 读取回包并按来源地址分发，直到 relay 失效
+    async fn run(
+        self: Arc<Self>,
+        mut control: TcpStream,
+        buf_size: usize,
+        probe_interval: Option<Duration>,
+        counters: RelayCounters,
+    ) {
+        let mut buf = vec![0u8; buf_size];
+        let mut truncation = TruncationWarnings::default();
+        let mut fragments = RelayFragments::new(counters.fragments.clone());
+        // 发送来自各个会话，按检测间隔的一半定期检查，判定最多延迟半个间隔
+        let mut probe = probe_interval.map(|interval| tokio::time::interval(interval / 2));
+        loop {
+            tokio::select! {
+                recv_res = self.datagram.get_ref().recv(&mut buf) => {
+                    let n = match recv_res {
+                        Ok(n) => n,
+                        Err(e) => {
+                            warn!(relay = %self.relay_addr, error = %e, "shared SOCKS5 UDP relay receive failed, closing it");
+                            break;
+                        }
+                    };
+                    if n >= buf.len() {
+                        truncation.record("SOCKS5 relay datagram", self.relay_addr, buf.len());
+                    }
+                    self.watchdog.lock().unwrap_or_else(|e| e.into_inner()).received();
+                    self.dispatch(&buf[..n], &mut fragments, &counters.unmatched).await;
+                }
+                _ = async {
+                    match &mut probe {
+                        Some(probe) => probe.tick().await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    let deadline = self.watchdog.lock().unwrap_or_else(|e| e.into_inner()).deadline();
+                    if deadline.is_some_and(|deadline| deadline <= Instant::now()) {
+                        counters.declared_dead.fetch_add(1, Ordering::Relaxed);
+                        warn!(
+                            relay = %self.relay_addr,
+                            sessions = self.session_count(),
+                            "shared SOCKS5 UDP relay stopped answering while its control connection stayed open, ending its sessions"
+                        );
+                        break;
+                    }
+                }
+                _ = wait_control_closed(&mut control) => {
+                    warn!(
+                        relay = %self.relay_addr,
+                        sessions = self.session_count(),
+                        "shared SOCKS5 UDP relay closed its control connection, ending its sessions"
+                    );
+                    break;
+                }
+            }
+        }
+        self.close();
+    }
+
+    /// 把一个 SOCKS5 UDP 数据报交给来源地址对应的会话
+    async fn dispatch(
+        &self,
+        datagram: &[u8],
+        fragments: &mut RelayFragments,
+        unmatched: &AtomicU64,
+    ) {
+        let (frag, remote, payload) = match fast_socks5::parse_udp_request(datagram).await {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                debug!(relay = %self.relay_addr, error = %e, "malformed SOCKS5 UDP datagram dropped");
+                return;
+            }
+        };
+        let Some(payload) = fragments.accept(frag, &remote, payload, self.relay_addr) else {
+            return;
+        };
+        if payload.is_empty() {
+            return;
+        }
+        let tx = match remote {
+            TargetAddr::Ip(addr) => self
+                .routes
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .get(&canonical(addr))
+                .cloned(),
+            TargetAddr::Domain(..) => None,
+        };
+        let Some(tx) = tx else {
+            unmatched.fetch_add(1, Ordering::Relaxed);
+            debug!(
+                relay = %self.relay_addr,
+                remote = %remote,
+                "packet on shared SOCKS5 UDP relay dropped, no session targets it"
+            );
+            return;
+        };
+        if tx.try_send(payload.into_owned()).is_err() {
+            trace!(
+                relay = %self.relay_addr,
+                remote = %remote,
+                "session queue full or closed, relay packet dropped"
+            );
+        }
+    }
+}
+
+/// 会话在共享 relay 上的登记，drop 时注销
+pub struct RelayAttachment {
+    relay: Arc<SharedRelay>,
+    remote: SocketAddr,
+    /// 该会话的回包 (已去掉 SOCKS5 UDP 头部)；relay 失效后关闭
+    pub inbound: mpsc::Receiver<Vec<u8>>,
+}
+
+impl RelayAttachment {
+    /// 所在的 relay
+    pub fn relay(&self) -> &SharedRelay {
+        &self.relay
+    }
+}
+
+impl Drop for RelayAttachment {
+    fn drop(&mut self) {
+        self.relay
+            .routes
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.remote);
+    }
+}
+
+/// 一个上游的共享 relay
+#[derive(Default)]
+struct UpstreamRelays {
+    relays: Vec<Arc<SharedRelay>>,
+    /// 下一次轮询的起点
+    next: usize,
+}
+
+/// 各上游的共享 relay
+pub struct SharedRelayPool {
+    /// 每个上游最多的 relay 数
+    size: usize,
+    /// 每个会话的接收队列长度
+    queue_capacity: usize,
+    /// relay 接收缓冲区大小
+    buf_size: usize,
+    /// 无响应检测间隔；None 表示不检测
+    probe_interval: Option<Duration>,
+    /// 上游名称 -> relay；新建 relay 时持有该上游的锁，同一上游的会话建立串行等待
+    upstreams: HashMap<String, Mutex<UpstreamRelays>>,
+    /// 活动 relay 的地址，监听端忽略来自这些地址的包
+    relay_addrs: Arc<RelayAddrs>,
+    counters: RelayCounters,
+}
+
+impl SharedRelayPool {
+    /// 为 `upstreams` 中的每个上游创建空的 relay 池
+    pub fn new<'a>(
+        size: usize,
+        queue_capacity: usize,
+        buf_size: usize,
+        probe_interval: Option<Duration>,
+        upstreams: impl IntoIterator<Item = &'a str>,
+        relay_addrs: Arc<RelayAddrs>,
+        counters: RelayCounters,
+    ) -> Self {
+        Self {
+            size: size.max(1),
+            queue_capacity,
+            buf_size,
+            probe_interval,
+            upstreams: upstreams
+                .into_iter()
+                .map(|name| (name.to_string(), Mutex::default()))
+                .collect(),
+            relay_addrs,
+            counters,
+        }
+    }
+
+    /// 为目标 `remote` 分配一个共享 relay
+    ///
+    /// relay 数未达上限时用 `associate` 新建一个 (读取任务计入 `metrics` 的任务数)，
+    /// 否则从上次的位置起轮流尝试已有的 relay；所有 relay 上都已有该目标时返回 None，
+    /// 调用方改用独占的 relay。`associate` 失败时原样返回其错误。
+    pub async fn attach<F, Fut, E>(
+        &self,
+        upstream: &str,
+        remote: SocketAddr,
+        metrics: &Arc<Registry>,
+        associate: F,
+    ) -> Result<Option<RelayAttachment>, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<(Socks5UdpDatagram, SocketAddr, TcpStream), E>>,
+    {
+        let Some(relays) = self.upstreams.get(upstream) else {
+            return Ok(None);
+        };
+        let mut relays = relays.lock().await;
+        relays.relays.retain(|relay| !relay.is_closed());
+
+        if relays.relays.len() < self.size {
+            let (datagram, relay_addr, control) = associate().await?;
+            let relay = self.spawn_relay(datagram, relay_addr, control, metrics);
+            info!(
+                relay = %relay_addr,
+                upstream,
+                index = relays.relays.len() + 1,
+                pool_size = self.size,
+                "shared SOCKS5 UDP relay opened"
+            );
+            relays.relays.push(Arc::clone(&relay));
+            return Ok(relay.try_attach(remote, self.queue_capacity));
+        }
+
+        let count = relays.relays.len();
+        for offset in 0..count {
+            let index = (relays.next + offset) % count;
+            if let Some(attachment) = relays.relays[index].try_attach(remote, self.queue_capacity) {
+                relays.next = (index + 1) % count;
+                return Ok(Some(attachment));
+            }
+        }
+        Ok(None)
+    }
+
+    fn spawn_relay(
+        &self,
+        datagram: Socks5UdpDatagram,
+        relay_addr: SocketAddr,
+        control: TcpStream,
+        metrics: &Arc<Registry>,
+    ) -> Arc<SharedRelay> {
+        let relay = Arc::new(SharedRelay {
+            datagram,
+            relay_addr,
+            routes: StdMutex::default(),
+            watchdog: StdMutex::new(RelayWatchdog::new(self.probe_interval)),
+            closed: AtomicBool::new(false),
+            task: StdMutex::default(),
+        });
+        let guard = RelayAddrGuard::register(&self.relay_addrs, relay_addr);
+        let task_guard = metrics.track_task(TaskKind::Relay);
+        let task = tokio::spawn({
+            let relay = Arc::clone(&relay);
+            let counters = self.counters.clone();
+            let buf_size = self.buf_size;
+            let probe_interval = self.probe_interval;
+            async move {
+                let _guard = guard;
+                let _task = task_guard;
+                relay.run(control, buf_size, probe_interval, counters).await;
+            }
+        });
+        *relay.task.lock().unwrap_or_else(|e| e.into_inner()) = Some(task);
+        relay
+    }
+
+    /// 各 relay 的会话数，按上游名称和 relay 地址排序
+    pub async fn stats(&self) -> Vec<SharedRelayStats> {
+        let mut stats = Vec::new();
+        for (upstream, relays) in &self.upstreams {
+            for relay in &relays.lock().await.relays {
+                if relay.is_closed() {
+                    continue;
+                }
+                stats.push(SharedRelayStats {
+                    upstream: upstream.clone(),
+                    relay_addr: relay.relay_addr,
+                    sessions: relay.session_count(),
+                });
+            }
+        }
+        stats.sort_by(|a, b| (&a.upstream, a.relay_addr).cmp(&(&b.upstream, b.relay_addr)));
+        stats
+    }
+
+    /// 关闭所有 relay：结束读取任务 (关闭控制连接) 并释放会话的接收队列
+    pub async fn close_all(&self) {
+        for relays in self.upstreams.values() {
+            for relay in relays.lock().await.relays.drain(..) {
+                relay.close();
+                if let Some(task) = relay.task.lock().unwrap_or_else(|e| e.into_inner()).take() {
+                    task.abort();
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,48 @@
+//! QUIC 连接级 tracing span
+//!
+//! 每个新流的 Initial 到达时创建一个 `quic_session` span，携带 DCID、客户端地址，
+//! 提取到 SNI 后再记录 SNI (字段名见 [`crate::logging`])。准入检查、会话建立和 relay 任务都在该 span 内运行，
+//! 其中的日志自动带上这些字段，解析/解密函数不再自行打印 DCID。
+
+use std::fmt;
+use std::net::SocketAddr;
+use tracing::Span;
+
+/// 以小写十六进制输出字节串
+pub struct Hex<'a>(pub &'a [u8]);
+
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for b in self.0 {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+/// 创建 `quic_session` span；`sni` 字段留空，由 [`record_sni`] 填入
+pub fn session_span(dcid: &[u8], client: SocketAddr) -> Span {
+    tracing::info_span!(
+        "quic_session",
+        listener = "quic",
+        dcid = %Hex(dcid),
+        client = %client,
+        sni = tracing::field::Empty
+    )
+}
+
+/// 在当前 span 中记录提取到的 SNI
+pub fn record_sni(sni: &str) {
+    Span::current().record("sni", sni);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex_display() {
+        assert_eq!(Hex(&[0x83, 0x94, 0x00, 0x0f]).to_string(), "8394000f");
+        assert_eq!(Hex(&[]).to_string(), "");
+    }
+}
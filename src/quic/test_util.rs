@@ -0,0 +1,245 @@
+//! 测试辅助：构造带 Header Protection 和 AEAD 保护的 QUIC Initial 包
+
+use crate::quic::crypto::{derive_initial_keys_for_role, InitialKeyRole};
+use crate::quic::parser::initial_packet_type;
+use ring::aead::quic::{HeaderProtectionKey, AES_128};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_128_GCM};
+
+pub use crate::quic::parser::encode_varint;
+
+/// QUIC 客户端 Initial 包的最小 UDP payload 长度 (RFC 9000 Section 14.1)
+const MIN_INITIAL_SIZE: usize = 1200;
+
+/// 构造只含 SNI 扩展的 TLS ClientHello handshake 消息 (不含 record 头)
+pub fn client_hello_handshake(sni: &str) -> Vec<u8> {
+    client_hello_with_extensions(Some(sni), &[])
+}
+
+/// 构造带 ALPN 扩展的 ClientHello，`protocols` 为空时不带 ALPN 扩展
+pub fn alpn_client_hello_handshake(sni: &str, protocols: &[&str]) -> Vec<u8> {
+    if protocols.is_empty() {
+        return client_hello_handshake(sni);
+    }
+
+    let mut list = Vec::new();
+    for protocol in protocols {
+        list.push(protocol.len() as u8);
+        list.extend_from_slice(protocol.as_bytes());
+    }
+    let mut ext = vec![0x00, 0x10];
+    ext.extend_from_slice(&((list.len() + 2) as u16).to_be_bytes());
+    ext.extend_from_slice(&(list.len() as u16).to_be_bytes());
+    ext.extend_from_slice(&list);
+    client_hello_with_extensions(Some(sni), &ext)
+}
+
+/// 构造使用 ECH 的外层 ClientHello，`public_name` 为外层 SNI
+pub fn ech_client_hello_handshake(public_name: Option<&str>) -> Vec<u8> {
+    // ECHClientHello: type = outer + HPKE cipher suite + config_id + enc + payload
+    let mut ech = vec![0x00, 0x00, 0x01, 0x00, 0x01, 0x2a];
+    ech.extend_from_slice(&[0x00, 0x20]);
+    ech.extend_from_slice(&[0x5a; 32]);
+    ech.extend_from_slice(&[0x00, 0x40]);
+    ech.extend_from_slice(&[0xa5; 64]);
+
+    let mut ext = vec![0xfe, 0x0d];
+    ext.extend_from_slice(&(ech.len() as u16).to_be_bytes());
+    ext.extend_from_slice(&ech);
+    client_hello_with_extensions(public_name, &ext)
+}
+
+/// 构造带 `key_share_len` 字节 key_share 扩展的 ClientHello
+///
+/// 模拟带 post-quantum key share (X25519MLKEM768 约 1.2KB) 的浏览器 ClientHello，
+/// 一个 Initial 装不下，会被拆到多个 Initial 中。
+pub fn large_client_hello_handshake(sni: &str, key_share_len: usize) -> Vec<u8> {
+    // KeyShareEntry: group (X25519MLKEM768) + key_exchange
+    let mut entry = vec![0x11, 0xec];
+    entry.extend_from_slice(&(key_share_len as u16).to_be_bytes());
+    entry.extend((0..key_share_len).map(|i| i as u8));
+
+    let mut key_share = vec![0x00, 0x33];
+    key_share.extend_from_slice(&((entry.len() + 2) as u16).to_be_bytes());
+    key_share.extend_from_slice(&(entry.len() as u16).to_be_bytes());
+    key_share.extend_from_slice(&entry);
+    client_hello_with_extensions(Some(sni), &key_share)
+}
+
+/// 构造 SNI 扩展 (如有) 在前、`extra` 扩展在后的 ClientHello
+pub fn client_hello_with_extensions(sni: Option<&str>, extra: &[u8]) -> Vec<u8> {
+    let mut ext = Vec::new();
+    if let Some(sni) = sni {
+        let name = sni.as_bytes();
+        ext.extend_from_slice(&[0x00, 0x00]);
+        ext.extend_from_slice(&((name.len() + 5) as u16).to_be_bytes());
+        ext.extend_from_slice(&((name.len() + 3) as u16).to_be_bytes());
+        ext.push(0x00);
+        ext.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        ext.extend_from_slice(name);
+    }
+    ext.extend_from_slice(extra);
+
+    let mut body = vec![0x03, 0x03];
+    body.extend_from_slice(&[0u8; 32]);
+    body.push(0x00);
+    body.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]);
+    body.extend_from_slice(&[0x01, 0x00]);
+    body.extend_from_slice(&(ext.len() as u16).to_be_bytes());
+    body.extend_from_slice(&ext);
+
+    let mut handshake = vec![0x01, 0x00];
+    handshake.extend_from_slice(&(body.len() as u16).to_be_bytes());
+    handshake.extend_from_slice(&body);
+    handshake
+}
+
+/// 构造 CRYPTO frame: type(0x06) + offset + length + data
+pub fn crypto_frame(offset: u64, data: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x06];
+    frame.extend_from_slice(&encode_varint(offset));
+    frame.extend_from_slice(&encode_varint(data.len() as u64));
+    frame.extend_from_slice(data);
+    frame
+}
+
+/// 用客户端 Initial 密钥保护 frames，返回完整的 Initial 包
+///
+/// payload 会用 PADDING 填充到 1200 字节；Packet Number 固定使用 2 字节编码。
+pub fn build_client_initial(
+    version: u32,
+    dcid: &[u8],
+    packet_number: u64,
+    frames: &[u8],
+) -> Vec<u8> {
+    build_client_initial_with_token(version, dcid, &[], packet_number, frames)
+}
+
+/// 构造携带 Token (例如收到 Retry 后) 的客户端 Initial 包
+pub fn build_client_initial_with_token(
+    version: u32,
+    dcid: &[u8],
+    token: &[u8],
+    packet_number: u64,
+    frames: &[u8],
+) -> Vec<u8> {
+    const PN_LEN: usize = 2;
+    const TAG_LEN: usize = 16;
+
+    let keys = derive_initial_keys_for_role(dcid, version, InitialKeyRole::Client).unwrap();
+
+    let mut header = vec![0xc0 | (initial_packet_type(version) << 4) | (PN_LEN as u8 - 1)];
+    header.extend_from_slice(&version.to_be_bytes());
+    header.push(dcid.len() as u8);
+    header.extend_from_slice(dcid);
+    header.push(0); // SCID length
+    header.extend_from_slice(&encode_varint(token.len() as u64));
+    header.extend_from_slice(token);
+
+    // Length 字段固定用 2 字节 VarInt，便于预先计算 padding
+    let unpadded = header.len() + 2 + PN_LEN + frames.len() + TAG_LEN;
+    let mut plaintext = frames.to_vec();
+    plaintext.resize(
+        frames.len() + MIN_INITIAL_SIZE.saturating_sub(unpadded),
+        0x00,
+    );
+
+    let length = (PN_LEN + plaintext.len() + TAG_LEN) as u16;
+    header.extend_from_slice(&(length | 0x4000).to_be_bytes());
+    let pn_offset = header.len();
+    header.extend_from_slice(&(packet_number as u16).to_be_bytes());
+
+    let mut nonce = [0u8; 12];
+    nonce.copy_from_slice(&keys.iv);
+    for (n, p) in nonce[4..].iter_mut().zip(packet_number.to_be_bytes()) {
+        *n ^= p;
+    }
+
+    let key = LessSafeKey::new(UnboundKey::new(&AES_128_GCM, &keys.key).unwrap());
+    key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::from(&header[..]),
+        &mut plaintext,
+    )
+    .unwrap();
+
+    let mut packet = header;
+    packet.extend_from_slice(&plaintext);
+
+    let hp_key = HeaderProtectionKey::new(&AES_128, &keys.hp_key).unwrap();
+    let sample_start = pn_offset + 4;
+    let mask = hp_key
+        .new_mask(&packet[sample_start..sample_start + 16])
+        .unwrap();
+    packet[0] ^= mask[0] & 0x0f;
+    for i in 0..PN_LEN {
+        packet[pn_offset + i] ^= mask[1 + i];
+    }
+
+    packet
+}
+
+/// RFC 9001 Appendix A 使用的客户端 DCID
+pub const RFC9001_DCID: [u8; 8] = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+
+/// RFC 9001 Appendix A.2 client Initial 中的 CRYPTO frame (ClientHello)
+pub const RFC9001_CLIENT_CRYPTO_FRAME: &str = concat!(
+    "060040f1010000ed0303ebf8fa56f12939b9584a3896472ec40bb863cfd3e868",
+    "04fe3a47f06a2b69484c00000413011302010000c000000010000e00000b6578",
+    "616d706c652e636f6dff01000100000a00080006001d00170018001000070005",
+    "04616c706e000500050100000000003300260024001d00209370b2c9caa47fba",
+    "baf4559fedba753de171fa71f50f1ce15d43e994ec74d748002b000302030400",
+    "0d0010000e0403050306030203080408050806002d00020101001c0002400100",
+    "3900320408ffffffffffffffff05048000ffff07048000ffff08011001048000",
+    "75300901100f088394c8f03e51570806048000ffff",
+);
+
+/// 解析十六进制字符串
+pub fn hex(s: &str) -> Vec<u8> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+/// 按 RFC 9001 Appendix A.2 组装完整的 client Initial
+///
+/// 未保护的 header 为 `c300000001088394c8f03e5157080000449e00000002`
+/// (PN = 2，4 字节编码)，payload 为 CRYPTO frame 加 PADDING 共 1162 字节。
+pub fn rfc9001_client_initial() -> Vec<u8> {
+    const PN_OFFSET: usize = 18;
+    const PN_LEN: usize = 4;
+    const PACKET_NUMBER: u64 = 2;
+
+    let keys =
+        derive_initial_keys_for_role(&RFC9001_DCID, 0x00000001, InitialKeyRole::Client).unwrap();
+    let mut header = hex("c300000001088394c8f03e5157080000449e00000002");
+    let mut plaintext = hex(RFC9001_CLIENT_CRYPTO_FRAME);
+    plaintext.resize(1162, 0x00);
+
+    let mut nonce = [0u8; 12];
+    nonce.copy_from_slice(&keys.iv);
+    for (n, p) in nonce[4..].iter_mut().zip(PACKET_NUMBER.to_be_bytes()) {
+        *n ^= p;
+    }
+    let key = LessSafeKey::new(UnboundKey::new(&AES_128_GCM, &keys.key).unwrap());
+    key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::from(&header[..]),
+        &mut plaintext,
+    )
+    .unwrap();
+    header.extend_from_slice(&plaintext);
+    let mut packet = header;
+
+    let hp_key = HeaderProtectionKey::new(&AES_128, &keys.hp_key).unwrap();
+    let sample_start = PN_OFFSET + 4;
+    let mask = hp_key
+        .new_mask(&packet[sample_start..sample_start + 16])
+        .unwrap();
+    packet[0] ^= mask[0] & 0x0f;
+    for i in 0..PN_LEN {
+        packet[PN_OFFSET + i] ^= mask[1 + i];
+    }
+
+    packet
+}
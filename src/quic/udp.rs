@@ -0,0 +1,759 @@
+//! QUIC 监听端的 UDP 批量收包
+//!
+//! 逐包 `recv_from` 每个数据报都要一次系统调用和一次任务唤醒。这里每次唤醒
+//! 尽量取空接收队列：Linux 上用 `recvmmsg` 一次系统调用读取多个数据报，
+//! 其他平台在一次可读事件内循环 `try_recv_from`。
+//!
+//! 多个 worker 时每个 worker 持有一个 `SO_REUSEPORT` 绑定到同一地址的 socket，
+//! 由内核按五元组把流量分散到各个 socket。
+//!
+//! 数据报大小上限由 `quic.max_datagram_size` 配置；恰好填满缓冲区的数据报
+//! 视为可能被截断，调用方应记录警告 (见 [`TruncationWarnings`])。
+//!
+//! 启用 `quic.enable_gso` 时 (仅 Linux)：监听 socket 打开 `UDP_GRO`，内核把同一
+//! 来源的连续数据报合并成一次读取，这里按控制消息中的分段大小拆回单个数据报；
+//! 发往同一客户端的等长数据报用 [`GsoBatch`] 攒成一批，以 `UDP_SEGMENT` 一次
+//! `sendmsg` 发出。socket 选项不可用或发送被拒绝时退回逐包发送。
+
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tracing::warn;
+
+/// 默认的单个数据报接收缓冲区大小 (以太网 MTU)
+pub const DEFAULT_MAX_DATAGRAM_SIZE: usize = 1500;
+
+/// QUIC 要求路径至少支持的 UDP payload 大小 (RFC 9000 Section 14)
+pub const MIN_DATAGRAM_SIZE: usize = 1200;
+
+/// UDP payload 的理论上限
+pub const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// 单次 GSO 发送最多的分段数 (内核 `UDP_MAX_SEGMENTS`)
+pub const GSO_MAX_SEGMENTS: usize = 64;
+
+/// 单次 GSO 发送的 payload 总字节数上限 (IPv4 下 UDP 能承载的最大 payload)
+const GSO_MAX_BYTES: usize = 65507;
+
+/// 把配置的数据报大小限制在 [`MIN_DATAGRAM_SIZE`, `MAX_DATAGRAM_SIZE`] 内
+pub fn clamp_datagram_size(size: usize) -> usize {
+    size.clamp(MIN_DATAGRAM_SIZE, MAX_DATAGRAM_SIZE)
+}
+
+/// 绑定 QUIC 监听用的 UDP socket
+///
+/// `reuse_port` 为 true 时设置 `SO_REUSEPORT`，允许多个 socket 绑定同一地址。
+pub fn bind_udp_socket(addr: SocketAddr, reuse_port: bool) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if reuse_port {
+        set_reuse_port(&socket)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+fn set_reuse_port(socket: &Socket) -> io::Result<()> {
+    socket.set_reuse_port(true)
+}
+
+#[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+fn set_reuse_port(_socket: &Socket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "SO_REUSEPORT is not supported on this platform",
+    ))
+}
+
+/// 在监听 socket 上开启 `UDP_GRO`
+///
+/// 开启后应使用 [`RecvBatch::with_gro`] 接收，以容纳合并后的读取。
+#[cfg(target_os = "linux")]
+pub fn enable_gro(socket: &UdpSocket) -> io::Result<()> {
+    setsockopt_int(socket, libc::UDP_GRO, 1)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn enable_gro(_socket: &UdpSocket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "UDP GRO is only supported on Linux",
+    ))
+}
+
+/// socket 是否已开启 `UDP_GRO`
+#[cfg(target_os = "linux")]
+pub fn gro_enabled(socket: &UdpSocket) -> bool {
+    getsockopt_int(socket, libc::UDP_GRO).is_ok_and(|value| value != 0)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn gro_enabled(_socket: &UdpSocket) -> bool {
+    false
+}
+
+/// 内核是否支持在该 socket 上使用 `UDP_SEGMENT` 发送
+#[cfg(target_os = "linux")]
+pub fn gso_supported(socket: &UdpSocket) -> bool {
+    getsockopt_int(socket, libc::UDP_SEGMENT).is_ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn gso_supported(_socket: &UdpSocket) -> bool {
+    false
+}
+
+#[cfg(target_os = "linux")]
+fn setsockopt_int(socket: &UdpSocket, option: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: value 在调用期间有效，长度与类型一致
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_UDP,
+            option,
+            (&value as *const libc::c_int).cast(),
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn getsockopt_int(socket: &UdpSocket, option: libc::c_int) -> io::Result<libc::c_int> {
+    use std::os::fd::AsRawFd;
+
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: value/len 在调用期间有效
+    let ret = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_UDP,
+            option,
+            (&mut value as *mut libc::c_int).cast(),
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(value)
+}
+
+/// 批次中的一个数据报
+pub struct Datagram<'a> {
+    /// 数据报内容
+    pub payload: &'a [u8],
+    /// 来源地址
+    pub src: SocketAddr,
+    /// 数据报可能被截断 (填满了接收缓冲区)
+    pub truncated: bool,
+}
+
+/// 批次中一个数据报的位置
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    start: usize,
+    len: usize,
+    src: SocketAddr,
+    truncated: bool,
+}
+
+/// 一批接收到的数据报
+///
+/// 所有缓冲区在一块连续内存中一次性分配，worker 在整个生命周期内复用。
+/// GRO 合并的一次读取会拆成多个数据报，因此数据报数量可能超过缓冲区数量。
+pub struct RecvBatch {
+    buf: Vec<u8>,
+    datagram_size: usize,
+    /// 单个接收缓冲区的大小；开启 GRO 时为合并读取的上限
+    slot_size: usize,
+    capacity: usize,
+    entries: Vec<Entry>,
+}
+
+impl RecvBatch {
+    /// 创建最多容纳 `capacity` 个数据报、每个最大 `datagram_size` 字节的批次
+    pub fn new(capacity: usize, datagram_size: usize) -> Self {
+        Self::with_slot_size(capacity, datagram_size, datagram_size)
+    }
+
+    /// 创建用于开启了 `UDP_GRO` 的 socket 的批次
+    ///
+    /// 每个缓冲区按合并读取的上限 (64 KiB) 分配，读取后按分段大小拆分。
+    pub fn with_gro(capacity: usize, datagram_size: usize) -> Self {
+        Self::with_slot_size(capacity, datagram_size, MAX_DATAGRAM_SIZE)
+    }
+
+    fn with_slot_size(capacity: usize, datagram_size: usize, slot_size: usize) -> Self {
+        let capacity = capacity.max(1);
+        let datagram_size = datagram_size.max(1);
+        let slot_size = slot_size.max(datagram_size);
+        Self {
+            buf: vec![0u8; capacity * slot_size],
+            datagram_size,
+            slot_size,
+            capacity,
+            entries: Vec::with_capacity(capacity),
+        }
+    }
+
+    /// 每次读取最多使用的缓冲区数量
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// 单个数据报的缓冲区大小
+    pub fn datagram_size(&self) -> usize {
+        self.datagram_size
+    }
+
+    /// 本批收到的数据报数量
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// 本批是否为空
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// 按接收顺序遍历
+    pub fn iter(&self) -> impl Iterator<Item = Datagram<'_>> {
+        self.entries.iter().map(move |entry| Datagram {
+            payload: &self.buf[entry.start..entry.start + entry.len],
+            src: entry.src,
+            truncated: entry.truncated,
+        })
+    }
+
+    /// 第 `slot` 个缓冲区
+    #[cfg(not(target_os = "linux"))]
+    fn slot_mut(&mut self, slot: usize) -> &mut [u8] {
+        let start = slot * self.slot_size;
+        &mut self.buf[start..start + self.slot_size]
+    }
+
+    /// 记录第 `slot` 个缓冲区中读到的 `len` 字节
+    ///
+    /// `segment_size` 为 GRO 控制消息给出的分段大小，按它拆成多个数据报；
+    /// 超过 `datagram_size` 的部分截掉并标记为可能被截断。
+    fn push_read(
+        &mut self,
+        slot: usize,
+        len: usize,
+        src: SocketAddr,
+        segment_size: Option<usize>,
+        kernel_truncated: bool,
+    ) {
+        let base = slot * self.slot_size;
+        if len == 0 {
+            self.entries.push(Entry {
+                start: base,
+                len: 0,
+                src,
+                truncated: kernel_truncated,
+            });
+            return;
+        }
+        let segment_size = segment_size.filter(|size| *size > 0).unwrap_or(len);
+        for offset in (0..len).step_by(segment_size) {
+            let segment_len = segment_size.min(len - offset);
+            self.entries.push(Entry {
+                start: base + offset,
+                len: segment_len.min(self.datagram_size),
+                src,
+                truncated: kernel_truncated
+                    || segment_len >= self.slot_size
+                    || segment_len > self.datagram_size,
+            });
+        }
+    }
+}
+
+/// 数据报截断警告，最多每 10 秒输出一次
+#[derive(Debug, Default)]
+pub struct TruncationWarnings {
+    last_warning: Option<Instant>,
+    suppressed: u64,
+}
+
+impl TruncationWarnings {
+    /// 记录一个可能被截断的数据报
+    pub fn record(&mut self, what: &str, src: impl std::fmt::Display, buf_size: usize) {
+        let now = Instant::now();
+        let should_warn = self
+            .last_warning
+            .is_none_or(|last| now.duration_since(last) >= Duration::from_secs(10));
+        if !should_warn {
+            self.suppressed += 1;
+            return;
+        }
+        self.last_warning = Some(now);
+        warn!(
+            what,
+            remote = %src,
+            buffer_size = buf_size,
+            suppressed = self.suppressed,
+            "datagram filled the receive buffer and may be truncated; consider raising quic.max_datagram_size"
+        );
+        self.suppressed = 0;
+    }
+}
+
+/// 等待 socket 可读并读取一批数据报，返回本批数量
+pub async fn recv_batch(socket: &UdpSocket, batch: &mut RecvBatch) -> io::Result<usize> {
+    batch.entries.clear();
+
+    #[cfg(target_os = "linux")]
+    {
+        socket
+            .async_io(tokio::io::Interest::READABLE, || recvmmsg(socket, batch))
+            .await?;
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let (len, addr) = socket.recv_from(batch.slot_mut(0)).await?;
+        batch.push_read(0, len, addr, None, false);
+        for slot in 1..batch.capacity() {
+            match socket.try_recv_from(batch.slot_mut(slot)) {
+                Ok((len, addr)) => batch.push_read(slot, len, addr, None, false),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    Ok(batch.entries.len())
+}
+
+/// 非阻塞 `recvmmsg`；接收队列为空时返回 `WouldBlock`
+#[cfg(target_os = "linux")]
+fn recvmmsg(socket: &UdpSocket, batch: &mut RecvBatch) -> io::Result<()> {
+    use std::mem::{size_of, zeroed};
+    use std::os::fd::AsRawFd;
+
+    let capacity = batch.capacity();
+    // SAFETY: sockaddr_storage/mmsghdr 是纯 C 结构体，全零是合法值
+    let mut storages: Vec<libc::sockaddr_storage> = vec![unsafe { zeroed() }; capacity];
+    // 每条消息一个控制消息缓冲区，用于接收 UDP_GRO 的分段大小 (u64 保证 cmsghdr 对齐)
+    let mut controls = vec![[0u64; 8]; capacity];
+    let size = batch.slot_size;
+    let mut iovecs: Vec<libc::iovec> = batch
+        .buf
+        .chunks_exact_mut(size)
+        .map(|buf| libc::iovec {
+            iov_base: buf.as_mut_ptr().cast(),
+            iov_len: buf.len(),
+        })
+        .collect();
+    let mut msgs: Vec<libc::mmsghdr> = (0..capacity)
+        .map(|i| {
+            let mut msg: libc::mmsghdr = unsafe { zeroed() };
+            msg.msg_hdr.msg_name = (&mut storages[i] as *mut libc::sockaddr_storage).cast();
+            msg.msg_hdr.msg_namelen = size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            msg.msg_hdr.msg_iov = &mut iovecs[i];
+            msg.msg_hdr.msg_iovlen = 1;
+            msg.msg_hdr.msg_control = controls[i].as_mut_ptr().cast();
+            msg.msg_hdr.msg_controllen = size_of::<[u64; 8]>() as _;
+            msg
+        })
+        .collect();
+
+    // SAFETY: msgs 中的指针指向本函数内存活的 storages/iovecs/controls 及 batch 的缓冲区
+    let n = unsafe {
+        libc::recvmmsg(
+            socket.as_raw_fd(),
+            msgs.as_mut_ptr(),
+            capacity as libc::c_uint,
+            libc::MSG_DONTWAIT,
+            std::ptr::null_mut(),
+        )
+    };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    for (i, msg) in msgs.iter().enumerate().take(n as usize) {
+        // SAFETY: 内核已写入 msg_namelen 字节的地址
+        let addr = unsafe { socket2::SockAddr::new(storages[i], msg.msg_hdr.msg_namelen) };
+        let Some(addr) = addr.as_socket() else {
+            continue;
+        };
+        let kernel_truncated = msg.msg_hdr.msg_flags & libc::MSG_TRUNC != 0;
+        let segment_size = gro_segment_size(&msg.msg_hdr);
+        batch.push_read(
+            i,
+            msg.msg_len as usize,
+            addr,
+            segment_size,
+            kernel_truncated,
+        );
+    }
+    Ok(())
+}
+
+/// 从 `recvmsg` 的控制消息中取出 UDP_GRO 分段大小
+#[cfg(target_os = "linux")]
+fn gro_segment_size(hdr: &libc::msghdr) -> Option<usize> {
+    // SAFETY: hdr 的控制消息缓冲区由内核填写，CMSG_* 宏只在 msg_controllen 范围内遍历
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(hdr);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_UDP && (*cmsg).cmsg_type == libc::UDP_GRO {
+                let size = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int);
+                return usize::try_from(size).ok();
+            }
+            cmsg = libc::CMSG_NXTHDR(hdr, cmsg);
+        }
+    }
+    None
+}
+
+/// 发往同一目的地址的一组数据报，开启 GSO 时用一次 `sendmsg` 发出
+///
+/// GSO 要求除最后一个外的分段等长，[`push`](Self::push) 在无法并入当前批次时
+/// 返回 false，调用方应先 [`flush`](Self::flush) 再重新加入。
+#[derive(Debug)]
+pub struct GsoBatch {
+    buf: Vec<u8>,
+    segment_size: usize,
+    segments: usize,
+    max_segments: usize,
+}
+
+impl GsoBatch {
+    /// 创建最多 `max_segments` 个分段的批次
+    pub fn new(max_segments: usize) -> Self {
+        Self {
+            buf: Vec::new(),
+            segment_size: 0,
+            segments: 0,
+            max_segments: max_segments.clamp(1, GSO_MAX_SEGMENTS),
+        }
+    }
+
+    /// 当前分段数
+    pub fn len(&self) -> usize {
+        self.segments
+    }
+
+    /// 是否没有待发送的数据报
+    pub fn is_empty(&self) -> bool {
+        self.segments == 0
+    }
+
+    /// 尝试加入一个数据报；与当前批次无法合并时返回 false
+    pub fn push(&mut self, payload: &[u8]) -> bool {
+        if self.segments == 0 {
+            self.buf.clear();
+            self.buf.extend_from_slice(payload);
+            self.segment_size = payload.len();
+            self.segments = 1;
+            return true;
+        }
+        // 上一个分段较短时批次已结束 (只有最后一个分段可以短于分段大小)
+        let last_was_short = self.buf.len() != self.segments * self.segment_size;
+        if last_was_short
+            || self.segments >= self.max_segments
+            || payload.is_empty()
+            || payload.len() > self.segment_size
+            || self.buf.len() + payload.len() > GSO_MAX_BYTES
+        {
+            return false;
+        }
+        self.buf.extend_from_slice(payload);
+        self.segments += 1;
+        true
+    }
+
+    /// 发送并清空批次
+    ///
+    /// `gso` 为 false 或只有一个分段时逐个 `send_to`；GSO 发送失败时关闭 `gso`
+    /// (之后所有共享该标志的发送方都逐包发送)，并逐包重发本批。
+    pub async fn flush(
+        &mut self,
+        socket: &UdpSocket,
+        dest: SocketAddr,
+        gso: &AtomicBool,
+    ) -> io::Result<()> {
+        if self.segments == 0 {
+            return Ok(());
+        }
+        let result = self.send(socket, dest, gso).await;
+        self.segments = 0;
+        self.buf.clear();
+        result
+    }
+
+    async fn send(&self, socket: &UdpSocket, dest: SocketAddr, gso: &AtomicBool) -> io::Result<()> {
+        if self.segments > 1 && gso.load(Ordering::Relaxed) {
+            match send_gso(socket, &self.buf, self.segment_size, dest).await {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    if gso.swap(false, Ordering::Relaxed) {
+                        warn!(
+                            dest = %dest,
+                            error = %e,
+                            "UDP GSO send failed, falling back to one datagram per send"
+                        );
+                    }
+                }
+            }
+        }
+        for segment in self.buf.chunks(self.segment_size.max(1)) {
+            socket.send_to(segment, dest).await?;
+        }
+        Ok(())
+    }
+}
+
+/// 以 `UDP_SEGMENT` 发送 `buf`，内核按 `segment_size` 切成多个数据报
+#[cfg(target_os = "linux")]
+async fn send_gso(
+    socket: &UdpSocket,
+    buf: &[u8],
+    segment_size: usize,
+    dest: SocketAddr,
+) -> io::Result<()> {
+    socket
+        .async_io(tokio::io::Interest::WRITABLE, || {
+            sendmsg_gso(socket, buf, segment_size, dest)
+        })
+        .await
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn send_gso(
+    _socket: &UdpSocket,
+    _buf: &[u8],
+    _segment_size: usize,
+    _dest: SocketAddr,
+) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "UDP GSO is only supported on Linux",
+    ))
+}
+
+/// 非阻塞 `sendmsg`，附带 `UDP_SEGMENT` 控制消息
+#[cfg(target_os = "linux")]
+fn sendmsg_gso(
+    socket: &UdpSocket,
+    buf: &[u8],
+    segment_size: usize,
+    dest: SocketAddr,
+) -> io::Result<()> {
+    use std::mem::{size_of, zeroed};
+    use std::os::fd::AsRawFd;
+
+    let segment_size = u16::try_from(segment_size)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "GSO segment too large"))?;
+    let addr = socket2::SockAddr::from(dest);
+    let mut iov = libc::iovec {
+        iov_base: buf.as_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    // u64 保证 cmsghdr 对齐
+    let mut control = [0u64; 4];
+
+    // SAFETY: msghdr 是纯 C 结构体，全零是合法值；所有指针在 sendmsg 返回前有效，
+    // 控制消息缓冲区足以容纳一个 u16 的 cmsg
+    let n = unsafe {
+        let mut msg: libc::msghdr = zeroed();
+        msg.msg_name = addr.as_ptr() as *mut libc::c_void;
+        msg.msg_namelen = addr.len();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = libc::CMSG_SPACE(size_of::<u16>() as u32) as _;
+
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_UDP;
+        (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(size_of::<u16>() as u32) as _;
+        std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, segment_size);
+
+        libc::sendmsg(socket.as_raw_fd(), &msg, libc::MSG_DONTWAIT)
+    };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_recv_batch_drains_queued_datagrams() {
+        let socket = bind_udp_socket("127.0.0.1:0".parse().unwrap(), false).unwrap();
+        let addr = socket.local_addr().unwrap();
+        let sender = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        for i in 0..5u8 {
+            sender.send_to(&[i; 10], addr).unwrap();
+        }
+        // 等数据报全部进入接收队列
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+
+        let mut batch = RecvBatch::new(4, DEFAULT_MAX_DATAGRAM_SIZE);
+        assert_eq!(recv_batch(&socket, &mut batch).await.unwrap(), 4);
+        for (i, datagram) in batch.iter().enumerate() {
+            assert_eq!(datagram.payload, &[i as u8; 10]);
+            assert_eq!(datagram.src, sender.local_addr().unwrap());
+            assert!(!datagram.truncated);
+        }
+
+        assert_eq!(recv_batch(&socket, &mut batch).await.unwrap(), 1);
+        assert_eq!(batch.iter().next().unwrap().payload, &[4u8; 10]);
+    }
+
+    #[tokio::test]
+    async fn test_large_datagrams_fit_and_oversized_are_flagged() {
+        let socket = bind_udp_socket("127.0.0.1:0".parse().unwrap(), false).unwrap();
+        let addr = socket.local_addr().unwrap();
+        let sender = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.send_to(&[1u8; 4000], addr).unwrap();
+        sender.send_to(&[2u8; 9000], addr).unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+
+        let mut batch = RecvBatch::new(2, 4096);
+        let mut received = Vec::new();
+        while received.len() < 2 {
+            recv_batch(&socket, &mut batch).await.unwrap();
+            received.extend(
+                batch
+                    .iter()
+                    .map(|d| (d.payload.len(), d.payload[0], d.truncated)),
+            );
+        }
+        assert_eq!(received, vec![(4000, 1, false), (4096, 2, true)]);
+    }
+
+    #[test]
+    fn test_gro_read_is_split_by_segment_size() {
+        let src: SocketAddr = "192.0.2.1:443".parse().unwrap();
+        let mut batch = RecvBatch::with_gro(2, 1500);
+        batch.buf[..3100].copy_from_slice(
+            &[[1u8; 1200], [2u8; 1200]]
+                .concat()
+                .into_iter()
+                .chain([3u8; 700])
+                .collect::<Vec<_>>(),
+        );
+        batch.push_read(0, 3100, src, Some(1200), false);
+        // 未合并的读取超过 datagram_size 时截断并标记
+        batch.push_read(1, 2000, src, None, false);
+
+        let datagrams: Vec<_> = batch
+            .iter()
+            .map(|d| (d.payload.len(), d.payload[0], d.truncated))
+            .collect();
+        assert_eq!(
+            datagrams,
+            vec![
+                (1200, 1, false),
+                (1200, 2, false),
+                (700, 3, false),
+                (1500, 0, true)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_gso_batch_only_merges_equal_segments() {
+        let mut batch = GsoBatch::new(4);
+        assert!(batch.push(&[0u8; 1200]));
+        assert!(batch.push(&[0u8; 1200]));
+        // 比分段大的包不能加入
+        assert!(!batch.push(&[0u8; 1300]));
+        // 较短的包作为最后一个分段，之后批次结束
+        assert!(batch.push(&[0u8; 500]));
+        assert!(!batch.push(&[0u8; 500]));
+        assert_eq!(batch.len(), 3);
+
+        let mut batch = GsoBatch::new(2);
+        assert!(batch.push(&[0u8; 100]));
+        assert!(batch.push(&[0u8; 100]));
+        assert!(!batch.push(&[0u8; 100]));
+    }
+
+    /// 本机 socket 上用 GSO 发送一批数据报，开启或未开启 GRO 的接收方都应按原样收到
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_gso_send_round_trips_with_and_without_gro() {
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        if !gso_supported(&sender) {
+            return;
+        }
+        let mut expected: Vec<Vec<u8>> = (0..5u8).map(|i| vec![i; 1200]).collect();
+        expected.push(vec![9u8; 500]);
+
+        for gro in [false, true] {
+            let receiver = bind_udp_socket("127.0.0.1:0".parse().unwrap(), false).unwrap();
+            let mut batch = if gro {
+                if enable_gro(&receiver).is_err() {
+                    continue;
+                }
+                assert!(gro_enabled(&receiver));
+                RecvBatch::with_gro(8, DEFAULT_MAX_DATAGRAM_SIZE)
+            } else {
+                RecvBatch::new(8, DEFAULT_MAX_DATAGRAM_SIZE)
+            };
+
+            let gso = AtomicBool::new(true);
+            let mut outgoing = GsoBatch::new(GSO_MAX_SEGMENTS);
+            for payload in &expected {
+                assert!(outgoing.push(payload));
+            }
+            outgoing
+                .flush(&sender, receiver.local_addr().unwrap(), &gso)
+                .await
+                .unwrap();
+            assert!(outgoing.is_empty());
+            assert!(
+                gso.load(Ordering::Relaxed),
+                "GSO send fell back (gro={})",
+                gro
+            );
+
+            let mut received = Vec::new();
+            while received.len() < expected.len() {
+                tokio::time::timeout(Duration::from_secs(1), recv_batch(&receiver, &mut batch))
+                    .await
+                    .unwrap()
+                    .unwrap();
+                received.extend(batch.iter().map(|d| d.payload.to_vec()));
+            }
+            assert_eq!(received, expected, "gro={}", gro);
+        }
+    }
+
+    #[test]
+    fn test_clamp_datagram_size() {
+        assert_eq!(clamp_datagram_size(0), MIN_DATAGRAM_SIZE);
+        assert_eq!(clamp_datagram_size(9000), 9000);
+        assert_eq!(clamp_datagram_size(100_000), MAX_DATAGRAM_SIZE);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_reuse_port_allows_multiple_sockets() {
+        let first = bind_udp_socket("127.0.0.1:0".parse().unwrap(), true).unwrap();
+        let addr = first.local_addr().unwrap();
+        let second = bind_udp_socket(addr, true).unwrap();
+        assert_eq!(second.local_addr().unwrap(), addr);
+        assert!(bind_udp_socket(addr, false).is_err());
+    }
+}
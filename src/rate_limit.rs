@@ -0,0 +1,194 @@
+//! 按来源 IP 的令牌桶限速
+//!
+//! 用于保护代价高的处理路径 (例如 QUIC Initial 的密钥派生和解密)。
+//! 调用方自行持有限速器并决定统计口径，不同协议的计数互不影响。
+//!
+//! IPv6 按 /64 前缀计数：单个主机通常就分到一整个 /64，按 /128 计数时换个地址就能拿到
+//! 新的令牌桶，还会撑满跟踪表。
+
+use lru::LruCache;
+use std::net::{IpAddr, Ipv6Addr};
+use std::num::NonZeroUsize;
+use std::time::Instant;
+
+/// 默认最多跟踪的来源数量
+const DEFAULT_MAX_ENTRIES: usize = 65536;
+
+/// 单个来源的令牌桶
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+/// 按来源 IP 的令牌桶限速器
+///
+/// 每个来源 (IPv4 地址或 IPv6 /64) 以 `rate` 个/秒的速度补充令牌，最多积攒 `burst` 个；
+/// `rate` 为 0 时不限速。跟踪的来源达到上限后淘汰最久未出现的来源。
+#[derive(Debug)]
+pub struct PerIpRateLimiter {
+    buckets: LruCache<IpAddr, Bucket>,
+    rate: f64,
+    burst: f64,
+}
+
+impl PerIpRateLimiter {
+    /// 创建限速器
+    pub fn new(rate: f64, burst: u32) -> Self {
+        Self::with_max_entries(rate, burst, DEFAULT_MAX_ENTRIES)
+    }
+
+    /// 创建限速器，`max_entries` 为最多跟踪的来源数量
+    pub fn with_max_entries(rate: f64, burst: u32, max_entries: usize) -> Self {
+        Self {
+            buckets: LruCache::new(NonZeroUsize::new(max_entries).unwrap_or(NonZeroUsize::MIN)),
+            rate: rate.max(0.0),
+            burst: f64::from(burst.max(1)),
+        }
+    }
+
+    /// 是否启用限速
+    pub fn is_enabled(&self) -> bool {
+        self.rate > 0.0
+    }
+
+    /// 消耗 `ip` 的一个令牌；令牌不足时返回 false
+    pub fn allow(&mut self, ip: IpAddr) -> bool {
+        self.allow_at(ip, Instant::now())
+    }
+
+    fn allow_at(&mut self, ip: IpAddr, now: Instant) -> bool {
+        if !self.is_enabled() {
+            return true;
+        }
+
+        let (rate, burst) = (self.rate, self.burst);
+        // 未跟踪的来源等价于令牌已补满；已满时淘汰最久未出现的来源
+        let bucket = self.buckets.get_or_insert_mut(source(ip), || Bucket {
+            tokens: burst,
+            updated_at: now,
+        });
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.updated_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// 当前跟踪的来源数量
+    pub fn len(&self) -> usize {
+        self.buckets.len()
+    }
+
+    /// 是否没有跟踪任何来源
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
+    }
+}
+
+/// 计数用的来源：IPv4 为地址本身，IPv6 取 /64 前缀 (IPv4 映射地址按 IPv4 处理)
+fn source(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & !((1u128 << 64) - 1))),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([192, 0, 2, last])
+    }
+
+    #[test]
+    fn test_burst_then_refill() {
+        let mut limiter = PerIpRateLimiter::new(10.0, 3);
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.allow_at(ip(1), start));
+        }
+        assert!(!limiter.allow_at(ip(1), start));
+
+        // 100ms 补充 1 个令牌
+        let later = start + Duration::from_millis(100);
+        assert!(limiter.allow_at(ip(1), later));
+        assert!(!limiter.allow_at(ip(1), later));
+    }
+
+    #[test]
+    fn test_sources_are_limited_independently() {
+        let mut limiter = PerIpRateLimiter::new(1.0, 1);
+        let now = Instant::now();
+
+        assert!(limiter.allow_at(ip(1), now));
+        assert!(!limiter.allow_at(ip(1), now));
+        assert!(limiter.allow_at(ip(2), now));
+    }
+
+    #[test]
+    fn test_zero_rate_disables_limit() {
+        let mut limiter = PerIpRateLimiter::new(0.0, 1);
+        for _ in 0..100 {
+            assert!(limiter.allow(ip(1)));
+        }
+        assert!(limiter.is_empty());
+    }
+
+    #[test]
+    fn test_tracked_sources_are_bounded() {
+        let mut limiter = PerIpRateLimiter::with_max_entries(1.0, 1, 2);
+        let now = Instant::now();
+
+        for last in 1..=10 {
+            assert!(limiter.allow_at(ip(last), now));
+        }
+        assert!(limiter.len() <= 2);
+    }
+
+    #[test]
+    fn test_least_recently_seen_source_is_evicted() {
+        let mut limiter = PerIpRateLimiter::with_max_entries(1.0, 1, 2);
+        let now = Instant::now();
+
+        assert!(limiter.allow_at(ip(1), now));
+        assert!(limiter.allow_at(ip(2), now));
+        // 再次出现的 ip(1) 不会被淘汰，ip(2) 成为最久未出现的来源
+        assert!(!limiter.allow_at(ip(1), now));
+        assert!(limiter.allow_at(ip(3), now));
+
+        assert_eq!(limiter.len(), 2);
+        assert!(!limiter.allow_at(ip(1), now));
+        assert!(limiter.allow_at(ip(2), now));
+    }
+
+    #[test]
+    fn test_ipv6_sources_share_a_bucket_per_64() {
+        let mut limiter = PerIpRateLimiter::new(1.0, 2);
+        let now = Instant::now();
+        let v6 = |s: &str| s.parse::<IpAddr>().unwrap();
+
+        assert!(limiter.allow_at(v6("2001:db8:1:2::1"), now));
+        assert!(limiter.allow_at(v6("2001:db8:1:2:ffff::9"), now));
+        assert!(!limiter.allow_at(v6("2001:db8:1:2::abcd"), now));
+        // 相邻的 /64 单独计数
+        assert!(limiter.allow_at(v6("2001:db8:1:3::1"), now));
+        assert_eq!(limiter.len(), 2);
+
+        // IPv4 映射地址与 IPv4 共用令牌桶
+        assert!(limiter.allow_at(ip(1), now));
+        assert!(limiter.allow_at(v6("::ffff:192.0.2.1"), now));
+        assert!(!limiter.allow_at(ip(1), now));
+    }
+}
@@ -0,0 +1,379 @@
+//! 拒绝与失败原因
+//!
+//! 连接 (QUIC 为新流) 没有正常转发结束时，结果归入 [`RejectReason`] (按策略或协议拒绝)
+//! 或 [`FailureReason`] (建连、转发或内部出错) 之一。各监听器只在一个出口记录结果：
+//! TCP/HTTP 为访问记录结束时 ([`crate::access::AccessEntry::finish`])，
+//! QUIC 为收包循环记录处理结果时。同一个取值同时用于按原因的计数
+//! (`sniproxy_rejections_total`、`sniproxy_failures_total`)、访问日志的 `outcome`
+//! 和统计日志的分类，各处的数字因此一致。
+//!
+//! 标签是稳定的对外接口，新增变体时须同时加入 `ALL`，`as_label` 不使用通配分支。
+
+use crate::backend::{ConnectError, HttpConnectError};
+use crate::quic::error::QuicError;
+use crate::relay::is_idle_timeout;
+use crate::socks5::socks4::Socks4Error;
+use crate::socks5::{PoolError, Socks5Error};
+use std::io;
+
+/// 拒绝的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RejectReason {
+    /// 首个数据不是 TLS 记录 (扫描器等)
+    NotTls,
+    /// ClientHello 格式错误
+    MalformedClientHello,
+    /// 超时或连接关闭前没有收全 ClientHello
+    IncompleteClientHello,
+    /// 完整的 ClientHello 中没有 SNI
+    NoSni,
+    /// 使用 ECH 但没有外层 SNI
+    EchWithoutSni,
+    /// 只支持低于 `tls.min_accept_version` 的 TLS 版本
+    TlsVersion,
+    /// 主机不在白名单
+    NotWhitelisted,
+    /// 按 `rules.ech_policy` 拒绝 ECH
+    EchRejected,
+    /// ALPN 与 `quic.require_alpn` 没有交集
+    AlpnMismatch,
+    /// HTTP 请求中没有可用的 Host
+    NoHost,
+    /// CONNECT 请求的目标无法解析
+    InvalidConnect,
+    /// CONNECT 请求头超过 `http.connect_max_header_bytes`
+    HeaderTooLarge,
+    /// CONNECT 的目标端口不在允许列表
+    PortNotAllowed,
+    /// CONNECT 隧道内的 SNI 与请求的主机不符
+    SniMismatch,
+    /// 来源 IP 的新 Initial 超出限速
+    RateLimited,
+    /// 会话数达到上限
+    OverLimit,
+    /// 来源是 SOCKS5 relay 或本监听地址 (反射/回环)
+    Reflected,
+}
+
+impl RejectReason {
+    /// 全部取值，顺序即计数数组的下标
+    pub const ALL: [RejectReason; 17] = [
+        RejectReason::NotTls,
+        RejectReason::MalformedClientHello,
+        RejectReason::IncompleteClientHello,
+        RejectReason::NoSni,
+        RejectReason::EchWithoutSni,
+        RejectReason::TlsVersion,
+        RejectReason::NotWhitelisted,
+        RejectReason::EchRejected,
+        RejectReason::AlpnMismatch,
+        RejectReason::NoHost,
+        RejectReason::InvalidConnect,
+        RejectReason::HeaderTooLarge,
+        RejectReason::PortNotAllowed,
+        RejectReason::SniMismatch,
+        RejectReason::RateLimited,
+        RejectReason::OverLimit,
+        RejectReason::Reflected,
+    ];
+
+    /// `reason` 标签的取值
+    pub fn as_label(self) -> &'static str {
+        match self {
+            RejectReason::NotTls => "not_tls",
+            RejectReason::MalformedClientHello => "malformed_client_hello",
+            RejectReason::IncompleteClientHello => "incomplete_client_hello",
+            RejectReason::NoSni => "no_sni",
+            RejectReason::EchWithoutSni => "ech_without_sni",
+            RejectReason::TlsVersion => "version_rejected",
+            RejectReason::NotWhitelisted => "not_whitelisted",
+            RejectReason::EchRejected => "ech_rejected",
+            RejectReason::AlpnMismatch => "alpn_mismatch",
+            RejectReason::NoHost => "no_host",
+            RejectReason::InvalidConnect => "invalid_connect",
+            RejectReason::HeaderTooLarge => "header_too_large",
+            RejectReason::PortNotAllowed => "port_not_allowed",
+            RejectReason::SniMismatch => "sni_mismatch",
+            RejectReason::RateLimited => "rate_limited",
+            RejectReason::OverLimit => "over_limit",
+            RejectReason::Reflected => "reflected",
+        }
+    }
+
+    /// 按配置的策略拒绝 (而不是客户端数据不可用)
+    pub fn is_policy(self) -> bool {
+        match self {
+            RejectReason::TlsVersion
+            | RejectReason::NotWhitelisted
+            | RejectReason::EchRejected
+            | RejectReason::AlpnMismatch
+            | RejectReason::PortNotAllowed
+            | RejectReason::SniMismatch
+            | RejectReason::RateLimited
+            | RejectReason::OverLimit
+            | RejectReason::Reflected => true,
+            RejectReason::NotTls
+            | RejectReason::MalformedClientHello
+            | RejectReason::IncompleteClientHello
+            | RejectReason::NoSni
+            | RejectReason::EchWithoutSni
+            | RejectReason::NoHost
+            | RejectReason::InvalidConnect
+            | RejectReason::HeaderTooLarge => false,
+        }
+    }
+}
+
+/// 失败的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FailureReason {
+    /// 经 SOCKS5 上游建连失败 (按错误类别见 `sniproxy_socks5_errors_total`)
+    Socks5ConnectFailed,
+    /// 经 SOCKS4a 或 HTTP CONNECT 上游建连失败
+    UpstreamConnectFailed,
+    /// 上游建连或握手超时
+    UpstreamTimeout,
+    /// 本地连接池在等待时间内没有空闲名额
+    PoolExhausted,
+    /// 等待客户端数据超时
+    ClientTimeout,
+    /// 与客户端之间的 I/O 错误
+    ClientIo,
+    /// 转发中超过 `server.transfer_idle_timeout` 没有数据
+    IdleTimeout,
+    /// QUIC Initial 解密 (含密钥派生、头部保护) 失败
+    QuicDecryptFailed,
+    /// QUIC 包格式错误
+    QuicMalformed,
+    /// 建立 QUIC 会话失败
+    QuicSessionFailed,
+    /// 其它错误
+    Internal,
+}
+
+impl FailureReason {
+    /// 全部取值，顺序即计数数组的下标
+    pub const ALL: [FailureReason; 11] = [
+        FailureReason::Socks5ConnectFailed,
+        FailureReason::UpstreamConnectFailed,
+        FailureReason::UpstreamTimeout,
+        FailureReason::PoolExhausted,
+        FailureReason::ClientTimeout,
+        FailureReason::ClientIo,
+        FailureReason::IdleTimeout,
+        FailureReason::QuicDecryptFailed,
+        FailureReason::QuicMalformed,
+        FailureReason::QuicSessionFailed,
+        FailureReason::Internal,
+    ];
+
+    /// `reason` 标签的取值
+    pub fn as_label(self) -> &'static str {
+        match self {
+            FailureReason::Socks5ConnectFailed => "socks5_connect_failed",
+            FailureReason::UpstreamConnectFailed => "upstream_connect_failed",
+            FailureReason::UpstreamTimeout => "upstream_timeout",
+            FailureReason::PoolExhausted => "pool_exhausted",
+            FailureReason::ClientTimeout => "client_timeout",
+            FailureReason::ClientIo => "client_io",
+            FailureReason::IdleTimeout => "idle_timeout",
+            FailureReason::QuicDecryptFailed => "quic_decrypt_failed",
+            FailureReason::QuicMalformed => "quic_malformed",
+            FailureReason::QuicSessionFailed => "quic_session_failed",
+            FailureReason::Internal => "internal",
+        }
+    }
+
+    /// 经后端建连失败的归类
+    pub fn of_connect(error: &ConnectError) -> FailureReason {
+        match error {
+            ConnectError::Socks5(Socks5Error::Timeout { .. })
+            | ConnectError::Socks4(Socks4Error::Timeout(_))
+            | ConnectError::HttpConnect(HttpConnectError::Timeout(_)) => {
+                FailureReason::UpstreamTimeout
+            }
+            ConnectError::Socks5(_) => FailureReason::Socks5ConnectFailed,
+            ConnectError::Socks4(_) | ConnectError::HttpConnect(_) => {
+                FailureReason::UpstreamConnectFailed
+            }
+        }
+    }
+
+    /// 由处理过程返回的错误归类
+    pub fn of(error: &anyhow::Error) -> FailureReason {
+        if let Some(error) = error.downcast_ref::<Socks5Error>() {
+            return match error {
+                Socks5Error::Timeout { .. } => FailureReason::UpstreamTimeout,
+                _ => FailureReason::Socks5ConnectFailed,
+            };
+        }
+        if let Some(error) = error.downcast_ref::<ConnectError>() {
+            return FailureReason::of_connect(error);
+        }
+        if let Some(error) = error.downcast_ref::<PoolError>() {
+            return match error {
+                PoolError::Connect(error) => FailureReason::of_connect(error),
+                PoolError::Exhausted { .. } | PoolError::Closed => FailureReason::PoolExhausted,
+            };
+        }
+        if error.is::<tokio::time::error::Elapsed>() {
+            return FailureReason::ClientTimeout;
+        }
+        if let Some(error) = error.downcast_ref::<QuicError>() {
+            return match Outcome::of_quic_error(error) {
+                Outcome::Failed(reason) => reason,
+                _ => FailureReason::QuicMalformed,
+            };
+        }
+        match error.downcast_ref::<io::Error>() {
+            Some(error) if is_idle_timeout(error) => FailureReason::IdleTimeout,
+            Some(error) if error.kind() == io::ErrorKind::TimedOut => FailureReason::ClientTimeout,
+            Some(_) => FailureReason::ClientIo,
+            None => FailureReason::Internal,
+        }
+    }
+}
+
+/// 一个连接 (QUIC 为新流) 的最终结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// 转发完成后关闭
+    Closed,
+    /// 客户端没有发送任何数据就关闭了连接
+    ClientClosed,
+    Rejected(RejectReason),
+    Failed(FailureReason),
+}
+
+impl Outcome {
+    /// 日志 `outcome` 字段的取值；拒绝和失败为其原因的标签
+    pub fn as_label(self) -> &'static str {
+        match self {
+            Outcome::Closed => "closed",
+            Outcome::ClientClosed => "client_closed",
+            Outcome::Rejected(reason) => reason.as_label(),
+            Outcome::Failed(reason) => reason.as_label(),
+        }
+    }
+
+    /// 处理新流 Initial 时的 QUIC 错误对应的结果
+    pub fn of_quic_error(error: &QuicError) -> Outcome {
+        match error {
+            QuicError::TlsError(_) | QuicError::CryptoFrameError(_) => {
+                Outcome::Rejected(RejectReason::MalformedClientHello)
+            }
+            QuicError::NoSniFound => Outcome::Rejected(RejectReason::NoSni),
+            QuicError::KeyDerivationFailed(_)
+            | QuicError::HeaderProtectionFailed(_)
+            | QuicError::PacketNumberError(_)
+            | QuicError::DecryptionFailed(_) => Outcome::Failed(FailureReason::QuicDecryptFailed),
+            QuicError::PacketTooShort { .. }
+            | QuicError::VarIntError(_)
+            | QuicError::NotInitialPacket(_)
+            | QuicError::InvalidDcid(_)
+            | QuicError::UnsupportedVersion { .. } => Outcome::Failed(FailureReason::QuicMalformed),
+            QuicError::Other(_) => Outcome::Failed(FailureReason::Internal),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::IdleTimeout;
+    use anyhow::anyhow;
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    #[test]
+    fn labels_are_unique_and_indexed_in_order() {
+        for (i, reason) in RejectReason::ALL.into_iter().enumerate() {
+            assert_eq!(reason as usize, i);
+        }
+        for (i, reason) in FailureReason::ALL.into_iter().enumerate() {
+            assert_eq!(reason as usize, i);
+        }
+        let labels: HashSet<&str> = RejectReason::ALL
+            .map(RejectReason::as_label)
+            .into_iter()
+            .chain(FailureReason::ALL.map(FailureReason::as_label))
+            .chain(["closed", "client_closed"])
+            .collect();
+        assert_eq!(
+            labels.len(),
+            RejectReason::ALL.len() + FailureReason::ALL.len() + 2
+        );
+    }
+
+    #[test]
+    fn classifies_errors() {
+        let cases: Vec<(anyhow::Error, FailureReason)> = vec![
+            (
+                Socks5Error::Timeout {
+                    stage: "connect",
+                    after: Duration::from_secs(1),
+                }
+                .into(),
+                FailureReason::UpstreamTimeout,
+            ),
+            (
+                Socks5Error::ConnectionRefused.into(),
+                FailureReason::Socks5ConnectFailed,
+            ),
+            (
+                ConnectError::from(Socks5Error::ConnectionRefused).into(),
+                FailureReason::Socks5ConnectFailed,
+            ),
+            (
+                ConnectError::from(Socks4Error::Rejected).into(),
+                FailureReason::UpstreamConnectFailed,
+            ),
+            (
+                ConnectError::from(HttpConnectError::Timeout(Duration::from_secs(1))).into(),
+                FailureReason::UpstreamTimeout,
+            ),
+            (
+                PoolError::Exhausted {
+                    waited: Duration::from_secs(1),
+                    active: 1,
+                    limit: 1,
+                }
+                .into(),
+                FailureReason::PoolExhausted,
+            ),
+            (
+                anyhow::Error::new(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    IdleTimeout(Duration::from_secs(1)),
+                ))
+                .context("client to upstream copy failed"),
+                FailureReason::IdleTimeout,
+            ),
+            (
+                io::Error::new(io::ErrorKind::TimedOut, "slow client").into(),
+                FailureReason::ClientTimeout,
+            ),
+            (
+                anyhow::Error::new(io::Error::from(io::ErrorKind::ConnectionReset))
+                    .context("failed to read ClientHello"),
+                FailureReason::ClientIo,
+            ),
+            (
+                QuicError::DecryptionFailed("tag".into()).into(),
+                FailureReason::QuicDecryptFailed,
+            ),
+            (anyhow!("something else"), FailureReason::Internal),
+        ];
+        for (error, expected) in cases {
+            assert_eq!(FailureReason::of(&error), expected, "{:#}", error);
+        }
+        assert_eq!(
+            Outcome::of_quic_error(&QuicError::NoSniFound),
+            Outcome::Rejected(RejectReason::NoSni)
+        );
+        assert_eq!(
+            Outcome::Rejected(RejectReason::NotWhitelisted).as_label(),
+            "not_whitelisted"
+        );
+    }
+}
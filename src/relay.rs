@@ -1,30 +1,118 @@
-use anyhow::{anyhow, Result};
-use std::time::Duration;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use crate::logging;
+use crate::reason::{FailureReason, Outcome};
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
 use tracing::{error, warn};
 
-pub async fn log_accept_error(kind: &str, error: &std::io::Error) {
-    error!(
-        fd_used = current_fd_count(),
-        "Error accepting {}: {}", kind, error
-    );
+/// 转发中超过空闲超时没有读到数据
+///
+/// 由 [`copy_with_idle_timeout`] 包装在 `TimedOut` 类型的 [`io::Error`] 中返回。
+#[derive(Error, Debug)]
+#[error("Forwarding idle timeout after {0:?}")]
+pub struct IdleTimeout(pub Duration);
 
-    if matches!(error.raw_os_error(), Some(23 | 24)) {
-        warn!(
-            "File descriptor limit reached while accepting {}; backing off before retry",
-            kind
+/// 记录 accept 失败并退避；持续失败时 (例如文件描述符耗尽) 日志经
+/// [`logging::allow_warning`] 限流
+pub async fn log_accept_error(kind: &'static str, error: &std::io::Error) {
+    let fd_exhausted = matches!(error.raw_os_error(), Some(23 | 24));
+    if logging::allow_warning(kind, None) {
+        error!(
+            fd_used = current_fd_count(),
+            kind,
+            error = %error,
+            "accept failed"
         );
+        if fd_exhausted {
+            warn!(
+                kind,
+                "file descriptor limit reached while accepting, backing off before retry"
+            );
+        }
+    }
+
+    if fd_exhausted {
         tokio::time::sleep(Duration::from_secs(1)).await;
     } else {
         tokio::time::sleep(Duration::from_millis(50)).await;
     }
 }
 
+/// 双向转发中先结束的方向的结果对应的连接结果：超过空闲超时为
+/// [`FailureReason::IdleTimeout`]，其余 (含对端重置) 视为正常关闭
+pub fn forwarding_outcome(result: &io::Result<u64>) -> Outcome {
+    match result {
+        Err(e) if is_idle_timeout(e) => Outcome::Failed(FailureReason::IdleTimeout),
+        _ => Outcome::Closed,
+    }
+}
+
+/// `error` 是否是 [`copy_with_idle_timeout`] 返回的空闲超时
+pub fn is_idle_timeout(error: &io::Error) -> bool {
+    error
+        .get_ref()
+        .is_some_and(|inner| inner.is::<IdleTimeout>())
+}
+
+/// 单个连接两个方向已转发的字节数，连接结束时记入按域名的统计
+#[derive(Debug, Default)]
+pub struct ConnectionBytes {
+    /// 客户端 → 上游
+    pub up: AtomicU64,
+    /// 上游 → 客户端
+    pub down: AtomicU64,
+}
+
+impl ConnectionBytes {
+    /// (上行, 下行) 字节数
+    pub fn totals(&self) -> (u64, u64) {
+        (
+            self.up.load(Ordering::Relaxed),
+            self.down.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// 包装 reader，在第一次读到数据时记下时刻 (用于上游首字节耗时)
+pub struct FirstRead<'a, R> {
+    inner: R,
+    at: &'a OnceLock<Instant>,
+}
+
+impl<'a, R> FirstRead<'a, R> {
+    pub fn new(inner: R, at: &'a OnceLock<Instant>) -> Self {
+        Self { inner, at }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for FirstRead<'_, R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let this = &mut *self;
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if buf.filled().len() > filled && this.at.get().is_none() {
+            let _ = this.at.set(Instant::now());
+        }
+        poll
+    }
+}
+
+/// 双向转发中的一个方向，每次写入后把字节数累加到 `forwarded` 中的每个计数器
 pub async fn copy_with_idle_timeout<R, W>(
     reader: &mut R,
     writer: &mut W,
     idle_timeout: Duration,
-) -> Result<u64>
+    forwarded: &[&AtomicU64],
+) -> io::Result<u64>
 where
     R: AsyncRead + Unpin,
     W: AsyncWrite + Unpin,
@@ -35,7 +123,7 @@ where
     loop {
         let n = tokio::time::timeout(idle_timeout, reader.read(&mut buf))
             .await
-            .map_err(|_| anyhow!("Forwarding idle timeout after {:?}", idle_timeout))??;
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, IdleTimeout(idle_timeout)))??;
 
         if n == 0 {
             writer.shutdown().await?;
@@ -44,6 +132,9 @@ where
 
         writer.write_all(&buf[..n]).await?;
         total += n as u64;
+        for counter in forwarded {
+            counter.fetch_add(n as u64, Ordering::Relaxed);
+        }
     }
 }
 
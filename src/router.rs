@@ -1,19 +1,42 @@
 /// 域名白名单规则引擎
 ///
 /// 根据配置的白名单规则检查域名是否被允许。
-use crate::config::{Config, Socks5Config};
+use crate::config::{Config, EchTrafficPolicy, Socks5Config, UnicodeSniPolicy};
+use crate::tls::sni::{to_ascii_hostname, EchStatus};
+use crate::upstream::DEFAULT_UPSTREAM;
+use std::borrow::Cow;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
 use tracing::debug;
 
+/// 按 ClientHello 做出的路由决定
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HelloRoute<'a> {
+    /// 经指定的上游转发，None 表示由调用方在健康的上游间分散 (同 [`Router::route`])
+    Upstream(Option<&'a str>),
+    /// `rules.ech_policy = "reject"` 拒绝使用 ECH 的连接
+    RejectEch,
+}
+
 /// 路由器
+///
+/// 与各监听器共享同一份 [`Config`]，克隆只增加引用计数。
 #[derive(Clone)]
 pub struct Router {
-    config: Config,
+    config: Arc<Config>,
 }
 
 impl Router {
     /// 创建新的路由器
-    pub fn new(config: Config) -> Self {
-        Self { config }
+    pub fn new(config: impl Into<Arc<Config>>) -> Self {
+        Self {
+            config: config.into(),
+        }
+    }
+
+    /// 路由器使用的配置
+    pub fn config(&self) -> &Arc<Config> {
+        &self.config
     }
 
     /// 检查域名是否被允许
@@ -23,25 +46,91 @@ impl Router {
     pub fn is_allowed(&self, hostname: &str) -> bool {
         // 空 allow 数组 → 允许所有
         if self.config.rules.allow.is_empty() {
-            debug!("No whitelist configured, allowing all domains");
+            debug!("no whitelist configured, allowing all domains");
             return true;
         }
 
         // 检查是否匹配任一模式
         for pattern in &self.config.rules.allow {
-            if self.match_pattern(hostname, pattern) {
-                debug!(
-                    "Domain '{}' matched whitelist pattern '{}'",
-                    hostname, pattern
-                );
+            if Self::match_pattern(hostname, pattern) {
+                debug!(sni = %hostname, pattern = %pattern, "domain matched whitelist");
                 return true;
             }
         }
 
-        debug!("Domain '{}' did not match any whitelist pattern", hostname);
+        debug!(sni = %hostname, "domain did not match any whitelist pattern");
         false
     }
 
+    /// 检查 TLS/QUIC 的 SNI 是否被允许
+    ///
+    /// 在 [`is_allowed`](Self::is_allowed) 之外，`rules.reject_ip_sni` 开启时拒绝 IP 地址形式的 SNI。
+    /// HTTP 代理的目标可以是 IP 地址，仍使用 `is_allowed`。
+    pub fn is_sni_allowed(&self, sni: &str) -> bool {
+        if self.config.rules.reject_ip_sni && sni.parse::<Ipv4Addr>().is_ok() {
+            debug!(sni = %sni, "sni is an IP address, rejected by reject_ip_sni");
+            return false;
+        }
+        self.is_allowed(sni)
+    }
+
+    /// 按 `rules.unicode_sni` 处理 TLS/QUIC 的 SNI，返回用于白名单检查和路由的主机名
+    ///
+    /// ASCII 的 SNI 原样返回；U-label 在 `convert` 时转换为 A-label，`reject` 时返回 None。
+    pub fn sni_hostname<'a>(&self, sni: &'a str) -> Option<Cow<'a, str>> {
+        if sni.is_ascii() {
+            return Some(Cow::Borrowed(sni));
+        }
+        match self.config.rules.unicode_sni {
+            UnicodeSniPolicy::Reject => {
+                debug!(sni = %sni, "sni is a U-label, rejected by unicode_sni");
+                None
+            }
+            // 解析时已检查过 A-label 形式
+            UnicodeSniPolicy::Convert => to_ascii_hostname(sni).ok(),
+        }
+    }
+
+    /// 按 `rules.routes` 为域名选择 SOCKS5 上游，返回第一个匹配规则的上游名称
+    ///
+    /// None 表示没有规则指定，由调用方在健康的上游间分散。
+    pub fn route(&self, hostname: &str) -> Option<&str> {
+        let rule = self
+            .config
+            .rules
+            .routes
+            .iter()
+            .find(|rule| Self::match_pattern(hostname, &rule.pattern))?;
+        debug!(
+            sni = %hostname,
+            upstream = %rule.upstream,
+            pattern = %rule.pattern,
+            "domain routed by rule"
+        );
+        Some(&rule.upstream)
+    }
+
+    /// 结合 `rules.ech_policy` 为 TCP 连接选择上游
+    ///
+    /// 没有使用 ECH 时与 [`route`](Self::route) 相同；使用 ECH 时 `hostname` 是外层 SNI。
+    pub fn route_hello(&self, hostname: &str, ech: EchStatus) -> HelloRoute<'_> {
+        if !ech.is_present() {
+            return HelloRoute::Upstream(self.route(hostname));
+        }
+        match self.config.rules.ech_policy {
+            EchTrafficPolicy::Allow => HelloRoute::Upstream(self.route(hostname)),
+            EchTrafficPolicy::Reject => HelloRoute::RejectEch,
+            EchTrafficPolicy::DefaultBackend => {
+                debug!(
+                    sni = %hostname,
+                    upstream = DEFAULT_UPSTREAM,
+                    "domain uses ECH, routed by ech_policy"
+                );
+                HelloRoute::Upstream(Some(DEFAULT_UPSTREAM))
+            }
+        }
+    }
+
     /// 灵活通配符匹配
     ///
     /// 支持多个 `*` 的通配符模式，例如：
@@ -49,7 +138,9 @@ impl Router {
     /// - `*.google.com` 只匹配 `www.google.com`，不匹配 `google.com`
     /// - `api.*.com` 匹配 `api.example.com`
     /// - `*.prod.*.internal` 匹配 `web.prod.db.internal`
-    fn match_pattern(&self, hostname: &str, pattern: &str) -> bool {
+    ///
+    /// 白名单之外按域名匹配的配置 (例如 `quic.idle_overrides`) 也使用同一语法。
+    pub fn match_pattern(hostname: &str, pattern: &str) -> bool {
         // "*" 匹配所有
         if pattern == "*" {
             return true;
@@ -86,7 +177,6 @@ impl Router {
     }
 
     /// 获取 SOCKS5 配置
-    #[allow(dead_code)]
     pub fn socks5_config(&self) -> &Socks5Config {
         &self.config.socks5
     }
@@ -107,18 +197,47 @@ mod tests {
                 console_log_level: "warn".to_string(),
                 max_client_connections: 512,
                 transfer_idle_timeout: 300,
+                stats_log_interval: 60,
                 quic_mode: "off".to_string(),
             },
             socks5: crate::config::Socks5Config {
                 addr: "127.0.0.1:1080".parse().unwrap(),
                 timeout: 30,
                 max_connections: 100,
+                max_uses_per_connection: 0,
+                pool_acquire_timeout: 10,
                 username: None,
                 password: None,
+                resolve: crate::config::ResolveMode::default(),
+                upstreams: Vec::new(),
+                chain: Vec::new(),
+                protocol: crate::config::UpstreamProtocol::default(),
+                bind_addr: None,
+                bind_device: None,
+                udp_relay_sharing: false,
+                udp_relay_pool_size: 4,
+                udp_reassembly: false,
+                udp_control_keepalive: 30,
+                health_interval: 15,
+                health_canary: None,
+                balance: crate::config::BalanceStrategy::Hash,
+                weight: 1,
+                connect_retries: 0,
             },
+            backend: crate::config::BackendConfig::default(),
+            pool: crate::config::PoolConfig::default(),
             rules: crate::config::RulesConfig {
                 allow: allow_patterns.into_iter().map(|s| s.to_string()).collect(),
+                routes: Vec::new(),
+                ech_policy: EchTrafficPolicy::Allow,
+                reject_ip_sni: false,
+                unicode_sni: UnicodeSniPolicy::Reject,
             },
+            http: crate::config::HttpConfig::default(),
+            tls: crate::config::TlsConfig::default(),
+            metrics: crate::config::MetricsConfig::default(),
+            quic: crate::config::QuicConfig::default(),
+            logging: crate::config::LoggingConfig::default(),
         }
     }
 
@@ -199,4 +318,105 @@ mod tests {
         assert!(router.is_allowed("any.domain.com"));
         assert!(router.is_allowed("foo.bar.baz"));
     }
+
+    #[test]
+    fn test_route_uses_first_matching_rule() {
+        let mut config = create_test_config(vec![]);
+        config.rules.routes = vec![
+            crate::config::RouteRule {
+                pattern: "*.eu.example.com".to_string(),
+                upstream: "eu".to_string(),
+            },
+            crate::config::RouteRule {
+                pattern: "*example.com".to_string(),
+                upstream: "us".to_string(),
+            },
+        ];
+        let router = Router::new(config);
+        assert_eq!(router.route("www.eu.example.com"), Some("eu"));
+        assert_eq!(router.route("example.com"), Some("us"));
+        assert_eq!(router.route("example.org"), None);
+    }
+
+    #[test]
+    fn test_clones_share_config() {
+        let config = Arc::new(create_test_config(vec!["*.google.com"]));
+        let router = Router::new(Arc::clone(&config));
+        let clone = router.clone();
+        assert!(Arc::ptr_eq(router.config(), &config));
+        assert!(Arc::ptr_eq(clone.config(), &config));
+        assert_eq!(Arc::strong_count(&config), 3);
+    }
+
+    #[test]
+    fn test_reject_ip_sni() {
+        let mut config = create_test_config(vec![]);
+        let router = Router::new(config.clone());
+        assert!(router.is_sni_allowed("192.0.2.1"));
+
+        config.rules.reject_ip_sni = true;
+        let router = Router::new(config);
+        assert!(!router.is_sni_allowed("192.0.2.1"));
+        assert!(router.is_sni_allowed("192.0.2.example"));
+        // HTTP 代理的目标不受影响
+        assert!(router.is_allowed("192.0.2.1"));
+    }
+
+    #[test]
+    fn test_unicode_sni() {
+        let mut config = create_test_config(vec!["*.xn--fiqs8s"]);
+        let router = Router::new(config.clone());
+        assert_eq!(
+            router.sni_hostname("www.example.com").as_deref(),
+            Some("www.example.com")
+        );
+        assert_eq!(router.sni_hostname("www.中国"), None);
+
+        config.rules.unicode_sni = UnicodeSniPolicy::Convert;
+        let router = Router::new(config);
+        let hostname = router.sni_hostname("www.中国").unwrap();
+        assert_eq!(hostname, "www.xn--fiqs8s");
+        assert!(router.is_sni_allowed(&hostname));
+        assert_eq!(router.sni_hostname("\u{301}中国"), None);
+    }
+
+    #[test]
+    fn test_ech_policy() {
+        let ech = EchStatus::GreaseOrReal {
+            outer_sni_present: true,
+        };
+        let mut config = create_test_config(vec![]);
+        config.rules.routes = vec![crate::config::RouteRule {
+            pattern: "*example.com".to_string(),
+            upstream: "us".to_string(),
+        }];
+
+        let router = Router::new(config.clone());
+        assert_eq!(
+            router.route_hello("www.example.com", ech),
+            HelloRoute::Upstream(Some("us"))
+        );
+
+        config.rules.ech_policy = EchTrafficPolicy::Reject;
+        let router = Router::new(config.clone());
+        assert_eq!(
+            router.route_hello("www.example.com", ech),
+            HelloRoute::RejectEch
+        );
+        assert_eq!(
+            router.route_hello("www.example.com", EchStatus::Absent),
+            HelloRoute::Upstream(Some("us"))
+        );
+
+        config.rules.ech_policy = EchTrafficPolicy::DefaultBackend;
+        let router = Router::new(config);
+        assert_eq!(
+            router.route_hello("www.example.com", ech),
+            HelloRoute::Upstream(Some("default"))
+        );
+        assert_eq!(
+            router.route_hello("example.org", EchStatus::Absent),
+            HelloRoute::Upstream(None)
+        );
+    }
 }
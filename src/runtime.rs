@@ -0,0 +1,247 @@
+//! tokio 运行时指标
+//!
+//! 吞吐量下降时用来区分 CPU 打满、任务排队和工作线程不足：[`spawn_sampler`] 每
+//! `metrics.runtime_sample_interval` 读取一次 tokio 的 `RuntimeMetrics` (稳定子集)，
+//! 抓取 `/metrics` 时输出最近一次的采样 (见 [`RuntimeSample`])。
+//!
+//! 工作线程的忙碌比例是上一个采样间隔内忙碌时间的占比，接近 1 说明该线程几乎没有空闲；
+//! 全局队列持续有积压说明工作线程来不及处理新任务。
+
+use crate::metrics::{Exposition, MetricKind, Registry, TaskKind};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::runtime::{Handle, RuntimeMetrics};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, MissedTickBehavior};
+
+/// 一次运行时采样
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RuntimeSample {
+    /// 工作线程数
+    pub workers: usize,
+    /// 存活的任务数
+    pub alive_tasks: usize,
+    /// 全局 (注入) 队列中等待的任务数
+    pub global_queue_depth: usize,
+    /// 各工作线程累计的忙碌时间
+    pub busy: Vec<Duration>,
+    /// 各工作线程累计的 park 次数
+    pub parks: Vec<u64>,
+    /// 各工作线程在上一个采样间隔内的忙碌比例 (0 到 1)，首次采样为 0
+    pub busy_ratio: Vec<f64>,
+}
+
+impl RuntimeSample {
+    /// 读取 `metrics`，与 `elapsed` 之前的采样 `previous` 比较得出忙碌比例
+    fn take(metrics: &RuntimeMetrics, previous: Option<&RuntimeSample>, elapsed: Duration) -> Self {
+        let workers = metrics.num_workers();
+        let busy: Vec<Duration> = (0..workers)
+            .map(|worker| metrics.worker_total_busy_duration(worker))
+            .collect();
+        let busy_ratio = busy_ratio(previous.map(|p| p.busy.as_slice()), &busy, elapsed);
+        RuntimeSample {
+            workers,
+            alive_tasks: metrics.num_alive_tasks(),
+            global_queue_depth: metrics.global_queue_depth(),
+            parks: (0..workers)
+                .map(|worker| metrics.worker_park_count(worker))
+                .collect(),
+            busy,
+            busy_ratio,
+        }
+    }
+
+    fn render(&self) -> String {
+        let mut out = Exposition::default();
+        for (name, help, value) in [
+            (
+                "sniproxy_runtime_workers",
+                "Tokio runtime worker threads",
+                self.workers,
+            ),
+            (
+                "sniproxy_runtime_alive_tasks",
+                "Tasks alive in the tokio runtime",
+                self.alive_tasks,
+            ),
+            (
+                "sniproxy_runtime_global_queue_depth",
+                "Tasks waiting in the tokio global queue",
+                self.global_queue_depth,
+            ),
+        ] {
+            out.family(name, MetricKind::Gauge, help);
+            out.sample(name, &[], value);
+        }
+
+        let workers: Vec<String> = (0..self.workers).map(|w| w.to_string()).collect();
+        out.family(
+            "sniproxy_runtime_worker_busy_ratio",
+            MetricKind::Gauge,
+            "Share of the last sampling interval each worker spent busy",
+        );
+        for (worker, ratio) in workers.iter().zip(&self.busy_ratio) {
+            out.sample(
+                "sniproxy_runtime_worker_busy_ratio",
+                &[("worker", worker)],
+                (ratio * 1000.0).round() / 1000.0,
+            );
+        }
+        out.family(
+            "sniproxy_runtime_worker_busy_seconds_total",
+            MetricKind::Counter,
+            "Time each worker spent busy",
+        );
+        for (worker, busy) in workers.iter().zip(&self.busy) {
+            out.sample(
+                "sniproxy_runtime_worker_busy_seconds_total",
+                &[("worker", worker)],
+                busy.as_secs_f64(),
+            );
+        }
+        out.family(
+            "sniproxy_runtime_worker_parks_total",
+            MetricKind::Counter,
+            "Times each worker parked waiting for work",
+        );
+        for (worker, parks) in workers.iter().zip(&self.parks) {
+            out.sample(
+                "sniproxy_runtime_worker_parks_total",
+                &[("worker", worker)],
+                parks,
+            );
+        }
+        out.finish()
+    }
+}
+
+/// 由两次采样的累计忙碌时间算出各工作线程在 `elapsed` 内的忙碌比例
+fn busy_ratio(before: Option<&[Duration]>, now: &[Duration], elapsed: Duration) -> Vec<f64> {
+    now.iter()
+        .enumerate()
+        .map(|(worker, now)| match before.and_then(|b| b.get(worker)) {
+            Some(before) if !elapsed.is_zero() => {
+                (now.saturating_sub(*before).as_secs_f64() / elapsed.as_secs_f64()).min(1.0)
+            }
+            _ => 0.0,
+        })
+        .collect()
+}
+
+/// 在当前运行时上启动采样任务，每 `interval` 采样一次并在抓取时输出最近的采样，
+/// `shutdown` 变为 true 时结束，返回任务句柄
+pub fn spawn_sampler(
+    registry: Arc<Registry>,
+    interval: Duration,
+    mut shutdown: watch::Receiver<bool>,
+) -> JoinHandle<()> {
+    let metrics = Handle::current().metrics();
+    let latest = Arc::new(Mutex::new(RuntimeSample::take(
+        &metrics,
+        None,
+        Duration::ZERO,
+    )));
+    registry.register_collector({
+        let latest = Arc::clone(&latest);
+        move || {
+            let text = latest.lock().unwrap_or_else(|e| e.into_inner()).render();
+            async move { text }
+        }
+    });
+
+    let task = registry.track_task(TaskKind::Background);
+    tokio::spawn(async move {
+        let _task = task;
+        let mut since = Instant::now();
+        let mut ticker = tokio::time::interval_at(since + interval, interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {}
+                _ = shutdown.wait_for(|stopping| *stopping) => break,
+            }
+            let now = Instant::now();
+            let mut latest = latest.lock().unwrap_or_else(|e| e.into_inner());
+            *latest = RuntimeSample::take(&metrics, Some(&latest), now - since);
+            since = now;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn samples_worker_metrics() {
+        let registry = Arc::new(Registry::default());
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let task = spawn_sampler(
+            Arc::clone(&registry),
+            Duration::from_millis(20),
+            shutdown_rx,
+        );
+
+        // 让一个工作线程忙上一段时间
+        tokio::spawn(async {
+            let until = std::time::Instant::now() + Duration::from_millis(60);
+            while std::time::Instant::now() < until {
+                std::hint::spin_loop();
+            }
+        })
+        .await
+        .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let text = registry.render().await;
+        for line in [
+            "sniproxy_runtime_workers 2",
+            "sniproxy_tasks{kind=\"background\"} 1",
+            "# TYPE sniproxy_runtime_worker_busy_ratio gauge",
+            "# TYPE sniproxy_runtime_worker_parks_total counter",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "missing {:?} in\n{}",
+                line,
+                text
+            );
+        }
+        assert!(text.contains("sniproxy_runtime_worker_busy_seconds_total{worker=\"1\"} "));
+        assert!(text.contains("sniproxy_runtime_alive_tasks "));
+
+        shutdown_tx.send(true).unwrap();
+        task.await.unwrap();
+        assert_eq!(registry.tasks(TaskKind::Background), 0);
+    }
+
+    #[test]
+    fn busy_ratio_is_relative_to_the_interval() {
+        let ms = Duration::from_millis;
+        let before = [ms(100), ms(500), ms(0)];
+        let now = [ms(600), ms(500), ms(2000), ms(300)];
+        // 第四个工作线程没有上一次采样，超过间隔的部分截断为 1
+        assert_eq!(
+            busy_ratio(Some(&before), &now, ms(1000)),
+            vec![0.5, 0.0, 1.0, 0.0]
+        );
+        assert_eq!(busy_ratio(None, &now, ms(1000)), vec![0.0; 4]);
+        assert_eq!(
+            busy_ratio(Some(&before), &now, Duration::ZERO),
+            vec![0.0; 4]
+        );
+
+        let sample = RuntimeSample {
+            workers: 2,
+            busy: vec![ms(600), ms(500)],
+            parks: vec![3, 4],
+            busy_ratio: vec![0.5, 0.0],
+            ..Default::default()
+        };
+        let text = sample.render();
+        assert!(text.contains("sniproxy_runtime_worker_busy_ratio{worker=\"0\"} 0.5\n"));
+        assert!(text.contains("sniproxy_runtime_worker_parks_total{worker=\"1\"} 4\n"));
+        assert!(text.contains("sniproxy_runtime_worker_busy_seconds_total{worker=\"0\"} 0.6\n"));
+    }
+}
@@ -0,0 +1,258 @@
+//! systemd 通知 (`Type=notify` 与 `WatchdogSec=`)
+//!
+//! 实现 sd_notify(3) 的数据报协议：向 `NOTIFY_SOCKET` 指向的 Unix 数据报 socket (`@` 开头为
+//! Linux 抽象命名空间) 发送以换行分隔的 `KEY=VALUE`。没有设置 `NOTIFY_SOCKET` 时
+//! [`Notifier::from_env`] 返回 `None`，调用方什么都不做。
+//!
+//! 启动时在全部监听 socket 绑定之后发送 `READY=1`，关闭开始时发送 `STOPPING=1`；
+//! [`spawn`] 的任务每个统计周期发送 `STATUS=` (打开的连接数与 QUIC 会话数)，
+//! 设置了 `WATCHDOG_USEC` 时每半个周期发送 `WATCHDOG=1`。
+
+use crate::metrics::{Registry, TaskKind};
+use std::io;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, info, warn};
+
+/// 向 systemd 发送状态通知
+#[derive(Debug)]
+pub struct Notifier {
+    socket: UnixDatagram,
+    addr: SocketAddr,
+}
+
+impl Notifier {
+    /// 按 `NOTIFY_SOCKET` 创建，没有设置或无法使用时返回 `None`
+    pub fn from_env() -> Option<Notifier> {
+        let path = std::env::var("NOTIFY_SOCKET").ok()?;
+        match Notifier::new(&path) {
+            Ok(notifier) => {
+                debug!(socket = %path, "systemd notification enabled");
+                Some(notifier)
+            }
+            Err(e) => {
+                warn!(socket = %path, error = %e, "NOTIFY_SOCKET ignored");
+                None
+            }
+        }
+    }
+
+    /// 向 `path` 发送通知，`@` 开头的为抽象命名空间中的名字
+    pub fn new(path: &str) -> io::Result<Notifier> {
+        let addr = match path.strip_prefix('@') {
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                SocketAddr::from_abstract_name(name)?
+            }
+            #[cfg(not(target_os = "linux"))]
+            Some(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "abstract socket names are only supported on Linux",
+                ))
+            }
+            None => SocketAddr::from_pathname(path)?,
+        };
+        let socket = UnixDatagram::unbound()?;
+        socket.set_nonblocking(true)?;
+        Ok(Notifier { socket, addr })
+    }
+
+    /// 发送一条通知，例如 `READY=1`；多个字段以换行分隔
+    pub fn notify(&self, state: &str) -> io::Result<()> {
+        self.socket.send_to_addr(state.as_bytes(), &self.addr)?;
+        Ok(())
+    }
+
+    /// 启动完成 (监听 socket 已绑定)
+    pub fn ready(&self) {
+        self.send("READY=1\nSTATUS=Serving");
+    }
+
+    /// 开始关闭
+    pub fn stopping(&self) {
+        self.send("STOPPING=1\nSTATUS=Draining connections");
+    }
+
+    fn send(&self, state: &str) {
+        if let Err(e) = self.notify(state) {
+            warn!(state = ?state, error = %e, "systemd notify failed");
+        }
+    }
+}
+
+/// 按 `WATCHDOG_USEC` (以及 `WATCHDOG_PID`，设置时必须是本进程) 计算发送 `WATCHDOG=1` 的间隔，
+/// 即 systemd 超时时间的一半；没有启用 watchdog 时返回 `None`
+pub fn watchdog_interval(usec: Option<&str>, pid: Option<&str>) -> Option<Duration> {
+    if let Some(pid) = pid {
+        if pid.trim().parse::<u32>().ok()? != std::process::id() {
+            return None;
+        }
+    }
+    let usec = usec?.trim().parse::<u64>().ok().filter(|usec| *usec > 0)?;
+    Some(Duration::from_micros(usec) / 2)
+}
+
+/// 启动通知任务：每 `status_interval` (为 0 时不发送) 发送一次连接数，启用了 watchdog 时
+/// (见 [`watchdog_interval`]) 发送心跳。调用方在退出前中止任务；都不需要时返回 `None`。
+pub fn spawn(
+    notifier: Arc<Notifier>,
+    registry: Arc<Registry>,
+    status_interval: Duration,
+    watchdog: Option<Duration>,
+) -> Option<JoinHandle<()>> {
+    if status_interval.is_zero() && watchdog.is_none() {
+        return None;
+    }
+    if let Some(interval) = watchdog {
+        info!(
+            "systemd watchdog enabled, sending keep-alive every {:?}",
+            interval
+        );
+    }
+    // 没有对应功能时使用一个永远不会先到的周期
+    let never = Duration::from_secs(86400 * 365);
+    let status_interval = Some(status_interval).filter(|i| !i.is_zero());
+    let task = registry.track_task(TaskKind::Background);
+    Some(tokio::spawn(async move {
+        let _task = task;
+        let mut status = tokio::time::interval(status_interval.unwrap_or(never));
+        let mut heartbeat = tokio::time::interval(watchdog.unwrap_or(never));
+        status.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // interval 的第一次 tick 立即完成：启动后先发送一次
+        loop {
+            tokio::select! {
+                _ = heartbeat.tick(), if watchdog.is_some() => {
+                    notifier.send("WATCHDOG=1");
+                }
+                _ = status.tick(), if status_interval.is_some() => {
+                    let totals = registry.totals().await;
+                    notifier.send(&format!(
+                        "STATUS=Serving: {} connections open, {} QUIC sessions",
+                        totals.active_connections, totals.quic_sessions
+                    ));
+                }
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Listener;
+
+    /// 在临时目录中绑定的接收端，代替 systemd
+    struct Systemd {
+        socket: UnixDatagram,
+        path: std::path::PathBuf,
+    }
+
+    impl Systemd {
+        fn bind(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "sniproxy-ng-notify-{}-{}.sock",
+                name,
+                std::process::id()
+            ));
+            let _ = std::fs::remove_file(&path);
+            let socket = UnixDatagram::bind(&path).unwrap();
+            socket
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            Systemd { socket, path }
+        }
+
+        fn recv(&self) -> String {
+            let mut buf = [0u8; 512];
+            let n = self.socket.recv(&mut buf).unwrap();
+            String::from_utf8(buf[..n].to_vec()).unwrap()
+        }
+    }
+
+    impl Drop for Systemd {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    // 接收端阻塞读取，任务需要在其它 worker 上运行
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn sends_readiness_status_and_heartbeats() {
+        let systemd = Systemd::bind("sequence");
+        // 与 NOTIFY_SOCKET 的取值相同；测试并行运行，不修改进程的环境变量
+        let notifier = Arc::new(Notifier::new(systemd.path.to_str().unwrap()).unwrap());
+
+        let registry = Arc::new(Registry::default());
+        let _open = registry.connection(Listener::Tcp);
+        notifier.ready();
+        let task = spawn(
+            Arc::clone(&notifier),
+            Arc::clone(&registry),
+            Duration::from_secs(3600),
+            Some(Duration::from_millis(50)),
+        )
+        .unwrap();
+
+        assert_eq!(systemd.recv(), "READY=1\nSTATUS=Serving");
+        let mut messages = vec![systemd.recv(), systemd.recv()];
+        messages.sort();
+        assert_eq!(
+            messages,
+            [
+                "STATUS=Serving: 1 connections open, 0 QUIC sessions",
+                "WATCHDOG=1"
+            ]
+        );
+        assert_eq!(systemd.recv(), "WATCHDOG=1");
+
+        task.abort();
+        notifier.stopping();
+        // 中止前已发出的心跳可能还在队列中
+        let mut last = systemd.recv();
+        while last == "WATCHDOG=1" {
+            last = systemd.recv();
+        }
+        assert_eq!(last, "STOPPING=1\nSTATUS=Draining connections");
+    }
+
+    #[test]
+    fn watchdog_interval_is_half_the_timeout() {
+        assert_eq!(
+            watchdog_interval(Some("30000000"), None),
+            Some(Duration::from_secs(15))
+        );
+        let pid = std::process::id().to_string();
+        assert_eq!(
+            watchdog_interval(Some("2000000"), Some(&pid)),
+            Some(Duration::from_secs(1))
+        );
+        // 发给其它进程的设置
+        assert_eq!(watchdog_interval(Some("2000000"), Some("1")), None);
+        assert_eq!(watchdog_interval(Some("0"), None), None);
+        assert_eq!(watchdog_interval(Some("soon"), None), None);
+        assert_eq!(watchdog_interval(None, None), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn supports_abstract_socket_names() {
+        use std::os::linux::net::SocketAddrExt;
+
+        let name = format!("sniproxy-ng-notify-{}", std::process::id());
+        let addr = SocketAddr::from_abstract_name(&name).unwrap();
+        let systemd = UnixDatagram::bind_addr(&addr).unwrap();
+        Notifier::new(&format!("@{}", name))
+            .unwrap()
+            .notify("READY=1")
+            .unwrap();
+        let mut buf = [0u8; 16];
+        let n = systemd.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+    }
+}
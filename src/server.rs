@@ -0,0 +1,542 @@
+//! 可嵌入的代理服务器
+//!
+//! [`Server::new`] 按配置创建上游、后端、指标注册表，并立即绑定全部监听 socket
+//! (HTTPS 的 TCP 与 QUIC、HTTP、指标端点)，绑定失败直接返回错误；[`Server::start`] 启动
+//! 监听器和后台任务 (健康检查、统计日志、statsd、运行时采样)，返回 [`ServerHandle`]。
+//! [`ServerHandle::shutdown`] 停止接受新连接、关闭连接池和 QUIC 会话，并在给定时间内等待
+//! 已建立的 TCP/HTTP 连接结束。
+//!
+//! 监听器出错退出时按 [`RestartPolicy`] 重启 (见 [`crate::supervisor`])，连续失败超过上限的
+//! 由 [`ServerHandle::wait`] 返回错误。
+//!
+//! 监听地址的端口为 0 时由系统分配，实际地址见 [`ServerHandle::local_addrs`]；
+//! QUIC 与 HTTPS 的 TCP 监听器使用同一个端口。
+
+use crate::backend::Backend;
+use crate::build_info;
+use crate::config::{Config, ConfigError};
+use crate::health::{HealthChecker, Readiness};
+use crate::logging::LogFilter;
+use crate::metrics::{self, Listener, Registry};
+use crate::router::Router;
+use crate::supervisor::{supervise, RestartPolicy};
+use crate::upstream::UpstreamSet;
+use crate::{http, quic, runtime, stats, statsd, tcp};
+use crate::{Error, Result};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::watch;
+use tokio::task::{JoinHandle, JoinSet};
+use tracing::{error, info, warn};
+
+/// 各监听器实际绑定的地址，未启用的为 `None`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LocalAddrs {
+    /// HTTPS (TLS over TCP)
+    pub https: Option<SocketAddr>,
+    /// QUIC/HTTP3 (UDP)
+    pub quic: Option<SocketAddr>,
+    /// HTTP 明文与 CONNECT
+    pub http: Option<SocketAddr>,
+    /// `/metrics` 等端点
+    pub metrics: Option<SocketAddr>,
+}
+
+/// 已绑定、尚未启动的代理服务器
+pub struct Server {
+    config: Arc<Config>,
+    upstreams: Arc<UpstreamSet>,
+    backend: Backend,
+    metrics: Arc<Registry>,
+    log_filter: Arc<LogFilter>,
+    restart_policy: RestartPolicy,
+    tcp: Option<TcpListener>,
+    quic: Option<Vec<Arc<UdpSocket>>>,
+    http: Option<TcpListener>,
+    metrics_listener: Option<TcpListener>,
+    local_addrs: LocalAddrs,
+}
+
+impl Server {
+    /// 按配置创建服务器并绑定全部监听 socket
+    ///
+    /// `server.quic_mode` (或环境变量 `SNIPROXY_QUIC_MODE`) 为 auto 时在这里探测 SOCKS5 UDP relay，
+    /// 不可用则不启动 QUIC。没有配置任何监听器时返回 [`ConfigError::NoListener`]，
+    /// 绑定失败时返回 [`Error::ListenerBind`]。
+    pub async fn new(config: Config) -> Result<Server> {
+        if config.server.listen_https_addr.is_none() && config.server.listen_http_addr.is_none() {
+            return Err(ConfigError::NoListener.into());
+        }
+        info!(config = %config.redacted_summary(), "effective configuration");
+        if config.rules.allow.is_empty() {
+            info!(patterns = 0, "whitelist empty, allowing all domains");
+        } else {
+            info!(patterns = config.rules.allow.len(), "whitelist loaded");
+        }
+
+        // SOCKS5 上游 (TCP 与 QUIC 共享健康状态)
+        let upstreams = Arc::new(UpstreamSet::from_config(&config)?);
+        let backend = Backend::from_config(&config, upstreams.clone())?;
+        match &backend {
+            Backend::Socks5(_) => {
+                info!(backend = "socks5", addr = %config.socks5.addr, "backend configured")
+            }
+            Backend::HttpConnect(proxy) => {
+                info!(backend = "http_connect", addr = %proxy.proxy_addr(), "backend configured")
+            }
+        }
+        if !config.socks5.upstreams.is_empty() {
+            info!(
+                balance = ?config.socks5.balance,
+                upstreams = %upstreams
+                    .iter()
+                    .map(|u| format!("{}={}", u.name(), u.config().addr))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                "socks5 upstreams configured"
+            );
+        }
+
+        let mut local_addrs = LocalAddrs::default();
+        // HTTPS 监听器 (TCP + QUIC)
+        let mut tcp = None;
+        let mut quic = None;
+        if let Some(addr) = config.server.listen_https_addr {
+            info!(listener = "https", addr = %addr, "listener configured");
+            warn_privileged(addr);
+            let listener = bind_tcp("HTTPS", addr).await?;
+            let bound = listener.local_addr()?;
+            local_addrs.https = Some(bound);
+            tcp = Some(listener);
+
+            match should_start_quic(&config).await {
+                Ok(true) => {
+                    // 端口为 0 时 QUIC 使用 TCP 分配到的端口
+                    let sockets = quic::bind(&config, bound)?;
+                    local_addrs.quic = Some(sockets[0].local_addr()?);
+                    quic = Some(sockets);
+                }
+                Ok(false) => {
+                    info!(
+                        listener = "quic",
+                        "quic listener disabled, clients fall back to tcp"
+                    );
+                }
+                Err(e) => {
+                    error!(listener = "quic", error = %e, "quic startup check failed");
+                }
+            }
+        }
+
+        // HTTP 监听器
+        let mut http = None;
+        if let Some(addr) = config.server.listen_http_addr {
+            info!(listener = "http", addr = %addr, "listener configured");
+            warn_privileged(addr);
+            let listener = bind_tcp("HTTP", addr).await?;
+            local_addrs.http = Some(listener.local_addr()?);
+            http = Some(listener);
+        }
+
+        let mut metrics_listener = None;
+        if let Some(addr) = config.metrics.listen_addr {
+            let listener = bind_tcp("metrics endpoint", addr).await?;
+            local_addrs.metrics = Some(listener.local_addr()?);
+            metrics_listener = Some(listener);
+        }
+
+        // Prometheus 指标，各监听器记录到同一个注册表
+        let metrics = Arc::new(Registry::default());
+        metrics::register_socks5_latency(&metrics, upstreams.latency().clone());
+        metrics::register_upstream_health(&metrics, Arc::clone(&upstreams));
+
+        Ok(Server {
+            config: Arc::new(config),
+            upstreams,
+            backend,
+            metrics,
+            log_filter: Arc::new(LogFilter::default()),
+            restart_policy: RestartPolicy::default(),
+            tcp,
+            quic,
+            http,
+            metrics_listener,
+            local_addrs,
+        })
+    }
+
+    /// 使用 `log_filter` 提供 `/admin/log_level` (默认的过滤器不对应任何日志输出)
+    pub fn with_log_filter(mut self, log_filter: Arc<LogFilter>) -> Self {
+        self.log_filter = log_filter;
+        self
+    }
+
+    /// 监听器出错退出时的重启策略，默认见 [`RestartPolicy::default`]
+    pub fn with_restart_policy(mut self, restart_policy: RestartPolicy) -> Self {
+        self.restart_policy = restart_policy;
+        self
+    }
+
+    /// 各监听器实际绑定的地址
+    pub fn local_addrs(&self) -> LocalAddrs {
+        self.local_addrs
+    }
+
+    /// 指标注册表
+    pub fn metrics(&self) -> &Arc<Registry> {
+        &self.metrics
+    }
+
+    /// 启动监听器和后台任务
+    pub fn start(self) -> ServerHandle {
+        let Server {
+            config,
+            upstreams,
+            backend,
+            metrics,
+            log_filter,
+            restart_policy,
+            tcp,
+            quic,
+            http,
+            metrics_listener,
+            local_addrs,
+        } = self;
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let mut background = Vec::new();
+
+        // 每分钟输出各上游的 SOCKS5 握手耗时 (TCP CONNECT 与 QUIC 的 UDP ASSOCIATE)
+        let latency_report = upstreams
+            .latency()
+            .clone()
+            .spawn_report_task(Duration::from_secs(60));
+        // 主动健康检查，down 的上游不参与 TCP 和 QUIC 的分散
+        if let Some(checker) = HealthChecker::from_config(&config, upstreams.clone()) {
+            background.push(checker.spawn(shutdown_rx.clone()));
+        }
+        // 不部署 Prometheus 时也能从日志中看到流量概况
+        if config.server.stats_log_interval > 0 {
+            background.push(stats::spawn(
+                metrics.clone(),
+                Duration::from_secs(config.server.stats_log_interval),
+                shutdown_rx.clone(),
+            ));
+        }
+        // 推送到 statsd/DogStatsD，与 /metrics 相互独立
+        if let Some(addr) = config.metrics.statsd_addr {
+            match statsd::spawn(metrics.clone(), addr, &config, shutdown_rx.clone()) {
+                Ok(task) => background.push(task),
+                Err(e) => error!(addr = %addr, error = %e, "statsd sink failed to start"),
+            }
+        }
+
+        // 就绪检查在关闭信号后即返回未就绪；指标端点本身等到监听器结束后才停止，
+        // 以便负载均衡器在连接排空期间仍能读到未就绪
+        let (metrics_shutdown_tx, metrics_shutdown_rx) = watch::channel(false);
+        let mut endpoint = None;
+        if let Some(listener) = metrics_listener {
+            // 运行时采样只在有指标端点时才有意义
+            if config.metrics.runtime_sample_interval > 0 {
+                background.push(runtime::spawn_sampler(
+                    metrics.clone(),
+                    Duration::from_secs(config.metrics.runtime_sample_interval),
+                    shutdown_rx.clone(),
+                ));
+            }
+            let readiness = Arc::new(Readiness::new(
+                &config,
+                upstreams.clone(),
+                metrics.clone(),
+                shutdown_rx.clone(),
+            ));
+            let status = Arc::new(build_info::status(&config));
+            let metrics = metrics.clone();
+            endpoint = Some(tokio::spawn(async move {
+                if let Err(e) = metrics::serve(
+                    listener,
+                    metrics,
+                    readiness,
+                    log_filter,
+                    status,
+                    metrics_shutdown_rx,
+                )
+                .await
+                {
+                    error!(listener = "metrics", error = %e, "metrics endpoint failed");
+                }
+            }));
+        }
+
+        // 各监听器出错时按 restart_policy 重启：第一次使用 new 中绑定的 socket，之后重新绑定同一地址
+        let mut listeners = JoinSet::new();
+        if let (Some(listener), Some(addr)) = (tcp, local_addrs.https) {
+            let (config, backend, metrics) =
+                (Arc::clone(&config), backend.clone(), metrics.clone());
+            let shutdown = shutdown_rx.clone();
+            let mut bound = Some(listener);
+            metrics.expect_listener(Listener::Tcp);
+            listeners.spawn(supervise(
+                Listener::Tcp,
+                restart_policy,
+                shutdown_rx.clone(),
+                move || {
+                    let listener = bound.take();
+                    let (config, backend, shutdown, metrics) = (
+                        Arc::clone(&config),
+                        backend.clone(),
+                        shutdown.clone(),
+                        metrics.clone(),
+                    );
+                    async move {
+                        let listener = match listener {
+                            Some(listener) => listener,
+                            None => bind_tcp("HTTPS", addr).await?,
+                        };
+                        tcp::serve(listener, config, backend, shutdown, metrics).await
+                    }
+                },
+            ));
+        }
+        if let (Some(sockets), Some(addr)) = (quic, local_addrs.quic) {
+            let (config, upstreams, metrics) =
+                (Arc::clone(&config), upstreams.clone(), metrics.clone());
+            let shutdown = shutdown_rx.clone();
+            let mut bound = Some(sockets);
+            metrics.expect_listener(Listener::Quic);
+            listeners.spawn(supervise(
+                Listener::Quic,
+                restart_policy,
+                shutdown_rx.clone(),
+                move || {
+                    let sockets = bound.take();
+                    let (config, upstreams, shutdown, metrics) = (
+                        Arc::clone(&config),
+                        upstreams.clone(),
+                        shutdown.clone(),
+                        metrics.clone(),
+                    );
+                    async move {
+                        let sockets = match sockets {
+                            Some(sockets) => sockets,
+                            None => quic::bind(&config, addr)?,
+                        };
+                        quic::run_on_sockets(sockets, config, upstreams, shutdown, metrics).await
+                    }
+                },
+            ));
+        }
+        if let (Some(listener), Some(addr)) = (http, local_addrs.http) {
+            let router = Arc::new(Router::new(Arc::clone(&config)));
+            let (config, backend, metrics) =
+                (Arc::clone(&config), backend.clone(), metrics.clone());
+            let shutdown = shutdown_rx.clone();
+            let mut bound = Some(listener);
+            metrics.expect_listener(Listener::Http);
+            listeners.spawn(supervise(
+                Listener::Http,
+                restart_policy,
+                shutdown_rx.clone(),
+                move || {
+                    let listener = bound.take();
+                    let (config, router, backend, shutdown, metrics) = (
+                        Arc::clone(&config),
+                        Arc::clone(&router),
+                        backend.clone(),
+                        shutdown.clone(),
+                        metrics.clone(),
+                    );
+                    async move {
+                        let listener = match listener {
+                            Some(listener) => listener,
+                            None => bind_tcp("HTTP", addr).await?,
+                        };
+                        http::serve(listener, config, router, backend, shutdown, metrics).await
+                    }
+                },
+            ));
+        }
+
+        ServerHandle {
+            local_addrs,
+            metrics,
+            shutdown_tx,
+            metrics_shutdown_tx,
+            listeners,
+            background,
+            latency_report,
+            endpoint,
+        }
+    }
+}
+
+/// 运行中的服务器
+pub struct ServerHandle {
+    local_addrs: LocalAddrs,
+    metrics: Arc<Registry>,
+    shutdown_tx: watch::Sender<bool>,
+    metrics_shutdown_tx: watch::Sender<bool>,
+    /// 各监听器的监督任务，返回重启次数或放弃重启时的错误
+    listeners: JoinSet<Result<u32>>,
+    /// 收到关闭信号后自行结束的后台任务
+    background: Vec<JoinHandle<()>>,
+    latency_report: JoinHandle<()>,
+    endpoint: Option<JoinHandle<()>>,
+}
+
+impl ServerHandle {
+    /// 各监听器实际绑定的地址
+    pub fn local_addrs(&self) -> LocalAddrs {
+        self.local_addrs
+    }
+
+    /// 指标注册表
+    pub fn metrics(&self) -> &Arc<Registry> {
+        &self.metrics
+    }
+
+    /// 等待全部监听器退出
+    ///
+    /// 有监听器重启失败、不再运行时立即返回其错误，其余监听器仍在运行，调用方应随后调用
+    /// [`shutdown`](Self::shutdown)。
+    pub async fn wait(&mut self) -> Result<()> {
+        while let Some(joined) = self.listeners.join_next().await {
+            match joined {
+                Ok(result) => result.map(drop)?,
+                Err(e) if e.is_panic() => {
+                    return Err(Error::Other(
+                        format!("listener task panicked: {}", e).into(),
+                    ))
+                }
+                Err(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// 优雅关闭：停止接受新连接，关闭连接池和 QUIC 会话，再等待已建立的 TCP/HTTP 连接结束
+    ///
+    /// 超过 `timeout` 仍未完成时中止监听器并返回错误；此时仍在转发的连接不会被中断。
+    /// 指标端点在这之后才停止，排空期间 `/readyz` 返回未就绪。
+    pub async fn shutdown(mut self, timeout: Duration) -> Result<()> {
+        let _ = self.shutdown_tx.send(true);
+        let metrics = Arc::clone(&self.metrics);
+        let listeners = &mut self.listeners;
+        let drained = tokio::time::timeout(timeout, async move {
+            while listeners.join_next().await.is_some() {}
+            // 连接任务持有活动连接守卫，全部结束后计数归零
+            while [Listener::Tcp, Listener::Http]
+                .into_iter()
+                .any(|listener| metrics.active_connections(listener) > 0)
+            {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await;
+
+        self.listeners.abort_all();
+        self.latency_report.abort();
+        for task in self.background.drain(..) {
+            task.await.ok();
+        }
+        let _ = self.metrics_shutdown_tx.send(true);
+        if let Some(endpoint) = self.endpoint.take() {
+            endpoint.await.ok();
+        }
+
+        drained.map_err(|_| {
+            let open: u64 = [Listener::Tcp, Listener::Http]
+                .into_iter()
+                .map(|listener| self.metrics.active_connections(listener))
+                .sum();
+            Error::ShutdownTimeout { timeout, open }
+        })
+    }
+}
+
+fn warn_privileged(addr: SocketAddr) {
+    // 检查端口是否需要权限
+    if addr.port() != 0 && addr.port() < 1024 {
+        warn!(
+            port = addr.port(),
+            "port requires root privileges, binding may fail"
+        );
+    }
+}
+
+/// 绑定 TCP 监听地址，失败时带上监听器的名字
+pub(crate) async fn bind_tcp(listener: &'static str, addr: SocketAddr) -> Result<TcpListener> {
+    TcpListener::bind(addr)
+        .await
+        .map_err(|source| Error::ListenerBind {
+            listener,
+            addr,
+            source,
+        })
+}
+
+async fn should_start_quic(config: &Config) -> Result<bool> {
+    let mode =
+        std::env::var("SNIPROXY_QUIC_MODE").unwrap_or_else(|_| config.server.quic_mode.clone());
+    info!(listener = "quic", mode = %mode, "quic startup mode");
+
+    // UDP 只能经单跳 SOCKS5 的 UDP ASSOCIATE 转发
+    if mode != "off" {
+        if let Some(reason) = quic::udp_unsupported_reason(config) {
+            return Err(ConfigError::Invalid {
+                section: "quic",
+                reason: reason.to_string(),
+            }
+            .into());
+        }
+    }
+
+    match mode.as_str() {
+        "off" => Ok(false),
+        "on" => {
+            match quic::session::probe_socks5_udp_relay(&config.socks5).await {
+                Ok(()) => {
+                    info!(
+                        listener = "quic",
+                        mode = "on",
+                        "socks5 udp relay probe succeeded"
+                    );
+                }
+                Err(e) => {
+                    warn!(
+                        listener = "quic",
+                        mode = "on",
+                        error = %e,
+                        "socks5 udp relay probe failed, starting quic anyway"
+                    );
+                }
+            }
+            Ok(true)
+        }
+        "auto" => match quic::session::probe_socks5_udp_relay(&config.socks5).await {
+            Ok(()) => {
+                info!(
+                    listener = "quic",
+                    mode = "auto",
+                    "socks5 udp relay probe succeeded, enabling quic"
+                );
+                Ok(true)
+            }
+            Err(e) => {
+                warn!(
+                    listener = "quic",
+                    mode = "auto",
+                    error = %e,
+                    "socks5 udp relay probe failed, disabling quic"
+                );
+                Ok(false)
+            }
+        },
+        other => Err(ConfigError::Invalid {
+            section: "server.quic_mode",
+            reason: format!("'{}'; expected auto, on, or off", other),
+        }
+        .into()),
+    }
+}
@@ -0,0 +1,111 @@
+//! Windows 服务 (`sniproxy-ng --service`)
+//!
+//! 由服务控制管理器 (SCM) 启动：工作目录切换到可执行文件所在目录，以便读取旁边的
+//! `config.toml`；SCM 的 Stop/Shutdown 请求与控制台的 Ctrl+C 一样走优雅关闭，
+//! 状态依次报告为 StartPending → Running → StopPending → Stopped。
+//!
+//! 安装：`sc.exe create sniproxy-ng binPath= "C:\sniproxy-ng\sniproxy-ng.exe --service"`
+
+use crate::CONFIG_PATH;
+use anyhow::{Context, Result};
+use sniproxy_ng::Config;
+use std::ffi::OsString;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use windows_service::service::{
+    ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+use windows_service::{define_windows_service, service_dispatcher};
+
+/// 服务名，与 `sc.exe create` 时使用的名字一致
+const SERVICE_NAME: &str = "sniproxy-ng";
+
+define_windows_service!(ffi_service_main, service_main);
+
+/// 连接 SCM 并在服务线程中运行，服务停止后返回
+pub fn run() -> Result<()> {
+    service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+        .context("Failed to start service dispatcher (not started by the service manager?)")
+}
+
+fn service_main(_arguments: Vec<OsString>) {
+    // 此时还没有日志，也没有控制台：错误只能通过事件日志中的退出码体现
+    if let Err(e) = run_service() {
+        eprintln!("Error: {:#}", e);
+    }
+}
+
+fn run_service() -> Result<()> {
+    let (stop_tx, stop_rx) = mpsc::unbounded_channel();
+    let status = service_control_handler::register(SERVICE_NAME, move |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            let _ = stop_tx.send("service stop");
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    })
+    .context("Failed to register service control handler")?;
+
+    let report = |state: ServiceState, controls_accepted: ServiceControlAccept, code: u32| {
+        let result = status.set_service_status(ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: state,
+            controls_accepted,
+            exit_code: ServiceExitCode::Win32(code),
+            checkpoint: 0,
+            wait_hint: match state {
+                ServiceState::StartPending | ServiceState::StopPending => Duration::from_secs(60),
+                _ => Duration::default(),
+            },
+            process_id: None,
+        });
+        if let Err(e) = result {
+            eprintln!("Error: Failed to report service status {:?}: {}", state, e);
+        }
+    };
+    report(ServiceState::StartPending, ServiceControlAccept::empty(), 0);
+
+    let failed = start(stop_rx, &report).unwrap_or_else(|e| {
+        eprintln!("Error: {:#}", e);
+        true
+    });
+    report(
+        ServiceState::Stopped,
+        ServiceControlAccept::empty(),
+        u32::from(failed),
+    );
+    Ok(())
+}
+
+/// 加载配置并运行代理，返回是否因失败而退出
+fn start(
+    stop: mpsc::UnboundedReceiver<&'static str>,
+    report: &impl Fn(ServiceState, ServiceControlAccept, u32),
+) -> Result<bool> {
+    // SCM 以 System32 为工作目录启动服务
+    let exe = std::env::current_exe().context("Failed to locate executable")?;
+    if let Some(dir) = exe.parent() {
+        std::env::set_current_dir(dir)
+            .with_context(|| format!("Failed to change directory to {}", dir.display()))?;
+    }
+    let config =
+        Config::load(CONFIG_PATH).with_context(|| format!("Failed to load {}", CONFIG_PATH))?;
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(crate::run(
+        config,
+        stop,
+        || {
+            report(
+                ServiceState::Running,
+                ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+                0,
+            )
+        },
+        || report(ServiceState::StopPending, ServiceControlAccept::empty(), 0),
+    ))
+}
@@ -0,0 +1,85 @@
+//! 关闭信号
+//!
+//! [`ShutdownSignals`] 汇总触发优雅关闭的信号：Unix 上为 SIGINT (Ctrl+C) 与 SIGTERM
+//! (systemd、Docker、Kubernetes 停止进程时发送)，Windows 上为 Ctrl+C、Ctrl+Break、
+//! 关闭控制台窗口和系统关机。每次收到返回信号的名字，供日志记录由哪个信号发起关闭；
+//! 关闭期间再次收到信号时调用方不再等待连接排空。
+//!
+//! 处理器在 [`ShutdownSignals::new`] 中同步注册，之后到达的信号不再按默认行为终止进程。
+
+use std::io;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// 已注册的关闭信号
+#[derive(Debug)]
+pub struct ShutdownSignals {
+    #[cfg(unix)]
+    interrupt: tokio::signal::unix::Signal,
+    #[cfg(unix)]
+    terminate: tokio::signal::unix::Signal,
+    #[cfg(windows)]
+    ctrl_c: tokio::signal::windows::CtrlC,
+    #[cfg(windows)]
+    ctrl_break: tokio::signal::windows::CtrlBreak,
+    #[cfg(windows)]
+    ctrl_close: tokio::signal::windows::CtrlClose,
+    #[cfg(windows)]
+    ctrl_shutdown: tokio::signal::windows::CtrlShutdown,
+}
+
+impl ShutdownSignals {
+    /// 注册信号处理器，需要在 tokio 运行时中调用
+    pub fn new() -> io::Result<ShutdownSignals> {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            Ok(ShutdownSignals {
+                interrupt: signal(SignalKind::interrupt())?,
+                terminate: signal(SignalKind::terminate())?,
+            })
+        }
+        #[cfg(windows)]
+        {
+            use tokio::signal::windows;
+            Ok(ShutdownSignals {
+                ctrl_c: windows::ctrl_c()?,
+                ctrl_break: windows::ctrl_break()?,
+                ctrl_close: windows::ctrl_close()?,
+                ctrl_shutdown: windows::ctrl_shutdown()?,
+            })
+        }
+    }
+
+    /// 等待下一个关闭信号，返回其名字 (如 `"SIGTERM"`)
+    pub async fn recv(&mut self) -> &'static str {
+        #[cfg(unix)]
+        {
+            tokio::select! {
+                _ = self.interrupt.recv() => "SIGINT",
+                _ = self.terminate.recv() => "SIGTERM",
+            }
+        }
+        #[cfg(windows)]
+        {
+            tokio::select! {
+                _ = self.ctrl_c.recv() => "Ctrl+C",
+                _ = self.ctrl_break.recv() => "Ctrl+Break",
+                _ = self.ctrl_close.recv() => "console close",
+                _ = self.ctrl_shutdown.recv() => "system shutdown",
+            }
+        }
+    }
+
+    /// 把收到的信号逐个发送到 `stop`，接收端关闭后结束
+    pub fn forward(mut self, stop: mpsc::UnboundedSender<&'static str>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                let signal = self.recv().await;
+                if stop.send(signal).is_err() {
+                    return;
+                }
+            }
+        })
+    }
+}
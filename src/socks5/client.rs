@@ -1,17 +1,347 @@
-use anyhow::{anyhow, Result};
+use crate::config::Socks5Config;
+use crate::logging;
+use crate::outbound::{self, BindError, DialError, OutboundBind, ProxyResolver};
+use crate::socks5::metrics::{MetricsSink, Socks5Operation};
 use fast_socks5::client::{Config, Socks5Stream};
-use std::time::Duration;
+use fast_socks5::util::target_addr::ToTargetAddr;
+use fast_socks5::{AuthenticationMethod, ReplyError, Socks5Command, SocksError};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio::net::TcpStream;
-use tracing::debug;
+use tracing::{debug, info, warn};
+
+/// SOCKS5 建连失败的分类
+///
+/// 服务器的回复码各自对应一个变体，以区分"目标不可达" (目标的问题) 和
+/// "规则不允许"、认证失败 (代理配置的问题)。
+#[derive(Error, Debug)]
+pub enum Socks5Error {
+    /// 认证方式不被接受或用户名/密码错误
+    #[error("SOCKS5 authentication failed: {0}")]
+    AuthFailed(String),
+
+    /// 代理的规则不允许该连接 (回复码 0x02)
+    #[error("SOCKS5 server: connection not allowed by ruleset")]
+    NotAllowedByRuleset,
+
+    /// 代理到目标的网络不可达 (回复码 0x03)
+    #[error("SOCKS5 server: network unreachable")]
+    NetworkUnreachable,
+
+    /// 目标主机不可达 (回复码 0x04)
+    #[error("SOCKS5 server: host unreachable")]
+    HostUnreachable,
+
+    /// 目标拒绝连接 (回复码 0x05)
+    #[error("SOCKS5 server: connection refused by target")]
+    ConnectionRefused,
+
+    /// TTL 过期 (回复码 0x06)
+    #[error("SOCKS5 server: TTL expired")]
+    TtlExpired,
+
+    /// 代理不支持该命令 (回复码 0x07)
+    #[error("SOCKS5 server: command not supported")]
+    CommandNotSupported,
+
+    /// 代理不支持该地址类型 (回复码 0x08)
+    #[error("SOCKS5 server: address type not supported")]
+    AddressTypeNotSupported,
+
+    /// 代理的一般性失败 (回复码 0x01)
+    #[error("SOCKS5 server: general failure")]
+    GeneralFailure,
+
+    /// 代理的响应不符合协议
+    #[error("SOCKS5 protocol error: {0}")]
+    Protocol(String),
+
+    /// 与代理之间的 I/O 错误 (含连接代理失败)
+    #[error("SOCKS5 I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// 出站 socket 绑定源地址或网卡失败
+    #[error("SOCKS5 {0}")]
+    Bind(#[from] BindError),
+
+    /// 建连或握手超时
+    #[error("SOCKS5 {stage} timed out after {after:?}")]
+    Timeout {
+        stage: &'static str,
+        after: Duration,
+    },
+}
+
+impl Socks5Error {
+    /// 所有可能的 [`metric_label`](Self::metric_label) 取值
+    pub const METRIC_LABELS: [&'static str; 13] = [
+        "auth_failed",
+        "not_allowed",
+        "network_unreachable",
+        "host_unreachable",
+        "connection_refused",
+        "ttl_expired",
+        "command_not_supported",
+        "address_type_not_supported",
+        "general_failure",
+        "protocol",
+        "io",
+        "bind",
+        "timeout",
+    ];
+
+    /// 用于统计的稳定分类
+    pub fn metric_label(&self) -> &'static str {
+        match self {
+            Socks5Error::AuthFailed(_) => "auth_failed",
+            Socks5Error::NotAllowedByRuleset => "not_allowed",
+            Socks5Error::NetworkUnreachable => "network_unreachable",
+            Socks5Error::HostUnreachable => "host_unreachable",
+            Socks5Error::ConnectionRefused => "connection_refused",
+            Socks5Error::TtlExpired => "ttl_expired",
+            Socks5Error::CommandNotSupported => "command_not_supported",
+            Socks5Error::AddressTypeNotSupported => "address_type_not_supported",
+            Socks5Error::GeneralFailure => "general_failure",
+            Socks5Error::Protocol(_) => "protocol",
+            Socks5Error::Io(_) => "io",
+            Socks5Error::Bind(_) => "bind",
+            Socks5Error::Timeout { .. } => "timeout",
+        }
+    }
+
+    /// 是否值得重试
+    ///
+    /// 代理暂时不可达或过载时重试可能成功；认证、规则和不支持的命令/地址类型
+    /// 重试结果不会改变，目标拒绝或不可达也不是重试能解决的。
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            Socks5Error::Io(_)
+                | Socks5Error::Timeout { .. }
+                | Socks5Error::GeneralFailure
+                | Socks5Error::TtlExpired
+        )
+    }
+
+    /// 按分类输出日志：目标侧的失败只记 info，代理配置问题附带排查提示
+    ///
+    /// `upstream` 为经过的上游，`count` 为该分类的累计次数；客户端和目标由调用方的
+    /// span 或事件携带 (见 [`crate::logging`])。
+    ///
+    /// 上游故障时每个连接都会失败，同一分类的日志经 [`logging::allow_warning`] 限流。
+    pub fn log(&self, operation: Socks5Operation, upstream: &dyn std::fmt::Display, count: u64) {
+        if !logging::allow_warning(self.metric_label(), None) {
+            return;
+        }
+        macro_rules! log {
+            ($level:ident, $message:literal) => {
+                $level!(
+                    operation = operation.label(),
+                    upstream = %upstream,
+                    outcome = self.metric_label(),
+                    failures_so_far = count,
+                    error = %self,
+                    $message
+                )
+            };
+        }
+        match self {
+            Socks5Error::HostUnreachable
+            | Socks5Error::ConnectionRefused
+            | Socks5Error::NetworkUnreachable
+            | Socks5Error::TtlExpired => log!(info, "target unavailable"),
+            Socks5Error::AuthFailed(_) => log!(
+                warn,
+                "socks5 authentication failed, check socks5.username/password"
+            ),
+            Socks5Error::Bind(_) => log!(
+                warn,
+                "outbound bind failed, check socks5.bind_addr/bind_device"
+            ),
+            Socks5Error::NotAllowedByRuleset => log!(
+                warn,
+                "socks5 request not allowed, check the SOCKS5 server's access rules"
+            ),
+            Socks5Error::CommandNotSupported | Socks5Error::AddressTypeNotSupported => {
+                log!(warn, "socks5 request not supported by the server")
+            }
+            Socks5Error::GeneralFailure
+            | Socks5Error::Protocol(_)
+            | Socks5Error::Io(_)
+            | Socks5Error::Timeout { .. } => log!(warn, "socks5 proxy failed"),
+        }
+    }
+}
+
+impl From<DialError> for Socks5Error {
+    fn from(error: DialError) -> Self {
+        match error {
+            DialError::Bind(e) => Socks5Error::Bind(e),
+            DialError::Connect { source, .. } => Socks5Error::Io(source),
+        }
+    }
+}
+
+impl From<SocksError> for Socks5Error {
+    fn from(error: SocksError) -> Self {
+        match error {
+            SocksError::Io(e) => Socks5Error::Io(e),
+            SocksError::AuthMethodUnacceptable(_)
+            | SocksError::AuthenticationFailed(_)
+            | SocksError::AuthenticationRejected(_) => Socks5Error::AuthFailed(error.to_string()),
+            SocksError::ReplyError(reply) => match reply {
+                ReplyError::GeneralFailure => Socks5Error::GeneralFailure,
+                ReplyError::ConnectionNotAllowed => Socks5Error::NotAllowedByRuleset,
+                ReplyError::NetworkUnreachable => Socks5Error::NetworkUnreachable,
+                ReplyError::HostUnreachable => Socks5Error::HostUnreachable,
+                ReplyError::ConnectionRefused => Socks5Error::ConnectionRefused,
+                ReplyError::TtlExpired => Socks5Error::TtlExpired,
+                ReplyError::CommandNotSupported => Socks5Error::CommandNotSupported,
+                ReplyError::AddressTypeNotSupported => Socks5Error::AddressTypeNotSupported,
+                ReplyError::ConnectionTimeout | ReplyError::Succeeded => {
+                    Socks5Error::Protocol(format!("unexpected reply: {}", reply))
+                }
+            },
+            // fast-socks5 把读写失败包装在 anyhow 里
+            SocksError::Other(e) => match e.downcast::<std::io::Error>() {
+                Ok(e) => Socks5Error::Io(e),
+                Err(e) => Socks5Error::Protocol(format!("{:#}", e)),
+            },
+            other => Socks5Error::Protocol(other.to_string()),
+        }
+    }
+}
+
+/// 按 [`Socks5Error::metric_label`] 分类的失败计数
+#[derive(Debug, Default)]
+pub struct Socks5ErrorCounters {
+    counts: [AtomicU64; Socks5Error::METRIC_LABELS.len()],
+}
+
+impl Socks5ErrorCounters {
+    /// 记录一次失败，返回该分类的累计次数
+    pub fn record(&self, error: &Socks5Error) -> u64 {
+        let label = error.metric_label();
+        Socks5Error::METRIC_LABELS
+            .iter()
+            .position(|l| *l == label)
+            .map_or(0, |i| self.counts[i].fetch_add(1, Ordering::Relaxed) + 1)
+    }
+
+    /// 各分类的累计次数 (含全部分类)
+    pub fn snapshot(&self) -> BTreeMap<&'static str, u64> {
+        Socks5Error::METRIC_LABELS
+            .iter()
+            .zip(&self.counts)
+            .map(|(label, count)| (*label, count.load(Ordering::Relaxed)))
+            .collect()
+    }
+}
+
+/// 第一次重试前的等待时间，之后每次翻倍
+pub const RETRY_BACKOFF: Duration = Duration::from_millis(250);
+
+/// 建连失败后的重试策略
+///
+/// 只重试 [`Socks5Error::is_retryable`] 的失败；每次尝试各自计时和报告统计。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// 首次失败后最多重试的次数
+    pub retries: u32,
+    /// 第一次重试前的等待时间，之后每次翻倍
+    pub backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// 不重试
+    pub const fn none() -> Self {
+        Self {
+            retries: 0,
+            backoff: RETRY_BACKOFF,
+        }
+    }
+
+    /// 由上游配置的 `connect_retries` 创建
+    pub fn from_config(config: &Socks5Config) -> Self {
+        Self {
+            retries: config.connect_retries,
+            ..Self::none()
+        }
+    }
+
+    /// 按策略执行 `attempt`，直到成功、失败不可重试或用完重试次数
+    pub(crate) async fn run<T, F, Fut>(
+        &self,
+        operation: Socks5Operation,
+        mut attempt: F,
+    ) -> Result<T, Socks5Error>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, Socks5Error>>,
+    {
+        let mut backoff = self.backoff;
+        let mut retried = 0;
+        loop {
+            match attempt().await {
+                Err(e) if e.is_retryable() && retried < self.retries => {
+                    retried += 1;
+                    debug!(
+                        operation = operation.label(),
+                        error = %e,
+                        backoff = ?backoff,
+                        retry = retried,
+                        max_retries = self.retries,
+                        "socks5 request failed, retrying"
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff = backoff.saturating_mul(2);
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::none()
+    }
+}
+
+/// 代理链上第一跳之后的一跳
+#[derive(Debug, Clone)]
+pub struct Socks5Hop {
+    /// 代理地址，格式: "IP:PORT" 或 "域名:PORT" (由上一跳解析)
+    pub addr: String,
+    /// 可选的认证信息
+    pub auth: Option<(String, String)>,
+}
 
 /// SOCKS5 客户端 (使用 fast-socks5 库)
 #[derive(Clone)]
 pub struct Socks5Client {
-    proxy_addr: String,
+    /// 第一跳的代理地址及其解析缓存
+    proxy: Arc<ProxyResolver>,
     /// 可选的认证信息
     auth: Option<(String, String)>,
+    /// 第一跳之后依次经过的代理
+    next_hops: Vec<Socks5Hop>,
+    /// 到第一跳的连接的源地址/网卡
+    bind: OutboundBind,
     /// SOCKS5 建连和握手超时
     timeout: Duration,
+    /// 握手耗时的统计后端及本客户端的标签
+    metrics: Option<(Arc<dyn MetricsSink>, String)>,
+    /// 建连失败后的重试策略
+    retry: RetryPolicy,
 }
 
 impl Socks5Client {
@@ -27,10 +357,52 @@ impl Socks5Client {
     /// ```
     pub fn new<S: Into<String>>(proxy_addr: S) -> Self {
         Self {
-            proxy_addr: proxy_addr.into(),
+            proxy: Arc::new(ProxyResolver::new(proxy_addr)),
             auth: None,
+            next_hops: Vec::new(),
+            bind: OutboundBind::default(),
             timeout: Duration::from_secs(30),
+            metrics: None,
+            retry: RetryPolicy::none(),
+        }
+    }
+
+    /// 由上游配置创建：地址、认证、超时、出站绑定、重试策略和代理链
+    ///
+    /// 配置了 `chain` 时从其第一跳开始依次经过各跳，`addr` 和认证信息不使用。
+    pub fn from_config(config: &Socks5Config) -> Self {
+        let (addr, auth, rest) = match config.chain.split_first() {
+            Some((first, rest)) => (
+                first.addr.clone(),
+                first.username.clone().zip(first.password.clone()),
+                rest,
+            ),
+            None => (
+                config.addr.to_string(),
+                config.username.clone().zip(config.password.clone()),
+                &[][..],
+            ),
+        };
+        let mut client = Self::new(addr)
+            .with_bind(OutboundBind::from_config(config))
+            .with_timeout(Duration::from_secs(config.timeout))
+            .with_retry_policy(RetryPolicy::from_config(config));
+        if let Some((username, password)) = auth {
+            client = client.with_auth(username, password);
+        }
+        for hop in rest {
+            client = client.with_next_hop(Socks5Hop {
+                addr: hop.addr.clone(),
+                auth: hop.username.clone().zip(hop.password.clone()),
+            });
         }
+        client
+    }
+
+    /// 使用共享的代理地址解析缓存 (代替 `new` 的地址)
+    pub fn with_resolver(mut self, proxy: Arc<ProxyResolver>) -> Self {
+        self.proxy = proxy;
+        self
     }
 
     /// 设置认证信息
@@ -45,8 +417,42 @@ impl Socks5Client {
         self
     }
 
+    /// 设置到代理的连接的源地址/网卡
+    pub fn with_bind(mut self, bind: OutboundBind) -> Self {
+        self.bind = bind;
+        self
+    }
+
+    /// 只设置到代理的连接的源地址，保留已有的网卡设置
+    pub fn with_bind_addr(mut self, addr: IpAddr) -> Self {
+        self.bind.addr = Some(addr);
+        self
+    }
+
+    /// 设置建连失败后的重试策略
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// 向 `sink` 报告每次 CONNECT 的耗时和结果，`label` 为上游名称
+    pub fn with_metrics(mut self, sink: Arc<dyn MetricsSink>, label: impl Into<String>) -> Self {
+        self.metrics = Some((sink, label.into()));
+        self
+    }
+
+    /// 在链尾追加一跳：经前面的代理 CONNECT 到 `hop`，再在该隧道上与 `hop` 握手
+    pub fn with_next_hop(mut self, hop: Socks5Hop) -> Self {
+        self.next_hops.push(hop);
+        self
+    }
+
     /// 连接到目标服务器 (通过 SOCKS5 代理)
     ///
+    /// 配置了后续跳 ([`Self::with_next_hop`]) 时，依次在上一跳的 CONNECT 隧道上完成
+    /// 下一跳的握手，最后一跳 CONNECT 到目标；超时覆盖整条链，按
+    /// [`Self::with_retry_policy`] 重试时每次尝试单独计时。
+    ///
     /// # 参数
     /// * `target` - 目标主机 (域名或IP)
     /// * `port` - 目标端口
@@ -63,63 +469,269 @@ impl Socks5Client {
     /// # Ok(())
     /// # }
     /// ```
-    pub async fn connect(&self, target: &str, port: u16) -> Result<Socks5Stream<TcpStream>> {
+    pub async fn connect(&self, target: &str, port: u16) -> Result<Socks5TcpStream, Socks5Error> {
+        self.retry
+            .run(Socks5Operation::Connect, || self.connect_once(target, port))
+            .await
+    }
+
+    async fn connect_once(&self, target: &str, port: u16) -> Result<Socks5TcpStream, Socks5Error> {
         debug!(
-            "SOCKS5 CONNECT to {}:{} via proxy {}",
-            target, port, self.proxy_addr
+            target = %format_args!("{}:{}", target, port),
+            proxy = %self.proxy.addr(),
+            "socks5 connect"
         );
 
-        let mut config = Config::default();
-        config.set_connect_timeout(self.timeout.as_secs().max(1));
-
-        // 使用 fast-socks5 库连接，并用外层 timeout 覆盖完整握手/请求过程
+        // 自己建立到代理的 TCP 连接：fast-socks5 会把连接代理失败映射成回复码，
+        // 与代理返回的"目标拒绝连接"无法区分
         let connect = async {
-            if let Some((username, password)) = &self.auth {
-                // 带认证
-                Socks5Stream::connect_with_password(
-                    &self.proxy_addr,
-                    target.to_string(),
-                    port,
-                    username.clone(),
-                    password.clone(),
-                    config,
-                )
-                .await
-                .map_err(|e| anyhow!("SOCKS5 connection failed: {}", e))
-            } else {
-                // 无认证
-                Socks5Stream::connect(&self.proxy_addr, target.to_string(), port, config)
-                    .await
-                    .map_err(|e| anyhow!("SOCKS5 connection failed: {}", e))
+            let socket = outbound::connect(&self.proxy, &self.bind).await?;
+            let mut stream = Socks5HopStream::Tcp(socket);
+            let mut auth = self.auth.clone();
+            for hop in &self.next_hops {
+                let (host, hop_port) = split_host_port(&hop.addr)?;
+                debug!(next_hop = %hop.addr, "socks5 chain connect to next hop");
+                let tunnel = handshake(stream, auth, host, hop_port).await?;
+                stream = Socks5HopStream::Tunnel(Box::new(tunnel));
+                auth = hop.auth.clone();
             }
+            handshake(stream, auth, target, port).await
         };
 
-        let socks5_stream = tokio::time::timeout(self.timeout, connect)
+        // 外层 timeout 覆盖建连、握手和请求的完整过程
+        let start = Instant::now();
+        let result = tokio::time::timeout(self.timeout, connect)
             .await
-            .map_err(|_| anyhow!("SOCKS5 connection timed out after {:?}", self.timeout))??;
+            .map_err(|_| Socks5Error::Timeout {
+                stage: "CONNECT",
+                after: self.timeout,
+            })
+            .and_then(|result| result);
+        if let Some((sink, label)) = &self.metrics {
+            let class = result
+                .as_ref()
+                .map_or_else(Socks5Error::metric_label, |_| "ok");
+            sink.record(label, Socks5Operation::Connect, class, start.elapsed());
+        }
+        let socks5_stream = result?;
 
         debug!(
-            "SOCKS5 CONNECT established: {}:{} via {}",
-            target, port, self.proxy_addr
+            target = %format_args!("{}:{}", target, port),
+            proxy = %self.proxy.addr(),
+            "socks5 connect established"
         );
 
         Ok(socks5_stream)
     }
 }
 
-/// 导出 fast-socks5 的类型以方便使用
-pub type Socks5TcpStream = Socks5Stream<TcpStream>;
+/// 与一跳代理握手并 CONNECT 到 `target:port`
+async fn handshake(
+    stream: Socks5HopStream,
+    auth: Option<(String, String)>,
+    target: &str,
+    port: u16,
+) -> Result<Socks5TcpStream, Socks5Error> {
+    let auth =
+        auth.map(|(username, password)| AuthenticationMethod::Password { username, password });
+    let mut stream = Socks5Stream::use_stream(stream, auth, Config::default()).await?;
+    let target_addr = (target, port).to_target_addr()?;
+    stream
+        .request(Socks5Command::TCPConnect, target_addr)
+        .await?;
+    Ok(stream)
+}
+
+/// 拆分 "host:port"，IPv6 字面量带方括号
+fn split_host_port(addr: &str) -> Result<(&str, u16), Socks5Error> {
+    let invalid = || Socks5Error::Protocol(format!("invalid SOCKS5 hop address '{}'", addr));
+    let (host, port) = addr.rsplit_once(':').ok_or_else(invalid)?;
+    let port = port.parse().map_err(|_| invalid())?;
+    let host = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+    Ok((host, port))
+}
+
+/// 到代理链中某一跳的连接
+///
+/// 第一跳是直接的 TCP 连接，之后每一跳都建立在上一跳的 CONNECT 隧道上。
+pub enum Socks5HopStream {
+    /// 到第一跳的 TCP 连接
+    Tcp(TcpStream),
+    /// 经上一跳 CONNECT 建立的隧道
+    Tunnel(Box<Socks5Stream<Socks5HopStream>>),
+}
+
+impl Socks5HopStream {
+    /// 链底层到第一跳的 TCP 连接
+    pub fn tcp_stream(&self) -> &TcpStream {
+        match self {
+            Socks5HopStream::Tcp(stream) => stream,
+            Socks5HopStream::Tunnel(tunnel) => tunnel.get_socket_ref().tcp_stream(),
+        }
+    }
+}
+
+impl AsyncRead for Socks5HopStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Socks5HopStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Socks5HopStream::Tunnel(tunnel) => Pin::new(tunnel.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Socks5HopStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Socks5HopStream::Tcp(stream) => Pin::new(stream).poll_write(cx, data),
+            Socks5HopStream::Tunnel(tunnel) => Pin::new(tunnel.as_mut()).poll_write(cx, data),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Socks5HopStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Socks5HopStream::Tunnel(tunnel) => Pin::new(tunnel.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Socks5HopStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Socks5HopStream::Tunnel(tunnel) => Pin::new(tunnel.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+/// 经 SOCKS5 (可能多跳) 建立的到目标的流
+pub type Socks5TcpStream = Socks5Stream<Socks5HopStream>;
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::time::{Duration, Instant};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
     use tokio::net::TcpListener;
 
+    /// 接受无认证方式 (或回复 `method`)，并以回复码 `reply` 响应 CONNECT 的代理
+    async fn spawn_replying_socks5(method: u8, reply: u8) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut greeting = [0u8; 2];
+            stream.read_exact(&mut greeting).await.unwrap();
+            let mut methods = vec![0u8; greeting[1] as usize];
+            stream.read_exact(&mut methods).await.unwrap();
+            stream.write_all(&[0x05, method]).await.unwrap();
+
+            let mut head = [0u8; 5];
+            if stream.read_exact(&mut head).await.is_err() {
+                return;
+            }
+            let mut rest = vec![0u8; head[4] as usize + 2];
+            stream.read_exact(&mut rest).await.unwrap();
+            stream
+                .write_all(&[0x05, reply, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+        });
+        addr
+    }
+
+    /// 代理链中的一跳：校验用户名/密码 (若给出)，记录 CONNECT 目标；`relay` 为 true
+    /// 时真正连接目标并转发，否则回显隧道数据
+    async fn spawn_chain_hop(
+        credentials: Option<(&'static str, &'static str)>,
+        relay: bool,
+    ) -> (
+        std::net::SocketAddr,
+        tokio::sync::mpsc::UnboundedReceiver<String>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (targets_tx, targets_rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut greeting = [0u8; 2];
+            stream.read_exact(&mut greeting).await.unwrap();
+            let mut methods = vec![0u8; greeting[1] as usize];
+            stream.read_exact(&mut methods).await.unwrap();
+
+            if let Some((username, password)) = credentials {
+                assert!(methods.contains(&0x02), "hop expects password auth");
+                stream.write_all(&[0x05, 0x02]).await.unwrap();
+                let mut head = [0u8; 2];
+                stream.read_exact(&mut head).await.unwrap();
+                let mut user = vec![0u8; head[1] as usize];
+                stream.read_exact(&mut user).await.unwrap();
+                let mut len = [0u8; 1];
+                stream.read_exact(&mut len).await.unwrap();
+                let mut pass = vec![0u8; len[0] as usize];
+                stream.read_exact(&mut pass).await.unwrap();
+                let ok = user == username.as_bytes() && pass == password.as_bytes();
+                stream
+                    .write_all(&[0x01, if ok { 0x00 } else { 0x01 }])
+                    .await
+                    .unwrap();
+                if !ok {
+                    return;
+                }
+            } else {
+                stream.write_all(&[0x05, 0x00]).await.unwrap();
+            }
+
+            let mut head = [0u8; 4];
+            stream.read_exact(&mut head).await.unwrap();
+            let target = match head[3] {
+                0x01 => {
+                    let mut ip = [0u8; 4];
+                    stream.read_exact(&mut ip).await.unwrap();
+                    std::net::Ipv4Addr::from(ip).to_string()
+                }
+                0x03 => {
+                    let mut len = [0u8; 1];
+                    stream.read_exact(&mut len).await.unwrap();
+                    let mut domain = vec![0u8; len[0] as usize];
+                    stream.read_exact(&mut domain).await.unwrap();
+                    String::from_utf8(domain).unwrap()
+                }
+                atyp => panic!("unexpected address type {}", atyp),
+            };
+            let port = stream.read_u16().await.unwrap();
+            let target = format!("{}:{}", target, port);
+            targets_tx.send(target.clone()).unwrap();
+            stream
+                .write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+
+            if relay {
+                let mut upstream = tokio::net::TcpStream::connect(&target).await.unwrap();
+                let _ = tokio::io::copy_bidirectional(&mut stream, &mut upstream).await;
+            } else {
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            }
+        });
+        (addr, targets_rx)
+    }
+
     #[test]
     fn test_client_creation() {
         let client = Socks5Client::new("127.0.0.1:1080");
-        assert_eq!(client.proxy_addr, "127.0.0.1:1080");
+        assert_eq!(client.proxy.addr(), "127.0.0.1:1080");
         assert!(client.auth.is_none());
     }
 
@@ -134,8 +746,84 @@ mod tests {
         assert_eq!(password, "pass");
     }
 
-    // 注意: 实际的连接测试需要运行中的 SOCKS5 代理
-    // 这里只测试客户端创建
+    #[test]
+    fn from_config_applies_every_field() {
+        let mut config: Socks5Config = toml::from_str(
+            r#"
+addr = "proxy.example.net:1080"
+username = "user"
+password = "pass"
+timeout = 7
+connect_retries = 2
+bind_addr = "192.0.2.10"
+bind_device = "eth1"
+"#,
+        )
+        .unwrap();
+        let client = Socks5Client::from_config(&config);
+        assert_eq!(client.proxy.addr(), "proxy.example.net:1080");
+        assert_eq!(client.auth, Some(("user".to_string(), "pass".to_string())));
+        assert_eq!(client.timeout, Duration::from_secs(7));
+        assert_eq!(client.bind, OutboundBind::from_config(&config));
+        assert_eq!(client.retry.retries, 2);
+        assert!(client.next_hops.is_empty());
+
+        // 代理链代替 addr 和认证信息
+        config.chain = toml::from_str::<Socks5Config>(
+            r#"
+addr = "127.0.0.1:1"
+chain = ["10.0.0.1:1080", { addr = "exit.example.net:1080", username = "u", password = "p" }]
+"#,
+        )
+        .unwrap()
+        .chain;
+        let client =
+            Socks5Client::from_config(&config).with_bind_addr("192.0.2.20".parse().unwrap());
+        assert_eq!(client.proxy.addr(), "10.0.0.1:1080");
+        assert!(client.auth.is_none());
+        assert_eq!(client.next_hops.len(), 1);
+        assert_eq!(client.next_hops[0].addr, "exit.example.net:1080");
+        assert_eq!(
+            client.next_hops[0].auth,
+            Some(("u".to_string(), "p".to_string()))
+        );
+        assert_eq!(client.bind.addr, Some("192.0.2.20".parse().unwrap()));
+        assert_eq!(client.bind.device.as_deref(), Some("eth1"));
+    }
+
+    #[tokio::test]
+    async fn retryable_failures_are_retried_per_policy() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed = listener.local_addr().unwrap();
+        drop(listener);
+        let metrics = Arc::new(crate::socks5::LatencyMetrics::default());
+        let policy = RetryPolicy {
+            retries: 2,
+            backoff: Duration::from_millis(1),
+        };
+        let error = Socks5Client::new(closed.to_string())
+            .with_metrics(metrics.clone(), "default")
+            .with_retry_policy(policy)
+            .connect("example.com", 443)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(error, Socks5Error::Io(_)), "{}", error);
+        assert_eq!(metrics.snapshot()[0].count, 3);
+
+        // 不可重试的失败只尝试一次
+        let addr = spawn_replying_socks5(0x00, 0x02).await;
+        let metrics = Arc::new(crate::socks5::LatencyMetrics::default());
+        let error = Socks5Client::new(addr.to_string())
+            .with_metrics(metrics.clone(), "default")
+            .with_retry_policy(policy)
+            .connect("example.com", 443)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.metric_label(), "not_allowed");
+        assert_eq!(metrics.snapshot()[0].count, 1);
+    }
 
     #[tokio::test]
     async fn connect_times_out_when_proxy_accepts_but_never_responds() {
@@ -151,7 +839,137 @@ mod tests {
         let started = Instant::now();
         let result = client.connect("example.com", 443).await;
 
-        assert!(result.is_err());
+        assert!(matches!(result, Err(Socks5Error::Timeout { .. })));
         assert!(started.elapsed() < Duration::from_secs(1));
     }
+
+    #[tokio::test]
+    async fn connect_through_chained_proxies_with_per_hop_auth() {
+        let (exit, mut exit_targets) = spawn_chain_hop(Some(("exit", "secret")), false).await;
+        let (entry, mut entry_targets) = spawn_chain_hop(Some(("entry", "pass")), true).await;
+
+        let client = Socks5Client::new(entry.to_string())
+            .with_auth("entry".to_string(), "pass".to_string())
+            .with_next_hop(Socks5Hop {
+                addr: exit.to_string(),
+                auth: Some(("exit".to_string(), "secret".to_string())),
+            })
+            .with_timeout(Duration::from_secs(2));
+        let mut stream = client.connect("example.com", 443).await.unwrap();
+
+        // 第一跳 CONNECT 到第二跳，第二跳 CONNECT 到真正的目标
+        assert_eq!(entry_targets.recv().await.unwrap(), exit.to_string());
+        assert_eq!(exit_targets.recv().await.unwrap(), "example.com:443");
+
+        stream.write_all(b"hello").await.unwrap();
+        let mut echoed = [0u8; 5];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"hello");
+        assert_eq!(
+            stream.get_socket_ref().tcp_stream().peer_addr().unwrap(),
+            entry
+        );
+    }
+
+    #[tokio::test]
+    async fn chained_hop_auth_failure_is_reported() {
+        let (exit, _exit_targets) = spawn_chain_hop(Some(("exit", "secret")), false).await;
+        let (entry, _entry_targets) = spawn_chain_hop(None, true).await;
+
+        let error = Socks5Client::new(entry.to_string())
+            .with_next_hop(Socks5Hop {
+                addr: exit.to_string(),
+                auth: Some(("exit".to_string(), "wrong".to_string())),
+            })
+            .with_timeout(Duration::from_secs(2))
+            .connect("example.com", 443)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(error, Socks5Error::AuthFailed(_)), "{}", error);
+    }
+
+    #[test]
+    fn test_split_host_port() {
+        assert_eq!(
+            split_host_port("exit.example.net:1080").unwrap(),
+            ("exit.example.net", 1080)
+        );
+        assert_eq!(split_host_port("[::1]:1080").unwrap(), ("::1", 1080));
+        assert!(split_host_port("exit.example.net").is_err());
+    }
+
+    #[tokio::test]
+    async fn connect_classifies_reply_codes() {
+        let cases = [
+            (0x01, "general_failure", true),
+            (0x02, "not_allowed", false),
+            (0x03, "network_unreachable", false),
+            (0x04, "host_unreachable", false),
+            (0x05, "connection_refused", false),
+            (0x07, "command_not_supported", false),
+        ];
+        for (reply, label, retryable) in cases {
+            let addr = spawn_replying_socks5(0x00, reply).await;
+            let error = match Socks5Client::new(addr.to_string())
+                .connect("example.com", 443)
+                .await
+            {
+                Ok(_) => panic!("reply {:#04x} should fail", reply),
+                Err(e) => e,
+            };
+            assert_eq!(
+                error.metric_label(),
+                label,
+                "reply {:#04x}: {}",
+                reply,
+                error
+            );
+            assert_eq!(error.is_retryable(), retryable, "{}", label);
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_distinguishes_proxy_and_auth_failures() {
+        // 代理本身拒绝连接是 I/O 错误，不是目标的"connection refused"
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed = listener.local_addr().unwrap();
+        drop(listener);
+        let metrics = Arc::new(crate::socks5::LatencyMetrics::default());
+        let error = Socks5Client::new(closed.to_string())
+            .with_metrics(metrics.clone(), "default")
+            .connect("example.com", 443)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(error, Socks5Error::Io(_)), "{}", error);
+        assert!(error.is_retryable());
+
+        // 没有可接受的认证方式
+        let addr = spawn_replying_socks5(0xff, 0x00).await;
+        let error = Socks5Client::new(addr.to_string())
+            .with_metrics(metrics.clone(), "default")
+            .connect("example.com", 443)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(error, Socks5Error::AuthFailed(_)), "{}", error);
+        assert!(!error.is_retryable());
+
+        // 每次握手都以失败分类报告给统计后端
+        let snapshot = metrics.snapshot();
+        assert_eq!((snapshot[0].count, snapshot[0].errors), (2, 2));
+        assert_eq!(
+            snapshot[0].results,
+            BTreeMap::from([("auth_failed", 1), ("io", 1)])
+        );
+
+        let counters = Socks5ErrorCounters::default();
+        assert_eq!(counters.record(&error), 1);
+        assert_eq!(counters.record(&error), 2);
+        let snapshot = counters.snapshot();
+        assert_eq!(snapshot.len(), Socks5Error::METRIC_LABELS.len());
+        assert_eq!(snapshot["auth_failed"], 2);
+        assert_eq!(snapshot["io"], 0);
+    }
 }
@@ -0,0 +1,338 @@
+//! SOCKS5 建连耗时与结果统计
+//!
+//! [`Socks5Client::connect`](super::Socks5Client::connect) 和
+//! [`Socks5UdpClient::associate`](super::udp::Socks5UdpClient::associate) 在构造时可以注入一个
+//! [`MetricsSink`]，每次握手结束后报告耗时和结果分类 ("ok" 或
+//! [`Socks5Error::metric_label`](super::Socks5Error::metric_label))。socks5 模块只依赖这个
+//! trait；默认实现 [`LatencyMetrics`] 按上游名称和操作累计固定分桶的直方图，输出
+//! p50/p95/p99 和各结果分类的次数。
+
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::info;
+
+/// 被统计的 SOCKS5 操作
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Socks5Operation {
+    /// TCP CONNECT (含代理链各跳的握手)
+    Connect,
+    /// UDP ASSOCIATE
+    UdpAssociate,
+}
+
+impl Socks5Operation {
+    /// 指标标签取值，与序列化的名称相同
+    pub fn label(&self) -> &'static str {
+        match self {
+            Socks5Operation::Connect => "connect",
+            Socks5Operation::UdpAssociate => "udp_associate",
+        }
+    }
+}
+
+impl fmt::Display for Socks5Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Socks5Operation::Connect => "CONNECT",
+            Socks5Operation::UdpAssociate => "UDP ASSOCIATE",
+        })
+    }
+}
+
+/// 接收 SOCKS5 握手耗时的统计后端
+pub trait MetricsSink: Send + Sync {
+    /// 记录一次握手：`backend` 为上游名称 (未命名时为代理地址)，`result` 为结果分类
+    fn record(
+        &self,
+        backend: &str,
+        operation: Socks5Operation,
+        result: &'static str,
+        elapsed: Duration,
+    );
+}
+
+/// 直方图各桶的上界 (毫秒)，超过最后一个上界的计入溢出桶
+pub const BUCKET_BOUNDS_MS: [u64; 13] =
+    [1, 2, 5, 10, 20, 50, 100, 200, 500, 1000, 2000, 5000, 10000];
+
+/// 固定分桶的耗时直方图
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+    /// 最后一项为溢出桶
+    buckets: [u64; BUCKET_BOUNDS_MS.len() + 1],
+    count: u64,
+    sum: Duration,
+    max: Duration,
+}
+
+impl Histogram {
+    fn observe(&mut self, elapsed: Duration) {
+        let index = BUCKET_BOUNDS_MS
+            .iter()
+            .position(|bound| elapsed <= Duration::from_millis(*bound))
+            .unwrap_or(BUCKET_BOUNDS_MS.len());
+        self.buckets[index] += 1;
+        self.count += 1;
+        self.sum += elapsed;
+        self.max = self.max.max(elapsed);
+    }
+
+    /// 分位数，取所在桶的上界 (不超过观测到的最大值)；落在溢出桶时取最大值
+    fn quantile(&self, q: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((q * self.count as f64).ceil() as u64).clamp(1, self.count);
+        let mut seen = 0;
+        for (index, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(match BUCKET_BOUNDS_MS.get(index) {
+                    Some(bound) => Duration::from_millis(*bound).min(self.max),
+                    None => self.max,
+                });
+            }
+        }
+        Some(self.max)
+    }
+}
+
+/// 一个 (上游, 操作) 的累计统计
+#[derive(Debug, Clone, Default)]
+struct Series {
+    latency: Histogram,
+    results: BTreeMap<&'static str, u64>,
+}
+
+/// 按上游和操作累计的握手耗时与结果
+#[derive(Debug, Default)]
+pub struct LatencyMetrics {
+    series: Mutex<HashMap<(String, Socks5Operation), Series>>,
+}
+
+impl MetricsSink for LatencyMetrics {
+    fn record(
+        &self,
+        backend: &str,
+        operation: Socks5Operation,
+        result: &'static str,
+        elapsed: Duration,
+    ) {
+        let mut series = self.series.lock().unwrap_or_else(|e| e.into_inner());
+        let entry = series.entry((backend.to_string(), operation)).or_default();
+        entry.latency.observe(elapsed);
+        *entry.results.entry(result).or_default() += 1;
+    }
+}
+
+impl LatencyMetrics {
+    /// 当前的统计快照，按上游名称和操作排序
+    pub fn snapshot(&self) -> Vec<LatencySnapshot> {
+        let series = self.series.lock().unwrap_or_else(|e| e.into_inner());
+        let mut snapshot: Vec<LatencySnapshot> = series
+            .iter()
+            .map(|((backend, operation), series)| LatencySnapshot {
+                backend: backend.clone(),
+                operation: *operation,
+                count: series.latency.count,
+                errors: series
+                    .results
+                    .iter()
+                    .filter(|(result, _)| **result != "ok")
+                    .map(|(_, count)| count)
+                    .sum(),
+                p50: series.latency.quantile(0.50),
+                p95: series.latency.quantile(0.95),
+                p99: series.latency.quantile(0.99),
+                results: series.results.clone(),
+            })
+            .collect();
+        snapshot.sort_by(|a, b| (&a.backend, a.operation).cmp(&(&b.backend, b.operation)));
+        snapshot
+    }
+
+    /// 各 (上游, 操作) 的完整直方图，按上游名称和操作排序，用于导出 Prometheus 指标
+    pub fn histograms(&self) -> Vec<LatencyHistogram> {
+        let series = self.series.lock().unwrap_or_else(|e| e.into_inner());
+        let mut histograms: Vec<LatencyHistogram> = series
+            .iter()
+            .map(|((backend, operation), series)| LatencyHistogram {
+                backend: backend.clone(),
+                operation: *operation,
+                buckets: series.latency.buckets,
+                sum: series.latency.sum,
+                results: series.results.clone(),
+            })
+            .collect();
+        histograms.sort_by(|a, b| (&a.backend, a.operation).cmp(&(&b.backend, b.operation)));
+        histograms
+    }
+
+    /// 启动统计输出任务
+    ///
+    /// 每 `interval` 为有新握手的 (上游, 操作) 输出一行汇总，返回任务句柄
+    pub fn spawn_report_task(self: Arc<Self>, interval: Duration) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            let mut reported: HashMap<(String, Socks5Operation), u64> = HashMap::new();
+            loop {
+                ticker.tick().await;
+                for snapshot in self.snapshot() {
+                    let key = (snapshot.backend.clone(), snapshot.operation);
+                    if reported.insert(key, snapshot.count) != Some(snapshot.count) {
+                        info!(summary = %snapshot.summary(), "socks5 latency");
+                    }
+                }
+            }
+        })
+    }
+}
+
+/// 一个 (上游, 操作) 的统计快照，可序列化为 JSON (耗时以毫秒输出)
+#[derive(Debug, Clone, Serialize)]
+pub struct LatencySnapshot {
+    pub backend: String,
+    pub operation: Socks5Operation,
+    /// 握手次数
+    pub count: u64,
+    /// 失败次数
+    pub errors: u64,
+    #[serde(rename = "p50_ms", serialize_with = "serialize_millis")]
+    pub p50: Option<Duration>,
+    #[serde(rename = "p95_ms", serialize_with = "serialize_millis")]
+    pub p95: Option<Duration>,
+    #[serde(rename = "p99_ms", serialize_with = "serialize_millis")]
+    pub p99: Option<Duration>,
+    /// 各结果分类的次数
+    pub results: BTreeMap<&'static str, u64>,
+}
+
+/// 一个 (上游, 操作) 的直方图 (见 [`LatencyMetrics::histograms`])
+#[derive(Debug, Clone)]
+pub struct LatencyHistogram {
+    pub backend: String,
+    pub operation: Socks5Operation,
+    /// 各桶 (上界见 [`BUCKET_BOUNDS_MS`]) 的次数，不累计；最后一项为溢出桶
+    pub buckets: [u64; BUCKET_BOUNDS_MS.len() + 1],
+    /// 耗时总和
+    pub sum: Duration,
+    /// 各结果分类的次数
+    pub results: BTreeMap<&'static str, u64>,
+}
+
+impl LatencySnapshot {
+    /// 失败率 (0.0 - 1.0)
+    pub fn error_rate(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.errors as f64 / self.count as f64
+        }
+    }
+
+    /// 一行汇总
+    pub fn summary(&self) -> String {
+        let ms = |d: Option<Duration>| d.map_or(0, |d| d.as_millis());
+        format!(
+            "'{}' {}: {} attempts, {:.1}% failed, p50={}ms p95={}ms p99={}ms",
+            self.backend,
+            self.operation,
+            self.count,
+            self.error_rate() * 100.0,
+            ms(self.p50),
+            ms(self.p95),
+            ms(self.p99)
+        )
+    }
+}
+
+fn serialize_millis<S: serde::Serializer>(
+    duration: &Option<Duration>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    match duration {
+        Some(duration) => serializer.serialize_u64(duration.as_millis() as u64),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latencies_fall_into_fixed_buckets() {
+        let mut histogram = Histogram::default();
+        for ms in [0, 1, 2, 3, 7, 10, 15_000] {
+            histogram.observe(Duration::from_millis(ms));
+        }
+        // <=1ms: 0,1；<=2ms: 2；<=5ms: 3；<=10ms: 7,10；溢出: 15s
+        assert_eq!(histogram.buckets[..5], [2, 1, 1, 2, 0]);
+        assert_eq!(histogram.buckets[BUCKET_BOUNDS_MS.len()], 1);
+        assert_eq!(histogram.count, 7);
+
+        assert_eq!(histogram.quantile(0.5), Some(Duration::from_millis(5)));
+        assert_eq!(histogram.quantile(0.8), Some(Duration::from_millis(10)));
+        assert_eq!(histogram.quantile(0.99), Some(Duration::from_secs(15)));
+        assert_eq!(Histogram::default().quantile(0.5), None);
+    }
+
+    #[test]
+    fn snapshot_is_per_backend_and_operation() {
+        let metrics = LatencyMetrics::default();
+        for _ in 0..19 {
+            metrics.record(
+                "default",
+                Socks5Operation::Connect,
+                "ok",
+                Duration::from_millis(40),
+            );
+        }
+        metrics.record(
+            "default",
+            Socks5Operation::Connect,
+            "timeout",
+            Duration::from_millis(3000),
+        );
+        metrics.record(
+            "backup",
+            Socks5Operation::UdpAssociate,
+            "ok",
+            Duration::from_micros(300),
+        );
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(
+            (snapshot[0].backend.as_str(), snapshot[0].operation),
+            ("backup", Socks5Operation::UdpAssociate)
+        );
+        // 分位数被最大观测值截断，不会超过实际耗时
+        assert_eq!(snapshot[0].p99, Some(Duration::from_micros(300)));
+
+        let connect = &snapshot[1];
+        assert_eq!((connect.count, connect.errors), (20, 1));
+        assert_eq!(connect.p50, Some(Duration::from_millis(50)));
+        assert_eq!(connect.p95, Some(Duration::from_millis(50)));
+        assert_eq!(connect.p99, Some(Duration::from_millis(3000)));
+        assert_eq!(
+            connect.summary(),
+            "'default' CONNECT: 20 attempts, 5.0% failed, p50=50ms p95=50ms p99=3000ms"
+        );
+
+        let histograms = metrics.histograms();
+        assert_eq!(histograms[1].buckets.iter().sum::<u64>(), 20);
+        assert_eq!(histograms[1].sum, Duration::from_millis(19 * 40 + 3000));
+        assert_eq!(histograms[1].results["timeout"], 1);
+
+        let json = serde_json::to_value(connect).unwrap();
+        assert_eq!(json["operation"], "connect");
+        assert_eq!(json["p99_ms"], 3000);
+        assert_eq!(json["results"]["timeout"], 1);
+    }
+}
@@ -1,7 +1,14 @@
 pub mod client;
+pub mod metrics;
 pub mod pool;
+pub mod socks4;
 pub mod udp;
 
+/// 测试辅助，`testing` feature 下对集成测试公开
+#[cfg(any(test, feature = "testing"))]
+pub mod test_util;
+
 // 重新导出常用类型
-pub use client::{Socks5Client, Socks5TcpStream};
-pub use pool::{ConnectionPool, PoolConfig};
+pub use client::{RetryPolicy, Socks5Client, Socks5Error, Socks5ErrorCounters, Socks5TcpStream};
+pub use metrics::{LatencyMetrics, MetricsSink};
+pub use pool::{ConnectionPool, PoolConfig, PoolError, WarmConnector};
@@ -1,14 +1,58 @@
 /// SOCKS5 连接池
 ///
-/// 复用 SOCKS5 连接以提升性能,避免频繁建立连接的开销。
-use crate::socks5::Socks5TcpStream;
-use anyhow::{anyhow, Result};
-use std::collections::HashMap;
+/// 复用后端 (SOCKS5 或 HTTP CONNECT) 连接以提升性能,避免频繁建立连接的开销。
+use crate::backend::{BackendStream, ConnectError};
+use crate::config::KeepWarmTarget;
+use crate::metrics::TaskGuard;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
 use std::time::{Duration, Instant};
-use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
-use tokio::sync::{Mutex, OwnedSemaphorePermit, Semaphore};
-use tracing::debug;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::{watch, Mutex, Notify, OwnedSemaphorePermit, Semaphore};
+use tracing::{debug, info, warn};
+
+/// 每个目标最多保留的空闲连接数 (keep-warm 目标取其 `min_idle` 与此的较大值)
+const MAX_IDLE_PER_TARGET: usize = 5;
+
+/// keep-warm 补充连接失败后的初始退避，连续失败时翻倍
+const KEEP_WARM_BACKOFF: Duration = Duration::from_secs(1);
+
+/// keep-warm 退避上限
+const KEEP_WARM_MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// 建立一个到目标的后端连接
+pub type ConnectFuture = Pin<Box<dyn Future<Output = Result<BackendStream, ConnectError>> + Send>>;
+
+/// 维护任务补充 keep-warm 连接时使用的 connector，可多次调用
+pub type WarmConnector = Arc<dyn Fn(&str, u16) -> ConnectFuture + Send + Sync>;
+
+/// 连接池错误
+#[derive(Error, Debug)]
+pub enum PoolError {
+    /// 在 `acquire_timeout` 内没有等到连接名额
+    #[error(
+        "SOCKS5 connection pool exhausted: waited {waited:?}, {active}/{limit} connections in use"
+    )]
+    Exhausted {
+        waited: Duration,
+        active: usize,
+        limit: usize,
+    },
+
+    /// 连接池已关闭，不再发放连接名额
+    #[error("SOCKS5 connection pool closed")]
+    Closed,
+
+    /// connector 建立新连接失败
+    #[error(transparent)]
+    Connect(#[from] ConnectError),
+}
 
 /// 连接池配置
 #[derive(Clone)]
@@ -21,6 +65,12 @@ pub struct PoolConfig {
     pub max_lifetime: Duration,
     /// 清理间隔
     pub cleanup_interval: Duration,
+    /// 每个连接最多被取出使用的次数，达到后不再放回空闲池 (0 = 不限)
+    pub max_uses_per_connection: u64,
+    /// 等待连接名额的最长时间，超时返回 [`PoolError::Exhausted`] (0 = 一直等待)
+    pub acquire_timeout: Duration,
+    /// 持续保持最少空闲连接数的目标 (`pool.keep_warm`)
+    pub keep_warm: Vec<KeepWarmTarget>,
 }
 
 impl Default for PoolConfig {
@@ -30,34 +80,85 @@ impl Default for PoolConfig {
             idle_timeout: Duration::from_secs(60),
             max_lifetime: Duration::from_secs(300),
             cleanup_interval: Duration::from_secs(30),
+            max_uses_per_connection: 0,
+            acquire_timeout: Duration::from_secs(10),
+            keep_warm: Vec::new(),
         }
     }
 }
 
 /// 连接池中的单个连接
 struct PooledConnection {
-    /// SOCKS5 流
-    stream: Socks5TcpStream,
+    /// 到后端的流
+    stream: BackendStream,
     /// 连接占用的并发名额，随连接一起释放
     _permit: OwnedSemaphorePermit,
     /// 创建时间
     created_at: Instant,
-    /// 最后使用时间
+    /// 最后使用时间 (取出或归还的时间)
     last_used: Instant,
-    /// 使用次数
+    /// 被取出使用的次数 (含创建时的第一次；keep-warm 预先建立的连接从 0 开始)
     use_count: u64,
 }
 
+impl PooledConnection {
+    /// 不阻塞、不消费数据地检查连接是否仍然可用
+    ///
+    /// 对端已关闭 (EOF) 或 socket 上有待处理的错误时返回 false；
+    /// 没有数据可读或有未读数据时视为可用。
+    fn is_alive(&self) -> bool {
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut byte = [0u8; 1];
+        let mut buf = ReadBuf::new(&mut byte);
+        match self.stream.tcp_stream().poll_peek(&mut cx, &mut buf) {
+            Poll::Pending => true,
+            Poll::Ready(Ok(n)) => n > 0,
+            Poll::Ready(Err(_)) => false,
+        }
+    }
+}
+
 /// 连接池
 pub struct ConnectionPool {
     /// 连接池配置
     config: PoolConfig,
     /// 空闲连接: target_addr -> Vec<Connection>
     idle_connections: Arc<Mutex<HashMap<String, Vec<PooledConnection>>>>,
-    /// 信号量:限制总连接数
+    /// 信号量:限制总连接数 (含空闲连接)，名额随连接一起释放
     semaphore: Arc<Semaphore>,
-    /// 活跃连接数
-    active_count: Arc<Mutex<usize>>,
+    /// 有连接归还到空闲池时通知，等待名额的调用方可以关闭一个空闲连接
+    returned: Arc<Notify>,
+    /// keep-warm 目标的空闲连接被取走或丢弃时通知，维护任务随即补充
+    drained: Arc<Notify>,
+    /// 累计计数，各克隆共享
+    counters: Arc<PoolCounters>,
+    /// 关闭信号：清理任务随之退出，之后归还的连接直接关闭
+    stopping: Arc<watch::Sender<bool>>,
+}
+
+/// 连接池的累计计数
+#[derive(Debug, Default)]
+struct PoolCounters {
+    /// 新建的连接数
+    created: AtomicU64,
+    /// 其中由维护任务为 keep-warm 目标预先建立的连接数
+    warmed: AtomicU64,
+    /// keep-warm 补充连接失败的次数
+    warm_failures: AtomicU64,
+    /// 从空闲池取出复用的次数
+    reused: AtomicU64,
+    /// 超过空闲超时或最大生命周期而丢弃的连接数
+    expired_discarded: AtomicU64,
+    /// 复用前发现已失效而丢弃的空闲连接数
+    stale_discarded: AtomicU64,
+    /// 达到 `max_uses_per_connection` 而关闭的连接数
+    retired_max_uses: AtomicU64,
+    /// 等待名额超时的次数
+    acquire_timeouts: AtomicU64,
+    /// 正在等待连接名额的调用方数
+    waiters: AtomicUsize,
+    /// 历史最长的名额等待时间 (微秒)
+    max_wait_micros: AtomicU64,
 }
 
 impl ConnectionPool {
@@ -66,74 +167,100 @@ impl ConnectionPool {
         let semaphore = Arc::new(Semaphore::new(config.max_connections));
 
         debug!(
-            "Created SOCKS5 connection pool: max_connections={}, idle_timeout={:?}",
-            config.max_connections, config.idle_timeout
+            max_connections = config.max_connections,
+            idle_timeout = ?config.idle_timeout,
+            "connection pool created"
         );
 
         Self {
             config,
             idle_connections: Arc::new(Mutex::new(HashMap::new())),
             semaphore,
-            active_count: Arc::new(Mutex::new(0)),
+            returned: Arc::new(Notify::new()),
+            drained: Arc::new(Notify::new()),
+            counters: Arc::new(PoolCounters::default()),
+            stopping: Arc::new(watch::channel(false).0),
         }
     }
 
+    /// 目标的 keep-warm 下限，不是 keep-warm 目标时为 0
+    fn min_idle(&self, key: &str) -> usize {
+        self.config
+            .keep_warm
+            .iter()
+            .filter(|warm| warm.key() == key)
+            .map(|warm| warm.min_idle)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// 空闲连接是否已超过空闲超时或最大生命周期
+    fn is_expired(&self, conn: &PooledConnection, now: Instant) -> bool {
+        now.duration_since(conn.last_used) >= self.config.idle_timeout
+            || now.duration_since(conn.created_at) >= self.config.max_lifetime
+    }
+
     /// 获取连接
     pub async fn get_connection(
         &self,
         target: &str,
         port: u16,
-        connector: impl FnOnce(
-            &str,
-            u16,
-        ) -> std::pin::Pin<
-            Box<dyn std::future::Future<Output = Result<Socks5TcpStream>> + Send>,
-        >,
-    ) -> Result<PooledConnectionGuard> {
+        connector: impl FnOnce(&str, u16) -> ConnectFuture,
+    ) -> Result<PooledConnectionGuard, PoolError> {
         let key = format!("{}:{}", target, port);
 
-        // 1. 尝试从空闲连接中获取
+        // 1. 尝试从空闲连接中获取 (最近归还的优先)，丢弃已过期或已被对端关闭的连接
         {
             let mut idle = self.idle_connections.lock().await;
+            let mut reused = None;
+            let now = Instant::now();
             if let Some(conns) = idle.get_mut(&key) {
-                if let Some(idx) = conns.iter().position(|c| {
-                    Instant::now().duration_since(c.last_used) < self.config.idle_timeout
-                }) {
-                    let conn = conns.remove(idx);
-                    debug!("Reusing pooled connection to {}", key);
-
-                    // 如果没有空闲连接了,移除 key
-                    if conns.is_empty() {
-                        idle.remove(&key);
+                // 丢弃即释放其名额
+                while let Some(conn) = conns.pop() {
+                    if self.is_expired(&conn, now) {
+                        self.counters
+                            .expired_discarded
+                            .fetch_add(1, Ordering::Relaxed);
+                        debug!(target = %key, outcome = "expired", "pooled connection discarded");
+                    } else if !conn.is_alive() {
+                        self.counters
+                            .stale_discarded
+                            .fetch_add(1, Ordering::Relaxed);
+                        debug!(target = %key, outcome = "stale", "pooled connection discarded");
+                    } else {
+                        reused = Some(conn);
+                        break;
                     }
+                }
 
-                    return Ok(PooledConnectionGuard {
-                        pool: self.clone(),
-                        key,
-                        connection: Some(conn),
-                    });
+                // 如果没有空闲连接了,移除 key
+                if conns.is_empty() {
+                    idle.remove(&key);
+                }
+                if self.min_idle(&key) > 0 {
+                    self.drained.notify_one();
                 }
             }
+
+            if let Some(mut conn) = reused {
+                debug!(target = %key, "pooled connection reused");
+                self.counters.reused.fetch_add(1, Ordering::Relaxed);
+                conn.use_count += 1;
+                conn.last_used = now;
+                return Ok(PooledConnectionGuard {
+                    pool: self.clone(),
+                    key,
+                    connection: Some(conn),
+                });
+            }
         }
 
         // 2. 没有可用连接,创建新连接
-        debug!("Creating new SOCKS5 connection to {}", key);
-
-        // 等待信号量(限制总连接数)
-        let permit = self
-            .semaphore
-            .clone()
-            .acquire_owned()
-            .await
-            .map_err(|e| anyhow!("Failed to acquire semaphore: {}", e))?;
+        debug!(target = %key, "pooled connection created");
 
+        let permit = self.acquire_permit().await?;
         let stream = connector(target, port).await?;
-
-        // 增加活跃连接计数
-        {
-            let mut count = self.active_count.lock().await;
-            *count += 1;
-        }
+        self.counters.created.fetch_add(1, Ordering::Relaxed);
 
         let conn = PooledConnection {
             stream,
@@ -150,76 +277,202 @@ impl ConnectionPool {
         })
     }
 
+    /// 获取一个连接名额 (限制总连接数)
+    ///
+    /// 超过 `acquire_timeout` 仍未获得时返回 [`PoolError::Exhausted`]。
+    async fn acquire_permit(&self) -> Result<OwnedSemaphorePermit, PoolError> {
+        if let Ok(permit) = self.semaphore.clone().try_acquire_owned() {
+            return Ok(permit);
+        }
+
+        let start = Instant::now();
+        let result = {
+            let _waiter = WaiterGuard::new(&self.counters.waiters);
+            match self.config.acquire_timeout {
+                Duration::ZERO => Ok(self.wait_for_permit().await),
+                limit => tokio::time::timeout(limit, self.wait_for_permit()).await,
+            }
+        };
+        let waited = start.elapsed();
+        self.counters
+            .max_wait_micros
+            .fetch_max(waited.as_micros() as u64, Ordering::Relaxed);
+
+        match result {
+            Ok(permit) => permit,
+            Err(_) => Err({
+                self.counters
+                    .acquire_timeouts
+                    .fetch_add(1, Ordering::Relaxed);
+                PoolError::Exhausted {
+                    waited,
+                    active: self.active_connections(),
+                    limit: self.config.max_connections,
+                }
+            }),
+        }
+    }
+
+    /// 等待连接名额
+    ///
+    /// 名额被空闲连接占满时关闭最久未使用的空闲连接让出名额，
+    /// 否则等待使用中的连接关闭或归还。
+    async fn wait_for_permit(&self) -> Result<OwnedSemaphorePermit, PoolError> {
+        loop {
+            if let Ok(permit) = self.semaphore.clone().try_acquire_owned() {
+                return Ok(permit);
+            }
+            if self.evict_oldest_idle().await {
+                continue;
+            }
+            tokio::select! {
+                permit = self.semaphore.clone().acquire_owned() => {
+                    return permit.map_err(|_| PoolError::Closed);
+                }
+                _ = self.returned.notified() => {}
+            }
+        }
+    }
+
+    /// 当前连接数：每个连接 (使用中、已取出或空闲) 恰好持有一个名额
+    fn active_connections(&self) -> usize {
+        self.config.max_connections - self.semaphore.available_permits()
+    }
+
+    /// 关闭最久未使用的空闲连接；没有空闲连接时返回 false
+    async fn evict_oldest_idle(&self) -> bool {
+        let mut idle = self.idle_connections.lock().await;
+        let Some((key, idx)) = idle
+            .iter()
+            .flat_map(|(key, conns)| conns.iter().enumerate().map(move |(i, c)| (key, i, c)))
+            .min_by_key(|(_, _, conn)| conn.last_used)
+            .map(|(key, idx, _)| (key.clone(), idx))
+        else {
+            return false;
+        };
+
+        if let Some(conns) = idle.get_mut(&key) {
+            conns.remove(idx);
+            if conns.is_empty() {
+                idle.remove(&key);
+            }
+        }
+        debug!(
+            target = %key,
+            outcome = "evicted",
+            "connection limit reached, idle connection closed"
+        );
+        true
+    }
+
     /// 归还连接到池中
-    async fn return_connection(&self, key: String, conn: PooledConnection) {
-        // 检查连接是否仍然有效
+    async fn return_connection(&self, key: String, mut conn: PooledConnection) {
+        // 超过最大生命周期的连接不再放回
         let now = Instant::now();
         let age = now.duration_since(conn.created_at);
-        let idle = now.duration_since(conn.last_used);
+        if age >= self.config.max_lifetime {
+            debug!(target = %key, age = ?age, outcome = "expired", "pooled connection discarded");
+            self.counters
+                .expired_discarded
+                .fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        // 空闲计时从归还时开始
+        conn.last_used = now;
 
-        // 如果连接太老或空闲太久,丢弃它
-        if age > self.config.max_lifetime || idle > self.config.idle_timeout {
+        let max_uses = self.config.max_uses_per_connection;
+        if max_uses > 0 && conn.use_count >= max_uses {
             debug!(
-                "Dropping expired connection to {} (age={:?}, idle={:?})",
-                key, age, idle
+                target = %key,
+                use_count = conn.use_count,
+                outcome = "max_uses",
+                "pooled connection discarded"
             );
-            let mut count = self.active_count.lock().await;
-            *count = count.saturating_sub(1);
+            self.counters
+                .retired_max_uses
+                .fetch_add(1, Ordering::Relaxed);
             return;
         }
 
         // 将连接返回到池中
         let mut idle = self.idle_connections.lock().await;
+        if *self.stopping.borrow() {
+            drop(idle);
+            debug!(target = %key, outcome = "shutdown", "pooled connection discarded");
+            let _ = conn.stream.shutdown().await;
+            return;
+        }
+        let limit = self.min_idle(&key).max(MAX_IDLE_PER_TARGET);
         let conns = idle.entry(key.clone()).or_insert_with(Vec::new);
 
-        // 限制每个目标的空闲连接数(最多5个)
-        if conns.len() < 5 {
+        // 限制每个目标的空闲连接数
+        if conns.len() < limit {
             debug!(
-                "Returning connection to {} to pool (use_count={})",
-                key, conn.use_count
+                target = %key,
+                use_count = conn.use_count,
+                "connection returned to pool"
             );
             conns.push(conn);
+            self.returned.notify_one();
         } else {
-            debug!("Pool full for {}, dropping connection", key);
-            let mut count = self.active_count.lock().await;
-            *count = count.saturating_sub(1);
+            debug!(target = %key, outcome = "pool_full", "pooled connection discarded");
         }
     }
 
     /// 获取统计信息
-    #[allow(dead_code)]
     pub async fn stats(&self) -> PoolStats {
         let idle = self.idle_connections.lock().await;
-        let active = *self.active_count.lock().await;
+        let active = self.active_connections();
 
         let idle_count = idle.values().map(|v| v.len()).sum();
+        let mut idle_use_counts = BTreeMap::new();
+        for conn in idle.values().flatten() {
+            *idle_use_counts.entry(conn.use_count).or_insert(0) += 1;
+        }
         let targets: Vec<String> = idle.keys().cloned().collect();
+        let mut idle_by_target: BTreeMap<String, usize> = idle
+            .iter()
+            .map(|(key, conns)| (key.clone(), conns.len()))
+            .collect();
+        // keep-warm 目标没有空闲连接时也列出，便于观察下限是否保持
+        for warm in &self.config.keep_warm {
+            idle_by_target.entry(warm.key()).or_insert(0);
+        }
 
+        let counters = &self.counters;
         PoolStats {
             active_connections: active,
             idle_connections: idle_count,
             total_targets: targets.len(),
+            created: counters.created.load(Ordering::Relaxed),
+            warmed: counters.warmed.load(Ordering::Relaxed),
+            warm_failures: counters.warm_failures.load(Ordering::Relaxed),
+            reused: counters.reused.load(Ordering::Relaxed),
+            expired_discarded: counters.expired_discarded.load(Ordering::Relaxed),
+            stale_discarded: counters.stale_discarded.load(Ordering::Relaxed),
+            idle_use_counts,
+            retired_max_uses: counters.retired_max_uses.load(Ordering::Relaxed),
+            acquire_timeouts: counters.acquire_timeouts.load(Ordering::Relaxed),
+            waiters: counters.waiters.load(Ordering::Relaxed),
+            max_wait: Duration::from_micros(counters.max_wait_micros.load(Ordering::Relaxed)),
             targets,
+            idle_by_target,
         }
     }
 
-    /// 清理过期连接
-    #[allow(dead_code)]
-    pub async fn cleanup(&self) {
+    /// 清理过期的空闲连接，返回清理的数量
+    pub async fn cleanup(&self) -> usize {
         let mut idle = self.idle_connections.lock().await;
         let now = Instant::now();
         let mut removed = 0;
 
         idle.retain(|_key, conns| {
             conns.retain(|conn| {
-                let idle_time = now.duration_since(conn.last_used);
-                let age = now.duration_since(conn.created_at);
-
-                let keep = idle_time < self.config.idle_timeout && age < self.config.max_lifetime;
-                if !keep {
+                let expired = self.is_expired(conn, now);
+                if expired {
                     removed += 1;
                 }
-                keep
+                !expired
             });
 
             // 如果没有空闲连接了,移除 key
@@ -227,22 +480,144 @@ impl ConnectionPool {
         });
 
         if removed > 0 {
-            debug!("Cleaned up {} expired connections", removed);
+            self.counters
+                .expired_discarded
+                .fetch_add(removed as u64, Ordering::Relaxed);
+            debug!(removed, "expired pooled connections cleaned up");
         }
+        removed
     }
 
-    /// 启动连接池清理任务
+    /// 为 keep-warm 目标补足空闲连接，返回新建的连接数
     ///
-    /// 定期清理过期的空闲连接，返回任务句柄
-    pub fn spawn_cleanup_task(self: Arc<Self>) -> tokio::task::JoinHandle<()> {
+    /// 只使用空闲的名额，不为此关闭其它空闲连接或等待。某个目标建连失败后本轮不再补充，
+    /// 按 `backoff` 指数退避到期后再试，上游持续故障时不会反复建连。
+    async fn top_up(
+        &self,
+        connector: &WarmConnector,
+        backoff: &mut HashMap<String, WarmBackoff>,
+    ) -> usize {
+        let mut warmed = 0;
+        for warm in &self.config.keep_warm {
+            let key = warm.key();
+            let state = backoff.entry(key.clone()).or_default();
+            if state.retry_at.is_some_and(|at| Instant::now() < at) {
+                continue;
+            }
+
+            let now = Instant::now();
+            let idle = self
+                .idle_connections
+                .lock()
+                .await
+                .get(&key)
+                .map_or(0, |conns| {
+                    conns
+                        .iter()
+                        .filter(|conn| !self.is_expired(conn, now))
+                        .count()
+                });
+            for _ in idle..warm.min_idle {
+                let Ok(permit) = self.semaphore.clone().try_acquire_owned() else {
+                    debug!(target = %key, "connection limit reached, not warming");
+                    return warmed;
+                };
+                match connector(&warm.target.host(), warm.target.port()).await {
+                    Ok(stream) => {
+                        self.counters.created.fetch_add(1, Ordering::Relaxed);
+                        self.counters.warmed.fetch_add(1, Ordering::Relaxed);
+                        let now = Instant::now();
+                        let conn = PooledConnection {
+                            stream,
+                            _permit: permit,
+                            created_at: now,
+                            last_used: now,
+                            use_count: 0,
+                        };
+                        self.return_connection(key.clone(), conn).await;
+                        *state = WarmBackoff::default();
+                        warmed += 1;
+                    }
+                    Err(e) => {
+                        self.counters.warm_failures.fetch_add(1, Ordering::Relaxed);
+                        let delay = state.fail();
+                        warn!(
+                            target = %key,
+                            consecutive_failures = state.failures,
+                            retry_in = ?delay,
+                            error = %format_args!("{:#}", e),
+                            "failed to warm connection"
+                        );
+                        break;
+                    }
+                }
+            }
+        }
+        if warmed > 0 {
+            debug!(warmed, "pooled connections warmed");
+        }
+        warmed
+    }
+
+    /// 启动连接池维护任务
+    ///
+    /// 每 `cleanup_interval` 清理过期的空闲连接，并输出一行统计 (连接池空闲且没有变化时省略)；
+    /// 随后以及 keep-warm 目标的空闲连接被取走时，经 `connector` 补足 keep-warm 目标。
+    /// `task` 随任务结束释放。返回任务句柄；[`shutdown`](Self::shutdown) 后任务结束
+    pub fn spawn_maintenance_task(
+        self: Arc<Self>,
+        connector: WarmConnector,
+        task: TaskGuard,
+    ) -> tokio::task::JoinHandle<()> {
+        let mut stopping = self.stopping.subscribe();
         tokio::spawn(async move {
+            let _task = task;
             let mut interval = tokio::time::interval(self.config.cleanup_interval);
+            let mut last = self.stats().await;
+            let mut backoff = HashMap::new();
             loop {
-                interval.tick().await;
-                self.cleanup().await;
+                let tick = tokio::select! {
+                    _ = interval.tick() => true,
+                    _ = self.drained.notified() => false,
+                    _ = stopping.wait_for(|stop| *stop) => break,
+                };
+                if tick {
+                    self.cleanup().await;
+                    let stats = self.stats().await;
+                    if stats.active_connections > 0 || stats.discarded() != last.discarded() {
+                        info!(summary = %stats.summary(&last), "connection pool");
+                    }
+                    last = stats;
+                }
+                tokio::select! {
+                    _ = self.top_up(&connector, &mut backoff) => {}
+                    _ = stopping.wait_for(|stop| *stop) => break,
+                }
             }
+            debug!("connection pool maintenance task stopped");
         })
     }
+
+    /// 关闭连接池，返回关闭的空闲连接数
+    ///
+    /// 停止清理任务，逐个关闭 (发送 FIN) 所有空闲连接。正在归还的连接持有空闲池的锁，
+    /// 这里取锁即等待其完成；之后归还的连接不再入池而是直接关闭。
+    /// 仍被调用方持有的连接不受影响，随守卫释放。
+    pub async fn shutdown(&self) -> usize {
+        self.stopping.send_replace(true);
+        let idle = std::mem::take(&mut *self.idle_connections.lock().await);
+        let mut closed = 0;
+        for (key, conns) in idle {
+            for mut conn in conns {
+                if let Err(e) = conn.stream.shutdown().await {
+                    debug!(target = %key, error = %e, "failed to close idle connection");
+                }
+                closed += 1;
+            }
+        }
+        info!(closed, "connection pool shut down");
+        closed
+    }
 }
 
 impl Clone for ConnectionPool {
@@ -251,12 +626,19 @@ impl Clone for ConnectionPool {
             config: self.config.clone(),
             idle_connections: Arc::clone(&self.idle_connections),
             semaphore: Arc::clone(&self.semaphore),
-            active_count: Arc::clone(&self.active_count),
+            returned: Arc::clone(&self.returned),
+            drained: Arc::clone(&self.drained),
+            counters: Arc::clone(&self.counters),
+            stopping: Arc::clone(&self.stopping),
         }
     }
 }
 
-/// 连接池守卫,自动归还连接
+/// 连接池守卫
+///
+/// 本身实现 `AsyncRead + AsyncWrite`，转发代码直接在 `&mut guard` 上读写，
+/// 结束时调用 [`finish`](Self::finish) 决定连接是否放回空闲池。
+/// 未调用 `finish` 就被丢弃时连接被关闭 (其状态未知，不能交给下一个调用方)。
 pub struct PooledConnectionGuard {
     pool: ConnectionPool,
     key: String,
@@ -264,127 +646,253 @@ pub struct PooledConnectionGuard {
 }
 
 impl PooledConnectionGuard {
-    /// 获取底层的 SOCKS5 流引用
-    #[allow(dead_code)]
-    pub fn get(&self) -> &Socks5TcpStream {
-        &self.connection.as_ref().unwrap().stream
-    }
-
-    /// 获取底层的 SOCKS5 流可变引用
-    #[allow(dead_code)]
-    pub fn get_mut(&mut self) -> &mut Socks5TcpStream {
-        &mut self.connection.as_mut().unwrap().stream
-    }
-
-    /// 取出流的所有权，用于需要转移所有权的场景 (如 split)
+    /// 结束使用
     ///
-    /// 注意：取出后连接不会被归还到池中
-    pub fn into_inner(mut self) -> PooledStream {
-        // 取出连接，阻止 Drop 中的归还逻辑
-        let conn = self.connection.take().unwrap();
-        PooledStream {
-            stream: conn.stream,
-            _permit: conn._permit,
+    /// `reusable` 由调用方判断：只有应用层消息已完整结束、隧道仍处于可以承载下一个
+    /// 请求的状态时才为 true (如完整读完响应的 HTTP keep-alive)。TLS 会话不能跨
+    /// 客户端复用，这类调用方总是传 false，连接在此关闭并释放名额。
+    pub async fn finish(mut self, reusable: bool) {
+        let Some(conn) = self.connection.take() else {
+            return;
+        };
+        if reusable {
+            self.pool.return_connection(self.key.clone(), conn).await;
+        } else {
+            debug!(target = %self.key, "non-reusable pooled connection closed");
         }
     }
-}
 
-/// 已从连接池取出的 SOCKS5 流。
-///
-/// 持有 semaphore permit，直到转发生命周期结束。
-pub struct PooledStream {
-    stream: Socks5TcpStream,
-    _permit: OwnedSemaphorePermit,
+    /// 连接只在 `finish` (消耗守卫) 和 drop 时取出，这里总是存在；
+    /// 以错误而不是 panic 兜底
+    fn stream_pin(&mut self) -> std::io::Result<std::pin::Pin<&mut BackendStream>> {
+        match self.connection.as_mut() {
+            Some(conn) => Ok(std::pin::Pin::new(&mut conn.stream)),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                "pooled connection already released",
+            )),
+        }
+    }
 }
 
-impl AsyncRead for PooledStream {
+impl AsyncRead for PooledConnectionGuard {
     fn poll_read(
         mut self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
+        cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
-    ) -> std::task::Poll<std::io::Result<()>> {
-        std::pin::Pin::new(&mut self.stream).poll_read(cx, buf)
+    ) -> Poll<std::io::Result<()>> {
+        self.stream_pin()?.poll_read(cx, buf)
     }
 }
 
-impl AsyncWrite for PooledStream {
+impl AsyncWrite for PooledConnectionGuard {
     fn poll_write(
         mut self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
+        cx: &mut Context<'_>,
         data: &[u8],
-    ) -> std::task::Poll<std::io::Result<usize>> {
-        std::pin::Pin::new(&mut self.stream).poll_write(cx, data)
+    ) -> Poll<std::io::Result<usize>> {
+        self.stream_pin()?.poll_write(cx, data)
     }
 
     fn poll_flush(
         mut self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<std::io::Result<()>> {
-        std::pin::Pin::new(&mut self.stream).poll_flush(cx)
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        self.stream_pin()?.poll_flush(cx)
     }
 
     fn poll_shutdown(
         mut self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<std::io::Result<()>> {
-        std::pin::Pin::new(&mut self.stream).poll_shutdown(cx)
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        self.stream_pin()?.poll_shutdown(cx)
     }
 }
 
 impl Drop for PooledConnectionGuard {
     fn drop(&mut self) {
-        if let Some(conn) = self.connection.take() {
-            let pool = self.pool.clone();
-            let key = self.key.clone();
-
-            tokio::spawn(async move {
-                pool.return_connection(key, conn).await;
-            });
+        if self.connection.take().is_some() {
+            debug!(
+                target = %self.key,
+                "pooled connection dropped without finish, closed"
+            );
         }
     }
 }
 
 /// 连接池统计信息
-#[derive(Debug, Clone)]
-#[allow(dead_code)]
+///
+/// 管理端口的 `GET /pool` 以 JSON 返回；计数均为累计值，`max_wait` 以毫秒输出为 `max_wait_ms`。
+#[derive(Debug, Clone, Serialize)]
 pub struct PoolStats {
+    /// 当前连接数 (使用中与空闲)
     pub active_connections: usize,
     pub idle_connections: usize,
     pub total_targets: usize,
     pub targets: Vec<String>,
+    /// 新建的连接数
+    pub created: u64,
+    /// 其中由维护任务为 keep-warm 目标预先建立的连接数
+    pub warmed: u64,
+    /// keep-warm 补充连接失败的次数
+    pub warm_failures: u64,
+    /// 从空闲池取出复用的次数
+    pub reused: u64,
+    /// 超过空闲超时或最大生命周期而丢弃的连接数
+    pub expired_discarded: u64,
+    /// 复用前发现已失效而丢弃的空闲连接数 (pool_stale_discarded)
+    pub stale_discarded: u64,
+    /// 空闲连接按已使用次数的分布: use_count -> 连接数
+    pub idle_use_counts: BTreeMap<u64, usize>,
+    /// 达到 `max_uses_per_connection` 而关闭的连接数
+    pub retired_max_uses: u64,
+    /// 等待名额超时的次数
+    pub acquire_timeouts: u64,
+    /// 正在等待连接名额的调用方数
+    pub waiters: usize,
+    /// 历史最长的名额等待时间
+    #[serde(rename = "max_wait_ms", serialize_with = "serialize_millis")]
+    pub max_wait: Duration,
+    /// 各目标的空闲连接数，keep-warm 目标即使为 0 也列出
+    pub idle_by_target: BTreeMap<String, usize>,
+}
+
+impl PoolStats {
+    /// 累计丢弃的连接数 (过期、失效和达到使用次数上限)
+    pub fn discarded(&self) -> u64 {
+        self.expired_discarded + self.stale_discarded + self.retired_max_uses
+    }
+
+    /// 一行汇总，丢弃数为相对更早的快照 `earlier` 的增量
+    pub fn summary(&self, earlier: &PoolStats) -> String {
+        format!(
+            "{} active, {} idle, {} targets, {} discarded since last",
+            self.active_connections,
+            self.idle_connections,
+            self.total_targets,
+            self.discarded().saturating_sub(earlier.discarded())
+        )
+    }
+}
+
+fn serialize_millis<S: serde::Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_u64(duration.as_millis() as u64)
+}
+
+/// keep-warm 目标连续补充失败后的退避状态
+#[derive(Debug, Default)]
+struct WarmBackoff {
+    /// 连续失败次数
+    failures: u32,
+    /// 在此之前不再尝试
+    retry_at: Option<Instant>,
+}
+
+impl WarmBackoff {
+    /// 记录一次失败，返回下次尝试前的等待时间
+    fn fail(&mut self) -> Duration {
+        self.failures += 1;
+        let delay = KEEP_WARM_BACKOFF
+            .saturating_mul(1 << self.failures.min(16).saturating_sub(1))
+            .min(KEEP_WARM_MAX_BACKOFF);
+        self.retry_at = Some(Instant::now() + delay);
+        delay
+    }
+}
+
+/// 等待名额期间计入 `waiters`，调用方放弃等待时同样减回
+struct WaiterGuard<'a>(&'a AtomicUsize);
+
+impl<'a> WaiterGuard<'a> {
+    fn new(waiters: &'a AtomicUsize) -> Self {
+        waiters.fetch_add(1, Ordering::Relaxed);
+        Self(waiters)
+    }
+}
+
+impl Drop for WaiterGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::metrics::{Registry, TaskKind};
     use std::time::Duration;
     use tokio::io::{AsyncReadExt, AsyncWriteExt};
     use tokio::net::TcpListener;
 
-    async fn spawn_minimal_socks5_server() -> std::net::SocketAddr {
+    fn cleanup_task(registry: &Arc<Registry>) -> TaskGuard {
+        registry.track_task(TaskKind::Cleanup)
+    }
+
+    /// 每个连接握手后回显收到的数据，`hold` 后关闭
+    async fn spawn_minimal_socks5_server(hold: Duration) -> std::net::SocketAddr {
         let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
         let addr = listener.local_addr().unwrap();
 
         tokio::spawn(async move {
-            let (mut stream, _) = listener.accept().await.unwrap();
-
-            let mut greeting = [0u8; 3];
-            stream.read_exact(&mut greeting).await.unwrap();
-            stream.write_all(&[0x05, 0x00]).await.unwrap();
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut greeting = [0u8; 3];
+                    stream.read_exact(&mut greeting).await.unwrap();
+                    stream.write_all(&[0x05, 0x00]).await.unwrap();
 
-            let mut request = [0u8; 10];
-            stream.read_exact(&mut request).await.unwrap();
-            stream
-                .write_all(&[0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1, 0x1f, 0x90])
-                .await
-                .unwrap();
+                    // CONNECT 请求：域名目标，长度可变
+                    let mut head = [0u8; 5];
+                    stream.read_exact(&mut head).await.unwrap();
+                    let mut rest = vec![0u8; head[4] as usize + 2];
+                    stream.read_exact(&mut rest).await.unwrap();
+                    stream
+                        .write_all(&[0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1, 0x1f, 0x90])
+                        .await
+                        .unwrap();
 
-            tokio::time::sleep(Duration::from_secs(5)).await;
+                    let (mut reader, mut writer) = stream.split();
+                    let _ =
+                        tokio::time::timeout(hold, tokio::io::copy(&mut reader, &mut writer)).await;
+                });
+            }
         });
 
         addr
     }
 
+    fn test_pool(max_connections: usize) -> ConnectionPool {
+        ConnectionPool::new(PoolConfig {
+            max_connections,
+            idle_timeout: Duration::from_secs(30),
+            max_lifetime: Duration::from_secs(120),
+            cleanup_interval: Duration::from_secs(30),
+            max_uses_per_connection: 0,
+            acquire_timeout: Duration::from_secs(10),
+            keep_warm: Vec::new(),
+        })
+    }
+
+    /// 先等待 `gate` 再经 `socks_addr` 建立连接的 connector
+    fn gated_connector(
+        socks_addr: std::net::SocketAddr,
+        gate: Arc<Semaphore>,
+    ) -> impl FnOnce(&str, u16) -> ConnectFuture {
+        move |target, port| {
+            let target = target.to_string();
+            Box::pin(async move {
+                gate.acquire().await.unwrap().forget();
+                crate::socks5::Socks5Client::new(socks_addr.to_string())
+                    .connect(&target, port)
+                    .await
+                    .map(BackendStream::Socks5)
+                    .map_err(Into::into)
+            })
+        }
+    }
+
     #[test]
     fn test_pool_config_default() {
         let config = PoolConfig::default();
@@ -399,6 +907,9 @@ mod tests {
             idle_timeout: Duration::from_secs(30),
             max_lifetime: Duration::from_secs(120),
             cleanup_interval: Duration::from_secs(30),
+            max_uses_per_connection: 0,
+            acquire_timeout: Duration::from_secs(10),
+            keep_warm: Vec::new(),
         };
 
         let pool = ConnectionPool::new(config);
@@ -410,13 +921,8 @@ mod tests {
 
     #[tokio::test]
     async fn checked_out_stream_holds_permit_until_dropped() {
-        let socks_addr = spawn_minimal_socks5_server().await;
-        let pool = ConnectionPool::new(PoolConfig {
-            max_connections: 1,
-            idle_timeout: Duration::from_secs(30),
-            max_lifetime: Duration::from_secs(120),
-            cleanup_interval: Duration::from_secs(30),
-        });
+        let socks_addr = spawn_minimal_socks5_server(Duration::from_secs(5)).await;
+        let pool = test_pool(1);
 
         let guard = pool
             .get_connection("example.com", 443, move |target, port| {
@@ -425,6 +931,8 @@ mod tests {
                     crate::socks5::Socks5Client::new(socks_addr.to_string())
                         .connect(&target, port)
                         .await
+                        .map(BackendStream::Socks5)
+                        .map_err(Into::into)
                 })
             })
             .await
@@ -432,12 +940,489 @@ mod tests {
 
         assert_eq!(pool.semaphore.available_permits(), 0);
 
-        let stream = guard.into_inner();
+        // 转发直接在守卫上读写，期间一直占用名额
+        let mut guard = guard;
+        guard.write_all(b"ping").await.unwrap();
+        let mut echoed = [0u8; 4];
+        guard.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"ping");
         assert_eq!(pool.semaphore.available_permits(), 0);
 
-        drop(stream);
+        drop(guard);
+        assert_eq!(pool.semaphore.available_permits(), 1);
+        assert_eq!(pool.stats().await.idle_connections, 0);
+    }
+
+    #[tokio::test]
+    async fn third_connection_waits_for_a_guard_to_drop() {
+        let socks_addr = spawn_minimal_socks5_server(Duration::from_secs(5)).await;
+        let pool = test_pool(2);
+        let gate = Arc::new(Semaphore::new(0));
+
+        // 两个 connector 阻塞期间已占满名额
+        let first = tokio::spawn({
+            let pool = pool.clone();
+            let connector = gated_connector(socks_addr, Arc::clone(&gate));
+            async move { pool.get_connection("a.example", 443, connector).await }
+        });
+        let second = tokio::spawn({
+            let pool = pool.clone();
+            let connector = gated_connector(socks_addr, Arc::clone(&gate));
+            async move { pool.get_connection("b.example", 443, connector).await }
+        });
         tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(pool.semaphore.available_permits(), 0);
 
-        assert_eq!(pool.semaphore.available_permits(), 1);
+        let third = tokio::spawn({
+            let pool = pool.clone();
+            let connector = gated_connector(socks_addr, Arc::clone(&gate));
+            async move { pool.get_connection("c.example", 443, connector).await }
+        });
+        gate.add_permits(3);
+
+        let first = first.await.unwrap().unwrap();
+        let second = second.await.unwrap().unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!third.is_finished());
+        assert_eq!(pool.stats().await.active_connections, 2);
+
+        // 归还到空闲池的连接为等待者让出名额
+        first.finish(true).await;
+        let third = tokio::time::timeout(Duration::from_secs(2), third)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+
+        let stats = pool.stats().await;
+        assert_eq!(stats.active_connections, 2);
+        assert_eq!(stats.idle_connections, 0);
+
+        third.finish(false).await;
+        second.finish(true).await;
+        // 空闲连接仍占名额，使用中的已释放
+        let stats = pool.stats().await;
+        assert_eq!(stats.idle_connections, 1);
+        assert_eq!(stats.active_connections, 1);
+    }
+
+    #[tokio::test]
+    async fn stale_idle_connection_is_replaced_transparently() {
+        // 服务器 100ms 后关闭空闲连接
+        let socks_addr = spawn_minimal_socks5_server(Duration::from_millis(100)).await;
+        let pool = test_pool(2);
+        let gate = Arc::new(Semaphore::new(Semaphore::MAX_PERMITS));
+
+        let guard = pool
+            .get_connection(
+                "example.com",
+                443,
+                gated_connector(socks_addr, Arc::clone(&gate)),
+            )
+            .await
+            .unwrap();
+        guard.finish(true).await;
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(pool.stats().await.idle_connections, 1);
+
+        let guard = pool
+            .get_connection(
+                "example.com",
+                443,
+                gated_connector(socks_addr, Arc::clone(&gate)),
+            )
+            .await
+            .unwrap();
+        let conn = guard.connection.as_ref().unwrap();
+        // 新建的连接，而不是被关闭的那一个
+        assert_eq!(conn.use_count, 1);
+        assert!(conn.is_alive());
+
+        let stats = pool.stats().await;
+        assert_eq!(stats.stale_discarded, 1);
+        assert_eq!(stats.idle_connections, 0);
+        assert_eq!(stats.active_connections, 1);
+    }
+
+    #[tokio::test]
+    async fn connection_is_closed_after_max_uses() {
+        let socks_addr = spawn_minimal_socks5_server(Duration::from_secs(5)).await;
+        let pool = ConnectionPool::new(PoolConfig {
+            max_uses_per_connection: 3,
+            ..PoolConfig::default()
+        });
+        let gate = Arc::new(Semaphore::new(Semaphore::MAX_PERMITS));
+
+        let mut use_counts = Vec::new();
+        for _ in 0..4 {
+            let guard = pool
+                .get_connection(
+                    "example.com",
+                    443,
+                    gated_connector(socks_addr, Arc::clone(&gate)),
+                )
+                .await
+                .unwrap();
+            use_counts.push(guard.connection.as_ref().unwrap().use_count);
+            guard.finish(true).await;
+
+            let stats = pool.stats().await;
+            if use_counts.len() < 3 {
+                assert_eq!(
+                    stats.idle_use_counts,
+                    BTreeMap::from([(use_counts.len() as u64, 1)])
+                );
+            }
+        }
+
+        // 第三次使用后关闭，第四次取出的是新连接
+        assert_eq!(use_counts, vec![1, 2, 3, 1]);
+        let stats = pool.stats().await;
+        assert_eq!(stats.retired_max_uses, 1);
+        assert_eq!(stats.idle_use_counts, BTreeMap::from([(1, 1)]));
+        assert_eq!(stats.active_connections, 1);
+    }
+
+    #[tokio::test]
+    async fn acquisition_times_out_when_pool_is_exhausted() {
+        let socks_addr = spawn_minimal_socks5_server(Duration::from_secs(5)).await;
+        let pool = ConnectionPool::new(PoolConfig {
+            max_connections: 1,
+            acquire_timeout: Duration::from_millis(200),
+            ..PoolConfig::default()
+        });
+        let gate = Arc::new(Semaphore::new(Semaphore::MAX_PERMITS));
+
+        let _held = pool
+            .get_connection(
+                "a.example",
+                443,
+                gated_connector(socks_addr, Arc::clone(&gate)),
+            )
+            .await
+            .unwrap();
+
+        let start = Instant::now();
+        let waiter = tokio::spawn({
+            let pool = pool.clone();
+            let connector = gated_connector(socks_addr, Arc::clone(&gate));
+            async move { pool.get_connection("b.example", 443, connector).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(pool.stats().await.waiters, 1);
+
+        let err = match waiter.await.unwrap() {
+            Ok(_) => panic!("acquisition should time out"),
+            Err(e) => e,
+        };
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(200), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(1), "{:?}", elapsed);
+
+        match err {
+            PoolError::Exhausted {
+                waited,
+                active,
+                limit,
+            } => {
+                assert!(waited >= Duration::from_millis(200));
+                assert_eq!((active, limit), (1, 1));
+            }
+            err => panic!("unexpected error: {}", err),
+        }
+
+        let stats = pool.stats().await;
+        assert_eq!(stats.waiters, 0);
+        assert!(stats.max_wait >= Duration::from_millis(200));
+        assert_eq!(stats.acquire_timeouts, 1);
+    }
+
+    /// 直接连本地监听器的 connector，监听器保持所有连接打开
+    async fn spawn_tcp_connector() -> impl Fn(&str, u16) -> ConnectFuture + Clone {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                held.push(stream);
+            }
+        });
+        move |_target, _port| {
+            Box::pin(async move {
+                Ok(BackendStream::Tcp(
+                    tokio::net::TcpStream::connect(addr).await.unwrap(),
+                ))
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn counters_follow_create_reuse_expire_lifecycle() {
+        let connector = spawn_tcp_connector().await;
+        let pool = ConnectionPool::new(PoolConfig {
+            idle_timeout: Duration::from_millis(100),
+            ..PoolConfig::default()
+        });
+
+        // 新建
+        let guard = pool
+            .get_connection("example.com", 443, connector.clone())
+            .await
+            .unwrap();
+        let stats = pool.stats().await;
+        assert_eq!((stats.created, stats.reused), (1, 0));
+
+        // 归还后复用
+        guard.finish(true).await;
+        let guard = pool
+            .get_connection("example.com", 443, connector.clone())
+            .await
+            .unwrap();
+        let stats = pool.stats().await;
+        assert_eq!((stats.created, stats.reused), (1, 1));
+        assert_eq!(stats.idle_connections, 0);
+
+        // 归还后空闲超时，由清理任务丢弃
+        guard.finish(true).await;
+        assert_eq!(pool.cleanup().await, 0);
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        let before = pool.stats().await;
+        assert_eq!(pool.cleanup().await, 1);
+
+        let stats = pool.stats().await;
+        assert_eq!(stats.expired_discarded, 1);
+        assert_eq!(stats.discarded(), 1);
+        assert_eq!((stats.active_connections, stats.idle_connections), (0, 0));
+        assert_eq!(
+            stats.summary(&before),
+            "0 active, 0 idle, 0 targets, 1 discarded since last"
+        );
+
+        // 取出时遇到已过期的空闲连接同样计数，然后新建
+        let guard = pool
+            .get_connection("example.com", 443, connector.clone())
+            .await
+            .unwrap();
+        guard.finish(true).await;
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        let _guard = pool
+            .get_connection("example.com", 443, connector)
+            .await
+            .unwrap();
+        let stats = pool.stats().await;
+        assert_eq!((stats.created, stats.reused), (3, 1));
+        assert_eq!(stats.expired_discarded, 2);
+        assert_eq!(stats.stale_discarded, 0);
+
+        let json = serde_json::to_value(&stats).unwrap();
+        assert_eq!(json["created"], 3);
+        assert_eq!(json["expired_discarded"], 2);
+        assert_eq!(json["max_wait_ms"], 0);
+    }
+
+    /// 轮询统计直到满足条件
+    async fn wait_for_stats(pool: &ConnectionPool, done: impl Fn(&PoolStats) -> bool) -> PoolStats {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let stats = pool.stats().await;
+                if done(&stats) {
+                    return stats;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("pool stats never reached the expected state")
+    }
+
+    fn keep_warm(target: &str, min_idle: usize) -> Vec<KeepWarmTarget> {
+        vec![KeepWarmTarget {
+            target: target.parse().unwrap(),
+            min_idle,
+        }]
+    }
+
+    #[tokio::test]
+    async fn keep_warm_floor_is_topped_up_after_use_and_expiry() {
+        let connector = spawn_tcp_connector().await;
+        let pool = Arc::new(ConnectionPool::new(PoolConfig {
+            idle_timeout: Duration::from_millis(300),
+            cleanup_interval: Duration::from_millis(50),
+            keep_warm: keep_warm("api.example.com:443", 3),
+            ..PoolConfig::default()
+        }));
+        let task = Arc::clone(&pool)
+            .spawn_maintenance_task(Arc::new(connector.clone()), cleanup_task(&Arc::default()));
+        let warm = |stats: &PoolStats| stats.idle_by_target["api.example.com:443"];
+
+        let stats = wait_for_stats(&pool, |stats| warm(stats) == 3).await;
+        assert_eq!((stats.created, stats.warmed), (3, 3));
+        assert_eq!(stats.idle_use_counts, BTreeMap::from([(0, 3)]));
+
+        // 取走一个预热连接，不必等到下一次清理即补回
+        let guard = pool
+            .get_connection("api.example.com", 443, connector.clone())
+            .await
+            .unwrap();
+        let stats = pool.stats().await;
+        assert_eq!((stats.created, stats.reused), (3, 1));
+        guard.finish(false).await;
+        let stats = wait_for_stats(&pool, |stats| stats.warmed == 4).await;
+        assert_eq!(warm(&stats), 3);
+
+        // 空闲超时后由维护任务丢弃，随即补足
+        let stats = wait_for_stats(&pool, |stats| {
+            stats.expired_discarded >= 3 && warm(stats) == 3
+        })
+        .await;
+        assert!(stats.warmed >= 7);
+        assert_eq!(stats.warm_failures, 0);
+
+        // 不在 keep-warm 列表中的目标不补充
+        let other = pool
+            .get_connection("other.example.com", 443, connector)
+            .await
+            .unwrap();
+        other.finish(false).await;
+        assert!(!pool
+            .stats()
+            .await
+            .idle_by_target
+            .contains_key("other.example.com:443"));
+
+        pool.shutdown().await;
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn failing_keep_warm_target_backs_off() {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let connector: WarmConnector = {
+            let attempts = Arc::clone(&attempts);
+            Arc::new(move |_target, _port| {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                Box::pin(async { Err(crate::socks5::Socks5Error::ConnectionRefused.into()) })
+            })
+        };
+        let pool = Arc::new(ConnectionPool::new(PoolConfig {
+            cleanup_interval: Duration::from_millis(20),
+            keep_warm: keep_warm("api.example.com:443", 3),
+            ..PoolConfig::default()
+        }));
+        let task =
+            Arc::clone(&pool).spawn_maintenance_task(connector, cleanup_task(&Arc::default()));
+
+        // 首次失败后退避 1 秒，期间的清理周期都不再尝试
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(attempts.load(Ordering::Relaxed), 1);
+        let stats = pool.stats().await;
+        assert_eq!(stats.warm_failures, 1);
+        assert_eq!(stats.idle_by_target["api.example.com:443"], 0);
+        assert_eq!(stats.active_connections, 0);
+
+        let mut backoff = WarmBackoff::default();
+        let delays: Vec<_> = (0..11).map(|_| backoff.fail().as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 32, 64, 128, 256, 300, 300]);
+
+        pool.shutdown().await;
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn shutdown_stops_maintenance_task_and_closes_idle_connections() {
+        // 后端每收到一个连接的 EOF 报告一次
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (eof_tx, mut eof_rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let eof_tx = eof_tx.clone();
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 16];
+                    while stream.read(&mut buf).await.is_ok_and(|n| n > 0) {}
+                    let _ = eof_tx.send(());
+                });
+            }
+        });
+        let connector = move |_target: &str, _port: u16| {
+            Box::pin(async move {
+                Ok(BackendStream::Tcp(
+                    tokio::net::TcpStream::connect(addr).await.unwrap(),
+                ))
+            }) as ConnectFuture
+        };
+
+        let pool = Arc::new(test_pool(4));
+        let registry = Arc::new(Registry::default());
+        let cleanup =
+            Arc::clone(&pool).spawn_maintenance_task(Arc::new(connector), cleanup_task(&registry));
+        assert_eq!(registry.tasks(TaskKind::Cleanup), 1);
+        let idle = pool.get_connection("a.com", 443, connector).await.unwrap();
+        let held = pool.get_connection("b.com", 443, connector).await.unwrap();
+        idle.finish(true).await;
+        assert_eq!(pool.stats().await.idle_connections, 1);
+
+        assert_eq!(pool.shutdown().await, 1);
+        tokio::time::timeout(Duration::from_secs(1), cleanup)
+            .await
+            .expect("maintenance task did not stop")
+            .unwrap();
+        assert_eq!(registry.tasks(TaskKind::Cleanup), 0);
+        tokio::time::timeout(Duration::from_secs(1), eof_rx.recv())
+            .await
+            .expect("idle connection was not closed");
+
+        // 关闭后归还的连接不再入池
+        held.finish(true).await;
+        tokio::time::timeout(Duration::from_secs(1), eof_rx.recv())
+            .await
+            .expect("connection returned after shutdown was not closed");
+        let stats = pool.stats().await;
+        assert_eq!((stats.active_connections, stats.idle_connections), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn finish_returns_only_reusable_connections() {
+        let socks_addr = spawn_minimal_socks5_server(Duration::from_secs(5)).await;
+        let pool = test_pool(4);
+        let gate = Arc::new(Semaphore::new(Semaphore::MAX_PERMITS));
+        let connect = |pool: &ConnectionPool| {
+            let pool = pool.clone();
+            let connector = gated_connector(socks_addr, Arc::clone(&gate));
+            async move {
+                pool.get_connection("example.com", 80, connector)
+                    .await
+                    .unwrap()
+            }
+        };
+
+        let reusable = connect(&pool).await;
+        let closed = connect(&pool).await;
+        let abandoned = connect(&pool).await;
+        assert_eq!(pool.stats().await.active_connections, 3);
+
+        // 可复用：放回空闲池，仍占一个名额
+        reusable.finish(true).await;
+        let stats = pool.stats().await;
+        assert_eq!((stats.active_connections, stats.idle_connections), (3, 1));
+
+        // 不可复用和未 finish 的连接直接关闭并释放名额
+        closed.finish(false).await;
+        drop(abandoned);
+        let stats = pool.stats().await;
+        assert_eq!((stats.active_connections, stats.idle_connections), (1, 1));
+
+        // 下一个调用方拿到放回的连接，且仍可读写
+        let mut reused = connect(&pool).await;
+        assert_eq!(reused.connection.as_ref().unwrap().use_count, 2);
+        reused.write_all(b"again").await.unwrap();
+        let mut echoed = [0u8; 5];
+        reused.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"again");
+        let stats = pool.stats().await;
+        assert_eq!((stats.active_connections, stats.idle_connections), (1, 0));
+
+        reused.finish(false).await;
+        assert_eq!(pool.stats().await.active_connections, 0);
     }
 }
@@ -0,0 +1,304 @@
+//! SOCKS4/SOCKS4a 客户端，用于只支持旧协议的上游
+//!
+//! 只有 CONNECT，没有 UDP，QUIC 会话不能经 SOCKS4a 上游转发。域名目标使用
+//! SOCKS4a 扩展 (目标 IP 写 0.0.0.1，域名附在用户 ID 之后由代理解析)；IPv4
+//! 字面量按 SOCKS4 原格式发送。
+
+use crate::outbound::{self, BindError, DialError, OutboundBind, ProxyResolver};
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::debug;
+
+/// 协议版本号
+const VERSION: u8 = 0x04;
+/// CONNECT 命令
+const CMD_CONNECT: u8 = 0x01;
+/// 回复中的"请求被允许"
+const REPLY_GRANTED: u8 = 0x5a;
+
+/// SOCKS4a 建连失败
+#[derive(Error, Debug)]
+pub enum Socks4Error {
+    /// 请求被拒绝或失败 (回复码 0x5B)
+    #[error("SOCKS4 server: request rejected or failed")]
+    Rejected,
+
+    /// 代理无法连接客户端的 identd (回复码 0x5C)
+    #[error("SOCKS4 server: cannot reach identd on the client")]
+    IdentdUnreachable,
+
+    /// identd 报告的用户 ID 与请求不符 (回复码 0x5D)
+    #[error("SOCKS4 server: identd reported a different user ID")]
+    IdentdMismatch,
+
+    /// 目标无法用 SOCKS4 表示，或代理的响应不符合协议
+    #[error("SOCKS4 protocol error: {0}")]
+    Protocol(String),
+
+    /// 与代理之间的 I/O 错误 (含连接代理失败)
+    #[error("SOCKS4 I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// 出站 socket 绑定源地址或网卡失败
+    #[error("SOCKS4 {0}")]
+    Bind(#[from] BindError),
+
+    /// 建连或握手超时
+    #[error("SOCKS4 CONNECT timed out after {0:?}")]
+    Timeout(Duration),
+}
+
+impl From<DialError> for Socks4Error {
+    fn from(error: DialError) -> Self {
+        match error {
+            DialError::Bind(e) => Socks4Error::Bind(e),
+            DialError::Connect { source, .. } => Socks4Error::Io(source),
+        }
+    }
+}
+
+/// SOCKS4a 客户端
+#[derive(Debug, Clone)]
+pub struct Socks4aClient {
+    /// 代理地址及其解析缓存
+    proxy: Arc<ProxyResolver>,
+    /// 请求中的用户 ID (SOCKS4 没有密码)
+    user_id: String,
+    /// 到代理的连接的源地址/网卡
+    bind: OutboundBind,
+    /// 建连和握手超时
+    timeout: Duration,
+}
+
+impl Socks4aClient {
+    /// 创建 SOCKS4a 客户端
+    pub fn new<S: Into<String>>(proxy_addr: S) -> Self {
+        Self {
+            proxy: Arc::new(ProxyResolver::new(proxy_addr)),
+            user_id: String::new(),
+            bind: OutboundBind::default(),
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// 使用共享的代理地址解析缓存 (代替 `new` 的地址)
+    pub fn with_resolver(mut self, proxy: Arc<ProxyResolver>) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    /// 设置请求中的用户 ID
+    pub fn with_user_id(mut self, user_id: String) -> Self {
+        self.user_id = user_id;
+        self
+    }
+
+    /// 设置到代理的连接的源地址/网卡
+    pub fn with_bind(mut self, bind: OutboundBind) -> Self {
+        self.bind = bind;
+        self
+    }
+
+    /// 设置建连和握手超时
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// 经代理连接 `target:port`，返回握手完成后的原始连接
+    pub async fn connect(&self, target: &str, port: u16) -> Result<TcpStream, Socks4Error> {
+        debug!(
+            target = %format_args!("{}:{}", target, port),
+            proxy = %self.proxy.addr(),
+            "socks4a connect"
+        );
+        let request = encode_connect(target, port, &self.user_id)?;
+
+        let connect = async {
+            let mut stream = outbound::connect(&self.proxy, &self.bind).await?;
+            stream.write_all(&request).await?;
+            let mut reply = [0u8; 8];
+            stream.read_exact(&mut reply).await?;
+            parse_reply(&reply)?;
+            Ok::<_, Socks4Error>(stream)
+        };
+        tokio::time::timeout(self.timeout, connect)
+            .await
+            .map_err(|_| Socks4Error::Timeout(self.timeout))?
+    }
+}
+
+/// 编码 CONNECT 请求
+///
+/// `VN=4 CD=1 DSTPORT DSTIP USERID NUL [HOSTNAME NUL]`，域名目标的 DSTIP 为 0.0.0.1。
+fn encode_connect(target: &str, port: u16, user_id: &str) -> Result<Vec<u8>, Socks4Error> {
+    if user_id.as_bytes().contains(&0) || target.as_bytes().contains(&0) {
+        return Err(Socks4Error::Protocol(
+            "user ID and hostname must not contain NUL".to_string(),
+        ));
+    }
+    let mut request = Vec::with_capacity(10 + user_id.len() + target.len());
+    request.extend_from_slice(&[VERSION, CMD_CONNECT]);
+    request.extend_from_slice(&port.to_be_bytes());
+    match target.parse::<Ipv4Addr>() {
+        Ok(ip) => {
+            request.extend_from_slice(&ip.octets());
+            request.extend_from_slice(user_id.as_bytes());
+            request.push(0);
+        }
+        Err(_) if target.contains(':') => {
+            return Err(Socks4Error::Protocol(format!(
+                "SOCKS4 cannot address IPv6 target {}",
+                target
+            )));
+        }
+        Err(_) => {
+            request.extend_from_slice(&[0, 0, 0, 1]);
+            request.extend_from_slice(user_id.as_bytes());
+            request.push(0);
+            request.extend_from_slice(target.as_bytes());
+            request.push(0);
+        }
+    }
+    Ok(request)
+}
+
+/// 解析 8 字节的回复：`VN=0 CD DSTPORT DSTIP`
+fn parse_reply(reply: &[u8; 8]) -> Result<(), Socks4Error> {
+    if reply[0] != 0x00 {
+        return Err(Socks4Error::Protocol(format!(
+            "unexpected reply version {:#04x}",
+            reply[0]
+        )));
+    }
+    match reply[1] {
+        REPLY_GRANTED => Ok(()),
+        0x5b => Err(Socks4Error::Rejected),
+        0x5c => Err(Socks4Error::IdentdUnreachable),
+        0x5d => Err(Socks4Error::IdentdMismatch),
+        code => Err(Socks4Error::Protocol(format!(
+            "unknown reply code {:#04x}",
+            code
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_encode_socks4a_domain() {
+        // 按 SOCKS4a 规范编码的请求
+        assert_eq!(
+            encode_connect("example.com", 443, "alice").unwrap(),
+            b"\x04\x01\x01\xbb\x00\x00\x00\x01alice\x00example.com\x00"
+        );
+        assert_eq!(
+            encode_connect("example.com", 80, "").unwrap(),
+            b"\x04\x01\x00\x50\x00\x00\x00\x01\x00example.com\x00"
+        );
+    }
+
+    #[test]
+    fn test_encode_socks4_ipv4() {
+        assert_eq!(
+            encode_connect("93.184.216.34", 443, "").unwrap(),
+            b"\x04\x01\x01\xbb\x5d\xb8\xd8\x22\x00"
+        );
+        assert!(encode_connect("2001:db8::1", 443, "").is_err());
+        assert!(encode_connect("example.com", 443, "a\0b").is_err());
+    }
+
+    #[test]
+    fn test_parse_reply() {
+        assert!(parse_reply(b"\x00\x5a\x00\x00\x00\x00\x00\x00").is_ok());
+        assert!(matches!(
+            parse_reply(b"\x00\x5b\x00\x00\x00\x00\x00\x00"),
+            Err(Socks4Error::Rejected)
+        ));
+        assert!(matches!(
+            parse_reply(b"\x00\x5c\x00\x00\x00\x00\x00\x00"),
+            Err(Socks4Error::IdentdUnreachable)
+        ));
+        assert!(matches!(
+            parse_reply(b"\x00\x5d\x00\x00\x00\x00\x00\x00"),
+            Err(Socks4Error::IdentdMismatch)
+        ));
+        // SOCKS5 服务器对 SOCKS4 请求的回复
+        assert!(matches!(
+            parse_reply(b"\x05\xff\x00\x00\x00\x00\x00\x00"),
+            Err(Socks4Error::Protocol(_))
+        ));
+    }
+
+    /// 最小 SOCKS4a 服务器：记录请求，回复 `code`，允许时回显隧道数据
+    async fn spawn_mock_socks4a(
+        code: u8,
+    ) -> (
+        std::net::SocketAddr,
+        tokio::sync::oneshot::Receiver<Vec<u8>>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (request_tx, request_rx) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![0u8; 8];
+            stream.read_exact(&mut request).await.unwrap();
+            // USERID NUL，DSTIP 为 0.0.0.x 时再读 HOSTNAME NUL
+            let fields = if request[4..7] == [0, 0, 0] { 2 } else { 1 };
+            for _ in 0..fields {
+                loop {
+                    let byte = stream.read_u8().await.unwrap();
+                    request.push(byte);
+                    if byte == 0 {
+                        break;
+                    }
+                }
+            }
+            request_tx.send(request).unwrap();
+            stream
+                .write_all(&[0x00, code, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+            if code == REPLY_GRANTED {
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            }
+        });
+        (addr, request_rx)
+    }
+
+    #[tokio::test]
+    async fn connect_through_mock_socks4a_server() {
+        let (addr, request) = spawn_mock_socks4a(REPLY_GRANTED).await;
+        let mut stream = Socks4aClient::new(addr.to_string())
+            .with_user_id("alice".to_string())
+            .connect("example.com", 443)
+            .await
+            .unwrap();
+        assert_eq!(
+            request.await.unwrap(),
+            b"\x04\x01\x01\xbb\x00\x00\x00\x01alice\x00example.com\x00"
+        );
+
+        stream.write_all(b"ping").await.unwrap();
+        let mut echoed = [0u8; 4];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"ping");
+
+        let (addr, _request) = spawn_mock_socks4a(0x5b).await;
+        let error = Socks4aClient::new(addr.to_string())
+            .connect("example.com", 443)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(error, Socks4Error::Rejected), "{}", error);
+    }
+}
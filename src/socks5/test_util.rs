@@ -0,0 +1,309 @@
+//! 测试辅助：支持 UDP ASSOCIATE 或 CONNECT 的最小 SOCKS5 服务器
+
+use fast_socks5::util::target_addr::TargetAddr;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+
+/// 模拟的 SOCKS5 UDP ASSOCIATE 服务器
+pub struct MockUdpAssociate {
+    /// SOCKS5 TCP 控制地址
+    pub addr: SocketAddr,
+    /// 已完成的 UDP ASSOCIATE 次数
+    pub associations: Arc<AtomicUsize>,
+    /// 仍然打开的 UDP ASSOCIATE 控制连接数
+    pub open_controls: Arc<AtomicUsize>,
+    /// relay 收到的 (目标地址, payload)
+    pub received: mpsc::UnboundedReceiver<(TargetAddr, Vec<u8>)>,
+    /// relay 代数，递增后之前建立的 relay 全部关闭
+    kill: watch::Sender<u64>,
+    /// 最近一次收到数据报的 relay socket 及其客户端地址
+    last_peer: LastPeer,
+}
+
+/// 只保存弱引用，不延长已关闭 relay 的生命周期
+type LastPeer = Arc<Mutex<Option<(Weak<UdpSocket>, SocketAddr)>>>;
+
+impl MockUdpAssociate {
+    /// 关闭所有已建立的 relay (UDP socket 和 TCP 控制连接)，之后的 ASSOCIATE 不受影响
+    pub fn kill_relays(&self) {
+        self.kill.send_modify(|generation| *generation += 1);
+    }
+
+    /// 经最近一次收到数据报的 relay 向其客户端回包，SOCKS5 头部的来源地址为 `source`
+    pub async fn reply(&self, source: TargetAddr, payload: &[u8]) {
+        self.reply_fragment(source, 0, payload).await;
+    }
+
+    /// 同 [`Self::reply`]，但 SOCKS5 头部的 FRAG 字段为 `frag`
+    pub async fn reply_fragment(&self, source: TargetAddr, frag: u8, payload: &[u8]) {
+        let (relay, peer) = self
+            .last_peer
+            .lock()
+            .unwrap()
+            .clone()
+            .and_then(|(relay, peer)| Some((relay.upgrade()?, peer)))
+            .expect("no live relay has received a datagram yet");
+        // [rsv][rsv][frag][addr][payload]
+        let mut datagram = vec![0x00, 0x00, frag];
+        datagram.extend_from_slice(&source.to_be_bytes().unwrap());
+        datagram.extend_from_slice(payload);
+        relay.send_to(&datagram, peer).await.unwrap();
+    }
+}
+
+/// 启动模拟服务器；每次 ASSOCIATE 在回复前等待 `delay`
+pub async fn spawn_mock_udp_associate(delay: Duration) -> MockUdpAssociate {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let associations = Arc::new(AtomicUsize::new(0));
+    let open_controls = Arc::new(AtomicUsize::new(0));
+    let (received_tx, received) = mpsc::unbounded_channel();
+    let (kill, kill_rx) = watch::channel(0u64);
+    let last_peer = LastPeer::default();
+
+    let counter = Arc::clone(&associations);
+    let open = Arc::clone(&open_controls);
+    let peer = Arc::clone(&last_peer);
+    tokio::spawn(async move {
+        loop {
+            let Ok((stream, _)) = listener.accept().await else {
+                return;
+            };
+            let counter = Arc::clone(&counter);
+            let open = Arc::clone(&open);
+            let received_tx = received_tx.clone();
+            let peer = Arc::clone(&peer);
+            let mut kill_rx = kill_rx.clone();
+            let generation = *kill_rx.borrow_and_update();
+            tokio::spawn(async move {
+                tokio::select! {
+                    _ = serve_associate(stream, delay, counter, open, received_tx, peer) => {}
+                    _ = kill_rx.wait_for(|g| *g != generation) => {}
+                }
+            });
+        }
+    });
+
+    MockUdpAssociate {
+        addr,
+        associations,
+        open_controls,
+        received,
+        kill,
+        last_peer,
+    }
+}
+
+async fn serve_associate(
+    mut stream: TcpStream,
+    delay: Duration,
+    counter: Arc<AtomicUsize>,
+    open: Arc<AtomicUsize>,
+    received_tx: mpsc::UnboundedSender<(TargetAddr, Vec<u8>)>,
+    last_peer: LastPeer,
+) -> std::io::Result<()> {
+    let mut greeting = [0u8; 2];
+    stream.read_exact(&mut greeting).await?;
+    let mut methods = vec![0u8; greeting[1] as usize];
+    stream.read_exact(&mut methods).await?;
+    stream.write_all(&[0x05, 0x00]).await?;
+
+    // [ver][cmd][rsv][atyp][addr...][port]
+    let mut request = [0u8; 4];
+    stream.read_exact(&mut request).await?;
+    skip_address(&mut stream, request[3]).await?;
+
+    tokio::time::sleep(delay).await;
+
+    let relay = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let relay_port = relay.local_addr()?.port().to_be_bytes();
+    counter.fetch_add(1, Ordering::SeqCst);
+    let _open = OpenControl::new(open);
+    stream
+        .write_all(&[
+            0x05,
+            0x00,
+            0x00,
+            0x01,
+            127,
+            0,
+            0,
+            1,
+            relay_port[0],
+            relay_port[1],
+        ])
+        .await?;
+
+    let mut buf = [0u8; 65536];
+    let mut control = [0u8; 1];
+    loop {
+        tokio::select! {
+            res = relay.recv_from(&mut buf) => {
+                let (n, peer) = res?;
+                *last_peer.lock().unwrap() = Some((Arc::downgrade(&relay), peer));
+                if let Some((target, payload)) = parse_udp_datagram(&buf[..n]) {
+                    let _ = received_tx.send((target, payload.to_vec()));
+                }
+            }
+            res = stream.read(&mut control) => {
+                if res? == 0 {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// 模拟的 SOCKS5 CONNECT 服务器，建立的隧道把收到的数据原样发回
+pub struct MockConnect {
+    /// SOCKS5 地址
+    pub addr: SocketAddr,
+    /// 每次 CONNECT 请求的目标
+    pub targets: mpsc::UnboundedReceiver<TargetAddr>,
+    accept: JoinHandle<()>,
+}
+
+impl MockConnect {
+    /// 停止接受连接并关闭监听 socket (已建立的隧道不受影响)
+    pub async fn stop(self) {
+        self.accept.abort();
+        self.accept.await.ok();
+    }
+}
+
+/// 启动模拟的 CONNECT 服务器
+pub async fn spawn_mock_connect() -> MockConnect {
+    spawn_mock_connect_delayed(Duration::ZERO).await
+}
+
+/// 启动模拟的 CONNECT 服务器，每个 CONNECT 请求等待 `delay` 后才回复
+pub async fn spawn_mock_connect_delayed(delay: Duration) -> MockConnect {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (targets_tx, targets) = mpsc::unbounded_channel();
+    let accept = tokio::spawn(async move {
+        loop {
+            let Ok((stream, _)) = listener.accept().await else {
+                return;
+            };
+            let targets_tx = targets_tx.clone();
+            tokio::spawn(async move {
+                let _ = serve_connect(stream, targets_tx, delay).await;
+            });
+        }
+    });
+    MockConnect {
+        addr,
+        targets,
+        accept,
+    }
+}
+
+async fn serve_connect(
+    mut stream: TcpStream,
+    targets_tx: mpsc::UnboundedSender<TargetAddr>,
+    delay: Duration,
+) -> std::io::Result<()> {
+    let mut greeting = [0u8; 2];
+    stream.read_exact(&mut greeting).await?;
+    let mut methods = vec![0u8; greeting[1] as usize];
+    stream.read_exact(&mut methods).await?;
+    stream.write_all(&[0x05, 0x00]).await?;
+
+    // [ver][cmd][rsv][atyp][addr...][port]
+    let mut request = vec![0u8; 4];
+    stream.read_exact(&mut request).await?;
+    let len = match request[3] {
+        0x01 => 4,
+        0x04 => 16,
+        _ => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).await?;
+            request.push(len[0]);
+            len[0] as usize
+        }
+    };
+    let mut rest = vec![0u8; len + 2];
+    stream.read_exact(&mut rest).await?;
+    request.extend_from_slice(&rest);
+    // 与 UDP 请求头的地址部分格式相同
+    if let Some((target, _)) = parse_udp_datagram(&request) {
+        let _ = targets_tx.send(target);
+    }
+    tokio::time::sleep(delay).await;
+    stream
+        .write_all(&[0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1, 0, 0])
+        .await?;
+
+    let (mut reader, mut writer) = stream.split();
+    tokio::io::copy(&mut reader, &mut writer).await?;
+    Ok(())
+}
+
+/// 控制连接存活期间计入 `open_controls`
+struct OpenControl(Arc<AtomicUsize>);
+
+impl OpenControl {
+    fn new(open: Arc<AtomicUsize>) -> Self {
+        open.fetch_add(1, Ordering::SeqCst);
+        Self(open)
+    }
+}
+
+impl Drop for OpenControl {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+async fn skip_address(stream: &mut TcpStream, atyp: u8) -> std::io::Result<()> {
+    let len = match atyp {
+        0x01 => 4,
+        0x04 => 16,
+        _ => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).await?;
+            len[0] as usize
+        }
+    };
+    let mut rest = vec![0u8; len + 2];
+    stream.read_exact(&mut rest).await?;
+    Ok(())
+}
+
+/// 解析 SOCKS5 UDP 请求头
+fn parse_udp_datagram(data: &[u8]) -> Option<(TargetAddr, &[u8])> {
+    enum Host {
+        Ip(std::net::IpAddr),
+        Domain(String),
+    }
+
+    let (host, rest) = match *data.get(3)? {
+        0x01 => {
+            let octets: [u8; 4] = data.get(4..8)?.try_into().ok()?;
+            (Host::Ip(octets.into()), data.get(8..)?)
+        }
+        0x04 => {
+            let octets: [u8; 16] = data.get(4..20)?.try_into().ok()?;
+            (Host::Ip(octets.into()), data.get(20..)?)
+        }
+        0x03 => {
+            let len = *data.get(4)? as usize;
+            let name = std::str::from_utf8(data.get(5..5 + len)?).ok()?;
+            (Host::Domain(name.to_string()), data.get(5 + len..)?)
+        }
+        _ => return None,
+    };
+    let port = u16::from_be_bytes([*rest.first()?, *rest.get(1)?]);
+    let target = match host {
+        Host::Ip(ip) => TargetAddr::Ip(SocketAddr::new(ip, port)),
+        Host::Domain(name) => TargetAddr::Domain(name, port),
+    };
+    Some((target, rest.get(2..)?))
+}
@@ -1,94 +1,327 @@
-use anyhow::{anyhow, Result};
+use crate::config::Socks5Config;
+use crate::outbound::{self, OutboundBind, ProxyResolver};
+use crate::socks5::client::{RetryPolicy, Socks5Error};
+use crate::socks5::metrics::{MetricsSink, Socks5Operation};
 use fast_socks5::client::Socks5Datagram;
-use std::net::{SocketAddr, ToSocketAddrs};
-use std::time::Duration;
+use fast_socks5::util::target_addr::TargetAddr;
+use socket2::{SockRef, TcpKeepalive};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncReadExt;
 use tokio::net::TcpStream;
 use tracing::debug;
 
 /// SOCKS5 UDP ASSOCIATE 客户端 (使用 fast-socks5)
+#[derive(Clone)]
 pub struct Socks5UdpClient {
-    proxy_addr: String,
+    /// 代理地址及其解析缓存
+    proxy: Arc<ProxyResolver>,
     /// 可选的认证信息
     auth: Option<(String, String)>,
+    /// 控制连接和 relay socket 的源地址/网卡
+    bind: OutboundBind,
     /// UDP ASSOCIATE 建连和握手超时
     timeout: Duration,
+    /// 控制连接的 TCP keepalive 间隔；None 表示不开启
+    keepalive: Option<Duration>,
+    /// 握手耗时的统计后端及本客户端的标签
+    metrics: Option<(Arc<dyn MetricsSink>, String)>,
+    /// 关联失败后的重试策略
+    retry: RetryPolicy,
 }
 
 impl Socks5UdpClient {
     /// 创建新的 SOCKS5 UDP 客户端
     pub fn new<S: Into<String>>(proxy_addr: S) -> Self {
         Self {
-            proxy_addr: proxy_addr.into(),
+            proxy: Arc::new(ProxyResolver::new(proxy_addr)),
             auth: None,
+            bind: OutboundBind::default(),
             timeout: Duration::from_secs(30),
+            keepalive: None,
+            metrics: None,
+            retry: RetryPolicy::none(),
         }
     }
 
+    /// 由上游配置创建：地址、认证、超时、出站绑定、重试策略和控制连接的 keepalive
+    pub fn from_config(config: &Socks5Config) -> Self {
+        let client = Self::new(config.addr.to_string())
+            .with_bind(OutboundBind::from_config(config))
+            .with_timeout(Duration::from_secs(config.timeout))
+            .with_retry_policy(RetryPolicy::from_config(config))
+            .with_keepalive(
+                (config.udp_control_keepalive > 0)
+                    .then(|| Duration::from_secs(config.udp_control_keepalive)),
+            );
+        match (&config.username, &config.password) {
+            (Some(username), Some(password)) => {
+                client.with_auth(username.clone(), password.clone())
+            }
+            _ => client,
+        }
+    }
+
+    /// 使用共享的代理地址解析缓存 (代替 `new` 的地址)
+    pub fn with_resolver(mut self, proxy: Arc<ProxyResolver>) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
     /// 设置认证信息
     pub fn with_auth(mut self, username: String, password: String) -> Self {
         self.auth = Some((username, password));
         self
     }
 
+    /// 设置控制连接和 relay socket 的源地址/网卡
+    pub fn with_bind(mut self, bind: OutboundBind) -> Self {
+        self.bind = bind;
+        self
+    }
+
+    /// 只设置控制连接和 relay socket 的源地址，保留已有的网卡设置
+    pub fn with_bind_addr(mut self, addr: IpAddr) -> Self {
+        self.bind.addr = Some(addr);
+        self
+    }
+
+    /// 设置关联失败后的重试策略
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// 设置控制连接的 TCP keepalive 间隔 (None 表示不开启)
+    ///
+    /// 关联在控制连接的生命周期内有效 (RFC 1928)。空闲会话的控制连接可能被中间的
+    /// NAT/防火墙悄然回收，keepalive 既维持其状态，也让断开的连接被及时发现。
+    pub fn with_keepalive(mut self, interval: Option<Duration>) -> Self {
+        self.keepalive = interval;
+        self
+    }
+
     /// 设置 UDP ASSOCIATE 建连和握手超时
     pub fn with_timeout(mut self, timeout: Duration) -> Self {
         self.timeout = timeout;
         self
     }
 
+    /// 向 `sink` 报告每次 UDP ASSOCIATE 的耗时和结果，`label` 为上游名称
+    pub fn with_metrics(mut self, sink: Arc<dyn MetricsSink>, label: impl Into<String>) -> Self {
+        self.metrics = Some((sink, label.into()));
+        self
+    }
+
     /// 建立 UDP ASSOCIATE 会话
     ///
     /// # 返回
     /// 返回 (Socks5Datagram, 中继服务器地址)
-    pub async fn associate(&self) -> Result<(Socks5Datagram<TcpStream>, SocketAddr)> {
-        debug!("SOCKS5 UDP ASSOCIATE via proxy {}", self.proxy_addr);
+    pub async fn associate(&self) -> Result<(Socks5Datagram<TcpStream>, SocketAddr), Socks5Error> {
+        let (datagram, relay_addr, _control) = self.associate_monitored().await?;
+        Ok((datagram, relay_addr))
+    }
 
-        // 1. 先建立 TCP 连接到 SOCKS5 代理
-        let tcp_stream = tokio::time::timeout(self.timeout, TcpStream::connect(&self.proxy_addr))
+    /// 建立 UDP ASSOCIATE 会话，并额外返回 TCP 控制连接的一个副本
+    ///
+    /// fast-socks5 不暴露控制连接；副本与其共享同一个 socket，
+    /// 配合 [`wait_control_closed`] 可以发现 SOCKS5 服务器关闭了关联。
+    pub async fn associate_monitored(
+        &self,
+    ) -> Result<(Socks5Datagram<TcpStream>, SocketAddr, TcpStream), Socks5Error> {
+        self.retry
+            .run(Socks5Operation::UdpAssociate, || self.associate_once())
             .await
-            .map_err(|_| anyhow!("SOCKS5 UDP TCP connect timed out after {:?}", self.timeout))?
-            .map_err(|e| anyhow!("Failed to connect to SOCKS5 proxy: {}", e))?;
+    }
+
+    async fn associate_once(
+        &self,
+    ) -> Result<(Socks5Datagram<TcpStream>, SocketAddr, TcpStream), Socks5Error> {
+        let start = Instant::now();
+        let result = self.associate_inner().await;
+        if let Some((sink, label)) = &self.metrics {
+            let class = result
+                .as_ref()
+                .map_or_else(Socks5Error::metric_label, |_| "ok");
+            sink.record(label, Socks5Operation::UdpAssociate, class, start.elapsed());
+        }
+        result
+    }
+
+    async fn associate_inner(
+        &self,
+    ) -> Result<(Socks5Datagram<TcpStream>, SocketAddr, TcpStream), Socks5Error> {
+        debug!(proxy = %self.proxy.addr(), "socks5 udp associate");
 
-        // 2. 使用 fast-socks5 建立 UDP ASSOCIATE
+        // 1. 先建立 TCP 连接到 SOCKS5 代理
+        let tcp_stream =
+            tokio::time::timeout(self.timeout, outbound::connect(&self.proxy, &self.bind))
+                .await
+                .map_err(|_| Socks5Error::Timeout {
+                    stage: "UDP ASSOCIATE connect",
+                    after: self.timeout,
+                })??;
+
+        // 握手完成前不读取副本，避免抢走 fast-socks5 的响应数据
+        let std_stream = tcp_stream.into_std()?;
+        if let Some(interval) = self.keepalive {
+            SockRef::from(&std_stream).set_tcp_keepalive(&keepalive_params(interval))?;
+        }
+        let control = std_stream.try_clone()?;
+        let tcp_stream = TcpStream::from_std(std_stream)?;
+
+        // 2. 使用 fast-socks5 建立 UDP ASSOCIATE，relay socket 绑定到配置的源地址
+        let local_addr = self.bind.udp_local_addr();
         let associate = async {
             if let Some((username, password)) = &self.auth {
                 // 带认证
-                Socks5Datagram::bind_with_password(tcp_stream, "0.0.0.0:0", username, password)
-                    .await
-                    .map_err(|e| anyhow!("SOCKS5 UDP ASSOCIATE failed: {}", e))
+                Socks5Datagram::bind_with_password(tcp_stream, local_addr, username, password).await
             } else {
                 // 无认证
-                Socks5Datagram::bind(tcp_stream, "0.0.0.0:0")
-                    .await
-                    .map_err(|e| anyhow!("SOCKS5 UDP ASSOCIATE failed: {}", e))
+                Socks5Datagram::bind(tcp_stream, local_addr).await
             }
         };
 
         let socks5_datagram = tokio::time::timeout(self.timeout, associate)
             .await
-            .map_err(|_| anyhow!("SOCKS5 UDP ASSOCIATE timed out after {:?}", self.timeout))??;
+            .map_err(|_| Socks5Error::Timeout {
+                stage: "UDP ASSOCIATE",
+                after: self.timeout,
+            })??;
+
+        // 握手期间 relay socket 还没有发送数据，此时绑定网卡仍然生效
+        self.bind
+            .apply_device(SockRef::from(socks5_datagram.get_ref()))?;
 
         // 获取中继服务器地址
         let proxy_addr = socks5_datagram
             .proxy_addr()
-            .map_err(|e| anyhow!("Failed to get relay address: {}", e))?;
+            .map_err(|e| Socks5Error::Protocol(format!("invalid relay address: {}", e)))?;
 
         let relay_addr = proxy_addr
             .to_socket_addrs()?
             .next()
-            .ok_or_else(|| anyhow!("No relay address"))?;
+            .ok_or_else(|| Socks5Error::Protocol("no relay address".to_string()))?;
 
         debug!(
-            "SOCKS5 UDP ASSOCIATE established via {}, relay: {}",
-            self.proxy_addr, relay_addr
+            proxy = %self.proxy.addr(),
+            relay = %relay_addr,
+            "socks5 udp associate established"
         );
 
-        Ok((socks5_datagram, relay_addr))
+        Ok((socks5_datagram, relay_addr, TcpStream::from_std(control)?))
+    }
+}
+
+/// 空闲 `interval` 后开始探测，之后每 `interval` 探测一次
+fn keepalive_params(interval: Duration) -> TcpKeepalive {
+    let keepalive = TcpKeepalive::new().with_time(interval);
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    let keepalive = keepalive.with_interval(interval);
+    keepalive
+}
+
+/// 等待 UDP ASSOCIATE 的 TCP 控制连接被关闭 (EOF 或出错)
+///
+/// 关联建立后服务器不应再在控制连接上发送数据，收到的任何数据都被忽略。
+pub async fn wait_control_closed(control: &mut TcpStream) {
+    let mut buf = [0u8; 64];
+    while let Ok(n) = control.read(&mut buf).await {
+        if n == 0 {
+            return;
+        }
+    }
+}
+
+/// SOCKS5 UDP 请求头的最大长度: RSV(2) + FRAG(1) + ATYP(1) + 域名(1 + 255) + PORT(2)
+pub const SOCKS5_UDP_HEADER_MAX: usize = 3 + 1 + 1 + 255 + 2;
+
+/// 分片重组队列的超时 (RFC 1928 要求不小于 5 秒)
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 每个重组器同时缓存的分片队列数上限 (每个来源地址一个队列)
+const MAX_REASSEMBLY_QUEUES: usize = 64;
+
+/// 重组后的数据报长度上限
+const MAX_REASSEMBLED_LEN: usize = 65535;
+
+/// SOCKS5 UDP 分片 (FRAG != 0) 的重组器
+///
+/// 按 RFC 1928：FRAG 的低 7 位是分片序号 (从 1 开始)，最高位标记序列的最后一片。
+/// 每个来源地址一个队列；序号 1 开始新的序列，其余分片必须紧接上一片，否则丢弃
+/// 整个队列。超过 [`REASSEMBLY_TIMEOUT`] 仍未收齐的队列被丢弃。
+#[derive(Debug, Default)]
+pub struct UdpReassembler {
+    queues: HashMap<TargetAddr, FragmentQueue>,
+}
+
+#[derive(Debug)]
+struct FragmentQueue {
+    /// 下一片的序号
+    next: u8,
+    data: Vec<u8>,
+    started: Instant,
+}
+
+/// 一个分片的处理结果
+#[derive(Debug, PartialEq, Eq)]
+pub enum Reassembly {
+    /// 收到最后一片，序列完整
+    Complete(Vec<u8>),
+    /// 已缓存，等待后续分片
+    Buffered,
+    /// 分片不连续、队列过期或超出长度/队列数上限，已丢弃
+    Dropped,
+}
+
+impl UdpReassembler {
+    /// 处理来自 `remote` 的一个分片 (`frag` 不为 0)
+    pub fn push(
+        &mut self,
+        frag: u8,
+        remote: &TargetAddr,
+        payload: &[u8],
+        now: Instant,
+    ) -> Reassembly {
+        self.queues
+            .retain(|_, queue| now.duration_since(queue.started) < REASSEMBLY_TIMEOUT);
+
+        let position = frag & 0x7f;
+        let last = frag & 0x80 != 0;
+        if position == 1 {
+            if !self.queues.contains_key(remote) && self.queues.len() >= MAX_REASSEMBLY_QUEUES {
+                return Reassembly::Dropped;
+            }
+            self.queues.insert(
+                remote.clone(),
+                FragmentQueue {
+                    next: 1,
+                    data: Vec::new(),
+                    started: now,
+                },
+            );
+        }
+        let Some(queue) = self.queues.get_mut(remote) else {
+            return Reassembly::Dropped;
+        };
+        if position != queue.next || queue.data.len() + payload.len() > MAX_REASSEMBLED_LEN {
+            self.queues.remove(remote);
+            return Reassembly::Dropped;
+        }
+        queue.data.extend_from_slice(payload);
+        queue.next = queue.next.saturating_add(1);
+        if last {
+            let queue = self.queues.remove(remote).expect("queue exists");
+            Reassembly::Complete(queue.data)
+        } else {
+            Reassembly::Buffered
+        }
     }
 }
 
 /// 导出 fast-socks5 的 UDP 类型
-#[allow(dead_code)]
 pub type Socks5UdpDatagram = Socks5Datagram<TcpStream>;
 
 #[cfg(test)]
@@ -100,7 +333,7 @@ mod tests {
     #[test]
     fn test_udp_client_creation() {
         let client = Socks5UdpClient::new("127.0.0.1:1080");
-        assert_eq!(client.proxy_addr, "127.0.0.1:1080");
+        assert_eq!(client.proxy.addr(), "127.0.0.1:1080");
         assert!(client.auth.is_none());
     }
 
@@ -115,6 +348,63 @@ mod tests {
         assert_eq!(password, "pass");
     }
 
+    #[test]
+    fn from_config_applies_every_field() {
+        let config: Socks5Config = toml::from_str(
+            r#"
+addr = "127.0.0.1:1080"
+username = "user"
+password = "pass"
+timeout = 7
+connect_retries = 1
+bind_device = "eth1"
+udp_control_keepalive = 15
+"#,
+        )
+        .unwrap();
+        let client =
+            Socks5UdpClient::from_config(&config).with_bind_addr("192.0.2.10".parse().unwrap());
+        assert_eq!(client.proxy.addr(), "127.0.0.1:1080");
+        assert_eq!(client.auth, Some(("user".to_string(), "pass".to_string())));
+        assert_eq!(client.timeout, Duration::from_secs(7));
+        assert_eq!(client.retry.retries, 1);
+        assert_eq!(client.keepalive, Some(Duration::from_secs(15)));
+        assert_eq!(client.bind.addr, Some("192.0.2.10".parse().unwrap()));
+        assert_eq!(client.bind.device.as_deref(), Some("eth1"));
+    }
+
+    #[test]
+    fn fragments_are_reassembled_in_order() {
+        let mut reassembler = UdpReassembler::default();
+        let a = TargetAddr::Ip("192.0.2.1:443".parse().unwrap());
+        let b = TargetAddr::Ip("192.0.2.2:443".parse().unwrap());
+        let now = Instant::now();
+
+        // 两个来源的序列交错到达互不影响
+        assert_eq!(reassembler.push(1, &a, b"he", now), Reassembly::Buffered);
+        assert_eq!(reassembler.push(1, &b, b"wo", now), Reassembly::Buffered);
+        assert_eq!(reassembler.push(2, &a, b"ll", now), Reassembly::Buffered);
+        assert_eq!(
+            reassembler.push(0x83, &a, b"o", now),
+            Reassembly::Complete(b"hello".to_vec())
+        );
+        assert_eq!(
+            reassembler.push(0x82, &b, b"rld", now),
+            Reassembly::Complete(b"world".to_vec())
+        );
+
+        // 缺片时丢弃整个队列，之后的分片也不再拼接
+        assert_eq!(reassembler.push(1, &a, b"x", now), Reassembly::Buffered);
+        assert_eq!(reassembler.push(3, &a, b"z", now), Reassembly::Dropped);
+        assert_eq!(reassembler.push(0x84, &a, b"!", now), Reassembly::Dropped);
+
+        // 超时的队列被丢弃
+        assert_eq!(reassembler.push(1, &a, b"x", now), Reassembly::Buffered);
+        let later = now + REASSEMBLY_TIMEOUT;
+        assert_eq!(reassembler.push(0x82, &a, b"y", later), Reassembly::Dropped);
+        assert!(reassembler.queues.is_empty());
+    }
+
     #[tokio::test]
     async fn associate_times_out_when_proxy_accepts_but_never_responds() {
         let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
@@ -132,4 +422,37 @@ mod tests {
         assert!(result.is_err());
         assert!(started.elapsed() < Duration::from_secs(1));
     }
+
+    #[tokio::test]
+    async fn control_connection_close_is_detected() {
+        let mut socks5 = crate::socks5::test_util::spawn_mock_udp_associate(Duration::ZERO).await;
+        let client = Socks5UdpClient::new(socks5.addr.to_string())
+            .with_keepalive(Some(Duration::from_secs(7)));
+        let (datagram, _, mut control) = client.associate_monitored().await.unwrap();
+
+        // 副本与控制连接是同一个 socket，keepalive 已开启
+        let socket = SockRef::from(&control);
+        assert!(socket.keepalive().unwrap());
+        #[cfg(target_os = "linux")]
+        assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(7));
+
+        // 副本不影响关联本身
+        datagram.send_to(b"ping", ("192.0.2.1", 443)).await.unwrap();
+        assert_eq!(socks5.received.recv().await.unwrap().1, b"ping");
+
+        let closed = tokio::time::timeout(
+            Duration::from_millis(200),
+            wait_control_closed(&mut control),
+        )
+        .await;
+        assert!(
+            closed.is_err(),
+            "control connection reported closed too early"
+        );
+
+        socks5.kill_relays();
+        tokio::time::timeout(Duration::from_secs(1), wait_control_closed(&mut control))
+            .await
+            .unwrap();
+    }
 }
@@ -0,0 +1,261 @@
+//! 周期性统计日志
+//!
+//! 不部署 Prometheus 时，每 `server.stats_log_interval` 在 `stats` target 下输出一行
+//! info 事件，汇总上一个窗口的新连接数、活动连接与 QUIC 会话、转发字节数、
+//! 按原因分类的拒绝和失败 (见 [`crate::reason`])、SOCKS5 失败，以及连接速率和吞吐量，
+//! 便于直接用 `journalctl` 排查。
+//!
+//! 数据来自与 `/metrics` 相同的 [`Registry`]：注册表自身的计数之外，QUIC 会话和
+//! SOCKS5 握手结果由各模块以 [`Registry::register_stats_source`] 提供，见 [`Totals`]。
+
+use crate::metrics::{Registry, TaskKind};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, MissedTickBehavior};
+use tracing::info;
+
+/// 某一时刻的累计计数；各来源的部分快照用 [`Totals::merge`] 相加
+///
+/// `active_connections` 和 `quic_sessions` 是当前值，其余是进程启动以来的累计值。
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Totals {
+    /// 接受的连接 (QUIC 为新建立的会话)
+    pub connections: u64,
+    /// TCP 与 HTTP 当前打开的连接
+    pub active_connections: u64,
+    /// 当前转发中的 QUIC 会话
+    pub quic_sessions: u64,
+    /// 客户端到上游的字节数
+    pub bytes_in: u64,
+    /// 上游到客户端的字节数
+    pub bytes_out: u64,
+    /// 按 [`crate::reason::RejectReason`] 分类的拒绝
+    pub rejections: BTreeMap<&'static str, u64>,
+    /// 按 [`crate::reason::FailureReason`] 分类的失败
+    pub failures: BTreeMap<&'static str, u64>,
+    /// 按 [`crate::socks5::Socks5Error::metric_label`] 分类的 SOCKS5 握手失败
+    pub socks5_errors: BTreeMap<&'static str, u64>,
+}
+
+impl Totals {
+    /// 累加另一个来源的计数
+    pub fn merge(&mut self, other: Totals) {
+        self.connections += other.connections;
+        self.active_connections += other.active_connections;
+        self.quic_sessions += other.quic_sessions;
+        self.bytes_in += other.bytes_in;
+        self.bytes_out += other.bytes_out;
+        for (reason, count) in other.rejections {
+            *self.rejections.entry(reason).or_default() += count;
+        }
+        for (reason, count) in other.failures {
+            *self.failures.entry(reason).or_default() += count;
+        }
+        for (label, count) in other.socks5_errors {
+            *self.socks5_errors.entry(label).or_default() += count;
+        }
+    }
+
+    /// 相对上一个快照的窗口统计
+    fn window(&self, previous: &Totals, elapsed: Duration) -> Window {
+        let delta = |now: &BTreeMap<&'static str, u64>, before: &BTreeMap<&'static str, u64>| {
+            now.iter()
+                .map(|(key, count)| {
+                    let before = before.get(key).copied().unwrap_or(0);
+                    (*key, count.saturating_sub(before))
+                })
+                .filter(|(_, count)| *count > 0)
+                .collect()
+        };
+        Window {
+            elapsed,
+            new_connections: self.connections.saturating_sub(previous.connections),
+            active_connections: self.active_connections,
+            quic_sessions: self.quic_sessions,
+            bytes_in: self.bytes_in.saturating_sub(previous.bytes_in),
+            bytes_out: self.bytes_out.saturating_sub(previous.bytes_out),
+            rejections: delta(&self.rejections, &previous.rejections),
+            failures: delta(&self.failures, &previous.failures),
+            socks5_errors: delta(&self.socks5_errors, &previous.socks5_errors),
+        }
+    }
+}
+
+/// 一个窗口内的变化量
+#[derive(Debug)]
+struct Window {
+    elapsed: Duration,
+    new_connections: u64,
+    active_connections: u64,
+    quic_sessions: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+    rejections: BTreeMap<&'static str, u64>,
+    failures: BTreeMap<&'static str, u64>,
+    socks5_errors: BTreeMap<&'static str, u64>,
+}
+
+impl Window {
+    /// 每秒的速率，保留两位小数
+    fn rate(&self, count: u64, unit: f64) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs == 0.0 {
+            return 0.0;
+        }
+        (count as f64 / unit / secs * 100.0).round() / 100.0
+    }
+
+    fn log(&self) {
+        const MB: f64 = 1_000_000.0;
+        info!(
+            target: "stats",
+            window_secs = self.elapsed.as_secs(),
+            new_connections = self.new_connections,
+            conn_per_sec = self.rate(self.new_connections, 1.0),
+            active_connections = self.active_connections,
+            quic_sessions = self.quic_sessions,
+            bytes_in = self.bytes_in,
+            bytes_out = self.bytes_out,
+            mb_in_per_sec = self.rate(self.bytes_in, MB),
+            mb_out_per_sec = self.rate(self.bytes_out, MB),
+            rejected = self.rejections.values().sum::<u64>(),
+            rejections = %format_counts(&self.rejections),
+            failed = self.failures.values().sum::<u64>(),
+            failures = %format_counts(&self.failures),
+            socks5_errors = self.socks5_errors.values().sum::<u64>(),
+            socks5_error_kinds = %format_counts(&self.socks5_errors),
+            "stats"
+        );
+    }
+}
+
+/// `reason=count` 以空格分隔，没有时为 `-`
+fn format_counts(counts: &BTreeMap<&'static str, u64>) -> String {
+    if counts.is_empty() {
+        return "-".to_string();
+    }
+    let mut out = String::new();
+    for (key, count) in counts {
+        if !out.is_empty() {
+            out.push(' ');
+        }
+        let _ = write!(out, "{}={}", key, count);
+    }
+    out
+}
+
+/// 启动统计日志任务，每 `interval` 输出一行，`shutdown` 变为 true 时结束，返回任务句柄
+pub fn spawn(
+    registry: Arc<Registry>,
+    interval: Duration,
+    mut shutdown: watch::Receiver<bool>,
+) -> JoinHandle<()> {
+    let task = registry.track_task(TaskKind::Background);
+    tokio::spawn(async move {
+        let _task = task;
+        let mut previous = registry.totals().await;
+        let mut since = Instant::now();
+        let mut ticker = tokio::time::interval_at(since + interval, interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {}
+                _ = shutdown.wait_for(|stopping| *stopping) => break,
+            }
+            let totals = registry.totals().await;
+            let now = Instant::now();
+            totals.window(&previous, now - since).log();
+            previous = totals;
+            since = now;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logging::capture::capture;
+    use crate::metrics::{Direction, Listener};
+    use crate::reason::{FailureReason, Outcome, RejectReason};
+
+    #[tokio::test(start_paused = true)]
+    async fn logs_one_line_per_window() {
+        let (events, _guard) = capture();
+        let registry = Arc::new(Registry::default());
+        let _open = registry.connection(Listener::Tcp);
+        registry.register_stats_source(|| async {
+            Totals {
+                quic_sessions: 2,
+                socks5_errors: BTreeMap::from([("timeout", 1)]),
+                ..Default::default()
+            }
+        });
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let task = spawn(Arc::clone(&registry), Duration::from_secs(60), shutdown_rx);
+        tokio::task::yield_now().await;
+
+        // 第一个窗口：3 个新连接、6 MB 下行、1 次白名单拒绝、1 次空闲超时
+        for _ in 0..3 {
+            registry.record_accepted(Listener::Http);
+        }
+        registry.record_bytes(Listener::Tcp, Direction::UpstreamToClient, 6_000_000);
+        registry.record_rejection(Listener::Quic, RejectReason::NotWhitelisted);
+        registry.record_outcome(Listener::Tcp, Outcome::Failed(FailureReason::IdleTimeout));
+        tokio::time::sleep(Duration::from_secs(61)).await;
+
+        // 第二个窗口：1 个新连接
+        registry.record_accepted(Listener::Quic);
+        tokio::time::sleep(Duration::from_secs(60)).await;
+
+        shutdown_tx.send(true).unwrap();
+        task.await.unwrap();
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 2, "{:#?}", events);
+        let first = &events[0];
+        assert_eq!(first.message, "stats");
+        assert_eq!(first.field("window_secs"), Some("60"));
+        assert_eq!(first.field("new_connections"), Some("3"));
+        assert_eq!(first.field("conn_per_sec"), Some("0.05"));
+        assert_eq!(first.field("active_connections"), Some("1"));
+        assert_eq!(first.field("quic_sessions"), Some("2"));
+        assert_eq!(first.field("bytes_out"), Some("6000000"));
+        assert_eq!(first.field("mb_out_per_sec"), Some("0.1"));
+        assert_eq!(first.field("rejections"), Some("not_whitelisted=1"));
+        assert_eq!(first.field("failed"), Some("1"));
+        assert_eq!(first.field("failures"), Some("idle_timeout=1"));
+        // 来源报告的是累计值，启动时已有的失败不计入窗口
+        assert_eq!(first.field("socks5_errors"), Some("0"));
+        assert_eq!(first.field("socks5_error_kinds"), Some("-"));
+
+        let second = &events[1];
+        assert_eq!(second.field("new_connections"), Some("1"));
+        assert_eq!(second.field("bytes_out"), Some("0"));
+        assert_eq!(second.field("rejected"), Some("0"));
+        assert_eq!(second.field("rejections"), Some("-"));
+        assert_eq!(second.field("failures"), Some("-"));
+    }
+
+    #[test]
+    fn window_counts_only_increases() {
+        let previous = Totals {
+            connections: 10,
+            socks5_errors: BTreeMap::from([("timeout", 2)]),
+            ..Default::default()
+        };
+        let mut now = previous.clone();
+        now.merge(Totals {
+            connections: 4,
+            socks5_errors: BTreeMap::from([("timeout", 1), ("io", 3)]),
+            ..Default::default()
+        });
+        let window = now.window(&previous, Duration::from_secs(2));
+        assert_eq!(window.new_connections, 4);
+        assert_eq!(window.rate(window.new_connections, 1.0), 2.0);
+        assert_eq!(format_counts(&window.socks5_errors), "io=3 timeout=1");
+    }
+}
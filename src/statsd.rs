@@ -0,0 +1,290 @@
+//! statsd/DogStatsD 指标推送
+//!
+//! 设置 `metrics.statsd_addr` 后，[`spawn`] 每 `metrics.statsd_flush_interval` 秒把注册表的计数
+//! ([`Registry::samples`]) 以 UDP 推送到该地址：计数器推送与上次推送的差 (`|c`)，gauge 推送当前值
+//! (`|g`)，连接阶段的耗时逐次推送 (`|ms`，由 [`StatsdSink`] 作为 [`Sink`] 接收)。
+//! 标签使用 DogStatsD 的 `|#key:value` 扩展：各指标自身的 `listener`、`reason` 等，加上 `backend`
+//! 和 `metrics.statsd_tags`。多行合并到不超过 [`MAX_PACKET_LEN`] 字节的数据报中，避免 IP 分片。
+//!
+//! 与 `/metrics` 相互独立，可以同时使用；collector 输出的指标 (连接池、QUIC 会话等) 只在
+//! `/metrics` 中提供。
+
+use crate::config::{BackendKind, Config};
+use crate::metrics::{Listener, MetricKind, Phase, Registry, Sample, Sink, TaskKind};
+use std::collections::HashMap;
+use std::fmt::{Display, Write as _};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, MissedTickBehavior};
+use tracing::{debug, info};
+
+/// 单个数据报的最大字节数：以太网 MTU 1500 减去 IP/UDP 头，再留出隧道封装的余量
+pub const MAX_PACKET_LEN: usize = 1432;
+
+/// 两次推送之间最多缓存的耗时观测，超出的丢弃
+const MAX_PENDING_TIMINGS: usize = 10_000;
+
+/// statsd 行的编码与耗时观测的缓存
+#[derive(Debug)]
+pub struct StatsdSink {
+    prefix: String,
+    /// 附加到每行的公共标签，例如 `backend:socks5`、`env:prod`
+    common_tags: Vec<String>,
+    /// 等待下次推送的耗时行
+    timings: Mutex<Vec<String>>,
+}
+
+impl StatsdSink {
+    /// 使用 `config.metrics` 的前缀和标签，另加 `backend` 标签
+    pub fn from_config(config: &Config) -> Self {
+        let backend = match config.backend.kind {
+            BackendKind::Socks5 => "socks5",
+            BackendKind::HttpConnect => "http_connect",
+        };
+        let mut common_tags = vec![format!("backend:{}", backend)];
+        common_tags.extend(config.metrics.statsd_tags.iter().cloned());
+        StatsdSink {
+            prefix: config.metrics.statsd_prefix.clone(),
+            common_tags,
+            timings: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// 编码一行，例如 `sniproxy.rejections:3|c|#listener:tcp,reason:no_sni,backend:socks5`
+    fn line(&self, name: &str, value: impl Display, kind: &str, tags: &[(&str, &str)]) -> String {
+        let mut line = String::new();
+        if !self.prefix.is_empty() {
+            let _ = write!(line, "{}.", self.prefix);
+        }
+        let _ = write!(line, "{}:{}|{}", name, value, kind);
+        let tags = tags
+            .iter()
+            .map(|(key, value)| format!("{}:{}", key, value))
+            .chain(self.common_tags.iter().cloned())
+            .collect::<Vec<_>>();
+        if !tags.is_empty() {
+            let _ = write!(line, "|#{}", tags.join(","));
+        }
+        line
+    }
+
+    /// 一次推送的全部行：计数器相对 `previous` 的增量 (没有变化的不推送)、gauge 的当前值，
+    /// 以及上次推送以来缓存的耗时
+    fn flush_lines(&self, samples: &[Sample], previous: &mut HashMap<String, u64>) -> Vec<String> {
+        let mut lines = Vec::new();
+        for sample in samples {
+            match sample.kind {
+                MetricKind::Counter => {
+                    let key = self.line(sample.name, "", "c", &sample.tags);
+                    let last = previous.insert(key, sample.value).unwrap_or(0);
+                    let delta = sample.value.saturating_sub(last);
+                    if delta > 0 {
+                        lines.push(self.line(sample.name, delta, "c", &sample.tags));
+                    }
+                }
+                MetricKind::Gauge | MetricKind::Histogram => {
+                    lines.push(self.line(sample.name, sample.value, "g", &sample.tags));
+                }
+            }
+        }
+        lines.append(&mut self.timings.lock().unwrap_or_else(|e| e.into_inner()));
+        lines
+    }
+}
+
+impl Sink for StatsdSink {
+    fn timing(&self, listener: Listener, phase: Phase, elapsed: Duration) {
+        let line = self.line(
+            "connection_phase",
+            elapsed.as_micros() as f64 / 1000.0,
+            "ms",
+            &[("listener", listener.label()), ("phase", phase.label())],
+        );
+        let mut timings = self.timings.lock().unwrap_or_else(|e| e.into_inner());
+        if timings.len() < MAX_PENDING_TIMINGS {
+            timings.push(line);
+        }
+    }
+}
+
+/// 把行按顺序合并成不超过 `max_len` 字节的数据报 (行间以换行分隔)，单行超过上限时单独成包
+fn batch(lines: &[String], max_len: usize) -> Vec<String> {
+    let mut packets = Vec::new();
+    let mut packet = String::new();
+    for line in lines {
+        if !packet.is_empty() && packet.len() + 1 + line.len() > max_len {
+            packets.push(std::mem::take(&mut packet));
+        }
+        if !packet.is_empty() {
+            packet.push('\n');
+        }
+        packet.push_str(line);
+    }
+    if !packet.is_empty() {
+        packets.push(packet);
+    }
+    packets
+}
+
+/// 启动推送任务：每 `metrics.statsd_flush_interval` 秒推送一次到 `addr`，`shutdown` 变为 true 时
+/// 推送最后一次后结束
+pub fn spawn(
+    registry: Arc<Registry>,
+    addr: SocketAddr,
+    config: &Config,
+    mut shutdown: watch::Receiver<bool>,
+) -> io::Result<JoinHandle<()>> {
+    let local: SocketAddr = if addr.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let socket = std::net::UdpSocket::bind(local)?;
+    socket.connect(addr)?;
+    socket.set_nonblocking(true)?;
+    let socket = UdpSocket::from_std(socket)?;
+
+    let sink = Arc::new(StatsdSink::from_config(config));
+    registry.add_sink(sink.clone());
+    let interval = Duration::from_secs(config.metrics.statsd_flush_interval.max(1));
+    info!(addr = %addr, interval = ?interval, "statsd sink started");
+
+    let task = registry.track_task(TaskKind::Background);
+    Ok(tokio::spawn(async move {
+        let _task = task;
+        let mut previous = HashMap::new();
+        let mut ticker = tokio::time::interval_at(Instant::now() + interval, interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            let stopping = tokio::select! {
+                _ = ticker.tick() => false,
+                _ = shutdown.wait_for(|stopping| *stopping) => true,
+            };
+            let lines = sink.flush_lines(&registry.samples(), &mut previous);
+            for packet in batch(&lines, MAX_PACKET_LEN) {
+                // 本机的 agent 未启动时会收到 ICMP 端口不可达，只影响这一次推送
+                if let Err(e) = socket.send(packet.as_bytes()).await {
+                    debug!(addr = %addr, error = %e, "statsd send failed");
+                    break;
+                }
+            }
+            if stopping {
+                break;
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Direction;
+    use crate::reason::RejectReason;
+
+    fn config(extra: &str) -> Config {
+        toml::from_str(&format!(
+            "[server]\n[socks5]\naddr = \"127.0.0.1:1080\"\n[metrics]\n{}",
+            extra
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn counters_are_sent_as_deltas() {
+        let sink = StatsdSink::from_config(&config("statsd_tags = [\"env:test\"]"));
+        let registry = Registry::default();
+        let mut previous = HashMap::new();
+        registry.record_accepted(Listener::Tcp);
+        registry.record_accepted(Listener::Tcp);
+
+        let lines = sink.flush_lines(&registry.samples(), &mut previous);
+        assert!(lines.contains(
+            &"sniproxy.connections.accepted:2|c|#listener:tcp,backend:socks5,env:test".to_string()
+        ));
+        assert!(lines.contains(
+            &"sniproxy.connections.active:0|g|#listener:http,backend:socks5,env:test".to_string()
+        ));
+        // 没有变化的计数器不推送
+        assert!(!lines.iter().any(|l| l.contains("rejections")));
+
+        registry.record_accepted(Listener::Tcp);
+        let lines = sink.flush_lines(&registry.samples(), &mut previous);
+        assert!(lines.contains(
+            &"sniproxy.connections.accepted:1|c|#listener:tcp,backend:socks5,env:test".to_string()
+        ));
+        let lines = sink.flush_lines(&registry.samples(), &mut previous);
+        assert!(!lines.iter().any(|l| l.contains("connections.accepted")));
+    }
+
+    #[test]
+    fn batches_stay_under_the_packet_limit() {
+        let lines: Vec<String> = (0..100).map(|i| format!("m{}:{}|c", i, i)).collect();
+        let packets = batch(&lines, 64);
+        assert!(packets.len() > 1);
+        assert!(packets.iter().all(|p| p.len() <= 64));
+        let joined: Vec<&str> = packets.iter().flat_map(|p| p.split('\n')).collect();
+        assert_eq!(joined, lines.iter().map(String::as_str).collect::<Vec<_>>());
+
+        // 超长的行单独成包
+        let long = "x".repeat(100);
+        assert_eq!(
+            batch(&["a:1|c".to_string(), long.clone()], 64),
+            vec!["a:1|c".to_string(), long]
+        );
+        assert!(batch(&[], 64).is_empty());
+    }
+
+    #[tokio::test]
+    async fn pushes_metrics_to_a_statsd_server() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let config = config("statsd_prefix = \"edge\"\nstatsd_flush_interval = 1");
+        let registry = Arc::new(Registry::default());
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let task = spawn(
+            Arc::clone(&registry),
+            server.local_addr().unwrap(),
+            &config,
+            shutdown_rx,
+        )
+        .unwrap();
+
+        // 模拟一个被拒绝的连接和一个转发完成的连接
+        registry.record_accepted(Listener::Tcp);
+        registry.record_rejection(Listener::Tcp, RejectReason::NoSni);
+        drop(registry.connection(Listener::Quic));
+        registry.record_phase(Listener::Quic, Phase::Connect, Duration::from_millis(12));
+        registry.record_bytes(Listener::Quic, Direction::UpstreamToClient, 4096);
+        shutdown_tx.send(true).unwrap();
+        task.await.unwrap();
+
+        let mut lines = Vec::new();
+        let mut buf = [0u8; 2048];
+        while let Ok(Ok(n)) =
+            tokio::time::timeout(Duration::from_millis(200), server.recv(&mut buf)).await
+        {
+            assert!(n <= MAX_PACKET_LEN);
+            let packet = std::str::from_utf8(&buf[..n]).unwrap();
+            lines.extend(packet.lines().map(str::to_string));
+        }
+        for expected in [
+            "edge.connections.accepted:1|c|#listener:tcp,backend:socks5",
+            "edge.rejections:1|c|#listener:tcp,reason:no_sni,backend:socks5",
+            "edge.connections.accepted:1|c|#listener:quic,backend:socks5",
+            "edge.bytes_forwarded:4096|c|#listener:quic,direction:upstream_to_client,backend:socks5",
+            "edge.connection_phase:12|ms|#listener:quic,phase:sni_to_connected,backend:socks5",
+            "edge.tasks:1|g|#kind:background,backend:socks5",
+        ] {
+            assert!(
+                lines.iter().any(|l| l == expected),
+                "missing {:?} in {:#?}",
+                expected,
+                lines
+            );
+        }
+    }
+}
@@ -0,0 +1,226 @@
+//! 监听器监督
+//!
+//! [`supervise`] 运行一个监听器，出错退出时按 [`RestartPolicy`] 退避后重启；连续失败超过
+//! `max_restarts` 次即放弃并返回错误，由 [`ServerHandle::wait`](crate::ServerHandle::wait)
+//! 交给调用方 (命令行以非零状态退出，交由 systemd/k8s 重启进程)。关闭信号优先于重启：
+//! 退避期间收到关闭信号时不再重启。
+
+use crate::metrics::Listener;
+use crate::{Error, Result};
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
+use tracing::{error, info, warn};
+
+/// 监听器的重启策略
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RestartPolicy {
+    /// 连续失败后最多重启的次数，0 表示不重启
+    pub max_restarts: u32,
+    /// 第一次重启前的等待时间，之后逐次翻倍
+    pub initial_backoff: Duration,
+    /// 重启等待时间的上限
+    pub max_backoff: Duration,
+    /// 运行超过这个时间后才失败的不算连续失败，重启次数和等待时间从头计算
+    pub stable_after: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            max_restarts: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            stable_after: Duration::from_secs(60),
+        }
+    }
+}
+
+/// 运行 `listener` 并在出错时重启，`run` 每次调用启动一次监听器
+///
+/// 监听器正常返回 (收到关闭信号) 或退避期间 `shutdown` 变为 true 时返回重启的次数；
+/// 连续失败超过 `policy.max_restarts` 次时返回最后一次的错误。
+pub async fn supervise<F, Fut>(
+    listener: Listener,
+    policy: RestartPolicy,
+    mut shutdown: watch::Receiver<bool>,
+    mut run: F,
+) -> Result<u32>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let name = listener.label();
+    let mut restarts = 0;
+    let mut failures = 0;
+    let mut backoff = policy.initial_backoff;
+    loop {
+        let started = Instant::now();
+        let error = match run().await {
+            Ok(()) => return Ok(restarts),
+            Err(e) => e,
+        };
+        if *shutdown.borrow() {
+            warn!(listener = name, error = %error, "listener failed during shutdown");
+            return Ok(restarts);
+        }
+        if started.elapsed() >= policy.stable_after {
+            failures = 0;
+            backoff = policy.initial_backoff;
+        }
+        if failures >= policy.max_restarts {
+            error!(listener = name, restarts, error = %error, "listener failed permanently");
+            return Err(Error::ListenerFailed {
+                listener,
+                restarts,
+                source: Box::new(error),
+            });
+        }
+        failures += 1;
+        warn!(
+            listener = name,
+            attempt = failures,
+            max_restarts = policy.max_restarts,
+            backoff = ?backoff,
+            error = %error,
+            "listener failed, restarting"
+        );
+        tokio::select! {
+            _ = tokio::time::sleep(backoff) => {}
+            _ = shutdown.wait_for(|stop| *stop) => {
+                info!(listener = name, "shutdown requested, not restarting listener");
+                return Ok(restarts);
+            }
+        }
+        backoff = backoff.saturating_mul(2).min(policy.max_backoff);
+        restarts += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    fn policy(max_restarts: u32) -> RestartPolicy {
+        RestartPolicy {
+            max_restarts,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(150),
+            stable_after: Duration::from_secs(60),
+        }
+    }
+
+    /// 前 `failures` 次调用失败，之后成功，返回调用计数
+    fn flaky(
+        failures: u32,
+    ) -> (
+        Arc<AtomicU32>,
+        impl FnMut() -> std::future::Ready<Result<()>>,
+    ) {
+        let calls = Arc::new(AtomicU32::new(0));
+        let counter = Arc::clone(&calls);
+        let run = move || {
+            let call = counter.fetch_add(1, Ordering::SeqCst);
+            std::future::ready(if call < failures {
+                Err(io::Error::other(format!("socket error {}", call)).into())
+            } else {
+                Ok(())
+            })
+        };
+        (calls, run)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn restarts_until_the_listener_succeeds() {
+        let (_shutdown_tx, shutdown) = watch::channel(false);
+        let (calls, run) = flaky(2);
+        let started = Instant::now();
+        let restarts = supervise(Listener::Quic, policy(3), shutdown, run)
+            .await
+            .unwrap();
+        assert_eq!(restarts, 2);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        // 100ms，然后翻倍并受上限限制为 150ms
+        assert_eq!(started.elapsed(), Duration::from_millis(250));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_after_max_restarts() {
+        let (_shutdown_tx, shutdown) = watch::channel(false);
+        let (calls, run) = flaky(u32::MAX);
+        let error = supervise(Listener::Tcp, policy(3), shutdown, run)
+            .await
+            .unwrap_err();
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+        assert!(matches!(
+            &error,
+            Error::ListenerFailed {
+                listener: Listener::Tcp,
+                restarts: 3,
+                source,
+            } if matches!(**source, Error::Io(_))
+        ));
+        assert_eq!(
+            error.to_string(),
+            "tcp listener failed after 3 restarts: I/O error: socket error 3"
+        );
+
+        let (_shutdown_tx, shutdown) = watch::channel(false);
+        let (calls, run) = flaky(u32::MAX);
+        assert!(supervise(Listener::Tcp, policy(0), shutdown, run)
+            .await
+            .is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_wins_over_restarts() {
+        let (shutdown_tx, shutdown) = watch::channel(false);
+        let (calls, run) = flaky(u32::MAX);
+        let task = tokio::spawn(supervise(Listener::Http, policy(10), shutdown, run));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        shutdown_tx.send(true).unwrap();
+        assert_eq!(task.await.unwrap().unwrap(), 0);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // 关闭过程中的失败不再重启
+        let (_shutdown_tx, shutdown) = watch::channel(true);
+        let (calls, run) = flaky(u32::MAX);
+        assert_eq!(
+            supervise(Listener::Http, policy(10), shutdown, run)
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn long_runs_reset_the_failure_count() {
+        let (_shutdown_tx, shutdown) = watch::channel(false);
+        let calls = Arc::new(AtomicU32::new(0));
+        let counter = Arc::clone(&calls);
+        // 每次运行 2 分钟后失败，第 5 次成功：每次都是稳定运行后的首次失败
+        let run = move || {
+            let call = counter.fetch_add(1, Ordering::SeqCst);
+            async move {
+                tokio::time::sleep(Duration::from_secs(120)).await;
+                if call < 4 {
+                    Err(io::Error::other("socket error").into())
+                } else {
+                    Ok(())
+                }
+            }
+        };
+        assert_eq!(
+            supervise(Listener::Tcp, policy(1), shutdown, run)
+                .await
+                .unwrap(),
+            4
+        );
+    }
+}
@@ -0,0 +1,117 @@
+//! 连接处理任务的派生
+//!
+//! 直接 `tokio::spawn` 的任务 panic 时只有该任务结束，丢弃的 `JoinHandle` 不会报告任何东西：
+//! 连接无声无息地消失。[`spawn_logged`] 派生处理任务的同时派生一个等待它的任务，
+//! panic 时在连接的 span 内输出一条错误日志 (带 panic 信息)，并计入
+//! `sniproxy_handler_panics_total{listener}`。活动连接数、并发名额等由守卫维护的状态在
+//! 展开时照常释放。
+
+use crate::metrics::{Listener, Registry};
+use std::any::Any;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+use tracing::{error, Instrument, Span};
+
+/// 在 `span` 内派生 `future`，panic 时记录日志与 `listener` 的 panic 计数
+///
+/// 返回等待处理任务的任务，处理任务结束 (包括 panic) 后完成；调用方通常直接丢弃。
+pub fn spawn_logged<F>(
+    listener: Listener,
+    registry: &Arc<Registry>,
+    span: Span,
+    future: F,
+) -> JoinHandle<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let handler = tokio::spawn(future.instrument(span.clone()));
+    let registry = Arc::clone(registry);
+    tokio::spawn(async move {
+        let Err(e) = handler.await else {
+            return;
+        };
+        // 运行时关闭时任务被取消，不是处理任务的问题
+        if let Ok(payload) = e.try_into_panic() {
+            registry.record_handler_panic(listener);
+            error!(
+                parent: &span,
+                listener = listener.label(),
+                panic = panic_message(payload.as_ref()),
+                "connection handler panicked"
+            );
+        }
+    })
+}
+
+/// panic 的信息：`panic!` 的参数为字符串时取其内容
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&'static str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "<non-string panic payload>"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logging::capture::capture;
+    use crate::logging::connection_span;
+    use tracing::Level;
+
+    #[tokio::test]
+    async fn panicking_handlers_are_logged_and_counted() {
+        let (events, _guard) = capture();
+        let registry = Arc::new(Registry::default());
+        let client = "198.51.100.7:50000".parse().unwrap();
+
+        let connection = registry.connection(Listener::Tcp);
+        spawn_logged(
+            Listener::Tcp,
+            &registry,
+            connection_span(Listener::Tcp, 42, client),
+            async move {
+                let _connection = connection;
+                panic!("pooled connection already taken");
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(registry.handler_panics(Listener::Tcp), 1);
+        assert_eq!(registry.handler_panics(Listener::Http), 0);
+        // 展开时守卫照常释放
+        assert_eq!(registry.active_connections(Listener::Tcp), 0);
+        let event = events
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|event| event.message == "connection handler panicked")
+            .cloned()
+            .unwrap();
+        assert_eq!(event.level, Level::ERROR);
+        assert_eq!(event.fields["conn_id"], "42");
+        assert_eq!(event.fields["client"], "198.51.100.7:50000");
+        assert_eq!(event.fields["panic"], "pooled connection already taken");
+
+        // 之后的连接照常处理
+        let (done_tx, done_rx) = tokio::sync::oneshot::channel();
+        spawn_logged(Listener::Tcp, &registry, Span::none(), async move {
+            done_tx.send(()).unwrap();
+        })
+        .await
+        .unwrap();
+        done_rx.await.unwrap();
+        assert_eq!(registry.handler_panics(Listener::Tcp), 1);
+    }
+
+    #[test]
+    fn panic_messages_from_payloads() {
+        assert_eq!(panic_message(&"static"), "static");
+        assert_eq!(panic_message(&format!("port {}", 443)), "port 443");
+        assert_eq!(panic_message(&42u32), "<non-string panic payload>");
+    }
+}
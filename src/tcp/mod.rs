@@ -1,85 +1,198 @@
-use crate::config::Config;
-use crate::relay::{copy_with_idle_timeout, log_accept_error};
-use crate::router::Router;
-use crate::socks5::{ConnectionPool, PoolConfig, Socks5Client};
-use crate::tls::sni::extract_sni;
-use anyhow::{anyhow, Result};
-use std::sync::Arc;
-use std::time::Duration;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use crate::access::AccessEntry;
+use crate::backend::{Backend, ConnectError};
+use crate::config::{Config, ConfigError, TlsConfig};
+use crate::logging::{self, connection_span};
+use crate::metrics::{Direction, Exposition, Listener, MetricKind, Phase, Registry, TaskKind};
+use crate::reason::{FailureReason, Outcome, RejectReason};
+use crate::relay::{
+    copy_with_idle_timeout, forwarding_outcome, log_accept_error, ConnectionBytes, FirstRead,
+};
+use crate::router::{HelloRoute, Router};
+use crate::server::bind_tcp;
+use crate::socks5::metrics::Socks5Operation;
+use crate::socks5::{ConnectionPool, PoolConfig, PoolError, Socks5ErrorCounters, WarmConnector};
+use crate::task;
+use crate::tls::sni::{
+    parse_client_hello, read_client_hello, version_name, ClientHelloStatus, SniError,
+};
+use crate::tls::version::version_anomalies;
+use anyhow::{anyhow, Context, Result};
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Semaphore;
+use tokio::sync::{watch, Semaphore};
 use tracing::{debug, info, trace, warn};
 
-#[derive(Clone)]
+/// 监听器内各连接共享的转发参数
 struct Socks5Runtime {
-    addr: String,
-    username: Option<String>,
-    password: Option<String>,
+    backend: Backend,
     timeout: Duration,
     transfer_idle_timeout: Duration,
+    /// SOCKS5 CONNECT 失败数，按分类计数
+    errors: Arc<Socks5ErrorCounters>,
+    /// 无法解析的 ClientHello 数
+    hello_errors: Arc<HelloErrorCounters>,
+    /// ClientHello 版本检查
+    tls: TlsConfig,
+    metrics: Arc<Registry>,
+}
+
+/// 无法解析的 ClientHello 数，扫描器噪声 (SSLv2、非 TLS 数据) 与格式错误的 TLS 分开计数
+#[derive(Debug, Default)]
+struct HelloErrorCounters {
+    scanner_noise: AtomicU64,
+    malformed: AtomicU64,
+}
+
+impl HelloErrorCounters {
+    /// 记录一次失败，返回该分类的累计次数
+    fn record(&self, error: &SniError) -> u64 {
+        let counter = if error.is_scanner_noise() {
+            &self.scanner_noise
+        } else {
+            &self.malformed
+        };
+        counter.fetch_add(1, Ordering::Relaxed) + 1
+    }
 }
 
 /// 运行 TCP 代理服务器 (HTTP/1.1 + TLS)
-pub async fn run(config: Config) -> Result<()> {
+///
+/// 每个连接按 `[[rules.routes]]` 或 SNI 的哈希选择 SOCKS5 上游，健康状态与 QUIC 共享；
+/// `[backend] type = "http_connect"` 时经 HTTP 正向代理建立隧道。
+/// 收到 `shutdown` 信号后停止接受新连接，关闭连接池 (停止清理任务、关闭空闲连接) 后返回；
+/// 已建立的连接继续转发直到结束。连接、SNI 提取和转发字节数记入 `metrics`，
+/// 连接池状态在抓取时读取。
+pub async fn run(
+    config: Arc<Config>,
+    backend: Backend,
+    shutdown: watch::Receiver<bool>,
+    metrics: Arc<Registry>,
+) -> crate::Result<()> {
     let listen_addr = config
         .server
         .listen_https_addr
-        .ok_or_else(|| anyhow!("HTTPS listen address not configured"))?;
+        .ok_or(ConfigError::MissingListenAddr {
+            field: "server.listen_https_addr",
+        })?;
 
-    info!("Starting TCP proxy server on {}", listen_addr);
+    info!(listener = "tcp", addr = %listen_addr, "starting tcp proxy server");
 
-    let listener = TcpListener::bind(&listen_addr).await?;
-    info!("TCP proxy server listening on {}", listen_addr);
+    let listener = bind_tcp("HTTPS", listen_addr).await?;
+    serve(listener, config, backend, shutdown, metrics).await
+}
+
+/// 在已绑定的 `listener` 上运行 TCP 代理服务器，其余同 [`run`]
+pub async fn serve(
+    listener: TcpListener,
+    config: Arc<Config>,
+    backend: Backend,
+    mut shutdown: watch::Receiver<bool>,
+    metrics: Arc<Registry>,
+) -> crate::Result<()> {
+    info!(listener = "tcp", addr = %listener.local_addr()?, "tcp proxy server listening");
+    let _listening = metrics.listening(Listener::Tcp);
+    let _task = metrics.track_task(TaskKind::Listener);
 
     // 创建路由器
-    let router = Arc::new(Router::new(config.clone()));
+    let router = Arc::new(Router::new(Arc::clone(&config)));
 
     // 创建连接池
     let pool_config = PoolConfig {
         max_connections: config.socks5.max_connections,
+        max_uses_per_connection: config.socks5.max_uses_per_connection,
+        acquire_timeout: Duration::from_secs(config.socks5.pool_acquire_timeout),
+        keep_warm: config.pool.keep_warm.clone(),
         ..Default::default()
     };
     let pool = Arc::new(ConnectionPool::new(pool_config));
-    debug!("SOCKS5 connection pool created");
-
-    // 启动连接池清理任务
-    pool.clone().spawn_cleanup_task();
-    debug!("TCP connection pool cleanup task started");
+    debug!(listener = "tcp", "socks5 connection pool created");
+    register_pool_metrics(&metrics, &pool);
+
+    // 启动连接池维护任务：keep-warm 连接与客户端流量一样按路由规则或哈希选择上游
+    let warm_connector: WarmConnector = {
+        let router = router.clone();
+        let backend = backend.clone();
+        Arc::new(move |host: &str, port| {
+            let dialer = backend.pick(router.route(host), host.as_bytes());
+            let host = host.to_string();
+            Box::pin(async move {
+                let stream = dialer.connect_tcp(&host, port).await;
+                match stream {
+                    Ok(_) => dialer.record_success(),
+                    Err(_) => dialer.record_failure(),
+                }
+                stream
+            })
+        })
+    };
+    let cleanup = pool
+        .clone()
+        .spawn_maintenance_task(warm_connector, metrics.track_task(TaskKind::Cleanup));
+    debug!(listener = "tcp", "connection pool maintenance task started");
 
     let accept_limit = Arc::new(Semaphore::new(config.server.max_client_connections.max(1)));
+    let runtime = Arc::new(Socks5Runtime {
+        backend,
+        timeout: Duration::from_secs(config.socks5.timeout),
+        transfer_idle_timeout: Duration::from_secs(config.server.transfer_idle_timeout.max(1)),
+        errors: Arc::new(Socks5ErrorCounters::default()),
+        hello_errors: Arc::new(HelloErrorCounters::default()),
+        tls: config.tls.clone(),
+        metrics: Arc::clone(&metrics),
+    });
 
     loop {
-        let client_permit = accept_limit
-            .clone()
-            .acquire_owned()
-            .await
-            .map_err(|e| anyhow!("TCP accept limiter closed: {}", e))?;
+        let accepted = tokio::select! {
+            accepted = async {
+                let client_permit = accept_limit
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .map_err(|e| anyhow!("TCP accept limiter closed: {}", e))?;
+                Ok::<_, anyhow::Error>((client_permit, listener.accept().await))
+            } => accepted?,
+            _ = shutdown.wait_for(|stop| *stop) => {
+                info!(listener = "tcp", "shutting down listener");
+                break;
+            }
+        };
+        let (client_permit, accepted) = accepted;
 
-        match listener.accept().await {
+        match accepted {
             Ok((client_stream, client_addr)) => {
-                trace!("Accepted TCP connection from {}", client_addr);
+                let accepted_at = Instant::now();
+                let conn_id = logging::next_conn_id();
+                let span = connection_span(Listener::Tcp, conn_id, client_addr);
+                trace!(parent: &span, "connection accepted");
 
                 // 克隆以供任务使用
                 let router_clone = router.clone();
                 let pool_clone = pool.clone();
-                let socks5 = Socks5Runtime {
-                    addr: config.socks5.addr.to_string(),
-                    username: config.socks5.username.clone(),
-                    password: config.socks5.password.clone(),
-                    timeout: Duration::from_secs(config.socks5.timeout),
-                    transfer_idle_timeout: Duration::from_secs(
-                        config.server.transfer_idle_timeout.max(1),
-                    ),
-                };
-                tokio::spawn(async move {
+                let connection = metrics.connection(Listener::Tcp);
+                let socks5 = Arc::clone(&runtime);
+                let registry = Arc::clone(&metrics);
+                task::spawn_logged(Listener::Tcp, &metrics, span, async move {
                     let _client_permit = client_permit;
-                    if let Err(e) =
-                        handle_client(client_stream, client_addr, router_clone, pool_clone, socks5)
-                            .await
+                    let _connection = connection;
+                    let mut access = AccessEntry::new(Listener::Tcp, conn_id, client_addr, 443);
+                    if let Err(e) = handle_client(
+                        client_stream,
+                        accepted_at,
+                        router_clone,
+                        pool_clone,
+                        socks5,
+                        &mut access,
+                    )
+                    .await
                     {
-                        warn!("TCP client {} failed: {}", client_addr, e);
+                        access.outcome = Outcome::Failed(FailureReason::of(&e));
+                        warn!(outcome = access.outcome.as_label(), error = %format_args!("{:#}", e), "connection failed");
                     }
+                    access.finish(&registry);
                 });
             }
             Err(e) => {
@@ -88,48 +201,182 @@ pub async fn run(config: Config) -> Result<()> {
             }
         }
     }
+
+    pool.shutdown().await;
+    cleanup.await.ok();
+    Ok(())
+}
+
+/// 抓取时输出连接池状态，管理端口的 `/pool` 返回完整的统计
+fn register_pool_metrics(metrics: &Registry, pool: &Arc<ConnectionPool>) {
+    let stats_pool = Arc::clone(pool);
+    metrics.register_pool_stats(move || {
+        let pool = Arc::clone(&stats_pool);
+        async move { serde_json::to_value(pool.stats().await).unwrap_or_default() }
+    });
+    let pool = Arc::clone(pool);
+    metrics.register_collector(move || {
+        let pool = Arc::clone(&pool);
+        async move {
+            let stats = pool.stats().await;
+            let mut out = Exposition::default();
+            let families: [(&str, MetricKind, &str, u64); 7] = [
+                (
+                    "sniproxy_pool_connections",
+                    MetricKind::Gauge,
+                    "Pooled upstream connections, in use and idle",
+                    stats.active_connections as u64,
+                ),
+                (
+                    "sniproxy_pool_idle_connections",
+                    MetricKind::Gauge,
+                    "Idle pooled upstream connections",
+                    stats.idle_connections as u64,
+                ),
+                (
+                    "sniproxy_pool_waiters",
+                    MetricKind::Gauge,
+                    "Callers waiting for a pool slot",
+                    stats.waiters as u64,
+                ),
+                (
+                    "sniproxy_pool_connections_created_total",
+                    MetricKind::Counter,
+                    "Upstream connections opened by the pool",
+                    stats.created,
+                ),
+                (
+                    "sniproxy_pool_connections_reused_total",
+                    MetricKind::Counter,
+                    "Idle connections taken from the pool for reuse",
+                    stats.reused,
+                ),
+                (
+                    "sniproxy_pool_connections_discarded_total",
+                    MetricKind::Counter,
+                    "Pooled connections discarded as expired, stale or retired",
+                    stats.discarded(),
+                ),
+                (
+                    "sniproxy_pool_acquire_timeouts_total",
+                    MetricKind::Counter,
+                    "Timeouts waiting for a pool slot",
+                    stats.acquire_timeouts,
+                ),
+            ];
+            for (name, kind, help, value) in families {
+                out.family(name, kind, help);
+                out.sample(name, &[], value);
+            }
+            out.finish()
+        }
+    });
 }
 
 /// 处理单个客户端连接
+///
+/// 在连接的 span (见 [`connection_span`]) 内运行，日志不再重复客户端地址。
+/// 从 `accepted_at` 起记录各阶段耗时 (见 [`Phase`])，连接关闭时一并记入日志。
 async fn handle_client(
     client_stream: TcpStream,
-    client_addr: std::net::SocketAddr,
+    accepted_at: Instant,
     router: Arc<Router>,
     pool: Arc<ConnectionPool>,
-    socks5: Socks5Runtime,
+    socks5: Arc<Socks5Runtime>,
+    access: &mut AccessEntry,
 ) -> Result<()> {
-    trace!("Handling TCP client {}", client_addr);
+    trace!("handling connection");
 
     // 1. 读取初始数据以提取 SNI
-    // 我们需要读取足够的数据来捕获 TLS ClientHello
-    let mut buffer = vec![0u8; 4096];
+    // ClientHello 可能跨多个 TLS record，读到完整为止；读到的数据在建立后端连接后转发
     let mut client_stream = client_stream;
-    let n = tokio::time::timeout(socks5.timeout, client_stream.peek(&mut buffer))
+    let buffer = read_client_hello(&mut client_stream, socks5.timeout)
         .await
-        .map_err(|_| {
-            anyhow!(
-                "Timed out waiting for initial TLS data from {}",
-                client_addr
-            )
-        })??;
-
-    if n == 0 {
-        debug!("TCP client {} closed connection immediately", client_addr);
+        .context("failed to read ClientHello")?;
+
+    if buffer.is_empty() {
+        access.outcome = Outcome::ClientClosed;
+        debug!(
+            outcome = "client_closed",
+            "client closed connection immediately"
+        );
         return Ok(());
     }
 
     // 2. 尝试提取 SNI
-    let sni = match extract_sni(&buffer[..n])? {
+    let mut hello = match parse_client_hello(&buffer) {
+        Ok(ClientHelloStatus::Complete(hello)) => hello,
+        Err(e) => {
+            socks5.metrics.record_sni(Listener::Tcp, false);
+            let count = socks5.hello_errors.record(&e);
+            if e.is_scanner_noise() {
+                access.outcome = Outcome::Rejected(RejectReason::NotTls);
+                debug!(
+                    outcome = access.outcome.as_label(),
+                    error = %e,
+                    failures_so_far = count,
+                    "client is not speaking TLS, closing connection"
+                );
+            } else {
+                access.outcome = Outcome::Rejected(RejectReason::MalformedClientHello);
+                warn!(
+                    outcome = access.outcome.as_label(),
+                    error = %e,
+                    failures_so_far = count,
+                    "malformed ClientHello, closing connection"
+                );
+            }
+            return Ok(());
+        }
+        Ok(ClientHelloStatus::NeedMoreData { need_at_least }) => {
+            socks5.metrics.record_sni(Listener::Tcp, false);
+            access.outcome = Outcome::Rejected(RejectReason::IncompleteClientHello);
+            warn!(
+                outcome = access.outcome.as_label(),
+                received = buffer.len(),
+                need_at_least,
+                "incomplete ClientHello, closing connection"
+            );
+            return Ok(());
+        }
+    };
+    socks5
+        .metrics
+        .record_sni(Listener::Tcp, hello.sni.is_some());
+    let sni_at = Instant::now();
+    let sni = match hello.sni.take() {
         Some(hostname) => {
-            debug!("Extracted SNI: {} from {}", hostname, client_addr);
+            socks5
+                .metrics
+                .record_phase(Listener::Tcp, Phase::Sni, sni_at - accepted_at);
+            logging::record_sni(&hostname);
+            access.host = Some(hostname.clone());
+            debug!(
+                ech = %hello.ech,
+                alpn = ?hello.alpn,
+                tls13 = hello.offers_tls13(),
+                "sni extracted"
+            );
             hostname
         }
+        None if hello.ech.is_present() => {
+            access.outcome = Outcome::Rejected(RejectReason::EchWithoutSni);
+            warn!(
+                outcome = access.outcome.as_label(),
+                "client uses ECH without an outer SNI, closing connection"
+            );
+            return Ok(());
+        }
         None => {
             // 没有 SNI,可能是直接连接或非 TLS 流量
-            warn!("No SNI found from {}", client_addr);
+            access.outcome = Outcome::Rejected(RejectReason::NoSni);
+            warn!(
+                outcome = access.outcome.as_label(),
+                "no SNI in ClientHello, closing connection"
+            );
 
             // 检查是否是 HTTP 明文请求
-            if let Ok(http_data) = std::str::from_utf8(&buffer[..n]) {
+            if let Ok(http_data) = std::str::from_utf8(&buffer) {
                 if http_data.starts_with("GET ")
                     || http_data.starts_with("POST ")
                     || http_data.starts_with("HEAD ")
@@ -146,108 +393,206 @@ async fn handle_client(
         }
     };
 
+    // U-label 形式的 SNI 按 rules.unicode_sni 转换或拒绝
+    let sni = match router.sni_hostname(&sni) {
+        Some(Cow::Borrowed(_)) => sni,
+        Some(Cow::Owned(hostname)) => hostname,
+        None => {
+            access.outcome = Outcome::Rejected(RejectReason::MalformedClientHello);
+            warn!(
+                outcome = access.outcome.as_label(),
+                "SNI is not ASCII, rejected by unicode_sni"
+            );
+            return Ok(());
+        }
+    };
+
+    // 版本检查：按配置告警或拒绝只支持旧版本的客户端
+    let anomalies = version_anomalies(&hello, socks5.tls.min_accept_version);
+    if socks5.tls.log_version_anomalies {
+        for anomaly in &anomalies {
+            warn!(anomaly = %anomaly, "ClientHello version anomaly");
+        }
+    }
+    if socks5.tls.reject_below_min_version && anomalies.iter().any(|a| a.is_below_minimum()) {
+        access.outcome = Outcome::Rejected(RejectReason::TlsVersion);
+        info!(
+            outcome = access.outcome.as_label(),
+            max_version = %version_name(hello.max_version()),
+            "client only offers old TLS versions, rejected by tls.min_accept_version"
+        );
+        return Ok(());
+    }
+
     // 3. 白名单检查
-    if !router.is_allowed(&sni) {
+    if !router.is_sni_allowed(&sni) {
+        access.outcome = Outcome::Rejected(RejectReason::NotWhitelisted);
         warn!(
-            "Domain {} not in whitelist, rejecting connection from {}",
-            sni, client_addr
+            outcome = access.outcome.as_label(),
+            "domain not in whitelist, connection rejected"
         );
         return Ok(());
     }
 
     // 4. 从 SNI 提取目标主机和端口
     // 默认使用 443 端口 (HTTPS)
-    let target_host = sni.clone();
+    let target_host = sni.as_str();
     let target_port = 443;
 
-    // 5. 通过连接池获取 SOCKS5 连接
-    debug!(
-        "Getting TCP upstream connection to {}:{}",
-        target_host, target_port
-    );
-
-    // 克隆需要移动到闭包中的值
-    let socks5_for_connect = socks5.clone();
+    // 5. 通过连接池获取后端连接
+    let target = format!("{}:{}", target_host, target_port);
+    debug!(target = %target, "getting upstream connection");
+
+    // 规则指定的上游优先，否则按 SNI 哈希；使用 ECH 时另按 rules.ech_policy 处理
+    let route = match router.route_hello(&sni, hello.ech) {
+        HelloRoute::Upstream(route) => route,
+        HelloRoute::RejectEch => {
+            access.outcome = Outcome::Rejected(RejectReason::EchRejected);
+            info!(
+                outcome = access.outcome.as_label(),
+                "client uses ECH, rejected by ech_policy"
+            );
+            return Ok(());
+        }
+    };
+    let dialer = socks5.backend.pick(route, sni.as_bytes());
+    access.upstream = Some(dialer.to_string());
 
     let conn_guard = pool
-        .get_connection(&target_host, target_port, move |host, port| {
-            // 将这些值移入 async block
-            let socks5 = socks5_for_connect.clone();
-            let host = host.to_string();
-
-            Box::pin(async move {
-                // 创建 SOCKS5 客户端并连接
-                let client =
-                    if let (Some(username), Some(password)) = (socks5.username, socks5.password) {
-                        Socks5Client::new(socks5.addr)
-                            .with_auth(username, password)
-                            .with_timeout(socks5.timeout)
-                    } else {
-                        Socks5Client::new(socks5.addr).with_timeout(socks5.timeout)
-                    };
-
-                client.connect(&host, port).await
-            })
+        .get_connection(target_host, target_port, {
+            let dialer = dialer.clone();
+            move |host, port| {
+                let host = host.to_string();
+                Box::pin(async move { dialer.connect_tcp(&host, port).await })
+            }
         })
-        .await?;
+        .await;
+    let mut conn_guard = match conn_guard {
+        Ok(guard) => {
+            dialer.record_success();
+            guard
+        }
+        Err(PoolError::Connect(e)) => {
+            dialer.record_failure();
+            access.outcome = Outcome::Failed(FailureReason::of_connect(&e));
+            if let ConnectError::Socks5(socks5_error) = &e {
+                let count = socks5.errors.record(socks5_error);
+                socks5_error.log(Socks5Operation::Connect, &dialer, count);
+            } else {
+                warn!(
+                    target = %target,
+                    upstream = %dialer,
+                    outcome = access.outcome.as_label(),
+                    error = %e,
+                    "upstream connect failed"
+                );
+            }
+            return Ok(());
+        }
+        // 本地连接池已满不是上游的问题
+        Err(e) => return Err(e.into()),
+    };
 
+    let connected_at = Instant::now();
+    socks5
+        .metrics
+        .record_phase(Listener::Tcp, Phase::Connect, connected_at - sni_at);
     info!(
-        "TCP route established: client={}, sni={}, target={}:{}",
-        client_addr, sni, target_host, target_port
+        target = %target,
+        upstream = %dialer,
+        outcome = "established",
+        ech = %hello.ech,
+        record_version = %hello.record_version.map_or("-".into(), version_name),
+        legacy_version = %version_name(hello.legacy_version),
+        tls13 = hello.offers_tls13(),
+        "route established"
     );
 
-    // 6. 现在我们需要实际读取之前 peek 的数据
-    // 因为 SOCKS5 连接已建立,我们开始转发数据
-    client_stream.read_exact(&mut buffer[..n]).await?;
-
-    // 获取 SOCKS5 流的所有权以进行 split
-    // 注意：连接将不会被归还到池中，因为所有权已转移
-    let socks5_stream = conn_guard.into_inner();
-    let mut socks5_stream = socks5_stream;
-
-    // 先将 peek 的数据写入 SOCKS5 流
-    socks5_stream.write_all(&buffer[..n]).await?;
-    trace!("Wrote {} bytes of initial TLS data to SOCKS5 stream", n);
+    // 6. 后端连接已建立，先转发之前读到的数据
+    conn_guard.write_all(&buffer).await?;
+    socks5.metrics.record_bytes(
+        Listener::Tcp,
+        Direction::ClientToUpstream,
+        buffer.len() as u64,
+    );
+    let bytes = ConnectionBytes::default();
+    bytes.up.fetch_add(buffer.len() as u64, Ordering::Relaxed);
+    trace!(bytes = buffer.len(), "initial TLS data written upstream");
 
-    // 7. 双向转发数据
+    // 7. 双向转发数据，连接仍归连接池管理
     let (mut client_read, mut client_write) = client_stream.split();
-    let (mut proxy_read, mut proxy_write) = tokio::io::split(socks5_stream);
+    let (proxy_read, mut proxy_write) = tokio::io::split(&mut conn_guard);
+    let first_byte_at = OnceLock::new();
+    let mut proxy_read = FirstRead::new(proxy_read, &first_byte_at);
 
     // 创建双向转发任务
     let idle_timeout = socks5.transfer_idle_timeout;
+    let metrics = &socks5.metrics;
     let client_to_proxy = async {
-        copy_with_idle_timeout(&mut client_read, &mut proxy_write, idle_timeout)
-            .await
-            .map_err(|e| anyhow!("Client to proxy copy failed: {}", e))
+        copy_with_idle_timeout(
+            &mut client_read,
+            &mut proxy_write,
+            idle_timeout,
+            &[
+                metrics.bytes(Listener::Tcp, Direction::ClientToUpstream),
+                &bytes.up,
+            ],
+        )
+        .await
     };
 
     let proxy_to_client = async {
-        copy_with_idle_timeout(&mut proxy_read, &mut client_write, idle_timeout)
-            .await
-            .map_err(|e| anyhow!("Proxy to client copy failed: {}", e))
+        copy_with_idle_timeout(
+            &mut proxy_read,
+            &mut client_write,
+            idle_timeout,
+            &[
+                metrics.bytes(Listener::Tcp, Direction::UpstreamToClient),
+                &bytes.down,
+            ],
+        )
+        .await
     };
 
     // 运行双向转发,任一方向结束时关闭连接
-    tokio::select! {
-        result = client_to_proxy => {
-            if let Err(e) = result {
-                debug!("TCP client-to-proxy forwarding ended: {}", e);
-            }
-        }
-        result = proxy_to_client => {
-            if let Err(e) = result {
-                debug!("TCP proxy-to-client forwarding ended: {}", e);
-            }
-        }
+    let (direction, result) = tokio::select! {
+        result = client_to_proxy => ("client_to_upstream", result),
+        result = proxy_to_client => ("upstream_to_client", result),
+    };
+    if let Err(e) = &result {
+        debug!(direction, error = %e, "forwarding ended");
     }
-
-    trace!("TCP connection from {} closed", client_addr);
+    access.outcome = forwarding_outcome(&result);
+
+    // TLS 会话不能交给另一个客户端，连接关闭并释放名额
+    conn_guard.finish(false).await;
+
+    let (up, down) = bytes.totals();
+    socks5.metrics.domains().record(&sni, up, down);
+    access.bytes_up = up;
+    access.bytes_down = down;
+    let first_byte = first_byte_at.get().map(|at| *at - connected_at);
+    if let Some(elapsed) = first_byte {
+        socks5
+            .metrics
+            .record_phase(Listener::Tcp, Phase::FirstByte, elapsed);
+    }
+    info!(
+        outcome = access.outcome.as_label(),
+        bytes_up = up,
+        bytes_down = down,
+        sni_ms = logging::millis(sni_at - accepted_at),
+        connect_ms = logging::millis(connected_at - sni_at),
+        first_byte_ms = first_byte.map(logging::millis),
+        "connection closed"
+    );
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::upstream::UpstreamSet;
 
     #[test]
     fn test_config_parsing() {
@@ -269,4 +614,394 @@ allow = ["*.google.com", "api.*.com"]
         assert_eq!(config.server.listen_https_addr.unwrap().port(), 8443);
         assert_eq!(config.socks5.addr.port(), 1080);
     }
+
+    /// 以 TLS record 包装 Handshake 消息
+    fn record(handshake: &[u8]) -> Vec<u8> {
+        let mut data = vec![0x16, 0x03, 0x01];
+        data.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        data.extend_from_slice(handshake);
+        data
+    }
+
+    /// 在空闲端口上运行的 TCP 监听器，经 `socks5` 转发，只允许 www.example.com
+    struct TestProxy {
+        listen_addr: std::net::SocketAddr,
+        config: Arc<Config>,
+        registry: Arc<Registry>,
+        shutdown_tx: watch::Sender<bool>,
+        task: tokio::task::JoinHandle<crate::Result<()>>,
+    }
+
+    impl TestProxy {
+        async fn start(socks5: std::net::SocketAddr) -> Self {
+            let listen_addr = std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap();
+            let config: Arc<Config> = toml::from_str::<Config>(&format!(
+                r#"
+[server]
+listen_https_addr = "{}"
+
+[socks5]
+addr = "{}"
+
+[rules]
+allow = ["www.example.com"]
+"#,
+                listen_addr, socks5
+            ))
+            .unwrap()
+            .into();
+            let upstreams = Arc::new(UpstreamSet::from_config(&config).unwrap());
+            let backend = Backend::from_config(&config, upstreams).unwrap();
+            let registry = Arc::new(Registry::default());
+            let (shutdown_tx, shutdown_rx) = watch::channel(false);
+            let task = tokio::spawn(run(
+                Arc::clone(&config),
+                backend,
+                shutdown_rx,
+                Arc::clone(&registry),
+            ));
+            Self {
+                listen_addr,
+                config,
+                registry,
+                shutdown_tx,
+                task,
+            }
+        }
+
+        /// 连接监听器，监听器尚未就绪时重试
+        async fn connect(&self) -> TcpStream {
+            for _ in 0..100 {
+                if let Ok(stream) = TcpStream::connect(self.listen_addr).await {
+                    return stream;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            panic!("listener did not come up");
+        }
+
+        async fn stop(self) {
+            self.shutdown_tx.send(true).unwrap();
+            self.task.await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn connections_share_the_listener_config() {
+        use crate::socks5::test_util::spawn_mock_connect;
+        use tokio::io::AsyncReadExt;
+
+        let mock = spawn_mock_connect().await;
+        let proxy = TestProxy::start(mock.addr).await;
+        let hello = crate::tls::fixtures::client_hello("chrome_tcp_ech_grease").data;
+        let forward = || async {
+            let mut client = proxy.connect().await;
+            client.write_all(&hello).await.unwrap();
+            let mut echoed = vec![0u8; hello.len()];
+            client.read_exact(&mut echoed).await.unwrap();
+            client
+        };
+
+        let first = forward().await;
+        let baseline = Arc::strong_count(&proxy.config);
+        // 转发中的连接不持有配置的副本或引用
+        let mut open = vec![first];
+        for _ in 0..3 {
+            open.push(forward().await);
+        }
+        assert_eq!(proxy.registry.active_connections(Listener::Tcp), 4);
+        assert_eq!(Arc::strong_count(&proxy.config), baseline);
+
+        drop(open);
+        proxy.stop().await;
+    }
+
+    #[tokio::test]
+    async fn run_reports_an_occupied_port() {
+        let occupied = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listen_addr = occupied.local_addr().unwrap();
+        let config: Arc<Config> = toml::from_str::<Config>(&format!(
+            r#"
+[server]
+listen_https_addr = "{}"
+
+[socks5]
+addr = "127.0.0.1:1080"
+"#,
+            listen_addr
+        ))
+        .unwrap()
+        .into();
+        let upstreams = Arc::new(UpstreamSet::from_config(&config).unwrap());
+        let backend = Backend::from_config(&config, upstreams).unwrap();
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+
+        let err = run(config, backend, shutdown_rx, Arc::default())
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(
+                err,
+                crate::Error::ListenerBind {
+                    listener: "HTTPS",
+                    addr,
+                    ..
+                } if addr == listen_addr
+            ),
+            "{}",
+            err
+        );
+    }
+
+    /// 第一次记录 SNI 阶段耗时时 panic 的 sink，模拟连接处理中的缺陷
+    #[derive(Default)]
+    struct PanicOnSni(std::sync::atomic::AtomicBool);
+
+    impl crate::metrics::Sink for PanicOnSni {
+        fn timing(&self, _listener: Listener, phase: Phase, _elapsed: Duration) {
+            if phase == Phase::Sni && !self.0.swap(true, std::sync::atomic::Ordering::SeqCst) {
+                panic!("sink failed");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn listener_survives_a_panicking_connection_handler() {
+        use crate::socks5::test_util::spawn_mock_connect;
+        use tokio::io::AsyncReadExt;
+
+        let mock = spawn_mock_connect().await;
+        let proxy = TestProxy::start(mock.addr).await;
+        proxy.registry.add_sink(Arc::new(PanicOnSni::default()));
+        let hello = crate::tls::fixtures::client_hello("chrome_tcp_ech_grease").data;
+
+        // 处理任务 panic：连接被关闭，名额和活动连接数照常释放
+        let mut first = proxy.connect().await;
+        first.write_all(&hello).await.unwrap();
+        let mut rest = Vec::new();
+        let _ = first.read_to_end(&mut rest).await;
+        assert!(rest.is_empty());
+        assert_eq!(
+            wait_for_count(|| proxy.registry.handler_panics(Listener::Tcp), 1).await,
+            1
+        );
+
+        // 监听器继续接受并转发后续连接
+        let mut second = proxy.connect().await;
+        second.write_all(&hello).await.unwrap();
+        let mut echoed = vec![0u8; hello.len()];
+        second.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(echoed, hello);
+        assert_eq!(proxy.registry.handler_panics(Listener::Tcp), 1);
+        assert_eq!(proxy.registry.active_connections(Listener::Tcp), 1);
+
+        drop(second);
+        proxy.stop().await;
+    }
+
+    #[tokio::test]
+    async fn connection_events_carry_standard_fields() {
+        use crate::logging::capture::capture;
+        use crate::quic::test_util::client_hello_with_extensions;
+        use crate::socks5::test_util::spawn_mock_connect;
+        use tokio::io::AsyncReadExt;
+
+        let (events, _guard) = capture();
+        let mock = spawn_mock_connect().await;
+        let proxy = TestProxy::start(mock.addr).await;
+
+        // 允许的 SNI：后端原样发回 ClientHello
+        let hello = crate::tls::fixtures::client_hello("chrome_tcp_ech_grease").data;
+        let mut allowed = proxy.connect().await;
+        let allowed_addr = allowed.local_addr().unwrap().to_string();
+        allowed.write_all(&hello).await.unwrap();
+        let mut echoed = vec![0u8; hello.len()];
+        allowed.read_exact(&mut echoed).await.unwrap();
+
+        // 不在白名单中：连接被关闭
+        let mut rejected = proxy.connect().await;
+        let rejected_addr = rejected.local_addr().unwrap().to_string();
+        rejected
+            .write_all(&record(&client_hello_with_extensions(
+                Some("blocked.example.org"),
+                &[],
+            )))
+            .await
+            .unwrap();
+        let mut rest = Vec::new();
+        rejected.read_to_end(&mut rest).await.unwrap();
+
+        proxy.stop().await;
+
+        let events = events.lock().unwrap();
+        let find = |message: &str| {
+            events
+                .iter()
+                .find(|event| event.message == message)
+                .unwrap_or_else(|| panic!("no {:?} event in {:#?}", message, events))
+                .clone()
+        };
+
+        let established = find("route established");
+        assert_eq!(established.field("listener"), Some("tcp"));
+        assert_eq!(established.field("client"), Some(allowed_addr.as_str()));
+        assert_eq!(established.field("sni"), Some("www.example.com"));
+        assert_eq!(established.field("target"), Some("www.example.com:443"));
+        assert!(established.field("upstream").is_some());
+        assert_eq!(established.field("outcome"), Some("established"));
+        assert_eq!(established.level, tracing::Level::INFO);
+
+        let not_whitelisted = find("domain not in whitelist, connection rejected");
+        assert_eq!(not_whitelisted.field("listener"), Some("tcp"));
+        assert_eq!(
+            not_whitelisted.field("client"),
+            Some(rejected_addr.as_str())
+        );
+        assert_eq!(not_whitelisted.field("sni"), Some("blocked.example.org"));
+        assert_eq!(not_whitelisted.field("outcome"), Some("not_whitelisted"));
+        assert_eq!(not_whitelisted.level, tracing::Level::WARN);
+
+        let established_id = established.field("conn_id").unwrap();
+        let rejected_id = not_whitelisted.field("conn_id").unwrap();
+        assert!(established_id.parse::<u64>().is_ok());
+        assert_ne!(established_id, rejected_id);
+    }
+
+    #[tokio::test]
+    async fn phase_histograms_see_upstream_delay() {
+        use crate::logging::capture::capture;
+        use crate::socks5::test_util::spawn_mock_connect_delayed;
+        use tokio::io::AsyncReadExt;
+
+        let (events, _guard) = capture();
+        // CONNECT 回复延迟 150ms：sni_to_connected 落在 (0.1, 0.2] 秒的桶中
+        let mock = spawn_mock_connect_delayed(Duration::from_millis(150)).await;
+        let proxy = TestProxy::start(mock.addr).await;
+
+        let hello = crate::tls::fixtures::client_hello("chrome_tcp_ech_grease").data;
+        let mut client = proxy.connect().await;
+        client.write_all(&hello).await.unwrap();
+        let mut echoed = vec![0u8; hello.len()];
+        client.read_exact(&mut echoed).await.unwrap();
+        drop(client);
+
+        let mut text = String::new();
+        for _ in 0..100 {
+            text = proxy.registry.render().await;
+            if text.contains("sniproxy_connection_phase_seconds_count{listener=\"tcp\",phase=\"connected_to_first_byte\"} 1\n") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        for line in [
+            "sniproxy_connection_phase_seconds_count{listener=\"tcp\",phase=\"accept_to_sni\"} 1",
+            "sniproxy_connection_phase_seconds_bucket{listener=\"tcp\",phase=\"sni_to_connected\",le=\"0.1\"} 0",
+            "sniproxy_connection_phase_seconds_bucket{listener=\"tcp\",phase=\"sni_to_connected\",le=\"0.2\"} 1",
+            "sniproxy_connection_phase_seconds_bucket{listener=\"tcp\",phase=\"connected_to_first_byte\",le=\"0.1\"} 1",
+            "sniproxy_connection_phase_seconds_count{listener=\"quic\",phase=\"sni_to_connected\"} 0",
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing {:?} in\n{}", line, text);
+        }
+        proxy.stop().await;
+
+        let events = events.lock().unwrap();
+        let closed = events
+            .iter()
+            .find(|event| event.message == "connection closed")
+            .expect("no connection closed event");
+        let connect_ms: f64 = closed.field("connect_ms").unwrap().parse().unwrap();
+        assert!((150.0..200.0).contains(&connect_ms), "{}", connect_ms);
+        assert!(closed.field("sni_ms").is_some());
+        assert!(closed.field("first_byte_ms").is_some());
+        assert_eq!(
+            closed.field("bytes_down"),
+            Some(hello.len().to_string().as_str())
+        );
+
+        let access = events
+            .iter()
+            .find(|event| event.message == "access")
+            .expect("no access event");
+        assert_eq!(access.field("host"), Some("www.example.com"));
+        assert_eq!(access.field("port"), Some("443"));
+        assert_eq!(access.field("outcome"), Some("closed"));
+        assert_eq!(access.field("status"), Some("200"));
+        assert_eq!(access.field("bytes_down"), closed.field("bytes_down"));
+        assert!(access.field("upstream").is_some());
+    }
+
+    /// 等待注册表中的计数达到 `expected`
+    async fn wait_for_count(count: impl Fn() -> u64, expected: u64) -> u64 {
+        for _ in 0..100 {
+            if count() >= expected {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        count()
+    }
+
+    #[tokio::test]
+    async fn rejections_and_failures_are_counted_by_reason() {
+        use crate::quic::test_util::client_hello_with_extensions;
+        use tokio::io::AsyncReadExt;
+
+        let mock = crate::socks5::test_util::spawn_mock_connect().await;
+        let proxy = TestProxy::start(mock.addr).await;
+        let cases: [(Vec<u8>, RejectReason); 3] = [
+            (b"GET / HTTP/1.1\r\n\r\n".to_vec(), RejectReason::NotTls),
+            (
+                record(&client_hello_with_extensions(None, &[])),
+                RejectReason::NoSni,
+            ),
+            (
+                record(&client_hello_with_extensions(
+                    Some("blocked.example.org"),
+                    &[],
+                )),
+                RejectReason::NotWhitelisted,
+            ),
+        ];
+        for (data, reason) in cases {
+            let mut client = proxy.connect().await;
+            client.write_all(&data).await.unwrap();
+            let mut rest = Vec::new();
+            client.read_to_end(&mut rest).await.unwrap();
+            let count = wait_for_count(|| proxy.registry.rejections(Listener::Tcp, reason), 1);
+            assert_eq!(count.await, 1, "{:?}", reason);
+        }
+        // 没有发送数据就关闭的连接不计入拒绝或失败
+        drop(proxy.connect().await);
+        let totals = proxy.registry.totals().await;
+        assert_eq!(totals.rejections.values().sum::<u64>(), 3);
+        assert_eq!(totals.failures.values().sum::<u64>(), 0);
+        proxy.stop().await;
+
+        // SOCKS5 上游不可用
+        let dead = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let proxy = TestProxy::start(dead).await;
+        let mut client = proxy.connect().await;
+        client
+            .write_all(&crate::tls::fixtures::client_hello("chrome_tcp_ech_grease").data)
+            .await
+            .unwrap();
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+        let count = wait_for_count(
+            || {
+                proxy
+                    .registry
+                    .failures(Listener::Tcp, FailureReason::Socks5ConnectFailed)
+            },
+            1,
+        );
+        assert_eq!(count.await, 1);
+        proxy.stop().await;
+    }
 }
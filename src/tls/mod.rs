@@ -1 +1,7 @@
 pub mod sni;
+pub mod version;
+
+/// ClientHello fixture 语料库，TLS 与 QUIC 的单元测试共用
+#[cfg(test)]
+#[path = "../../tests/common/fixtures.rs"]
+pub(crate) mod fixtures;
@@ -1,16 +1,35 @@
-use anyhow::{bail, Result};
+use std::borrow::Cow;
 use std::fmt;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
 
 /// TLS SNI 提取错误类型
-#[derive(Debug)]
-#[allow(dead_code)]
+///
+/// 只表示数据本身格式错误，调用方应放弃；数据只是还没收全时返回
+/// [`SniStatus::NeedMoreData`] / [`ClientHelloStatus::NeedMoreData`]。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SniError {
+    /// 消息已完整但长度不足以容纳 ClientHello 的固定字段
     DataTooShort,
     NotHandshake,
     NotClientHello,
     InvalidExtension,
-    InvalidHostname,
+    InvalidHostname(HostnameError),
     SniNotFound,
+    /// server_name 扩展中有多个值不同的 host_name
+    ConflictingServerNames,
+    /// SSLv2 格式的 CLIENT-HELLO (旧扫描器仍会发送)
+    Ssl2NotSupported,
+    /// 既不是 TLS record 也不是 Handshake 消息 (明文 HTTP、随机字节等)
+    UnknownProtocol,
+}
+
+impl SniError {
+    /// 是否为扫描器之类的非 TLS 流量，而不是格式错误的 TLS ClientHello
+    pub fn is_scanner_noise(&self) -> bool {
+        matches!(self, SniError::Ssl2NotSupported | SniError::UnknownProtocol)
+    }
 }
 
 impl fmt::Display for SniError {
@@ -20,179 +39,755 @@ impl fmt::Display for SniError {
             SniError::NotHandshake => write!(f, "Not Handshake"),
             SniError::NotClientHello => write!(f, "Not ClientHello"),
             SniError::InvalidExtension => write!(f, "Invalid extension"),
-            SniError::InvalidHostname => write!(f, "Invalid hostname"),
+            SniError::InvalidHostname(reason) => write!(f, "Invalid hostname: {}", reason),
             SniError::SniNotFound => write!(f, "SNI not found"),
+            SniError::ConflictingServerNames => write!(f, "Conflicting server names"),
+            SniError::Ssl2NotSupported => write!(f, "SSLv2 ClientHello not supported"),
+            SniError::UnknownProtocol => write!(f, "Unknown protocol (not TLS)"),
         }
     }
 }
 
 impl std::error::Error for SniError {}
 
-pub fn extract_sni(data: &[u8]) -> Result<Option<String>> {
-    // 支持两种输入：
-    // 1) 传统 TCP+TLS：TLS record layer（开头 0x16）
-    // 2) QUIC CRYPTO stream：直接携带 TLS Handshake message（开头 0x01）
-    let payload: &[u8] = if data.first().copied() == Some(0x16) {
-        // TLS record: [type(1)=0x16][version(2)][len(2)][handshake...]
-        if data.len() < 5 {
-            bail!(SniError::DataTooShort);
-        }
-        let length = u16::from_be_bytes([data[3], data[4]]) as usize;
-        if data.len() < 5 + length {
-            bail!(SniError::DataTooShort);
-        }
-        &data[5..5 + length]
-    } else {
-        // QUIC CRYPTO: raw TLS handshake bytes
-        data
-    };
+/// SNI 主机名不合法的原因，见 [`validate_hostname`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostnameError {
+    Empty,
+    /// 总长度超过 253 字节
+    TooLong(usize),
+    /// 空标签 (连续的点、以点开头或结尾)
+    EmptyLabel,
+    /// 标签超过 63 字节
+    LabelTooLong(usize),
+    /// 含无法按 IDNA 转换为 A-label 的非 ASCII 字符，或不是合法的 UTF-8
+    NonAscii,
+    /// 字母、数字、连字符以外的 ASCII 字符
+    InvalidCharacter(char),
+    /// 标签以连字符开头或结尾
+    HyphenAtLabelEdge,
+}
 
-    if payload.len() < 4 {
-        bail!(SniError::DataTooShort);
+impl fmt::Display for HostnameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostnameError::Empty => write!(f, "empty"),
+            HostnameError::TooLong(len) => write!(f, "{} bytes long (max 253)", len),
+            HostnameError::EmptyLabel => write!(f, "empty label"),
+            HostnameError::LabelTooLong(len) => write!(f, "label of {} bytes (max 63)", len),
+            HostnameError::NonAscii => write!(f, "non-ASCII characters that are not a valid IDN"),
+            HostnameError::InvalidCharacter(c) => write!(f, "invalid character {:?}", c),
+            HostnameError::HyphenAtLabelEdge => write!(f, "label starts or ends with a hyphen"),
+        }
     }
+}
 
-    // TLS Handshake: [msg_type(1)][len(3)][body...]
-    let handshake_type = payload[0];
-    if handshake_type != 0x01 {
-        // QUIC 场景下这里通常就是 0x01；如果不是，说明我们拿到的不是 ClientHello 起始处
-        bail!(SniError::NotHandshake);
+impl std::error::Error for HostnameError {}
+
+/// [`extract_sni`] / [`extract_sni_ref`] 的结果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SniStatus<S = String> {
+    /// ClientHello 完整，携带 SNI
+    Found(S),
+    /// ClientHello 完整，但没有 SNI 扩展
+    NotPresent,
+    /// 数据还没收全，缓冲区至少要有 `need_at_least` 字节才值得重试
+    NeedMoreData { need_at_least: usize },
+}
+
+impl<S> SniStatus<S> {
+    /// 转换 `Found` 中的主机名
+    pub fn map<T>(self, f: impl FnOnce(S) -> T) -> SniStatus<T> {
+        match self {
+            SniStatus::Found(sni) => SniStatus::Found(f(sni)),
+            SniStatus::NotPresent => SniStatus::NotPresent,
+            SniStatus::NeedMoreData { need_at_least } => SniStatus::NeedMoreData { need_at_least },
+        }
+    }
+}
+
+/// [`parse_client_hello`] 的结果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientHelloStatus {
+    /// ClientHello 完整
+    Complete(ClientHelloInfo),
+    /// 数据还没收全，缓冲区至少要有 `need_at_least` 字节才值得重试
+    NeedMoreData { need_at_least: usize },
+}
+
+/// 解析中止的原因，仅在本模块内部使用
+enum Stop {
+    NeedMoreData { need_at_least: usize },
+    Malformed(SniError),
+}
+
+impl From<SniError> for Stop {
+    fn from(error: SniError) -> Self {
+        Stop::Malformed(error)
+    }
+}
+
+/// 输入数据的格式，由首字节判断
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputKind {
+    /// TLS record (content type 0x16 handshake)
+    TlsRecord,
+    /// 不带 record 头的 ClientHello Handshake 消息 (QUIC CRYPTO 数据)
+    RawHandshake,
+    /// SSLv2 CLIENT-HELLO：2 字节长度 (最高位为 1)、消息类型 1、版本 0x0002 或 0x03xx
+    Ssl2Hello,
+    /// 其它数据
+    Unknown,
+}
+
+/// 判断输入数据的格式；数据太少还无法判断时返回 None
+pub fn classify_input(data: &[u8]) -> Option<InputKind> {
+    Some(match *data.first()? {
+        0x16 => InputKind::TlsRecord,
+        0x01 => InputKind::RawHandshake,
+        first if first & 0x80 != 0 => match data.get(2..4)? {
+            [0x01, 0x00 | 0x03] => InputKind::Ssl2Hello,
+            _ => InputKind::Unknown,
+        },
+        _ => InputKind::Unknown,
+    })
+}
+
+/// 等待 ClientHello 时最多缓冲的字节数
+pub const MAX_CLIENT_HELLO_LEN: usize = 16 * 1024;
+
+/// 返回 TLS Handshake 消息 (不含 record 头) 的总字节数
+///
+/// 用于判断数据还没收全时还差多少；前 4 字节 (类型 + 长度) 尚未收全时返回 None。
+pub fn handshake_message_len(data: &[u8]) -> Option<usize> {
+    let header = data.get(..4)?;
+    let body_len =
+        ((header[1] as usize) << 16) | ((header[2] as usize) << 8) | (header[3] as usize);
+    Some(4 + body_len)
+}
+
+/// server_name 扩展类型 (RFC 6066)
+const EXT_SERVER_NAME: u16 = 0x0000;
+
+/// application_layer_protocol_negotiation 扩展类型 (RFC 7301)
+const EXT_ALPN: u16 = 0x0010;
+
+/// supported_versions 扩展类型 (RFC 8446)
+const EXT_SUPPORTED_VERSIONS: u16 = 0x002b;
+
+/// TLS 1.3 的版本号
+pub const TLS13: u16 = 0x0304;
+
+/// encrypted_client_hello 扩展类型 (draft-ietf-tls-esni)
+const EXT_ENCRYPTED_CLIENT_HELLO: u16 = 0xfe0d;
+
+/// ECHClientHello.type 中的 outer 取值
+const ECH_OUTER: u8 = 0x00;
+
+/// ClientHello 的 ECH 状态
+///
+/// 外层 ClientHello 中的 GREASE ECH (浏览器在没有 ECH 配置时也会发送) 与真实 ECH
+/// 格式相同，只解密失败才能区分，这里不做区分。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EchStatus {
+    /// 没有 outer 类型的 encrypted_client_hello 扩展
+    #[default]
+    Absent,
+    /// 携带 outer 类型的 encrypted_client_hello 扩展，真实目标可能隐藏在内层
+    GreaseOrReal {
+        /// 外层 ClientHello 是否有 SNI (真实 ECH 时为 public_name)
+        outer_sni_present: bool,
+    },
+}
+
+impl EchStatus {
+    /// 是否携带 ECH 扩展 (含 GREASE)
+    pub fn is_present(&self) -> bool {
+        matches!(self, EchStatus::GreaseOrReal { .. })
     }
+}
 
-    let hs_len =
-        ((payload[1] as usize) << 16) | ((payload[2] as usize) << 8) | (payload[3] as usize);
-    if payload.len() < 4 + hs_len {
-        bail!(SniError::DataTooShort);
+impl fmt::Display for EchStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EchStatus::Absent => write!(f, "absent"),
+            EchStatus::GreaseOrReal {
+                outer_sni_present: true,
+            } => write!(f, "grease-or-real"),
+            EchStatus::GreaseOrReal {
+                outer_sni_present: false,
+            } => write!(f, "grease-or-real (no outer SNI)"),
+        }
     }
+}
 
-    let client_hello = &payload[4..4 + hs_len];
+/// ClientHello 中与路由相关的信息
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientHelloInfo {
+    /// 明文 SNI；使用 ECH 时为外层 ClientHello 的 public_name
+    pub sni: Option<String>,
+    /// encrypted_client_hello 扩展的状态
+    pub ech: EchStatus,
+    /// ALPN 协议列表，按客户端偏好顺序；没有 ALPN 扩展时为空
+    pub alpn: Vec<String>,
+    /// supported_versions 扩展中的版本，按客户端偏好顺序，已去掉 GREASE 值；
+    /// 没有该扩展时为空 (客户端最高只支持 TLS 1.2)
+    pub supported_versions: Vec<u16>,
+    /// 解析消耗的字节数：TLS record 输入为整个 record，QUIC CRYPTO 输入为 Handshake 消息
+    pub consumed: usize,
+    /// 第一个 TLS record 头中的版本；QUIC CRYPTO 输入没有 record 头，为 None
+    pub record_version: Option<u16>,
+    /// ClientHello 的 legacy_version (TLS 1.3 客户端固定为 0x0303)
+    pub legacy_version: u16,
+}
 
-    if client_hello.len() < 38 {
-        bail!(SniError::DataTooShort);
+impl ClientHelloInfo {
+    /// 客户端是否提供 TLS 1.3
+    pub fn offers_tls13(&self) -> bool {
+        self.supported_versions.contains(&TLS13)
     }
 
-    let mut offset = 34;
+    /// 客户端支持的最高版本：有 supported_versions 扩展时取其中最高的，否则为 legacy_version
+    pub fn max_version(&self) -> u16 {
+        self.supported_versions
+            .iter()
+            .copied()
+            .max()
+            .unwrap_or(self.legacy_version)
+    }
+}
 
-    if offset >= client_hello.len() {
-        return Ok(None);
+/// 版本号的可读名称，例如 0x0303 -> "TLS 1.2"
+pub fn version_name(version: u16) -> Cow<'static, str> {
+    match version {
+        0x0300 => "SSL 3.0".into(),
+        0x0301 => "TLS 1.0".into(),
+        0x0302 => "TLS 1.1".into(),
+        0x0303 => "TLS 1.2".into(),
+        0x0304 => "TLS 1.3".into(),
+        other => format!("{:#06x}", other).into(),
     }
+}
 
-    let session_id_length = client_hello[offset] as usize;
-    offset += 1 + session_id_length;
+/// 是否为 GREASE 保留值 (RFC 8701)：0x0a0a、0x1a1a、…、0xfafa
+pub fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
 
-    if offset >= client_hello.len() {
-        return Ok(None);
+/// 带边界检查的只读游标
+///
+/// ClientHello 及其扩展的解析都经由它读取，越界时返回 None 而不是 panic；
+/// 所有长度运算集中在这里。
+#[derive(Debug, Clone, Copy)]
+struct Reader<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { rest: data }
     }
 
-    let cipher_suites_length =
-        u16::from_be_bytes([client_hello[offset], client_hello[offset + 1]]) as usize;
-    offset += 2 + cipher_suites_length;
+    fn is_empty(&self) -> bool {
+        self.rest.is_empty()
+    }
 
-    if offset >= client_hello.len() {
-        return Ok(None);
+    /// 读取 `len` 字节
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let (head, rest) = self.rest.split_at_checked(len)?;
+        self.rest = rest;
+        Some(head)
     }
 
-    let compression_length = client_hello[offset] as usize;
-    offset += 1 + compression_length;
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
 
-    if offset + 2 > client_hello.len() {
-        return Ok(None);
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
     }
 
-    let extensions_length =
-        u16::from_be_bytes([client_hello[offset], client_hello[offset + 1]]) as usize;
-    offset += 2;
+    /// 读取 1 字节长度前缀的向量
+    fn vec_u8(&mut self) -> Option<&'a [u8]> {
+        let len = self.u8()?;
+        self.bytes(len as usize)
+    }
 
-    if offset + extensions_length > client_hello.len() {
-        bail!(SniError::InvalidExtension);
+    /// 读取 2 字节长度前缀的向量
+    fn vec_u16(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()?;
+        self.bytes(len as usize)
     }
+}
 
-    let ext_end = offset + extensions_length;
-    let mut ext_count = 0;
+/// ClientHello 扩展列表的迭代器，按出现顺序产出 `(扩展类型, 扩展数据)`
+///
+/// 扩展数据越界或末尾剩下不足一个扩展头的字节时产出一次
+/// [`SniError::InvalidExtension`] 后结束。
+pub struct Extensions<'a> {
+    reader: Reader<'a>,
+}
 
-    while offset < ext_end {
-        if offset + 4 > client_hello.len() {
-            break;
+impl<'a> Extensions<'a> {
+    /// `data` 为 extensions 字段的内容 (不含 2 字节的总长度)
+    pub fn new(data: &'a [u8]) -> Self {
+        Self {
+            reader: Reader::new(data),
         }
+    }
+}
 
-        let ext_type = u16::from_be_bytes([client_hello[offset], client_hello[offset + 1]]);
-        let ext_length =
-            u16::from_be_bytes([client_hello[offset + 2], client_hello[offset + 3]]) as usize;
-        offset += 4;
-
-        ext_count += 1;
+impl<'a> Iterator for Extensions<'a> {
+    type Item = Result<(u16, &'a [u8]), SniError>;
 
-        if offset + ext_length > client_hello.len() {
-            bail!(SniError::InvalidExtension);
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.reader.is_empty() {
+            return None;
         }
+        let extension = self
+            .reader
+            .u16()
+            .and_then(|ext_type| Some((ext_type, self.reader.vec_u16()?)));
+        if extension.is_none() {
+            self.reader = Reader::new(&[]);
+        }
+        Some(extension.ok_or(SniError::InvalidExtension))
+    }
+}
+
+/// 提取 ClientHello 中的 SNI
+pub fn extract_sni(data: &[u8]) -> Result<SniStatus, SniError> {
+    extract_sni_ref(data).map(|status| status.map(Cow::into_owned))
+}
 
-        if ext_type == 0x0000 {
-            tracing::debug!("Found SNI extension (extension #{})", ext_count);
-            return parse_sni_extension(&client_hello[offset..offset + ext_length]).map(Some);
+/// 提取 ClientHello 中的 SNI，不复制主机名
+///
+/// ClientHello 在一个 TLS record (或 QUIC CRYPTO 数据) 内时返回指向 `data` 的
+/// `Cow::Borrowed`；跨多个 record 时需要拼接，返回 `Cow::Owned`。只解析 server_name
+/// 扩展，不检查 ALPN 等其它扩展的内容。
+pub fn extract_sni_ref(data: &[u8]) -> Result<SniStatus<Cow<'_, str>>, SniError> {
+    let client_hello = match client_hello_body(data) {
+        Ok((client_hello, _)) => client_hello,
+        Err(Stop::NeedMoreData { need_at_least }) => {
+            return Ok(SniStatus::NeedMoreData { need_at_least })
         }
+        Err(Stop::Malformed(error)) => return Err(error),
+    };
+    let sni = match client_hello {
+        Cow::Borrowed(client_hello) => find_sni(client_hello)?.map(Cow::Borrowed),
+        Cow::Owned(client_hello) => find_sni(&client_hello)?.map(|sni| Cow::Owned(sni.to_string())),
+    };
+    Ok(sni.map_or(SniStatus::NotPresent, SniStatus::Found))
+}
 
-        offset += ext_length;
+/// 在 ClientHello 消息体中查找 server_name 扩展
+fn find_sni(client_hello: &[u8]) -> Result<Option<&str>, SniError> {
+    let Some(extensions) = extensions_block(client_hello)? else {
+        return Ok(None);
+    };
+    let mut sni = None;
+    for extension in Extensions::new(extensions) {
+        let (ext_type, ext_data) = extension?;
+        if ext_type == EXT_SERVER_NAME {
+            sni = parse_sni_extension(ext_data)?;
+        }
     }
+    Ok(sni)
+}
 
-    tracing::debug!("SNI extension not found (checked {} extensions)", ext_count);
-    Ok(None)
+/// 解析 ClientHello，提取 SNI、ALPN、supported_versions 并识别 ECH
+///
+/// ECH 的真实 SNI 在加密的内层 ClientHello 中，这里只能拿到外层的 public_name。
+pub fn parse_client_hello(data: &[u8]) -> Result<ClientHelloStatus, SniError> {
+    match parse(data) {
+        Ok(info) => Ok(ClientHelloStatus::Complete(info)),
+        Err(Stop::NeedMoreData { need_at_least }) => {
+            Ok(ClientHelloStatus::NeedMoreData { need_at_least })
+        }
+        Err(Stop::Malformed(error)) => Err(error),
+    }
 }
 
-fn parse_sni_extension(data: &[u8]) -> Result<String> {
-    if data.len() < 2 {
-        bail!(SniError::InvalidExtension);
+fn parse(data: &[u8]) -> Result<ClientHelloInfo, Stop> {
+    let (client_hello, consumed) = client_hello_body(data)?;
+    // extensions_block 已确认固定字段完整
+    let extensions = extensions_block(&client_hello)?;
+    let mut info = ClientHelloInfo {
+        record_version: (classify_input(data) == Some(InputKind::TlsRecord))
+            .then(|| u16::from_be_bytes([data[1], data[2]])),
+        legacy_version: u16::from_be_bytes([client_hello[0], client_hello[1]]),
+        consumed,
+        ..Default::default()
+    };
+    let Some(extensions) = extensions else {
+        return Ok(info);
+    };
+
+    let mut ext_count = 0;
+    let mut ech_outer = false;
+
+    for extension in Extensions::new(extensions) {
+        let (ext_type, ext_data) = extension?;
+        ext_count += 1;
+        match ext_type {
+            EXT_SERVER_NAME => {
+                tracing::debug!(ext_index = ext_count, "sni extension found");
+                info.sni = parse_sni_extension(ext_data)?.map(str::to_string);
+            }
+            EXT_ALPN => {
+                info.alpn = parse_alpn_extension(ext_data)?;
+                tracing::debug!(alpn = ?info.alpn, "alpn extension found");
+            }
+            EXT_SUPPORTED_VERSIONS => {
+                info.supported_versions = parse_supported_versions_extension(ext_data)?;
+            }
+            EXT_ENCRYPTED_CLIENT_HELLO => {
+                ech_outer = is_outer_ech_extension(ext_data)?;
+            }
+            _ => {}
+        }
+    }
+
+    if ech_outer {
+        // SNI 扩展可能出现在 ECH 扩展之后，全部扫描完再确定
+        info.ech = EchStatus::GreaseOrReal {
+            outer_sni_present: info.sni.is_some(),
+        };
+        tracing::debug!(
+            outer_sni = info.sni.as_deref().unwrap_or("-"),
+            "client hello uses ECH"
+        );
+    }
+    if info.sni.is_none() {
+        tracing::debug!(ext_count, "sni extension not found");
     }
+    Ok(info)
+}
 
-    let list_length = u16::from_be_bytes([data[0], data[1]]) as usize;
+/// 取出完整的 ClientHello 消息体 (不含 4 字节的 Handshake 头) 和消耗的字节数
+///
+/// 只有 ClientHello 跨多个 TLS record 时才复制。
+fn client_hello_body(data: &[u8]) -> Result<(Cow<'_, [u8]>, usize), Stop> {
+    // 支持两种输入：
+    // 1) 传统 TCP+TLS：TLS record layer（开头 0x16）
+    // 2) QUIC CRYPTO stream：直接携带 TLS Handshake message（开头 0x01）
+    // 其它数据 (SSLv2、明文协议、随机字节) 直接拒绝
+    let (payload, consumed) = match classify_input(data) {
+        Some(InputKind::TlsRecord) => handshake_from_records(data)?,
+        Some(InputKind::RawHandshake) => (
+            Cow::Borrowed(data),
+            handshake_message_len(data).unwrap_or(0),
+        ),
+        Some(InputKind::Ssl2Hello) => return Err(SniError::Ssl2NotSupported.into()),
+        Some(InputKind::Unknown) => return Err(SniError::UnknownProtocol.into()),
+        // 判断 SSLv2 需要前 4 字节，与 Handshake 头一样长
+        None => return Err(Stop::NeedMoreData { need_at_least: 4 }),
+    };
 
-    if data.len() < 2 + list_length {
-        bail!(SniError::InvalidExtension);
+    // TLS Handshake: [msg_type(1)][len(3)][body...]
+    // 只有 QUIC CRYPTO 输入会走到这里：record 输入已凑够完整的 Handshake 消息
+    let Some(need) = handshake_message_len(&payload) else {
+        return Err(Stop::NeedMoreData { need_at_least: 4 });
+    };
+    if payload.len() < need {
+        return Err(Stop::NeedMoreData {
+            need_at_least: need,
+        });
     }
+    let client_hello = match payload {
+        Cow::Borrowed(payload) => Cow::Borrowed(&payload[4..need]),
+        Cow::Owned(mut payload) => {
+            payload.truncate(need);
+            payload.drain(..4);
+            Cow::Owned(payload)
+        }
+    };
+    Ok((client_hello, consumed))
+}
 
-    let mut offset = 2;
-    if offset + 3 > data.len() {
-        bail!(SniError::InvalidExtension);
+/// 跳过 ClientHello 的固定字段，返回 extensions 字段的内容；没有扩展时返回 None
+///
+/// ```text
+/// struct {
+///     ProtocolVersion legacy_version;
+///     Random random;                                  // 32 字节
+///     opaque legacy_session_id<0..32>;
+///     CipherSuite cipher_suites<2..2^16-2>;
+///     opaque legacy_compression_methods<1..2^8-1>;
+///     Extension extensions<8..2^16-1>;                // TLS 1.2 及以前可以省略
+/// } ClientHello;
+/// ```
+///
+/// Handshake 消息已经完整，固定字段越界说明格式错误 ([`SniError::DataTooShort`])，
+/// extensions 越界为 [`SniError::InvalidExtension`]；extensions 之后多余的字节被忽略。
+fn extensions_block(client_hello: &[u8]) -> Result<Option<&[u8]>, SniError> {
+    let mut reader = Reader::new(client_hello);
+    reader
+        .bytes(2 + 32)
+        .and_then(|_| reader.vec_u8())
+        .and_then(|_| reader.vec_u16())
+        .and_then(|_| reader.vec_u8())
+        .ok_or(SniError::DataTooShort)?;
+    if reader.is_empty() {
+        return Ok(None);
     }
+    reader.vec_u16().map(Some).ok_or(SniError::InvalidExtension)
+}
 
-    let name_type = data[offset];
-    offset += 1;
+/// 拆开 TLS record，返回其中的 Handshake 数据和消耗的字节数
+///
+/// ClientHello 可能被拆到多个连续的 handshake record 中 (部分客户端和基于分片的
+/// 抗审查工具会这样做)，这里按顺序拼接，直到第一个 Handshake 消息完整为止；
+/// 只有一个 record 时不复制。
+///
+/// ```text
+/// TLSPlaintext: [type(1)=0x16][version(2)][len(2)][fragment...]
+/// ```
+///
+/// 数据不够时 `need_at_least` 为下一步解析所需的最少字节数：缺 record 头时补齐头部，
+/// 缺 record 内容时补齐该 record，Handshake 消息还差 `n` 字节时至少还要一个 record 头加 `n`。
+fn handshake_from_records(data: &[u8]) -> Result<(Cow<'_, [u8]>, usize), Stop> {
+    let mut payload = Cow::Borrowed(&[][..]);
+    let mut offset = 0;
+    loop {
+        let Some(header) = data.get(offset..offset + 5) else {
+            let missing = match handshake_message_len(&payload) {
+                Some(need) => need - payload.len(),
+                None => 4 - payload.len(),
+            };
+            return Err(Stop::NeedMoreData {
+                need_at_least: offset + 5 + missing,
+            });
+        };
+        if header[0] != 0x16 {
+            return Err(SniError::NotHandshake.into());
+        }
+        let length = u16::from_be_bytes([header[3], header[4]]) as usize;
+        let Some(fragment) = data.get(offset + 5..offset + 5 + length) else {
+            return Err(Stop::NeedMoreData {
+                need_at_least: offset + 5 + length,
+            });
+        };
+        offset += 5 + length;
+        if payload.is_empty() {
+            payload = Cow::Borrowed(fragment);
+        } else {
+            payload.to_mut().extend_from_slice(fragment);
+        }
 
-    if name_type != 0x00 {
-        bail!(SniError::InvalidHostname);
+        if payload.first().is_some_and(|&msg_type| msg_type != 0x01) {
+            return Err(SniError::NotHandshake.into());
+        }
+        if handshake_message_len(&payload).is_some_and(|need| payload.len() >= need) {
+            return Ok((payload, offset));
+        }
     }
+}
 
-    let name_length = u16::from_be_bytes([data[offset], data[offset + 1]]) as usize;
-    offset += 2;
+/// 从客户端读取数据，直到包含完整的 ClientHello (可能跨多个 TLS record)
+///
+/// 每次读取最多等待 `timeout`。返回读到的全部数据，由调用方解析：对端提前关闭、
+/// 超过 [`MAX_CLIENT_HELLO_LEN`] 或数据不是 ClientHello 时同样返回；
+/// 对端没有发送任何数据就关闭时返回空；等待超时返回 [`io::ErrorKind::TimedOut`]。
+pub async fn read_client_hello<R>(reader: &mut R, timeout: Duration) -> io::Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut data = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = tokio::time::timeout(timeout, reader.read(&mut buf))
+            .await
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Timed out waiting for TLS ClientHello",
+                )
+            })??;
+        if n == 0 {
+            return Ok(data);
+        }
+        data.extend_from_slice(&buf[..n]);
 
-    if offset + name_length > data.len() {
-        bail!(SniError::InvalidExtension);
+        match parse_client_hello(&data) {
+            Ok(ClientHelloStatus::NeedMoreData { .. }) if data.len() < MAX_CLIENT_HELLO_LEN => {}
+            _ => return Ok(data),
+        }
     }
+}
 
-    let hostname_bytes = &data[offset..offset + name_length];
+/// 解析 server_name 扩展，返回第一个 host_name 项
+///
+/// ```text
+/// struct { NameType name_type; select (name_type) { case host_name: HostName; } name; } ServerName;
+/// struct { ServerName server_name_list<1..2^16-1> } ServerNameList;
+/// ```
+///
+/// 其它类型的项 (RFC 6066 之后未定义新类型，个别中间设备会插入) 跳过，按同样的
+/// 2 字节长度前缀计算其长度；没有 host_name 项时返回 None。多个 host_name 项的值
+/// 不同 (忽略大小写) 时无法确定目标，视为格式错误。
+fn parse_sni_extension(data: &[u8]) -> Result<Option<&str>, SniError> {
+    let mut extension = Reader::new(data);
+    let mut names = match extension.vec_u16() {
+        Some(list) if extension.is_empty() && !list.is_empty() => Reader::new(list),
+        _ => return Err(SniError::InvalidExtension),
+    };
 
-    let hostname =
-        String::from_utf8(hostname_bytes.to_vec()).map_err(|_| SniError::InvalidHostname)?;
+    let mut hostname: Option<&str> = None;
+    let mut skipped = 0;
+    while !names.is_empty() {
+        let name_type = names.u8().ok_or(SniError::InvalidExtension)?;
+        let name = names.vec_u16().ok_or(SniError::InvalidExtension)?;
+        if name_type != 0x00 {
+            tracing::debug!(name_type, "server_name entry of unknown type skipped");
+            skipped += 1;
+            continue;
+        }
 
-    if !is_valid_hostname(&hostname) {
-        bail!(SniError::InvalidHostname);
+        // 非 ASCII 不是合法的 UTF-8 也一样拒绝
+        let name = std::str::from_utf8(name)
+            .map_err(|_| SniError::InvalidHostname(HostnameError::NonAscii))?;
+        validate_hostname(name).map_err(SniError::InvalidHostname)?;
+        match hostname {
+            None => hostname = Some(name),
+            Some(first) if first.eq_ignore_ascii_case(name) => {}
+            Some(_) => return Err(SniError::ConflictingServerNames),
+        }
     }
 
-    tracing::debug!("Extracted SNI hostname: {}", hostname);
+    match hostname {
+        Some(hostname) => tracing::debug!(sni = %hostname, skipped, "sni extracted"),
+        None => tracing::debug!(skipped, "server_name extension has no host_name entry"),
+    }
     Ok(hostname)
 }
 
-fn is_valid_hostname(hostname: &str) -> bool {
-    if hostname.is_empty() || hostname.len() > 253 {
-        return false;
+/// 解析 ALPN 扩展中的 ProtocolNameList
+///
+/// ```text
+/// opaque ProtocolName<1..2^8-1>;
+/// struct { ProtocolName protocol_name_list<2..2^16-1> } ProtocolNameList;
+/// ```
+fn parse_alpn_extension(data: &[u8]) -> Result<Vec<String>, SniError> {
+    let mut extension = Reader::new(data);
+    let mut list = match extension.vec_u16() {
+        Some(list) if extension.is_empty() => Reader::new(list),
+        _ => return Err(SniError::InvalidExtension),
+    };
+
+    let mut protocols = Vec::new();
+    while !list.is_empty() {
+        match list.vec_u8() {
+            Some(name) if !name.is_empty() => {
+                protocols.push(String::from_utf8_lossy(name).into_owned())
+            }
+            _ => return Err(SniError::InvalidExtension),
+        }
+    }
+
+    if protocols.is_empty() {
+        return Err(SniError::InvalidExtension);
+    }
+    Ok(protocols)
+}
+
+/// encrypted_client_hello 扩展是否为 outer 类型
+///
+/// ```text
+/// enum { outer(0), inner(1) } ECHClientHelloType;
+/// struct { ECHClientHelloType type; select (type) { ... } } ECHClientHello;
+/// ```
+///
+/// 只看类型字段即可分类；outer 的 cipher_suite、enc、payload 对路由没有用处，不做解析。
+/// inner 类型只应出现在加密的内层 ClientHello 中，出现在明文里时忽略。
+fn is_outer_ech_extension(data: &[u8]) -> Result<bool, SniError> {
+    match data.first() {
+        Some(&ECH_OUTER) => Ok(true),
+        Some(ech_type) => {
+            tracing::debug!(ech_type, "non-outer ech extension ignored");
+            Ok(false)
+        }
+        None => Err(SniError::InvalidExtension),
+    }
+}
+
+/// 解析 ClientHello 中的 supported_versions 扩展，去掉 GREASE 值
+///
+/// ```text
+/// struct { ProtocolVersion versions<2..254> } SupportedVersions;
+/// ```
+fn parse_supported_versions_extension(data: &[u8]) -> Result<Vec<u16>, SniError> {
+    let Some((&len, list)) = data.split_first() else {
+        return Err(SniError::InvalidExtension);
+    };
+    if len as usize != list.len() || list.is_empty() || list.len() % 2 != 0 {
+        return Err(SniError::InvalidExtension);
+    }
+    Ok(list
+        .chunks_exact(2)
+        .map(|version| u16::from_be_bytes([version[0], version[1]]))
+        .filter(|&version| !is_grease(version))
+        .collect())
+}
+
+/// 检查 SNI 主机名 (RFC 6066 / RFC 1123)
+///
+/// 总长度不超过 253 字节，点分的每个标签 1-63 字节，只含字母、数字和连字符且不以
+/// 连字符开头或结尾。RFC 6066 要求 HostName 为 ASCII 且不带末尾的点，国际化域名
+/// 应由客户端转换成 `xn--` 形式的 A-label 后发送；个别客户端直接发送 U-label (含非
+/// ASCII 字符)，这里按其 A-label 形式检查 (见 [`to_ascii_hostname`])，是否接受由
+/// `rules.unicode_sni` 决定。是否接受 IP 地址形式的 SNI 由 `rules.reject_ip_sni` 决定，
+/// 不在这里检查。
+pub fn validate_hostname(hostname: &str) -> Result<(), HostnameError> {
+    to_ascii_hostname(hostname).map(drop)
+}
+
+/// 把 SNI 主机名转换为 ASCII 形式并按 [`validate_hostname`] 的规则检查
+///
+/// ASCII 主机名原样借用；U-label 按 IDNA (UTS #46) 转换成 `xn--` 形式的 A-label，
+/// 转换失败时返回 [`HostnameError::NonAscii`]。
+pub fn to_ascii_hostname(hostname: &str) -> Result<Cow<'_, str>, HostnameError> {
+    if hostname.is_ascii() {
+        validate_ldh(hostname)?;
+        return Ok(Cow::Borrowed(hostname));
     }
+    let ascii = idna::domain_to_ascii(hostname).map_err(|_| HostnameError::NonAscii)?;
+    validate_ldh(&ascii)?;
+    Ok(Cow::Owned(ascii))
+}
 
-    hostname
-        .chars()
-        .all(|c| c.is_alphanumeric() || c == '.' || c == '-')
+/// ASCII 主机名的长度和 LDH 检查
+fn validate_ldh(hostname: &str) -> Result<(), HostnameError> {
+    if hostname.is_empty() {
+        return Err(HostnameError::Empty);
+    }
+    if hostname.len() > 253 {
+        return Err(HostnameError::TooLong(hostname.len()));
+    }
+    for label in hostname.split('.') {
+        if label.is_empty() {
+            return Err(HostnameError::EmptyLabel);
+        }
+        if label.len() > 63 {
+            return Err(HostnameError::LabelTooLong(label.len()));
+        }
+        if let Some(c) = label
+            .chars()
+            .find(|&c| !c.is_ascii_alphanumeric() && c != '-')
+        {
+            return Err(HostnameError::InvalidCharacter(c));
+        }
+        if label.starts_with('-') || label.ends_with('-') {
+            return Err(HostnameError::HyphenAtLabelEdge);
+        }
+    }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::tls::fixtures;
 
     #[test]
     fn test_extract_sni_simple() {
@@ -283,9 +878,7 @@ mod tests {
         assert_eq!(data[0], 0x16);
         assert_eq!(data[5], 0x01);
 
-        let result = extract_sni(&data);
-        assert!(result.is_ok(), "extract_sni failed: {:?}", result);
-        assert_eq!(result.unwrap(), Some("test".to_string()));
+        assert_eq!(extract_sni(&data), Ok(SniStatus::Found("test".to_string())));
     }
 
     #[test]
@@ -325,23 +918,767 @@ mod tests {
         data[rec_pos] = (rec_len >> 8) as u8;
         data[rec_pos + 1] = (rec_len & 0xFF) as u8;
 
-        let result = extract_sni(&data);
-        assert!(result.is_ok());
-        assert_eq!(result.unwrap(), None);
+        assert_eq!(extract_sni(&data), Ok(SniStatus::NotPresent));
+
+        // 后面跟着的数据不计入
+        let len = data.len();
+        data.extend_from_slice(&[0x17, 0x03, 0x03]);
+        let info = complete(&data);
+        assert_eq!(info.consumed, len);
+        assert!(info.supported_versions.is_empty());
+        assert!(!info.offers_tls13());
     }
 
     #[test]
     fn test_data_too_short() {
+        // record 头都不完整：至少要有 record 头和 Handshake 头
         let data = [0x16, 0x03, 0x01];
-        assert!(extract_sni(&data).is_err());
+        assert_eq!(
+            extract_sni(&data),
+            Ok(SniStatus::NeedMoreData { need_at_least: 9 })
+        );
+    }
+
+    #[test]
+    fn test_handshake_message_len() {
+        assert_eq!(handshake_message_len(&[0x01, 0x00, 0x01]), None);
+        assert_eq!(
+            handshake_message_len(&[0x01, 0x00, 0x01, 0x02]),
+            Some(4 + 0x102)
+        );
+    }
+
+    #[test]
+    fn test_truncated_cipher_suites_length() {
+        // session_id 占满到 ClientHello 倒数第 1 字节，cipher suites 长度只剩 1 字节
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0u8; 32]);
+        body.push(2);
+        body.extend_from_slice(&[0u8; 3]);
+        assert_eq!(body.len(), 38);
+
+        let mut data = vec![0x01, 0x00, 0x00, body.len() as u8];
+        data.extend_from_slice(&body);
+        // 消息已完整，固定字段放不下就是格式错误，不当作没有扩展
+        assert_eq!(extract_sni(&data), Err(SniError::DataTooShort));
+    }
+
+    /// 解析完整的 ClientHello
+    fn complete(data: &[u8]) -> ClientHelloInfo {
+        match parse_client_hello(data) {
+            Ok(ClientHelloStatus::Complete(info)) => info,
+            other => panic!("expected a complete ClientHello, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_truncated_hello_needs_more_data() {
+        let handshake = client_hello_with(&sni_extension("www.example.com"));
+        let record = split_into_records(&handshake, &[]);
+        let cases = [
+            // QUIC CRYPTO：先要 Handshake 头，再要整个消息
+            (&handshake[..0], 4),
+            (&handshake[..3], 4),
+            (&handshake[..4], handshake.len()),
+            (&handshake[..handshake.len() - 1], handshake.len()),
+            // TLS record：先要 record 头和 Handshake 头，再要整个 record
+            (&record[..1], 9),
+            (&record[..5], record.len()),
+            (&record[..record.len() - 1], record.len()),
+        ];
+        for (data, need_at_least) in cases {
+            assert_eq!(
+                extract_sni(data),
+                Ok(SniStatus::NeedMoreData { need_at_least }),
+                "{} bytes",
+                data.len()
+            );
+        }
+        assert_eq!(
+            extract_sni(&record),
+            Ok(SniStatus::Found("www.example.com".to_string()))
+        );
+
+        // 第一个 record 之后还差的字节数
+        let split = split_into_records(&handshake, &[10]);
+        let first = 5 + 10;
+        assert_eq!(
+            extract_sni(&split[..first]),
+            Ok(SniStatus::NeedMoreData {
+                need_at_least: first + 5 + handshake.len() - 10
+            })
+        );
+    }
+
+    #[test]
+    fn test_corrupted_hello_is_malformed() {
+        let handshake = client_hello_with(&sni_extension("www.example.com"));
+
+        // Handshake 消息已完整但放不下 ClientHello 的固定字段
+        let mut short = vec![0x01, 0x00, 0x00, 0x10];
+        short.extend_from_slice(&[0u8; 16]);
+        assert_eq!(extract_sni(&short), Err(SniError::DataTooShort));
+
+        // 不是 ClientHello
+        let mut server_hello = handshake.clone();
+        server_hello[0] = 0x02;
+        assert_eq!(
+            extract_sni(&split_into_records(&server_hello, &[])),
+            Err(SniError::NotHandshake)
+        );
+        assert_eq!(extract_sni(&server_hello), Err(SniError::UnknownProtocol));
+
+        // extensions 总长度超出 ClientHello
+        let mut overrun = handshake.clone();
+        let ext_len_pos = 4 + 41;
+        overrun[ext_len_pos + 1] += 1;
+        assert_eq!(extract_sni(&overrun), Err(SniError::InvalidExtension));
+
+        // 主机名含非法字符
+        let bad = client_hello_with(&sni_extension("www.exa mple.com"));
+        assert_eq!(
+            extract_sni(&bad),
+            Err(SniError::InvalidHostname(HostnameError::InvalidCharacter(
+                ' '
+            )))
+        );
+    }
+
+    /// 构造 QUIC CRYPTO 流形式的 ClientHello (无 record 头)
+    fn client_hello_with(extensions: &[u8]) -> Vec<u8> {
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0u8; 32]);
+        body.push(0x00);
+        body.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]);
+        body.extend_from_slice(&[0x01, 0x00]);
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(extensions);
+
+        let mut data = vec![0x01, 0x00];
+        data.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        data.extend_from_slice(&body);
+        data
+    }
+
+    fn extension(ext_type: u16, data: &[u8]) -> Vec<u8> {
+        let mut ext = ext_type.to_be_bytes().to_vec();
+        ext.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        ext.extend_from_slice(data);
+        ext
+    }
+
+    fn sni_extension(name: &str) -> Vec<u8> {
+        let mut list = vec![0x00];
+        list.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        list.extend_from_slice(name.as_bytes());
+        let mut data = (list.len() as u16).to_be_bytes().to_vec();
+        data.extend_from_slice(&list);
+        extension(0x0000, &data)
+    }
+
+    /// 由 `(name_type, name)` 列表构造 server_name 扩展
+    fn server_name_extension(entries: &[(u8, &str)]) -> Vec<u8> {
+        let mut list = Vec::new();
+        for (name_type, name) in entries {
+            list.push(*name_type);
+            list.extend_from_slice(&(name.len() as u16).to_be_bytes());
+            list.extend_from_slice(name.as_bytes());
+        }
+        let mut data = (list.len() as u16).to_be_bytes().to_vec();
+        data.extend_from_slice(&list);
+        extension(EXT_SERVER_NAME, &data)
+    }
+
+    #[test]
+    fn test_server_name_list_entries() {
+        let sni = |entries: &[(u8, &str)]| {
+            extract_sni(&client_hello_with(&server_name_extension(entries)))
+        };
+        let found = |name: &str| Ok(SniStatus::Found(name.to_string()));
+
+        assert_eq!(sni(&[(0, "www.example.com")]), found("www.example.com"));
+        // 未知类型在前，跳过
+        assert_eq!(
+            sni(&[(7, "opaque \x00 data"), (0, "www.example.com")]),
+            found("www.example.com")
+        );
+        assert_eq!(sni(&[(7, "opaque")]), Ok(SniStatus::NotPresent));
+        // 重复且相同 (忽略大小写) 的 host_name 取第一个
+        assert_eq!(
+            sni(&[(0, "www.example.com"), (0, "WWW.example.com")]),
+            found("www.example.com")
+        );
+        assert_eq!(
+            sni(&[(0, "www.example.com"), (0, "evil.example.com")]),
+            Err(SniError::ConflictingServerNames)
+        );
+        // 空列表、列表后有多余字节
+        assert_eq!(sni(&[]), Err(SniError::InvalidExtension));
+        let mut trailing = server_name_extension(&[(0, "a.com")]);
+        trailing[3] += 1;
+        trailing.push(0);
+        assert_eq!(
+            extract_sni(&client_hello_with(&trailing)),
+            Err(SniError::InvalidExtension)
+        );
+
+        let info = complete(&client_hello_with(&server_name_extension(&[
+            (1, "x"),
+            (0, "www.example.com"),
+        ])));
+        assert_eq!(info.sni.as_deref(), Some("www.example.com"));
+    }
+
+    #[test]
+    fn test_ech_with_outer_sni() {
+        // ECH 扩展在 SNI 之前也要识别
+        let mut extensions = extension(EXT_ENCRYPTED_CLIENT_HELLO, &[ECH_OUTER, 0x00, 0x01]);
+        extensions.extend_from_slice(&sni_extension("public.example.com"));
+
+        let info = complete(&client_hello_with(&extensions));
+        assert_eq!(info.sni.as_deref(), Some("public.example.com"));
+        assert_eq!(
+            info.ech,
+            EchStatus::GreaseOrReal {
+                outer_sni_present: true
+            }
+        );
+    }
+
+    #[test]
+    fn test_ech_without_sni() {
+        let extensions = extension(EXT_ENCRYPTED_CLIENT_HELLO, &[ECH_OUTER, 0xaa, 0xbb]);
+        let info = complete(&client_hello_with(&extensions));
+        assert_eq!(info.sni, None);
+        assert_eq!(
+            info.ech,
+            EchStatus::GreaseOrReal {
+                outer_sni_present: false
+            }
+        );
+
+        // 空的 ECH 扩展不合法
+        let extensions = extension(EXT_ENCRYPTED_CLIENT_HELLO, &[]);
+        assert!(parse_client_hello(&client_hello_with(&extensions)).is_err());
+    }
+
+    fn alpn_extension(protocols: &[&[u8]]) -> Vec<u8> {
+        let mut list = Vec::new();
+        for protocol in protocols {
+            list.push(protocol.len() as u8);
+            list.extend_from_slice(protocol);
+        }
+        let mut data = (list.len() as u16).to_be_bytes().to_vec();
+        data.extend_from_slice(&list);
+        extension(EXT_ALPN, &data)
+    }
+
+    #[test]
+    fn test_alpn_is_extracted_in_order() {
+        let mut extensions = sni_extension("www.example.com");
+        extensions.extend_from_slice(&alpn_extension(&[b"h3", b"h3-29"]));
+
+        let info = complete(&client_hello_with(&extensions));
+        assert_eq!(info.alpn, vec!["h3".to_string(), "h3-29".to_string()]);
+
+        let info = complete(&client_hello_with(&sni_extension("www.example.com")));
+        assert!(info.alpn.is_empty());
+    }
+
+    #[test]
+    fn test_malformed_alpn_is_rejected() {
+        for data in [
+            &[][..],
+            &[0x00, 0x00][..],             // 空列表
+            &[0x00, 0x03, 0x02, b'h'][..], // 列表长度与扩展长度不符
+            &[0x00, 0x02, 0x00, 0x00][..], // 空协议名
+            &[0x00, 0x02, 0x05, b'h'][..], // 协议名越界
+        ] {
+            let mut extensions = sni_extension("www.example.com");
+            extensions.extend_from_slice(&extension(EXT_ALPN, data));
+            assert!(parse_client_hello(&client_hello_with(&extensions)).is_err());
+        }
+    }
+
+    fn supported_versions_extension(versions: &[u16]) -> Vec<u8> {
+        let mut data = vec![(versions.len() * 2) as u8];
+        for version in versions {
+            data.extend_from_slice(&version.to_be_bytes());
+        }
+        extension(EXT_SUPPORTED_VERSIONS, &data)
+    }
+
+    #[test]
+    fn test_supported_versions_skip_grease() {
+        // Chrome 风格：GREASE 扩展和 GREASE 版本号穿插其中
+        let mut extensions = extension(0x0a0a, &[]);
+        extensions.extend_from_slice(&sni_extension("www.example.com"));
+        extensions.extend_from_slice(&supported_versions_extension(&[0x7a7a, TLS13, 0x0303]));
+        extensions.extend_from_slice(&alpn_extension(&[b"h2", b"http/1.1"]));
+        extensions.extend_from_slice(&extension(0xdada, &[0x00]));
+        let hello = client_hello_with(&extensions);
+
+        let info = complete(&hello);
+        assert_eq!(info.sni.as_deref(), Some("www.example.com"));
+        assert_eq!(info.supported_versions, vec![TLS13, 0x0303]);
+        assert!(info.offers_tls13());
+        assert_eq!(info.alpn, vec!["h2".to_string(), "http/1.1".to_string()]);
+        assert_eq!(info.ech, EchStatus::Absent);
+        assert_eq!(info.consumed, hello.len());
+
+        // 只有 TLS 1.2
+        let info = complete(&client_hello_with(&supported_versions_extension(&[0x0303])));
+        assert_eq!(info.sni, None);
+        assert!(!info.offers_tls13());
+
+        for data in [
+            &[][..],
+            &[0x00][..],
+            &[0x03, 0x03, 0x04, 0x03][..],
+            &[0x04, 0x03, 0x04][..],
+        ] {
+            let extensions = extension(EXT_SUPPORTED_VERSIONS, data);
+            assert!(parse_client_hello(&client_hello_with(&extensions)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_is_grease() {
+        for value in (0..16u16).map(|i| (i << 12) | 0x0a00 | (i << 4) | 0x0a) {
+            assert!(is_grease(value), "{:#06x}", value);
+        }
+        for value in [0x0000, 0x0304, 0x0a1a, 0x1a0a, 0xfafb] {
+            assert!(!is_grease(value), "{:#06x}", value);
+        }
+    }
+
+    #[test]
+    fn test_extension_iterator() {
+        let mut data = extension(0x0a0a, &[]);
+        data.extend_from_slice(&extension(EXT_ALPN, &[1, 2, 3]));
+        let extensions: Vec<_> = Extensions::new(&data).map(Result::unwrap).collect();
+        assert_eq!(
+            extensions,
+            vec![(0x0a0a, &[][..]), (EXT_ALPN, &[1, 2, 3][..])]
+        );
+
+        // 末尾不足一个扩展头
+        data.extend_from_slice(&[0x00, 0x01]);
+        let mut extensions = Extensions::new(&data);
+        assert!(extensions.nth(2).unwrap().is_err());
+        assert!(extensions.next().is_none());
+
+        // 扩展数据越界
+        let mut extensions = Extensions::new(&[0x00, 0x10, 0x00, 0x05, 0x01]);
+        assert!(extensions.next().unwrap().is_err());
+        assert!(extensions.next().is_none());
+    }
+
+    /// 把 Handshake 消息在 `cuts` 处拆成多个 TLS record
+    fn split_into_records(handshake: &[u8], cuts: &[usize]) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut start = 0;
+        for &end in cuts.iter().chain([handshake.len()].iter()) {
+            data.extend_from_slice(&[0x16, 0x03, 0x01]);
+            data.extend_from_slice(&((end - start) as u16).to_be_bytes());
+            data.extend_from_slice(&handshake[start..end]);
+            start = end;
+        }
+        data
+    }
+
+    #[test]
+    fn test_extract_sni_ref_borrows_from_input() {
+        let handshake = client_hello_with(&sni_extension("www.example.com"));
+        let record = split_into_records(&handshake, &[]);
+
+        for data in [&handshake, &record] {
+            let Ok(SniStatus::Found(Cow::Borrowed(sni))) = extract_sni_ref(data) else {
+                panic!("{:?}", extract_sni_ref(data));
+            };
+            assert_eq!(sni, "www.example.com");
+            // 主机名就是输入中的那段字节
+            let start = sni.as_ptr() as usize - data.as_ptr() as usize;
+            assert!(start + sni.len() <= data.len());
+            assert_eq!(&data[start..start + sni.len()], b"www.example.com");
+        }
+
+        // 跨 record 时只能复制
+        let split = split_into_records(&handshake, &[10]);
+        let Ok(SniStatus::Found(Cow::Owned(sni))) = extract_sni_ref(&split) else {
+            panic!("{:?}", extract_sni_ref(&split));
+        };
+        assert_eq!(sni, "www.example.com");
+        assert_eq!(
+            extract_sni_ref(&split[..20]),
+            Ok(SniStatus::NeedMoreData {
+                need_at_least: split.len()
+            })
+        );
+        assert_eq!(
+            extract_sni_ref(&client_hello_with(&[])),
+            Ok(SniStatus::NotPresent)
+        );
+    }
+
+    #[test]
+    fn test_client_hello_split_across_records() {
+        let mut extensions = alpn_extension(&[b"h2"]);
+        extensions.extend_from_slice(&sni_extension("split.example.com"));
+        let handshake = client_hello_with(&extensions);
+        // ALPN 扩展从 ClientHello 第 43 字节开始，SNI 主机名在末尾 17 字节
+        let mid_extension = 4 + 43 + 3;
+        let mid_sni = handshake.len() - 8;
+
+        for cuts in [
+            &[2][..],             // Handshake 头中间
+            &[mid_extension][..], // 扩展头中间
+            &[mid_sni][..],       // SNI 主机名中间
+            &[1, mid_extension, mid_sni][..],
+        ] {
+            let data = split_into_records(&handshake, cuts);
+            let info = complete(&data);
+            assert_eq!(info.sni.as_deref(), Some("split.example.com"), "{:?}", cuts);
+            assert_eq!(info.alpn, vec!["h2".to_string()]);
+            assert_eq!(info.consumed, data.len());
+
+            // 任何更短的前缀都只是还没收全
+            for len in 1..data.len() {
+                match parse_client_hello(&data[..len]) {
+                    Ok(ClientHelloStatus::NeedMoreData { need_at_least }) => {
+                        assert!(need_at_least > len, "{:?} at {}", cuts, len)
+                    }
+                    other => panic!("{:?} at {}: {:?}", cuts, len, other),
+                }
+            }
+        }
+
+        // 后续 record 不是 handshake 类型
+        let mut data = split_into_records(&handshake[..mid_sni], &[]);
+        data.extend_from_slice(&[0x17, 0x03, 0x03, 0x00, 0x01, 0x00]);
+        assert_eq!(parse_client_hello(&data), Err(SniError::NotHandshake));
+    }
+
+    #[tokio::test]
+    async fn test_read_client_hello_waits_for_all_records() {
+        let handshake = client_hello_with(&sni_extension("split.example.com"));
+        let data = split_into_records(&handshake, &[40, 60]);
+        let (mut client, mut server) = tokio::io::duplex(1024);
+
+        let writer = {
+            let data = data.clone();
+            tokio::spawn(async move {
+                use tokio::io::AsyncWriteExt;
+                for chunk in data.chunks(30) {
+                    client.write_all(chunk).await.unwrap();
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+                client
+            })
+        };
+        let read = read_client_hello(&mut server, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(read, data);
+        assert_eq!(
+            extract_sni(&read),
+            Ok(SniStatus::Found("split.example.com".to_string()))
+        );
+
+        // 对端没有发送数据就关闭
+        drop(writer.await.unwrap());
+        let (client, mut server) = tokio::io::duplex(1024);
+        drop(client);
+        assert!(read_client_hello(&mut server, Duration::from_secs(5))
+            .await
+            .unwrap()
+            .is_empty());
+
+        // 对端不发送数据也不关闭
+        let (_client, mut server) = tokio::io::duplex(1024);
+        let error = read_client_hello(&mut server, Duration::from_millis(10))
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_client_hello_corpus_golden() {
+        let corpus = fixtures::client_hellos();
+        assert!(corpus.len() >= 9, "fixture corpus is incomplete");
+        for fixture in &corpus {
+            let name = &fixture.name;
+            let expected = &fixture.expected;
+            let info = complete(&fixture.data);
+            assert_eq!(info.sni, expected.sni, "{}", name);
+            assert_eq!(info.alpn, expected.alpn, "{}", name);
+            let versions: Vec<String> = info
+                .supported_versions
+                .iter()
+                .map(|&v| version_name(v).into_owned())
+                .collect();
+            assert_eq!(versions, expected.supported_versions, "{}", name);
+            assert_eq!(info.ech.is_present(), expected.ech, "{}", name);
+            assert_eq!(
+                info.record_version.map(|v| version_name(v).into_owned()),
+                expected.record_version,
+                "{}",
+                name
+            );
+            assert_eq!(
+                version_name(info.legacy_version),
+                expected.legacy_version,
+                "{}",
+                name
+            );
+            assert_eq!(
+                info.record_version.is_some(),
+                fixture.transport == fixtures::Transport::Tcp,
+                "{}",
+                name
+            );
+            assert_eq!(info.consumed, fixture.data.len(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_browser_ech_grease_fixtures() {
+        for name in ["chrome_tcp_ech_grease", "firefox_tcp_ech_grease"] {
+            let data = fixtures::client_hello(name).data;
+            let info = complete(&data);
+            assert_eq!(
+                info.ech,
+                EchStatus::GreaseOrReal {
+                    outer_sni_present: true
+                },
+                "{}",
+                name
+            );
+            assert_eq!(info.ech.to_string(), "grease-or-real");
+            assert_eq!(info.supported_versions, vec![TLS13, 0x0303]);
+        }
+    }
+
+    #[test]
+    fn test_openssl_client_hello_fixture() {
+        let data = fixtures::client_hello("openssl_s_client_tcp").data;
+        let info = complete(&data);
+        assert_eq!(info.ech, EchStatus::Absent);
+        assert!(info.offers_tls13());
+        assert_eq!(info.max_version(), TLS13);
+
+        // QUIC CRYPTO 形式没有 record 头
+        let info = complete(&data[5..]);
+        assert_eq!(info.record_version, None);
+        assert_eq!(info.legacy_version, 0x0303);
+        assert_eq!(version_name(info.max_version()), "TLS 1.3");
+    }
+
+    /// 固定种子的 xorshift，变异测试可重现
+    fn xorshift(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    /// 解析任意数据不能 panic；找到的 SNI 必须在输入范围内
+    fn check_arbitrary(data: &[u8]) {
+        let _ = parse_client_hello(data);
+        if let Ok(SniStatus::Found(Cow::Borrowed(sni))) = extract_sni_ref(data) {
+            let range = data.as_ptr_range();
+            assert!(range.contains(&sni.as_ptr()));
+            assert!(sni.as_ptr() as usize + sni.len() <= range.end as usize);
+        }
+    }
+
+    #[test]
+    fn test_mutated_length_fields_do_not_panic() {
+        let mut fixtures: Vec<Vec<u8>> = fixtures::client_hellos()
+            .into_iter()
+            .map(|fixture| fixture.data)
+            .collect();
+        fixtures.push(client_hello_with(&sni_extension("www.example.com")));
+
+        let mut state = 0x3677_u64;
+        for fixture in &fixtures {
+            for len in 0..fixture.len() {
+                check_arbitrary(&fixture[..len]);
+            }
+            for _ in 0..2000 {
+                let mut data = fixture.clone();
+                // 长度字段多为 0x00/0xff 附近的极值，偏向这些取值
+                for _ in 0..1 + xorshift(&mut state) % 3 {
+                    let pos = (xorshift(&mut state) as usize) % data.len();
+                    data[pos] = match xorshift(&mut state) % 4 {
+                        0 => 0xff,
+                        1 => 0x00,
+                        _ => xorshift(&mut state) as u8,
+                    };
+                }
+                check_arbitrary(&data);
+                // 同时变异 record 内的 QUIC 形式 (去掉 record 头)
+                if data.first() == Some(&0x16) {
+                    check_arbitrary(&data[5..]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_oversized_extension_lengths() {
+        let handshake = client_hello_with(&sni_extension("www.example.com"));
+        let ext_len_pos = 4 + 41;
+
+        // extensions_length = 0xffff，超出消息
+        let mut data = handshake.clone();
+        data[ext_len_pos..ext_len_pos + 2].copy_from_slice(&[0xff, 0xff]);
+        assert_eq!(extract_sni(&data), Err(SniError::InvalidExtension));
+
+        // 单个扩展长度 = 0xffff
+        let mut data = handshake.clone();
+        data[ext_len_pos + 4..ext_len_pos + 6].copy_from_slice(&[0xff, 0xff]);
+        assert_eq!(extract_sni(&data), Err(SniError::InvalidExtension));
+        assert_eq!(parse_client_hello(&data), Err(SniError::InvalidExtension));
+
+        // server_name 列表长度超出扩展
+        let mut data = handshake.clone();
+        data[ext_len_pos + 6..ext_len_pos + 8].copy_from_slice(&[0xff, 0xff]);
+        assert_eq!(extract_sni(&data), Err(SniError::InvalidExtension));
+    }
+
+    #[test]
+    fn test_inner_ech_type_is_ignored() {
+        let mut extensions = sni_extension("www.example.com");
+        extensions.extend_from_slice(&extension(EXT_ENCRYPTED_CLIENT_HELLO, &[0x01]));
+        let info = complete(&client_hello_with(&extensions));
+        assert_eq!(info.ech, EchStatus::Absent);
+        assert!(!info.ech.is_present());
+    }
+
+    #[test]
+    fn test_plain_client_hello_is_not_ech() {
+        let info = complete(&client_hello_with(&sni_extension("www.example.com")));
+        assert_eq!(info.sni.as_deref(), Some("www.example.com"));
+        assert_eq!(info.ech, EchStatus::Absent);
+    }
+
+    #[test]
+    fn test_non_tls_input_is_classified() {
+        let sslv2 = include_bytes!("../../tests/fixtures/sslv2_client_hello.bin");
+        let random = include_bytes!("../../tests/fixtures/random_bytes.bin");
+        let handshake = client_hello_with(&[]);
+
+        assert_eq!(classify_input(&handshake), Some(InputKind::RawHandshake));
+        assert_eq!(
+            classify_input(&split_into_records(&handshake, &[])),
+            Some(InputKind::TlsRecord)
+        );
+        assert_eq!(classify_input(sslv2), Some(InputKind::Ssl2Hello));
+        assert_eq!(classify_input(random), Some(InputKind::Unknown));
+        assert_eq!(classify_input(b"GET / HTTP/1.1"), Some(InputKind::Unknown));
+        // 最高位为 1 的随机数据不是 SSLv2
+        assert_eq!(
+            classify_input(&[0x80, 0x10, 0x02, 0x00]),
+            Some(InputKind::Unknown)
+        );
+        assert_eq!(classify_input(&sslv2[..3]), None);
+        assert_eq!(classify_input(&[]), None);
+
+        assert_eq!(extract_sni(sslv2), Err(SniError::Ssl2NotSupported));
+        assert_eq!(extract_sni(random), Err(SniError::UnknownProtocol));
+        assert_eq!(
+            extract_sni(b"GET / HTTP/1.1"),
+            Err(SniError::UnknownProtocol)
+        );
+        assert!(SniError::Ssl2NotSupported.is_scanner_noise());
+        assert!(!SniError::NotHandshake.is_scanner_noise());
+        assert_eq!(
+            extract_sni(&sslv2[..2]),
+            Ok(SniStatus::NeedMoreData { need_at_least: 4 })
+        );
     }
 
     #[test]
     fn test_hostname_validation() {
-        assert!(is_valid_hostname("www.google.com"));
-        assert!(is_valid_hostname("example.com"));
-        assert!(is_valid_hostname("test"));
-        assert!(!is_valid_hostname(""));
-        assert!(is_valid_hostname("test中文.com")); // 简化验证,允许中文
+        assert_eq!(validate_hostname("www.google.com"), Ok(()));
+        assert_eq!(validate_hostname("example.com"), Ok(()));
+        assert_eq!(validate_hostname("test"), Ok(()));
+        assert_eq!(validate_hostname("xn--test-3f5fy05j.com"), Ok(()));
+        assert_eq!(validate_hostname("a-b.c0.example"), Ok(()));
+        assert_eq!(validate_hostname(""), Err(HostnameError::Empty));
+        // U-label 按 A-label 形式检查
+        assert_eq!(validate_hostname("test中文.com"), Ok(()));
+        assert_eq!(
+            to_ascii_hostname("test中文.com").as_deref(),
+            Ok("xn--test-3f5fy05j.com")
+        );
+        assert!(matches!(
+            to_ascii_hostname("example.com"),
+            Ok(Cow::Borrowed("example.com"))
+        ));
+        assert_eq!(
+            validate_hostname("\u{301}中文.com"),
+            Err(HostnameError::NonAscii)
+        );
+        assert_eq!(
+            validate_hostname("中文_x.com"),
+            Err(HostnameError::InvalidCharacter('_'))
+        );
+        assert_eq!(validate_hostname("....."), Err(HostnameError::EmptyLabel));
+        assert_eq!(
+            validate_hostname("example.com."),
+            Err(HostnameError::EmptyLabel)
+        );
+        assert_eq!(
+            validate_hostname("-a.com"),
+            Err(HostnameError::HyphenAtLabelEdge)
+        );
+        assert_eq!(
+            validate_hostname("a-.com"),
+            Err(HostnameError::HyphenAtLabelEdge)
+        );
+        assert_eq!(
+            validate_hostname("under_score.com"),
+            Err(HostnameError::InvalidCharacter('_'))
+        );
+        assert_eq!(validate_hostname(&"a".repeat(63)), Ok(()));
+        assert_eq!(
+            validate_hostname(&format!("{}.com", "a".repeat(64))),
+            Err(HostnameError::LabelTooLong(64))
+        );
+        let long = vec!["a".repeat(63); 4].join(".");
+        assert_eq!(long.len(), 255);
+        assert_eq!(validate_hostname(&long), Err(HostnameError::TooLong(255)));
+        assert_eq!(validate_hostname(&long[2..]), Ok(()));
+    }
+
+    #[test]
+    fn test_unicode_sni_returned_as_sent() {
+        // 转换与否由 rules.unicode_sni 决定 (见 Router::sni_hostname)
+        let hello = client_hello_with(&sni_extension("test中文.com"));
+        assert_eq!(
+            extract_sni(&hello),
+            Ok(SniStatus::Found("test中文.com".to_string()))
+        );
+        let hello = client_hello_with(&sni_extension("\u{301}中文.com"));
+        assert_eq!(
+            extract_sni(&hello),
+            Err(SniError::InvalidHostname(HostnameError::NonAscii))
+        );
+    }
+
+    #[test]
+    fn test_invalid_sni_reports_reason() {
+        let hello = client_hello_with(&sni_extension("-a.com"));
+        let error = extract_sni(&hello).unwrap_err();
+        assert_eq!(
+            error,
+            SniError::InvalidHostname(HostnameError::HyphenAtLabelEdge)
+        );
+        assert_eq!(
+            error.to_string(),
+            "Invalid hostname: label starts or ends with a hyphen"
+        );
     }
 }
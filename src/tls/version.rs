@@ -0,0 +1,128 @@
+//! ClientHello 版本检查 (`[tls]`)
+//!
+//! 只用于 TCP 入口：QUIC 固定使用 TLS 1.3，没有 record 头。
+
+use crate::config::TlsVersion;
+use crate::tls::sni::{version_name, ClientHelloInfo};
+use std::fmt;
+
+/// ClientHello 中值得注意的版本信息
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VersionAnomaly {
+    /// record 版本不在 TLS 1.0 - 1.2 之间 (例如 SSL 3.0 的 0x0300)
+    UnusualRecordVersion(u16),
+    /// 客户端支持的最高版本低于 `tls.min_accept_version`
+    BelowMinimum { max: u16, min: u16 },
+}
+
+impl VersionAnomaly {
+    /// 是否为 `tls.reject_below_min_version` 拒绝的情况
+    pub fn is_below_minimum(&self) -> bool {
+        matches!(self, VersionAnomaly::BelowMinimum { .. })
+    }
+}
+
+impl fmt::Display for VersionAnomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VersionAnomaly::UnusualRecordVersion(version) => {
+                write!(f, "unusual record version {}", version_name(*version))
+            }
+            VersionAnomaly::BelowMinimum { max, min } => write!(
+                f,
+                "highest offered version {} is below {}",
+                version_name(*max),
+                version_name(*min)
+            ),
+        }
+    }
+}
+
+/// 检查 ClientHello 的版本，`min` 为 `tls.min_accept_version`
+pub fn version_anomalies(hello: &ClientHelloInfo, min: TlsVersion) -> Vec<VersionAnomaly> {
+    let mut anomalies = Vec::new();
+    // 客户端的第一个 record 通常为 0x0301 (兼容旧服务器) 或 0x0303
+    if let Some(version) = hello
+        .record_version
+        .filter(|version| !(0x0301..=0x0303).contains(version))
+    {
+        anomalies.push(VersionAnomaly::UnusualRecordVersion(version));
+    }
+    if hello.max_version() < min.wire() {
+        anomalies.push(VersionAnomaly::BelowMinimum {
+            max: hello.max_version(),
+            min: min.wire(),
+        });
+    }
+    anomalies
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tls::sni::TLS13;
+
+    fn hello(
+        record_version: Option<u16>,
+        legacy_version: u16,
+        versions: &[u16],
+    ) -> ClientHelloInfo {
+        ClientHelloInfo {
+            record_version,
+            legacy_version,
+            supported_versions: versions.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn modern_client_has_no_anomalies() {
+        let modern = hello(Some(0x0301), 0x0303, &[TLS13, 0x0303]);
+        assert_eq!(modern.max_version(), TLS13);
+        assert!(version_anomalies(&modern, TlsVersion::Tls13).is_empty());
+        // QUIC 输入没有 record 头
+        assert!(version_anomalies(&hello(None, 0x0303, &[TLS13]), TlsVersion::Tls12).is_empty());
+    }
+
+    #[test]
+    fn old_clients_are_flagged() {
+        // 没有 supported_versions 时按 legacy_version
+        let tls10 = hello(Some(0x0301), 0x0301, &[]);
+        let anomalies = version_anomalies(&tls10, TlsVersion::Tls12);
+        assert_eq!(
+            anomalies,
+            vec![VersionAnomaly::BelowMinimum {
+                max: 0x0301,
+                min: 0x0303
+            }]
+        );
+        assert!(anomalies[0].is_below_minimum());
+        assert_eq!(
+            anomalies[0].to_string(),
+            "highest offered version TLS 1.0 is below TLS 1.2"
+        );
+        assert!(version_anomalies(&tls10, TlsVersion::Tls10).is_empty());
+
+        // 只支持 TLS 1.2 的客户端在要求 1.3 时被标记
+        let tls12 = hello(Some(0x0301), 0x0303, &[]);
+        assert!(version_anomalies(&tls12, TlsVersion::Tls12).is_empty());
+        assert!(version_anomalies(&tls12, TlsVersion::Tls13)[0].is_below_minimum());
+    }
+
+    #[test]
+    fn unusual_record_version_is_flagged() {
+        let ssl3_record = hello(Some(0x0300), 0x0303, &[TLS13]);
+        let anomalies = version_anomalies(&ssl3_record, TlsVersion::Tls12);
+        assert_eq!(
+            anomalies,
+            vec![VersionAnomaly::UnusualRecordVersion(0x0300)]
+        );
+        assert!(!anomalies[0].is_below_minimum());
+        assert_eq!(anomalies[0].to_string(), "unusual record version SSL 3.0");
+        assert_eq!(
+            version_anomalies(&hello(Some(0x7f1c), 0x0303, &[TLS13]), TlsVersion::Tls12)[0]
+                .to_string(),
+            "unusual record version 0x7f1c"
+        );
+    }
+}
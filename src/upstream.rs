@@ -0,0 +1,831 @@
+//! SOCKS5 上游集合
+//!
+//! `[socks5]` 本身是名为 [`DEFAULT_UPSTREAM`] 的上游，`[[socks5.upstreams]]` 追加
+//! 更多上游。`[[rules.routes]]` 把匹配的域名固定到某个上游；没有规则的连接按
+//! `socks5.balance` ([`BalanceStrategy`]) 分散到健康的上游，默认按调用方给出的键
+//! (QUIC 为 DCID，TCP 为 SNI) 做 rendezvous 哈希。
+//!
+//! 每个上游用原子计数记录在途的 TCP 连接和 QUIC 会话 ([`ActiveSlot`] 存活期间计入)，
+//! `least_connections` 据此选择；选择本身只读原子变量，不持有全局锁。
+//!
+//! 健康状态是被动的，TCP 和 QUIC 共用同一个集合：连续 [`FAILURE_THRESHOLD`] 次
+//! 连接失败后上游被标记为不可用，不再参与哈希分散，[`RETRY_AFTER`] 后重新参与；
+//! 成功一次即恢复。规则指定的上游不可用时仍然使用 (规则是策略而非负载分散)。
+//!
+//! 启用主动健康检查 (见 [`crate::health`]) 时，探测结果另外维护一个 [`BackendState`]：
+//! 探测连续失败 [`FAILURE_THRESHOLD`] 次为 [`BackendState::Down`]，同样不参与分散，
+//! 直到探测再次成功。[`UpstreamSet::health`] 给出各上游状态的快照，管理端口的
+//! `/upstreams` 以 JSON 返回，状态和状态变化次数另见 [`crate::metrics::register_upstream_health`]。
+//!
+//! 集合内的上游共享一个 [`LatencyMetrics`]，按上游名称统计 SOCKS5 握手耗时。
+//! 每个上游有一个 [`ProxyResolver`]，到该上游的所有连接共享代理地址的解析缓存。
+
+use crate::config::{BalanceStrategy, Config, ConfigError, Socks5Config, UpstreamProtocol};
+use crate::outbound::ProxyResolver;
+use crate::socks5::{LatencyMetrics, MetricsSink};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// `[socks5]` 对应的上游名称
+pub const DEFAULT_UPSTREAM: &str = "default";
+
+/// 连续失败多少次后标记为不可用
+pub const FAILURE_THRESHOLD: u32 = 3;
+
+/// 不可用的上游多久后重新参与选择
+pub const RETRY_AFTER: Duration = Duration::from_secs(30);
+
+/// 探测耗时 EWMA 中新样本的权重
+const LATENCY_EWMA_WEIGHT: f64 = 0.3;
+
+/// 被动健康状态
+#[derive(Debug, Default)]
+struct Health {
+    consecutive_failures: u32,
+    /// 被标记为不可用的时间
+    down_since: Option<Instant>,
+}
+
+/// 主动健康检查判定的上游状态
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendState {
+    /// 最近一次探测成功 (或尚未探测)
+    #[default]
+    Healthy,
+    /// 探测失败，但未达到 [`FAILURE_THRESHOLD`] 次
+    Degraded,
+    /// 探测连续失败 [`FAILURE_THRESHOLD`] 次，不参与负载分散
+    Down,
+}
+
+impl fmt::Display for BackendState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BackendState::Healthy => "healthy",
+            BackendState::Degraded => "degraded",
+            BackendState::Down => "down",
+        })
+    }
+}
+
+/// 主动探测的结果
+#[derive(Debug, Default)]
+struct ProbeHealth {
+    state: BackendState,
+    consecutive_failures: u32,
+    last_error: Option<String>,
+    latency_ewma: Option<Duration>,
+    /// 状态变化的次数 (累计)
+    transitions: u64,
+    /// 最近一次探测成功的时刻，之后的探测失败时清空
+    last_success: Option<Instant>,
+}
+
+/// 一个上游的健康状态快照，可序列化为 JSON (耗时以毫秒输出)
+#[derive(Debug, Clone, Serialize)]
+pub struct UpstreamHealth {
+    pub name: String,
+    pub addr: String,
+    /// 主动探测判定的状态
+    pub state: BackendState,
+    /// 是否参与负载分散 (综合主动与被动健康状态)
+    pub selectable: bool,
+    /// 探测连续失败次数
+    pub consecutive_failures: u32,
+    /// 最近一次探测失败的原因
+    pub last_error: Option<String>,
+    /// 探测耗时的 EWMA
+    #[serde(rename = "latency_ewma_ms", serialize_with = "serialize_millis")]
+    pub latency_ewma: Option<Duration>,
+    /// 状态变化的次数
+    pub transitions: u64,
+    /// 累计分配到该上游的连接/会话数
+    pub assigned: u64,
+    /// 在途的 TCP 连接数
+    pub active_streams: u64,
+    /// 在途的 QUIC 会话数
+    pub active_sessions: u64,
+    pub weight: u32,
+}
+
+/// 上游的一个在途连接或会话，丢弃时计数减一
+#[derive(Debug)]
+pub struct ActiveSlot(Arc<AtomicU64>);
+
+impl ActiveSlot {
+    fn open(counter: &Arc<AtomicU64>) -> Self {
+        counter.fetch_add(1, Ordering::Relaxed);
+        Self(Arc::clone(counter))
+    }
+}
+
+impl Drop for ActiveSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// 选择上游的流量类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Traffic {
+    /// TCP 连接：所有上游都可承载，按在途连接计数
+    Tcp,
+    /// QUIC 会话：只在能承载 UDP 的上游间分散，按在途会话计数
+    Udp,
+}
+
+/// 一个 SOCKS5 上游
+#[derive(Debug)]
+pub struct Upstream {
+    name: String,
+    config: Socks5Config,
+    health: Mutex<Health>,
+    /// 主动健康检查的结果
+    probe: Mutex<ProbeHealth>,
+    /// 分配到该上游的连接/会话数 (累计)
+    assigned: AtomicU64,
+    /// 在途的 TCP 连接数
+    active_streams: Arc<AtomicU64>,
+    /// 在途的 QUIC 会话数
+    active_sessions: Arc<AtomicU64>,
+    /// 集合共享的握手耗时统计
+    latency: Arc<LatencyMetrics>,
+    /// `addr` 的解析缓存
+    resolver: Arc<ProxyResolver>,
+}
+
+impl Upstream {
+    fn new(name: impl Into<String>, config: Socks5Config, latency: Arc<LatencyMetrics>) -> Self {
+        Self {
+            name: name.into(),
+            resolver: Arc::new(ProxyResolver::new(config.addr.to_string())),
+            config,
+            health: Mutex::new(Health::default()),
+            probe: Mutex::new(ProbeHealth::default()),
+            assigned: AtomicU64::new(0),
+            active_streams: Arc::default(),
+            active_sessions: Arc::default(),
+            latency,
+        }
+    }
+
+    /// 上游名称
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// 连接该上游使用的 SOCKS5 配置
+    pub fn config(&self) -> &Socks5Config {
+        &self.config
+    }
+
+    /// 报告该上游握手耗时的统计后端，配合 `with_metrics(sink, self.name())` 使用
+    pub fn metrics(&self) -> Arc<dyn MetricsSink> {
+        self.latency.clone()
+    }
+
+    /// 代理地址 (`addr`) 的解析缓存，配合客户端的 `with_resolver` 使用
+    pub fn resolver(&self) -> Arc<ProxyResolver> {
+        Arc::clone(&self.resolver)
+    }
+
+    /// 能否承载 UDP (QUIC)：SOCKS4a 和代理链只有 TCP CONNECT
+    pub fn supports_udp(&self) -> bool {
+        self.config.protocol == UpstreamProtocol::Socks5 && self.config.chain.is_empty()
+    }
+
+    /// 累计分配到该上游的连接/会话数
+    pub fn assigned(&self) -> u64 {
+        self.assigned.load(Ordering::Relaxed)
+    }
+
+    /// 计入一个在途的 TCP 连接，返回值存活期间计数
+    pub fn open_stream(&self) -> ActiveSlot {
+        ActiveSlot::open(&self.active_streams)
+    }
+
+    /// 计入一个在途的 QUIC 会话，返回值存活期间计数
+    pub fn open_session(&self) -> ActiveSlot {
+        ActiveSlot::open(&self.active_sessions)
+    }
+
+    /// 在途的 TCP 连接或 QUIC 会话数
+    fn load(&self, traffic: Traffic) -> u64 {
+        match traffic {
+            Traffic::Tcp => self.active_streams.load(Ordering::Relaxed),
+            Traffic::Udp => self.active_sessions.load(Ordering::Relaxed),
+        }
+    }
+
+    /// 当前是否参与负载分散
+    pub fn is_healthy(&self) -> bool {
+        self.is_healthy_at(Instant::now())
+    }
+
+    fn is_healthy_at(&self, now: Instant) -> bool {
+        if self.state() == BackendState::Down {
+            return false;
+        }
+        let health = self.health.lock().unwrap_or_else(|e| e.into_inner());
+        health
+            .down_since
+            .is_none_or(|since| now.duration_since(since) >= RETRY_AFTER)
+    }
+
+    /// 记录一次成功的连接
+    pub fn record_success(&self) {
+        let mut health = self.health.lock().unwrap_or_else(|e| e.into_inner());
+        if health.down_since.take().is_some() {
+            info!(upstream = %self.name, "SOCKS5 upstream recovered");
+        }
+        health.consecutive_failures = 0;
+    }
+
+    /// 记录一次失败的连接
+    pub fn record_failure(&self) {
+        self.record_failure_at(Instant::now());
+    }
+
+    fn record_failure_at(&self, now: Instant) {
+        let mut health = self.health.lock().unwrap_or_else(|e| e.into_inner());
+        health.consecutive_failures = health.consecutive_failures.saturating_add(1);
+        if health.consecutive_failures < FAILURE_THRESHOLD {
+            return;
+        }
+        if health.down_since.is_none() {
+            warn!(
+                upstream = %self.name,
+                addr = %self.config.addr,
+                consecutive_failures = health.consecutive_failures,
+                retry_after = ?RETRY_AFTER,
+                "SOCKS5 upstream failing repeatedly, skipping it"
+            );
+        }
+        // 重试期间再次失败时重新计时
+        health.down_since = Some(now);
+    }
+
+    /// 主动健康检查判定的状态
+    pub fn state(&self) -> BackendState {
+        self.probe.lock().unwrap_or_else(|e| e.into_inner()).state
+    }
+
+    /// 最近一次探测成功的时刻；尚未探测或最近一次探测失败时为 `None`
+    pub fn last_probe_success(&self) -> Option<Instant> {
+        self.probe
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .last_success
+    }
+
+    /// 记录一次成功的探测，`latency` 为探测耗时
+    pub fn record_probe_success(&self, latency: Duration) {
+        let mut probe = self.probe.lock().unwrap_or_else(|e| e.into_inner());
+        probe.consecutive_failures = 0;
+        probe.last_success = Some(Instant::now());
+        probe.latency_ewma = Some(match probe.latency_ewma {
+            Some(ewma) => {
+                ewma.mul_f64(1.0 - LATENCY_EWMA_WEIGHT) + latency.mul_f64(LATENCY_EWMA_WEIGHT)
+            }
+            None => latency,
+        });
+        self.transition(&mut probe, BackendState::Healthy);
+    }
+
+    /// 记录一次失败的探测
+    pub fn record_probe_failure(&self, error: impl fmt::Display) {
+        let mut probe = self.probe.lock().unwrap_or_else(|e| e.into_inner());
+        probe.consecutive_failures = probe.consecutive_failures.saturating_add(1);
+        probe.last_error = Some(error.to_string());
+        probe.last_success = None;
+        let state = if probe.consecutive_failures >= FAILURE_THRESHOLD {
+            BackendState::Down
+        } else {
+            BackendState::Degraded
+        };
+        self.transition(&mut probe, state);
+    }
+
+    fn transition(&self, probe: &mut ProbeHealth, state: BackendState) {
+        let previous = std::mem::replace(&mut probe.state, state);
+        if previous == state {
+            return;
+        }
+        probe.transitions += 1;
+        match state {
+            BackendState::Healthy => info!(
+                upstream = %self.name,
+                addr = %self.config.addr,
+                state = %state,
+                previous = %previous,
+                "SOCKS5 upstream healthy again"
+            ),
+            _ => warn!(
+                upstream = %self.name,
+                addr = %self.config.addr,
+                state = %state,
+                previous = %previous,
+                failed_checks = probe.consecutive_failures,
+                error = probe.last_error.as_deref().unwrap_or("unknown error"),
+                "SOCKS5 upstream health changed"
+            ),
+        }
+    }
+
+    /// 健康状态快照
+    pub fn health(&self) -> UpstreamHealth {
+        let selectable = self.is_healthy();
+        let probe = self.probe.lock().unwrap_or_else(|e| e.into_inner());
+        UpstreamHealth {
+            name: self.name.clone(),
+            addr: self.config.addr.to_string(),
+            state: probe.state,
+            selectable,
+            consecutive_failures: probe.consecutive_failures,
+            last_error: probe.last_error.clone(),
+            latency_ewma: probe.latency_ewma,
+            transitions: probe.transitions,
+            assigned: self.assigned(),
+            active_streams: self.load(Traffic::Tcp),
+            active_sessions: self.load(Traffic::Udp),
+            weight: self.config.weight,
+        }
+    }
+}
+
+/// 所有 SOCKS5 上游
+#[derive(Debug)]
+pub struct UpstreamSet {
+    upstreams: Vec<Arc<Upstream>>,
+    latency: Arc<LatencyMetrics>,
+    balance: BalanceStrategy,
+    /// `round_robin` 与 `weighted` 的游标
+    cursor: AtomicUsize,
+}
+
+/// 上游配置无效
+fn invalid(reason: String) -> ConfigError {
+    ConfigError::Invalid {
+        section: "SOCKS5 upstream",
+        reason,
+    }
+}
+
+impl UpstreamSet {
+    /// 由配置创建；上游重名或路由规则引用了不存在的上游时返回错误
+    pub fn from_config(config: &Config) -> Result<Self, ConfigError> {
+        let mut default = config.socks5.clone();
+        default.upstreams.clear();
+        if default.protocol == UpstreamProtocol::Socks4a && !default.chain.is_empty() {
+            return Err(invalid(
+                "socks5.chain is only supported with protocol = \"socks5\"".to_string(),
+            ));
+        }
+        let latency = Arc::new(LatencyMetrics::default());
+        let mut upstreams = vec![Arc::new(Upstream::new(
+            DEFAULT_UPSTREAM,
+            default.clone(),
+            latency.clone(),
+        ))];
+
+        let mut names: HashSet<&str> = HashSet::from([DEFAULT_UPSTREAM]);
+        for extra in &config.socks5.upstreams {
+            if !names.insert(&extra.name) {
+                return Err(invalid(format!(
+                    "Duplicate SOCKS5 upstream name '{}'",
+                    extra.name
+                )));
+            }
+            let upstream_config = Socks5Config {
+                addr: extra.addr.clone(),
+                username: extra.username.clone(),
+                password: extra.password.clone(),
+                protocol: extra.protocol,
+                weight: extra.weight,
+                // 代理链只属于 [socks5] 本身
+                chain: Vec::new(),
+                ..default.clone()
+            };
+            upstreams.push(Arc::new(Upstream::new(
+                &extra.name,
+                upstream_config,
+                latency.clone(),
+            )));
+        }
+
+        for rule in &config.rules.routes {
+            if !names.contains(rule.upstream.as_str()) {
+                return Err(invalid(format!(
+                    "rules.routes pattern '{}' refers to unknown SOCKS5 upstream '{}'",
+                    rule.pattern, rule.upstream
+                )));
+            }
+        }
+
+        if let Some(upstream) = upstreams.iter().find(|u| u.config.weight == 0) {
+            return Err(invalid(format!(
+                "SOCKS5 upstream '{}' has weight 0",
+                upstream.name
+            )));
+        }
+
+        Ok(Self {
+            upstreams,
+            latency,
+            balance: config.socks5.balance,
+            cursor: AtomicUsize::new(0),
+        })
+    }
+
+    /// 只有一个上游 (`[socks5]`) 的集合
+    pub fn single(socks5: Socks5Config) -> Self {
+        let latency = Arc::new(LatencyMetrics::default());
+        Self {
+            balance: socks5.balance,
+            cursor: AtomicUsize::new(0),
+            upstreams: vec![Arc::new(Upstream::new(
+                DEFAULT_UPSTREAM,
+                socks5,
+                latency.clone(),
+            ))],
+            latency,
+        }
+    }
+
+    /// 各上游的 SOCKS5 握手耗时统计
+    pub fn latency(&self) -> &Arc<LatencyMetrics> {
+        &self.latency
+    }
+
+    /// 各上游的健康状态，按配置顺序
+    pub fn health(&self) -> Vec<UpstreamHealth> {
+        self.upstreams.iter().map(|u| u.health()).collect()
+    }
+
+    /// 按名称查找上游
+    pub fn get(&self, name: &str) -> Option<&Arc<Upstream>> {
+        self.upstreams.iter().find(|u| u.name == name)
+    }
+
+    /// 所有上游
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<Upstream>> {
+        self.upstreams.iter()
+    }
+
+    /// 选择上游并计入其分配数
+    ///
+    /// `route` 为路由规则指定的上游名称 (见 [`crate::router::Router::route`])；
+    /// 否则按 `socks5.balance` 在健康的上游间选择 (`key` 供 `hash` 使用)，
+    /// 全部不可用时在所有上游间选择。
+    pub fn select(&self, route: Option<&str>, key: &[u8]) -> Arc<Upstream> {
+        let upstream = self.select_at(route, key, Instant::now(), Traffic::Tcp);
+        upstream.assigned.fetch_add(1, Ordering::Relaxed);
+        upstream
+    }
+
+    /// 为 QUIC 会话选择上游：同 [`Self::select`]，但只在能承载 UDP 的上游间分散，
+    /// `least_connections` 按在途会话计数
+    ///
+    /// 规则指定的上游仍然优先，即使它不支持 UDP (由调用方拒绝会话)。
+    pub fn select_udp(&self, route: Option<&str>, key: &[u8]) -> Arc<Upstream> {
+        let upstream = self.select_at(route, key, Instant::now(), Traffic::Udp);
+        upstream.assigned.fetch_add(1, Ordering::Relaxed);
+        upstream
+    }
+
+    fn select_at(
+        &self,
+        route: Option<&str>,
+        key: &[u8],
+        now: Instant,
+        traffic: Traffic,
+    ) -> Arc<Upstream> {
+        if let Some(upstream) = route.and_then(|name| self.get(name)) {
+            return Arc::clone(upstream);
+        }
+        let candidates: Vec<&Arc<Upstream>> = self
+            .upstreams
+            .iter()
+            .filter(|u| traffic == Traffic::Tcp || u.supports_udp())
+            .collect();
+        let healthy: Vec<&Arc<Upstream>> = candidates
+            .iter()
+            .copied()
+            .filter(|u| u.is_healthy_at(now))
+            .collect();
+        let chosen = if !healthy.is_empty() {
+            self.balance(&healthy, key, traffic)
+        } else if !candidates.is_empty() {
+            self.balance(&candidates, key, traffic)
+        } else {
+            let all: Vec<&Arc<Upstream>> = self.upstreams.iter().collect();
+            self.balance(&all, key, traffic)
+        };
+        Arc::clone(chosen)
+    }
+
+    /// 按 `socks5.balance` 在 `pool` (非空) 中选择
+    fn balance<'a>(
+        &self,
+        pool: &[&'a Arc<Upstream>],
+        key: &[u8],
+        traffic: Traffic,
+    ) -> &'a Arc<Upstream> {
+        match self.balance {
+            BalanceStrategy::Hash => highest_score(pool.iter().copied(), key).unwrap_or(pool[0]),
+            BalanceStrategy::Failover => pool[0],
+            BalanceStrategy::RoundRobin => {
+                pool[self.cursor.fetch_add(1, Ordering::Relaxed) % pool.len()]
+            }
+            // 比较 load/weight，交叉相乘避免浮点；并列时取配置顺序靠前的
+            BalanceStrategy::LeastConnections => pool
+                .iter()
+                .copied()
+                .min_by(|a, b| {
+                    let a_load = u128::from(a.load(traffic)) * u128::from(b.config.weight);
+                    let b_load = u128::from(b.load(traffic)) * u128::from(a.config.weight);
+                    a_load.cmp(&b_load)
+                })
+                .unwrap_or(pool[0]),
+            BalanceStrategy::Weighted => {
+                let total: usize = pool.iter().map(|u| u.config.weight as usize).sum();
+                let mut slot = self.cursor.fetch_add(1, Ordering::Relaxed) % total.max(1);
+                for upstream in pool {
+                    let weight = upstream.config.weight as usize;
+                    if slot < weight {
+                        return upstream;
+                    }
+                    slot -= weight;
+                }
+                pool[0]
+            }
+        }
+    }
+}
+
+fn serialize_millis<S: serde::Serializer>(
+    duration: &Option<Duration>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match duration {
+        Some(duration) => serializer.serialize_u64(duration.as_millis() as u64),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// rendezvous 哈希：得分最高的上游胜出，上游增减时只有少数键改变归属
+fn highest_score<'a>(
+    upstreams: impl Iterator<Item = &'a Arc<Upstream>>,
+    key: &[u8],
+) -> Option<&'a Arc<Upstream>> {
+    upstreams.max_by_key(|upstream| fnv1a(key, upstream.name.as_bytes()))
+}
+
+/// 64 位 FNV-1a，依次散列 `key` 和 `salt`
+///
+/// FNV 的高位对末尾字节混合不足，而比较得分主要看高位，最后再做一次 fmix64。
+fn fnv1a(key: &[u8], salt: &[u8]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for byte in key.iter().chain([0xff].iter()).chain(salt) {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{RouteRule, Socks5Upstream, UpstreamProtocol};
+
+    fn config(extra: &[&str], routes: &[(&str, &str)]) -> Config {
+        let mut config: Config = toml::from_str(
+            r#"
+[server]
+listen_https_addr = "127.0.0.1:443"
+
+[socks5]
+addr = "127.0.0.1:1080"
+"#,
+        )
+        .unwrap();
+        config.socks5.upstreams = extra
+            .iter()
+            .enumerate()
+            .map(|(i, name)| Socks5Upstream {
+                name: name.to_string(),
+                addr: format!("127.0.0.1:{}", 1081 + i).parse().unwrap(),
+                username: None,
+                password: None,
+                protocol: UpstreamProtocol::default(),
+                weight: 1,
+            })
+            .collect();
+        config.rules.routes = routes
+            .iter()
+            .map(|(pattern, upstream)| RouteRule {
+                pattern: pattern.to_string(),
+                upstream: upstream.to_string(),
+            })
+            .collect();
+        config
+    }
+
+    #[test]
+    fn test_from_config_validates_names_and_routes() {
+        let set = UpstreamSet::from_config(&config(&["eu"], &[("*.eu", "eu")])).unwrap();
+        assert_eq!(set.iter().count(), 2);
+        let eu = set.get("eu").unwrap();
+        assert_eq!(eu.config().addr.port(), 1081);
+        assert_eq!(
+            eu.config().timeout,
+            set.get("default").unwrap().config().timeout
+        );
+
+        assert!(UpstreamSet::from_config(&config(&["eu", "eu"], &[])).is_err());
+        assert!(UpstreamSet::from_config(&config(&["default"], &[])).is_err());
+        assert!(UpstreamSet::from_config(&config(&[], &[("*.eu", "eu")])).is_err());
+    }
+
+    #[test]
+    fn test_route_wins_over_hash() {
+        let set = UpstreamSet::from_config(&config(&["eu", "us"], &[])).unwrap();
+        for i in 0..32u8 {
+            assert_eq!(set.select(Some("us"), &[i]).name(), "us");
+        }
+        assert_eq!(set.get("us").unwrap().assigned(), 32);
+    }
+
+    #[test]
+    fn test_hash_spreads_across_upstreams() {
+        let set = UpstreamSet::from_config(&config(&["a", "b"], &[])).unwrap();
+        for i in 0..3000u32 {
+            set.select(None, &i.to_be_bytes());
+        }
+        for upstream in set.iter() {
+            // 期望 1000，允许 ±20%
+            let assigned = upstream.assigned();
+            assert!(
+                (800..=1200).contains(&assigned),
+                "{}: {}",
+                upstream.name(),
+                assigned
+            );
+        }
+
+        // 同一个键总是选中同一个上游
+        let key = b"\x01\x02\x03\x04";
+        let first = set.select(None, key);
+        assert!(Arc::ptr_eq(&first, &set.select(None, key)));
+    }
+
+    #[test]
+    fn test_unhealthy_upstream_is_skipped_until_retry() {
+        let set = UpstreamSet::from_config(&config(&["backup"], &[])).unwrap();
+        let default = set.get("default").unwrap();
+        let start = Instant::now();
+
+        for _ in 0..FAILURE_THRESHOLD - 1 {
+            default.record_failure_at(start);
+        }
+        assert!(default.is_healthy_at(start));
+        default.record_failure_at(start);
+        assert!(!default.is_healthy_at(start));
+
+        // 不可用期间所有键都落到另一个上游，规则指定时仍然使用
+        for i in 0..64u8 {
+            assert_eq!(
+                set.select_at(None, &[i], start, Traffic::Tcp).name(),
+                "backup"
+            );
+        }
+        assert_eq!(
+            set.select_at(Some("default"), &[0], start, Traffic::Tcp)
+                .name(),
+            "default"
+        );
+
+        // 重试时间到后重新参与，成功一次即恢复
+        assert!(default.is_healthy_at(start + RETRY_AFTER));
+        default.record_success();
+        assert!(default.is_healthy());
+    }
+
+    #[test]
+    fn test_udp_selection_skips_socks4a_upstreams() {
+        let mut config = config(&["legacy", "modern"], &[("*.old", "legacy")]);
+        config.socks5.upstreams[0].protocol = UpstreamProtocol::Socks4a;
+        let set = UpstreamSet::from_config(&config).unwrap();
+        assert!(!set.get("legacy").unwrap().supports_udp());
+
+        for i in 0..64u8 {
+            assert_ne!(set.select_udp(None, &[i]).name(), "legacy");
+        }
+        // 规则指定时仍然选中，由 QUIC 拒绝会话
+        assert_eq!(set.select_udp(Some("legacy"), b"key").name(), "legacy");
+
+        config.socks5.protocol = UpstreamProtocol::Socks4a;
+        config.socks5.chain = vec![crate::config::Socks5ChainHop {
+            addr: "10.0.0.1:1080".to_string(),
+            username: None,
+            password: None,
+        }];
+        assert!(UpstreamSet::from_config(&config).is_err());
+    }
+
+    fn balanced(strategy: BalanceStrategy, weights: &[u32]) -> UpstreamSet {
+        let mut config = config(&["b", "c"], &[]);
+        config.socks5.balance = strategy;
+        config.socks5.weight = weights[0];
+        for (upstream, weight) in config.socks5.upstreams.iter_mut().zip(&weights[1..]) {
+            upstream.weight = *weight;
+        }
+        UpstreamSet::from_config(&config).unwrap()
+    }
+
+    fn names(set: &UpstreamSet, count: usize) -> Vec<String> {
+        (0..count)
+            .map(|i| set.select(None, &[i as u8]).name().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_failover_round_robin_and_weighted_sequences() {
+        let set = balanced(BalanceStrategy::Failover, &[1, 1, 1]);
+        assert_eq!(names(&set, 3), ["default", "default", "default"]);
+        for _ in 0..FAILURE_THRESHOLD {
+            set.get("default").unwrap().record_failure();
+        }
+        assert_eq!(names(&set, 2), ["b", "b"]);
+
+        let set = balanced(BalanceStrategy::RoundRobin, &[1, 1, 1]);
+        assert_eq!(
+            names(&set, 4),
+            ["default", "b", "c", "default"].map(String::from)
+        );
+
+        let set = balanced(BalanceStrategy::Weighted, &[3, 1, 2]);
+        assert_eq!(
+            names(&set, 7),
+            ["default", "default", "default", "b", "c", "c", "default"].map(String::from)
+        );
+        // 不可用的上游不占权重
+        for _ in 0..FAILURE_THRESHOLD {
+            set.get("default").unwrap().record_failure();
+        }
+        assert_eq!(names(&set, 3), ["c", "c", "b"].map(String::from));
+    }
+
+    #[test]
+    fn test_least_connections_follows_open_and_closed_slots() {
+        let set = balanced(BalanceStrategy::LeastConnections, &[1, 1, 2]);
+        let mut open = Vec::new();
+        let connect = |open: &mut Vec<(String, ActiveSlot)>| {
+            let upstream = set.select(None, b"key");
+            open.push((upstream.name().to_string(), upstream.open_stream()));
+            upstream.name().to_string()
+        };
+        // 并列时取配置顺序靠前的；c 的权重为 2，可以承载两倍的连接
+        let sequence: Vec<String> = (0..6).map(|_| connect(&mut open)).collect();
+        assert_eq!(sequence, ["default", "b", "c", "c", "default", "b"]);
+        assert_eq!(set.get("c").unwrap().health().active_streams, 2);
+
+        // 关闭 b 上的连接后新连接回到 b
+        open.retain(|(name, _)| name != "b");
+        assert_eq!(set.get("b").unwrap().health().active_streams, 0);
+        assert_eq!(connect(&mut open), "b");
+        assert_eq!(connect(&mut open), "b");
+        assert_eq!(connect(&mut open), "c");
+
+        // QUIC 按会话计数，与 TCP 连接互不影响
+        let _session = set.get("default").unwrap().open_session();
+        assert_eq!(set.select_udp(None, b"key").name(), "b");
+    }
+
+    #[test]
+    fn test_zero_weight_is_rejected() {
+        let mut config = config(&["b"], &[]);
+        config.socks5.upstreams[0].weight = 0;
+        assert!(UpstreamSet::from_config(&config).is_err());
+    }
+
+    #[test]
+    fn test_all_unhealthy_falls_back_to_all() {
+        let set = UpstreamSet::single(config(&[], &[]).socks5);
+        let default = set.get(DEFAULT_UPSTREAM).unwrap();
+        for _ in 0..FAILURE_THRESHOLD {
+            default.record_failure();
+        }
+        assert_eq!(set.select(None, b"key").name(), DEFAULT_UPSTREAM);
+    }
+}
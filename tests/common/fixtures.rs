@@ -0,0 +1,116 @@
+//! ClientHello fixture 语料库 (`tests/fixtures/client_hellos`) 的加载
+//!
+//! 每个 fixture 由 `<name>.hex` (十六进制，允许空白和 `#` 开头的注释行) 与
+//! `<name>.json` (来源和期望的解析结果) 组成，来源见 `scripts/gen-client-hello-fixtures.py`。
+//! TCP fixture 为完整的 TLS record，QUIC fixture 为解密后 CRYPTO 流中的 Handshake 消息。
+//!
+//! 单元测试 (`crate::tls::fixtures`)、基准和集成测试都以 `#[path]` 引入本文件，
+//! 不各自解析语料。
+
+#![allow(dead_code)]
+
+use serde::Deserialize;
+use std::path::PathBuf;
+
+/// fixture 的传输方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Transport {
+    /// TLS record 形式
+    Tcp,
+    /// QUIC CRYPTO 流形式 (无 record 头)
+    Quic,
+}
+
+/// 期望的解析结果，版本号使用 `version_name` 的写法 (例如 "TLS 1.3")
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Expected {
+    pub sni: Option<String>,
+    pub alpn: Vec<String>,
+    /// 去掉 GREASE 之后的 supported_versions
+    pub supported_versions: Vec<String>,
+    pub ech: bool,
+    /// QUIC fixture 没有 record 头，为 None
+    pub record_version: Option<String>,
+    pub legacy_version: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Sidecar {
+    source: String,
+    captured: bool,
+    transport: Transport,
+    expected: Expected,
+}
+
+/// 一个 ClientHello fixture
+#[derive(Debug, Clone)]
+pub struct ClientHelloFixture {
+    pub name: String,
+    /// 抓取方式或构造方式
+    pub source: String,
+    /// 是否为真实客户端发出的数据 (否则为按浏览器行为构造)
+    pub captured: bool,
+    pub transport: Transport,
+    pub data: Vec<u8>,
+    pub expected: Expected,
+}
+
+/// 语料库目录
+pub fn corpus_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/client_hellos")
+}
+
+/// 解析 `.hex` 文件内容
+pub fn parse_hex(text: &str) -> Vec<u8> {
+    let digits: String = text
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .flat_map(|line| line.chars().filter(|c| !c.is_whitespace()))
+        .collect();
+    assert!(digits.len().is_multiple_of(2), "odd number of hex digits");
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).expect("invalid hex digit"))
+        .collect()
+}
+
+/// 加载全部 fixture，按名称排序
+pub fn client_hellos() -> Vec<ClientHelloFixture> {
+    let mut names: Vec<String> = std::fs::read_dir(corpus_dir())
+        .expect("read fixture corpus")
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            (path.extension()? == "json").then(|| path.file_stem()?.to_str().map(String::from))?
+        })
+        .collect();
+    names.sort();
+    names.into_iter().map(|name| client_hello(&name)).collect()
+}
+
+/// 加载指定名称的 fixture
+pub fn client_hello(name: &str) -> ClientHelloFixture {
+    let dir = corpus_dir();
+    let read = |ext: &str| {
+        let path = dir.join(format!("{}.{}", name, ext));
+        std::fs::read_to_string(&path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e))
+    };
+    let sidecar: Sidecar =
+        serde_json::from_str(&read("json")).unwrap_or_else(|e| panic!("{}.json: {}", name, e));
+    ClientHelloFixture {
+        name: name.to_string(),
+        source: sidecar.source,
+        captured: sidecar.captured,
+        transport: sidecar.transport,
+        data: parse_hex(&read("hex")),
+        expected: sidecar.expected,
+    }
+}
+
+/// 加载某种传输方式的全部 fixture
+pub fn client_hellos_over(transport: Transport) -> Vec<ClientHelloFixture> {
+    client_hellos()
+        .into_iter()
+        .filter(|fixture| fixture.transport == transport)
+        .collect()
+}
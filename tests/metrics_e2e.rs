@@ -0,0 +1,518 @@
+//! 指标端到端测试：经 TCP 监听器转发到模拟的 SOCKS5 CONNECT 服务器，再抓取 /metrics；
+//! 停止模拟服务器和发出关闭信号时检查 /readyz
+//!
+//! 监听器、模拟后端和抓取都在 localhost 上，需要 `testing` feature。
+
+#[path = "common/fixtures.rs"]
+mod fixtures;
+
+use fast_socks5::util::target_addr::TargetAddr;
+use sniproxy_ng::backend::Backend;
+use sniproxy_ng::health::{HealthChecker, Readiness};
+use sniproxy_ng::logging::LogFilter;
+use sniproxy_ng::metrics::{self, Listener, Registry};
+use sniproxy_ng::socks5::test_util::spawn_mock_connect;
+use sniproxy_ng::upstream::UpstreamSet;
+use sniproxy_ng::{build_info, tcp, Config};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::time::timeout;
+
+/// 取一个当前空闲的本地端口 (tcp::run 按配置自行绑定)
+fn free_addr() -> SocketAddr {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+/// 连接监听器，监听器尚未就绪时重试
+async fn connect(addr: SocketAddr) -> TcpStream {
+    for _ in 0..100 {
+        if let Ok(stream) = TcpStream::connect(addr).await {
+            return stream;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("listener on {} did not come up", addr);
+}
+
+/// 发送一个 GET 请求，返回完整响应
+async fn get(addr: SocketAddr, path: &str) -> String {
+    send(
+        addr,
+        format!("GET {} HTTP/1.1\r\nHost: metrics\r\n\r\n", path),
+    )
+    .await
+}
+
+/// 发送一个带请求体的 PUT 请求，请求体分两次写出；返回完整响应
+async fn put(addr: SocketAddr, path: &str, body: &str) -> String {
+    let mut stream = connect(addr).await;
+    stream
+        .write_all(
+            format!(
+                "PUT {} HTTP/1.1\r\nHost: metrics\r\ncontent-length: {}\r\n\r\n",
+                path,
+                body.len()
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    stream.write_all(body.as_bytes()).await.unwrap();
+    read_response(stream).await
+}
+
+async fn send(addr: SocketAddr, request: String) -> String {
+    let mut stream = connect(addr).await;
+    stream.write_all(request.as_bytes()).await.unwrap();
+    read_response(stream).await
+}
+
+async fn read_response(mut stream: TcpStream) -> String {
+    let mut response = String::new();
+    timeout(Duration::from_secs(2), stream.read_to_string(&mut response))
+        .await
+        .unwrap()
+        .unwrap();
+    response
+}
+
+/// 以 TLS record 包装 Handshake 消息
+fn record(handshake: &[u8]) -> Vec<u8> {
+    let mut data = vec![0x16, 0x03, 0x01];
+    data.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+    data.extend_from_slice(handshake);
+    data
+}
+
+#[tokio::test]
+async fn scrape_reports_tcp_traffic() {
+    let mut backend = spawn_mock_connect().await;
+    let listen_addr = free_addr();
+    let config: Config = toml::from_str(&format!(
+        r#"
+[server]
+listen_https_addr = "{}"
+
+[socks5]
+addr = "{}"
+
+[rules]
+allow = ["www.example.com"]
+"#,
+        listen_addr, backend.addr
+    ))
+    .unwrap();
+
+    let upstreams = Arc::new(UpstreamSet::from_config(&config).unwrap());
+    let registry = Arc::new(Registry::default());
+    metrics::register_socks5_latency(&registry, upstreams.latency().clone());
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let proxy = tokio::spawn(tcp::run(
+        Arc::new(config.clone()),
+        Backend::from_config(&config, upstreams.clone()).unwrap(),
+        shutdown_rx.clone(),
+        Arc::clone(&registry),
+    ));
+    let metrics_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let metrics_addr = metrics_listener.local_addr().unwrap();
+    let readiness = Arc::new(Readiness::new(
+        &config,
+        upstreams,
+        Arc::clone(&registry),
+        shutdown_rx.clone(),
+    ));
+    let endpoint = tokio::spawn(metrics::serve(
+        metrics_listener,
+        Arc::clone(&registry),
+        readiness,
+        Arc::new(LogFilter::default()),
+        Arc::new(build_info::status(&config)),
+        shutdown_rx,
+    ));
+
+    // 允许的 SNI：ClientHello 转发到后端，后端原样发回
+    let hello = fixtures::client_hello("chrome_tcp_ech_grease").data;
+    let mut client = connect(listen_addr).await;
+    client.write_all(&hello).await.unwrap();
+    let mut echoed = vec![0u8; hello.len()];
+    timeout(Duration::from_secs(2), client.read_exact(&mut echoed))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(echoed, hello);
+    assert_eq!(
+        backend.targets.recv().await.unwrap(),
+        TargetAddr::Domain("www.example.com".to_string(), 443)
+    );
+    drop(client);
+
+    // 不在白名单中的 SNI
+    let mut client = connect(listen_addr).await;
+    let rejected = record(&fixtures::client_hello("rfc9001_quic").data);
+    client.write_all(&rejected).await.unwrap();
+    let mut rest = Vec::new();
+    client.read_to_end(&mut rest).await.unwrap();
+    assert!(rest.is_empty());
+
+    // 不是 TLS
+    let mut client = connect(listen_addr).await;
+    client
+        .write_all(b"GET / HTTP/1.1\r\nHost: www.example.com\r\n\r\n")
+        .await
+        .unwrap();
+    client.read_to_end(&mut rest).await.unwrap();
+
+    // 连接任务在客户端关闭后异步结束
+    let mut text = String::new();
+    for _ in 0..100 {
+        text = get(metrics_addr, "/metrics").await;
+        if text.contains("sniproxy_connections_active{listener=\"tcp\"} 0\n") {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(text.starts_with("HTTP/1.1 200 OK\r\n"), "{}", text);
+    assert!(text.contains("Content-Type: text/plain; version=0.0.4"));
+    let bytes = hello.len();
+    for line in [
+        "# TYPE sniproxy_connections_accepted_total counter".to_string(),
+        "sniproxy_connections_accepted_total{listener=\"tcp\"} 3".to_string(),
+        "sniproxy_connections_active{listener=\"tcp\"} 0".to_string(),
+        "sniproxy_sni_extractions_total{listener=\"tcp\",result=\"success\"} 2".to_string(),
+        "sniproxy_sni_extractions_total{listener=\"tcp\",result=\"failure\"} 1".to_string(),
+        "sniproxy_whitelist_rejections_total{listener=\"tcp\"} 1".to_string(),
+        format!(
+            "sniproxy_bytes_forwarded_total{{listener=\"tcp\",direction=\"client_to_upstream\"}} {}",
+            bytes
+        ),
+        format!(
+            "sniproxy_bytes_forwarded_total{{listener=\"tcp\",direction=\"upstream_to_client\"}} {}",
+            bytes
+        ),
+        "sniproxy_socks5_connect_duration_seconds_count{upstream=\"default\",operation=\"connect\"} 1"
+            .to_string(),
+        "sniproxy_socks5_handshakes_total{upstream=\"default\",operation=\"connect\",result=\"ok\"} 1"
+            .to_string(),
+        "sniproxy_pool_connections_created_total 1".to_string(),
+        "sniproxy_pool_connections 0".to_string(),
+        "sniproxy_top_domain_connections{domain=\"www.example.com\"} 1".to_string(),
+        "sniproxy_top_domain_connections{domain=\"other\"} 0".to_string(),
+        format!(
+            "sniproxy_top_domain_bytes{{domain=\"www.example.com\",direction=\"upstream_to_client\"}} {}",
+            bytes
+        ),
+    ] {
+        assert!(
+            text.lines().any(|l| l == line),
+            "missing {:?} in\n{}",
+            line,
+            text
+        );
+    }
+
+    let top = get(metrics_addr, "/domains/top?n=5").await;
+    assert!(top.starts_with("HTTP/1.1 200 OK\r\n"), "{}", top);
+    let body = top.split("\r\n\r\n").nth(1).unwrap();
+    let top: serde_json::Value = serde_json::from_str(body).unwrap();
+    assert_eq!(top["tracked"], 1);
+    assert_eq!(top["domains"][0]["domain"], "www.example.com");
+    assert_eq!(top["domains"][0]["connections"], 1);
+    assert_eq!(top["domains"][0]["bytes_up"], bytes);
+    assert_eq!(top["other"]["connections"], 0);
+    assert!(get(metrics_addr, "/domains/top?n=0")
+        .await
+        .starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+    // 没有运行 QUIC 监听器
+    let sessions = get(metrics_addr, "/quic/sessions").await;
+    assert!(sessions.starts_with("HTTP/1.1 200 OK\r\n"), "{}", sessions);
+    assert_eq!(sessions.split("\r\n\r\n").nth(1), Some("[]\n"));
+
+    let pool = get(metrics_addr, "/pool").await;
+    assert!(pool.starts_with("HTTP/1.1 200 OK\r\n"), "{}", pool);
+    let pool: serde_json::Value =
+        serde_json::from_str(pool.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+    assert_eq!(pool["created"], 1);
+    assert_eq!(pool["active_connections"], 0);
+    assert!(pool["max_wait_ms"].is_number());
+
+    let status = get(metrics_addr, "/status").await;
+    assert!(status.starts_with("HTTP/1.1 200 OK\r\n"), "{}", status);
+    let status: serde_json::Value =
+        serde_json::from_str(status.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+    assert_eq!(status["build"]["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(status["config"]["listen"]["https"], listen_addr.to_string());
+    assert_eq!(status["config"]["rules"]["allow"], 1);
+
+    assert!(get(metrics_addr, "/")
+        .await
+        .starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+    shutdown_tx.send(true).unwrap();
+    timeout(Duration::from_secs(5), proxy)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    timeout(Duration::from_secs(5), endpoint)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+}
+
+/// 请求 `/readyz`，返回是否就绪和响应体
+async fn ready(addr: SocketAddr) -> (bool, String) {
+    let response = get(addr, "/readyz").await;
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let ready = head.starts_with("HTTP/1.1 200 OK\r\n");
+    assert!(ready || head.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+    assert!(head.contains("Content-Type: application/json"));
+    (ready, body.to_string())
+}
+
+/// 轮询 `/readyz` 直到就绪状态为 `expected`，返回最后的响应体
+async fn wait_ready(addr: SocketAddr, expected: bool) -> String {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let (ready, body) = ready(addr).await;
+        if ready == expected {
+            return body;
+        }
+        assert!(
+            Instant::now() < deadline,
+            "readiness never became {}: {}",
+            expected,
+            body
+        );
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+#[tokio::test]
+async fn readiness_follows_listener_backend_and_shutdown() {
+    let backend = spawn_mock_connect().await;
+    let listen_addr = free_addr();
+    let config: Config = toml::from_str(&format!(
+        r#"
+[server]
+listen_https_addr = "{}"
+
+[socks5]
+addr = "{}"
+timeout = 1
+health_interval = 1
+"#,
+        listen_addr, backend.addr
+    ))
+    .unwrap();
+    let interval = Duration::from_secs(config.socks5.health_interval);
+
+    let upstreams = Arc::new(UpstreamSet::from_config(&config).unwrap());
+    let registry = Arc::new(Registry::default());
+    registry.expect_listener(Listener::Tcp);
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let readiness = Arc::new(Readiness::new(
+        &config,
+        upstreams.clone(),
+        Arc::clone(&registry),
+        shutdown_rx.clone(),
+    ));
+    // 与 main 一样，端点在监听器关闭后才停止
+    let (endpoint_tx, endpoint_rx) = watch::channel(false);
+    let metrics_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let metrics_addr = metrics_listener.local_addr().unwrap();
+    let endpoint = tokio::spawn(metrics::serve(
+        metrics_listener,
+        Arc::clone(&registry),
+        readiness,
+        Arc::new(LogFilter::default()),
+        Arc::new(serde_json::Value::Null),
+        endpoint_rx,
+    ));
+
+    // 监听器未绑定、上游未探测
+    let (ready_now, body) = ready(metrics_addr).await;
+    assert!(!ready_now);
+    let report: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(report["status"], "not_ready");
+    let failing: Vec<&str> = report["failing"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|c| c["component"].as_str().unwrap())
+        .collect();
+    assert_eq!(failing, ["listener:tcp", "backend"]);
+    assert_eq!(
+        get(metrics_addr, "/healthz").await,
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 16\r\nConnection: close\r\n\r\n{\"status\":\"ok\"}\n"
+    );
+
+    let checker = HealthChecker::from_config(&config, upstreams.clone())
+        .unwrap()
+        .spawn(shutdown_rx.clone());
+    let proxy = tokio::spawn(tcp::run(
+        Arc::new(config.clone()),
+        Backend::from_config(&config, upstreams).unwrap(),
+        shutdown_rx,
+        Arc::clone(&registry),
+    ));
+    assert_eq!(
+        wait_ready(metrics_addr, true).await,
+        "{\"status\":\"ready\"}\n"
+    );
+
+    // 停止 SOCKS5 服务器：下一次探测失败即未就绪
+    backend.stop().await;
+    let stopped = Instant::now();
+    let body = wait_ready(metrics_addr, false).await;
+    assert!(
+        stopped.elapsed() <= interval + Duration::from_millis(500),
+        "readiness flipped after {:?}",
+        stopped.elapsed()
+    );
+    let report: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(report["failing"][0]["component"], "backend");
+    assert!(report["failing"][0]["reason"]
+        .as_str()
+        .unwrap()
+        .contains("default: "));
+
+    // 关闭期间端点仍在，报告未就绪
+    shutdown_tx.send(true).unwrap();
+    let (ready_now, body) = ready(metrics_addr).await;
+    assert!(!ready_now);
+    assert!(body.contains("\"component\":\"shutdown\""), "{}", body);
+    timeout(Duration::from_secs(5), proxy)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    timeout(Duration::from_secs(5), checker)
+        .await
+        .unwrap()
+        .unwrap();
+    let (_, body) = ready(metrics_addr).await;
+    assert!(body.contains("\"component\":\"listener:tcp\""), "{}", body);
+
+    endpoint_tx.send(true).unwrap();
+    timeout(Duration::from_secs(5), endpoint)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn log_level_endpoint_swaps_filter() {
+    use tracing_subscriber::prelude::*;
+
+    let config: Config = toml::from_str("[server]\n[socks5]\naddr = \"127.0.0.1:9\"\n").unwrap();
+    let upstreams = Arc::new(UpstreamSet::from_config(&config).unwrap());
+    let registry = Arc::new(Registry::default());
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let readiness = Arc::new(Readiness::new(
+        &config,
+        upstreams,
+        Arc::clone(&registry),
+        shutdown_rx.clone(),
+    ));
+    let log_filter = Arc::new(LogFilter::default());
+    let _guard = tracing::subscriber::set_default(
+        tracing_subscriber::registry().with(log_filter.layer("console", "warn")),
+    );
+    let metrics_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let metrics_addr = metrics_listener.local_addr().unwrap();
+    let endpoint = tokio::spawn(metrics::serve(
+        metrics_listener,
+        registry,
+        readiness,
+        Arc::clone(&log_filter),
+        Arc::new(serde_json::Value::Null),
+        shutdown_rx,
+    ));
+    let json = |response: &str| -> serde_json::Value {
+        serde_json::from_str(response.split_once("\r\n\r\n").unwrap().1).unwrap()
+    };
+
+    let response = get(metrics_addr, "/admin/log_level").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    let state = json(&response);
+    assert_eq!(state["filters"]["console"], "warn");
+    assert_eq!(state["overridden"], false);
+
+    // 请求体可以是 JSON 字符串
+    let response = put(
+        metrics_addr,
+        "/admin/log_level",
+        "\"info,sniproxy_ng::quic=trace\"",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert_eq!(
+        json(&response)["filters"]["console"],
+        "info,sniproxy_ng::quic=trace"
+    );
+    assert!(tracing::enabled!(target: "sniproxy_ng::quic::session", tracing::Level::TRACE));
+    assert!(!tracing::enabled!(target: "sniproxy_ng::tcp", tracing::Level::DEBUG));
+
+    // 无效的过滤器返回 400，当前设置不变
+    let response = put(metrics_addr, "/admin/log_level", "sniproxy_ng=loud").await;
+    assert!(
+        response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+        "{}",
+        response
+    );
+    let response = put(metrics_addr, "/admin/log_level", "").await;
+    assert!(
+        response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+        "{}",
+        response
+    );
+    let state = json(&get(metrics_addr, "/admin/log_level").await);
+    assert_eq!(state["filters"]["console"], "info,sniproxy_ng::quic=trace");
+    assert_eq!(state["overridden"], true);
+
+    // 纯文本请求体
+    let response = put(metrics_addr, "/admin/log_level", "debug\n").await;
+    assert_eq!(json(&response)["filters"]["console"], "debug");
+    assert!(tracing::enabled!(target: "sniproxy_ng::tcp", tracing::Level::DEBUG));
+
+    // 恢复配置的过滤器
+    let response = send(
+        metrics_addr,
+        "DELETE /admin/log_level HTTP/1.1\r\nHost: metrics\r\n\r\n".to_string(),
+    )
+    .await;
+    let state = json(&response);
+    assert_eq!(state["filters"]["console"], "warn");
+    assert_eq!(state["overridden"], false);
+
+    // 没有配置文件可供重新读取
+    let response = send(
+        metrics_addr,
+        "POST /admin/log_level/reload HTTP/1.1\r\nHost: metrics\r\n\r\n".to_string(),
+    )
+    .await;
+    assert!(
+        response.starts_with("HTTP/1.1 500 Internal Server Error\r\n")
+            && response.ends_with("no config file to reload log levels from\n"),
+        "{}",
+        response
+    );
+
+    shutdown_tx.send(true).unwrap();
+    timeout(Duration::from_secs(5), endpoint)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+}
@@ -0,0 +1,176 @@
+//! QUIC 端到端测试：客户端 Initial → SOCKS5 UDP relay → 回包 → 客户端
+//!
+//! 监听 socket、模拟 relay 和客户端都在 localhost 上，需要 `testing` feature。
+
+#[path = "common/fixtures.rs"]
+mod fixtures;
+
+use fast_socks5::util::target_addr::TargetAddr;
+use sniproxy_ng::quic::session::{QuicSessionConfig, QuicSessionManager};
+use sniproxy_ng::quic::test_util::{build_client_initial, crypto_frame};
+use sniproxy_ng::router::Router;
+use sniproxy_ng::socks5::test_util::spawn_mock_udp_associate;
+use sniproxy_ng::upstream::UpstreamSet;
+use sniproxy_ng::{quic, Config};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::watch;
+use tokio::time::timeout;
+
+/// 抓取的 QUIC v1 客户端 Initial，ClientHello 的 SNI 为 www.example.com
+const CAPTURED_INITIAL: &[u8] = include_bytes!("fixtures/quic_initial_dump.bin");
+
+fn config(socks5_addr: std::net::SocketAddr) -> Config {
+    toml::from_str(&format!(
+        r#"
+[server]
+listen_https_addr = "127.0.0.1:443"
+
+[socks5]
+addr = "{}"
+
+[rules]
+allow = ["www.example.com", "example.com"]
+"#,
+        socks5_addr
+    ))
+    .unwrap()
+}
+
+#[tokio::test]
+async fn captured_initial_round_trips_through_relay() {
+    let mut relay = spawn_mock_udp_associate(Duration::ZERO).await;
+    let config = config(relay.addr);
+
+    let listener = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    let listen_addr = listener.local_addr().unwrap();
+    let manager = QuicSessionManager::new(
+        QuicSessionConfig::default(),
+        Router::new(config.clone()),
+        Arc::new(UpstreamSet::single(config.socks5.clone())),
+        Arc::clone(&listener),
+    );
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let server = tokio::spawn(quic::serve(
+        vec![listener],
+        manager.clone(),
+        32,
+        shutdown_rx,
+    ));
+
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    client.connect(listen_addr).await.unwrap();
+    client.send(CAPTURED_INITIAL).await.unwrap();
+
+    // Initial 原样发往 SNI 对应的目标
+    let (target, payload) = timeout(Duration::from_secs(2), relay.received.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        target,
+        TargetAddr::Domain("www.example.com".to_string(), 443)
+    );
+    assert_eq!(payload, CAPTURED_INITIAL);
+
+    // 目标的回包经 relay 回到客户端
+    relay
+        .reply(
+            TargetAddr::Ip("203.0.113.10:443".parse().unwrap()),
+            b"server initial",
+        )
+        .await;
+    let mut buf = [0u8; 1500];
+    let n = timeout(Duration::from_secs(2), client.recv(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&buf[..n], b"server initial");
+
+    let snapshot = manager.snapshot();
+    assert_eq!(snapshot.len(), 1);
+    assert_eq!(snapshot[0].sni, "www.example.com");
+    assert_eq!(snapshot[0].client, client.local_addr().unwrap());
+
+    // 收包循环按处理结果计数
+    let packets = manager.stats().await.packets;
+    assert_eq!(packets.outcomes["forwarded_new"], 1);
+    assert_eq!(packets.bytes_forwarded, CAPTURED_INITIAL.len() as u64);
+
+    shutdown_tx.send(true).unwrap();
+    timeout(Duration::from_secs(5), server)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(manager.session_count(), 0);
+}
+
+/// 语料库中的 QUIC ClientHello 按 1000 字节切分进客户端 Initial，经监听端路由到 SNI 对应的目标
+#[tokio::test]
+async fn fixture_corpus_initials_are_routed_by_sni() {
+    let mut relay = spawn_mock_udp_associate(Duration::ZERO).await;
+    let config = config(relay.addr);
+
+    let listener = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    let listen_addr = listener.local_addr().unwrap();
+    let manager = QuicSessionManager::new(
+        QuicSessionConfig::default(),
+        Router::new(config.clone()),
+        Arc::new(UpstreamSet::single(config.socks5.clone())),
+        Arc::clone(&listener),
+    );
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let server = tokio::spawn(quic::serve(
+        vec![listener],
+        manager.clone(),
+        32,
+        shutdown_rx,
+    ));
+
+    let corpus = fixtures::client_hellos_over(fixtures::Transport::Quic);
+    assert!(!corpus.is_empty());
+    for (i, fixture) in corpus.iter().enumerate() {
+        let sni = fixture.expected.sni.as_deref().unwrap();
+        let dcid = [0x5a, 0x3f, 0x00, 0x11, 0x22, 0x33, 0x44, i as u8];
+        let packets: Vec<Vec<u8>> = fixture
+            .data
+            .chunks(1000)
+            .enumerate()
+            .map(|(pn, chunk)| {
+                let frame = crypto_frame((pn * 1000) as u64, chunk);
+                build_client_initial(0x00000001, &dcid, pn as u64, &frame)
+            })
+            .collect();
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(listen_addr).await.unwrap();
+        for packet in &packets {
+            client.send(packet).await.unwrap();
+        }
+
+        // 缓存的前几个 Initial 在 ClientHello 收全后按顺序转发
+        for packet in &packets {
+            let (target, payload) = timeout(Duration::from_secs(2), relay.received.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(
+                target,
+                TargetAddr::Domain(sni.to_string(), 443),
+                "{}",
+                fixture.name
+            );
+            assert_eq!(&payload, packet, "{}", fixture.name);
+        }
+    }
+    assert_eq!(manager.session_count(), corpus.len());
+
+    shutdown_tx.send(true).unwrap();
+    timeout(Duration::from_secs(5), server)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+}
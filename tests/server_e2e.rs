@@ -0,0 +1,206 @@
+//! 以库的形式在进程内启动 [`Server`]：监听临时端口，经模拟的 SOCKS5 CONNECT 服务器完成一次
+//! TCP SNI 转发，再检查优雅关闭会等待已建立的连接
+//!
+//! 需要 `testing` feature 中的模拟服务器。
+
+#[path = "common/fixtures.rs"]
+mod fixtures;
+
+use fast_socks5::util::target_addr::TargetAddr;
+use sniproxy_ng::metrics::Listener;
+use sniproxy_ng::socks5::test_util::spawn_mock_connect;
+use sniproxy_ng::{Config, ConfigError, Error, Server};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+fn config(socks5: SocketAddr) -> Config {
+    toml::from_str(&format!(
+        r#"
+[server]
+listen_https_addr = "127.0.0.1:0"
+listen_http_addr = "127.0.0.1:0"
+stats_log_interval = 0
+
+[socks5]
+addr = "{}"
+health_interval = 0
+
+[rules]
+allow = ["www.example.com"]
+
+[metrics]
+listen_addr = "127.0.0.1:0"
+runtime_sample_interval = 0
+"#,
+        socks5
+    ))
+    .unwrap()
+}
+
+/// 发送 ClientHello，等待后端原样发回
+async fn echo_hello(addr: SocketAddr) -> TcpStream {
+    let hello = fixtures::client_hello("chrome_tcp_ech_grease").data;
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    client.write_all(&hello).await.unwrap();
+    let mut echoed = vec![0u8; hello.len()];
+    timeout(Duration::from_secs(2), client.read_exact(&mut echoed))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(echoed, hello);
+    client
+}
+
+#[tokio::test]
+async fn serves_tcp_sni_in_process_and_drains_on_shutdown() {
+    let mut backend = spawn_mock_connect().await;
+    let server = Server::new(config(backend.addr)).await.unwrap();
+    let addrs = server.local_addrs();
+    let https = addrs.https.unwrap();
+    assert_ne!(https.port(), 0);
+    assert_ne!(addrs.http.unwrap().port(), 0);
+    assert_eq!(addrs.quic, None);
+    let handle = server.start();
+    assert_eq!(handle.local_addrs(), addrs);
+
+    // 完整的 SNI 转发
+    drop(echo_hello(https).await);
+    assert_eq!(
+        backend.targets.recv().await.unwrap(),
+        TargetAddr::Domain("www.example.com".to_string(), 443)
+    );
+
+    // 指标端点同时在运行
+    let mut metrics = TcpStream::connect(addrs.metrics.unwrap()).await.unwrap();
+    metrics
+        .write_all(b"GET /readyz HTTP/1.1\r\nHost: metrics\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    metrics.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+
+    // 关闭时等待仍在转发的连接
+    let open = echo_hello(https).await;
+    let registry = handle.metrics().clone();
+    let shutdown = tokio::spawn(handle.shutdown(Duration::from_secs(5)));
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(!shutdown.is_finished());
+    assert_eq!(registry.active_connections(Listener::Tcp), 1);
+    // 已停止接受新连接
+    assert!(TcpStream::connect(https).await.is_err());
+
+    drop(open);
+    timeout(Duration::from_secs(5), shutdown)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(registry.active_connections(Listener::Tcp), 0);
+}
+
+#[tokio::test]
+async fn shutdown_reports_connections_left_open() {
+    let backend = spawn_mock_connect().await;
+    let server = Server::new(config(backend.addr)).await.unwrap();
+    let https = server.local_addrs().https.unwrap();
+    let handle = server.start();
+
+    let _open = echo_hello(https).await;
+    let error = handle
+        .shutdown(Duration::from_millis(200))
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(error, Error::ShutdownTimeout { open: 1, .. }),
+        "{}",
+        error
+    );
+}
+
+#[tokio::test]
+async fn bind_errors_surface_from_new() {
+    let occupied = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let occupied_addr = occupied.local_addr().unwrap();
+    let mut config = config("127.0.0.1:9".parse().unwrap());
+    config.server.listen_http_addr = Some(occupied_addr);
+    match Server::new(config).await.err().unwrap() {
+        Error::ListenerBind {
+            listener,
+            addr,
+            source,
+        } => {
+            assert_eq!(listener, "HTTP");
+            assert_eq!(addr, occupied_addr);
+            assert_eq!(source.kind(), std::io::ErrorKind::AddrInUse);
+        }
+        error => panic!("unexpected error: {}", error),
+    }
+
+    let mut config = self::config("127.0.0.1:9".parse().unwrap());
+    config.server.listen_https_addr = None;
+    config.server.listen_http_addr = None;
+    assert!(matches!(
+        Server::new(config).await.err().unwrap(),
+        Error::Config(ConfigError::NoListener)
+    ));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn sigterm_starts_a_graceful_drain() {
+    use sniproxy_ng::signals::ShutdownSignals;
+
+    let backend = spawn_mock_connect().await;
+    let server = Server::new(config(backend.addr)).await.unwrap();
+    let https = server.local_addrs().https.unwrap();
+    let handle = server.start();
+    let open = echo_hello(https).await;
+
+    // 与 main 相同：信号经通道交给关闭流程；注册之后 SIGTERM 不再终止测试进程
+    let (stop_tx, mut stop_rx) = tokio::sync::mpsc::unbounded_channel();
+    let forwarder = ShutdownSignals::new().unwrap().forward(stop_tx);
+    let kill = |signal: &str| {
+        let status = std::process::Command::new("kill")
+            .args([signal, &std::process::id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+    };
+    kill("-TERM");
+    assert_eq!(
+        timeout(Duration::from_secs(5), stop_rx.recv())
+            .await
+            .unwrap(),
+        Some("SIGTERM")
+    );
+
+    let registry = handle.metrics().clone();
+    let shutdown = tokio::spawn(handle.shutdown(Duration::from_secs(5)));
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    // 排空中：停止接受新连接，已建立的连接继续转发
+    assert!(!shutdown.is_finished());
+    assert!(TcpStream::connect(https).await.is_err());
+    assert_eq!(registry.active_connections(Listener::Tcp), 1);
+
+    // 排空期间的第二个信号同样送达，由调用方决定立即退出
+    kill("-TERM");
+    assert_eq!(
+        timeout(Duration::from_secs(5), stop_rx.recv())
+            .await
+            .unwrap(),
+        Some("SIGTERM")
+    );
+
+    drop(open);
+    timeout(Duration::from_secs(5), shutdown)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(registry.active_connections(Listener::Tcp), 0);
+    forwarder.abort();
+}